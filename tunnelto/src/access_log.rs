@@ -0,0 +1,38 @@
+use colored::Colorize;
+use tunnelto_lib::AccessLogEntry;
+
+/// Print an entry of the edge's access log (`--access-log`), i.e.
+/// `EDGE 14:02:11 403 GET /admin from 203.0.113.7 (path_access_rules)`
+pub fn print(entry: &AccessLogEntry) {
+    let at = chrono::DateTime::parse_from_rfc3339(&entry.at)
+        .map(|at| {
+            at.with_timezone(&chrono::Local)
+                .format("%H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|_| entry.at.clone());
+
+    let status = match entry.edge_status {
+        None => "-->".green(),
+        Some(status) if status >= 500 => status.to_string().red(),
+        Some(status) => status.to_string().yellow(),
+    };
+
+    let mut line = format!("{} {} {}", "EDGE".dimmed(), at, status);
+    if let (Some(method), Some(path)) = (entry.method.as_ref(), entry.path.as_ref()) {
+        line.push_str(&format!(" {} {}", method, path));
+    }
+    if let Some(visitor_ip) = entry.visitor_ip.as_ref() {
+        line.push_str(&format!(" from {}", visitor_ip));
+    }
+    if let Some(reason) = entry.reason.as_ref() {
+        line.push_str(&format!(" ({})", reason));
+    }
+    if entry.method.is_none() {
+        if let Some(request_id) = entry.request_id.as_ref() {
+            line.push_str(&format!(" request_id={}", request_id));
+        }
+    }
+
+    eprintln!("{}", line);
+}
@@ -0,0 +1,213 @@
+use crate::introspect::{ForwardError, Middleware, MiddlewareResult, Next, ProxyRequest};
+use colored::Colorize;
+use futures::FutureExt;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Warn when too many responses have a status, i.e. `5xx>10/min` or `404>50/30s`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlertRule {
+    pub status: StatusMatch,
+    /// alert once more than this many responses match within `window`
+    pub threshold: usize,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusMatch {
+    /// `5xx` is `Class(5)`
+    Class(u16),
+    Exact(u16),
+}
+
+impl StatusMatch {
+    fn matches(&self, status: u16) -> bool {
+        match self {
+            StatusMatch::Class(class) => status / 100 == *class,
+            StatusMatch::Exact(exact) => status == *exact,
+        }
+    }
+}
+
+impl FromStr for AlertRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid alert `{}`, expected i.e. `5xx>10/min`", s);
+
+        let (status, rest) = s.trim().split_once('>').ok_or_else(invalid)?;
+        let (threshold, window) = rest.split_once('/').ok_or_else(invalid)?;
+
+        let status = status.trim().to_lowercase();
+        let status = match status.strip_suffix("xx") {
+            Some(class) => StatusMatch::Class(class.parse().map_err(|_| invalid())?),
+            None => StatusMatch::Exact(status.parse().map_err(|_| invalid())?),
+        };
+        match status {
+            StatusMatch::Class(1..=5) | StatusMatch::Exact(100..=599) => {}
+            _ => return Err(invalid()),
+        }
+
+        let threshold = threshold.trim().parse().map_err(|_| invalid())?;
+
+        // `/min` reads better than `/1m`
+        let window = match window.trim() {
+            "s" | "sec" => "1s".to_string(),
+            "m" | "min" => "1m".to_string(),
+            "h" | "hour" => "1h".to_string(),
+            window => window.to_string(),
+        };
+        let window = crate::config::parse_duration(&window)?;
+        if window.as_secs() == 0 {
+            return Err(invalid());
+        }
+
+        Ok(AlertRule {
+            status,
+            threshold,
+            window,
+        })
+    }
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            StatusMatch::Class(class) => write!(f, "{}xx", class)?,
+            StatusMatch::Exact(status) => write!(f, "{}", status)?,
+        }
+        write!(f, ">{}/{}s", self.threshold, self.window.as_secs())
+    }
+}
+
+struct Watch {
+    rule: AlertRule,
+    hits: VecDeque<Instant>,
+    last_alert: Option<Instant>,
+}
+
+impl Watch {
+    /// Count a matching response, returning how many are in the window if the rule fires.
+    /// A rule fires at most once per window so a broken deploy doesn't flood the console.
+    fn hit(&mut self, now: Instant) -> Option<usize> {
+        self.hits.push_back(now);
+        while let Some(oldest) = self.hits.front() {
+            if now.duration_since(*oldest) <= self.rule.window {
+                break;
+            }
+            self.hits.pop_front();
+        }
+
+        let quiet = self
+            .last_alert
+            .is_none_or(|last| now.duration_since(last) > self.rule.window);
+        if self.hits.len() > self.rule.threshold && quiet {
+            self.last_alert = Some(now);
+            return Some(self.hits.len());
+        }
+        None
+    }
+}
+
+/// Watches response statuses against the configured alert rules
+pub struct Alerts {
+    watches: Vec<Mutex<Watch>>,
+    webhook: Option<String>,
+}
+
+impl Alerts {
+    pub fn new(rules: Vec<AlertRule>, webhook: Option<String>) -> Self {
+        Alerts {
+            watches: rules
+                .into_iter()
+                .map(|rule| {
+                    Mutex::new(Watch {
+                        rule,
+                        hits: VecDeque::new(),
+                        last_alert: None,
+                    })
+                })
+                .collect(),
+            webhook,
+        }
+    }
+
+    fn observe(&self, status: u16) {
+        let now = Instant::now();
+        for watch in &self.watches {
+            let mut watch = watch.lock().unwrap();
+            if !watch.rule.status.matches(status) {
+                continue;
+            }
+            if let Some(count) = watch.hit(now) {
+                self.alert(&watch.rule, count);
+            }
+        }
+    }
+
+    fn alert(&self, rule: &AlertRule, count: usize) {
+        let public_url = crate::introspect::public_url();
+        let message = format!(
+            "{} matching responses in the last {:?} ({}) on {}",
+            count,
+            rule.window,
+            rule,
+            public_url.as_deref().unwrap_or("your tunnel")
+        );
+        eprintln!("\x07{} {}", "ALERT".on_red().white().bold(), message);
+        crate::notify::send(crate::notify::Event::Alert { message });
+
+        if let Some(url) = self.webhook.clone() {
+            let body = serde_json::json!({
+                "rule": rule.to_string(),
+                "count": count,
+                "window_secs": rule.window.as_secs(),
+                "public_url": public_url,
+                "triggered_at": chrono::Utc::now().to_rfc3339(),
+            });
+            tokio::spawn(send_webhook(url, body));
+        }
+    }
+}
+
+async fn send_webhook(url: String, body: serde_json::Value) {
+    let request = hyper::Request::post(&url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(body.to_string()));
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            log::error!("invalid alert webhook {}: {:?}", url, e);
+            return;
+        }
+    };
+
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    match client.request(request).await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => log::error!("alert webhook answered {}", response.status()),
+        Err(e) => log::error!("failed to send alert webhook: {:?}", e),
+    }
+}
+
+impl Middleware for Alerts {
+    fn handle<'a>(&'a self, request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a> {
+        async move {
+            let result = next.run(request).await;
+
+            // count failures to reach the local service as what the visitor got
+            let status = match &result {
+                Ok(response) => response.status.as_u16(),
+                Err(ForwardError::LocalServerError) => 502,
+                Err(_) => 400,
+            };
+            self.observe(status);
+
+            result
+        }
+        .boxed()
+    }
+}
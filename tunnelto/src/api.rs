@@ -0,0 +1,56 @@
+use super::*;
+use hyper::body::HttpBody;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// POST a json request to the control server's api and parse the json reply
+pub async fn post<Req: Serialize, Res: DeserializeOwned>(
+    config: &Config,
+    path: &str,
+    request: &Req,
+) -> Result<Res, Error> {
+    let body = serde_json::to_vec(request).unwrap_or_default();
+    let request = hyper::Request::post(format!("{}/{}", config.control_api_url, path))
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(body))
+        .map_err(|e| {
+            error!("failed to build {} request: {:?}", path, e);
+            Error::ServerReplyInvalid
+        })?;
+
+    send(path, request).await
+}
+
+/// GET a json reply from the control server's api
+pub async fn get<Res: DeserializeOwned>(config: &Config, path: &str) -> Result<Res, Error> {
+    let request = hyper::Request::get(format!("{}/{}", config.control_api_url, path))
+        .body(hyper::Body::empty())
+        .map_err(|e| {
+            error!("failed to build {} request: {:?}", path, e);
+            Error::ServerReplyInvalid
+        })?;
+
+    send(path, request).await
+}
+
+async fn send<Res: DeserializeOwned>(
+    path: &str,
+    request: hyper::Request<hyper::Body>,
+) -> Result<Res, Error> {
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let mut response = client.request(request).await.map_err(|e| {
+        error!("{} request failed: {:?}", path, e);
+        Error::NoResponseFromServer
+    })?;
+
+    let mut data = vec![];
+    while let Some(chunk) = response.body_mut().data().await {
+        let chunk = chunk.map_err(|_| Error::NoResponseFromServer)?;
+        data.extend_from_slice(&chunk);
+    }
+
+    serde_json::from_slice::<Res>(&data).map_err(|e| {
+        error!("Couldn't parse {} response from {:?}", path, e);
+        Error::ServerReplyInvalid
+    })
+}
@@ -0,0 +1,30 @@
+use super::*;
+
+/// Approve a teammate's tunnel held by the account's approval policy
+pub async fn approve(config: &Config, sub_domain: String) -> Result<(), Error> {
+    let auth_key = config.secret_key.clone().ok_or(Error::NoAuthenticationKey)?;
+
+    let request = ApprovalRequest {
+        auth_key,
+        sub_domain,
+    };
+    let response: ApprovalResponse = api::post(config, "approve", &request).await?;
+
+    match response {
+        ApprovalResponse::Approved { sub_domain, target } => {
+            eprintln!(
+                "{} {} now forwards traffic{}.",
+                "Approved!".green(),
+                sub_domain.bold().green(),
+                target
+                    .map(|target| format!(" to {}", target))
+                    .unwrap_or_default()
+            );
+        }
+        ApprovalResponse::Failed { reason } => {
+            eprintln!("{} {}", "Approval failed:".red(), reason);
+        }
+    }
+
+    Ok(())
+}
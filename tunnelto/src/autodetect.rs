@@ -0,0 +1,144 @@
+use colored::Colorize;
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Ports popular dev servers listen on by default
+const COMMON_DEV_PORTS: &[u16] = &[
+    3000, 3001, 4000, 4200, 5000, 5173, 5500, 8000, 8080, 8081, 8888, 9000,
+];
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(150);
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub port: u16,
+    pub process: Option<String>,
+}
+
+pub fn is_listening(host: &str, port: u16) -> bool {
+    let addrs = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return false,
+    };
+
+    addrs
+        .into_iter()
+        .any(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok())
+}
+
+/// Best effort: ask `lsof` which process is listening on the port
+fn process_name(port: u16) -> Option<String> {
+    let output = std::process::Command::new("lsof")
+        .args(["-nP", &format!("-iTCP:{}", port), "-sTCP:LISTEN", "-Fc"])
+        .output()
+        .ok()?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|l| l.starts_with('c'))
+        .map(|l| l[1..].to_string())
+}
+
+pub fn scan(host: &str) -> Vec<Candidate> {
+    COMMON_DEV_PORTS
+        .iter()
+        .filter(|port| is_listening(host, **port))
+        .map(|port| Candidate {
+            port: *port,
+            process: process_name(*port),
+        })
+        .collect()
+}
+
+/// Find the local port to forward to, prompting if more than one is listening
+pub fn detect_local_port(host: &str) -> Option<u16> {
+    let candidates = scan(host);
+
+    match candidates.as_slice() {
+        [] => {
+            eprintln!(
+                "{} checked ports {:?} on {}",
+                "No local server found:".red(),
+                COMMON_DEV_PORTS,
+                host
+            );
+            None
+        }
+        [single] => {
+            eprintln!(
+                "{} {}",
+                "Found local server on port".green(),
+                describe(single).yellow()
+            );
+            Some(single.port)
+        }
+        _ if !std::io::stdin().is_terminal() => {
+            eprintln!(
+                "{} {}. Use --port to choose one.",
+                "Multiple local servers found:".red(),
+                candidates
+                    .iter()
+                    .map(describe)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+            None
+        }
+        _ => prompt(&candidates),
+    }
+}
+
+fn describe(candidate: &Candidate) -> String {
+    match candidate.process.as_ref() {
+        Some(process) => format!("{} ({})", candidate.port, process),
+        None => format!("{}", candidate.port),
+    }
+}
+
+fn prompt(candidates: &[Candidate]) -> Option<u16> {
+    eprintln!("{}", "Multiple local servers found:".yellow());
+    for (i, candidate) in candidates.iter().enumerate() {
+        eprintln!("  [{}] {}", i + 1, describe(candidate));
+    }
+
+    loop {
+        eprint!("Which one should be exposed? [1-{}]: ", candidates.len());
+        let _ = std::io::stderr().flush();
+
+        let mut line = String::new();
+        if std::io::stdin().lock().read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(i) if i >= 1 && i <= candidates.len() => return Some(candidates[i - 1].port),
+            _ => eprintln!("{}", "invalid choice".red()),
+        }
+    }
+}
+
+/// Hint at likely alternatives when nothing listens on the chosen port
+pub fn warn_if_not_listening(host: &str, port: u16) {
+    if is_listening(host, port) {
+        return;
+    }
+
+    let candidates = scan(host);
+    eprintln!(
+        "{} nothing is listening on {}:{}",
+        "Warning:".yellow(),
+        host,
+        port
+    );
+
+    if !candidates.is_empty() {
+        eprintln!(
+            "         did you mean: {}?",
+            candidates
+                .iter()
+                .map(describe)
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use super::*;
+use hyper::body::HttpBody;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Request `check.url` and assert on the response, through the public tunnel if asked to.
+/// Retries until the response matches or `check.timeout` elapses.
+pub async fn run_check(config: Config, check: CheckCommand) -> Result<(), Error> {
+    let base_url = if check.via_public {
+        let introspect = introspect::start_introspection_server(config.clone());
+        let wormhole = connect_to_wormhole(&config).await?;
+        let public_url = wormhole.public_url.clone();
+        let (restart_tx, _restart_rx) = unbounded();
+        tokio::spawn(serve_wormhole(config.clone(), wormhole, introspect, restart_tx));
+        public_url
+    } else {
+        introspect::local_addr(&config)
+    };
+
+    let url = if check.url.starts_with('/') {
+        format!("{}{}", base_url, check.url)
+    } else {
+        check.url.clone()
+    };
+
+    eprintln!("{} checking {} {}", "=>".green(), check.method, url.yellow());
+
+    let deadline = std::time::Instant::now() + check.timeout;
+    loop {
+        let result = request_once(&check, &url).await;
+        match result {
+            Ok(status) => {
+                eprintln!("{} {}", "Check passed:".green(), status);
+                return Ok(());
+            }
+            Err(e) if std::time::Instant::now() + RETRY_INTERVAL < deadline => {
+                debug!("check not passing yet: {}", e);
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+            Err(e) => return Err(Error::CheckFailed(e)),
+        }
+    }
+}
+
+async fn request_once(check: &CheckCommand, url: &str) -> Result<u16, String> {
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let request = hyper::Request::builder()
+        .method(check.method.as_str())
+        .uri(url)
+        .body(hyper::Body::empty())
+        .map_err(|e| format!("invalid request: {}", e))?;
+
+    let response = client
+        .request(request)
+        .await
+        .map_err(|e| format!("request failed: {}", e))?;
+
+    let (parts, mut body) = response.into_parts();
+    let status = parts.status.as_u16();
+    if status != check.expect_status {
+        return Err(format!(
+            "expected status {}, got {}",
+            check.expect_status, status
+        ));
+    }
+
+    if let Some(expected) = check.expect_body.as_ref() {
+        let mut data = vec![];
+        while let Some(Ok(chunk)) = body.data().await {
+            data.extend_from_slice(&chunk);
+        }
+
+        if !String::from_utf8_lossy(&data).contains(expected.as_str()) {
+            return Err(format!("response body does not contain {:?}", expected));
+        }
+    }
+
+    Ok(status)
+}
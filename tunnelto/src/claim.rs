@@ -0,0 +1,35 @@
+use super::*;
+
+/// Ask the server to verify our ownership of `domain` and claim its sub-domain
+pub async fn claim_domain(config: &Config, domain: String) -> Result<(), Error> {
+    let auth_key = config.secret_key.clone().ok_or(Error::NoAuthenticationKey)?;
+
+    let response: ClaimResponse =
+        api::post(config, "claim", &ClaimRequest { auth_key, domain }).await?;
+
+    match response {
+        ClaimResponse::Verified { sub_domain } => {
+            eprintln!(
+                "{} The sub-domain {} is now claimed by your account.",
+                "Success!".green(),
+                sub_domain.bold().green()
+            );
+        }
+        ClaimResponse::Pending {
+            txt_name,
+            txt_value,
+        } => {
+            eprintln!(
+                "{}\n\n    {} TXT \"{}\"\n\nthen run this command again.",
+                "To verify you own this domain, add the following DNS record:".yellow(),
+                txt_name.bold(),
+                txt_value
+            );
+        }
+        ClaimResponse::Failed { reason } => {
+            eprintln!("{} {}", "Claim failed:".red(), reason);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! Client certificates, for servers that authenticate tunnels by the certificate presented
+//! on the control connection instead of an auth key:
+//! `tunnelto --client-cert client.pem --client-cert-key client.key`, PEM files with the
+//! key in PKCS#8 as `openssl req -x509 -newkey ...` writes them. The server's operator
+//! maps the certificate's SHA-256 fingerprint to your account, get it with
+//! `openssl x509 -noout -fingerprint -sha256 -in client.pem`.
+use super::*;
+use std::path::PathBuf;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::Connector;
+
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl ClientCert {
+    fn identity(&self) -> Result<native_tls::Identity, Error> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|e| Error::ClientCert(format!("{:?}: {}", path, e)))
+        };
+        native_tls::Identity::from_pkcs8(&read(&self.cert)?, &read(&self.key)?)
+            .map_err(|e| Error::ClientCert(e.to_string()))
+    }
+}
+
+/// Connect to the control server, presenting the certificate if there is one
+pub async fn connect(url: &str, cert: Option<&ClientCert>) -> Result<WebSocket, Error> {
+    let cert = match cert {
+        Some(cert) => cert,
+        None => return Ok(tokio_tungstenite::connect_async(url).await?.0),
+    };
+
+    let connector = native_tls::TlsConnector::builder()
+        .identity(cert.identity()?)
+        .build()
+        .map_err(|e| Error::ClientCert(e.to_string()))?;
+
+    let request = url.into_client_request()?;
+    let host = request
+        .uri()
+        .host()
+        .ok_or_else(|| Error::InvalidUrl(url.to_string()))?
+        .to_string();
+    let port = match (request.uri().port_u16(), request.uri().scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("ws")) => 80,
+        (None, _) => 443,
+    };
+    let socket = TcpStream::connect((host.as_str(), port))
+        .await
+        .map_err(tokio_tungstenite::tungstenite::Error::from)?;
+
+    let (websocket, _) = tokio_tungstenite::client_async_tls_with_config(
+        request,
+        socket,
+        None,
+        Some(Connector::NativeTls(connector)),
+    )
+    .await?;
+    Ok(websocket)
+}
@@ -1,16 +1,25 @@
 use structopt::StructOpt;
+use structopt::clap::Shell;
+use crate::alerts::AlertRule;
+use crate::client_cert::ClientCert;
+use tunnelto_lib::acl::AccessRule;
+use tunnelto_lib::rate_limit::RateLimitRule;
+use crate::exec::{ExecCommand, RestartPolicy};
+use crate::openapi::OpenApiSpec;
+use crate::target::TargetPolicy;
+use crate::profile::Profile;
 use super::*;
 
-const HOST_ENV:&'static str = "CTRL_HOST";
-const PORT_ENV:&'static str = "CTRL_PORT";
-const TLS_OFF_ENV:&'static str = "CTRL_TLS_OFF";
+const HOST_ENV:&str = "CTRL_HOST";
+const PORT_ENV:&str = "CTRL_PORT";
+const TLS_OFF_ENV:&str = "CTRL_TLS_OFF";
 
-const DEFAULT_HOST:&'static str = "tunnelto.dev";
-const DEFAULT_CONTROL_HOST:&'static str = "wormhole.tunnelto.dev";
-const DEFAULT_CONTROL_PORT:&'static str = "443";
+const DEFAULT_HOST:&str = "tunnelto.dev";
+const DEFAULT_CONTROL_HOST:&str = "wormhole.tunnelto.dev";
+const DEFAULT_CONTROL_PORT:&str = "443";
 
-const SETTINGS_DIR:&'static str = ".tunnelto";
-const SECRET_KEY_FILE:&'static str = "key.token";
+pub const SETTINGS_DIR:&str = ".tunnelto";
+const SECRET_KEY_FILE:&str = "key.token";
 
 /// Command line arguments
 #[derive(Debug, StructOpt)]
@@ -27,10 +36,26 @@ struct Opts {
     #[structopt(short = "k", long = "key")]
     key: Option<String>,
 
+    /// Authenticate with a JWT from an identity provider the server trusts (i.e. CI OIDC tokens)
+    #[structopt(long = "jwt", env = "TUNNELTO_JWT", hide_env_values = true)]
+    jwt: Option<String>,
+
+    /// Authenticate with this client certificate (PEM) instead of a key, on servers that take them
+    #[structopt(long = "client-cert", env = "TUNNELTO_CLIENT_CERT", requires = "client-cert-key", conflicts_with_all = &["key", "jwt"])]
+    client_cert: Option<std::path::PathBuf>,
+
+    /// The private key of --client-cert (PEM, PKCS#8)
+    #[structopt(long = "client-cert-key", env = "TUNNELTO_CLIENT_CERT_KEY", requires = "client-cert")]
+    client_cert_key: Option<std::path::PathBuf>,
+
     /// Specify a sub-domain for this tunnel
     #[structopt(short = "s", long = "subdomain")]
     sub_domain: Option<String>,
 
+    /// Serve the tunnel on one of the server's public base domains only (requires a key)
+    #[structopt(long = "base-domain")]
+    base_domain: Option<String>,
+
     /// Sets the HOST (i.e. localhost) to forward incoming tunnel traffic to
     #[structopt(long = "host", default_value = "localhost")]
     local_host: String,
@@ -39,13 +64,222 @@ struct Opts {
     #[structopt(long = "scheme", default_value = "http")]
     scheme: String,
 
+    /// Forward TLS connections to the target untouched, routed by SNI (local service must speak TLS)
+    #[structopt(long = "tls-passthrough")]
+    tls_passthrough: bool,
+
+    /// Have the server sign forwarded requests with this secret (see tunnelto_lib::verify)
+    #[structopt(long = "signing-secret")]
+    signing_secret: Option<String>,
+
+    /// Only let these networks reach a path, i.e. `/admin/*=10.0.0.0/8,192.168.0.0/16` (repeatable)
+    #[structopt(long = "acl", number_of_values = 1, parse(try_from_str = str::parse))]
+    acl: Vec<AccessRule>,
+
+    /// Read access rules from a file, one `PATH=CIDR[,CIDR...]` per line
+    #[structopt(long = "acl-file")]
+    acl_file: Option<String>,
+
+    /// Cap visitor requests to a path, i.e. `/api/search=2` or `/*=20:40` for RPS[:BURST] (repeatable, first match wins)
+    #[structopt(long = "rate-limit", number_of_values = 1, parse(try_from_str = str::parse))]
+    rate_limits: Vec<RateLimitRule>,
+
+    /// Add a header to requests forwarded to the local service, i.e. `X-Env: staging` (repeatable)
+    #[structopt(long = "header", number_of_values = 1, parse(try_from_str = parse_header))]
+    headers: Vec<(String, String)>,
+
+    /// Warn when responses cross a threshold, i.e. `5xx>10/min` or `404>50/30s` (repeatable)
+    #[structopt(long = "alert", number_of_values = 1, parse(try_from_str = str::parse))]
+    alerts: Vec<AlertRule>,
+
+    /// Also POST alerts as json to this url
+    #[structopt(long = "alert-webhook")]
+    alert_webhook: Option<String>,
+
+    /// Check requests against this OpenAPI 3 spec (yaml or json), flagging violations in the inspector
+    #[structopt(long = "openapi")]
+    openapi: Option<String>,
+
+    /// Answer requests that break the --openapi spec with a 422 instead of forwarding them
+    #[structopt(long = "openapi-reject", requires = "openapi")]
+    openapi_reject: bool,
+
+    /// Snapshot the first response to each path under this route, i.e. `/api/*`, and flag later
+    /// ones changing its status or json fields (repeatable)
+    #[structopt(long = "snapshot", number_of_values = 1, parse(try_from_str = parse_route))]
+    snapshot_routes: Vec<String>,
+
+    /// Don't start the plugins in ~/.tunnelto/plugins
+    #[structopt(long = "no-plugins")]
+    no_plugins: bool,
+
+    /// What the tunnel carries, so the server can tune for it: general, webhooks,
+    /// streaming or bulk-files
+    #[structopt(long = "traffic-profile", default_value = "general")]
+    traffic_profile: TrafficProfile,
+
+    /// Check every stream arrives intact on both ends of the tunnel, logging any
+    /// truncated or garbled data
+    #[structopt(long = "verify-integrity")]
+    verify_integrity: bool,
+
+    /// Prove your key by signing a challenge from the server instead of sending the key
+    /// itself, so nothing between you and the server can learn it
+    #[structopt(long = "sign-hello", conflicts_with_all = &["jwt", "client-cert"])]
+    sign_hello: bool,
+
+    /// Show the server's access log for your tunnel: visitor ips, and requests the
+    /// server answered itself (denied, rate-limited, failed) that never reached you
+    #[structopt(long = "access-log")]
+    access_log: bool,
+
+    /// Answer errors (tunnel not found, local service unavailable) with json bodies instead of text
+    #[structopt(long = "json-errors")]
+    json_errors: bool,
+
+    /// Forward to another machine reachable from this one, i.e. a NAS at 192.168.1.50:8080
+    #[structopt(long = "target", conflicts_with_all = &["port", "auto", "exec"])]
+    target: Option<String>,
+
+    /// Expose a --target on the local network without asking for confirmation
+    #[structopt(long = "confirm-target")]
+    confirm_target: bool,
+
+    /// Allow a --target outside the local network
+    #[structopt(long = "allow-public-target")]
+    allow_public_target: bool,
+
     /// Sets the port to forward incoming tunnel traffic to on the target host
     #[structopt(short = "p", long = "port")]
     port: Option<String>,
 
+    /// Detect a dev server listening on a common local port and forward to it
+    #[structopt(long = "auto")]
+    auto: bool,
+
+    /// Start the local service with this shell command, wait for its port and supervise it
+    #[structopt(long = "exec")]
+    exec: Option<String>,
+
+    /// Restart the --exec service when it exits: never, on-failure or always
+    #[structopt(long = "restart", default_value = "on-failure")]
+    restart: RestartPolicy,
+
+    /// How long to wait for the --exec service to listen on its port
+    #[structopt(long = "exec-ready-timeout", default_value = "60s", parse(try_from_str = parse_duration))]
+    exec_ready_timeout: Duration,
+
+    /// Retry requests for this long (i.e. 5s) while the local server is restarting
+    #[structopt(long = "grace-local", parse(try_from_str = parse_duration))]
+    grace_local: Option<Duration>,
+
+    /// On Ctrl-C or SIGTERM, wait this long for the requests under way to finish before exiting
+    #[structopt(long = "drain-timeout", default_value = "30s", parse(try_from_str = parse_duration))]
+    drain_timeout: Duration,
+
+    /// Send stream traffic over a second connection so large transfers don't delay the control channel
+    #[structopt(long = "data-connection")]
+    data_connection: bool,
+
+    /// Spread stream traffic over this many data connections, for large downloads on
+    /// high-latency links where a single connection is window-limited (implies --data-connection)
+    #[structopt(long = "data-connections")]
+    data_connections: Option<usize>,
+
+    /// Stand by for a reserved sub-domain, taking over its traffic while the client serving it is down
+    #[structopt(long = "standby", requires = "sub-domain")]
+    standby: bool,
+
+    /// Serve every sub-domain of the base domain that no other tunnel serves, i.e. for a
+    /// wildcard staging router (requires a key allowed to)
+    #[structopt(long = "catch-all", conflicts_with_all = &["sub-domain", "standby", "tls-passthrough"])]
+    catch_all: bool,
+
+    /// Advertise the tunnel's public url to teammates on the local network (see `discover`)
+    #[structopt(long = "advertise")]
+    advertise: bool,
+
+    /// Show desktop notifications when the tunnel connects, drops, fails to authenticate
+    /// or gets its first request
+    #[structopt(long = "notify")]
+    notify: bool,
+
+    /// Periodically log internal counts (streams, queues, open files) to hunt slow leaks
+    #[structopt(long = "soak")]
+    soak: bool,
+
     /// Sets the address of the local introspection dashboard
     #[structopt(long = "dashboard-address")]
     dashboard_address: Option<String>,
+
+    /// Print the effective configuration (after profiles and interpolation) and exit
+    #[structopt(long = "print-config")]
+    print_config: bool,
+
+    /// Hide credentials in --print-config
+    #[structopt(long = "redact", requires = "print-config")]
+    redact: bool,
+}
+
+#[derive(Debug, StructOpt)]
+enum KeysCommand {
+    /// Create a key for a workshop or contractor that stops working after a while
+    Guest {
+        /// How long the key works, i.e. 24h (at most 30 days)
+        #[structopt(long = "expires", parse(try_from_str = parse_duration))]
+        expires: Duration,
+
+        /// Only allow sub-domains starting with this, i.e. `workshop-`
+        #[structopt(long = "subdomain-prefix")]
+        sub_domain_prefix: Option<String>,
+
+        /// Only allow this sub-domain, besides those matching --subdomain-prefix (repeatable)
+        #[structopt(long = "subdomain", number_of_values = 1)]
+        sub_domains: Vec<String>,
+
+        /// Name the key in the server's logs
+        #[structopt(long = "label")]
+        label: Option<String>,
+    },
+
+    /// Add a key to the account, i.e. one per machine so each can be rotated on its own
+    Add {
+        /// Name the key in the server's logs, i.e. `laptop` or `ci`
+        #[structopt(long = "label")]
+        label: String,
+    },
+
+    /// Replace the key with a new one that keeps its label; the old key stops working and
+    /// its tunnels are closed, the account's other keys keep working
+    Rotate,
+}
+
+#[derive(Debug, StructOpt)]
+enum HostsCommand {
+    /// Point a custom domain at the relay in the hosts file until Ctrl-C, to test it end
+    /// to end before changing its DNS (usually needs sudo)
+    Add {
+        /// The custom domain, i.e. api.mycompany.com
+        domain: String,
+
+        /// Point it at this machine instead, for a server running locally
+        #[structopt(long = "dev")]
+        dev: bool,
+
+        /// Change the hosts file without asking
+        #[structopt(long = "yes")]
+        yes: bool,
+    },
+
+    /// Remove the entries tunnelto added, i.e. those left behind by a crash
+    Clean {
+        /// Change the hosts file without asking
+        #[structopt(long = "yes")]
+        yes: bool,
+    },
+
+    /// List the entries tunnelto added
+    List,
 }
 
 #[derive(Debug, StructOpt)]
@@ -56,6 +290,317 @@ enum SubCommand {
         #[structopt(short = "k", long = "key")]
         key: String
     },
+
+    /// Start the tunnel described by a profile file (subdomain, target, headers, auth, acl)
+    Up {
+        /// The profile, values may reference the environment as ${VAR} or ${VAR:-default}
+        #[structopt(short = "f", long = "file", default_value = "tunnel.yaml")]
+        file: String,
+    },
+
+    /// Print a shell completion script, i.e. `tunnelto completions bash > /etc/bash_completion.d/tunnelto`
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+
+    /// Claim the sub-domain matching a domain you own (verified via a DNS TXT record)
+    Claim {
+        /// The domain you own, i.e. acme.com to claim the `acme` sub-domain
+        domain: String,
+    },
+
+    /// Let a teammate's account claim a sub-domain you own for a while
+    Grant {
+        /// The sub-domain to lend out
+        sub_domain: String,
+
+        /// The teammate's account id
+        #[structopt(long = "to")]
+        to: String,
+
+        /// How long the grant lasts, i.e. 48h (at most 30 days)
+        #[structopt(long = "for", parse(try_from_str = parse_duration))]
+        duration: Duration,
+    },
+
+    /// Take back a sub-domain grant, closing the teammate's tunnel on it
+    Revoke {
+        /// The sub-domain that was lent out
+        sub_domain: String,
+
+        /// The teammate's account id
+        #[structopt(long = "to")]
+        to: String,
+    },
+
+    /// Let a teammate's tunnel that the account's policy holds start forwarding traffic
+    Approve {
+        /// The sub-domain of the held tunnel
+        sub_domain: String,
+    },
+
+    /// Manage the account's keys
+    Keys {
+        #[structopt(subcommand)]
+        command: KeysCommand,
+    },
+
+    /// Manage hosts file entries for testing custom domains before changing their DNS
+    Hosts {
+        #[structopt(subcommand)]
+        command: HostsCommand,
+    },
+
+    /// Send a realistic provider webhook (i.e. stripe.checkout.completed) through your tunnel
+    TestWebhook {
+        /// The webhook template to send
+        #[structopt(long = "template")]
+        template: String,
+
+        /// Where to send it, defaults to the public url of `--subdomain`
+        #[structopt(long = "url")]
+        url: Option<String>,
+
+        /// Path on the tunnel to send it to
+        #[structopt(long = "path", default_value = "/")]
+        path: String,
+
+        /// Sign the webhook like the provider would with this secret
+        #[structopt(long = "secret")]
+        secret: Option<String>,
+    },
+
+    /// Replay a scenario file (exported from the inspector) against your local service
+    Scenario {
+        /// The scenario YAML file
+        file: String,
+
+        /// Where to send requests, defaults to the --host/--port/--scheme local service
+        #[structopt(long = "target")]
+        target: Option<String>,
+    },
+
+    /// Watch a running tunnel in the terminal: its public url and live requests, with replay.
+    /// Start the tunnel with --dashboard-address to know where to find it.
+    Ui {
+        /// The running tunnel's --dashboard-address
+        #[structopt(long = "dashboard", default_value = "127.0.0.1:4040")]
+        dashboard: String,
+    },
+
+    /// List tunnels teammates are advertising on the local network with --advertise
+    Discover {
+        /// How long to listen for advertisements
+        #[structopt(long = "timeout", default_value = "3s", parse(try_from_str = parse_duration))]
+        timeout: Duration,
+    },
+
+    /// Open the tunnel, request a url and assert on the response (exits non-zero on failure)
+    Check {
+        /// Path (or full url) to request, i.e. /healthz
+        #[structopt(long = "url", default_value = "/")]
+        url: String,
+
+        /// The request method
+        #[structopt(long = "method", default_value = "GET")]
+        method: String,
+
+        /// The status code the response must have
+        #[structopt(long = "expect-status", default_value = "200")]
+        expect_status: u16,
+
+        /// Text the response body must contain
+        #[structopt(long = "expect-body")]
+        expect_body: Option<String>,
+
+        /// Request through the public tunnel url instead of the local service directly
+        #[structopt(long = "via-public")]
+        via_public: bool,
+
+        /// Keep retrying for this long (i.e. 30s) before failing
+        #[structopt(long = "timeout", default_value = "10s", parse(try_from_str = parse_duration))]
+        timeout: Duration,
+    },
+
+    /// List the visitor connections open on your tunnels
+    Connections,
+
+    /// Terminate a visitor connection on one of your tunnels
+    Kick {
+        /// The connection id, as shown by `connections`
+        id: String,
+    },
+
+    /// List the sub-domains your tunnels used recently, and when and where from
+    History,
+
+    /// Export the transcripts the server keeps of requests to your tunnels (time, visitor
+    /// ip, request line and status, never bodies) as json lines, or turn them on or off.
+    /// Pick a tunnel with --subdomain.
+    Transcripts {
+        /// First day to export, YYYY-MM-DD in UTC, defaults to as far back as they are kept
+        #[structopt(long = "since")]
+        since: Option<String>,
+
+        /// Last day to export, YYYY-MM-DD in UTC, defaults to today
+        #[structopt(long = "until")]
+        until: Option<String>,
+
+        /// Start keeping transcripts of your account's tunnels
+        #[structopt(long = "enable", conflicts_with = "disable")]
+        enable: bool,
+
+        /// Stop keeping transcripts, the ones kept expire as usual
+        #[structopt(long = "disable")]
+        disable: bool,
+
+        /// Write them to this file instead of stdout
+        #[structopt(long = "output", short = "o")]
+        output: Option<String>,
+    },
+
+    /// Diagnose connection problems: control server reachability and clock skew
+    Doctor,
+
+    /// Measure the latency to each relay and connect tunnels to the fastest from now on
+    Ping {
+        /// A relay to measure, as name=host[:port]. Repeat it or separate relays with commas
+        #[structopt(long = "relay", env = "TUNNELTO_RELAYS", use_delimiter = true)]
+        relays: Vec<ping::Relay>,
+    },
+
+    /// Show what's new since the version you last looked at, and which of it the server supports
+    Whatsnew {
+        /// List the features of every release
+        #[structopt(long = "all")]
+        all: bool,
+    },
+
+    /// Sign in with the identity provider the server trusts (OAuth2 device flow) and store
+    /// the token for future use, instead of a key set with `set-auth`
+    Login {
+        /// OpenID issuer to sign in with, defaults to the one the server announces
+        #[structopt(long = "issuer")]
+        issuer: Option<String>,
+
+        /// Client id registered for the device flow at --issuer
+        #[structopt(long = "client-id")]
+        client_id: Option<String>,
+
+        /// Get access tokens for this api instead of id tokens, with --issuer
+        #[structopt(long = "audience")]
+        audience: Option<String>,
+    },
+
+    /// Tell what an error code (i.e. TUN-2003) means and what to do about it, lists the
+    /// codes without one
+    Explain {
+        code: Option<String>,
+    },
+
+    /// Point a running tunnel at another local target, i.e. `localhost:4000`, keeping its url.
+    /// Requests under way finish against the old target. Pick the tunnel with --subdomain
+    /// if several are running.
+    Retarget {
+        /// The new target, HOST:PORT (checked like --target)
+        target: String,
+    },
+
+    /// Send requests carrying a header to another local target on a running tunnel, i.e.
+    /// `--header "X-Preview: v2" localhost:4000`, to test two builds behind one url.
+    /// Lists the routes without arguments.
+    Route {
+        /// The header and value requests must carry, `Name: value`
+        #[structopt(long = "header", parse(try_from_str = parse_header))]
+        header: Option<(String, String)>,
+
+        /// Where matching requests go, HOST:PORT (checked like --target)
+        target: Option<String>,
+
+        /// Stop routing requests carrying --header
+        #[structopt(long = "remove", requires = "header", conflicts_with = "target")]
+        remove: bool,
+    },
+
+    /// List the tunnels running on this machine and where they forward, only the one
+    /// on --subdomain if given
+    Status,
+}
+
+/// A one-off command to run instead of starting a tunnel
+#[derive(Debug, Clone)]
+pub enum Command {
+    Claim { domain: String },
+    /// revokes the grant if `duration` is `None`
+    Grant {
+        sub_domain: String,
+        grantee: String,
+        duration: Option<Duration>,
+    },
+    Approve { sub_domain: String },
+    GuestKey {
+        duration: Duration,
+        sub_domain_prefix: Option<String>,
+        sub_domains: Vec<String>,
+        label: Option<String>,
+    },
+    AddKey { label: String },
+    RotateKey,
+    AddHost { domain: String, dev: bool, yes: bool },
+    CleanHosts { yes: bool },
+    ListHosts,
+    Visitors { kick: Option<String> },
+    History,
+    Transcripts {
+        sub_domain: Option<String>,
+        since: Option<String>,
+        until: Option<String>,
+        /// turns them on or off if set
+        enable: Option<bool>,
+        output: Option<String>,
+    },
+    Doctor,
+    Ping { relays: Vec<ping::Relay> },
+    WhatsNew { all: bool },
+    Login {
+        issuer: Option<String>,
+        client_id: Option<String>,
+        audience: Option<String>,
+    },
+    Retarget {
+        target: String,
+        sub_domain: Option<String>,
+    },
+    Route {
+        header: Option<(String, String)>,
+        target: Option<String>,
+        remove: bool,
+        sub_domain: Option<String>,
+    },
+    Status { sub_domain: Option<String> },
+    TestWebhook {
+        template: String,
+        url: Option<String>,
+        path: String,
+        secret: Option<String>,
+    },
+    Scenario { file: String, target: Option<String> },
+    Check(CheckCommand),
+    Discover { timeout: Duration },
+    Ui { dashboard: String },
+    PrintConfig { redact: bool },
+}
+
+/// A request to make and what its response must look like
+#[derive(Debug, Clone)]
+pub struct CheckCommand {
+    pub url: String,
+    pub method: String,
+    pub expect_status: u16,
+    pub expect_body: Option<String>,
+    pub via_public: bool,
+    pub timeout: Duration,
 }
 
 /// Config
@@ -63,23 +608,60 @@ enum SubCommand {
 pub struct Config {
     pub client_id: ClientId,
     pub control_url: String,
+    pub control_api_url: String,
     pub local_host: String,
     pub scheme: String,
     pub host: String,
     pub local_port: Option<String>,
     pub sub_domain: Option<String>,
+    pub base_domain: Option<String>,
     pub secret_key: Option<SecretKey>,
+    pub jwt: Option<String>,
+    pub client_cert: Option<ClientCert>,
     pub tls_off: bool,
+    pub tls_passthrough: bool,
+    pub signing_secret: Option<String>,
+    pub access_rules: Vec<AccessRule>,
+    pub rate_limits: Vec<RateLimitRule>,
+    /// added to requests forwarded to the local service
+    pub request_headers: Vec<(String, String)>,
+    pub alerts: Vec<AlertRule>,
+    pub alert_webhook: Option<String>,
+    pub openapi: Option<Arc<OpenApiSpec>>,
+    /// answer requests breaking the spec with a 422
+    pub openapi_reject: bool,
+    /// routes whose responses are compared to their first one
+    pub snapshot_routes: Vec<String>,
+    /// start the plugins in ~/.tunnelto/plugins
+    pub plugins: bool,
+    pub error_format: ErrorFormat,
+    pub traffic_profile: TrafficProfile,
+    pub verify_integrity: bool,
+    pub sign_hello: bool,
+    pub access_log: bool,
     pub first_run: bool,
     pub dashboard_address: Option<String>,
+    pub grace_local: Duration,
+    pub drain_timeout: Duration,
     pub verbose: bool,
+    pub soak: bool,
+    pub advertise: bool,
+    pub notify: bool,
+    /// data connections to open, none to keep stream traffic on the control connection
+    pub data_connections: usize,
+    pub standby: bool,
+    pub catch_all: bool,
+    pub exec: Option<ExecCommand>,
+    pub command: Option<Command>,
 }
 
 impl Config {
-    /// Parse the URL to use to connect to the wormhole control server
+    /// Parse the URL to use to connect to the wormhole control server. Errors are printed
+    /// where they happen, so there's nothing more to say about them.
+    #[allow(clippy::result_unit_err)]
     pub fn get() -> Result<Config, ()> {
         // parse the opts
-        let opts: Opts = Opts::from_args();
+        let mut opts: Opts = Opts::from_args();
 
         if opts.verbose {
             std::env::set_var("RUST_LOG", "tunnelto=debug");
@@ -87,6 +669,26 @@ impl Config {
 
         pretty_env_logger::init();
 
+        if let Some(SubCommand::Up { file }) = opts.command.as_ref() {
+            let file = file.clone();
+            opts.command = None;
+            let profile = Profile::load(&file).map_err(|e| {
+                eprintln!("{} {}: {}", "Invalid profile".red(), file, e);
+            })?;
+            apply_profile(&mut opts, profile)?;
+        }
+
+        let print_config = opts.print_config.then_some(Command::PrintConfig { redact: opts.redact });
+
+        // a stored login stands in for the stored key, not for one given
+        let jwt = match (opts.jwt.take(), opts.key.as_ref()) {
+            (Some(jwt), _) => Some(jwt),
+            (None, None) => crate::login::stored_token(),
+            (None, Some(_)) => None,
+        };
+
+        let mut command = None;
+        let mut local_host = opts.local_host.clone();
         let (secret_key, sub_domain, local_port) = match opts.command {
             Some(SubCommand::SetAuth { key }) => {
                 let key = opts.key.unwrap_or(key);
@@ -98,67 +700,375 @@ impl Config {
                 };
                 std::fs::create_dir_all(&settings_dir).expect("Fail to create file in home directory");
                 std::fs::write(settings_dir.join(SECRET_KEY_FILE), key).expect("Failed to save authentication key file.");
+                crate::login::forget();
 
                 eprintln!("Authentication key stored successfully!");
                 std::process::exit(0);
             },
+            Some(SubCommand::Completions { shell }) => {
+                Opts::clap().gen_completions_to("tunnelto", shell, &mut std::io::stdout());
+                std::process::exit(0);
+            },
+            Some(SubCommand::Explain { code }) => {
+                let known = crate::error::explain(code.as_deref());
+                std::process::exit(if known { 0 } else { 1 });
+            },
+            // replaced by the profile's settings above
+            Some(SubCommand::Up { .. }) => unreachable!(),
+            Some(SubCommand::Claim { domain }) => {
+                command = Some(Command::Claim { domain });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Grant { sub_domain, to, duration }) => {
+                command = Some(Command::Grant { sub_domain, grantee: to, duration: Some(duration) });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Revoke { sub_domain, to }) => {
+                command = Some(Command::Grant { sub_domain, grantee: to, duration: None });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Approve { sub_domain }) => {
+                command = Some(Command::Approve { sub_domain });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Keys { command: KeysCommand::Guest { expires, sub_domain_prefix, sub_domains, label } }) => {
+                command = Some(Command::GuestKey { duration: expires, sub_domain_prefix, sub_domains, label });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Keys { command: KeysCommand::Add { label } }) => {
+                command = Some(Command::AddKey { label });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Keys { command: KeysCommand::Rotate }) => {
+                command = Some(Command::RotateKey);
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Hosts { command: HostsCommand::Add { domain, dev, yes } }) => {
+                command = Some(Command::AddHost { domain, dev, yes });
+                (None, None, None)
+            },
+            Some(SubCommand::Hosts { command: HostsCommand::Clean { yes } }) => {
+                command = Some(Command::CleanHosts { yes });
+                (None, None, None)
+            },
+            Some(SubCommand::Hosts { command: HostsCommand::List }) => {
+                command = Some(Command::ListHosts);
+                (None, None, None)
+            },
+            Some(SubCommand::TestWebhook { template, url, path, secret }) => {
+                command = Some(Command::TestWebhook { template, url, path, secret });
+                (opts.key.or_else(read_secret_key_file), opts.sub_domain, None)
+            },
+            Some(SubCommand::Scenario { file, target }) => {
+                command = Some(Command::Scenario { file, target });
+                (None, None, opts.port)
+            },
+            Some(SubCommand::Check { url, method, expect_status, expect_body, via_public, timeout }) => {
+                command = Some(Command::Check(CheckCommand { url, method, expect_status, expect_body, via_public, timeout }));
+                (opts.key.or_else(read_secret_key_file), opts.sub_domain, opts.port)
+            },
+            Some(SubCommand::Discover { timeout }) => {
+                command = Some(Command::Discover { timeout });
+                (None, None, None)
+            },
+            Some(SubCommand::Ui { dashboard }) => {
+                command = Some(Command::Ui { dashboard });
+                (None, None, None)
+            },
+            Some(SubCommand::Connections) => {
+                command = Some(Command::Visitors { kick: None });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Kick { id }) => {
+                command = Some(Command::Visitors { kick: Some(id) });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::History) => {
+                command = Some(Command::History);
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Transcripts { since, until, enable, disable, output }) => {
+                let enable = match (enable, disable) {
+                    (true, _) => Some(true),
+                    (_, true) => Some(false),
+                    _ => None,
+                };
+                command = Some(Command::Transcripts { sub_domain: opts.sub_domain, since, until, enable, output });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Login { issuer, client_id, audience }) => {
+                command = Some(Command::Login { issuer, client_id, audience });
+                (opts.key.or_else(read_secret_key_file), None, None)
+            },
+            Some(SubCommand::Doctor) => {
+                command = Some(Command::Doctor);
+                (None, None, None)
+            },
+            Some(SubCommand::Ping { relays }) => {
+                command = Some(Command::Ping { relays });
+                (None, None, None)
+            },
+            Some(SubCommand::Whatsnew { all }) => {
+                command = Some(Command::WhatsNew { all });
+                (None, None, None)
+            },
+            Some(SubCommand::Retarget { target }) => {
+                let (host, port) = target::parse_target(&target).map_err(|e| {
+                    eprintln!("{} {}", "Error:".red(), e);
+                })?;
+                target::check_target(&host, port, TargetPolicy {
+                    confirmed: opts.confirm_target,
+                    allow_public: opts.allow_public_target,
+                })?;
+
+                command = Some(Command::Retarget { target, sub_domain: opts.sub_domain });
+                (None, None, None)
+            },
+            Some(SubCommand::Route { header, target, remove }) => {
+                if let Some(target) = target.as_ref() {
+                    if header.is_none() {
+                        eprintln!("{} pass the --header requests must carry to go to {}", "Error:".red(), target);
+                        return Err(());
+                    }
+                    let (host, port) = target::parse_target(target).map_err(|e| {
+                        eprintln!("{} {}", "Error:".red(), e);
+                    })?;
+                    target::check_target(&host, port, TargetPolicy {
+                        confirmed: opts.confirm_target,
+                        allow_public: opts.allow_public_target,
+                    })?;
+                }
+
+                command = Some(Command::Route { header, target, remove, sub_domain: opts.sub_domain });
+                (None, None, None)
+            },
+            Some(SubCommand::Status) => {
+                command = Some(Command::Status { sub_domain: opts.sub_domain });
+                (None, None, None)
+            },
+            None if opts.target.is_some() => {
+                let target = opts.target.unwrap_or_default();
+                let (host, port) = target::parse_target(&target).map_err(|e| {
+                    eprintln!("{} {}", "Error:".red(), e);
+                })?;
+                target::check_target(&host, port, TargetPolicy {
+                    confirmed: opts.confirm_target,
+                    allow_public: opts.allow_public_target,
+                })?;
+
+                local_host = host;
+                (opts.key.or_else(read_secret_key_file), opts.sub_domain, Some(port.to_string()))
+            },
             None => {
                 let key = opts.key;
                 let sub_domain = opts.sub_domain;
-                let port = opts.port;
-
-                (match key {
-                    Some(key) => Some(key),
-                    None => {
-                        dirs::home_dir()
-                            .map(|h| h.join(SETTINGS_DIR).join(SECRET_KEY_FILE))
-                            .map(|path| {
-                                if path.exists() {
-                                    std::fs::read_to_string(path)
-                                        .map_err(|e| error!("Error reading authentication token: {:?}", e))
-                                        .ok()
-                                } else {
-                                    None
-                                }
-                            })
-                            .unwrap_or(None)
+                let port = match opts.port {
+                    None if opts.auto => {
+                        Some(autodetect::detect_local_port(&opts.local_host).ok_or(())?.to_string())
                     }
-                }, sub_domain, port)
+                    port => port,
+                };
+
+                // an --exec service isn't listening until we start it
+                match port.as_ref().and_then(|p| p.parse().ok()) {
+                    Some(port) if opts.exec.is_none() => autodetect::warn_if_not_listening(&opts.local_host, port),
+                    _ => {}
+                }
+
+                (key.or_else(read_secret_key_file), sub_domain, port)
             }
         };
 
+        let mut access_rules = opts.acl;
+        if let Some(path) = opts.acl_file.as_ref() {
+            access_rules.extend(read_acl_file(path)?);
+        }
+        if !access_rules.is_empty() && opts.tls_passthrough {
+            eprintln!("{}", "Access rules can't be enforced on TLS passthrough tunnels, ignoring them.".yellow());
+            access_rules.clear();
+        }
+
+        let mut rate_limits = opts.rate_limits;
+        if !rate_limits.is_empty() && opts.tls_passthrough {
+            eprintln!("{}", "Rate limits can't be enforced on TLS passthrough tunnels, ignoring them.".yellow());
+            rate_limits.clear();
+        }
+        if rate_limits.len() > tunnelto_lib::rate_limit::MAX_RULES {
+            eprintln!("{} at most {} rate limits are allowed", "Error:".red(), tunnelto_lib::rate_limit::MAX_RULES);
+            return Err(());
+        }
+
+        let openapi = match opts.openapi.as_ref() {
+            Some(_) if opts.tls_passthrough => {
+                eprintln!("{}", "Requests can't be validated on TLS passthrough tunnels, ignoring --openapi.".yellow());
+                None
+            },
+            Some(path) => {
+                let spec = OpenApiSpec::load(path).map_err(|e| {
+                    eprintln!("{} failed to read the OpenAPI spec {}: {}", "Error:".red(), path, e);
+                })?;
+                Some(Arc::new(spec))
+            },
+            None => None,
+        };
+
+        let mut snapshot_routes = opts.snapshot_routes;
+        if !snapshot_routes.is_empty() && opts.tls_passthrough {
+            eprintln!("{}", "Responses can't be snapshotted on TLS passthrough tunnels, ignoring --snapshot.".yellow());
+            snapshot_routes.clear();
+        }
+
+        if print_config.is_some() {
+            command = print_config;
+        }
+
+        let client_cert = match (opts.client_cert, opts.client_cert_key) {
+            (Some(cert), Some(key)) => Some(ClientCert { cert, key }),
+            _ => None,
+        };
+        let authenticated = secret_key.is_some() || jwt.is_some() || client_cert.is_some();
+
+        // a standby waits for a sub-domain the account reserved
+        if opts.standby && !authenticated {
+            eprintln!("{} --standby needs an authentication key", "Error:".red());
+            return Err(());
+        }
+        if opts.catch_all && !authenticated {
+            eprintln!("{} --catch-all needs an authentication key", "Error:".red());
+            return Err(());
+        }
+
+        let data_connections = opts
+            .data_connections
+            .unwrap_or(opts.data_connection as usize);
+        if data_connections > tunnelto_lib::MAX_DATA_CONNECTIONS {
+            eprintln!("{} at most {} data connections are allowed", "Error:".red(), tunnelto_lib::MAX_DATA_CONNECTIONS);
+            return Err(());
+        }
+
+        let (restart, ready_timeout) = (opts.restart, opts.exec_ready_timeout);
+
         // get the host url
         let tls_off = env::var(TLS_OFF_ENV).is_ok();
         let host = env::var(HOST_ENV)
-            .unwrap_or(format!("{}", DEFAULT_HOST));
+            .unwrap_or(DEFAULT_HOST.to_string());
+
+        // the relay `tunnelto ping` found fastest, unless the control server is set
+        let relay = match env::var(HOST_ENV) {
+            Ok(_) => None,
+            Err(_) => ping::stored_relay(),
+        };
+        if let Some(relay) = relay.as_ref() {
+            info!("using relay {} ({}ms when last pinged)", relay.relay.name, relay.echo_ms);
+        }
 
         let control_host = env::var(HOST_ENV)
-            .unwrap_or(format!("{}", DEFAULT_CONTROL_HOST));
+            .ok()
+            .or_else(|| relay.as_ref().map(|r| r.relay.host.clone()))
+            .unwrap_or(DEFAULT_CONTROL_HOST.to_string());
 
         let port = env::var(PORT_ENV)
-            .unwrap_or(format!("{}", DEFAULT_CONTROL_PORT));
+            .ok()
+            .or_else(|| relay.as_ref().map(|r| r.relay.port.to_string()))
+            .unwrap_or(DEFAULT_CONTROL_PORT.to_string());
 
         let scheme = if tls_off { "ws" } else { "wss" };
         let control_url = format!("{}://{}:{}/wormhole", scheme, control_host, port);
+        let control_api_url = format!("{}://{}:{}", if tls_off { "http" } else { "https" }, control_host, port);
 
         info!("Control Server URL: {}", &control_url);
 
         Ok(Config {
             client_id: ClientId::generate(),
-            local_host: opts.local_host,
+            local_host,
             scheme: opts.scheme,
             control_url,
+            control_api_url,
             host,
             local_port,
             sub_domain,
+            base_domain: opts.base_domain,
             dashboard_address: opts.dashboard_address,
+            grace_local: opts.grace_local.unwrap_or_default(),
+            drain_timeout: opts.drain_timeout,
             verbose: opts.verbose,
-            secret_key: secret_key.map(|s| SecretKey(s)),
+            soak: opts.soak,
+            advertise: opts.advertise,
+            notify: opts.notify,
+            data_connections,
+            standby: opts.standby,
+            catch_all: opts.catch_all,
+            exec: opts.exec.map(|command| ExecCommand {
+                command,
+                restart,
+                ready_timeout,
+            }),
+            secret_key: secret_key.map(SecretKey),
+            jwt,
+            client_cert,
             tls_off,
+            tls_passthrough: opts.tls_passthrough,
+            signing_secret: opts.signing_secret,
+            access_rules,
+            rate_limits,
+            request_headers: opts.headers,
+            alerts: opts.alerts,
+            alert_webhook: opts.alert_webhook,
+            openapi,
+            openapi_reject: opts.openapi_reject,
+            snapshot_routes,
+            plugins: !opts.no_plugins && !opts.tls_passthrough,
+            error_format: if opts.json_errors { ErrorFormat::Json } else { ErrorFormat::Text },
+            traffic_profile: opts.traffic_profile,
+            verify_integrity: opts.verify_integrity,
+            sign_hello: opts.sign_hello,
+            access_log: opts.access_log,
             first_run: true,
+            command,
         })
     }
 
+    /// Print the settings a tunnel would start with, hiding credentials if `redact` is set
+    pub fn print(&self, redact: bool) {
+        let secret = |value: Option<&String>| match value {
+            Some(_) if redact => "<redacted>".to_string(),
+            value => format!("{:?}", value),
+        };
+
+        println!("control_url: {}", self.control_url);
+        println!("local_addr: {}", introspect::local_addr(self));
+        println!("sub_domain: {:?}", self.sub_domain);
+        println!("base_domain: {:?}", self.base_domain);
+        println!("key: {}", secret(self.secret_key.as_ref().map(|k| &k.0)));
+        println!("jwt: {}", secret(self.jwt.as_ref()));
+        println!("client_cert: {:?}", self.client_cert);
+        println!("signing_secret: {}", secret(self.signing_secret.as_ref()));
+        println!("tls_passthrough: {}", self.tls_passthrough);
+        println!("access_rules: {:?}", self.access_rules.iter().map(ToString::to_string).collect::<Vec<_>>());
+        println!("rate_limits: {:?}", self.rate_limits.iter().map(ToString::to_string).collect::<Vec<_>>());
+        println!("request_headers: {:?}", self.request_headers);
+        println!("alerts: {:?}", self.alerts.iter().map(ToString::to_string).collect::<Vec<_>>());
+        println!("alert_webhook: {}", secret(self.alert_webhook.as_ref()));
+        println!("openapi: {:?}", self.openapi.as_ref().map(|spec| &spec.source));
+        println!("openapi_reject: {}", self.openapi_reject);
+        println!("snapshot_routes: {:?}", self.snapshot_routes);
+        println!("plugins: {}", self.plugins);
+        println!("error_format: {:?}", self.error_format);
+        println!("traffic_profile: {:?}", self.traffic_profile);
+        println!("verify_integrity: {}", self.verify_integrity);
+        println!("sign_hello: {}", self.sign_hello);
+        println!("access_log: {}", self.access_log);
+        println!("exec: {:?}", self.exec);
+        println!("grace_local: {:?}", self.grace_local);
+        println!("drain_timeout: {:?}", self.drain_timeout);
+        println!("data_connections: {}", self.data_connections);
+        println!("standby: {}", self.standby);
+        println!("catch_all: {}", self.catch_all);
+        println!("advertise: {}", self.advertise);
+        println!("notify: {}", self.notify);
+        println!("dashboard_address: {:?}", self.dashboard_address);
+    }
+
     pub fn activation_url(&self, server_chosen_sub_domain: &str) -> String {
         format!("{}://{}",
                   if self.tls_off { "http" } else { "https" },
@@ -168,6 +1078,128 @@ impl Config {
     pub fn activation_host(&self, server_chosen_sub_domain: &str) -> String {
         format!("{}.{}",
                 &server_chosen_sub_domain,
-                &self.host)
+                self.base_domain.as_ref().unwrap_or(&self.host))
+    }
+}
+
+/// Fill in what the command line left unset from a profile
+fn apply_profile(opts: &mut Opts, profile: Profile) -> Result<(), ()> {
+    opts.sub_domain = opts.sub_domain.take().or(profile.subdomain);
+    opts.base_domain = opts.base_domain.take().or(profile.base_domain);
+    opts.port = opts.port.take().or(profile.port.map(|p| p.to_string()));
+    opts.target = opts.target.take().or(profile.target);
+    opts.exec = opts.exec.take().or(profile.exec);
+    opts.json_errors |= profile.json_errors;
+
+    // `${VAR:-}` leaves a credential empty when the variable isn't set
+    let auth = profile.auth;
+    let non_empty = |s: &String| !s.is_empty();
+    opts.key = opts.key.take().or(auth.key.filter(non_empty));
+    opts.jwt = opts.jwt.take().or(auth.jwt.filter(non_empty));
+    opts.signing_secret = opts.signing_secret.take().or(auth.signing_secret.filter(non_empty));
+
+    // these have defaults, so the profile wins
+    if let Some(host) = profile.host {
+        opts.local_host = host;
+    }
+    if let Some(scheme) = profile.scheme {
+        opts.scheme = scheme;
+    }
+
+    opts.headers.extend(profile.headers);
+    for rule in profile.acl {
+        // validated when the profile was loaded
+        opts.acl.push(rule.parse().map_err(|_| ())?);
+    }
+    for rule in profile.rate_limits {
+        opts.rate_limits.push(rule.parse().map_err(|_| ())?);
+    }
+
+    Ok(())
+}
+
+/// Parse a `Name: value` header
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected `Name: value`, got `{}`", s))?;
+    let (name, value) = (name.trim(), value.trim());
+
+    warp::http::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name `{}`", name))?;
+    warp::http::header::HeaderValue::from_str(value)
+        .map_err(|_| format!("invalid value for header `{}`", name))?;
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn parse_route(s: &str) -> Result<String, String> {
+    let route = s.trim();
+    if !route.starts_with('/') {
+        return Err(format!("route must start with `/`: `{}`", route));
+    }
+    Ok(route.to_string())
+}
+
+fn read_acl_file(path: &str) -> Result<Vec<AccessRule>, ()> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        eprintln!("{} {}: {}", "Failed to read access rules file".red(), path, e);
+    })?;
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse().map_err(|e| {
+                eprintln!("{} {}: {}", "Invalid access rule in".red(), path, e);
+            })
+        })
+        .collect()
+}
+
+fn read_secret_key_file() -> Option<String> {
+    dirs::home_dir()
+        .map(|h| h.join(SETTINGS_DIR).join(SECRET_KEY_FILE))
+        .map(|path| {
+            if path.exists() {
+                std::fs::read_to_string(path)
+                    .map_err(|e| error!("Error reading authentication token: {:?}", e))
+                    .ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(None)
+}
+
+/// Store `new` in place of the stored key if that is `old`, i.e. after rotating it
+pub fn replace_secret_key_file(old: &SecretKey, new: &SecretKey) -> bool {
+    let path = match dirs::home_dir().map(|h| h.join(SETTINGS_DIR).join(SECRET_KEY_FILE)) {
+        Some(path) => path,
+        None => return false,
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(stored) if stored.trim() == old.0 => std::fs::write(&path, &new.0)
+            .map_err(|e| error!("Error saving authentication token: {:?}", e))
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// Parse durations like `500ms`, `5s`, `2m` (bare numbers are seconds)
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value.parse().map_err(|_| format!("invalid duration: {}", s))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 60 * 60)),
+        _ => Err(format!("invalid duration unit: {}", unit)),
     }
 }
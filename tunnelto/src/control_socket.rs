@@ -0,0 +1,402 @@
+//! A socket every running tunnel listens on so commands like `tunnelto retarget` can
+//! reach it: a unix socket, `~/.tunnelto/sockets/<pid>.sock`, or on windows the named
+//! pipe `\\.\pipe\tunnelto-<pid>`, found through `~/.tunnelto/sockets/<pid>.pipe`.
+//! Each connection carries one json `ControlRequest` line and gets one
+//! `ControlResponse` line back.
+use super::*;
+use crate::introspect::HeaderRoute;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+const SOCKETS_DIR: &str = "sockets";
+
+#[cfg(not(windows))]
+const SOCKET_EXTENSION: &str = "sock";
+#[cfg(windows)]
+const SOCKET_EXTENSION: &str = "pipe";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    /// forward new requests to `HOST:PORT`, keeping the tunnel's scheme
+    Retarget {
+        target: String,
+    },
+    /// forward new requests carrying `header: value` to `HOST:PORT` instead
+    Route {
+        header: String,
+        value: String,
+        target: String,
+    },
+    /// stop routing requests carrying `header: value`
+    Unroute {
+        header: String,
+        value: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Status {
+        public_url: Option<String>,
+        local_addr: String,
+        #[serde(default)]
+        routes: Vec<HeaderRoute>,
+    },
+    Retargeted {
+        from: String,
+        to: String,
+        /// requests still being answered, by the old target
+        draining: usize,
+    },
+    /// the routes after the change
+    Routes {
+        routes: Vec<HeaderRoute>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// A running tunnel, as it answered `ControlRequest::Status`
+pub struct RunningTunnel {
+    pub socket: PathBuf,
+    pub public_url: String,
+    pub local_addr: String,
+    pub routes: Vec<HeaderRoute>,
+}
+
+fn sockets_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(SETTINGS_DIR).join(SOCKETS_DIR))
+}
+
+/// The sockets of the tunnels that may be running, some of them stale
+pub fn sockets() -> Vec<PathBuf> {
+    let entries = match sockets_dir().map(std::fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return vec![],
+    };
+
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == SOCKET_EXTENSION))
+        .collect()
+}
+
+/// The running tunnels, only the one on `sub_domain` if given
+pub async fn running(sub_domain: Option<&str>) -> Vec<RunningTunnel> {
+    let mut running = vec![];
+    for socket in sockets() {
+        match send(&socket, &ControlRequest::Status).await {
+            Ok(ControlResponse::Status {
+                public_url,
+                local_addr,
+                routes,
+            }) => running.push(RunningTunnel {
+                socket,
+                public_url: public_url.unwrap_or_else(|| "(connecting)".to_string()),
+                local_addr,
+                routes,
+            }),
+            Ok(_) => {}
+            Err(e) => debug!("skipping tunnel: {}", e),
+        }
+    }
+
+    if let Some(sub_domain) = sub_domain {
+        let host = format!("://{}.", sub_domain);
+        running.retain(|tunnel| tunnel.public_url.contains(&host));
+    }
+    running
+}
+
+/// The tunnel on `sub_domain` if given, otherwise the only one running
+pub async fn find(sub_domain: Option<&str>) -> Result<RunningTunnel, Error> {
+    let mut running = running(sub_domain).await;
+    match running.len() {
+        0 => Err(Error::ControlSocket(match sub_domain {
+            Some(sub_domain) => format!("no running tunnel serves {}", sub_domain),
+            None => "no running tunnel found".to_string(),
+        })),
+        1 => Ok(running.remove(0)),
+        _ => {
+            let urls = running
+                .iter()
+                .map(|tunnel| tunnel.public_url.as_str())
+                .collect::<Vec<_>>();
+            Err(Error::ControlSocket(format!(
+                "several tunnels are running ({}), pick one with --subdomain",
+                urls.join(", ")
+            )))
+        }
+    }
+}
+
+fn handle(config: &Config, request: ControlRequest) -> ControlResponse {
+    match request {
+        ControlRequest::Status => ControlResponse::Status {
+            public_url: introspect::public_url(),
+            local_addr: introspect::current_local_addr(),
+            routes: introspect::routes(),
+        },
+        ControlRequest::Retarget { .. } | ControlRequest::Route { .. }
+            if config.tls_passthrough =>
+        {
+            ControlResponse::Failed {
+                reason: "TLS passthrough tunnels can't be retargeted".to_string(),
+            }
+        }
+        ControlRequest::Retarget { target } => {
+            if let Err(reason) = target::parse_target(&target) {
+                return ControlResponse::Failed { reason };
+            }
+
+            let to = format!("{}://{}", config.scheme, target);
+            let draining = introspect::in_flight();
+            let from = introspect::retarget(to.clone());
+            eprintln!(
+                "{} {} (was {}, {} requests draining)",
+                "Now forwarding to".green(),
+                to.bold(),
+                from,
+                draining
+            );
+            ControlResponse::Retargeted { from, to, draining }
+        }
+        ControlRequest::Route {
+            header,
+            value,
+            target,
+        } => {
+            if let Err(reason) = target::parse_target(&target) {
+                return ControlResponse::Failed { reason };
+            }
+
+            let route = HeaderRoute {
+                header,
+                value,
+                target: format!("{}://{}", config.scheme, target),
+            };
+            eprintln!(
+                "{} {}: {} to {}",
+                "Routing requests with".green(),
+                route.header,
+                route.value,
+                route.target.bold()
+            );
+            ControlResponse::Routes {
+                routes: introspect::add_route(route),
+            }
+        }
+        ControlRequest::Unroute { header, value } => {
+            eprintln!("{} {}: {}", "Stopped routing".yellow(), header, value);
+            ControlResponse::Routes {
+                routes: introspect::remove_route(&header, &value),
+            }
+        }
+    }
+}
+
+/// Answer the one command a connection carries
+async fn serve<S>(config: Config, stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut line = String::new();
+    if BufReader::new(reader).read_line(&mut line).await.is_err() {
+        return;
+    }
+
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => handle(&config, request),
+        Err(e) => ControlResponse::Failed {
+            reason: format!("invalid request: {}", e),
+        },
+    };
+    let mut response = serde_json::to_vec(&response).unwrap_or_default();
+    response.push(b'\n');
+    let _ = writer.write_all(&response).await;
+}
+
+/// Send one command and read the line answering it
+async fn exchange<S>(stream: S, request: &ControlRequest) -> std::io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut request = serde_json::to_vec(request).unwrap_or_default();
+    request.push(b'\n');
+    writer.write_all(&request).await?;
+
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    Ok(line)
+}
+
+/// Where this process's socket goes
+fn socket_path() -> Option<PathBuf> {
+    let dir = sockets_dir()?;
+    let _ = std::fs::create_dir_all(&dir);
+    Some(dir.join(format!("{}.{}", std::process::id(), SOCKET_EXTENSION)))
+}
+
+/// Listen for commands, replacing a stale socket left by an earlier process with our pid
+#[cfg(unix)]
+pub fn spawn(config: &Config) {
+    let path = match socket_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let _ = std::fs::remove_file(&path);
+
+    let listener = match tokio::net::UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!(
+                "failed to open the control socket {}: {}",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+    debug!("control socket: {}", path.display());
+
+    let config = config.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(config.clone(), stream));
+                }
+                Err(e) => {
+                    warn!("control socket failed: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// The pipe behind a socket file, named after the pid the file is
+#[cfg(windows)]
+fn pipe_name(path: &Path) -> String {
+    let pid = path.file_stem().unwrap_or_default().to_string_lossy();
+    format!(r"\\.\pipe\tunnelto-{}", pid)
+}
+
+/// Listen for commands on a named pipe. Pipes live outside the file system, so an
+/// empty file in the sockets directory stands in for it for `sockets()` to find.
+#[cfg(windows)]
+pub fn spawn(config: &Config) {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let path = match socket_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let name = pipe_name(&path);
+    let create = |first: bool| {
+        ServerOptions::new()
+            .first_pipe_instance(first)
+            .reject_remote_clients(true)
+            .create(&name)
+    };
+
+    let mut server = match create(true) {
+        Ok(server) => server,
+        Err(e) => {
+            warn!("failed to open the control pipe {}: {}", name, e);
+            return;
+        }
+    };
+    if let Err(e) = std::fs::write(&path, b"") {
+        warn!(
+            "failed to open the control socket {}: {}",
+            path.display(),
+            e
+        );
+        return;
+    }
+    debug!("control socket: {} ({})", path.display(), name);
+
+    let config = config.clone();
+    tokio::spawn(async move {
+        loop {
+            // the next instance must exist before this one is handed off, or clients
+            // connecting in between find no pipe
+            let next = match server.connect().await.and_then(|_| create(false)) {
+                Ok(next) => next,
+                Err(e) => {
+                    warn!("control socket failed: {}", e);
+                    return;
+                }
+            };
+            let stream = std::mem::replace(&mut server, next);
+            tokio::spawn(serve(config.clone(), stream));
+        }
+    });
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn spawn(_config: &Config) {}
+
+#[cfg(unix)]
+async fn connect(path: &Path) -> std::io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+/// Open the pipe behind `path`, waiting while every instance of it is busy
+#[cfg(windows)]
+async fn connect(path: &Path) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+
+    const ERROR_PIPE_BUSY: i32 = 231;
+    const BUSY_RETRIES: usize = 20;
+
+    let name = pipe_name(path);
+    let mut retries = 0;
+    loop {
+        match ClientOptions::new().open(&name) {
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) && retries < BUSY_RETRIES => {
+                retries += 1;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Send a command to the tunnel listening on `path`. A socket nothing listens on
+/// anymore is removed.
+#[cfg(any(unix, windows))]
+pub async fn send(path: &Path, request: &ControlRequest) -> Result<ControlResponse, Error> {
+    use std::io::ErrorKind;
+
+    let failed = |e: std::io::Error| Error::ControlSocket(format!("{}: {}", path.display(), e));
+
+    let stream = match connect(path).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            // refused on unix, no such pipe on windows
+            if matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::NotFound) {
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(failed(e));
+        }
+    };
+
+    let line = exchange(stream, request).await.map_err(failed)?;
+    serde_json::from_str(&line).map_err(|_| Error::MalformedMessageFromServer)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub async fn send(_path: &Path, _request: &ControlRequest) -> Result<ControlResponse, Error> {
+    Err(Error::ControlSocket(
+        "control sockets need a unix or windows system".to_string(),
+    ))
+}
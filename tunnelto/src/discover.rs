@@ -0,0 +1,128 @@
+use super::*;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::sync::Mutex as StdMutex;
+use std::time::Instant;
+
+/// mDNS service type tunnels are advertised under
+const SERVICE_TYPE: &str = "_tunnelto._tcp.local.";
+const URL_PROPERTY: &str = "url";
+const USER_PROPERTY: &str = "user";
+
+struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+    url: String,
+}
+
+lazy_static::lazy_static! {
+    static ref ADVERTISEMENT: StdMutex<Option<Advertisement>> = StdMutex::new(None);
+}
+
+fn user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+/// Announce the tunnel's public url on the local network, replacing an earlier
+/// announcement if the url changed after a reconnect
+pub fn advertise(public_url: &str) {
+    // the instance name is the sub-domain, unique among tunnels
+    let sub_domain = public_url
+        .split("://")
+        .last()
+        .and_then(|host| host.split('.').next())
+        .unwrap_or("tunnelto");
+
+    let mut current = ADVERTISEMENT.lock().unwrap();
+    if current.as_ref().map(|a| a.url.as_str()) == Some(public_url) {
+        return;
+    }
+
+    if let Some(previous) = current.take() {
+        let _ = previous.daemon.unregister(&previous.fullname);
+        let _ = previous.daemon.shutdown();
+    }
+
+    let mut properties = HashMap::new();
+    properties.insert(URL_PROPERTY.to_string(), public_url.to_string());
+    properties.insert(USER_PROPERTY.to_string(), user());
+
+    let port = if public_url.starts_with("https") { 443 } else { 80 };
+    let result = ServiceInfo::new(
+        SERVICE_TYPE,
+        sub_domain,
+        &format!("{}.local.", sub_domain),
+        (),
+        port,
+        properties,
+    )
+    .map(ServiceInfo::enable_addr_auto)
+    .and_then(|info| {
+        let daemon = ServiceDaemon::new()?;
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info)?;
+        Ok((daemon, fullname))
+    });
+
+    match result {
+        Ok((daemon, fullname)) => {
+            info!("advertising {} on the local network as {}", public_url, fullname);
+            *current = Some(Advertisement {
+                daemon,
+                fullname,
+                url: public_url.to_string(),
+            });
+        }
+        Err(e) => warn!("failed to advertise tunnel on the local network: {:?}", e),
+    }
+}
+
+/// List the tunnels teammates are advertising on the local network
+pub async fn discover(timeout: Duration) -> Result<(), Error> {
+    let daemon = ServiceDaemon::new().map_err(|e| Error::Discovery(e.to_string()))?;
+    let events = daemon
+        .browse(SERVICE_TYPE)
+        .map_err(|e| Error::Discovery(e.to_string()))?;
+
+    eprintln!("Looking for tunnels on the local network...");
+
+    let mut found: HashMap<String, (String, String)> = HashMap::new();
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let event = match tokio::time::timeout(remaining, events.recv_async()).await {
+            Ok(Ok(event)) => event,
+            _ => break,
+        };
+
+        if let ServiceEvent::ServiceResolved(info) = event {
+            let url = match info.get_property_val_str(URL_PROPERTY) {
+                Some(url) => url.to_string(),
+                None => continue,
+            };
+            let user = info
+                .get_property_val_str(USER_PROPERTY)
+                .unwrap_or_default()
+                .to_string();
+            found.insert(info.get_fullname().to_string(), (url, user));
+        }
+    }
+    let _ = daemon.shutdown();
+
+    if found.is_empty() {
+        eprintln!("{}", "No tunnels found.".yellow());
+        return Ok(());
+    }
+
+    let mut found = found.into_values().collect::<Vec<_>>();
+    found.sort();
+    for (url, user) in found {
+        if user.is_empty() {
+            println!("{}", url.bold().green());
+        } else {
+            println!("{}\t{}", url.bold().green(), user);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use super::*;
+
+/// Skew below this is normal NTP drift and not worth mentioning
+const CLOCK_SKEW_NOTICE: i64 = 5;
+/// Skew the server tolerates by default (CLOCK_SKEW_SECS), past it keys and reconnect
+/// tokens near expiry are rejected
+const CLOCK_SKEW_LIMIT: i64 = 60;
+
+/// Look for the usual reasons tunnels fail to connect or authenticate:
+/// an unreachable control server and a badly skewed clock
+pub async fn doctor(config: &Config) -> Result<(), Error> {
+    eprintln!("{} checking {}", "=>".green(), config.control_api_url.yellow());
+
+    let mut failed = 0;
+    match server_time(config).await {
+        Ok((latency, server_time)) => {
+            eprintln!(
+                "{} control server reachable ({}ms)",
+                "ok".green(),
+                latency.num_milliseconds()
+            );
+
+            // the server stamped its reply somewhere during the round trip
+            let local_time = chrono::Utc::now() - latency / 2;
+            let skew = (local_time - server_time).num_seconds();
+            let ahead_or_behind = if skew > 0 { "ahead of" } else { "behind" };
+            match skew.abs() {
+                s if s <= CLOCK_SKEW_NOTICE => {
+                    eprintln!("{} clock in sync with the server", "ok".green())
+                }
+                s if s <= CLOCK_SKEW_LIMIT => eprintln!(
+                    "{} clock is {}s {} the server, within what it tolerates",
+                    "warn".yellow(),
+                    s,
+                    ahead_or_behind
+                ),
+                s => {
+                    failed += 1;
+                    eprintln!(
+                        "{} clock is {}s {} the server: keys and reconnects near expiry will be rejected, sync your clock (i.e. enable NTP)",
+                        "fail".red(),
+                        s,
+                        ahead_or_behind
+                    );
+                }
+            }
+        }
+        Err(e) => {
+            failed += 1;
+            eprintln!("{} control server unreachable: {}", "fail".red(), e);
+        }
+    }
+
+    match api::get::<CapabilitiesResponse>(config, "capabilities").await {
+        Ok(response) => print_capabilities(&response),
+        // older servers don't announce them
+        Err(e) => eprintln!("{} server capabilities unknown: {}", "warn".yellow(), e),
+    }
+
+    if failed > 0 {
+        return Err(Error::DoctorFailed(failed));
+    }
+    eprintln!("{}", "No problems found.".green());
+    Ok(())
+}
+
+fn print_capabilities(response: &CapabilitiesResponse) {
+    let capabilities = &response.capabilities;
+    let supported = |yes: bool| if yes { "yes".green() } else { "no".dimmed() };
+
+    eprintln!("{} server capabilities:", "=>".green());
+    eprintln!("   tcp tunnels:      {}", supported(capabilities.tcp_tunnels));
+    eprintln!("   tls passthrough:  {}", supported(capabilities.tls_passthrough));
+    eprintln!(
+        "   compression:      {}",
+        if capabilities.compression.is_empty() {
+            "no".dimmed()
+        } else {
+            capabilities.compression.join(", ").green()
+        }
+    );
+    eprintln!(
+        "   quic:             {}",
+        match capabilities.quic_endpoint.as_ref() {
+            Some(endpoint) => endpoint.green(),
+            None => "no".dimmed(),
+        }
+    );
+    eprintln!("   inspector relay:  {}", supported(capabilities.inspector_relay));
+    debug!("server features: {:?}", response.features);
+
+    for deprecation in response.deprecations.iter() {
+        eprintln!("{} deprecated {}", "warn".yellow(), deprecation);
+    }
+}
+
+/// Round trip time to the control server and its clock, from the `Date` header
+async fn server_time(
+    config: &Config,
+) -> Result<(chrono::Duration, chrono::DateTime<chrono::Utc>), String> {
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let url = format!("{}/health_check", config.control_api_url)
+        .parse()
+        .map_err(|e| format!("invalid url: {}", e))?;
+
+    let sent_at = chrono::Utc::now();
+    let response = client
+        .get(url)
+        .await
+        .map_err(|e| format!("request failed: {}", e))?;
+    let latency = chrono::Utc::now() - sent_at;
+
+    if !response.status().is_success() {
+        return Err(format!("health check returned {}", response.status()));
+    }
+
+    let date = response
+        .headers()
+        .get(hyper::header::DATE)
+        .and_then(|date| date.to_str().ok())
+        .ok_or_else(|| "the server did not send its time".to_string())?;
+    let server_time = chrono::DateTime::parse_from_rfc2822(date)
+        .map_err(|e| format!("invalid server time {:?}: {}", date, e))?;
+
+    Ok((latency, server_time.with_timezone(&chrono::Utc)))
+}
@@ -1,22 +1,21 @@
+use colored::Colorize;
 use thiserror::Error;
+use tunnelto_lib::{ErrorCode, TunnelError};
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Failed to connect to control server: {0}.")]
-    WebSocketError(#[from] tokio_tungstenite::tungstenite::error::Error),
+    WebSocketError(Box<tokio_tungstenite::tungstenite::error::Error>),
 
-    #[error("Server denied the connection. Please check your authentication key.")]
-    AuthenticationFailed,
+    #[error("{0}")]
+    Tunnel(#[from] TunnelError),
+
+    #[error("An authentication key is required, set one with `tunnelto set-auth`.")]
+    NoAuthenticationKey,
 
     #[error("Server sent a malformed message.")]
     MalformedMessageFromServer,
 
-    #[error("Invalid sub-domain specified.")]
-    InvalidSubDomain,
-
-    #[error("Cannot use this sub-domain, it is already taken.")]
-    SubDomainInUse,
-
     #[error("The server responded with an invalid response.")]
     ServerReplyInvalid,
 
@@ -25,4 +24,115 @@ pub enum Error {
 
     #[error("The server timed out sending us something.")]
     Timeout,
+
+    #[error("Invalid url: {0}")]
+    InvalidUrl(String),
+
+    #[error("Request failed: {0}")]
+    RequestFailed(String),
+
+    #[error("Invalid scenario: {0}")]
+    InvalidScenario(String),
+
+    #[error("{0} scenario steps failed.")]
+    ScenarioFailed(usize),
+
+    #[error("{0} doctor checks failed.")]
+    DoctorFailed(usize),
+
+    #[error("Check failed: {0}")]
+    CheckFailed(String),
+
+    #[error("Local service failed: {0}")]
+    ExecFailed(String),
+
+    #[error("Local network discovery failed: {0}")]
+    Discovery(String),
+
+    #[error("Terminal error: {0}")]
+    Terminal(String),
+
+    #[error("Can't reach the running tunnel: {0}")]
+    ControlSocket(String),
+
+    #[error("Login failed: {0}")]
+    Login(String),
+
+    #[error("Failed to write {0}: {1}")]
+    Output(String, String),
+
+    #[error("Can't update the hosts file: {0}")]
+    HostsFile(String),
+
+    #[error("Can't use the client certificate: {0}")]
+    ClientCert(String),
+}
+
+impl From<tokio_tungstenite::tungstenite::error::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::error::Error) -> Self {
+        Error::WebSocketError(Box::new(e))
+    }
+}
+
+impl Error {
+    /// The stable code of the error, for those `tunnelto explain` knows about
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::WebSocketError(_) => Some(ErrorCode::ControlUnreachable),
+            Error::Tunnel(e) => Some(e.code()),
+            Error::NoAuthenticationKey => Some(ErrorCode::NoAuthenticationKey),
+            Error::MalformedMessageFromServer | Error::ServerReplyInvalid => {
+                Some(ErrorCode::MalformedServerMessage)
+            }
+            Error::NoResponseFromServer | Error::Timeout => Some(ErrorCode::ControlTimeout),
+            _ => None,
+        }
+    }
+
+    /// Print the error, with its code and where to learn more about it
+    pub fn report(&self) {
+        match self.code() {
+            Some(code) => {
+                eprintln!(
+                    "Error: {} {}",
+                    format!("{}", self).red(),
+                    format!("[{}]", code).dimmed()
+                );
+                eprintln!("Run `tunnelto explain {}` for what to do about it.", code);
+            }
+            None => eprintln!("Error: {}", format!("{}", self).red()),
+        }
+    }
+}
+
+/// `tunnelto explain [CODE]`: what an error code means and what to do about it, every
+/// code without one. False if the code is unknown.
+pub fn explain(query: Option<&str>) -> bool {
+    let query = match query {
+        Some(query) => query,
+        None => {
+            for code in ErrorCode::ALL {
+                eprintln!("{}  {}", code.code().bold(), code.summary());
+            }
+            return true;
+        }
+    };
+
+    match ErrorCode::lookup(query) {
+        Some(code) => {
+            eprintln!("{} {}", code.code().bold(), code.summary().bold());
+            eprintln!("{} {}", "reason:".dimmed(), code.reason());
+            eprintln!();
+            eprintln!("{}", code.help());
+            true
+        }
+        None => {
+            eprintln!(
+                "{} unknown error code `{}`, run `tunnelto explain` to list them",
+                "Error:".red(),
+                query
+            );
+            false
+        }
+    }
 }
@@ -0,0 +1,175 @@
+use super::*;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Instant;
+use tokio::process::{Child, Command};
+
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Restart delays double while the service keeps crashing shortly after starting
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+const STABLE_RUNTIME: Duration = Duration::from_secs(10);
+
+/// When to restart the local service after it exits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(format!(
+                "invalid restart policy: {} (expected never, on-failure or always)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecCommand {
+    /// shell command line that starts the local service
+    pub command: String,
+    pub restart: RestartPolicy,
+    /// how long to wait for the local port to start listening
+    pub ready_timeout: Duration,
+}
+
+fn spawn_child(command: &str) -> Result<Child, Error> {
+    let mut child = if cfg!(windows) {
+        let mut child = Command::new("cmd");
+        child.args(["/C", command]);
+        child
+    } else {
+        let mut child = Command::new("sh");
+        child.args(["-c", command]);
+        child
+    };
+
+    child
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::ExecFailed(format!("could not start `{}`: {}", command, e)))
+}
+
+/// The port the local service should listen on, with the same defaults as `local_addr`
+fn local_port(config: &Config) -> u16 {
+    match config.local_port.as_ref().and_then(|p| p.parse().ok()) {
+        Some(port) => port,
+        None if config.scheme == "https" => 443,
+        None => 8000,
+    }
+}
+
+/// Start the local service and wait until it listens, then supervise it in the background
+pub async fn start(config: &Config, exec: ExecCommand) -> Result<(), Error> {
+    let host = config.local_host.clone();
+    let port = local_port(config);
+
+    eprintln!("{} {}", "Starting local service:".green(), exec.command.yellow());
+    let mut child = spawn_child(&exec.command)?;
+
+    let deadline = Instant::now() + exec.ready_timeout;
+    loop {
+        if autodetect::is_listening(&host, port) {
+            break;
+        }
+
+        let exited = child
+            .try_wait()
+            .map_err(|e| Error::ExecFailed(format!("could not check `{}`: {}", exec.command, e)))?;
+        if let Some(status) = exited {
+            return Err(Error::ExecFailed(format!(
+                "`{}` exited before listening on {}:{} ({})",
+                exec.command, host, port, status
+            )));
+        }
+
+        if Instant::now() > deadline {
+            return Err(Error::ExecFailed(format!(
+                "`{}` did not listen on {}:{} within {:?}",
+                exec.command, host, port, exec.ready_timeout
+            )));
+        }
+
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+
+    tokio::spawn(supervise(child, exec));
+    Ok(())
+}
+
+/// Restart the service per its policy, and tear it down when we are interrupted
+async fn supervise(mut child: Child, exec: ExecCommand) {
+    let mut restart_delay = MIN_RESTART_DELAY;
+
+    loop {
+        let started = Instant::now();
+
+        let status = tokio::select! {
+            status = child.wait() => status,
+            // keep serving the requests under way until the tunnel drained
+            _ = shutdown::drained() => {
+                eprintln!("{}", "Stopping local service...".yellow());
+                let _ = child.kill().await;
+                std::process::exit(0);
+            }
+        };
+
+        let failed = match &status {
+            Ok(status) => !status.success(),
+            Err(_) => true,
+        };
+        eprintln!(
+            "{} `{}` exited: {}",
+            "Local service stopped:".red(),
+            exec.command,
+            match status {
+                Ok(status) => status.to_string(),
+                Err(e) => e.to_string(),
+            }
+        );
+
+        let restart = match exec.restart {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        };
+        if !restart {
+            exit_on_shutdown().await;
+        }
+
+        if started.elapsed() > STABLE_RUNTIME {
+            restart_delay = MIN_RESTART_DELAY;
+        }
+        eprintln!("Restarting local service in {:?}...", restart_delay);
+        tokio::select! {
+            _ = tokio::time::sleep(restart_delay) => {}
+            _ = exit_on_shutdown() => {}
+        }
+        restart_delay = std::cmp::min(restart_delay * 2, MAX_RESTART_DELAY);
+
+        child = match spawn_child(&exec.command) {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("Error: {}", format!("{}", e).red());
+                exit_on_shutdown().await;
+            }
+        };
+    }
+}
+
+/// The shutdown task leaves exiting to us while we supervise the local service
+async fn exit_on_shutdown() -> ! {
+    shutdown::drained().await;
+    std::process::exit(0);
+}
@@ -0,0 +1,52 @@
+use super::*;
+
+/// Let `grantee` claim a sub-domain we own until the grant runs out, or revoke
+/// the grant if there's no `duration`
+pub async fn grant(
+    config: &Config,
+    sub_domain: String,
+    grantee: String,
+    duration: Option<Duration>,
+) -> Result<(), Error> {
+    let auth_key = config.secret_key.clone().ok_or(Error::NoAuthenticationKey)?;
+
+    let request = GrantRequest {
+        auth_key,
+        sub_domain,
+        grantee,
+        duration_secs: duration.map(|d| d.as_secs().max(1)),
+    };
+    let response: GrantResponse = api::post(config, "grant", &request).await?;
+
+    match response {
+        GrantResponse::Granted {
+            sub_domain,
+            grantee,
+            expires_at,
+        } => {
+            eprintln!(
+                "{} {} may claim {} until {}.",
+                "Success!".green(),
+                grantee,
+                sub_domain.bold().green(),
+                expires_at
+            );
+        }
+        GrantResponse::Revoked {
+            sub_domain,
+            grantee,
+        } => {
+            eprintln!(
+                "{} {} may no longer claim {}.",
+                "Success!".green(),
+                grantee,
+                sub_domain.bold()
+            );
+        }
+        GrantResponse::Failed { reason } => {
+            eprintln!("{} {}", "Grant failed:".red(), reason);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use super::*;
+
+/// How long ago a connection was, i.e. `3h ago`
+fn ago(at: &chrono::DateTime<chrono::Local>) -> String {
+    let secs = (chrono::Local::now() - *at).num_seconds().max(0);
+    match secs {
+        s if s < 60 => "just now".to_string(),
+        s if s < 60 * 60 => format!("{}m ago", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h ago", s / (60 * 60)),
+        s => format!("{}d ago", s / (24 * 60 * 60)),
+    }
+}
+
+/// List the sub-domains our tunnels used recently
+pub async fn history(config: &Config) -> Result<(), Error> {
+    let auth_key = config
+        .secret_key
+        .clone()
+        .ok_or(Error::NoAuthenticationKey)?;
+
+    let response: HistoryResponse =
+        api::post(config, "history", &HistoryRequest { auth_key }).await?;
+
+    match response {
+        HistoryResponse::Entries { entries } if entries.is_empty() => {
+            eprintln!("No sub-domains used recently.");
+        }
+        HistoryResponse::Entries { entries } => {
+            println!(
+                "{:<32} {:<28} CLIENT HOST",
+                "SUB-DOMAIN", "LAST CONNECTED"
+            );
+            for entry in entries {
+                let last_connected =
+                    match chrono::DateTime::parse_from_rfc3339(&entry.last_connected_at) {
+                        Ok(at) => {
+                            let at = at.with_timezone(&chrono::Local);
+                            format!("{} ({})", at.format("%Y-%m-%d %H:%M"), ago(&at))
+                        }
+                        Err(_) => entry.last_connected_at,
+                    };
+                println!(
+                    "{:<32} {:<28} {}",
+                    entry.sub_domain,
+                    last_connected,
+                    entry.client_hostname.unwrap_or_else(|| "-".to_string())
+                );
+            }
+        }
+        HistoryResponse::Failed { reason } => {
+            eprintln!("{} {}", "Failed:".red(), reason);
+        }
+    }
+
+    Ok(())
+}
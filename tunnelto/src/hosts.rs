@@ -0,0 +1,187 @@
+//! Hosts file entries for testing a custom domain end to end before its DNS points at
+//! the relay: `tunnelto hosts add api.mycompany.com` points the domain at the relay (or
+//! at this machine with `--dev`, for a server running locally) until stopped with
+//! Ctrl-C. Entries are tagged, so only those tunnelto added are ever changed, and
+//! `tunnelto hosts clean` removes any left behind.
+//!
+//! The hosts file is usually only writable by root or an administrator, the path can
+//! be changed with TUNNELTO_HOSTS_FILE.
+use super::*;
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+const HOSTS_FILE_ENV: &str = "TUNNELTO_HOSTS_FILE";
+
+/// Ends every line tunnelto adds
+const MARKER: &str = "# added by tunnelto";
+
+fn hosts_path() -> PathBuf {
+    if let Ok(path) = env::var(HOSTS_FILE_ENV) {
+        return path.into();
+    }
+    if cfg!(windows) {
+        PathBuf::from(r"C:\Windows\System32\drivers\etc\hosts")
+    } else {
+        PathBuf::from("/etc/hosts")
+    }
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    std::fs::read_to_string(path).map_err(|e| Error::HostsFile(format!("{:?}: {}", path, e)))
+}
+
+fn write(path: &Path, lines: &[String]) -> Result<(), Error> {
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    std::fs::write(path, contents).map_err(|e| match e.kind() {
+        std::io::ErrorKind::PermissionDenied if cfg!(windows) => Error::HostsFile(format!(
+            "{:?} isn't writable, run tunnelto as an administrator",
+            path
+        )),
+        std::io::ErrorKind::PermissionDenied => {
+            Error::HostsFile(format!("{:?} isn't writable, run tunnelto with sudo", path))
+        }
+        _ => Error::HostsFile(format!("{:?}: {}", path, e)),
+    })
+}
+
+fn is_ours(line: &str) -> bool {
+    line.trim_end().ends_with(MARKER)
+}
+
+/// Whether the line maps `domain` to an address
+fn names(line: &str, domain: &str) -> bool {
+    let entry = line.split('#').next().unwrap_or_default();
+    entry
+        .split_whitespace()
+        .skip(1)
+        .any(|name| name.eq_ignore_ascii_case(domain))
+}
+
+fn confirm(question: &str) -> Result<bool, Error> {
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::HostsFile(
+            "pass --yes to change the hosts file without a prompt".into(),
+        ));
+    }
+
+    eprint!("{} [y/N]: ", question);
+    let _ = std::io::stderr().flush();
+
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| Error::HostsFile(e.to_string()))?;
+    Ok(matches!(line.trim(), "y" | "Y" | "yes"))
+}
+
+/// The relay's address, where visitors of a tunnel end up
+async fn relay_address(config: &Config) -> Result<IpAddr, Error> {
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((config.host.as_str(), 443))
+        .await
+        .map_err(|e| Error::HostsFile(format!("can't resolve the relay {}: {}", config.host, e)))?
+        .map(|addr| addr.ip())
+        .collect();
+
+    // hosts files take either, but v4 works on more networks
+    addrs
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addrs.first())
+        .copied()
+        .ok_or_else(|| Error::HostsFile(format!("the relay {} has no address", config.host)))
+}
+
+/// Point `domain` at the relay, or at this machine with `dev`, until Ctrl-C
+pub async fn add(config: &Config, domain: String, dev: bool, yes: bool) -> Result<(), Error> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    if domain.is_empty() || domain.contains(|c: char| c.is_whitespace() || c == '#') {
+        return Err(Error::HostsFile(format!("invalid domain {:?}", domain)));
+    }
+
+    let ip = if dev {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    } else {
+        relay_address(config).await?
+    };
+    let entry = format!("{} {} {}", ip, domain, MARKER);
+
+    let path = hosts_path();
+    let mut lines: Vec<String> = read(&path)?
+        .lines()
+        .filter(|line| !(is_ours(line) && names(line, &domain)))
+        .map(String::from)
+        .collect();
+
+    // the first entry for a name wins, and ours goes last
+    if let Some(existing) = lines.iter().find(|line| names(line, &domain)) {
+        eprintln!(
+            "{} {:?} already has `{}`, which takes precedence",
+            "Warning:".yellow(),
+            path,
+            existing.trim()
+        );
+    }
+
+    if !yes && !confirm(&format!("Add `{} {}` to {:?}?", ip, domain, path))? {
+        return Ok(());
+    }
+    lines.push(entry);
+    write(&path, &lines)?;
+
+    eprintln!(
+        "{} {} now points at {}, press Ctrl-C to remove it.",
+        "Success!".green(),
+        domain.bold(),
+        ip
+    );
+    let _ = tokio::signal::ctrl_c().await;
+
+    let lines: Vec<String> = read(&path)?
+        .lines()
+        .filter(|line| !(is_ours(line) && names(line, &domain)))
+        .map(String::from)
+        .collect();
+    write(&path, &lines)?;
+    eprintln!("Removed {} from {:?}.", domain, path);
+    Ok(())
+}
+
+/// Remove every entry tunnelto added, i.e. those left behind by a crash
+pub fn clean(yes: bool) -> Result<(), Error> {
+    let path = hosts_path();
+    let contents = read(&path)?;
+    let ours = contents.lines().filter(|line| is_ours(line)).count();
+    if ours == 0 {
+        eprintln!("No entries added by tunnelto in {:?}.", path);
+        return Ok(());
+    }
+
+    if !yes && !confirm(&format!("Remove {} entries from {:?}?", ours, path))? {
+        return Ok(());
+    }
+    let lines: Vec<String> = contents
+        .lines()
+        .filter(|line| !is_ours(line))
+        .map(String::from)
+        .collect();
+    write(&path, &lines)?;
+    eprintln!("Removed {} entries from {:?}.", ours, path);
+    Ok(())
+}
+
+/// Print the entries tunnelto added
+pub fn list() -> Result<(), Error> {
+    let path = hosts_path();
+    let contents = read(&path)?;
+    let mut ours = contents.lines().filter(|line| is_ours(line)).peekable();
+    if ours.peek().is_none() {
+        eprintln!("No entries added by tunnelto in {:?}.", path);
+    }
+    for line in ours {
+        println!("{}", line.trim_end_matches(MARKER).trim_end());
+    }
+    Ok(())
+}
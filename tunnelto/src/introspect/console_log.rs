@@ -13,6 +13,7 @@ pub fn connect_failed() {
 pub struct Log {
     method: String,
     path: String,
+    request_id: Option<String>,
 }
 
 lazy_static::lazy_static! {
@@ -43,7 +44,11 @@ pub fn log_incoming(stream_id: StreamId, data: Vec<u8>) {
         }
     };
 
-    LOGS.write().unwrap().insert(stream_id, Log { method: method.to_string(), path: path.to_string() });
+    let request_id = req.headers.iter()
+        .find(|h| h.name.eq_ignore_ascii_case(tunnelto_lib::verify::REQUEST_ID_HEADER))
+        .map(|h| String::from_utf8_lossy(h.value).to_string());
+
+    LOGS.write().unwrap().insert(stream_id, Log { method: method.to_string(), path: path.to_string(), request_id });
 }
 
 pub fn log_outgoing(stream_id: StreamId, data: Vec<u8>) {
@@ -75,6 +80,10 @@ pub fn log_outgoing(stream_id: StreamId, data: Vec<u8>) {
 
     eprint!("{}", out);
 
-    eprintln!("\t\t{}\t{}", log.method.to_uppercase().yellow(), log.path.blue());
+    eprint!("\t\t{}\t{}", log.method.to_uppercase().yellow(), log.path.blue());
+    match log.request_id.as_ref() {
+        Some(request_id) => eprintln!("\t{}", request_id.dimmed()),
+        None => eprintln!(),
+    }
     logs.remove(&stream_id);
 }
@@ -0,0 +1,194 @@
+//! The client's own stages of the forwarding path, see `tunnelto_lib::middleware`
+use super::*;
+use futures::FutureExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+pub use tunnelto_lib::middleware::*;
+
+/// requests being answered by the local service
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// How many requests the local service is answering, i.e. still draining after a retarget
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+/// Counts a request in `IN_FLIGHT` until dropped
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The terminal stage: sends the request to the local server
+pub struct LocalService {
+    pub client: HttpClient,
+    pub grace_local: Duration,
+}
+
+impl Endpoint for LocalService {
+    fn call(&self, request: ProxyRequest) -> MiddlewareResult<'_> {
+        self.forward(request).boxed()
+    }
+}
+
+impl LocalService {
+    async fn forward(&self, request: ProxyRequest) -> Result<ProxyResponse, ForwardError> {
+        let _in_flight = InFlight::start();
+        // read once: a retarget only moves requests that haven't started
+        let local_addr = local_addr_for(&request.headers);
+        let url = format!("{}{}", local_addr, request.path_and_query());
+        log::debug!("forwarding to: {}", &url);
+
+        let uri = url.parse::<hyper::Uri>().map_err(|e| {
+            log::error!("invalid incoming url: {}, error: {:?}", url, e);
+            ForwardError::InvalidURL
+        })?;
+
+        // the local server may be restarting: retry refused connections within the grace period
+        let retry_until = std::time::Instant::now() + self.grace_local;
+        let response = loop {
+            let mut local_request = hyper::Request::builder()
+                .method(request.method.clone())
+                .version(hyper::Version::HTTP_11)
+                .uri(uri.clone());
+
+            for (header_name, value) in request.headers.iter() {
+                local_request = local_request.header(header_name, value)
+            }
+
+            let local_request = local_request
+                .body(hyper::Body::from(request.body.clone()))
+                .map_err(|e| {
+                    log::error!("failed to build request: {:?}", e);
+                    ForwardError::InvalidRequest
+                })?;
+
+            match self.client.request(local_request).await {
+                Ok(response) => break response,
+                Err(e) if e.is_connect() && std::time::Instant::now() < retry_until => {
+                    log::debug!("local server unavailable, retrying: {:?}", e);
+                    tokio::time::sleep(LOCAL_RETRY_INTERVAL).await;
+                }
+                Err(e) => {
+                    log::error!("local server error: {:?}", e);
+                    return Err(ForwardError::LocalServerError);
+                }
+            }
+        };
+
+        let (parts, mut body) = response.into_parts();
+
+        let mut response_data = vec![];
+        while let Some(next) = body.data().await {
+            let chunk = next.map_err(|e| {
+                log::error!("error reading local response: {:?}", e);
+                ForwardError::LocalServerError
+            })?;
+
+            response_data.extend_from_slice(&chunk);
+        }
+
+        Ok(ProxyResponse::new(
+            parts.status,
+            parts.headers,
+            response_data,
+        ))
+    }
+}
+
+/// Adds configured headers to requests, replacing any the visitor sent
+pub struct AddHeaders(pub Vec<(HeaderName, HeaderValue)>);
+
+impl Middleware for AddHeaders {
+    fn handle<'a>(&'a self, mut request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a> {
+        for (name, value) in &self.0 {
+            request.headers.insert(name.clone(), value.clone());
+        }
+        next.run(request)
+    }
+}
+
+/// What the client's stages found out about an exchange, kept in the response's
+/// extensions for `RecordRequests` to show in the inspector
+#[derive(Debug, Clone, Default)]
+pub struct Findings {
+    /// how the request broke the OpenAPI spec
+    pub violations: Vec<String>,
+    /// how the response departs from its snapshot
+    pub contract_changes: Vec<String>,
+}
+
+impl Findings {
+    pub fn of(response: &mut ProxyResponse) -> &mut Findings {
+        if response.extensions.get::<Findings>().is_none() {
+            response.extensions.insert(Findings::default());
+        }
+        response.extensions.get_mut::<Findings>().unwrap()
+    }
+}
+
+/// Stores each exchange for the inspector
+pub struct RecordRequests;
+
+impl Middleware for RecordRequests {
+    fn handle<'a>(&'a self, request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a> {
+        async move {
+            let recorded = request.clone();
+            let response = next.run(request).await?;
+            let findings = response
+                .extensions
+                .get::<Findings>()
+                .cloned()
+                .unwrap_or_default();
+
+            let stored_request = Request {
+                id: Uuid::new_v4().to_string(),
+                request_id: recorded.request_id(),
+                status: response.status.as_u16(),
+                path: recorded.path,
+                query: recorded.query,
+                method: recorded.method,
+                headers: header_map(&recorded.headers),
+                body_data: recorded.body,
+                response_headers: header_map(&response.headers),
+                response_data: response.body.clone(),
+                started: recorded.started,
+                completed: chrono::Utc::now().naive_utc(),
+                is_replay: false,
+                violations: findings.violations,
+                contract_changes: findings.contract_changes,
+            };
+
+            REQUESTS
+                .write()
+                .unwrap()
+                .insert(stored_request.id.clone(), stored_request);
+
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+pub fn header_map(headers: &HeaderMap) -> HashMap<String, Vec<String>> {
+    let mut map = HashMap::new();
+    headers.keys().for_each(|k| {
+        let values = headers
+            .get_all(k)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(|s| s.to_owned())
+            .collect();
+        map.insert(k.as_str().to_owned(), values);
+    });
+    map
+}
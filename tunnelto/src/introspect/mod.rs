@@ -1,5 +1,10 @@
 pub mod console_log;
 pub use self::console_log::*;
+pub mod middleware;
+pub use self::middleware::*;
+pub mod scenario;
+pub mod share;
+pub mod snapshot;
 use super::*;
 use bytes::Buf;
 use futures::{Stream, StreamExt};
@@ -9,7 +14,9 @@ use hyper_tls::HttpsConnector;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use warp::http::header::{HeaderName, HeaderValue};
 use warp::http::HeaderMap;
 use warp::http::Method;
 use warp::path::FullPath;
@@ -17,9 +24,13 @@ use warp::Filter;
 
 type HttpClient = hyper::Client<HttpsConnector<HttpConnector>>;
 
+const LOCAL_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
 #[derive(Debug, Clone)]
 pub struct Request {
     id: String,
+    /// the id the edge assigned, to correlate with server logs and error pages
+    request_id: Option<String>,
     status: u16,
     is_replay: bool,
     path: String,
@@ -31,6 +42,10 @@ pub struct Request {
     response_data: Vec<u8>,
     started: chrono::NaiveDateTime,
     completed: chrono::NaiveDateTime,
+    /// how the request broke the OpenAPI spec, if one was given
+    violations: Vec<String>,
+    /// how the response departs from its snapshot, if its route has one
+    contract_changes: Vec<String>,
 }
 
 impl Request {
@@ -56,6 +71,130 @@ impl Request {
 
 lazy_static::lazy_static! {
     pub static ref REQUESTS:Arc<RwLock<HashMap<String, Request>>> = Arc::new(RwLock::new(HashMap::new()));
+    static ref PUBLIC_URL: RwLock<Option<String>> = RwLock::new(None);
+    static ref LOCAL_ADDR: RwLock<String> = RwLock::new(String::new());
+    static ref ROUTES: RwLock<Vec<HeaderRoute>> = RwLock::new(vec![]);
+}
+
+/// Requests carrying `header: value` go to `target` instead of the local service, i.e. to
+/// test a second build side by side behind the same public url
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderRoute {
+    pub header: String,
+    pub value: String,
+    /// i.e. http://localhost:4000
+    pub target: String,
+}
+
+impl HeaderRoute {
+    fn is_for(&self, header: &str, value: &str) -> bool {
+        self.header.eq_ignore_ascii_case(header) && self.value == value
+    }
+
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all(self.header.as_str())
+            .iter()
+            .any(|value| value.to_str().is_ok_and(|value| value.trim() == self.value))
+    }
+}
+
+/// Remember the tunnel's current public url for `/api/status`
+pub fn set_public_url(url: &str) {
+    *PUBLIC_URL.write().unwrap() = Some(url.to_string());
+}
+
+pub fn public_url() -> Option<String> {
+    PUBLIC_URL.read().unwrap().clone()
+}
+
+/// The local service requests are forwarded to now, see `retarget`
+pub fn current_local_addr() -> String {
+    LOCAL_ADDR.read().unwrap().clone()
+}
+
+/// Forward new requests to `local_addr`, letting those under way finish against the
+/// old address. Returns the old address.
+pub fn retarget(local_addr: String) -> String {
+    std::mem::replace(&mut *LOCAL_ADDR.write().unwrap(), local_addr)
+}
+
+/// Where a request with these headers goes: the first matching route's target, or the
+/// local service
+pub fn local_addr_for(headers: &HeaderMap) -> String {
+    ROUTES
+        .read()
+        .unwrap()
+        .iter()
+        .find(|route| route.matches(headers))
+        .map(|route| route.target.clone())
+        .unwrap_or_else(current_local_addr)
+}
+
+pub fn routes() -> Vec<HeaderRoute> {
+    ROUTES.read().unwrap().clone()
+}
+
+/// Add a route, replacing the one for the same header and value. Returns the routes.
+pub fn add_route(route: HeaderRoute) -> Vec<HeaderRoute> {
+    let mut routes = ROUTES.write().unwrap();
+    match routes
+        .iter_mut()
+        .find(|existing| existing.is_for(&route.header, &route.value))
+    {
+        Some(existing) => *existing = route,
+        None => routes.push(route),
+    }
+    routes.clone()
+}
+
+/// Drop the route for a header and value. Returns the routes left.
+pub fn remove_route(header: &str, value: &str) -> Vec<HeaderRoute> {
+    let mut routes = ROUTES.write().unwrap();
+    routes.retain(|route| !route.is_for(header, value));
+    routes.clone()
+}
+
+/// A recorded request, as listed by `/api/requests`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestSummary {
+    pub id: String,
+    pub request_id: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub elapsed: String,
+    /// when the response was sent, `HH:MM:SS` UTC
+    pub completed: String,
+    pub is_replay: bool,
+    #[serde(default)]
+    pub violations: Vec<String>,
+    #[serde(default)]
+    pub contract_changes: Vec<String>,
+}
+
+impl From<&Request> for RequestSummary {
+    fn from(request: &Request) -> Self {
+        RequestSummary {
+            id: request.id.clone(),
+            request_id: request.request_id.clone(),
+            method: request.method.to_string(),
+            path: request.path_and_query(),
+            status: request.status,
+            elapsed: request.elapsed(),
+            completed: request.completed.format("%H:%M:%S").to_string(),
+            is_replay: request.is_replay,
+            violations: request.violations.clone(),
+            contract_changes: request.contract_changes.clone(),
+        }
+    }
+}
+
+/// The tunnel an inspector belongs to, as reported by `/api/status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InspectorStatus {
+    pub public_url: Option<String>,
+    pub local_addr: String,
 }
 
 #[derive(Debug, Clone)]
@@ -64,21 +203,63 @@ pub struct IntrospectionAddrs {
     pub web_explorer_address: SocketAddr,
 }
 
-#[derive(Debug)]
-pub enum ForwardError {
-    IncomingRead,
-    InvalidURL,
-    InvalidRequest,
-    LocalServerError,
+/// A `ForwardError` warp can reject with
+struct Rejected(ForwardError);
+impl warp::reject::Reject for Rejected {}
+
+impl std::fmt::Debug for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
 }
-impl warp::reject::Reject for ForwardError {}
 
-pub fn start_introspection_server(config: Config) -> IntrospectionAddrs {
+/// Answer the visitor in the tunnel's error format, echoing the edge's request id so they
+/// can report it
+fn error_reply(
+    error: &ForwardError,
+    request_id: Option<&str>,
+    format: ErrorFormat,
+) -> warp::http::Response<hyper::Body> {
+    let (status, code, message) = match error {
+        ForwardError::IncomingRead | ForwardError::InvalidURL | ForwardError::InvalidRequest => (
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "Error: Invalid request",
+        ),
+        ForwardError::LocalServerError => (
+            warp::http::StatusCode::BAD_GATEWAY,
+            ErrorCode::LocalUnreachable,
+            "Error: Local service unavailable",
+        ),
+    };
+
+    let mut reply = warp::http::Response::builder().status(status);
+    if let Some(request_id) = request_id {
+        reply = reply.header(verify::REQUEST_ID_HEADER, request_id);
+    }
+
+    let body = match (format, request_id) {
+        (ErrorFormat::Json, _) => {
+            reply = reply.header(warp::http::header::CONTENT_TYPE, "application/json");
+            ErrorEnvelope::new(status.as_u16(), code, message, request_id).to_json()
+        }
+        (ErrorFormat::Text, Some(request_id)) => {
+            format!("{} ({})\nRequest ID: {}", message, code, request_id)
+        }
+        (ErrorFormat::Text, None) => format!("{} ({})", message, code),
+    };
+
+    reply
+        .body(hyper::Body::from(body))
+        .unwrap_or_else(|_| warp::http::Response::new(hyper::Body::empty()))
+}
+
+/// The address of the local service we forward to, i.e: http://localhost:8000
+pub fn local_addr(config: &Config) -> String {
     let port = if config.scheme.as_str() == "http" {
         let port = config
             .local_port
-            .as_ref()
-            .map(|p| p.as_str())
+            .as_deref()
             .unwrap_or("8000");
         format!(":{}", port)
     } else {
@@ -86,27 +267,88 @@ pub fn start_introspection_server(config: Config) -> IntrospectionAddrs {
             .local_port
             .as_ref()
             .map(|p| format!(":{}", p))
-            .unwrap_or(String::new())
+            .unwrap_or_default()
     };
 
-    let local_addr = format!("{}://{}{}", &config.scheme, &config.local_host, port);
+    format!("{}://{}{}", &config.scheme, &config.local_host, port)
+}
+
+pub fn start_introspection_server(config: Config) -> IntrospectionAddrs {
+    let local_addr = local_addr(&config);
+    retarget(local_addr.clone());
 
     let https = hyper_tls::HttpsConnector::new();
     let http_client = hyper::Client::builder().build::<_, hyper::Body>(https);
 
+    let mut chain = MiddlewareChain::new(LocalService {
+        client: http_client.clone(),
+        grace_local: config.grace_local,
+    });
+
+    // first, so alerts count what visitors finally got
+    if !config.alerts.is_empty() {
+        chain = chain.with(crate::alerts::Alerts::new(
+            config.alerts.clone(),
+            config.alert_webhook.clone(),
+        ));
+    }
+
+    if config.notify {
+        chain = chain.with(crate::notify::NotifyFirstRequest::new());
+    }
+
+    // before recording, so the inspector shows what the local service got
+    let headers = config
+        .request_headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(value).ok()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+    if !headers.is_empty() {
+        chain = chain.with(AddHeaders(headers));
+    }
+
+    // before recording too, so the inspector shows what plugins made of the request
+    if config.plugins {
+        if let Some(plugins) = crate::plugins::Plugins::start(&local_addr) {
+            chain = chain.with(plugins);
+        }
+    }
+    chain = chain.with(RecordRequests);
+
+    // after recording, so the inspector shows the violations of the requests it rejects
+    if let Some(spec) = config.openapi.clone() {
+        chain = chain.with(crate::openapi::ValidateRequests {
+            spec,
+            reject: config.openapi_reject,
+        });
+    }
+    // innermost, to compare what the local service answered
+    if !config.snapshot_routes.is_empty() {
+        chain = chain.with(snapshot::SnapshotResponses {
+            routes: config.snapshot_routes.clone(),
+        });
+    }
+    let chain = Arc::new(chain);
+
     let get_client = move || {
         let client = http_client.clone();
         warp::any().map(move || client.clone()).boxed()
     };
 
+    let error_format = config.error_format;
     let intercept = warp::any()
-        .and(warp::any().map(move || local_addr.clone()))
+        .and(warp::any().map(move || chain.clone()))
+        .and(warp::any().map(move || error_format))
         .and(warp::method())
         .and(warp::path::full())
         .and(opt_raw_query())
         .and(warp::header::headers_cloned())
         .and(warp::body::stream())
-        .and(get_client())
         .and_then(forward);
 
     let (forward_address, intercept_server) =
@@ -133,7 +375,12 @@ pub fn start_introspection_server(config: Config) -> IntrospectionAddrs {
         );
         res
     }));
-    let forward_clone = forward_address.clone();
+    let forward_clone = forward_address;
+    let share_config = config.clone();
+    let status = InspectorStatus {
+        public_url: None,
+        local_addr,
+    };
 
     let web_explorer = warp::get()
         .and(warp::path::end())
@@ -142,11 +389,34 @@ pub fn start_introspection_server(config: Config) -> IntrospectionAddrs {
             .and(warp::path("detail"))
             .and(warp::path::param())
             .and_then(request_detail))
+        .or(warp::get()
+            .and(warp::path("scenario.yaml"))
+            .and_then(scenario::export_scenario))
         .or(warp::post()
             .and(warp::path("replay"))
             .and(warp::path::param())
             .and(get_client())
-            .and_then(move |id, client| replay_request(id, client, forward_clone.clone())))
+            .and_then(move |id, client| replay_request(id, client, forward_clone)))
+        .or(warp::post()
+            .and(warp::path("share"))
+            .and(warp::path::param())
+            .and_then(move |id| share::share_request(id, share_config.clone())))
+        .or(warp::post()
+            .and(warp::path("snapshot"))
+            .and(warp::path::param())
+            .and_then(snapshot::accept_snapshot))
+        .or(warp::get()
+            .and(warp::path!("api" / "requests"))
+            .map(|| warp::reply::json(&request_summaries())))
+        .or(warp::get().and(warp::path!("api" / "status")).map(move || {
+            let mut status = status.clone();
+            status.public_url = public_url();
+            status.local_addr = current_local_addr();
+            warp::reply::json(&status)
+        }))
+        .or(warp::get()
+            .and(warp::path!("debug" / "counts"))
+            .map(|| warp::reply::json(&crate::soak::InternalCounts::collect())))
         .or(css)
         .or(logo);
 
@@ -169,122 +439,59 @@ pub fn start_introspection_server(config: Config) -> IntrospectionAddrs {
 }
 
 async fn forward(
-    local_addr: String,
+    chain: Arc<MiddlewareChain<LocalService>>,
+    error_format: ErrorFormat,
     method: Method,
     path: FullPath,
     query: Option<String>,
     headers: HeaderMap,
     mut body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + Unpin + 'static,
-    client: HttpClient,
 ) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
     let started = chrono::Utc::now().naive_utc();
 
-    let mut request_headers = HashMap::new();
-    headers.keys().for_each(|k| {
-        let values = headers
-            .get_all(k)
-            .iter()
-            .filter_map(|v| v.to_str().ok())
-            .map(|s| s.to_owned())
-            .collect();
-        request_headers.insert(k.as_str().to_owned(), values);
-    });
-
     let mut collected: Vec<u8> = vec![];
 
     while let Some(chunk) = body.next().await {
         let chunk = chunk.map_err(|e| {
             log::error!("error reading incoming buffer: {:?}", e);
-            warp::reject::custom(ForwardError::IncomingRead)
+            warp::reject::custom(Rejected(ForwardError::IncomingRead))
         })?;
 
         collected.extend_from_slice(chunk.chunk())
     }
 
-    let query_str = if let Some(query) = query.as_ref() {
-        format!("?{}", query)
-    } else {
-        String::new()
+    let request = ProxyRequest {
+        method,
+        path: path.as_str().to_owned(),
+        query,
+        headers,
+        body: collected,
+        started,
     };
 
-    let url = format!("{}{}{}", local_addr, path.as_str(), query_str);
-    log::debug!("forwarding to: {}", &url);
-
-    let mut request = hyper::Request::builder()
-        .method(method.clone())
-        .version(hyper::Version::HTTP_11)
-        .uri(url.parse::<hyper::Uri>().map_err(|e| {
-            log::error!("invalid incoming url: {}, error: {:?}", url, e);
-            warp::reject::custom(ForwardError::InvalidURL)
-        })?);
-
-    for header in headers {
-        if let Some(header_name) = header.0 {
-            request = request.header(header_name, header.1)
+    let request_id = request.request_id();
+    let response = match chain.run(request).await {
+        Ok(response) => response,
+        Err(e) => {
+            return Ok(Box::new(error_reply(
+                &e,
+                request_id.as_deref(),
+                error_format,
+            )))
         }
-    }
-
-    // let _ = request.headers_mut().replace(&mut headers);
-    let request = request
-        .body(hyper::Body::from(collected.clone()))
-        .map_err(|e| {
-            log::error!("failed to build request: {:?}", e);
-            warp::reject::custom(ForwardError::InvalidRequest)
-        })?;
-
-    let response = client.request(request).await.map_err(|e| {
-        log::error!("local server error: {:?}", e);
-        warp::reject::custom(ForwardError::LocalServerError)
-    })?;
-
-    let mut response_headers = HashMap::new();
-    response.headers().keys().for_each(|k| {
-        let values = response
-            .headers()
-            .get_all(k)
-            .iter()
-            .filter_map(|v| v.to_str().ok())
-            .map(|s| s.to_owned())
-            .collect();
-        response_headers.insert(k.as_str().to_owned(), values);
-    });
-
-    let (parts, mut body) = response.into_parts();
-
-    let mut response_data = vec![];
-    while let Some(next) = body.data().await {
-        let chunk = next.map_err(|e| {
-            log::error!("error reading local response: {:?}", e);
-            warp::reject::custom(ForwardError::LocalServerError)
-        })?;
+    };
 
-        response_data.extend_from_slice(&chunk);
+    let mut reply = warp::http::Response::builder().status(response.status);
+    if let Some(headers) = reply.headers_mut() {
+        *headers = response.headers;
     }
 
-    let stored_request = Request {
-        id: Uuid::new_v4().to_string(),
-        status: parts.status.as_u16(),
-        path: path.as_str().to_owned(),
-        query,
-        method,
-        headers: request_headers,
-        body_data: collected,
-        response_headers,
-        response_data: response_data.clone(),
-        started,
-        completed: chrono::Utc::now().naive_utc(),
-        is_replay: false,
-    };
-
-    REQUESTS
-        .write()
-        .unwrap()
-        .insert(stored_request.id.clone(), stored_request);
+    let reply = reply.body(response.body).map_err(|e| {
+        log::error!("failed to build response: {:?}", e);
+        warp::reject::custom(Rejected(ForwardError::LocalServerError))
+    })?;
 
-    Ok(Box::new(warp::http::Response::from_parts(
-        parts,
-        response_data,
-    )))
+    Ok(Box::new(reply))
 }
 
 #[derive(Debug, Clone, askama::Template)]
@@ -310,7 +517,7 @@ struct BodyData {
 
 impl AsRef<BodyData> for BodyData {
     fn as_ref(&self) -> &BodyData {
-        &self
+        self
     }
 }
 
@@ -325,13 +532,21 @@ async fn inspector() -> Result<Page<Inspector>, warp::reject::Rejection> {
         .read()
         .unwrap()
         .values()
-        .map(|r| r.clone())
+        .cloned()
         .collect();
-    requests.sort_by(|a, b| b.completed.cmp(&a.completed));
+    requests.sort_by_key(|r| std::cmp::Reverse(r.completed));
     let inspect = Inspector { requests };
     Ok(Page(inspect))
 }
 
+/// Recorded requests, newest first
+fn request_summaries() -> Vec<RequestSummary> {
+    let requests = REQUESTS.read().unwrap();
+    let mut requests = requests.values().collect::<Vec<_>>();
+    requests.sort_by_key(|r| std::cmp::Reverse(r.completed));
+    requests.into_iter().map(RequestSummary::from).collect()
+}
+
 async fn request_detail(rid: String) -> Result<Page<InspectorDetail>, warp::reject::Rejection> {
     let request: Request = match REQUESTS.read().unwrap().get(&rid) {
         Some(r) => r.clone(),
@@ -399,7 +614,7 @@ async fn replay_request(
         .version(hyper::Version::HTTP_11)
         .uri(url.parse::<hyper::Uri>().map_err(|e| {
             log::error!("invalid incoming url: {}, error: {:?}", url, e);
-            warp::reject::custom(ForwardError::InvalidURL)
+            warp::reject::custom(Rejected(ForwardError::InvalidURL))
         })?);
 
     for (header, values) in &request.headers {
@@ -412,12 +627,12 @@ async fn replay_request(
         .body(hyper::Body::from(request.body_data))
         .map_err(|e| {
             log::error!("failed to build request: {:?}", e);
-            warp::reject::custom(ForwardError::InvalidRequest)
+            warp::reject::custom(Rejected(ForwardError::InvalidRequest))
         })?;
 
     let _ = client.request(new_request).await.map_err(|e| {
         log::error!("local server error: {:?}", e);
-        warp::reject::custom(ForwardError::LocalServerError)
+        warp::reject::custom(Rejected(ForwardError::LocalServerError))
     })?;
 
     let response = warp::http::Response::builder()
@@ -447,7 +662,7 @@ where
 
 fn opt_raw_query() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Copy {
     warp::filters::query::raw()
-        .map(|q| Some(q))
+        .map(Some)
         .or(warp::any().map(|| None))
         .unify()
 }
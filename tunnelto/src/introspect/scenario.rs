@@ -0,0 +1,313 @@
+use super::*;
+use serde::{Deserialize, Serialize};
+
+/// A replayable sequence of requests, written as YAML:
+///
+/// ```yaml
+/// concurrency: 4          # virtual users running the steps in parallel
+/// iterations: 10          # times each user runs the steps
+/// variables:
+///   user: alice
+/// steps:
+///   - name: login
+///     method: POST
+///     path: /login
+///     headers:
+///       content-type: application/json
+///     body: '{"user": "${user}"}'
+///     expect_status: 200
+///     extract:
+///       token: json:/token              # json pointer into the response body
+///       cookie: header:set-cookie       # response header
+///     think_ms: 250
+///   - method: GET
+///     path: /me
+///     headers:
+///       authorization: Bearer ${token}
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    #[serde(default = "default_one")]
+    pub concurrency: usize,
+    #[serde(default = "default_one")]
+    pub iterations: usize,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Step {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub method: String,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect_status: Option<u16>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extract: HashMap<String, String>,
+    #[serde(default)]
+    pub think_ms: u64,
+}
+
+fn default_one() -> usize {
+    1
+}
+
+impl Step {
+    fn label(&self, index: usize) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("#{} {} {}", index + 1, self.method, self.path))
+    }
+}
+
+/// Headers that describe the original connection rather than the request
+const SKIPPED_HEADERS: &[&str] = &["host", "content-length", "connection"];
+
+impl Scenario {
+    /// Turn captured requests into steps, in the order they arrived
+    pub fn from_captured(mut requests: Vec<Request>) -> Self {
+        requests.sort_by_key(|a| a.started);
+
+        let mut steps: Vec<Step> = vec![];
+        let mut previous: Option<chrono::NaiveDateTime> = None;
+        for request in requests.into_iter().filter(|r| !r.is_replay) {
+            // keep the captured pacing as think time before this request
+            if let (Some(last), Some(previous)) = (steps.last_mut(), previous) {
+                last.think_ms = (request.started - previous).num_milliseconds().max(0) as u64;
+            }
+            previous = Some(request.started);
+
+            let headers = request
+                .headers
+                .iter()
+                .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.to_lowercase().as_str()))
+                .filter_map(|(name, values)| values.first().map(|v| (name.clone(), v.clone())))
+                .collect();
+
+            steps.push(Step {
+                name: None,
+                method: request.method.to_string(),
+                path: request.path_and_query(),
+                headers,
+                body: if request.body_data.is_empty() {
+                    None
+                } else {
+                    Some(String::from_utf8_lossy(&request.body_data).to_string())
+                },
+                expect_status: Some(request.status),
+                extract: HashMap::new(),
+                think_ms: 0,
+            });
+        }
+
+        Scenario {
+            concurrency: 1,
+            iterations: 1,
+            variables: HashMap::new(),
+            steps,
+        }
+    }
+}
+
+/// Replace `${name}` with the variable's value
+fn substitute(input: &str, variables: &HashMap<String, String>) -> String {
+    let mut output = input.to_string();
+    for (name, value) in variables {
+        output = output.replace(&format!("${{{}}}", name), value);
+    }
+    output
+}
+
+fn extract(source: &str, headers: &hyper::HeaderMap, body: &[u8]) -> Option<String> {
+    if let Some(pointer) = source.strip_prefix("json:") {
+        let json: serde_json::Value = serde_json::from_slice(body).ok()?;
+        return match json.pointer(pointer)? {
+            serde_json::Value::String(s) => Some(s.clone()),
+            value => Some(value.to_string()),
+        };
+    }
+
+    if let Some(name) = source.strip_prefix("header:") {
+        return headers.get(name)?.to_str().ok().map(String::from);
+    }
+
+    None
+}
+
+#[derive(Debug, Clone)]
+struct StepResult {
+    step: usize,
+    status: Option<u16>,
+    elapsed: Duration,
+    ok: bool,
+}
+
+async fn run_step(
+    client: &HttpClient,
+    target: &str,
+    step: &Step,
+    index: usize,
+    variables: &mut HashMap<String, String>,
+) -> StepResult {
+    let started = std::time::Instant::now();
+    let url = format!("{}{}", target, substitute(&step.path, variables));
+
+    let mut request = hyper::Request::builder().method(step.method.as_str()).uri(url);
+    for (name, value) in &step.headers {
+        request = request.header(name.as_str(), substitute(value, variables));
+    }
+    let body = step
+        .body
+        .as_ref()
+        .map(|b| substitute(b, variables))
+        .unwrap_or_default();
+
+    let failed = |status| StepResult {
+        step: index,
+        status,
+        elapsed: started.elapsed(),
+        ok: false,
+    };
+
+    let request = match request.body(hyper::Body::from(body)) {
+        Ok(request) => request,
+        Err(e) => {
+            log::error!("invalid scenario step {}: {:?}", step.label(index), e);
+            return failed(None);
+        }
+    };
+
+    let response = match client.request(request).await {
+        Ok(response) => response,
+        Err(e) => {
+            log::warn!("scenario step {} failed: {:?}", step.label(index), e);
+            return failed(None);
+        }
+    };
+
+    let (parts, mut body) = response.into_parts();
+    let mut data = vec![];
+    while let Some(Ok(chunk)) = body.data().await {
+        data.extend_from_slice(&chunk);
+    }
+
+    for (name, source) in &step.extract {
+        match extract(source, &parts.headers, &data) {
+            Some(value) => {
+                variables.insert(name.clone(), value);
+            }
+            None => log::warn!("step {}: nothing to extract at {}", step.label(index), source),
+        }
+    }
+
+    let status = parts.status.as_u16();
+    StepResult {
+        step: index,
+        status: Some(status),
+        elapsed: started.elapsed(),
+        ok: step.expect_status.is_none_or(|expected| expected == status),
+    }
+}
+
+async fn run_user(client: HttpClient, target: String, scenario: Arc<Scenario>) -> Vec<StepResult> {
+    let mut results = vec![];
+
+    for _ in 0..scenario.iterations {
+        let mut variables = scenario.variables.clone();
+        for (index, step) in scenario.steps.iter().enumerate() {
+            results.push(run_step(&client, &target, step, index, &mut variables).await);
+
+            if step.think_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(step.think_ms)).await;
+            }
+        }
+    }
+
+    results
+}
+
+/// Run the scenario in `file` against `target` and print a summary per step
+pub async fn run_scenario(file: String, target: String) -> Result<(), Error> {
+    let data = std::fs::read_to_string(&file)
+        .map_err(|e| Error::InvalidScenario(format!("{}: {}", file, e)))?;
+    let scenario: Scenario =
+        serde_yaml::from_str(&data).map_err(|e| Error::InvalidScenario(e.to_string()))?;
+    let scenario = Arc::new(scenario);
+
+    eprintln!(
+        "Running {} steps x {} iterations with {} users against {}",
+        scenario.steps.len(),
+        scenario.iterations,
+        scenario.concurrency,
+        target.yellow()
+    );
+
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let users = (0..scenario.concurrency.max(1))
+        .map(|_| tokio::spawn(run_user(client.clone(), target.clone(), scenario.clone())));
+
+    let mut results = vec![];
+    for user in futures::future::join_all(users).await {
+        results.extend(user.unwrap_or_default());
+    }
+
+    let mut failures = 0;
+    for (index, step) in scenario.steps.iter().enumerate() {
+        let step_results: Vec<&StepResult> = results.iter().filter(|r| r.step == index).collect();
+        if step_results.is_empty() {
+            continue;
+        }
+
+        let failed = step_results.iter().filter(|r| !r.ok).count();
+        failures += failed;
+        let total: Duration = step_results.iter().map(|r| r.elapsed).sum();
+        let max = step_results.iter().map(|r| r.elapsed).max().unwrap_or_default();
+        let statuses = step_results
+            .iter()
+            .map(|r| r.status.map_or("err".to_string(), |s| s.to_string()))
+            .collect::<std::collections::BTreeSet<String>>();
+
+        let summary = format!(
+            "{:<40} runs={:<5} failed={:<5} avg={:>6}ms max={:>6}ms status={:?}",
+            step.label(index),
+            step_results.len(),
+            failed,
+            total.as_millis() / step_results.len() as u128,
+            max.as_millis(),
+            statuses
+        );
+        if failed > 0 {
+            eprintln!("{}", summary.red());
+        } else {
+            eprintln!("{}", summary.green());
+        }
+    }
+
+    if failures > 0 {
+        return Err(Error::ScenarioFailed(failures));
+    }
+    Ok(())
+}
+
+/// Export the captured requests as a scenario
+pub async fn export_scenario() -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
+    let requests = REQUESTS.read().unwrap().values().cloned().collect();
+    let scenario = Scenario::from_captured(requests);
+    let yaml = serde_yaml::to_string(&scenario).unwrap_or_default();
+
+    let response = warp::http::Response::builder()
+        .header(warp::http::header::CONTENT_TYPE, "application/x-yaml")
+        .header(
+            warp::http::header::CONTENT_DISPOSITION,
+            "attachment; filename=\"scenario.yaml\"",
+        )
+        .body(yaml);
+
+    Ok(Box::new(response))
+}
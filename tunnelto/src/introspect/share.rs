@@ -0,0 +1,96 @@
+use super::*;
+use tunnelto_lib::inspect::{body_text, InspectLinkRequest, InspectLinkResponse, InspectSnapshot};
+
+#[derive(Debug, Clone, askama::Template)]
+#[template(path = "share.html")]
+struct Shared {
+    request_id: String,
+    link: Option<String>,
+    expires_at: Option<String>,
+    error: Option<String>,
+}
+
+/// The captured request as shared, before redaction
+fn snapshot(request: &Request) -> InspectSnapshot {
+    InspectSnapshot {
+        method: request.method.to_string(),
+        path: request.path_and_query(),
+        status: request.status,
+        request_id: request.request_id.clone(),
+        captured_at: request
+            .completed
+            .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+            .to_string(),
+        request_headers: flatten(&request.headers),
+        request_body: body_text(&request.body_data),
+        response_headers: flatten(&request.response_headers),
+        response_body: body_text(&request.response_data),
+    }
+}
+
+fn flatten(headers: &HashMap<String, Vec<String>>) -> Vec<(String, String)> {
+    let mut flat = headers
+        .iter()
+        .flat_map(|(name, values)| values.iter().map(move |v| (name.clone(), v.clone())))
+        .collect::<Vec<_>>();
+    flat.sort();
+    flat
+}
+
+async fn share(config: &Config, request: &Request) -> Result<(String, String), String> {
+    let auth_key = config
+        .secret_key
+        .clone()
+        .ok_or_else(|| Error::NoAuthenticationKey.to_string())?;
+
+    let request = InspectLinkRequest {
+        auth_key,
+        snapshot: snapshot(request).redacted(),
+    };
+    let response: InspectLinkResponse = api::post(config, "inspect_links", &request)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match response {
+        InspectLinkResponse::Created {
+            path,
+            url,
+            expires_at,
+        } => Ok((
+            url.unwrap_or_else(|| format!("{}{}", config.control_api_url, path)),
+            expires_at,
+        )),
+        InspectLinkResponse::Failed { reason } => Err(reason),
+    }
+}
+
+/// Share a captured request through a public inspect link, redacted
+pub async fn share_request(
+    rid: String,
+    config: Config,
+) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
+    let request: Request = match REQUESTS.read().unwrap().get(&rid) {
+        Some(r) => r.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let page = match share(&config, &request).await {
+        Ok((link, expires_at)) => Shared {
+            request_id: rid,
+            link: Some(link),
+            expires_at: Some(expires_at),
+            error: None,
+        },
+        Err(e) => {
+            log::error!("failed to share request {}: {}", &rid, e);
+            Shared {
+                request_id: rid,
+                link: None,
+                expires_at: None,
+                error: Some(e),
+            }
+        }
+    };
+
+    Ok(Box::new(Page(page)))
+}
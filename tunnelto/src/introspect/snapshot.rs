@@ -0,0 +1,214 @@
+//! Response snapshots (`--snapshot /api/*`): the first response to each method and path
+//! under a snapshotted route becomes its canonical one, and later responses that change
+//! its status or the shape of its json (fields added, removed or changing type) are
+//! flagged in the inspector, to catch breaking changes while iterating on the service.
+//! Snapshots live as long as the tunnel, the inspector replaces one with a newer
+//! response on "Accept as snapshot".
+use super::*;
+use colored::Colorize;
+use futures::FutureExt;
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
+
+/// changes reported per response, the rest are counted
+const MAX_CHANGES: usize = 20;
+
+lazy_static::lazy_static! {
+    /// by `METHOD /path`
+    static ref SNAPSHOTS: RwLock<HashMap<String, Snapshot>> = RwLock::new(HashMap::new());
+}
+
+/// What a response promises its callers
+#[derive(Debug, Clone)]
+struct Snapshot {
+    status: u16,
+    /// the type of each field of a json body by its path, i.e. `body.user.id: number`,
+    /// array items under `[]`
+    shape: Option<BTreeMap<String, &'static str>>,
+}
+
+impl Snapshot {
+    fn of(status: u16, headers: &HeaderMap, body: &[u8]) -> Self {
+        let is_json = headers
+            .get(warp::http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("json"));
+
+        let shape = serde_json::from_slice::<Value>(body)
+            .ok()
+            .filter(|_| is_json)
+            .map(|value| {
+                let mut shape = BTreeMap::new();
+                collect_shape(&value, "body".to_string(), &mut shape);
+                shape
+            });
+
+        Snapshot { status, shape }
+    }
+
+    /// How `current` departs from this snapshot
+    fn changes(&self, current: &Snapshot) -> Vec<String> {
+        let mut changes = vec![];
+        if self.status != current.status {
+            changes.push(format!(
+                "status changed from {} to {}",
+                self.status, current.status
+            ));
+        }
+
+        match (&self.shape, &current.shape) {
+            (Some(before), Some(after)) => {
+                for (field, kind) in before {
+                    match after.get(field) {
+                        None if unseen(after, field) => {}
+                        None => changes.push(format!("field `{}` was removed", field)),
+                        // a field that may be null isn't a change of type
+                        Some(&"null") => {}
+                        Some(now) if *kind != "null" && now != kind => changes.push(format!(
+                            "field `{}` changed from {} to {}",
+                            field, kind, now
+                        )),
+                        Some(_) => {}
+                    }
+                }
+                let added = after
+                    .keys()
+                    .filter(|field| !before.contains_key(*field) && !unseen(before, field));
+                for field in added {
+                    changes.push(format!("field `{}` was added", field));
+                }
+            }
+            (Some(_), None) => changes.push("body is no longer json".to_string()),
+            (None, Some(_)) => changes.push("body is now json".to_string()),
+            (None, None) => {}
+        }
+
+        if changes.len() > MAX_CHANGES {
+            let more = changes.len() - MAX_CHANGES;
+            changes.truncate(MAX_CHANGES);
+            changes.push(format!("and {} more", more));
+        }
+        changes
+    }
+}
+
+fn collect_shape(value: &Value, path: String, shape: &mut BTreeMap<String, &'static str>) {
+    let kind = match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    };
+    // items of different types keep the first, null aside
+    let known = shape.entry(path.clone()).or_insert(kind);
+    if *known == "null" {
+        *known = kind;
+    }
+
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                collect_shape(item, format!("{}[]", path), shape);
+            }
+        }
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                collect_shape(field, format!("{}.{}", path, name), shape);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether `shape` can't tell if it has `field`, as the field would be in an array
+/// that was empty or under a field that was null
+fn unseen(shape: &BTreeMap<String, &'static str>, field: &str) -> bool {
+    field
+        .match_indices(['.', '['])
+        .any(|(i, _)| match shape.get(&field[..i]) {
+            Some(&"null") => true,
+            Some(&"array") => !shape.contains_key(&format!("{}[]", &field[..i])),
+            _ => false,
+        })
+}
+
+fn key(method: &Method, path: &str) -> String {
+    format!("{} {}", method, path)
+}
+
+/// Snapshots responses to requests under `routes`, flagging those departing from theirs
+pub struct SnapshotResponses {
+    pub routes: Vec<String>,
+}
+
+impl Middleware for SnapshotResponses {
+    fn handle<'a>(&'a self, request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a> {
+        async move {
+            let snapshotted = self
+                .routes
+                .iter()
+                .any(|route| tunnelto_lib::acl::path_matches(route, &request.path));
+            if !snapshotted {
+                return next.run(request).await;
+            }
+
+            let key = key(&request.method, &request.path);
+            let mut response = next.run(request).await?;
+            let current = Snapshot::of(response.status.as_u16(), &response.headers, &response.body);
+
+            let changes = match SNAPSHOTS.write().unwrap().entry(key.clone()) {
+                Entry::Occupied(canonical) => canonical.get().changes(&current),
+                Entry::Vacant(entry) => {
+                    entry.insert(current);
+                    vec![]
+                }
+            };
+
+            if !changes.is_empty() {
+                eprintln!(
+                    "{} {}: {}",
+                    "Contract change".yellow(),
+                    key,
+                    changes.join("; ")
+                );
+                Findings::of(&mut response).contract_changes.extend(changes);
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+/// Make a recorded response the canonical one for its method and path
+pub async fn accept_snapshot(rid: String) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
+    let request: Request = match REQUESTS.read().unwrap().get(&rid) {
+        Some(r) => r.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let mut headers = HeaderMap::new();
+    if let Some(value) = request
+        .response_headers
+        .get(warp::http::header::CONTENT_TYPE.as_str())
+        .and_then(|values| values.first())
+        .and_then(|value| HeaderValue::from_str(value).ok())
+    {
+        headers.insert(warp::http::header::CONTENT_TYPE, value);
+    }
+
+    let snapshot = Snapshot::of(request.status, &headers, &request.response_data);
+    SNAPSHOTS
+        .write()
+        .unwrap()
+        .insert(key(&request.method, &request.path), snapshot);
+
+    let response = warp::http::Response::builder()
+        .status(warp::http::StatusCode::SEE_OTHER)
+        .header(warp::http::header::LOCATION, format!("/detail/{}", rid))
+        .body(b"".to_vec());
+
+    Ok(Box::new(response))
+}
@@ -0,0 +1,106 @@
+use super::*;
+
+/// Create a key that opens tunnels for the account until it expires, optionally
+/// only on `sub_domains` and those starting with `sub_domain_prefix`
+pub async fn create_guest_key(
+    config: &Config,
+    duration: Duration,
+    sub_domain_prefix: Option<String>,
+    sub_domains: Vec<String>,
+    label: Option<String>,
+) -> Result<(), Error> {
+    let auth_key = config.secret_key.clone().ok_or(Error::NoAuthenticationKey)?;
+
+    let request = GuestKeyRequest {
+        auth_key,
+        duration_secs: duration.as_secs().max(1),
+        sub_domain_prefix,
+        sub_domains,
+        label,
+    };
+    let response: GuestKeyResponse = api::post(config, "keys/guest", &request).await?;
+
+    match response {
+        GuestKeyResponse::Created {
+            key,
+            expires_at,
+            sub_domain_prefix,
+            sub_domains,
+        } => {
+            eprintln!("{} Guest key valid until {}:", "Success!".green(), expires_at);
+            let mut scope = sub_domains
+                .iter()
+                .map(|sub_domain| sub_domain.bold().to_string())
+                .collect::<Vec<_>>();
+            if let Some(prefix) = sub_domain_prefix {
+                scope.push(format!("sub-domains starting with {}", prefix.bold()));
+            }
+            if !scope.is_empty() {
+                eprintln!("It only opens {}.", scope.join(", "));
+            }
+            // the key alone on stdout, for scripts handing out keys
+            println!("{}", key.0);
+        }
+        GuestKeyResponse::Failed { reason } => {
+            eprintln!("{} {}", "Creating guest key failed:".red(), reason);
+        }
+    }
+
+    Ok(())
+}
+
+/// Add a key to the account, with the same entitlements as ours
+pub async fn add_key(config: &Config, label: String) -> Result<(), Error> {
+    let auth_key = config.secret_key.clone().ok_or(Error::NoAuthenticationKey)?;
+
+    let request = AccountKeyRequest { auth_key, label };
+    let response: AccountKeyResponse = api::post(config, "keys", &request).await?;
+
+    match response {
+        AccountKeyResponse::Created { key, label } => {
+            eprintln!(
+                "{} Added key {}:",
+                "Success!".green(),
+                label.unwrap_or_default().bold()
+            );
+            println!("{}", key.0);
+        }
+        AccountKeyResponse::Failed { reason } => {
+            eprintln!("{} {}", "Adding key failed:".red(), reason);
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace our key with a new one, storing it if ours was the stored key
+pub async fn rotate_key(config: &Config) -> Result<(), Error> {
+    let auth_key = config.secret_key.clone().ok_or(Error::NoAuthenticationKey)?;
+
+    let request = KeyRotationRequest {
+        auth_key: auth_key.clone(),
+    };
+    let response: AccountKeyResponse = api::post(config, "keys/rotate", &request).await?;
+
+    match response {
+        AccountKeyResponse::Created { key, label } => {
+            let label = label
+                .map(|label| format!(" {}", label.bold()))
+                .unwrap_or_default();
+            eprintln!(
+                "{} Rotated key{}, the old one no longer works.",
+                "Success!".green(),
+                label
+            );
+            if config::replace_secret_key_file(&auth_key, &key) {
+                eprintln!("The new key was stored for future use.");
+            }
+            println!("{}", key.0);
+        }
+        AccountKeyResponse::Failed { reason } => {
+            eprintln!("{} {}", "Rotating key failed:".red(), reason);
+        }
+    }
+
+    Ok(())
+}
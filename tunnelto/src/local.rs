@@ -7,12 +7,24 @@ use tokio::io::{ReadHalf, WriteHalf};
 use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
 
 use crate::introspect;
+use crate::stream_integrity;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static::lazy_static! {
+    static ref SEND_END: AtomicBool = AtomicBool::new(false);
+}
+
+/// Tell the server when the local service closes a stream, so it closes the visitor's
+/// connection too (once the server announced `features::STREAM_END`)
+pub fn send_end() {
+    SEND_END.store(true, Ordering::SeqCst);
+}
 
 /// Establish a new local stream and start processing messages to it
-pub async fn setup_new_stream(local_port: u16, mut tunnel_tx: UnboundedSender<ControlPacket>, stream_id: StreamId) {
+pub async fn setup_new_stream(local_addr: &str, mut tunnel_tx: UnboundedSender<ControlPacket>, stream_id: StreamId) {
     info!("setting up local stream: {}", &stream_id.to_string());
 
-    let local_tcp = match TcpStream::connect(format!("localhost:{}", local_port)).await {
+    let local_tcp = match TcpStream::connect(local_addr).await {
         Ok(s) => s,
         Err(e) => {
             warn!("failed to connect to local service: {:?}", e);
@@ -40,17 +52,26 @@ pub async fn setup_new_stream(local_port: u16, mut tunnel_tx: UnboundedSender<Co
 
 pub async fn process_local_tcp(mut stream: ReadHalf<TcpStream>, mut tunnel: UnboundedSender<ControlPacket>, stream_id: StreamId) {
     let mut buf = [0; 4*1024];
+    // everything sent to the server, for the trailer
+    let mut checksum = stream_integrity::StreamChecksum::default();
 
     loop {
         let n = stream.read(&mut buf).await.expect("failed to read data from socket");
 
         if n == 0 {
             info!("done reading from client stream");
+            if stream_integrity::enabled() {
+                let _ = tunnel.send(ControlPacket::Trailer(stream_id.clone(), checksum)).await;
+            }
+            if SEND_END.load(Ordering::SeqCst) {
+                let _ = tunnel.send(ControlPacket::End(stream_id.clone())).await;
+            }
             ACTIVE_STREAMS.write().unwrap().remove(&stream_id);
             return
         }
 
         let data = buf[..n].to_vec();
+        checksum.update(&data);
         debug!("read from local service: {:?}", std::str::from_utf8(&data).unwrap_or("<non utf8>"));
 
         let packet = ControlPacket::Data(stream_id.clone(), data.clone());
@@ -62,9 +83,16 @@ pub async fn process_local_tcp(mut stream: ReadHalf<TcpStream>, mut tunnel: Unbo
 }
 
 async fn forward_to_local_tcp(stream_id: StreamId, mut sink: WriteHalf<TcpStream>, mut queue: UnboundedReceiver<StreamMessage>) {
+    // everything written to the local service, to check against the server's trailer
+    let mut checksum = stream_integrity::StreamChecksum::default();
+
     loop {
         let data = match queue.next().await {
             Some(StreamMessage::Data(data)) => data,
+            Some(StreamMessage::Trailer(sent)) => {
+                stream_integrity::verify(&stream_id, &checksum, &sent);
+                continue
+            }
             None | Some(StreamMessage::Close) => {
                 warn!("closing stream");
                 let _ = sink.shutdown().await.map_err(|e| {
@@ -75,6 +103,7 @@ async fn forward_to_local_tcp(stream_id: StreamId, mut sink: WriteHalf<TcpStream
         };
 
         sink.write_all(&data).await.expect("failed to write packet data to local tcp socket");
+        checksum.update(&data);
         debug!("wrote to local service: {:?}", data.len());
 
         let stream_id_clone =  stream_id.clone();
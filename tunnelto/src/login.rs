@@ -0,0 +1,349 @@
+//! `tunnelto login`: sign in with the identity provider the server trusts using the OAuth2
+//! device flow (RFC 8628), instead of copying a key into `tunnelto set-auth`.
+//!
+//! The provider is the one the server announces at `/capabilities`, or `--issuer` and
+//! `--client-id`. The token we get is stored in `~/.tunnelto/login.json` and used as the
+//! client's JWT when no `--key` or `--jwt` is given, renewed with the refresh token
+//! when it's about to expire.
+use super::*;
+use hyper::body::HttpBody;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+const LOGIN_FILE: &str = "login.json";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// tokens expiring sooner than this are renewed
+const RENEW_BEFORE_SECS: i64 = 60;
+
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    device_authorization_endpoint: Option<String>,
+    token_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    /// some providers still call it `verification_url`
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+struct Tokens {
+    id_token: Option<String>,
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// The login kept between runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredLogin {
+    issuer: String,
+    client_id: String,
+    #[serde(default)]
+    audience: Option<String>,
+    token_endpoint: String,
+    /// the JWT presented to the server
+    token: String,
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+impl StoredLogin {
+    /// Take the token the server wants from a token response, keeping the refresh token
+    /// if the provider didn't send a new one
+    fn update(&mut self, tokens: Tokens) -> Result<(), Error> {
+        let token = match self.audience {
+            Some(_) => tokens.access_token,
+            None => tokens.id_token.or(tokens.access_token),
+        };
+        self.token = token.ok_or_else(|| Error::Login("the provider sent no token".into()))?;
+        if tokens.refresh_token.is_some() {
+            self.refresh_token = tokens.refresh_token;
+        }
+        Ok(())
+    }
+}
+
+fn login_file() -> Option<std::path::PathBuf> {
+    dirs::home_dir().map(|home| home.join(SETTINGS_DIR).join(LOGIN_FILE))
+}
+
+fn read() -> Option<StoredLogin> {
+    let data = std::fs::read(login_file()?).ok()?;
+    serde_json::from_slice(&data)
+        .map_err(|e| error!("Error reading login: {:?}", e))
+        .ok()
+}
+
+fn save(login: &StoredLogin) -> Result<(), Error> {
+    let path = login_file().ok_or_else(|| Error::Login("no home directory".into()))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| Error::Login(e.to_string()))?;
+    }
+    let data = serde_json::to_vec_pretty(login).unwrap_or_default();
+    std::fs::write(&path, data).map_err(|e| Error::Login(e.to_string()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600));
+    }
+    Ok(())
+}
+
+/// The stored login's token, to authenticate with
+pub fn stored_token() -> Option<String> {
+    read().map(|login| login.token)
+}
+
+/// Forget the stored login, i.e. once a key is set with `set-auth`
+pub fn forget() {
+    if let Some(path) = login_file().filter(|path| path.exists()) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Seconds until the JWT expires, if it says
+fn expires_in(token: &str) -> Option<i64> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let exp = serde_json::from_slice::<serde_json::Value>(&payload)
+        .ok()?
+        .get("exp")?
+        .as_i64()?;
+    Some(exp - chrono::Utc::now().timestamp())
+}
+
+async fn fetch<T: DeserializeOwned>(
+    request: hyper::Request<hyper::Body>,
+) -> Result<Result<T, TokenError>, Error> {
+    let uri = request.uri().to_string();
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let mut response = client
+        .request(request)
+        .await
+        .map_err(|e| Error::Login(format!("{} failed: {}", uri, e)))?;
+
+    let mut data = vec![];
+    while let Some(chunk) = response.body_mut().data().await {
+        let chunk = chunk.map_err(|e| Error::Login(e.to_string()))?;
+        data.extend_from_slice(&chunk);
+    }
+
+    if response.status().is_success() {
+        return serde_json::from_slice::<T>(&data)
+            .map(Ok)
+            .map_err(|e| Error::Login(format!("invalid reply from {}: {}", uri, e)));
+    }
+    serde_json::from_slice::<TokenError>(&data)
+        .map(Err)
+        .map_err(|_| Error::Login(format!("{} answered {}", uri, response.status())))
+}
+
+async fn post_form<T: DeserializeOwned>(
+    url: &str,
+    form: &[(&str, &str)],
+) -> Result<Result<T, TokenError>, Error> {
+    let body = serde_urlencoded::to_string(form).unwrap_or_default();
+    let request = hyper::Request::post(url)
+        .header(
+            hyper::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .header(hyper::header::ACCEPT, "application/json")
+        .body(hyper::Body::from(body))
+        .map_err(|e| Error::InvalidUrl(e.to_string()))?;
+    fetch(request).await
+}
+
+fn describe(error: TokenError) -> String {
+    match error.error_description {
+        Some(description) => format!("{}: {}", error.error, description),
+        None => error.error,
+    }
+}
+
+/// The identity provider to sign in with, the server's unless given
+async fn provider(
+    config: &Config,
+    issuer: Option<String>,
+    client_id: Option<String>,
+    audience: Option<String>,
+) -> Result<LoginProvider, Error> {
+    match (issuer, client_id) {
+        (Some(issuer), Some(client_id)) => Ok(LoginProvider {
+            issuer,
+            client_id,
+            audience,
+        }),
+        (None, None) => {
+            let response = api::get::<CapabilitiesResponse>(config, "capabilities").await?;
+            response.capabilities.login.ok_or_else(|| {
+                Error::Login(
+                    "the server doesn't offer login, pass --issuer and --client-id or use `tunnelto set-auth`"
+                        .into(),
+                )
+            })
+        }
+        _ => Err(Error::Login("--issuer and --client-id go together".into())),
+    }
+}
+
+/// Run the device flow and store the token
+pub async fn login(
+    config: &Config,
+    issuer: Option<String>,
+    client_id: Option<String>,
+    audience: Option<String>,
+) -> Result<(), Error> {
+    let provider = provider(config, issuer, client_id, audience).await?;
+
+    let discovery_url = format!(
+        "{}/.well-known/openid-configuration",
+        provider.issuer.trim_end_matches('/')
+    );
+    let discovery = hyper::Request::get(&discovery_url)
+        .body(hyper::Body::empty())
+        .map_err(|e| Error::InvalidUrl(e.to_string()))?;
+    let discovery = fetch::<Discovery>(discovery)
+        .await?
+        .map_err(|e| Error::Login(describe(e)))?;
+    let device_endpoint = discovery.device_authorization_endpoint.ok_or_else(|| {
+        Error::Login(format!(
+            "{} doesn't support the device flow",
+            provider.issuer
+        ))
+    })?;
+
+    let mut form = vec![
+        ("client_id", provider.client_id.as_str()),
+        ("scope", "openid offline_access"),
+    ];
+    if let Some(audience) = provider.audience.as_ref() {
+        form.push(("audience", audience));
+    }
+    let device = post_form::<DeviceAuthorization>(&device_endpoint, &form)
+        .await?
+        .map_err(|e| Error::Login(describe(e)))?;
+
+    eprintln!(
+        "{} Open {} and enter the code {}",
+        "=>".green(),
+        device.verification_uri.bold(),
+        device.user_code.bold().green()
+    );
+    if let Some(complete) = device.verification_uri_complete.as_ref() {
+        eprintln!("   or open {}", complete.bold());
+    }
+
+    let spinner = spinner::new_spinner("waiting for you to sign in");
+    let deadline = std::time::Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = Duration::from_secs(device.interval.max(1));
+    let tokens = loop {
+        tokio::time::sleep(interval).await;
+        if std::time::Instant::now() > deadline {
+            spinner.finish_and_clear();
+            return Err(Error::Login(
+                "the code expired, run `tunnelto login` again".into(),
+            ));
+        }
+
+        let form = [
+            ("grant_type", DEVICE_CODE_GRANT),
+            ("device_code", device.device_code.as_str()),
+            ("client_id", provider.client_id.as_str()),
+        ];
+        match post_form::<Tokens>(&discovery.token_endpoint, &form).await? {
+            Ok(tokens) => break tokens,
+            Err(e) if e.error == "authorization_pending" => {}
+            Err(e) if e.error == "slow_down" => interval += Duration::from_secs(5),
+            Err(e) => {
+                spinner.finish_and_clear();
+                return Err(Error::Login(describe(e)));
+            }
+        }
+    };
+    spinner.finish_and_clear();
+
+    let mut login = StoredLogin {
+        issuer: provider.issuer,
+        client_id: provider.client_id,
+        audience: provider.audience,
+        token_endpoint: discovery.token_endpoint,
+        token: String::new(),
+        refresh_token: None,
+    };
+    login.update(tokens)?;
+    save(&login)?;
+
+    eprintln!("{}", "Logged in successfully!".green());
+    if config.secret_key.is_some() {
+        eprintln!(
+            "The login is used instead of your stored key, `tunnelto set-auth` switches back."
+        );
+    }
+    Ok(())
+}
+
+/// Renew the stored login's token if it's what we authenticate with and it's about to expire
+pub async fn refresh(config: &mut Config) {
+    let mut login = match read() {
+        Some(login) if config.jwt.as_ref() == Some(&login.token) => login,
+        _ => return,
+    };
+    match expires_in(&login.token) {
+        Some(secs) if secs < RENEW_BEFORE_SECS => {}
+        _ => return,
+    }
+
+    let refresh_token = match login.refresh_token.clone() {
+        Some(refresh_token) => refresh_token,
+        None => {
+            eprintln!(
+                "{}",
+                "Your login expired, run `tunnelto login` again.".yellow()
+            );
+            return;
+        }
+    };
+    let form = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token.as_str()),
+        ("client_id", login.client_id.as_str()),
+    ];
+    let renewed = match post_form::<Tokens>(&login.token_endpoint, &form).await {
+        Ok(Ok(tokens)) => login.update(tokens).and_then(|_| save(&login)),
+        Ok(Err(e)) => Err(Error::Login(describe(e))),
+        Err(e) => Err(e),
+    };
+
+    match renewed {
+        Ok(()) => {
+            debug!("renewed login token");
+            config.jwt = Some(login.token);
+        }
+        Err(e) => eprintln!(
+            "{} {}, run `tunnelto login` again",
+            "Failed to renew your login:".yellow(),
+            e
+        ),
+    }
+}
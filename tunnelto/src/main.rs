@@ -12,11 +12,45 @@ use std::collections::HashMap;
 use std::env;
 use std::sync::{Arc, RwLock};
 
+mod access_log;
+mod alerts;
+mod api;
+mod approve;
+mod autodetect;
+mod check;
+mod claim;
+mod client_cert;
 mod config;
+mod control_socket;
+mod discover;
+mod doctor;
 mod error;
+mod exec;
+mod grant;
+mod history;
+mod hosts;
 mod introspect;
+mod keys;
 mod local;
+mod login;
+mod notify;
+mod openapi;
+mod ping;
+mod plugins;
+mod profile;
+mod retarget;
+mod route;
+mod shutdown;
+mod soak;
 mod spinner;
+mod status;
+mod stream_integrity;
+mod target;
+mod transcripts;
+mod ui;
+mod visitors;
+mod webhook;
+mod whatsnew;
 pub use self::error::*;
 
 pub use config::*;
@@ -28,6 +62,9 @@ use futures::future::Either;
 use std::time::Duration;
 use tokio::sync::Mutex;
 
+/// how many times we follow a busy server to another instance per connect
+const MAX_REDIRECTS: usize = 3;
+
 pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, UnboundedSender<StreamMessage>>>>;
 
 lazy_static::lazy_static! {
@@ -38,21 +75,142 @@ lazy_static::lazy_static! {
 #[derive(Debug, Clone)]
 pub enum StreamMessage {
     Data(Vec<u8>),
+    /// the server finished sending, check the local service got all of it
+    Trailer(stream_integrity::StreamChecksum),
     Close,
 }
 
 #[tokio::main]
 async fn main() {
-    setup_panic!();
+    // human-panic 1.x still names the hook's argument `PanicInfo`
+    #[allow(deprecated)]
+    {
+        setup_panic!();
+    }
 
     let mut config = match Config::get() {
         Ok(config) => config,
         Err(_) => return,
     };
 
+    login::refresh(&mut config).await;
+
+    if let Some(command) = config.command.clone() {
+        let result = match command {
+            Command::Claim { domain } => claim::claim_domain(&config, domain).await,
+            Command::Grant {
+                sub_domain,
+                grantee,
+                duration,
+            } => grant::grant(&config, sub_domain, grantee, duration).await,
+            Command::Approve { sub_domain } => approve::approve(&config, sub_domain).await,
+            Command::GuestKey {
+                duration,
+                sub_domain_prefix,
+                sub_domains,
+                label,
+            } => {
+                keys::create_guest_key(&config, duration, sub_domain_prefix, sub_domains, label)
+                    .await
+            }
+            Command::AddKey { label } => keys::add_key(&config, label).await,
+            Command::RotateKey => keys::rotate_key(&config).await,
+            Command::AddHost { domain, dev, yes } => hosts::add(&config, domain, dev, yes).await,
+            Command::CleanHosts { yes } => hosts::clean(yes),
+            Command::ListHosts => hosts::list(),
+            Command::Visitors { kick } => visitors::visitors(&config, kick).await,
+            Command::History => history::history(&config).await,
+            Command::Transcripts {
+                sub_domain,
+                since,
+                until,
+                enable,
+                output,
+            } => transcripts::transcripts(&config, sub_domain, since, until, enable, output).await,
+            Command::Doctor => doctor::doctor(&config).await,
+            Command::Ping { relays } => ping::ping(&config, relays).await,
+            Command::WhatsNew { all } => whatsnew::whatsnew(&config, all).await,
+            Command::Login {
+                issuer,
+                client_id,
+                audience,
+            } => login::login(&config, issuer, client_id, audience).await,
+            Command::Retarget { target, sub_domain } => {
+                retarget::retarget(target, sub_domain).await
+            }
+            Command::Route {
+                header,
+                target,
+                remove,
+                sub_domain,
+            } => route::route(header, target, remove, sub_domain).await,
+            Command::Status { sub_domain } => status::status(sub_domain).await,
+            Command::Check(check) => check::run_check(config.clone(), check).await,
+            Command::Discover { timeout } => discover::discover(timeout).await,
+            Command::Ui { dashboard } => ui::run_ui(dashboard).await,
+            Command::PrintConfig { redact } => {
+                config.print(redact);
+                Ok(())
+            }
+            Command::Scenario { file, target } => {
+                let target = target.unwrap_or_else(|| introspect::local_addr(&config));
+                introspect::scenario::run_scenario(file, target).await
+            }
+            Command::TestWebhook {
+                template,
+                url,
+                path,
+                secret,
+            } => {
+                let url = url.or_else(|| {
+                    config
+                        .sub_domain
+                        .as_ref()
+                        .map(|sub_domain| format!("{}{}", config.activation_url(sub_domain), path))
+                });
+                match url {
+                    Some(url) => webhook::send_test_webhook(template, url, secret).await,
+                    None => Err(Error::InvalidUrl(
+                        "pass --url or the --subdomain of your tunnel".to_string(),
+                    )),
+                }
+            }
+        };
+
+        if let Err(e) = result {
+            e.report();
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(exec) = config.exec.clone() {
+        if let Err(e) = exec::start(&config, exec).await {
+            e.report();
+            std::process::exit(1);
+        }
+    }
+
+    shutdown::spawn(&config);
+
+    if config.notify {
+        notify::enable();
+    }
+
     let introspect_addrs = introspect::start_introspection_server(config.clone());
+    control_socket::spawn(&config);
+
+    if config.soak {
+        soak::spawn();
+    }
 
     loop {
+        // the shutdown task exits once the streams under way finish
+        if shutdown::is_draining() {
+            futures::future::pending::<()>().await
+        }
+
+        login::refresh(&mut config).await;
         let (restart_tx, mut restart_rx) = unbounded();
         let wormhole = run_wormhole(config.clone(), introspect_addrs.clone(), restart_tx);
         let result = futures::future::select(Box::pin(wormhole), restart_rx.next()).await;
@@ -60,17 +218,44 @@ async fn main() {
 
         match result {
             Either::Left((Err(e), _)) => match e {
-                Error::WebSocketError(_) | Error::NoResponseFromServer | Error::Timeout => {
+                Error::WebSocketError(_)
+                | Error::NoResponseFromServer
+                | Error::Timeout
+                | Error::Tunnel(TunnelError::Internal(_)) => {
                     error!("Control error: {:?}. Retrying in 5 seconds.", e);
+                    notify::send(notify::Event::Disconnected {
+                        reason: e.to_string(),
+                    });
                     tokio::time::sleep(Duration::from_secs(5)).await;
                 }
+                Error::Tunnel(TunnelError::AuthFailed(_)) => {
+                    e.report();
+                    eprintln!(
+                        "If your key should be valid, run `tunnelto doctor` to check your clock isn't skewed."
+                    );
+                    notify::send(notify::Event::AuthFailed);
+                    return;
+                }
+                Error::Tunnel(TunnelError::KeyRejected(_)) => {
+                    e.report();
+                    notify::send(notify::Event::AuthFailed);
+                    return;
+                }
                 _ => {
-                    eprintln!("Error: {}", format!("{}", e).red());
+                    e.report();
+                    notify::send(notify::Event::Stopped {
+                        reason: e.to_string(),
+                    });
                     return;
                 }
             },
             Either::Right((Some(e), _)) => {
                 warn!("restarting in 3 seconds...from error: {:?}", e);
+                notify::send(notify::Event::Disconnected {
+                    reason: e
+                        .map(|e| e.to_string())
+                        .unwrap_or_else(|| "connection closed".to_string()),
+                });
                 tokio::time::sleep(Duration::from_secs(3)).await;
             }
             _ => {}
@@ -84,10 +269,26 @@ async fn main() {
 async fn run_wormhole(
     config: Config,
     introspect: IntrospectionAddrs,
-    mut restart_tx: UnboundedSender<Option<Error>>,
+    restart_tx: UnboundedSender<Option<Error>>,
 ) -> Result<(), Error> {
-    let websocket = connect_to_wormhole(&config).await?;
+    let wormhole = connect_to_wormhole(&config).await?;
+    introspect::set_public_url(&wormhole.public_url);
+    notify::send(notify::Event::Connected {
+        public_url: wormhole.public_url.clone(),
+    });
+    if config.advertise {
+        discover::advertise(&wormhole.public_url);
+    }
+    serve_wormhole(config, wormhole, introspect, restart_tx).await
+}
 
+/// Relay streams between an established tunnel and the local service until it closes
+async fn serve_wormhole(
+    config: Config,
+    wormhole: Wormhole,
+    introspect: IntrospectionAddrs,
+    mut restart_tx: UnboundedSender<Option<Error>>,
+) -> Result<(), Error> {
     if config.first_run {
         eprintln!(
             "Local Inspect Dashboard: {}{}",
@@ -96,37 +297,123 @@ async fn run_wormhole(
         );
     }
 
+    // passthrough streams skip the inspector and go straight to the local service
+    let local_addr = if config.tls_passthrough {
+        format!(
+            "{}:{}",
+            config.local_host,
+            config.local_port.as_deref().unwrap_or("443")
+        )
+    } else {
+        format!("localhost:{}", introspect.forward_address.port())
+    };
+
     // split reading and writing
-    let (mut ws_sink, mut ws_stream) = websocket.split();
+    let (mut ws_sink, mut ws_stream) = wormhole.websocket.split();
+    let (mut data_sinks, data_streams): (Vec<_>, Vec<_>) = wormhole
+        .data_websockets
+        .into_iter()
+        .map(|ws| ws.split())
+        .unzip();
 
     // tunnel channel
     let (tunnel_tx, mut tunnel_rx) = unbounded::<ControlPacket>();
 
+    // stream packets from the data connections are handled like control ones
+    for mut data_stream in data_streams {
+        let local_addr = local_addr.clone();
+        let tunnel_tx = tunnel_tx.clone();
+        let mut restart = restart_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let message = match data_stream.next().await {
+                    Some(Ok(message)) if !message.is_close() => message,
+                    _ => {
+                        warn!("data connection closed");
+                        let _ = restart.send(Some(Error::Timeout)).await;
+                        return;
+                    }
+                };
+
+                let result =
+                    process_control_flow_message(&local_addr, tunnel_tx.clone(), message.into_data())
+                        .await
+                        .map_err(|e| error!("Malformed protocol data packet: {:?}", e));
+                if result.is_err() {
+                    let _ = restart.send(Some(Error::MalformedMessageFromServer)).await;
+                    return;
+                }
+            }
+        });
+    }
+
     // continuously write to websocket tunnel
     let mut restart = restart_tx.clone();
     tokio::spawn(async move {
+        let mut pending = SendQueue::default();
+        let mut spreader = parallel_data::Spreader::default();
         loop {
-            let packet = match tunnel_rx.next().await {
-                Some(data) => data,
-                None => {
-                    warn!("control flow didn't send anything!");
-                    let _ = restart.send(Some(Error::Timeout)).await;
-                    return;
-                }
+            if pending.is_empty() {
+                match tunnel_rx.next().await {
+                    Some(data) => pending.push(data),
+                    None => {
+                        warn!("control flow didn't send anything!");
+                        let _ = restart.send(Some(Error::Timeout)).await;
+                        return;
+                    }
+                };
+            }
+
+            // pick up everything queued meanwhile so pings can skip ahead of bulk data
+            while let Ok(Some(packet)) = tunnel_rx.try_next() {
+                pending.push(packet);
+            }
+            soak::SEND_BACKLOG.store(pending.len(), std::sync::atomic::Ordering::Relaxed);
+
+            let packet = match pending.pop() {
+                Some(packet) => packet,
+                None => continue,
             };
 
-            if let Err(e) = ws_sink.send(Message::binary(packet.serialize())).await {
+            // pings and goodbyes stay on the control connection, stream packets use the
+            // data ones if open, spread over them if there are several
+            let goodbye = matches!(packet, ControlPacket::Goodbye);
+            let (sink, packet) = match packet {
+                ControlPacket::Ping(_) | ControlPacket::Goodbye => (&mut ws_sink, packet),
+                _ if data_sinks.is_empty() => (&mut ws_sink, packet),
+                packet => {
+                    let (connection, packet) = spreader.assign(packet, data_sinks.len());
+                    (&mut data_sinks[connection], packet)
+                }
+            };
+            if let Err(e) = sink.send(Message::binary(packet.serialize())).await {
                 warn!("failed to write message to tunnel websocket: {:?}", e);
-                let _ = restart.send(Some(Error::WebSocketError(e))).await;
+                let _ = restart.send(Some(e.into())).await;
                 return;
             }
+            if goodbye {
+                shutdown::GOODBYE_PENDING.store(false, std::sync::atomic::Ordering::SeqCst);
+            }
         }
     });
 
-    // continuously read from websocket tunnel
-
+    // continuously read from websocket tunnel, saying goodbye once we're shutting down;
+    // servers that can't take one free the sub-domain when we disconnect
+    let mut shutdown = Box::pin(shutdown::requested());
+    let mut said_goodbye = !wormhole.goodbye;
     loop {
-        match ws_stream.next().await {
+        let next = tokio::select! {
+            next = ws_stream.next() => next,
+            _ = &mut shutdown, if !said_goodbye => {
+                info!("shutting down, saying goodbye to the server");
+                said_goodbye = true;
+                shutdown::GOODBYE_PENDING.store(true, std::sync::atomic::Ordering::SeqCst);
+                let _ = tunnel_tx.clone().send(ControlPacket::Goodbye).await;
+                continue;
+            }
+        };
+
+        match next {
             Some(Ok(message)) if message.is_close() => {
                 debug!("got close message");
                 let _ = restart_tx.send(None).await;
@@ -134,7 +421,7 @@ async fn run_wormhole(
             }
             Some(Ok(message)) => {
                 let packet = process_control_flow_message(
-                    &introspect,
+                    &local_addr,
                     tunnel_tx.clone(),
                     message.into_data(),
                 )
@@ -144,6 +431,13 @@ async fn run_wormhole(
                     Error::MalformedMessageFromServer
                 })?;
                 debug!("Processed packet: {:?}", packet.packet_type());
+
+                // the server is being replaced: connect to its successor right away
+                if let ControlPacket::Handover = packet {
+                    info!("server is handing over, reconnecting");
+                    tokio::spawn(finish_wormhole(local_addr, tunnel_tx, ws_stream));
+                    return Ok(());
+                }
             }
             Some(Err(e)) => {
                 warn!("websocket read error: {:?}", e);
@@ -157,13 +451,46 @@ async fn run_wormhole(
     }
 }
 
-async fn connect_to_wormhole(
-    config: &Config,
-) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Error> {
+/// Keep serving the streams under way on a connection the server handed over from,
+/// until the server closes it
+async fn finish_wormhole(
+    local_addr: String,
+    tunnel_tx: UnboundedSender<ControlPacket>,
+    mut ws_stream: futures::stream::SplitStream<WebSocket>,
+) {
+    while let Some(Ok(message)) = ws_stream.next().await {
+        if message.is_close() {
+            break;
+        }
+        let result =
+            process_control_flow_message(&local_addr, tunnel_tx.clone(), message.into_data()).await;
+        if let Err(e) = result {
+            error!("Malformed protocol control packet: {:?}", e);
+            break;
+        }
+    }
+    debug!("handed over connection closed");
+}
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// An open tunnel
+pub struct Wormhole {
+    websocket: WebSocket,
+    /// secondary connections for stream packets, see `DataHello`
+    data_websockets: Vec<WebSocket>,
+    /// the server frees our sub-domain when we say goodbye
+    goodbye: bool,
+    /// primary public url
+    pub public_url: String,
+}
+
+/// Open the tunnel
+async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
     let spinner = if config.first_run {
         eprintln!(
             "{}\n\n",
-            format!("{}", include_str!("../static/img/wormhole_ascii.txt")).green()
+            include_str!("../static/img/wormhole_ascii.txt").green()
         );
         Some(spinner::new_spinner(
             "initializing remote tunnel, please stand by",
@@ -172,14 +499,26 @@ async fn connect_to_wormhole(
         None
     };
 
-    let (mut websocket, _) = tokio_tungstenite::connect_async(&config.control_url).await?;
+    // send our Client Hello message, a certificate given stands in for stored credentials
+    let client_type = match (config.jwt.clone(), config.secret_key.clone()) {
+        _ if config.client_cert.is_some() => Some(ClientType::Certificate),
+        (Some(token), _) => Some(ClientType::Jwt { token }),
+        // the key never leaves us, we sign the server's challenge with it instead
+        (None, Some(key)) if config.sign_hello => Some(ClientType::SignedAuth {
+            key_id: hello_signature::key_id(&key.0),
+        }),
+        (None, Some(key)) => Some(ClientType::Auth { key }),
+        (None, None) => None,
+    };
 
-    // send our Client Hello message
-    let client_hello = match config.secret_key.clone() {
-        Some(secret_key) => ClientHello::generate(
-            config.sub_domain.clone(),
-            ClientType::Auth { key: secret_key },
-        ),
+    let mut client_hello = match client_type {
+        Some(client_type) => {
+            let mut hello = ClientHello::generate(config.sub_domain.clone(), client_type);
+            hello.base_domain = config.base_domain.clone();
+            // gets us back the sub-domain the server picked, along with our key
+            hello.reconnect_token = RECONNECT_TOKEN.lock().await.clone();
+            hello
+        }
         None => {
             // if we have a reconnect token, use it.
             if let Some(reconnect) = RECONNECT_TOKEN.lock().await.clone() {
@@ -190,42 +529,150 @@ async fn connect_to_wormhole(
         }
     };
 
-    info!("connecting to wormhole...");
+    if config.tls_passthrough {
+        client_hello.tunnel_type = TunnelType::TlsPassthrough;
+    }
+    client_hello.signing_secret = config.signing_secret.clone();
+    client_hello.access_rules = config.access_rules.clone();
+    client_hello.rate_limits = config.rate_limits.clone();
+    client_hello.error_format = config.error_format;
+    client_hello.standby = config.standby;
+    client_hello.catch_all = config.catch_all;
+    client_hello.traffic_profile = config.traffic_profile;
+    client_hello.integrity = config.verify_integrity;
+    client_hello.access_log = config.access_log;
+    client_hello.handover = true;
+    client_hello.target = Some(introspect::current_local_addr());
+    client_hello.client_hostname = hostname::get()
+        .ok()
+        .and_then(|hostname| hostname.into_string().ok());
 
-    let hello = serde_json::to_vec(&client_hello).unwrap();
-    websocket
-        .send(Message::binary(hello))
-        .await
-        .expect("Failed to send client hello to wormhole server.");
+    info!("connecting to wormhole...");
 
-    // wait for Server hello
-    let server_hello_data = websocket
-        .next()
-        .await
-        .ok_or(Error::NoResponseFromServer)??
-        .into_data();
-    let server_hello = serde_json::from_slice::<ServerHello>(&server_hello_data).map_err(|e| {
-        error!("Couldn't parse server_hello from {:?}", e);
-        Error::ServerReplyInvalid
-    })?;
+    // busy servers may send us to another instance
+    let mut control_url = config.control_url.clone();
+    let mut redirects = 0;
+    let signing_key = config.secret_key.as_ref().filter(|_| config.sign_hello);
+    let (websocket, server_hello) = loop {
+        match send_client_hello(
+            &control_url,
+            &client_hello,
+            signing_key,
+            config.client_cert.as_ref(),
+        )
+        .await?
+        {
+            (_, ServerHello::Redirect { endpoint }) if redirects < MAX_REDIRECTS => {
+                info!("server redirected us to {}", &endpoint);
+                redirects += 1;
+                control_url = endpoint;
+            }
+            result => break result,
+        }
+    };
 
-    let sub_domain = match server_hello {
+    let (sub_domain, public_urls, data_token, goodbye) = match server_hello {
         ServerHello::Success {
             sub_domain,
             client_id,
-            ..
+            public_urls,
+            features,
+            limits,
+            reconnect_token,
+            data_token,
+            capabilities,
+            deprecations,
+            awaiting_approval,
         } => {
             info!("Server accepted our connection. I am client_{}", client_id);
-            sub_domain
-        }
-        ServerHello::AuthFailed => {
-            return Err(Error::AuthenticationFailed);
-        }
-        ServerHello::InvalidSubDomain => {
-            return Err(Error::InvalidSubDomain);
+            debug!(
+                "server features: {:?}, capabilities: {:?}, limits: {:?}",
+                features, capabilities, limits
+            );
+
+            if config.first_run {
+                for deprecation in deprecations {
+                    eprintln!("{} {}", "Deprecated:".yellow(), deprecation);
+                }
+                whatsnew::notice(&features);
+            }
+
+            // held again on every reconnect
+            if awaiting_approval {
+                eprintln!(
+                    "{} your account's policy holds this tunnel until a teammate runs `tunnelto approve {}`",
+                    "Awaiting approval:".yellow(),
+                    sub_domain
+                );
+            }
+
+            if let Some(reconnect) = reconnect_token {
+                let _ = RECONNECT_TOKEN.lock().await.replace(reconnect);
+            }
+
+            // older servers don't send urls, assume the default layout
+            let public_urls = if public_urls.is_empty() {
+                vec![config.activation_url(&sub_domain)]
+            } else {
+                public_urls
+            };
+
+            if config.standby {
+                if !features.iter().any(|f| f == features::STANDBY) {
+                    warn!("the server doesn't support standbys, this client serves the sub-domain");
+                } else if config.first_run {
+                    eprintln!("{}", "Standing by: traffic comes here while the primary client is down.".yellow());
+                }
+            }
+
+            if config.catch_all && !features.iter().any(|f| f == features::CATCH_ALL) {
+                warn!("the server doesn't support catch-all tunnels, this client serves a single sub-domain");
+            }
+
+            if config.traffic_profile != TrafficProfile::General
+                && !features.iter().any(|f| f == features::TRAFFIC_PROFILE)
+            {
+                warn!("the server doesn't tune for traffic profiles, using its defaults");
+            }
+
+            if !config.rate_limits.is_empty() && !features.iter().any(|f| f == features::RATE_LIMITS) {
+                warn!("the server doesn't enforce rate limits, visitors aren't held to them");
+            }
+
+            if config.access_log && !features.iter().any(|f| f == features::ACCESS_LOG) {
+                warn!("the server doesn't stream access logs, only requests reaching us are shown");
+            }
+
+            if config.verify_integrity {
+                if features.iter().any(|f| f == features::INTEGRITY) {
+                    crate::stream_integrity::enable();
+                } else {
+                    warn!("the server can't check stream integrity, streams go unchecked");
+                }
+            }
+
+            let data_connections = if features.iter().any(|f| f == features::PARALLEL_DATA) {
+                config.data_connections
+            } else {
+                if config.data_connections > 1 {
+                    warn!("the server takes a single data connection, using one");
+                }
+                config.data_connections.min(1)
+            };
+            let data_token = data_token
+                .filter(|_| features.iter().any(|f| f == features::DATA_CONNECTION))
+                .map(|token| (token, data_connections));
+            let goodbye = features.iter().any(|f| f == features::GOODBYE);
+            if features.iter().any(|f| f == features::STREAM_END) {
+                local::send_end();
+            }
+            (sub_domain, public_urls, data_token, goodbye)
         }
-        ServerHello::SubDomainInUse => {
-            return Err(Error::SubDomainInUse);
+        other => {
+            return Err(other
+                .error()
+                .map(Error::Tunnel)
+                .unwrap_or(Error::ServerReplyInvalid));
         }
     };
 
@@ -235,10 +682,14 @@ async fn connect_to_wormhole(
         if let Some(pb) = spinner {
             pb.finish_with_message(&format!(
                 "Success! Remote tunnel created on: {}",
-                &config.activation_url(&sub_domain).bold().green()
+                &public_urls[0].bold().green()
             ));
         }
 
+        for url in public_urls.iter().skip(1) {
+            eprintln!("{} Also available on: {}", "=>".green(), url.bold().green());
+        }
+
         if config.sub_domain.is_some() && (config.sub_domain.as_ref() != Some(&sub_domain)) {
             eprintln!("{}",
                       ">>> Notice: to access the full sub-domain feature, get your a free authentication key at https://dashboard.tunnelto.dev.".yellow());
@@ -246,6 +697,7 @@ async fn connect_to_wormhole(
 
         let p = match (config.scheme.as_str(), config.local_port.as_ref()) {
             (_, Some(p)) => format!(":{}", p),
+            (_, None) if config.tls_passthrough => ":443".to_string(),
             ("http", None) => ":8000".to_string(),
             (_, _) => "".to_string(),
         };
@@ -259,11 +711,107 @@ async fn connect_to_wormhole(
         );
     }
 
-    Ok(websocket)
+    let mut data_websockets = vec![];
+    if let Some((token, count)) = data_token {
+        while data_websockets.len() < count {
+            match open_data_connection(&control_url, token.clone()).await {
+                Some(websocket) => data_websockets.push(websocket),
+                None => break,
+            }
+        }
+    }
+
+    Ok(Wormhole {
+        websocket,
+        data_websockets,
+        goodbye,
+        public_url: public_urls[0].clone(),
+    })
+}
+
+/// Open the secondary connection stream packets travel over, falling back to
+/// the control connection if the server won't have it
+async fn open_data_connection(control_url: &str, token: String) -> Option<WebSocket> {
+    let result = async {
+        let (mut websocket, _) =
+            tokio_tungstenite::connect_async(format!("{}/data", control_url)).await?;
+
+        let hello = serde_json::to_vec(&DataHello { token }).unwrap_or_default();
+        websocket.send(Message::binary(hello)).await?;
+
+        let reply = websocket
+            .next()
+            .await
+            .ok_or(Error::NoResponseFromServer)??
+            .into_data();
+        match serde_json::from_slice::<DataHelloReply>(&reply) {
+            Ok(DataHelloReply::Accepted) => Ok(websocket),
+            _ => Err(Error::ServerReplyInvalid),
+        }
+    }
+    .await;
+
+    match result {
+        Ok(websocket) => {
+            info!("opened data connection");
+            Some(websocket)
+        }
+        Err(e) => {
+            warn!("data connection failed, using the control connection: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Connect to the control server, send our hello and wait for its reply, signing the
+/// hello with our key if the server challenges us to
+async fn send_client_hello(
+    control_url: &str,
+    client_hello: &ClientHello,
+    signing_key: Option<&SecretKey>,
+    client_cert: Option<&client_cert::ClientCert>,
+) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, ServerHello), Error> {
+    let mut websocket = client_cert::connect(control_url, client_cert).await?;
+
+    let hello = serde_json::to_vec(client_hello).unwrap();
+    websocket
+        .send(Message::binary(hello.clone()))
+        .await
+        .expect("Failed to send client hello to wormhole server.");
+
+    let server_hello = match (receive_server_hello(&mut websocket).await?, signing_key) {
+        (ServerHello::Challenge { challenge }, Some(key)) => {
+            let proof = hello_signature::sign(&key.0, &challenge, &hello);
+            let proof = serde_json::to_vec(&proof).unwrap_or_default();
+            websocket.send(Message::binary(proof)).await?;
+            receive_server_hello(&mut websocket).await?
+        }
+        (ServerHello::Challenge { .. }, None) => {
+            error!("the server challenged a hello we didn't sign");
+            return Err(Error::ServerReplyInvalid);
+        }
+        (server_hello, _) => server_hello,
+    };
+
+    Ok((websocket, server_hello))
+}
+
+async fn receive_server_hello(
+    websocket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+) -> Result<ServerHello, Error> {
+    let server_hello_data = websocket
+        .next()
+        .await
+        .ok_or(Error::NoResponseFromServer)??
+        .into_data();
+    serde_json::from_slice::<ServerHello>(&server_hello_data).map_err(|e| {
+        error!("Couldn't parse server_hello from {:?}", e);
+        Error::ServerReplyInvalid
+    })
 }
 
 async fn process_control_flow_message(
-    introspect: &IntrospectionAddrs,
+    local_addr: &str,
     mut tunnel_tx: UnboundedSender<ControlPacket>,
     payload: Vec<u8>,
 ) -> Result<ControlPacket, Box<dyn std::error::Error>> {
@@ -281,7 +829,9 @@ async fn process_control_flow_message(
             }
             let _ = tunnel_tx.send(ControlPacket::Ping(None)).await;
         }
-        ControlPacket::Refused(_) => return Err("unexpected control packet".into()),
+        ControlPacket::Refused(_) | ControlPacket::Goodbye | ControlPacket::Sequenced(..) => {
+            return Err("unexpected control packet".into())
+        }
         ControlPacket::End(stream_id) => {
             // find the stream
             let stream_id = stream_id.clone();
@@ -299,6 +849,14 @@ async fn process_control_flow_message(
                 }
             });
         }
+        ControlPacket::Trailer(stream_id, checksum) => {
+            let stream = ACTIVE_STREAMS.read().unwrap().get(stream_id).cloned();
+            if let Some(mut tx) = stream {
+                let _ = tx.send(StreamMessage::Trailer(*checksum)).await;
+            }
+        }
+        ControlPacket::AccessLog(entry) => access_log::print(entry),
+        ControlPacket::Handover => {}
         ControlPacket::Data(stream_id, data) => {
             info!(
                 "stream[{:?}] -> new data: {:?}",
@@ -306,9 +864,15 @@ async fn process_control_flow_message(
                 data.len()
             );
 
-            if !ACTIVE_STREAMS.read().unwrap().contains_key(&stream_id) {
+            let is_new = !ACTIVE_STREAMS.read().unwrap().contains_key(stream_id);
+            if is_new && shutdown::is_draining() {
+                info!("shutting down, refusing stream {}", stream_id.to_string());
+                let _ = tunnel_tx.send(ControlPacket::Refused(stream_id.clone())).await;
+                return Ok(control_packet.clone());
+            }
+            if is_new {
                 local::setup_new_stream(
-                    introspect.forward_address.port(),
+                    local_addr,
                     tunnel_tx.clone(),
                     stream_id.clone(),
                 )
@@ -316,7 +880,7 @@ async fn process_control_flow_message(
             }
 
             // find the right stream
-            let active_stream = ACTIVE_STREAMS.read().unwrap().get(&stream_id).cloned();
+            let active_stream = ACTIVE_STREAMS.read().unwrap().get(stream_id).cloned();
 
             // forward data to it
             if let Some(mut tx) = active_stream {
@@ -324,7 +888,7 @@ async fn process_control_flow_message(
                 info!("forwarded to local tcp ({})", stream_id.to_string());
             } else {
                 error!("got data but no stream to send it to.");
-                let _ = tunnel_tx
+                tunnel_tx
                     .send(ControlPacket::Refused(stream_id.clone()))
                     .await?;
             }
@@ -0,0 +1,82 @@
+use crate::introspect::{Middleware, MiddlewareResult, Next, ProxyRequest};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static::lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref CONNECTED: AtomicBool = AtomicBool::new(false);
+}
+
+/// Changes worth a desktop notification when the tunnel runs in the background
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connected { public_url: String },
+    Disconnected { reason: String },
+    AuthFailed,
+    /// the client gave up and exited
+    Stopped { reason: String },
+    FirstRequest { method: String, path: String },
+    Alert { message: String },
+}
+
+/// Turn on notifications (`--notify`)
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn send(event: Event) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    // only report a disconnect once, not every failed reconnect
+    match &event {
+        Event::Connected { .. } => CONNECTED.store(true, Ordering::SeqCst),
+        Event::Disconnected { .. } if !CONNECTED.swap(false, Ordering::SeqCst) => return,
+        _ => {}
+    }
+
+    let body = match event {
+        Event::Connected { public_url } => format!("Tunnel is up on {}", public_url),
+        Event::Disconnected { reason } => format!("Tunnel disconnected, reconnecting: {}", reason),
+        Event::AuthFailed => "The server rejected your authentication key".to_string(),
+        Event::Stopped { reason } => format!("Tunnel stopped: {}", reason),
+        Event::FirstRequest { method, path } => format!("First request: {} {}", method, path),
+        Event::Alert { message } => message,
+    };
+
+    // showing a notification blocks on the desktop's notification service
+    tokio::task::spawn_blocking(move || {
+        let result = notify_rust::Notification::new()
+            .summary("tunnelto")
+            .body(&body)
+            .show();
+        if let Err(e) = result {
+            log::debug!("failed to show desktop notification: {:?}", e);
+        }
+    });
+}
+
+/// Notifies about the first request through the tunnel
+pub struct NotifyFirstRequest {
+    seen: AtomicBool,
+}
+
+impl NotifyFirstRequest {
+    pub fn new() -> Self {
+        NotifyFirstRequest {
+            seen: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Middleware for NotifyFirstRequest {
+    fn handle<'a>(&'a self, request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a> {
+        if !self.seen.swap(true, Ordering::SeqCst) {
+            send(Event::FirstRequest {
+                method: request.method.to_string(),
+                path: request.path.clone(),
+            });
+        }
+        next.run(request)
+    }
+}
@@ -0,0 +1,600 @@
+//! Check requests against an OpenAPI 3 spec (`--openapi spec.yaml`) on their way to the
+//! local service. Violations are flagged in the inspector, and with `--openapi-reject`
+//! answered with a 422 instead of being forwarded.
+//!
+//! Only what a request can get wrong is checked: its path and method, its parameters,
+//! and its body's content type and schema (types, required properties, enums, lengths
+//! and ranges, `$ref`s, `allOf`/`anyOf`/`oneOf`). Formats, patterns and responses aren't.
+use crate::introspect::{
+    Findings, Middleware, MiddlewareResult, Next, ProxyRequest, ProxyResponse,
+};
+use colored::Colorize;
+use futures::FutureExt;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use warp::http::StatusCode;
+
+/// violations reported per request, the rest are counted
+const MAX_VIOLATIONS: usize = 20;
+
+/// `$ref`s and nested schemas followed before giving up, in case they loop
+const MAX_DEPTH: usize = 32;
+
+const METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `{id}`
+    Param(String),
+}
+
+#[derive(Debug)]
+pub struct OpenApiSpec {
+    /// the file it was read from
+    pub source: String,
+    root: Value,
+    /// the path of the first server's url, i.e. `/v1`, left off request paths
+    base_path: String,
+    /// path templates, split in segments
+    paths: Vec<(String, Vec<Segment>)>,
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+impl OpenApiSpec {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(path, &text)
+    }
+
+    /// Parse a spec, in YAML or JSON
+    pub fn parse(source: &str, text: &str) -> Result<Self, String> {
+        let root: Value = serde_yaml::from_str(text).map_err(|e| e.to_string())?;
+
+        match root.get("openapi").and_then(Value::as_str) {
+            Some(version) if version.starts_with('3') => {}
+            Some(version) => return Err(format!("OpenAPI {} isn't supported, only 3.x", version)),
+            None => return Err("not an OpenAPI 3 spec, `openapi` is missing".to_string()),
+        }
+
+        let paths = root
+            .get("paths")
+            .and_then(Value::as_object)
+            .ok_or("the spec has no `paths`")?
+            .keys()
+            .map(|template| {
+                let parsed = segments(template)
+                    .map(|segment| {
+                        match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                            Some(name) => Segment::Param(name.to_string()),
+                            None => Segment::Literal(segment.to_string()),
+                        }
+                    })
+                    .collect();
+                (template.clone(), parsed)
+            })
+            .collect();
+
+        let base_path = root
+            .pointer("/servers/0/url")
+            .and_then(Value::as_str)
+            .map(|url| match url.split_once("://") {
+                Some((_, rest)) => rest.find('/').map_or("", |i| &rest[i..]),
+                None => url,
+            })
+            .unwrap_or_default()
+            .trim_end_matches('/')
+            .to_string();
+
+        Ok(OpenApiSpec {
+            source: source.to_string(),
+            root,
+            base_path,
+            paths,
+        })
+    }
+
+    /// Follow `$ref`s into the spec
+    fn resolve<'a>(&'a self, mut value: &'a Value) -> &'a Value {
+        for _ in 0..MAX_DEPTH {
+            let pointer = match value.get("$ref").and_then(Value::as_str) {
+                Some(reference) => reference.trim_start_matches('#'),
+                None => break,
+            };
+            match self.root.pointer(pointer) {
+                Some(target) => value = target,
+                None => break,
+            }
+        }
+        value
+    }
+
+    /// The path template matching the path and its parameters, preferring templates
+    /// with more literal segments, so `/users/me` wins over `/users/{id}`
+    fn find_path(&self, path: &str) -> Option<(&str, HashMap<String, String>)> {
+        let path = segments(path).collect::<Vec<_>>();
+
+        self.paths
+            .iter()
+            .filter(|(_, template)| template.len() == path.len())
+            .filter_map(|(name, template)| {
+                let mut params = HashMap::new();
+                for (segment, actual) in template.iter().zip(&path) {
+                    match segment {
+                        Segment::Literal(literal) if literal == actual => {}
+                        Segment::Literal(_) => return None,
+                        Segment::Param(param) => {
+                            params.insert(param.clone(), actual.to_string());
+                        }
+                    }
+                }
+                let literals = template
+                    .iter()
+                    .filter(|s| matches!(s, Segment::Literal(_)))
+                    .count();
+                Some((literals, name.as_str(), params))
+            })
+            .max_by_key(|(literals, _, _)| *literals)
+            .map(|(_, name, params)| (name, params))
+    }
+
+    /// What's wrong with the request, nothing if it matches the spec
+    pub fn validate(&self, request: &ProxyRequest) -> Vec<String> {
+        let path = request
+            .path
+            .strip_prefix(self.base_path.as_str())
+            .filter(|_| !self.base_path.is_empty())
+            .unwrap_or(&request.path);
+
+        let (template, path_params) = match self.find_path(path) {
+            Some(found) => found,
+            None => return vec![format!("no path in the spec matches {}", path)],
+        };
+        let item = self.resolve(&self.root["paths"][template]);
+
+        let method = request.method.as_str().to_lowercase();
+        let operation = match item
+            .get(&method)
+            .filter(|_| METHODS.contains(&method.as_str()))
+        {
+            Some(operation) => operation,
+            None => return vec![format!("{} isn't allowed on {}", request.method, template)],
+        };
+
+        let mut violations = vec![];
+        let query: Vec<(String, String)> = request
+            .query
+            .as_deref()
+            .and_then(|query| serde_urlencoded::from_str(query).ok())
+            .unwrap_or_default();
+
+        for parameter in self.parameters(item, operation) {
+            let name = parameter
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let location = parameter
+                .get("in")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let required = parameter
+                .get("required")
+                .and_then(Value::as_bool)
+                .unwrap_or(false)
+                || location == "path";
+
+            let values: Vec<String> = match location {
+                "path" => path_params.get(name).cloned().into_iter().collect(),
+                "query" => query
+                    .iter()
+                    .filter(|(key, _)| key == name)
+                    .map(|(_, value)| value.clone())
+                    .collect(),
+                "header" => request
+                    .headers
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .map(String::from)
+                    .collect(),
+                // cookies and anything newer aren't checked
+                _ => continue,
+            };
+
+            if values.is_empty() {
+                if required {
+                    violations.push(format!(
+                        "missing required {} parameter `{}`",
+                        location, name
+                    ));
+                }
+                continue;
+            }
+
+            if let Some(schema) = parameter.get("schema") {
+                let value = self.parameter_value(&values, schema);
+                let at = format!("{} parameter `{}`", location, name);
+                self.check(&value, schema, &at, 0, &mut violations);
+            }
+        }
+
+        if let Some(body) = operation.get("requestBody") {
+            self.check_body(self.resolve(body), request, &mut violations);
+        }
+
+        if violations.len() > MAX_VIOLATIONS {
+            let more = violations.len() - MAX_VIOLATIONS;
+            violations.truncate(MAX_VIOLATIONS);
+            violations.push(format!("and {} more", more));
+        }
+        violations
+    }
+
+    /// The operation's parameters, overriding those of its path with the same name and
+    /// location
+    fn parameters<'a>(&'a self, item: &'a Value, operation: &'a Value) -> Vec<&'a Value> {
+        let list = |value: &'a Value| {
+            value
+                .get("parameters")
+                .and_then(Value::as_array)
+                .map(|parameters| parameters.iter().map(|p| self.resolve(p)).collect())
+                .unwrap_or_default()
+        };
+        let key =
+            |parameter: &Value| (parameter.get("name").cloned(), parameter.get("in").cloned());
+
+        let mut parameters: Vec<&Value> = list(operation);
+        for parameter in list(item) {
+            if !parameters.iter().any(|p| key(p) == key(parameter)) {
+                parameters.push(parameter);
+            }
+        }
+        parameters
+    }
+
+    /// A parameter's text as the json its schema expects, so it can be checked like a body
+    fn parameter_value(&self, values: &[String], schema: &Value) -> Value {
+        let schema = self.resolve(schema);
+        if schema_type(schema) == Some("array") {
+            let items = schema.get("items").unwrap_or(&Value::Null);
+            // `?id=1&id=2`, or `?id=1,2`
+            let values = match values {
+                [single] => single.split(',').map(|v| v.to_string()).collect(),
+                values => values.to_vec(),
+            };
+            return Value::Array(values.iter().map(|v| self.scalar(v, items)).collect());
+        }
+        self.scalar(&values[0], schema)
+    }
+
+    /// Text as the scalar its schema expects, or a string if it isn't one
+    fn scalar(&self, text: &str, schema: &Value) -> Value {
+        let parsed = match schema_type(self.resolve(schema)) {
+            Some("integer") | Some("number") => serde_json::from_str::<serde_json::Number>(text)
+                .ok()
+                .map(Value::Number),
+            Some("boolean") => text.parse().ok().map(Value::Bool),
+            _ => None,
+        };
+        parsed.unwrap_or_else(|| Value::String(text.to_string()))
+    }
+
+    fn check_body(&self, body: &Value, request: &ProxyRequest, violations: &mut Vec<String>) {
+        if request.body.is_empty() {
+            if body
+                .get("required")
+                .and_then(Value::as_bool)
+                .unwrap_or(false)
+            {
+                violations.push("missing required request body".to_string());
+            }
+            return;
+        }
+
+        let content = match body.get("content").and_then(Value::as_object) {
+            Some(content) => content,
+            None => return,
+        };
+        let content_type = request
+            .headers
+            .get(warp::http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_lowercase())
+            .unwrap_or_default();
+
+        let media = match media_type(content, &content_type) {
+            Some(media) => media,
+            None => {
+                violations.push(format!(
+                    "content type `{}` isn't accepted, expected {}",
+                    content_type,
+                    content.keys().cloned().collect::<Vec<_>>().join(", ")
+                ));
+                return;
+            }
+        };
+        let schema = match media.get("schema") {
+            Some(schema) => schema,
+            None => return,
+        };
+
+        if content_type.ends_with("json") {
+            match serde_json::from_slice::<Value>(&request.body) {
+                Ok(value) => self.check(&value, schema, "body", 0, violations),
+                Err(e) => violations.push(format!("body isn't valid json: {}", e)),
+            }
+        } else if content_type == "application/x-www-form-urlencoded" {
+            let fields: Vec<(String, String)> =
+                serde_urlencoded::from_bytes(&request.body).unwrap_or_default();
+            let properties = self.resolve(schema).get("properties");
+
+            let mut form = Map::new();
+            for (name, value) in fields {
+                let property = properties
+                    .and_then(|p| p.get(&name))
+                    .unwrap_or(&Value::Null);
+                form.insert(name, self.scalar(&value, property));
+            }
+            self.check(&Value::Object(form), schema, "body", 0, violations);
+        }
+    }
+
+    /// Check a value against a schema, reporting problems as found `at` the value
+    fn check(&self, value: &Value, schema: &Value, at: &str, depth: usize, out: &mut Vec<String>) {
+        if depth > MAX_DEPTH {
+            return;
+        }
+        let schema = self.resolve(schema);
+        let matches = |schema: &Value| {
+            let mut problems = vec![];
+            self.check(value, schema, at, depth + 1, &mut problems);
+            problems.is_empty()
+        };
+
+        if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+            for schema in all {
+                self.check(value, schema, at, depth + 1, out);
+            }
+        }
+        for keyword in &["anyOf", "oneOf"] {
+            if let Some(any) = schema.get(*keyword).and_then(Value::as_array) {
+                if !any.iter().any(&matches) {
+                    out.push(format!("{} matches none of the allowed schemas", at));
+                }
+            }
+        }
+
+        if value.is_null() && is_nullable(schema) {
+            return;
+        }
+        if let Some(expected) = schema_types(schema) {
+            if !expected.iter().any(|t| has_type(value, t)) {
+                out.push(format!("{} must be {}", at, expected.join(" or ")));
+                return;
+            }
+        }
+
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                let allowed = allowed.iter().map(Value::to_string).collect::<Vec<_>>();
+                out.push(format!("{} must be one of {}", at, allowed.join(", ")));
+            }
+        }
+
+        match value {
+            Value::String(s) => {
+                let len = s.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                    if len < min {
+                        out.push(format!("{} must be at least {} characters", at, min));
+                    }
+                }
+                if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                    if len > max {
+                        out.push(format!("{} must be at most {} characters", at, max));
+                    }
+                }
+            }
+            Value::Number(n) => check_range(n.as_f64().unwrap_or_default(), schema, at, out),
+            Value::Array(items) => {
+                let len = items.len() as u64;
+                if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+                    if len < min {
+                        out.push(format!("{} must have at least {} items", at, min));
+                    }
+                }
+                if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+                    if len > max {
+                        out.push(format!("{} must have at most {} items", at, max));
+                    }
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        let at = format!("{}[{}]", at, i);
+                        self.check(item, item_schema, &at, depth + 1, out);
+                    }
+                }
+            }
+            Value::Object(object) => self.check_object(object, schema, at, depth, out),
+            _ => {}
+        }
+    }
+
+    fn check_object(
+        &self,
+        object: &Map<String, Value>,
+        schema: &Value,
+        at: &str,
+        depth: usize,
+        out: &mut Vec<String>,
+    ) {
+        let properties = schema.get("properties").and_then(Value::as_object);
+
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    out.push(format!("{} is missing `{}`", at, name));
+                }
+            }
+        }
+
+        for (name, value) in object {
+            let at = format!("{}.{}", at, name);
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property) => self.check(value, property, &at, depth + 1, out),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => out.push(format!("{} isn't allowed", at)),
+                    Some(extra @ Value::Object(_)) => self.check(value, extra, &at, depth + 1, out),
+                    _ => {}
+                },
+            }
+        }
+    }
+}
+
+/// The media type in `content` for a request's content type: exact, then `type/*`,
+/// then `*/*`
+fn media_type<'a>(content: &'a Map<String, Value>, content_type: &str) -> Option<&'a Value> {
+    let wildcard = content_type
+        .split('/')
+        .next()
+        .map(|kind| format!("{}/*", kind))
+        .unwrap_or_default();
+
+    content
+        .iter()
+        .find(|(name, _)| name.to_lowercase() == content_type)
+        .or_else(|| content.iter().find(|(name, _)| **name == wildcard))
+        .or_else(|| content.iter().find(|(name, _)| *name == "*/*"))
+        .map(|(_, media)| media)
+}
+
+fn schema_type(schema: &Value) -> Option<&str> {
+    schema.get("type").and_then(Value::as_str)
+}
+
+/// The types a schema allows: `type: string`, or `type: [string, "null"]` in 3.1
+fn schema_types(schema: &Value) -> Option<Vec<&str>> {
+    match schema.get("type")? {
+        Value::String(t) => Some(vec![t.as_str()]),
+        Value::Array(types) => Some(types.iter().filter_map(Value::as_str).collect()),
+        _ => None,
+    }
+}
+
+fn is_nullable(schema: &Value) -> bool {
+    schema
+        .get("nullable")
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+        || schema_types(schema).is_some_and(|types| types.contains(&"null"))
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match (expected, value) {
+        ("string", Value::String(_)) => true,
+        ("number", Value::Number(_)) => true,
+        ("integer", Value::Number(n)) => {
+            n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|f| f.fract() == 0.0)
+        }
+        ("boolean", Value::Bool(_)) => true,
+        ("array", Value::Array(_)) => true,
+        ("object", Value::Object(_)) => true,
+        ("null", Value::Null) => true,
+        _ => false,
+    }
+}
+
+/// `minimum`/`maximum`, with 3.0's boolean `exclusiveMinimum`/`exclusiveMaximum` or
+/// 3.1's numeric ones
+fn check_range(n: f64, schema: &Value, at: &str, out: &mut Vec<String>) {
+    let exclusive = |name: &str| schema.get(name).and_then(Value::as_bool).unwrap_or(false);
+
+    if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+        if n < min || (exclusive("exclusiveMinimum") && n == min) {
+            out.push(format!("{} must be at least {}", at, min));
+        }
+    }
+    if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+        if n > max || (exclusive("exclusiveMaximum") && n == max) {
+            out.push(format!("{} must be at most {}", at, max));
+        }
+    }
+    if let Some(min) = schema.get("exclusiveMinimum").and_then(Value::as_f64) {
+        if n <= min {
+            out.push(format!("{} must be more than {}", at, min));
+        }
+    }
+    if let Some(max) = schema.get("exclusiveMaximum").and_then(Value::as_f64) {
+        if n >= max {
+            out.push(format!("{} must be less than {}", at, max));
+        }
+    }
+}
+
+/// Checks requests against the spec, flagging violations for the inspector or, with
+/// `reject`, answering them with a 422
+pub struct ValidateRequests {
+    pub spec: Arc<OpenApiSpec>,
+    pub reject: bool,
+}
+
+impl ValidateRequests {
+    fn unprocessable(request: &ProxyRequest, violations: Vec<String>) -> ProxyResponse {
+        let request_id = request.request_id();
+        let body = json!({
+            "status": 422,
+            "reason": "openapi_violation",
+            "message": "The request doesn't match the API's OpenAPI spec",
+            "request_id": request_id,
+            "violations": violations,
+        });
+
+        let mut headers = warp::http::HeaderMap::new();
+        headers.insert(
+            warp::http::header::CONTENT_TYPE,
+            warp::http::HeaderValue::from_static("application/json"),
+        );
+        let mut response = ProxyResponse::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            headers,
+            body.to_string().into_bytes(),
+        );
+        Findings::of(&mut response).violations = violations;
+        response
+    }
+}
+
+impl Middleware for ValidateRequests {
+    fn handle<'a>(&'a self, request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a> {
+        async move {
+            let violations = self.spec.validate(&request);
+            if violations.is_empty() {
+                return next.run(request).await;
+            }
+
+            eprintln!(
+                "{} {} {}: {}",
+                "OpenAPI violation".yellow(),
+                request.method,
+                request.path_and_query(),
+                violations.join("; ")
+            );
+            if self.reject {
+                return Ok(Self::unprocessable(&request, violations));
+            }
+
+            let mut response = next.run(request).await?;
+            Findings::of(&mut response).violations.extend(violations);
+            Ok(response)
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,229 @@
+//! Pick the closest relay: `tunnelto ping` connects to every relay at once, times the
+//! handshake (TCP and TLS) and a few echo round trips to each, and prints them fastest
+//! first. Relays are given with `--relay` or TUNNELTO_RELAYS, as `name=host[:port]`
+//! separated by commas (i.e. `us=us.wormhole.example.com,eu=eu.wormhole.example.com`),
+//! and default to the configured control server alone.
+//!
+//! The fastest relay is kept in `~/.tunnelto/relay.json`, and tunnels connect to it from
+//! then on unless CTRL_HOST is set. Run `tunnelto ping` again to measure anew.
+use super::*;
+use hyper::service::Service;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
+
+const RELAY_FILE: &str = "relay.json";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Round trips per relay, the fastest counts
+const ECHOES: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Relay {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Relay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, address) = match s.split_once('=') {
+            Some((name, address)) => (name.trim(), address.trim()),
+            None => (s, s),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| format!("invalid port in relay `{}`", s))?,
+            ),
+            None => (address, 443),
+        };
+        if name.is_empty() || host.is_empty() {
+            return Err(format!("invalid relay `{}`, expected name=host[:port]", s));
+        }
+        Ok(Relay {
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// The fastest relay as last measured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRelay {
+    #[serde(flatten)]
+    pub relay: Relay,
+    pub echo_ms: u64,
+    /// rfc3339
+    pub measured_at: String,
+}
+
+fn relay_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(SETTINGS_DIR).join(RELAY_FILE))
+}
+
+/// The relay `tunnelto ping` found fastest, if it was run
+pub fn stored_relay() -> Option<StoredRelay> {
+    let data = std::fs::read(relay_path()?).ok()?;
+    serde_json::from_slice(&data)
+        .map_err(|e| debug!("ignoring invalid {}: {:?}", RELAY_FILE, e))
+        .ok()
+}
+
+fn store_relay(stored: &StoredRelay) -> Result<(), Error> {
+    let path = relay_path().ok_or_else(|| {
+        Error::Output(RELAY_FILE.to_string(), "no home directory".to_string())
+    })?;
+    let data = serde_json::to_vec_pretty(stored).unwrap_or_default();
+    path.parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, data))
+        .map_err(|e| Error::Output(format!("{:?}", path), e.to_string()))
+}
+
+#[derive(Debug)]
+struct Latency {
+    handshake: Duration,
+    echo: Duration,
+}
+
+/// Connect to the relay and time the handshake, then the fastest of a few health checks
+/// over the open connection
+async fn probe(relay: &Relay, tls_off: bool) -> Result<Latency, String> {
+    let scheme = if tls_off { "http" } else { "https" };
+    let uri: hyper::Uri = format!("{}://{}:{}", scheme, relay.host, relay.port)
+        .parse()
+        .map_err(|e| format!("invalid address: {}", e))?;
+
+    let mut connector = hyper_tls::HttpsConnector::new();
+    let started = Instant::now();
+    let stream = tokio::time::timeout(PROBE_TIMEOUT, async {
+        futures::future::poll_fn(|cx| connector.poll_ready(cx)).await?;
+        connector.call(uri).await
+    })
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map_err(|e| e.to_string())?;
+    let handshake = started.elapsed();
+
+    let (mut sender, connection) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(connection);
+
+    let mut echo = None;
+    for _ in 0..ECHOES {
+        let request = hyper::Request::get("/health_check")
+            .header(hyper::header::HOST, relay.host.as_str())
+            .body(hyper::Body::empty())
+            .map_err(|e| e.to_string())?;
+
+        let started = Instant::now();
+        let response = tokio::time::timeout(PROBE_TIMEOUT, sender.send_request(request))
+            .await
+            .map_err(|_| "timed out".to_string())?
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("health check answered {}", response.status()));
+        }
+        let _ = hyper::body::to_bytes(response.into_body()).await;
+        let elapsed = started.elapsed();
+        echo = Some(echo.map_or(elapsed, |fastest: Duration| fastest.min(elapsed)));
+    }
+
+    Ok(Latency {
+        handshake,
+        echo: echo.unwrap_or_default(),
+    })
+}
+
+/// The control server the config points at, when no relays are given
+fn configured_relay(config: &Config) -> Option<Relay> {
+    let uri: hyper::Uri = config.control_api_url.parse().ok()?;
+    Some(Relay {
+        name: "default".to_string(),
+        host: uri.host()?.to_string(),
+        port: uri
+            .port_u16()
+            .unwrap_or(if config.tls_off { 80 } else { 443 }),
+    })
+}
+
+/// Measure every relay at once, print them fastest first and keep the fastest
+pub async fn ping(config: &Config, relays: Vec<Relay>) -> Result<(), Error> {
+    let configured = !relays.is_empty();
+    let relays = if configured {
+        relays
+    } else {
+        configured_relay(config).into_iter().collect()
+    };
+
+    let probes = relays.iter().map(|relay| probe(relay, config.tls_off));
+    let results = futures::future::join_all(probes).await;
+    let mut results: Vec<(&Relay, Result<Latency, String>)> =
+        relays.iter().zip(results).collect();
+    // unreachable relays last
+    results.sort_by_key(|(_, latency)| match latency {
+        Ok(latency) => (false, latency.echo, latency.handshake),
+        Err(_) => (true, Duration::default(), Duration::default()),
+    });
+
+    let width = relays.iter().map(|relay| relay.name.len()).max().unwrap_or(0).max(5);
+    println!(
+        "{}",
+        format!("   {:<width$}  {:>9}  {:>6}  {}", "relay", "handshake", "echo", "address", width = width)
+            .bold()
+    );
+    for (rank, (relay, latency)) in results.iter().enumerate() {
+        let address = format!("{}:{}", relay.host, relay.port);
+        match latency {
+            Ok(latency) => println!(
+                "{:>2} {:<width$}  {:>7}ms  {:>4}ms  {}",
+                rank + 1,
+                relay.name,
+                latency.handshake.as_millis(),
+                latency.echo.as_millis(),
+                address.dimmed(),
+                width = width
+            ),
+            Err(e) => println!(
+                " - {:<width$}  {:>9}  {:>6}  {} {}",
+                relay.name,
+                "-",
+                "-",
+                address.dimmed(),
+                e.red(),
+                width = width
+            ),
+        }
+    }
+
+    let fastest = match results.first() {
+        Some((relay, Ok(latency))) => StoredRelay {
+            relay: (*relay).clone(),
+            echo_ms: latency.echo.as_millis() as u64,
+            measured_at: chrono::Utc::now().to_rfc3339(),
+        },
+        _ => {
+            return Err(Error::RequestFailed(
+                "no relay could be reached".to_string(),
+            ))
+        }
+    };
+
+    // a single default relay is where tunnels connect anyway
+    if configured {
+        store_relay(&fastest)?;
+        eprintln!(
+            "\n{} tunnels connect to {} from now on, unless CTRL_HOST is set.",
+            "=>".green(),
+            fastest.relay.name.bold()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,425 @@
+//! Plugins: executables in `~/.tunnelto/plugins/` the client starts with each tunnel and
+//! talks to over their stdin and stdout, one json message per line, so the client can be
+//! extended in any language. Turn them off with `--no-plugins`.
+//!
+//! The client opens with `{"event": "hello", "version": 1, "local_addr": "..."}` and the
+//! plugin answers with its manifest, i.e. `{"name": "audit", "events": ["request",
+//! "response"], "transform": true}`. It then gets the events it subscribed to:
+//!
+//! - `{"event": "request", "id": "...", "method": "POST", "path": "/hook", "query": null,
+//!   "headers": {"content-type": ["application/json"]}, "body": "<base64>"}` before the
+//!   request is forwarded
+//! - `{"event": "response", "id": "...", "status": 200, "headers": {...}, "body": "..."}`
+//!   once it's answered
+//!
+//! A plugin with `"transform": true` answers each request event within 2 seconds with
+//! `{"id": "...", "action": "continue"}`, `{"id": "...", "action": "modify", ...}` with
+//! the request fields to replace, or `{"id": "...", "action": "respond", "status": 403,
+//! "headers": {...}, "body": "<base64>"}` to answer the request itself. Plugins see the
+//! request in the order of their file names, each getting what the previous one made of it.
+use crate::introspect::{
+    header_map, Middleware, MiddlewareResult, Next, ProxyRequest, ProxyResponse,
+};
+use crate::{debug, warn};
+use colored::Colorize;
+use futures::FutureExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::oneshot;
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::{HeaderMap, Method, StatusCode};
+
+const PLUGINS_DIR: &str = "plugins";
+const PROTOCOL_VERSION: u32 = 1;
+
+/// how long a transforming plugin has to answer a request, and any plugin to take an event
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EventKind {
+    Request,
+    Response,
+}
+
+/// What a plugin announces itself with
+#[derive(Debug, Clone, Deserialize)]
+struct Manifest {
+    name: String,
+    #[serde(default)]
+    events: Vec<EventKind>,
+    /// answers request events, possibly changing or answering the request
+    #[serde(default)]
+    transform: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Action {
+    Continue,
+    Modify {
+        method: Option<String>,
+        path: Option<String>,
+        query: Option<String>,
+        headers: Option<HashMap<String, Vec<String>>>,
+        body: Option<String>,
+    },
+    Respond {
+        status: u16,
+        #[serde(default)]
+        headers: HashMap<String, Vec<String>>,
+        #[serde(default)]
+        body: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Reply {
+    id: String,
+    #[serde(flatten)]
+    action: Action,
+}
+
+struct Plugin {
+    path: PathBuf,
+    /// set once the plugin announced itself, it gets no events before
+    manifest: RwLock<Option<Manifest>>,
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    /// transform answers awaited, by request id
+    pending: Mutex<HashMap<String, oneshot::Sender<Action>>>,
+    alive: AtomicBool,
+    _child: Child,
+}
+
+impl Plugin {
+    fn name(&self) -> String {
+        match self.manifest.read().unwrap().as_ref() {
+            Some(manifest) => manifest.name.clone(),
+            None => self.path.display().to_string(),
+        }
+    }
+
+    /// Whether it's running and subscribed to `kind`, and whether it transforms requests
+    fn subscribed(&self, kind: EventKind) -> Option<bool> {
+        if !self.alive.load(Ordering::SeqCst) {
+            return None;
+        }
+        let manifest = self.manifest.read().unwrap();
+        let manifest = manifest.as_ref()?;
+        if !manifest.events.contains(&kind) {
+            return None;
+        }
+        Some(manifest.transform && kind == EventKind::Request)
+    }
+
+    async fn send(&self, message: &Value) -> bool {
+        let mut line = message.to_string().into_bytes();
+        line.push(b'\n');
+
+        let mut stdin = self.stdin.lock().await;
+        match tokio::time::timeout(REPLY_TIMEOUT, stdin.write_all(&line)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                self.stopped(&e.to_string());
+                false
+            }
+            Err(_) => {
+                warn!("plugin {} isn't reading its events", self.name());
+                false
+            }
+        }
+    }
+
+    /// Send a request event and wait for the plugin's answer
+    async fn ask(&self, id: &str, event: &Value) -> Option<Action> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.to_string(), tx);
+
+        if !self.send(event).await {
+            self.pending.lock().unwrap().remove(id);
+            return None;
+        }
+
+        match tokio::time::timeout(REPLY_TIMEOUT, rx).await {
+            Ok(action) => action.ok(),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(id);
+                warn!(
+                    "plugin {} didn't answer request {} in time",
+                    self.name(),
+                    id
+                );
+                None
+            }
+        }
+    }
+
+    fn stopped(&self, reason: &str) {
+        if self.alive.swap(false, Ordering::SeqCst) {
+            eprintln!(
+                "{} {} ({})",
+                "Plugin stopped:".yellow(),
+                self.name(),
+                reason
+            );
+        }
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// Greet the plugin, then read its manifest and answers until it exits
+    async fn listen(self: Arc<Self>, stdout: tokio::process::ChildStdout, local_addr: String) {
+        let hello = json!({
+            "event": "hello",
+            "version": PROTOCOL_VERSION,
+            "local_addr": local_addr,
+        });
+        if !self.send(&hello).await {
+            return;
+        }
+
+        let mut lines = BufReader::new(stdout).lines();
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => return self.stopped("exited"),
+                Err(e) => return self.stopped(&e.to_string()),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if self.manifest.read().unwrap().is_none() {
+                match serde_json::from_str::<Manifest>(&line) {
+                    Ok(manifest) => {
+                        eprintln!(
+                            "{} {} {:?}{}",
+                            "Plugin loaded:".green(),
+                            manifest.name.bold(),
+                            manifest.events,
+                            if manifest.transform {
+                                ", transforming"
+                            } else {
+                                ""
+                            }
+                        );
+                        *self.manifest.write().unwrap() = Some(manifest);
+                    }
+                    Err(e) => return self.stopped(&format!("invalid manifest: {}", e)),
+                }
+                continue;
+            }
+
+            match serde_json::from_str::<Reply>(&line) {
+                Ok(reply) => {
+                    if let Some(tx) = self.pending.lock().unwrap().remove(&reply.id) {
+                        let _ = tx.send(reply.action);
+                    }
+                }
+                Err(e) => warn!("invalid message from plugin {}: {}", self.name(), e),
+            }
+        }
+    }
+}
+
+fn plugins_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(crate::config::SETTINGS_DIR).join(PLUGINS_DIR))
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    metadata.is_file()
+}
+
+/// The executables in the plugins directory, by name
+fn discover() -> Vec<PathBuf> {
+    let entries = match plugins_dir().map(std::fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return vec![],
+    };
+
+    let mut plugins = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.metadata().is_ok_and(|m| is_executable(&m)))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    plugins.sort();
+    plugins
+}
+
+fn to_header_map(headers: &HashMap<String, Vec<String>>) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, values) in headers {
+        let name = match HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        for value in values.iter().filter_map(|v| HeaderValue::from_str(v).ok()) {
+            map.append(name.clone(), value);
+        }
+    }
+    map
+}
+
+/// Passes requests and responses through the plugins
+pub struct Plugins {
+    plugins: Vec<Arc<Plugin>>,
+}
+
+impl Plugins {
+    /// Start the plugins found, `None` if there are none
+    pub fn start(local_addr: &str) -> Option<Self> {
+        let mut plugins = vec![];
+        for path in discover() {
+            let child = Command::new(&path)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(e) => {
+                    eprintln!(
+                        "{} {}: {}",
+                        "Failed to start plugin".yellow(),
+                        path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+                (Some(stdin), Some(stdout)) => (stdin, stdout),
+                _ => continue,
+            };
+            let plugin = Arc::new(Plugin {
+                path,
+                manifest: RwLock::new(None),
+                stdin: tokio::sync::Mutex::new(stdin),
+                pending: Mutex::new(HashMap::new()),
+                alive: AtomicBool::new(true),
+                _child: child,
+            });
+            tokio::spawn(plugin.clone().listen(stdout, local_addr.to_string()));
+            plugins.push(plugin);
+        }
+
+        if plugins.is_empty() {
+            return None;
+        }
+        Some(Plugins { plugins })
+    }
+}
+
+fn request_event(id: &str, request: &ProxyRequest) -> Value {
+    json!({
+        "event": "request",
+        "id": id,
+        "method": request.method.as_str(),
+        "path": request.path,
+        "query": request.query,
+        "headers": header_map(&request.headers),
+        "body": base64::encode(&request.body),
+    })
+}
+
+/// Apply a plugin's answer, the response if it answered the request itself
+fn apply(plugin: &Plugin, action: Action, request: &mut ProxyRequest) -> Option<ProxyResponse> {
+    match action {
+        Action::Continue => None,
+        Action::Modify {
+            method,
+            path,
+            query,
+            headers,
+            body,
+        } => {
+            if let Some(method) = method.and_then(|m| Method::from_bytes(m.as_bytes()).ok()) {
+                request.method = method;
+            }
+            if let Some(path) = path.filter(|path| path.starts_with('/')) {
+                request.path = path;
+            }
+            if query.is_some() {
+                request.query = query.filter(|query| !query.is_empty());
+            }
+            if let Some(headers) = headers {
+                request.headers = to_header_map(&headers);
+            }
+            match body.map(|body| base64::decode(&body)) {
+                Some(Ok(body)) => request.body = body,
+                Some(Err(_)) => warn!("plugin {} sent a body that isn't base64", plugin.name()),
+                None => {}
+            }
+            None
+        }
+        Action::Respond {
+            status,
+            headers,
+            body,
+        } => Some(ProxyResponse::new(
+            StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            to_header_map(&headers),
+            base64::decode(&body).unwrap_or_else(|_| body.into_bytes()),
+        )),
+    }
+}
+
+impl Middleware for Plugins {
+    fn handle<'a>(&'a self, mut request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a> {
+        async move {
+            let id = uuid::Uuid::new_v4().to_string();
+
+            for plugin in &self.plugins {
+                let transform = match plugin.subscribed(EventKind::Request) {
+                    Some(transform) => transform,
+                    None => continue,
+                };
+                let event = request_event(&id, &request);
+                if !transform {
+                    plugin.send(&event).await;
+                    continue;
+                }
+
+                if let Some(action) = plugin.ask(&id, &event).await {
+                    if let Some(response) = apply(plugin, action, &mut request) {
+                        debug!("plugin {} answered request {}", plugin.name(), id);
+                        return Ok(response);
+                    }
+                }
+            }
+
+            let response = next.run(request).await?;
+
+            for plugin in &self.plugins {
+                if plugin.subscribed(EventKind::Response).is_none() {
+                    continue;
+                }
+                let event = json!({
+                    "event": "response",
+                    "id": id,
+                    "status": response.status.as_u16(),
+                    "headers": header_map(&response.headers),
+                    "body": base64::encode(&response.body),
+                });
+                plugin.send(&event).await;
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
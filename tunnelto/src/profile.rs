@@ -0,0 +1,106 @@
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use tunnelto_lib::interpolate::interpolate;
+
+/// A tunnel setup that can be committed to a repo and started with `tunnelto up -f tunnel.yaml`.
+/// Strings may reference the environment or files, see `tunnelto_lib::interpolate`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub subdomain: Option<String>,
+    pub base_domain: Option<String>,
+    /// the local service
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub scheme: Option<String>,
+    /// another machine to forward to, see `--target`
+    pub target: Option<String>,
+    /// shell command that starts the local service, see `--exec`
+    pub exec: Option<String>,
+    /// request headers added before forwarding to the local service
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub auth: ProfileAuth,
+    /// access rules, `PATH=CIDR[,CIDR...]`
+    #[serde(default)]
+    pub acl: Vec<String>,
+    /// request rates, `PATH=RPS[:BURST]`
+    #[serde(default)]
+    pub rate_limits: Vec<String>,
+    #[serde(default)]
+    pub json_errors: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileAuth {
+    pub key: Option<String>,
+    pub jwt: Option<String>,
+    pub signing_secret: Option<String>,
+}
+
+impl Profile {
+    /// Read, interpolate and validate a profile
+    pub fn load(path: &str) -> Result<Profile, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut value: Value = serde_yaml::from_str(&contents).map_err(|e| e.to_string())?;
+        interpolate_value(&mut value)?;
+
+        let profile: Profile = serde_yaml::from_value(value).map_err(|e| e.to_string())?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.target.is_some() && (self.port.is_some() || self.host.is_some()) {
+            return Err("`target` can't be combined with `host` or `port`".into());
+        }
+        if self.target.is_some() && self.exec.is_some() {
+            return Err("`target` can't be combined with `exec`".into());
+        }
+
+        match self.scheme.as_deref() {
+            None | Some("http") | Some("https") => {}
+            Some(scheme) => return Err(format!("`scheme` must be http or https, got `{}`", scheme)),
+        }
+
+        for (name, value) in &self.headers {
+            warp::http::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name `{}`", name))?;
+            warp::http::header::HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value for header `{}`", name))?;
+        }
+
+        for rule in &self.acl {
+            rule.parse::<tunnelto_lib::acl::AccessRule>()
+                .map_err(|e| format!("invalid access rule: {}", e))?;
+        }
+
+        for rule in &self.rate_limits {
+            rule.parse::<tunnelto_lib::rate_limit::RateLimitRule>()
+                .map_err(|e| format!("invalid rate limit: {}", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn interpolate_value(value: &mut Value) -> Result<(), String> {
+    match value {
+        Value::String(s) => *s = interpolate(s).map_err(|e| e.to_string())?,
+        Value::Sequence(values) => {
+            for value in values {
+                interpolate_value(value)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                interpolate_value(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
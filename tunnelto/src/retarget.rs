@@ -0,0 +1,26 @@
+use super::*;
+use crate::control_socket::{ControlRequest, ControlResponse};
+
+/// Move a running tunnel to another local target, keeping its public url. Picks the
+/// tunnel on `sub_domain` if given, otherwise the only one running.
+pub async fn retarget(target: String, sub_domain: Option<String>) -> Result<(), Error> {
+    let tunnel = control_socket::find(sub_domain.as_deref()).await?;
+
+    match control_socket::send(&tunnel.socket, &ControlRequest::Retarget { target }).await? {
+        ControlResponse::Retargeted { from, to, draining } => {
+            eprintln!(
+                "{} {} now forwards to {} (was {}).",
+                "Retargeted!".green(),
+                tunnel.public_url.bold(),
+                to.bold().green(),
+                from
+            );
+            if draining > 0 {
+                eprintln!("{} requests under way finish against {}.", draining, from);
+            }
+            Ok(())
+        }
+        ControlResponse::Failed { reason } => Err(Error::ControlSocket(reason)),
+        _ => Err(Error::ServerReplyInvalid),
+    }
+}
@@ -0,0 +1,50 @@
+use super::*;
+use crate::control_socket::{ControlRequest, ControlResponse};
+use crate::introspect::HeaderRoute;
+
+/// Route requests carrying a header to another local target on a running tunnel, stop
+/// routing them with `remove`, or list the routes if no header is given
+pub async fn route(
+    header: Option<(String, String)>,
+    target: Option<String>,
+    remove: bool,
+    sub_domain: Option<String>,
+) -> Result<(), Error> {
+    let tunnel = control_socket::find(sub_domain.as_deref()).await?;
+
+    let request = match (header, target) {
+        (Some((header, value)), _) if remove => ControlRequest::Unroute { header, value },
+        (Some((header, value)), Some(target)) => ControlRequest::Route {
+            header,
+            value,
+            target,
+        },
+        _ => {
+            print_routes(&tunnel.public_url, &tunnel.local_addr, &tunnel.routes);
+            return Ok(());
+        }
+    };
+
+    match control_socket::send(&tunnel.socket, &request).await? {
+        ControlResponse::Routes { routes } => {
+            eprintln!("{}", "Routes updated!".green());
+            print_routes(&tunnel.public_url, &tunnel.local_addr, &routes);
+            Ok(())
+        }
+        ControlResponse::Failed { reason } => Err(Error::ControlSocket(reason)),
+        _ => Err(Error::ServerReplyInvalid),
+    }
+}
+
+pub fn print_routes(public_url: &str, local_addr: &str, routes: &[HeaderRoute]) {
+    eprintln!("{}", public_url.bold());
+    for route in routes {
+        eprintln!(
+            "  {}: {} => {}",
+            route.header,
+            route.value,
+            route.target.green()
+        );
+    }
+    eprintln!("  everything else => {}", local_addr);
+}
@@ -0,0 +1,101 @@
+//! Clean shutdown. On Ctrl-C or SIGTERM the client stops taking new streams, says goodbye
+//! so the server frees the sub-domain right away instead of when the connection drops, and
+//! lets the requests under way finish for up to --drain-timeout (30s) before exiting. A
+//! second Ctrl-C exits without waiting.
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use tokio::sync::watch;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// the goodbye is queued but not written to the server yet
+pub static GOODBYE_PENDING: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref REQUESTED: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+    static ref DRAINED: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+}
+
+/// Whether we're shutting down, new streams are refused
+pub fn is_draining() -> bool {
+    *REQUESTED.1.borrow()
+}
+
+async fn wait_for(flag: &watch::Receiver<bool>) {
+    let mut flag = flag.clone();
+    while !*flag.borrow() {
+        if flag.changed().await.is_err() {
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Resolves once a shutdown was requested
+pub async fn requested() {
+    wait_for(&REQUESTED.1).await
+}
+
+/// Resolves once the streams under way finished, or the drain timed out
+pub async fn drained() {
+    wait_for(&DRAINED.1).await
+}
+
+/// Ctrl-C, or SIGTERM where there are signals
+async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn drain(timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let in_flight = ACTIVE_STREAMS.read().unwrap().len();
+        if in_flight == 0 && !GOODBYE_PENDING.load(Ordering::SeqCst) {
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!("drain timed out with {} streams under way", in_flight);
+            return;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Shut down cleanly on Ctrl-C or SIGTERM. With --exec, the supervisor exits once it
+/// stopped the local service, see `exec::supervise`.
+pub fn spawn(config: &Config) {
+    let timeout = config.drain_timeout;
+    let exit = config.exec.is_none();
+
+    tokio::spawn(async move {
+        signal().await;
+        let in_flight = ACTIVE_STREAMS.read().unwrap().len();
+        eprintln!(
+            "{} finishing {} requests under way, press Ctrl-C again to quit now",
+            "Shutting down:".yellow(),
+            in_flight
+        );
+        let _ = REQUESTED.0.send(true);
+
+        tokio::select! {
+            _ = drain(timeout) => {}
+            _ = signal() => {}
+        }
+
+        let _ = DRAINED.0.send(true);
+        if exit {
+            std::process::exit(0);
+        }
+    });
+}
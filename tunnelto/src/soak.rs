@@ -0,0 +1,45 @@
+use super::*;
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const SOAK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Packets waiting for the tunnel websocket, as last seen by the sender loop
+pub static SEND_BACKLOG: AtomicUsize = AtomicUsize::new(0);
+
+/// Sizes of everything that could grow without bound in a long-running tunnel
+#[derive(Debug, Clone, Serialize)]
+pub struct InternalCounts {
+    pub active_streams: usize,
+    pub captured_requests: usize,
+    pub send_backlog: usize,
+    pub open_fds: Option<usize>,
+}
+
+impl InternalCounts {
+    pub fn collect() -> Self {
+        InternalCounts {
+            active_streams: ACTIVE_STREAMS.read().unwrap().len(),
+            captured_requests: introspect::REQUESTS.read().unwrap().len(),
+            send_backlog: SEND_BACKLOG.load(Ordering::Relaxed),
+            open_fds: open_fd_count(),
+        }
+    }
+}
+
+/// Print the internal counts every minute
+pub fn spawn() {
+    tokio::spawn(async move {
+        loop {
+            let counts = InternalCounts::collect();
+            eprintln!(
+                "soak: active_streams={} captured_requests={} send_backlog={} open_fds={:?}",
+                counts.active_streams,
+                counts.captured_requests,
+                counts.send_backlog,
+                counts.open_fds
+            );
+            tokio::time::sleep(SOAK_INTERVAL).await;
+        }
+    });
+}
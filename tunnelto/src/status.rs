@@ -0,0 +1,16 @@
+use super::*;
+
+/// List the tunnels running on this machine and where they forward, only the one on
+/// `sub_domain` if given
+pub async fn status(sub_domain: Option<String>) -> Result<(), Error> {
+    let running = control_socket::running(sub_domain.as_deref()).await;
+    if running.is_empty() {
+        eprintln!("No tunnels running.");
+        return Ok(());
+    }
+
+    for tunnel in running {
+        route::print_routes(&tunnel.public_url, &tunnel.local_addr, &tunnel.routes);
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use colored::Colorize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+pub use tunnelto_lib::integrity::StreamChecksum;
+use tunnelto_lib::StreamId;
+
+lazy_static::lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref MISMATCHED: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Send trailers for our streams (`--verify-integrity`, once the server agreed)
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Check what we delivered to the local service against what the server says it sent
+pub fn verify(stream_id: &StreamId, delivered: &StreamChecksum, sent: &StreamChecksum) {
+    match delivered.verify(sent) {
+        Ok(()) => log::debug!(
+            "stream intact: {} bytes stream={}",
+            delivered.bytes,
+            stream_id.to_string()
+        ),
+        Err(mismatch) => {
+            let count = MISMATCHED.fetch_add(1, Ordering::Relaxed) + 1;
+            log::error!(
+                "request to local service differs from what the server sent, {} stream={}",
+                mismatch,
+                stream_id.to_string()
+            );
+            eprintln!(
+                "{} {} ({} streams so far)",
+                "INTEGRITY".on_red().white().bold(),
+                mismatch,
+                count
+            );
+        }
+    }
+}
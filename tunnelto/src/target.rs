@@ -0,0 +1,174 @@
+use colored::Colorize;
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How far from this machine a forwarding target is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reach {
+    /// this machine
+    Loopback,
+    /// another machine on the local network
+    Lan,
+    /// anywhere else
+    Public,
+}
+
+fn reach(ip: IpAddr) -> Reach {
+    if ip.is_loopback() {
+        return Reach::Loopback;
+    }
+
+    let lan = match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // unique local (fc00::/7) and link local (fe80::/10)
+            first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    };
+
+    if lan {
+        Reach::Lan
+    } else {
+        Reach::Public
+    }
+}
+
+/// What the user agreed to expose with `--target`
+#[derive(Debug, Clone, Copy)]
+pub struct TargetPolicy {
+    /// skip the confirmation prompt for machines on the local network
+    pub confirmed: bool,
+    /// allow targets outside the local network
+    pub allow_public: bool,
+}
+
+/// Split `HOST:PORT` (or `[V6]:PORT`) into its parts
+pub fn parse_target(target: &str) -> Result<(String, u16), String> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected HOST:PORT, got `{}`", target))?;
+    let port = port
+        .parse()
+        .map_err(|_| format!("invalid port in `{}`", target))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(format!("missing host in `{}`", target));
+    }
+
+    Ok((host.to_string(), port))
+}
+
+/// Resolve a `--target`, check it is allowed and reachable, and tell the user what
+/// they are about to expose
+pub fn check_target(host: &str, port: u16, policy: TargetPolicy) -> Result<(), ()> {
+    let addrs: Vec<SocketAddr> = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            eprintln!("{} could not resolve {}: {}", "Error:".red(), host, e);
+            eprintln!("       check the hostname, or use the target's IP address");
+            return Err(());
+        }
+    };
+
+    // judge the target by the farthest address it resolves to
+    let farthest = match addrs.iter().map(|addr| reach(addr.ip())).max() {
+        Some(reach) => reach,
+        None => {
+            eprintln!("{} {} has no addresses", "Error:".red(), host);
+            return Err(());
+        }
+    };
+
+    if host.parse::<IpAddr>().is_err() {
+        eprintln!(
+            "{} {} resolves to {}",
+            "Target:".green(),
+            host,
+            addrs
+                .iter()
+                .map(|addr| addr.ip().to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+
+    match farthest {
+        Reach::Loopback => {}
+        Reach::Public if !policy.allow_public => {
+            eprintln!(
+                "{} {}:{} is not on your local network. Pass --allow-public-target to expose it anyway.",
+                "Refusing:".red(),
+                host,
+                port
+            );
+            return Err(());
+        }
+        Reach::Lan | Reach::Public => {
+            eprintln!(
+                "{} anyone with the public url will be able to reach {}:{} through this machine.",
+                "Warning:".yellow(),
+                host,
+                port
+            );
+            if !policy.confirmed && !confirm()? {
+                return Err(());
+            }
+        }
+    }
+
+    diagnose(&addrs);
+    Ok(())
+}
+
+fn confirm() -> Result<bool, ()> {
+    if !std::io::stdin().is_terminal() {
+        eprintln!("Pass --confirm-target to expose another machine without a prompt.");
+        return Err(());
+    }
+
+    eprint!("Expose it? [y/N]: ");
+    let _ = std::io::stderr().flush();
+
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line).map_err(|_| ())?;
+    Ok(matches!(line.trim(), "y" | "Y" | "yes"))
+}
+
+/// Explain why the target can't be reached, if it can't. The tunnel still starts:
+/// devices are often asleep or booting.
+fn diagnose(addrs: &[SocketAddr]) {
+    let mut failure = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            Ok(_) => return,
+            Err(e) => failure = Some((*addr, e)),
+        }
+    }
+
+    let (addr, error) = match failure {
+        Some(failure) => failure,
+        None => return,
+    };
+
+    let hint = match error.kind() {
+        std::io::ErrorKind::ConnectionRefused => {
+            "the machine is up but nothing is listening on that port"
+        }
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
+            "no answer: the machine may be off, asleep, or a firewall is dropping connections"
+        }
+        _ => "check the address, and that this machine can route to it",
+    };
+
+    eprintln!(
+        "{} can't reach {} ({}): {}",
+        "Warning:".yellow(),
+        addr,
+        error,
+        hint
+    );
+}
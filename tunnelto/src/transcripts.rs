@@ -0,0 +1,82 @@
+use super::*;
+
+/// Export the account's request transcripts as json lines, or turn them on or off
+pub async fn transcripts(
+    config: &Config,
+    sub_domain: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    enable: Option<bool>,
+    output: Option<String>,
+) -> Result<(), Error> {
+    let auth_key = config
+        .secret_key
+        .clone()
+        .ok_or(Error::NoAuthenticationKey)?;
+
+    let request = TranscriptRequest {
+        auth_key,
+        sub_domain,
+        since,
+        until,
+        enable,
+    };
+    let response: TranscriptResponse = api::post(config, "transcripts", &request).await?;
+
+    let (enabled, retention_days, entries, truncated) = match response {
+        TranscriptResponse::Entries {
+            enabled,
+            retention_days,
+            entries,
+            truncated,
+        } => (enabled, retention_days, entries, truncated),
+        TranscriptResponse::Failed { reason } => {
+            eprintln!("{} {}", "Failed:".red(), reason);
+            return Ok(());
+        }
+    };
+
+    match enable {
+        Some(true) => {
+            eprintln!(
+                "{} Transcripts of your tunnels' requests are kept for {} days.",
+                "Enabled.".green(),
+                retention_days
+            );
+            return Ok(());
+        }
+        Some(false) => {
+            eprintln!(
+                "{} The transcripts kept so far expire after {} days.",
+                "Disabled.".green(),
+                retention_days
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let mut lines = String::new();
+    for entry in entries.iter() {
+        lines.push_str(&serde_json::to_string(entry).unwrap_or_default());
+        lines.push('\n');
+    }
+    match output.as_ref() {
+        Some(path) => {
+            std::fs::write(path, lines).map_err(|e| Error::Output(path.clone(), e.to_string()))?
+        }
+        None => print!("{}", lines),
+    }
+
+    eprintln!("Exported {} requests.", entries.len());
+    if truncated {
+        eprintln!(
+            "{} there are more, export the rest with --since and --until.",
+            "Truncated:".yellow()
+        );
+    }
+    if !enabled {
+        eprintln!("Transcripts are off, `tunnelto transcripts --enable` turns them on.");
+    }
+    Ok(())
+}
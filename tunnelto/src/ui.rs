@@ -0,0 +1,303 @@
+use super::*;
+use crate::introspect::{InspectorStatus, RequestSummary};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use hyper::body::HttpBody;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Table, TableState};
+use ratatui::Terminal;
+use serde::de::DeserializeOwned;
+use std::io::Write;
+use std::time::Instant;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type HttpClient = hyper::Client<hyper::client::HttpConnector>;
+
+/// What the ui shows of a running client's inspector
+struct App {
+    base_url: String,
+    status: Option<InspectorStatus>,
+    requests: Vec<RequestSummary>,
+    table: TableState,
+    /// keep the newest request selected as new ones arrive
+    follow: bool,
+    message: Option<String>,
+}
+
+impl App {
+    fn selected(&self) -> Option<&RequestSummary> {
+        self.table.selected().and_then(|i| self.requests.get(i))
+    }
+
+    fn select(&mut self, index: usize) {
+        if self.requests.is_empty() {
+            self.table.select(None);
+            return;
+        }
+        let index = index.min(self.requests.len() - 1);
+        self.follow = index == 0;
+        self.table.select(Some(index));
+    }
+
+    async fn refresh(&mut self, client: &HttpClient) {
+        let requests =
+            get_json::<Vec<RequestSummary>>(client, &format!("{}/api/requests", self.base_url))
+                .await;
+        let status =
+            get_json::<InspectorStatus>(client, &format!("{}/api/status", self.base_url)).await;
+
+        match (requests, status) {
+            (Ok(requests), Ok(status)) => {
+                // stay on the same request as new ones push it down
+                let selected_id = self.selected().map(|r| r.id.clone());
+                self.requests = requests;
+                self.status = Some(status);
+
+                let index = match selected_id {
+                    Some(id) if !self.follow => self.requests.iter().position(|r| r.id == id),
+                    _ => None,
+                };
+                match index {
+                    Some(index) => self.table.select(Some(index)),
+                    None => self.select(0),
+                }
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                self.message = Some(format!("inspector unreachable: {}", e));
+            }
+        }
+    }
+}
+
+/// A full-screen view of a running client: its public url and live requests, with replay
+pub async fn run_ui(dashboard: String) -> Result<(), Error> {
+    let base_url = if dashboard.starts_with("http") {
+        dashboard.trim_end_matches('/').to_string()
+    } else {
+        format!("http://{}", dashboard)
+    };
+    let client = hyper::Client::new();
+
+    // fail before taking over the terminal if no client is there
+    let status = get_json::<InspectorStatus>(&client, &format!("{}/api/status", base_url)).await?;
+
+    let mut app = App {
+        base_url,
+        status: Some(status),
+        requests: vec![],
+        table: TableState::default(),
+        follow: true,
+        message: None,
+    };
+
+    let mut terminal = setup_terminal()?;
+    let result = event_loop(&mut terminal, &client, &mut app).await;
+    restore_terminal(&mut terminal);
+    result
+}
+
+type UiTerminal = Terminal<CrosstermBackend<std::io::Stdout>>;
+
+fn setup_terminal() -> Result<UiTerminal, Error> {
+    let terminal_error = |e: std::io::Error| Error::Terminal(e.to_string());
+
+    terminal::enable_raw_mode().map_err(terminal_error)?;
+    let mut stdout = std::io::stdout();
+    crossterm::execute!(stdout, EnterAlternateScreen).map_err(terminal_error)?;
+    Terminal::new(CrosstermBackend::new(stdout)).map_err(terminal_error)
+}
+
+fn restore_terminal(terminal: &mut UiTerminal) {
+    let _ = terminal::disable_raw_mode();
+    let _ = crossterm::execute!(terminal.backend_mut(), LeaveAlternateScreen);
+    let _ = terminal.show_cursor();
+}
+
+async fn event_loop(
+    terminal: &mut UiTerminal,
+    client: &HttpClient,
+    app: &mut App,
+) -> Result<(), Error> {
+    let mut last_refresh: Option<Instant> = None;
+
+    loop {
+        if last_refresh.is_none_or(|t| t.elapsed() > REFRESH_INTERVAL) {
+            app.refresh(client).await;
+            last_refresh = Some(Instant::now());
+        }
+
+        terminal
+            .draw(|frame| draw(frame, app))
+            .map_err(|e| Error::Terminal(e.to_string()))?;
+
+        while event::poll(Duration::ZERO).map_err(|e| Error::Terminal(e.to_string()))? {
+            let key = match event::read().map_err(|e| Error::Terminal(e.to_string()))? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+
+            let selected = app.table.selected().unwrap_or(0);
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => app.select(selected + 1),
+                KeyCode::Up | KeyCode::Char('k') => app.select(selected.saturating_sub(1)),
+                KeyCode::Home | KeyCode::Char('g') => app.select(0),
+                KeyCode::Char('r') => {
+                    app.message = Some(replay(client, app).await);
+                    last_refresh = None;
+                }
+                KeyCode::Char('c') => app.message = Some(copy_public_url(app)),
+                _ => {}
+            }
+        }
+
+        tokio::time::sleep(INPUT_POLL_INTERVAL).await;
+    }
+}
+
+fn draw(frame: &mut ratatui::Frame, app: &mut App) {
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .split(frame.size());
+
+    let (public_url, local_addr) = match app.status.as_ref() {
+        Some(status) => (
+            status
+                .public_url
+                .clone()
+                .unwrap_or_else(|| "connecting...".to_string()),
+            status.local_addr.clone(),
+        ),
+        None => ("unknown".to_string(), "unknown".to_string()),
+    };
+    let header = Paragraph::new(Line::from(vec![
+        Span::styled(
+            public_url,
+            Style::default()
+                .fg(Color::Green)
+                .add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(" => "),
+        Span::styled(local_addr, Style::default().fg(Color::Yellow)),
+    ]))
+    .block(Block::default().borders(Borders::ALL).title(" tunnelto "));
+    frame.render_widget(header, areas[0]);
+
+    let rows = app.requests.iter().map(|r| {
+        let status_color = match r.status {
+            200..=299 => Color::Green,
+            300..=399 => Color::Cyan,
+            400..=499 => Color::Yellow,
+            _ => Color::Red,
+        };
+        Row::new(vec![
+            Span::raw(r.completed.clone()),
+            Span::raw(r.method.clone()),
+            Span::styled(r.status.to_string(), Style::default().fg(status_color)),
+            Span::raw(r.elapsed.clone()),
+            Span::raw(if r.is_replay {
+                format!("{} (replay)", r.path)
+            } else {
+                r.path.clone()
+            }),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(9),
+            Constraint::Length(8),
+            Constraint::Length(4),
+            Constraint::Length(7),
+            Constraint::Min(10),
+        ],
+    )
+    .header(
+        Row::new(vec!["TIME", "METHOD", "CODE", "TOOK", "PATH"])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+    )
+    .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+    .block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" requests ({}) ", app.requests.len())),
+    );
+    frame.render_stateful_widget(table, areas[1], &mut app.table);
+
+    let footer = match app.message.as_ref() {
+        Some(message) => Line::from(Span::styled(
+            message.clone(),
+            Style::default().fg(Color::Yellow),
+        )),
+        None => Line::from(
+            "q quit  ↑/↓ select  g follow newest  r replay  c copy public url",
+        ),
+    };
+    frame.render_widget(Paragraph::new(footer), areas[2]);
+}
+
+async fn replay(client: &HttpClient, app: &App) -> String {
+    let request = match app.selected() {
+        Some(request) => request,
+        None => return "no request selected".to_string(),
+    };
+
+    let url = format!("{}/replay/{}", app.base_url, request.id);
+    let result = hyper::Request::post(url)
+        .body(hyper::Body::empty())
+        .map_err(|e| e.to_string());
+    let result = match result {
+        Ok(request) => client.request(request).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(response) if response.status().is_success() || response.status().is_redirection() => {
+            format!("replayed {} {}", request.method, request.path)
+        }
+        Ok(response) => format!("replay failed: {}", response.status()),
+        Err(e) => format!("replay failed: {}", e),
+    }
+}
+
+/// Copy through the terminal (OSC 52), which also works over ssh
+fn copy_public_url(app: &App) -> String {
+    let url = match app.status.as_ref().and_then(|s| s.public_url.as_ref()) {
+        Some(url) => url,
+        None => return "no public url yet".to_string(),
+    };
+
+    let mut stdout = std::io::stdout();
+    let _ = write!(stdout, "\x1b]52;c;{}\x07", base64::encode(url));
+    let _ = stdout.flush();
+    format!("copied {}", url)
+}
+
+async fn get_json<T: DeserializeOwned>(client: &HttpClient, url: &str) -> Result<T, Error> {
+    let uri = url
+        .parse::<hyper::Uri>()
+        .map_err(|_| Error::InvalidUrl(url.to_string()))?;
+    let mut response = client
+        .get(uri)
+        .await
+        .map_err(|e| Error::RequestFailed(e.to_string()))?;
+
+    let mut data = vec![];
+    while let Some(chunk) = response.body_mut().data().await {
+        let chunk = chunk.map_err(|e| Error::RequestFailed(e.to_string()))?;
+        data.extend_from_slice(&chunk);
+    }
+
+    serde_json::from_slice(&data).map_err(|e| Error::RequestFailed(e.to_string()))
+}
@@ -0,0 +1,40 @@
+use super::*;
+
+/// List visitor connections on our tunnels, or terminate one of them
+pub async fn visitors(config: &Config, kick: Option<String>) -> Result<(), Error> {
+    let auth_key = config.secret_key.clone().ok_or(Error::NoAuthenticationKey)?;
+
+    let response: VisitorsResponse =
+        api::post(config, "visitors", &VisitorsRequest { auth_key, kick }).await?;
+
+    match response {
+        VisitorsResponse::Connections { connections } if connections.is_empty() => {
+            eprintln!("No visitors connected.");
+        }
+        VisitorsResponse::Connections { connections } => {
+            println!(
+                "{:<20} {:<16} {:<40} {:>8} {:>10} {:>10}",
+                "ID", "SUB-DOMAIN", "VISITOR", "SECS", "IN", "OUT"
+            );
+            for c in connections {
+                println!(
+                    "{:<20} {:<16} {:<40} {:>8} {:>10} {:>10}",
+                    c.id,
+                    c.sub_domain,
+                    c.peer_addr.unwrap_or_else(|| "-".to_string()),
+                    c.connected_secs,
+                    c.bytes_in,
+                    c.bytes_out
+                );
+            }
+        }
+        VisitorsResponse::Kicked { id } => {
+            eprintln!("{} Terminated visitor connection {}", "Success!".green(), id);
+        }
+        VisitorsResponse::Failed { reason } => {
+            eprintln!("{} {}", "Failed:".red(), reason);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use super::*;
+use hyper::body::HttpBody;
+
+/// A provider-shaped webhook we can send through a tunnel
+struct Template {
+    name: &'static str,
+    provider: Provider,
+    event: &'static str,
+    payload: fn(&str) -> serde_json::Value,
+}
+
+#[derive(Clone, Copy)]
+enum Provider {
+    Stripe,
+    GitHub,
+}
+
+const TEMPLATES: &[Template] = &[
+    Template {
+        name: "stripe.checkout.completed",
+        provider: Provider::Stripe,
+        event: "checkout.session.completed",
+        payload: stripe_checkout_completed,
+    },
+    Template {
+        name: "stripe.payment_intent.succeeded",
+        provider: Provider::Stripe,
+        event: "payment_intent.succeeded",
+        payload: stripe_payment_intent_succeeded,
+    },
+    Template {
+        name: "github.push",
+        provider: Provider::GitHub,
+        event: "push",
+        payload: github_push,
+    },
+    Template {
+        name: "github.ping",
+        provider: Provider::GitHub,
+        event: "ping",
+        payload: github_ping,
+    },
+];
+
+/// Send a fake webhook built from `template` to `url`, signed with `secret` if given
+pub async fn send_test_webhook(
+    template: String,
+    url: String,
+    secret: Option<String>,
+) -> Result<(), Error> {
+    let template = match TEMPLATES.iter().find(|t| t.name == template) {
+        Some(template) => template,
+        None => {
+            eprintln!("{} unknown template: {}", "Error:".red(), template);
+            eprintln!("Available templates:");
+            for t in TEMPLATES {
+                eprintln!("    {}", t.name);
+            }
+            return Ok(());
+        }
+    };
+
+    let id = uuid::Uuid::new_v4().to_simple().to_string();
+    let body = serde_json::to_vec(&(template.payload)(&id)).unwrap_or_default();
+    let timestamp = chrono::Utc::now().timestamp();
+
+    let mut request = hyper::Request::post(&url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(hyper::header::USER_AGENT, user_agent(template.provider));
+
+    request = match template.provider {
+        Provider::Stripe => {
+            let mut request = request;
+            if let Some(secret) = secret.as_ref() {
+                let signed = [timestamp.to_string().as_bytes(), b".", &body].concat();
+                request = request.header(
+                    "Stripe-Signature",
+                    format!("t={},v1={}", timestamp, hmac_hex(secret, &signed)),
+                );
+            }
+            request
+        }
+        Provider::GitHub => {
+            let mut request = request
+                .header("X-GitHub-Event", template.event)
+                .header("X-GitHub-Delivery", id.as_str());
+            if let Some(secret) = secret.as_ref() {
+                request = request.header(
+                    "X-Hub-Signature-256",
+                    format!("sha256={}", hmac_hex(secret, &body)),
+                );
+            }
+            request
+        }
+    };
+
+    let request = request.body(hyper::Body::from(body)).map_err(|e| {
+        error!("failed to build webhook request: {:?}", e);
+        Error::InvalidUrl(url.clone())
+    })?;
+
+    eprintln!("Sending {} to {}", template.name.bold(), url);
+
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let mut response = client
+        .request(request)
+        .await
+        .map_err(|e| Error::RequestFailed(e.to_string()))?;
+
+    let mut data = vec![];
+    while let Some(Ok(chunk)) = response.body_mut().data().await {
+        data.extend_from_slice(&chunk);
+    }
+
+    let status = response.status();
+    let status_line = format!("{}", status);
+    eprintln!(
+        "=> {}",
+        if status.is_success() {
+            status_line.green()
+        } else {
+            status_line.red()
+        }
+    );
+    if !data.is_empty() {
+        eprintln!("{}", String::from_utf8_lossy(&data));
+    }
+
+    Ok(())
+}
+
+fn hmac_hex(secret: &str, data: &[u8]) -> String {
+    hex::encode(hmac_sha256::HMAC::mac(data, secret.as_bytes()))
+}
+
+fn user_agent(provider: Provider) -> &'static str {
+    match provider {
+        Provider::Stripe => "Stripe/1.0 (+https://stripe.com/docs/webhooks)",
+        Provider::GitHub => "GitHub-Hookshot/tunnelto-test",
+    }
+}
+
+fn stripe_event(id: &str, event: &str, object: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "id": format!("evt_{}", id),
+        "object": "event",
+        "api_version": "2020-08-27",
+        "created": chrono::Utc::now().timestamp(),
+        "livemode": false,
+        "pending_webhooks": 1,
+        "request": { "id": null, "idempotency_key": null },
+        "type": event,
+        "data": { "object": object },
+    })
+}
+
+fn stripe_checkout_completed(id: &str) -> serde_json::Value {
+    stripe_event(
+        id,
+        "checkout.session.completed",
+        serde_json::json!({
+            "id": format!("cs_test_{}", id),
+            "object": "checkout.session",
+            "amount_subtotal": 2000,
+            "amount_total": 2000,
+            "currency": "usd",
+            "customer": format!("cus_{}", &id[..14]),
+            "customer_details": { "email": "jenny.rosen@example.com" },
+            "mode": "payment",
+            "payment_intent": format!("pi_{}", id),
+            "payment_status": "paid",
+            "status": "complete",
+        }),
+    )
+}
+
+fn stripe_payment_intent_succeeded(id: &str) -> serde_json::Value {
+    stripe_event(
+        id,
+        "payment_intent.succeeded",
+        serde_json::json!({
+            "id": format!("pi_{}", id),
+            "object": "payment_intent",
+            "amount": 2000,
+            "amount_received": 2000,
+            "currency": "usd",
+            "customer": format!("cus_{}", &id[..14]),
+            "status": "succeeded",
+        }),
+    )
+}
+
+fn github_repository() -> serde_json::Value {
+    serde_json::json!({
+        "id": 1296269,
+        "name": "hello-world",
+        "full_name": "octocat/hello-world",
+        "private": false,
+        "owner": { "login": "octocat", "id": 1 },
+        "html_url": "https://github.com/octocat/hello-world",
+        "default_branch": "main",
+    })
+}
+
+fn github_push(id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "ref": "refs/heads/main",
+        "before": "0000000000000000000000000000000000000000",
+        "after": &id[..32],
+        "repository": github_repository(),
+        "pusher": { "name": "octocat", "email": "octocat@github.com" },
+        "sender": { "login": "octocat", "id": 1 },
+        "commits": [{
+            "id": &id[..32],
+            "message": "Update README",
+            "author": { "name": "octocat", "email": "octocat@github.com" },
+        }],
+    })
+}
+
+fn github_ping(id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "zen": "Keep it logically awesome.",
+        "hook_id": 12345678,
+        "hook": { "type": "Repository", "id": 12345678, "events": ["push"], "active": true },
+        "repository": github_repository(),
+        "sender": { "login": "octocat", "id": 1 },
+        "delivery": id,
+    })
+}
@@ -0,0 +1,166 @@
+//! What's new in each release of the client, from the manifest in `static/whatsnew.json`.
+//! Features the server has to support name the protocol feature it advertises for them,
+//! so `tunnelto whatsnew` can tell which ones the server lets us use. The last version
+//! shown is kept in `~/.tunnelto/last_version`, and tunnels opened after an upgrade point
+//! to `tunnelto whatsnew` until it is run.
+use super::*;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+const LAST_VERSION_FILE: &str = "last_version";
+
+/// The version of this client
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Clone, Deserialize)]
+struct Release {
+    version: String,
+    features: Vec<Feature>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Feature {
+    title: String,
+    /// how to use it, i.e. the flag to pass
+    #[serde(default)]
+    usage: Option<String>,
+    /// the protocol feature the server must advertise, see `tunnelto_lib::features`
+    #[serde(default)]
+    server_feature: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref MANIFEST: Vec<Release> = serde_json::from_str(include_str!("../static/whatsnew.json"))
+        .expect("invalid whatsnew manifest");
+}
+
+/// `1.2.3` as `[1, 2, 3]`, for comparing versions
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn last_version_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(SETTINGS_DIR).join(LAST_VERSION_FILE))
+}
+
+/// The version `tunnelto whatsnew` last showed, `None` on a fresh install
+fn last_version() -> Option<String> {
+    let version = std::fs::read_to_string(last_version_path()?).ok()?;
+    Some(version.trim().to_string())
+}
+
+fn store_last_version() {
+    let path = match last_version_path() {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, CURRENT_VERSION))
+    {
+        debug!("failed to store the last version in {:?}: {:?}", path, e);
+    }
+}
+
+/// The releases after `since` up to this one, newest first
+fn releases_since(since: Option<&str>) -> Vec<&'static Release> {
+    let since = since.map(parse_version);
+    let current = parse_version(CURRENT_VERSION);
+    let mut releases: Vec<&Release> = MANIFEST
+        .iter()
+        .filter(|release| {
+            let version = parse_version(&release.version);
+            version <= current && since.as_ref().is_none_or(|since| &version > since)
+        })
+        .collect();
+    releases.sort_by_key(|release| std::cmp::Reverse(parse_version(&release.version)));
+    releases
+}
+
+/// List the features added since the version last shown, or in every release with `all`,
+/// and whether the server supports them
+pub async fn whatsnew(config: &Config, all: bool) -> Result<(), Error> {
+    let last_version = last_version().filter(|_| !all);
+    let releases = releases_since(last_version.as_deref());
+    if releases.is_empty() {
+        eprintln!(
+            "Nothing new since {}, `tunnelto whatsnew --all` lists every release.",
+            last_version.as_deref().unwrap_or(CURRENT_VERSION)
+        );
+        store_last_version();
+        return Ok(());
+    }
+
+    // older servers don't announce their features
+    let server_features = match api::get::<CapabilitiesResponse>(config, "capabilities").await {
+        Ok(response) => Some(response.features),
+        Err(e) => {
+            eprintln!("{} server features unknown: {}", "warn".yellow(), e);
+            None
+        }
+    };
+
+    for release in releases {
+        println!("{} {}", "tunnelto".green(), release.version.green());
+        for feature in release.features.iter() {
+            let supported = match (feature.server_feature.as_ref(), server_features.as_ref()) {
+                (None, _) => String::new(),
+                (Some(_), None) => " (needs server support)".dimmed().to_string(),
+                (Some(needed), Some(features)) if features.contains(needed) => {
+                    " (available on your server)".green().to_string()
+                }
+                (Some(_), Some(_)) => " (not on your server yet)".dimmed().to_string(),
+            };
+            println!("  - {}{}", feature.title, supported);
+            if let Some(usage) = feature.usage.as_ref() {
+                println!("    {}", usage.yellow());
+            }
+        }
+        println!();
+    }
+
+    store_last_version();
+    Ok(())
+}
+
+/// After an upgrade, point to `tunnelto whatsnew` with how many of the new features the
+/// server we're connected to supports
+pub fn notice(server_features: &[String]) {
+    let last_version = match last_version() {
+        Some(version) => version,
+        None => {
+            // a fresh install, everything is new
+            store_last_version();
+            return;
+        }
+    };
+
+    let features: Vec<&Feature> = releases_since(Some(&last_version))
+        .into_iter()
+        .flat_map(|release| release.features.iter())
+        .collect();
+    if features.is_empty() {
+        return;
+    }
+    let usable = features
+        .iter()
+        .filter(|feature| {
+            feature
+                .server_feature
+                .as_ref()
+                .is_none_or(|needed| server_features.contains(needed))
+        })
+        .count();
+
+    eprintln!(
+        "{} {} new features since {}, {} of them usable with this server: run `tunnelto whatsnew`",
+        "What's new:".green(),
+        features.len(),
+        last_version,
+        usable
+    );
+}
@@ -0,0 +1,326 @@
+//! Access rules a tunnel asks the edge to enforce on visitor requests.
+//!
+//! A rule is written `PATH=CIDR[,CIDR...]`, i.e. `/admin/*=10.0.0.0/8`. A path ending
+//! in `*` matches anything starting with what comes before it, otherwise the path must
+//! match exactly or be a parent directory of the request path. Request paths are
+//! decoded and normalized first (`//`, `.` and `..` resolved), as the local service would
+//! see them. The first rule matching a request decides: visitors outside its networks
+//! are refused.
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AclError {
+    #[error("expected PATH=CIDR[,CIDR...], got `{0}`")]
+    Malformed(String),
+
+    #[error("path must start with `/`: `{0}`")]
+    InvalidPath(String),
+
+    #[error("invalid network `{0}`")]
+    InvalidNetwork(String),
+}
+
+/// An IPv4 or IPv6 network, i.e. `10.0.0.0/8`. A bare address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Treat IPv4-mapped IPv6 addresses (from dual-stack listeners) as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = ip {
+        let octets = v6.octets();
+        if octets[..10].iter().all(|b| *b == 0) && octets[10] == 0xff && octets[11] == 0xff {
+            return IpAddr::from([octets[12], octets[13], octets[14], octets[15]]);
+        }
+    }
+    ip
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    let remaining_bits = prefix_len % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = AclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AclError::InvalidNetwork(s.to_string());
+
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s.trim(), None),
+        };
+
+        let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse::<u8>().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = AclError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        format!("{}/{}", cidr.addr, cidr.prefix_len)
+    }
+}
+
+/// Only visitors from `allow` may request paths matching `path`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessRule {
+    pub path: String,
+    pub allow: Vec<Cidr>,
+}
+
+impl AccessRule {
+    pub fn matches_path(&self, path: &str) -> bool {
+        path_matches(&self.path, path)
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+impl FromStr for AccessRule {
+    type Err = AclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, networks) = s
+            .split_once('=')
+            .ok_or_else(|| AclError::Malformed(s.to_string()))?;
+
+        let path = path.trim();
+        if !path.starts_with('/') {
+            return Err(AclError::InvalidPath(path.to_string()));
+        }
+
+        let allow = networks
+            .split(',')
+            .filter(|n| !n.trim().is_empty())
+            .map(Cidr::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        if allow.is_empty() {
+            return Err(AclError::Malformed(s.to_string()));
+        }
+
+        Ok(AccessRule {
+            path: path.to_string(),
+            allow,
+        })
+    }
+}
+
+impl fmt::Display for AccessRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let allow = self
+            .allow
+            .iter()
+            .map(|cidr| String::from(*cidr))
+            .collect::<Vec<_>>();
+        write!(f, "{}={}", self.path, allow.join(","))
+    }
+}
+
+/// Whether a request `path` falls under a rule's `pattern`, see the module docs
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    // ignore the query, and decode escapes so `/%61dmin` can't sneak past `/admin`
+    let path = normalize(&percent_decode(path.split('?').next().unwrap_or_default()));
+
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        return path.starts_with(prefix);
+    }
+
+    let rule_path = pattern.trim_end_matches('/');
+    path == rule_path
+        || path
+            .strip_prefix(rule_path)
+            .map(|rest| rest.starts_with('/'))
+            .unwrap_or(false)
+}
+
+/// The first rule matching `path`, if any
+pub fn matching_rule<'a>(rules: &'a [AccessRule], path: &str) -> Option<&'a AccessRule> {
+    rules.iter().find(|rule| rule.matches_path(path))
+}
+
+/// Collapse repeated slashes and resolve `.` and `..` segments, so `//admin` or
+/// `/x/../admin` can't sneak past `/admin` either. A trailing slash is kept.
+fn normalize(path: &str) -> String {
+    let mut segments = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    let trailing_slash = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                out.push(hi << 4 | lo);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        // dual-stack listeners see IPv4 visitors mapped into IPv6
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+
+        let net: Cidr = "192.168.1.0/23".parse().unwrap();
+        assert!(net.contains(ip("192.168.0.255")));
+        assert!(!net.contains(ip("192.168.2.1")));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+        assert!(!net.contains(ip("10.0.0.1")));
+
+        let host: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(host.contains(ip("127.0.0.1")));
+        assert!(!host.contains(ip("127.0.0.2")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(
+            String::from("10.0.0.0/8".parse::<Cidr>().unwrap()),
+            "10.0.0.0/8"
+        );
+        assert_eq!(String::from("::1".parse::<Cidr>().unwrap()), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn access_rule_parse() {
+        let rule: AccessRule = "/admin/*=10.0.0.0/8, 127.0.0.1".parse().unwrap();
+        assert_eq!(rule.path, "/admin/*");
+        assert_eq!(rule.allow.len(), 2);
+        assert_eq!(rule.to_string(), "/admin/*=10.0.0.0/8,127.0.0.1/32");
+
+        assert!("admin=10.0.0.0/8".parse::<AccessRule>().is_err());
+        assert!("/admin=".parse::<AccessRule>().is_err());
+        assert!("/admin".parse::<AccessRule>().is_err());
+    }
+
+    #[test]
+    fn path_matches_directories() {
+        assert!(path_matches("/admin", "/admin"));
+        assert!(path_matches("/admin", "/admin/users"));
+        assert!(path_matches("/admin/", "/admin/users?page=2"));
+        assert!(!path_matches("/admin", "/administrator"));
+        assert!(!path_matches("/admin", "/"));
+
+        assert!(path_matches("/api*", "/api-v2/keys"));
+        assert!(path_matches("/admin/*", "/admin/"));
+        assert!(!path_matches("/admin/*", "/public/admin/"));
+    }
+
+    #[test]
+    fn path_matches_normalized() {
+        assert!(path_matches("/admin", "/%61dmin"));
+        assert!(path_matches("/admin", "//admin"));
+        assert!(path_matches("/admin", "/./admin/"));
+        assert!(path_matches("/admin", "/public/../admin"));
+        assert!(path_matches("/admin", "/public/%2e%2e/admin/x"));
+        assert!(path_matches("/admin", "/../../admin"));
+        assert!(path_matches("/admin/*", "/admin/x/.."));
+        assert!(!path_matches("/admin", "/admin/../public"));
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize("/"), "/");
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("/a//b/./c/"), "/a/b/c/");
+        assert_eq!(normalize("/a/b/.."), "/a/");
+        assert_eq!(normalize("/a/.."), "/");
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{ErrorCode, ServerHello};
+use thiserror::Error;
+
+/// Why a tunnel could not be established, shared by client and server
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TunnelError {
+    /// the client hello could not be understood
+    #[error("Invalid client hello: {0}")]
+    InvalidClientHello(String),
+
+    /// the credentials presented were rejected
+    #[error("Server denied the connection: {0}. Please check your authentication key.")]
+    AuthFailed(String),
+
+    /// the key is known but no longer works: it expired or was revoked
+    #[error("Server denied the connection: {0}.")]
+    KeyRejected(String),
+
+    #[error("Invalid sub-domain specified.")]
+    InvalidSubDomain,
+
+    #[error("The server does not serve the requested base domain.")]
+    InvalidBaseDomain,
+
+    #[error("Cannot use this sub-domain, it is already taken.")]
+    SubDomainInUse,
+
+    #[error("This sub-domain is reserved by another account.")]
+    SubDomainReserved,
+
+    #[error("You have reached the maximum number of tunnels for your plan.")]
+    TunnelLimitReached,
+
+    #[error("The server does not support the requested tunnel type.")]
+    UnsupportedTunnelType,
+
+    /// the server failed while handling the handshake
+    #[error("The server encountered an internal error: {0}")]
+    Internal(String),
+}
+
+impl TunnelError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TunnelError::InvalidClientHello(_) => ErrorCode::InvalidClientHello,
+            TunnelError::AuthFailed(_) => ErrorCode::AuthFailed,
+            TunnelError::KeyRejected(_) => ErrorCode::KeyRejected,
+            TunnelError::InvalidSubDomain => ErrorCode::InvalidSubDomain,
+            TunnelError::InvalidBaseDomain => ErrorCode::InvalidBaseDomain,
+            TunnelError::SubDomainInUse => ErrorCode::SubDomainInUse,
+            TunnelError::SubDomainReserved => ErrorCode::SubDomainReserved,
+            TunnelError::TunnelLimitReached => ErrorCode::TunnelLimitReached,
+            TunnelError::UnsupportedTunnelType => ErrorCode::UnsupportedTunnelType,
+            TunnelError::Internal(_) => ErrorCode::ServerError,
+        }
+    }
+}
+
+impl From<&TunnelError> for ServerHello {
+    fn from(error: &TunnelError) -> Self {
+        match error {
+            TunnelError::InvalidClientHello(reason) | TunnelError::AuthFailed(reason) => {
+                ServerHello::AuthFailed {
+                    code: error.code().code().to_string(),
+                    reason: reason.clone(),
+                }
+            }
+            // what failed is for the server's logs, not the client
+            TunnelError::Internal(_) => ServerHello::ServerError,
+            TunnelError::KeyRejected(reason) => ServerHello::KeyRejected {
+                reason: reason.clone(),
+            },
+            TunnelError::InvalidSubDomain | TunnelError::InvalidBaseDomain => {
+                ServerHello::InvalidSubDomain
+            }
+            TunnelError::SubDomainInUse => ServerHello::SubDomainInUse,
+            TunnelError::SubDomainReserved => ServerHello::SubDomainReserved,
+            TunnelError::TunnelLimitReached => ServerHello::TunnelLimitReached,
+            TunnelError::UnsupportedTunnelType => ServerHello::UnsupportedTunnelType,
+        }
+    }
+}
+
+impl ServerHello {
+    /// The hello as the client, built with `client_version` of this crate, can read it.
+    /// Clients that don't say theirs may predate `TunnelLimitReached`, so they're told of
+    /// the limit with an `AuthFailed` carrying its code and reason instead.
+    pub fn for_client(self, client_version: Option<&str>) -> Self {
+        match self {
+            ServerHello::TunnelLimitReached if client_version.is_none() => {
+                let error = TunnelError::TunnelLimitReached;
+                ServerHello::AuthFailed {
+                    code: error.code().code().to_string(),
+                    reason: error.to_string(),
+                }
+            }
+            hello => hello,
+        }
+    }
+
+    /// The error a non-success hello stands for
+    pub fn error(&self) -> Option<TunnelError> {
+        match self {
+            ServerHello::Success { .. }
+            | ServerHello::Redirect { .. }
+            | ServerHello::Challenge { .. } => None,
+            ServerHello::SubDomainInUse => Some(TunnelError::SubDomainInUse),
+            ServerHello::SubDomainReserved => Some(TunnelError::SubDomainReserved),
+            ServerHello::InvalidSubDomain => Some(TunnelError::InvalidSubDomain),
+            ServerHello::AuthFailed { code, reason } => match ErrorCode::lookup(code) {
+                Some(ErrorCode::InvalidClientHello) => {
+                    Some(TunnelError::InvalidClientHello(reason.clone()))
+                }
+                Some(ErrorCode::TunnelLimitReached) => Some(TunnelError::TunnelLimitReached),
+                _ => Some(TunnelError::AuthFailed(reason.clone())),
+            },
+            ServerHello::ServerError => Some(TunnelError::Internal(
+                "the server failed to handle the hello".to_string(),
+            )),
+            ServerHello::KeyRejected { reason } => Some(TunnelError::KeyRejected(reason.clone())),
+            ServerHello::TunnelLimitReached => Some(TunnelError::TunnelLimitReached),
+            ServerHello::UnsupportedTunnelType => Some(TunnelError::UnsupportedTunnelType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(error: TunnelError) -> Option<TunnelError> {
+        let data = serde_json::to_vec(&ServerHello::from(&error)).unwrap();
+        serde_json::from_slice::<ServerHello>(&data)
+            .unwrap()
+            .error()
+    }
+
+    #[test]
+    fn refusals_keep_their_reason() {
+        for error in [
+            TunnelError::InvalidClientHello("bad json".into()),
+            TunnelError::AuthFailed("this server only takes signed hellos".into()),
+            TunnelError::KeyRejected("the key was revoked".into()),
+            TunnelError::SubDomainInUse,
+        ] {
+            assert_eq!(round_trip(error.clone()), Some(error));
+        }
+    }
+
+    #[test]
+    fn unversioned_clients_get_hellos_they_know() {
+        let hello = ServerHello::from(&TunnelError::TunnelLimitReached);
+        assert!(matches!(
+            hello.clone().for_client(Some("0.1.10")),
+            ServerHello::TunnelLimitReached
+        ));
+
+        let downgraded = hello.for_client(None);
+        assert!(matches!(downgraded, ServerHello::AuthFailed { .. }));
+        assert_eq!(downgraded.error(), Some(TunnelError::TunnelLimitReached));
+    }
+
+    #[test]
+    fn server_errors_arent_auth_failures() {
+        let error = round_trip(TunnelError::Internal("db down at 10.0.0.3".into()));
+        match error {
+            Some(TunnelError::Internal(reason)) => assert!(!reason.contains("10.0.0.3")),
+            other => panic!("expected an internal error, got {:?}", other),
+        }
+    }
+}
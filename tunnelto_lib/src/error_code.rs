@@ -0,0 +1,330 @@
+use std::fmt;
+
+/// Stable codes for every failure visitors or users of the client can run into, shared
+/// by the client, the server, error pages and json error bodies so a failure can be
+/// referenced precisely, i.e. in support conversations or scripts.
+/// `tunnelto explain TUN-2003` tells what a code means.
+///
+/// - `TUN-1xxx` the local service
+/// - `TUN-2xxx` limits on the tunnel
+/// - `TUN-3xxx` routing and access at the edge
+/// - `TUN-4xxx` opening a tunnel
+/// - `TUN-5xxx` the client's connection to the control server
+///
+/// Codes are never reused or renumbered, only added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    LocalUnreachable,
+    InvalidRequest,
+
+    RequestTimeout,
+    PayloadTooLarge,
+    RateLimited,
+    TooManyConcurrentRequests,
+    TunnelOverloaded,
+    ServerOverloaded,
+
+    TunnelNotFound,
+    InvalidHost,
+    ErrorLocatingTunnel,
+    RoutingScriptFailed,
+    Forbidden,
+    AwaitingApproval,
+    Unauthorized,
+    AuthorizationUnavailable,
+
+    AuthFailed,
+    KeyRejected,
+    InvalidSubDomain,
+    InvalidBaseDomain,
+    SubDomainInUse,
+    SubDomainReserved,
+    TunnelLimitReached,
+    UnsupportedTunnelType,
+    InvalidClientHello,
+    ServerError,
+
+    ControlUnreachable,
+    ControlTimeout,
+    NoAuthenticationKey,
+    MalformedServerMessage,
+}
+
+impl ErrorCode {
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::LocalUnreachable,
+        ErrorCode::InvalidRequest,
+        ErrorCode::RequestTimeout,
+        ErrorCode::PayloadTooLarge,
+        ErrorCode::RateLimited,
+        ErrorCode::TooManyConcurrentRequests,
+        ErrorCode::TunnelOverloaded,
+        ErrorCode::ServerOverloaded,
+        ErrorCode::TunnelNotFound,
+        ErrorCode::InvalidHost,
+        ErrorCode::ErrorLocatingTunnel,
+        ErrorCode::RoutingScriptFailed,
+        ErrorCode::Forbidden,
+        ErrorCode::AwaitingApproval,
+        ErrorCode::Unauthorized,
+        ErrorCode::AuthorizationUnavailable,
+        ErrorCode::AuthFailed,
+        ErrorCode::KeyRejected,
+        ErrorCode::InvalidSubDomain,
+        ErrorCode::InvalidBaseDomain,
+        ErrorCode::SubDomainInUse,
+        ErrorCode::SubDomainReserved,
+        ErrorCode::TunnelLimitReached,
+        ErrorCode::UnsupportedTunnelType,
+        ErrorCode::InvalidClientHello,
+        ErrorCode::ServerError,
+        ErrorCode::ControlUnreachable,
+        ErrorCode::ControlTimeout,
+        ErrorCode::NoAuthenticationKey,
+        ErrorCode::MalformedServerMessage,
+    ];
+
+    /// The code, i.e. `TUN-2003`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::LocalUnreachable => "TUN-1001",
+            ErrorCode::InvalidRequest => "TUN-1002",
+            ErrorCode::RequestTimeout => "TUN-2001",
+            ErrorCode::PayloadTooLarge => "TUN-2002",
+            ErrorCode::RateLimited => "TUN-2003",
+            ErrorCode::TooManyConcurrentRequests => "TUN-2004",
+            ErrorCode::TunnelOverloaded => "TUN-2005",
+            ErrorCode::ServerOverloaded => "TUN-2006",
+            ErrorCode::TunnelNotFound => "TUN-3001",
+            ErrorCode::InvalidHost => "TUN-3002",
+            ErrorCode::ErrorLocatingTunnel => "TUN-3003",
+            ErrorCode::RoutingScriptFailed => "TUN-3004",
+            ErrorCode::Forbidden => "TUN-3005",
+            ErrorCode::AwaitingApproval => "TUN-3006",
+            ErrorCode::Unauthorized => "TUN-3007",
+            ErrorCode::AuthorizationUnavailable => "TUN-3008",
+            ErrorCode::AuthFailed => "TUN-4001",
+            ErrorCode::KeyRejected => "TUN-4002",
+            ErrorCode::InvalidSubDomain => "TUN-4003",
+            ErrorCode::InvalidBaseDomain => "TUN-4004",
+            ErrorCode::SubDomainInUse => "TUN-4005",
+            ErrorCode::SubDomainReserved => "TUN-4006",
+            ErrorCode::TunnelLimitReached => "TUN-4007",
+            ErrorCode::UnsupportedTunnelType => "TUN-4008",
+            ErrorCode::InvalidClientHello => "TUN-4009",
+            ErrorCode::ServerError => "TUN-4010",
+            ErrorCode::ControlUnreachable => "TUN-5001",
+            ErrorCode::ControlTimeout => "TUN-5002",
+            ErrorCode::NoAuthenticationKey => "TUN-5003",
+            ErrorCode::MalformedServerMessage => "TUN-5004",
+        }
+    }
+
+    /// The machine-readable cause json error bodies and access logs carry, i.e. `rate_limited`
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ErrorCode::LocalUnreachable => "local_service_unavailable",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::RequestTimeout => "request_timeout",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::TooManyConcurrentRequests => "too_many_requests",
+            ErrorCode::TunnelOverloaded => "tunnel_overloaded",
+            ErrorCode::ServerOverloaded => "server_overloaded",
+            ErrorCode::TunnelNotFound => "tunnel_not_found",
+            ErrorCode::InvalidHost => "invalid_host",
+            ErrorCode::ErrorLocatingTunnel => "error_locating_tunnel",
+            ErrorCode::RoutingScriptFailed => "routing_script_failed",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::AwaitingApproval => "awaiting_approval",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::AuthorizationUnavailable => "authorization_unavailable",
+            ErrorCode::AuthFailed => "auth_failed",
+            ErrorCode::KeyRejected => "key_rejected",
+            ErrorCode::InvalidSubDomain => "invalid_sub_domain",
+            ErrorCode::InvalidBaseDomain => "invalid_base_domain",
+            ErrorCode::SubDomainInUse => "sub_domain_in_use",
+            ErrorCode::SubDomainReserved => "sub_domain_reserved",
+            ErrorCode::TunnelLimitReached => "tunnel_limit_reached",
+            ErrorCode::UnsupportedTunnelType => "unsupported_tunnel_type",
+            ErrorCode::InvalidClientHello => "invalid_client_hello",
+            ErrorCode::ServerError => "server_error",
+            ErrorCode::ControlUnreachable => "control_unreachable",
+            ErrorCode::ControlTimeout => "control_timeout",
+            ErrorCode::NoAuthenticationKey => "no_authentication_key",
+            ErrorCode::MalformedServerMessage => "malformed_server_message",
+        }
+    }
+
+    /// What happened, in a line
+    pub fn summary(&self) -> &'static str {
+        match self {
+            ErrorCode::LocalUnreachable => "The local service could not be reached",
+            ErrorCode::InvalidRequest => "The visitor's request could not be read or forwarded",
+            ErrorCode::RequestTimeout => "The request took too long to be answered",
+            ErrorCode::PayloadTooLarge => "The request body is larger than the tunnel allows",
+            ErrorCode::RateLimited => "Too many requests to this path",
+            ErrorCode::TooManyConcurrentRequests => "Too many requests under way on the tunnel",
+            ErrorCode::TunnelOverloaded => "The tunnel can't keep up with its traffic",
+            ErrorCode::ServerOverloaded => "The server is overloaded and turned the request away",
+            ErrorCode::TunnelNotFound => "No tunnel is open on this host",
+            ErrorCode::InvalidHost => "The request's host isn't served by this server",
+            ErrorCode::ErrorLocatingTunnel => "The server failed to find the tunnel's client",
+            ErrorCode::RoutingScriptFailed => "The server's routing script failed",
+            ErrorCode::Forbidden => "The request was refused by the tunnel's access rules",
+            ErrorCode::AwaitingApproval => "The tunnel is held until the account approves it",
+            ErrorCode::Unauthorized => "The request needs credentials",
+            ErrorCode::AuthorizationUnavailable => "The server's authorization service failed",
+            ErrorCode::AuthFailed => "The server did not accept the authentication key",
+            ErrorCode::KeyRejected => "The authentication key expired or was revoked",
+            ErrorCode::InvalidSubDomain => "The requested sub-domain is not valid",
+            ErrorCode::InvalidBaseDomain => "The server does not serve the requested base domain",
+            ErrorCode::SubDomainInUse => "The sub-domain is in use by another tunnel",
+            ErrorCode::SubDomainReserved => "The sub-domain is reserved by another account",
+            ErrorCode::TunnelLimitReached => "The account has as many tunnels open as it may",
+            ErrorCode::UnsupportedTunnelType => "The server does not offer this kind of tunnel",
+            ErrorCode::InvalidClientHello => "The server could not understand the client",
+            ErrorCode::ServerError => "The server failed while opening the tunnel",
+            ErrorCode::ControlUnreachable => "The control server could not be reached",
+            ErrorCode::ControlTimeout => "The control server stopped answering",
+            ErrorCode::NoAuthenticationKey => "No authentication key is set",
+            ErrorCode::MalformedServerMessage => "The server sent a message the client can't read",
+        }
+    }
+
+    /// What to do about it
+    pub fn help(&self) -> &'static str {
+        match self {
+            ErrorCode::LocalUnreachable => {
+                "Make sure the local service is running and listening on the host and port the \
+                 client forwards to (--host, --port), and that it answers before --grace-local runs out."
+            }
+            ErrorCode::InvalidRequest => {
+                "The visitor sent a request the client couldn't parse or rebuild for the local \
+                 service, check its url and headers. Run the client with --verbose for details."
+            }
+            ErrorCode::RequestTimeout => {
+                "The local service took too long to answer or the visitor too long to send the \
+                 request. Speed up slow endpoints, or stream long responses."
+            }
+            ErrorCode::PayloadTooLarge => {
+                "Send a smaller body, or raise the tunnel's request size limit with a traffic \
+                 profile such as --traffic-profile bulk-files."
+            }
+            ErrorCode::RateLimited => {
+                "A rate limit on the path was exceeded. Wait for the time in the Retry-After header \
+                 before retrying, or raise the tunnel's --rate-limit."
+            }
+            ErrorCode::TooManyConcurrentRequests => {
+                "The tunnel has as many requests under way as its plan allows. Retry once some \
+                 finish, or answer requests faster."
+            }
+            ErrorCode::TunnelOverloaded => {
+                "The client isn't reading the tunnel's traffic fast enough. Check the client's \
+                 network and load, and retry shortly."
+            }
+            ErrorCode::ServerOverloaded => {
+                "The server sheds requests to lower priority tunnels while it is overloaded. \
+                 Retry after the time in the Retry-After header, or ask the server's operator \
+                 for a higher priority plan."
+            }
+            ErrorCode::TunnelNotFound => {
+                "Check the url, and that the client for this sub-domain is running and connected."
+            }
+            ErrorCode::InvalidHost => {
+                "The Host header must be a sub-domain of a domain the server serves. Check the url."
+            }
+            ErrorCode::ErrorLocatingTunnel => {
+                "The server could not reach the instance holding the tunnel. Retry shortly, and \
+                 contact the server's operator if it persists."
+            }
+            ErrorCode::RoutingScriptFailed => {
+                "The operator's routing script errored on the request. Contact the server's \
+                 operator with the request id."
+            }
+            ErrorCode::Forbidden => {
+                "The tunnel's access rules or an authorization service refused the visitor. Check \
+                 the tunnel's --acl rules."
+            }
+            ErrorCode::AwaitingApproval => {
+                "The account's policy holds new tunnels. An owner of the account approves it with \
+                 `tunnelto approve <sub-domain>`."
+            }
+            ErrorCode::Unauthorized => {
+                "Sign in, or send the credentials the tunnel expects, i.e. its basic auth."
+            }
+            ErrorCode::AuthorizationUnavailable => {
+                "The server could not ask its authorization service about the request. Retry \
+                 shortly, and contact the server's operator if it persists."
+            }
+            ErrorCode::AuthFailed => {
+                "Check the key set with `tunnelto set-auth` or --key. If it should be valid, run \
+                 `tunnelto doctor` to check your clock isn't skewed."
+            }
+            ErrorCode::KeyRejected => {
+                "Get a new key for your account and set it with `tunnelto set-auth`."
+            }
+            ErrorCode::InvalidSubDomain => {
+                "Sub-domains are lowercase letters, digits and dashes, and some names are \
+                 reserved. Pick another --subdomain."
+            }
+            ErrorCode::InvalidBaseDomain => {
+                "Pick one of the domains the server serves with --base-domain, or leave it out."
+            }
+            ErrorCode::SubDomainInUse => {
+                "Another tunnel holds the sub-domain. Close it, or pick another --subdomain."
+            }
+            ErrorCode::SubDomainReserved => {
+                "Another account owns the sub-domain. Pick another --subdomain, or ask its owner \
+                 for a grant with `tunnelto grant`."
+            }
+            ErrorCode::TunnelLimitReached => {
+                "Close one of the account's tunnels, or upgrade the plan."
+            }
+            ErrorCode::UnsupportedTunnelType => {
+                "The server doesn't allow this tunnel type (i.e. tcp or tls passthrough) for your \
+                 account. Open an http tunnel instead."
+            }
+            ErrorCode::InvalidClientHello => {
+                "The client may be too old or too new for the server. Update the client."
+            }
+            ErrorCode::ServerError => {
+                "Retry shortly, and contact the server's operator if it persists."
+            }
+            ErrorCode::ControlUnreachable => {
+                "Check your network connection and the control server's address (CTRL_HOST, \
+                 CTRL_PORT). Run `tunnelto doctor` to diagnose."
+            }
+            ErrorCode::ControlTimeout => {
+                "The connection to the control server stalled, the client retries on its own. \
+                 Run `tunnelto doctor` if it keeps happening."
+            }
+            ErrorCode::NoAuthenticationKey => {
+                "Set a key with `tunnelto set-auth --key <KEY>`, or pass --key."
+            }
+            ErrorCode::MalformedServerMessage => {
+                "The client may be too old for the server. Update the client."
+            }
+        }
+    }
+
+    /// The error a code (`TUN-2003`, `2003`) or reason (`rate_limited`) stands for
+    pub fn lookup(query: &str) -> Option<ErrorCode> {
+        let query = query.trim();
+        let number = query
+            .get(..4)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("TUN-"))
+            .map_or(query, |_| &query[4..]);
+
+        ErrorCode::ALL
+            .iter()
+            .find(|code| &code.code()[4..] == number || code.reason() == query)
+            .copied()
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
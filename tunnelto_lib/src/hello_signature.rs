@@ -0,0 +1,154 @@
+//! Client hellos proving an auth key without sending it, so a proxy terminating TLS in
+//! front of the control server can neither learn the key nor replay a handshake it
+//! recorded.
+//!
+//! A signing client sends `ClientType::SignedAuth` with the key's `key_id` in place of the
+//! key. The server answers `ServerHello::Challenge` with a random challenge for this
+//! connection only, and the client replies with a `HelloProof`: the Ed25519 signature of
+//! `"<challenge>."` followed by its hello as sent. The signing key is derived from the
+//! auth key and never leaves the client; the server checks the signature against the
+//! `public_key` it stored along with the key, so its store doesn't hold anything that
+//! can sign either. Keys stored before servers kept public keys can't sign until they
+//! are stored again, i.e. rotated.
+use super::ClientId;
+use rand::Rng;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use thiserror::Error;
+
+/// What the signing key is derived from the auth key with
+const SIGNING_KEY_LABEL: &[u8] = b"tunnelto hello signing key v1";
+
+/// The client's reply to `ServerHello::Challenge`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HelloProof {
+    /// base64url
+    pub signature: String,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("the key has no public key to check hello signatures with")]
+    NoPublicKey,
+
+    #[error("hello signature does not match")]
+    BadSignature,
+}
+
+/// The id the server stores an auth key under, sent in place of the key
+pub fn key_id(auth_key: &str) -> String {
+    let hash = sha2::Sha256::digest(auth_key.as_bytes());
+    base64::encode_config(&hash, base64::URL_SAFE_NO_PAD)
+}
+
+/// The client id of the key with this `key_id`, as `SecretKey::client_id` gives it
+pub fn client_id(key_id: &str) -> Option<ClientId> {
+    let hash = base64::decode_config(key_id, base64::URL_SAFE_NO_PAD).ok()?;
+    Some(ClientId(base64::encode(&hash)))
+}
+
+fn signing_key(auth_key: &str) -> Ed25519KeyPair {
+    let seed = hmac_sha256::HMAC::mac(SIGNING_KEY_LABEL, auth_key.as_bytes());
+    Ed25519KeyPair::from_seed_unchecked(&seed).expect("a 32 byte seed is a valid key")
+}
+
+/// The public half of the key's signing key, for the server to store with the key
+pub fn public_key(auth_key: &str) -> String {
+    let key_pair = signing_key(auth_key);
+    base64::encode_config(key_pair.public_key().as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+/// A fresh challenge for one connection
+pub fn challenge() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+fn signed_message(challenge: &str, hello: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}.", challenge).into_bytes();
+    message.extend_from_slice(hello);
+    message
+}
+
+/// Sign the hello, exactly as it was sent, for the server's challenge
+pub fn sign(auth_key: &str, challenge: &str, hello: &[u8]) -> HelloProof {
+    let signature = signing_key(auth_key).sign(&signed_message(challenge, hello));
+    HelloProof {
+        signature: base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD),
+    }
+}
+
+/// Check the proof signs the hello, as received, for the challenge we issued
+pub fn verify(
+    public_key: &str,
+    challenge: &str,
+    hello: &[u8],
+    proof: &HelloProof,
+) -> Result<(), SignatureError> {
+    let public_key = base64::decode_config(public_key, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| SignatureError::NoPublicKey)?;
+    let signature = base64::decode_config(&proof.signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| SignatureError::BadSignature)?;
+
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(&signed_message(challenge, hello), &signature)
+        .map_err(|_| SignatureError::BadSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SecretKey;
+
+    const KEY: &str = "bvpWdqknC6D6TBFHdX0ykW";
+    const HELLO: &[u8] = br#"{"client_type":{"SignedAuth":{"key_id":"x"}},"sub_domain":"foo"}"#;
+
+    #[test]
+    fn sign_and_verify() {
+        let challenge = challenge();
+        let proof = sign(KEY, &challenge, HELLO);
+        assert_eq!(verify(&public_key(KEY), &challenge, HELLO, &proof), Ok(()));
+    }
+
+    #[test]
+    fn proof_is_bound_to_challenge_hello_and_key() {
+        let challenge = challenge();
+        let proof = sign(KEY, &challenge, HELLO);
+        let public_key = public_key(KEY);
+
+        // replayed on another connection
+        assert_eq!(
+            verify(&public_key, &super::challenge(), HELLO, &proof),
+            Err(SignatureError::BadSignature)
+        );
+        // the hello was changed on the way, i.e. to ask for another sub-domain
+        let tampered = String::from_utf8_lossy(HELLO).replace("foo", "bar");
+        assert_eq!(
+            verify(&public_key, &challenge, tampered.as_bytes(), &proof),
+            Err(SignatureError::BadSignature)
+        );
+        // signed with another key
+        assert_eq!(
+            verify(&super::public_key("other"), &challenge, HELLO, &proof),
+            Err(SignatureError::BadSignature)
+        );
+        assert_eq!(
+            verify("", &challenge, HELLO, &proof),
+            Err(SignatureError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn nothing_sent_reveals_the_key() {
+        let challenge = challenge();
+        let proof = sign(KEY, &challenge, HELLO);
+        for sent in &[key_id(KEY), public_key(KEY), proof.signature] {
+            assert!(!sent.contains(KEY));
+        }
+        // what the key is stored under stays the same for signing clients
+        assert_eq!(
+            client_id(&key_id(KEY)),
+            Some(SecretKey(KEY.to_string()).client_id())
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! One captured request and its response, shared read-only through a short-lived
+//! inspect link the server serves, i.e. to paste into a bug report. Snapshots are
+//! redacted before they leave the client, and again by the server.
+use serde::{Deserialize, Serialize};
+
+use crate::SecretKey;
+
+/// bodies are cut at this length
+pub const MAX_BODY_BYTES: usize = 16 * 1024;
+
+const REDACTED: &str = "[redacted]";
+
+/// headers never shared, whatever their value
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// header, query parameter and json field names containing any of these are redacted
+const SENSITIVE_NAMES: &[&str] = &[
+    "token",
+    "secret",
+    "password",
+    "passwd",
+    "api_key",
+    "api-key",
+    "apikey",
+    "session",
+    "signature",
+    "credential",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InspectSnapshot {
+    pub method: String,
+    /// with the query, if any
+    pub path: String,
+    pub status: u16,
+    /// the id the edge assigned to the request
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// when the response was sent, RFC 3339
+    pub captured_at: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: String,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: String,
+}
+
+impl InspectSnapshot {
+    /// Drop credentials from the headers, query and bodies, and cut the bodies short
+    pub fn redacted(mut self) -> Self {
+        self.path = redact_path(&self.path);
+        redact_headers(&mut self.request_headers);
+        redact_headers(&mut self.response_headers);
+        self.request_body = redact_body(&self.request_body);
+        self.response_body = redact_body(&self.response_body);
+        self
+    }
+}
+
+/// Like `String::from_utf8_lossy`, but describing binary data instead of mangling it
+pub fn body_text(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("[{} bytes of binary data]", data.len()),
+    }
+}
+
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SENSITIVE_NAMES.iter().any(|word| name.contains(word))
+}
+
+fn redact_headers(headers: &mut [(String, String)]) {
+    for (name, value) in headers.iter_mut() {
+        if SENSITIVE_HEADERS.contains(&name.to_lowercase().as_str()) || is_sensitive(name) {
+            *value = REDACTED.to_string();
+        }
+    }
+}
+
+fn redact_path(path: &str) -> String {
+    match path.split_once('?') {
+        Some((path, query)) => format!("{}?{}", path, redact_form(query)),
+        None => path.to_string(),
+    }
+}
+
+/// `a=1&token=2` => `a=1&token=[redacted]`
+fn redact_form(form: &str) -> String {
+    form.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if is_sensitive(name) => format!("{}={}", name, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn redact_body(body: &str) -> String {
+    let body = if let Ok(mut json) = serde_json::from_str::<serde_json::Value>(body) {
+        redact_json(&mut json);
+        serde_json::to_string_pretty(&json).unwrap_or_default()
+    } else if !body.is_empty() && !body.contains(char::is_whitespace) && body.contains('=') {
+        redact_form(body)
+    } else {
+        body.to_string()
+    };
+    truncate(body)
+}
+
+fn redact_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                if is_sensitive(name) {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+/// Cut the body to `MAX_BODY_BYTES`, marker included, so redacting twice cuts once
+fn truncate(mut body: String) -> String {
+    if body.len() <= MAX_BODY_BYTES {
+        return body;
+    }
+    let marker = format!("\n[cut from {} bytes]", body.len());
+    let mut end = MAX_BODY_BYTES - marker.len();
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    body.truncate(end);
+    body.push_str(&marker);
+    body
+}
+
+/// Request to share a snapshot through an inspect link
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InspectLinkRequest {
+    pub auth_key: SecretKey,
+    pub snapshot: InspectSnapshot,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum InspectLinkResponse {
+    Created {
+        /// the link's path on the control server
+        path: String,
+        /// the full link, if the server knows its public address
+        #[serde(default)]
+        url: Option<String>,
+        expires_at: String,
+    },
+    Failed {
+        reason: String,
+    },
+}
@@ -0,0 +1,92 @@
+//! End-to-end integrity checks for stream data relayed through the tunnel.
+//!
+//! When a client asks for it (`ClientHello::integrity`) and the server supports it
+//! (`features::INTEGRITY`), whichever side finishes sending a stream follows its last
+//! `Data` packet with a `Trailer` packet. The trailer carries the length and CRC-32 of
+//! everything sent on the stream in that direction. The receiving side compares it with
+//! what it actually delivered, so bytes lost or garbled between the two sockets are
+//! reported instead of silently shipped.
+use std::fmt;
+
+/// Running length and CRC-32 of the data sent or delivered on a stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamChecksum {
+    pub bytes: u64,
+    pub crc32: u32,
+}
+
+/// How the data delivered differs from what the other side sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// fewer or more bytes arrived than were sent
+    Length { sent: u64, delivered: u64 },
+    /// the right number of bytes arrived, but not the same ones
+    Corrupted { bytes: u64 },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Length { sent, delivered } => write!(
+                f,
+                "length mismatch: {} bytes sent, {} delivered",
+                sent, delivered
+            ),
+            Mismatch::Corrupted { bytes } => {
+                write!(
+                    f,
+                    "checksum mismatch over {} bytes: data was garbled",
+                    bytes
+                )
+            }
+        }
+    }
+}
+
+impl StreamChecksum {
+    pub const ENCODED_LEN: usize = 12;
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut hasher = crc32fast::Hasher::new_with_initial(self.crc32);
+        hasher.update(data);
+        self.crc32 = hasher.finalize();
+        self.bytes += data.len() as u64;
+    }
+
+    /// Compare what was delivered (`self`) with the trailer the sender sent
+    pub fn verify(&self, sent: &StreamChecksum) -> Result<(), Mismatch> {
+        if self.bytes != sent.bytes {
+            return Err(Mismatch::Length {
+                sent: sent.bytes,
+                delivered: self.bytes,
+            });
+        }
+        if self.crc32 != sent.crc32 {
+            return Err(Mismatch::Corrupted { bytes: self.bytes });
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            self.bytes.to_be_bytes().to_vec(),
+            self.crc32.to_be_bytes().to_vec(),
+        ]
+        .concat()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != Self::ENCODED_LEN {
+            return None;
+        }
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[..8]);
+        let mut crc32 = [0u8; 4];
+        crc32.copy_from_slice(&data[8..]);
+
+        Some(StreamChecksum {
+            bytes: u64::from_be_bytes(bytes),
+            crc32: u32::from_be_bytes(crc32),
+        })
+    }
+}
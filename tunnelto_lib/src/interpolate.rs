@@ -0,0 +1,73 @@
+//! Secrets and settings interpolated into config values, so configs can be committed
+//! without embedding them.
+//!
+//! - `${VAR}` is the environment variable `VAR`, an error if it isn't set
+//! - `${VAR:-default}` falls back to `default` if `VAR` is unset or empty
+//! - `${file:/run/secrets/key}` is the contents of the file, without the trailing newline
+//! - `$$` is a literal `$`
+use std::env;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum InterpolateError {
+    #[error("environment variable `{0}` is not set")]
+    MissingVariable(String),
+
+    #[error("can't read `{0}`: {1}")]
+    UnreadableFile(String, String),
+
+    #[error("unterminated `${{` in `{0}`")]
+    Unterminated(String),
+}
+
+/// Replace every reference in `s`
+pub fn interpolate(s: &str) -> Result<String, InterpolateError> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("$$") {
+            out.push('$');
+            rest = &rest[2..];
+            continue;
+        }
+        if !rest.starts_with("${") {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+
+        let end = rest
+            .find('}')
+            .ok_or_else(|| InterpolateError::Unterminated(s.to_string()))?;
+        out.push_str(&resolve(&rest[2..end])?);
+        rest = &rest[end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn resolve(expr: &str) -> Result<String, InterpolateError> {
+    if let Some(path) = expr.strip_prefix("file:") {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| InterpolateError::UnreadableFile(path.to_string(), e.to_string()))?;
+        return Ok(contents.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
+
+    let (name, default) = match expr.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (expr, None),
+    };
+
+    match (env::var(name), default) {
+        (Ok(value), None) => Ok(value),
+        // like a shell, the default also covers set but empty variables
+        (Ok(value), Some(_)) if !value.is_empty() => Ok(value),
+        (_, Some(default)) => Ok(default.to_string()),
+        _ => Err(InterpolateError::MissingVariable(name.to_string())),
+    }
+}
@@ -1,6 +1,21 @@
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
+use std::collections::VecDeque;
+
+mod error;
+pub use self::error::*;
+mod error_code;
+pub use self::error_code::*;
+pub mod verify;
+pub mod acl;
+pub mod hello_signature;
+pub mod interpolate;
+pub mod integrity;
+pub mod inspect;
+pub mod middleware;
+pub mod parallel_data;
+pub mod rate_limit;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(transparent)]
@@ -33,10 +48,176 @@ pub enum ServerHello {
     Success {
         sub_domain: String,
         client_id: ClientId,
+        /// every public url the tunnel is reachable on
+        #[serde(default)]
+        public_urls: Vec<String>,
+        /// optional protocol features the server enabled for this tunnel
+        #[serde(default)]
+        features: Vec<String>,
+        #[serde(default)]
+        limits: TunnelLimits,
+        #[serde(default)]
+        reconnect_token: Option<ReconnectToken>,
+        /// short-lived token for opening a secondary data connection, see `DataHello`
+        #[serde(default)]
+        data_token: Option<String>,
+        /// what the server can do beyond plain http tunnels, none of it for older servers
+        #[serde(default)]
+        capabilities: Box<Capabilities>,
+        /// protocol features the server is phasing out
+        #[serde(default)]
+        deprecations: Vec<Deprecation>,
+        /// the account's policy holds the tunnel until a teammate approves it, see
+        /// `ApprovalRequest`
+        #[serde(default)]
+        awaiting_approval: bool,
     },
     SubDomainInUse,
+    /// another account reserved the sub-domain
+    SubDomainReserved,
     InvalidSubDomain,
-    AuthFailed,
+    /// the hello or its credentials were refused, `code` telling which as
+    /// `ErrorCode::code` does, i.e. `TUN-4001`
+    AuthFailed {
+        code: String,
+        reason: String,
+    },
+    /// the server failed while handling the hello, try again later
+    ServerError,
+    /// like `AuthFailed`, for a key that expired or was revoked, saying which
+    KeyRejected {
+        reason: String,
+    },
+    TunnelLimitReached,
+    UnsupportedTunnelType,
+    /// this instance is busy, reconnect to the control endpoint given
+    Redirect {
+        endpoint: String,
+    },
+    /// sign the hello for this challenge to prove the key, see `hello_signature`
+    Challenge {
+        challenge: String,
+    },
+}
+
+/// Limits the server enforces on a tunnel, `None` means unlimited
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TunnelLimits {
+    pub max_tunnels: Option<u32>,
+    pub max_bandwidth: Option<u64>,
+}
+
+/// Optional capabilities a server may have, announced in `ServerHello::Success` and
+/// served at `/capabilities`, so clients can leave out what the server can't do
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Capabilities {
+    /// raw TCP tunnels
+    pub tcp_tunnels: bool,
+    /// `TunnelType::TlsPassthrough` tunnels
+    pub tls_passthrough: bool,
+    /// encodings the server can compress stream data with, empty if none
+    pub compression: Vec<String>,
+    /// where the server accepts tunnels over QUIC, if it does
+    pub quic_endpoint: Option<String>,
+    /// the server can relay a client's request inspector to the web
+    pub inspector_relay: bool,
+    /// where `tunnelto login` signs in, if the server takes JWTs from an identity provider
+    pub login: Option<LoginProvider>,
+}
+
+/// An OpenID provider clients get JWTs from with the OAuth2 device flow
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LoginProvider {
+    /// its endpoints are discovered at `<issuer>/.well-known/openid-configuration`
+    pub issuer: String,
+    /// the client registered for the device flow
+    pub client_id: String,
+    /// set if the server takes access tokens for this api rather than id tokens
+    #[serde(default)]
+    pub audience: Option<String>,
+}
+
+/// A feature the server is phasing out, for the client to warn about
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Deprecation {
+    /// what is deprecated, i.e. `anonymous` or one of `features`
+    pub feature: String,
+    /// why, and what to use instead
+    pub message: String,
+    /// when support ends, if decided
+    #[serde(default)]
+    pub removed_after: Option<String>,
+}
+
+impl std::fmt::Display for Deprecation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.feature, self.message)?;
+        if let Some(removed_after) = self.removed_after.as_ref() {
+            write!(f, " (removed after {})", removed_after)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reply to `GET /capabilities` on the control server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapabilitiesResponse {
+    pub features: Vec<String>,
+    pub capabilities: Capabilities,
+    pub deprecations: Vec<Deprecation>,
+}
+
+/// Optional protocol features announced in `ServerHello::Success`
+pub mod features {
+    /// the server hands out reconnect tokens to resume the tunnel
+    pub const RECONNECT_TOKEN: &str = "reconnect_token";
+    /// the server routes raw TLS connections to the tunnel by SNI
+    pub const TLS_PASSTHROUGH: &str = "tls_passthrough";
+    /// the client may move stream traffic to a second websocket at `<control url>/data`
+    pub const DATA_CONNECTION: &str = "data_connection";
+    /// a second client may stand by for a reserved sub-domain, see `ClientHello::standby`
+    pub const STANDBY: &str = "standby";
+    /// the server tunes streams for the profile in `ClientHello::traffic_profile`
+    pub const TRAFFIC_PROFILE: &str = "traffic_profile";
+    /// the server sends and checks stream trailers, see `integrity`
+    pub const INTEGRITY: &str = "integrity";
+    /// the server streams the tunnel's access log, see `ClientHello::access_log`
+    pub const ACCESS_LOG: &str = "access_log";
+    /// the server holds tunnels to sensitive targets until a teammate approves them
+    pub const APPROVAL: &str = "approval";
+    /// the server enforces the request rates in `ClientHello::rate_limits`
+    pub const RATE_LIMITS: &str = "rate_limits";
+    /// the server asks clients to reconnect before it's replaced, see `ControlPacket::Handover`
+    pub const HANDOVER: &str = "handover";
+    /// sub-domains no tunnel serves go to a catch-all tunnel, see `ClientHello::catch_all`
+    pub const CATCH_ALL: &str = "catch_all";
+    /// the server frees the sub-domain of a client shutting down, see `ControlPacket::Goodbye`
+    pub const GOODBYE: &str = "goodbye";
+    /// the server closes the visitor's connection when the client sends `ControlPacket::End`
+    pub const STREAM_END: &str = "stream_end";
+    /// the client may open up to `MAX_DATA_CONNECTIONS` data connections and spread
+    /// streams over them, see `ControlPacket::Sequenced`
+    pub const PARALLEL_DATA: &str = "parallel_data";
+}
+
+/// Data connections a client may open with one `data_token`
+pub const MAX_DATA_CONNECTIONS: usize = 4;
+
+/// First message on a secondary data connection. Once accepted, stream packets
+/// (`Init`, `Data`, `Refused`, `End`) travel over it while pings stay on the control
+/// connection, so bulk transfers can't hold up the control channel. Servers with
+/// `features::PARALLEL_DATA` take up to `MAX_DATA_CONNECTIONS` with the same token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DataHello {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum DataHelloReply {
+    Accepted,
+    /// the token was unknown or expired, keep using the control connection
+    Rejected,
 }
 
 impl ServerHello {
@@ -63,6 +244,136 @@ pub struct ClientHello {
     pub sub_domain: Option<String>,
     pub client_type: ClientType,
     pub reconnect_token: Option<ReconnectToken>,
+    /// which of the server's base domains to serve the sub-domain on
+    #[serde(default)]
+    pub base_domain: Option<String>,
+    #[serde(default)]
+    pub tunnel_type: TunnelType,
+    /// secret the server signs forwarded requests with, see `verify`
+    #[serde(default)]
+    pub signing_secret: Option<String>,
+    /// path rules the edge enforces on visitors, see `acl`
+    #[serde(default)]
+    pub access_rules: Vec<acl::AccessRule>,
+    /// per-path request rates the edge enforces on visitors, see `rate_limit`
+    #[serde(default)]
+    pub rate_limits: Vec<rate_limit::RateLimitRule>,
+    #[serde(default)]
+    pub error_format: ErrorFormat,
+    /// wait as a passive standby for a reserved sub-domain, taking over its traffic
+    /// while the client serving it is disconnected
+    #[serde(default)]
+    pub standby: bool,
+    /// what the tunnel expects to carry, so the server can tune for it
+    #[serde(default)]
+    pub traffic_profile: TrafficProfile,
+    /// the machine the client runs on, kept in the account's sub-domain history
+    #[serde(default)]
+    pub client_hostname: Option<String>,
+    /// exchange `Trailer` packets to check streams arrive intact, see `integrity`
+    #[serde(default)]
+    pub integrity: bool,
+    /// stream the edge's access log for the tunnel as `AccessLog` packets
+    #[serde(default)]
+    pub access_log: bool,
+    /// reconnect right away on `ControlPacket::Handover`, serving the streams under way
+    /// on the old connection until the server closes it
+    #[serde(default)]
+    pub handover: bool,
+    /// the local service the tunnel forwards to, i.e. `http://localhost:5432`, checked
+    /// against the account's approval policy
+    #[serde(default)]
+    pub target: Option<String>,
+    /// serve every sub-domain of the base domain no other tunnel serves, instead of one
+    #[serde(default)]
+    pub catch_all: bool,
+    /// the version of this crate the client was built with, so the server only answers
+    /// with hellos it can read, see `ServerHello::for_client`
+    #[serde(default)]
+    pub client_version: Option<String>,
+}
+
+/// How visitor traffic reaches the tunnel
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelType {
+    /// http requests routed by host header
+    #[default]
+    Http,
+    /// raw TLS connections routed by SNI, never terminated by the server
+    TlsPassthrough,
+}
+
+/// The traffic a tunnel expects to carry
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrafficProfile {
+    /// a mix of pages and api calls
+    #[default]
+    General,
+    /// few, small requests, i.e. provider webhooks
+    Webhooks,
+    /// long-lived connections, i.e. server-sent events or video
+    Streaming,
+    /// large uploads and downloads
+    BulkFiles,
+}
+
+impl std::str::FromStr for TrafficProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "general" => Ok(TrafficProfile::General),
+            "webhooks" => Ok(TrafficProfile::Webhooks),
+            "streaming" => Ok(TrafficProfile::Streaming),
+            "bulk-files" => Ok(TrafficProfile::BulkFiles),
+            _ => Err(format!(
+                "unknown traffic profile `{}`, expected general, webhooks, streaming or bulk-files",
+                s
+            )),
+        }
+    }
+}
+
+/// How errors answered on the tunnel's behalf are rendered for visitors
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    /// plain text, for browsers
+    #[default]
+    Text,
+    /// an `ErrorEnvelope`, for api clients
+    Json,
+}
+
+/// The json body of an error answered on a tunnel's behalf
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorEnvelope {
+    pub status: u16,
+    /// stable code, i.e. `TUN-3001`, see `ErrorCode`
+    #[serde(default)]
+    pub code: String,
+    /// stable machine-readable cause, i.e. `tunnel_not_found`
+    pub reason: String,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+impl ErrorEnvelope {
+    pub fn new(status: u16, code: ErrorCode, message: &str, request_id: Option<&str>) -> Self {
+        ErrorEnvelope {
+            status,
+            code: code.code().to_string(),
+            reason: code.reason().to_string(),
+            message: message.to_string(),
+            request_id: request_id.map(String::from),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -81,6 +392,21 @@ impl ClientHello {
             client_type: typ,
             sub_domain,
             reconnect_token: None,
+            base_domain: None,
+            tunnel_type: TunnelType::Http,
+            signing_secret: None,
+            access_rules: vec![],
+            rate_limits: vec![],
+            error_format: ErrorFormat::Text,
+            standby: false,
+            traffic_profile: TrafficProfile::default(),
+            client_hostname: None,
+            integrity: false,
+            access_log: false,
+            handover: false,
+            target: None,
+            catch_all: false,
+            client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
         }
     }
 
@@ -90,6 +416,21 @@ impl ClientHello {
             sub_domain: None,
             client_type: ClientType::Anonymous,
             reconnect_token: Some(reconnect_token),
+            base_domain: None,
+            tunnel_type: TunnelType::Http,
+            signing_secret: None,
+            access_rules: vec![],
+            rate_limits: vec![],
+            error_format: ErrorFormat::Text,
+            standby: false,
+            traffic_profile: TrafficProfile::default(),
+            client_hostname: None,
+            integrity: false,
+            access_log: false,
+            handover: false,
+            target: None,
+            catch_all: false,
+            client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
         }
     }
 }
@@ -97,7 +438,247 @@ impl ClientHello {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientType {
     Auth { key: SecretKey },
+    /// an auth key proven by signing the server's challenge instead of sent, by its
+    /// `key_id`, see `hello_signature`
+    SignedAuth { key_id: String },
     Anonymous,
+    /// a signed token from an identity provider the server trusts (i.e. GitHub OIDC)
+    Jwt { token: String },
+    /// the certificate the client presented when connecting to the control server over TLS
+    Certificate,
+}
+
+/// Request to claim the sub-domain matching a domain the account owns
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClaimRequest {
+    pub auth_key: SecretKey,
+    pub domain: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ClaimResponse {
+    Verified { sub_domain: String },
+    Pending { txt_name: String, txt_value: String },
+    Failed { reason: String },
+}
+
+/// Request to let another account claim a sub-domain you own for a while, or to
+/// take that permission back
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GrantRequest {
+    pub auth_key: SecretKey,
+    pub sub_domain: String,
+    /// the account id of the teammate
+    pub grantee: String,
+    /// how long the grant lasts, revokes it if `None`
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum GrantResponse {
+    Granted {
+        sub_domain: String,
+        grantee: String,
+        /// rfc3339 timestamp
+        expires_at: String,
+    },
+    Revoked {
+        sub_domain: String,
+        grantee: String,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// Request to let a teammate's tunnel held by the account's approval policy start
+/// forwarding traffic. The key can't be the one that opened the tunnel.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApprovalRequest {
+    pub auth_key: SecretKey,
+    pub sub_domain: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ApprovalResponse {
+    Approved {
+        sub_domain: String,
+        /// what the tunnel forwards to, as its client declared it
+        target: Option<String>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// Request to create a key that works like the account's own for a while, i.e. for
+/// a workshop, optionally held to sub-domains starting with a prefix
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GuestKeyRequest {
+    pub auth_key: SecretKey,
+    pub duration_secs: u64,
+    #[serde(default)]
+    pub sub_domain_prefix: Option<String>,
+    /// the only sub-domains the key may open, besides those matching the prefix
+    #[serde(default)]
+    pub sub_domains: Vec<String>,
+    /// names the key in the server's logs
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum GuestKeyResponse {
+    Created {
+        key: SecretKey,
+        /// rfc3339 timestamp
+        expires_at: String,
+        sub_domain_prefix: Option<String>,
+        #[serde(default)]
+        sub_domains: Vec<String>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// Request to add a key to the account, with the same entitlements as `auth_key`, i.e.
+/// one per machine so each can be rotated on its own
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountKeyRequest {
+    pub auth_key: SecretKey,
+    /// names the key in the server's logs, i.e. `laptop` or `ci`
+    pub label: String,
+}
+
+/// Request to replace `auth_key` with a new key that keeps its label and entitlements.
+/// The old key is revoked, the account's other keys keep working.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyRotationRequest {
+    pub auth_key: SecretKey,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AccountKeyResponse {
+    Created {
+        key: SecretKey,
+        label: Option<String>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// Request to list or terminate visitor connections on the account's tunnels
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VisitorsRequest {
+    pub auth_key: SecretKey,
+    /// the connection to terminate, if any
+    #[serde(default)]
+    pub kick: Option<String>,
+}
+
+/// A visitor connection currently open on a tunnel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VisitorConnection {
+    pub id: String,
+    pub sub_domain: String,
+    pub peer_addr: Option<String>,
+    pub connected_secs: u64,
+    /// bytes received from the visitor
+    pub bytes_in: u64,
+    /// bytes sent to the visitor
+    pub bytes_out: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum VisitorsResponse {
+    Connections { connections: Vec<VisitorConnection> },
+    Kicked { id: String },
+    Failed { reason: String },
+}
+
+/// Request to list the sub-domains the account's tunnels used recently
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryRequest {
+    pub auth_key: SecretKey,
+}
+
+/// A sub-domain the account used, as of its last connection
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub sub_domain: String,
+    /// rfc3339 timestamp
+    pub last_connected_at: String,
+    pub client_hostname: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HistoryResponse {
+    /// newest first
+    Entries { entries: Vec<HistoryEntry> },
+    Failed { reason: String },
+}
+
+/// Request to export the account's request transcripts, or turn them on or off with `enable`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TranscriptRequest {
+    pub auth_key: SecretKey,
+    /// only this sub-domain's requests
+    #[serde(default)]
+    pub sub_domain: Option<String>,
+    /// first and last day to export, `YYYY-MM-DD` in UTC
+    #[serde(default)]
+    pub since: Option<String>,
+    #[serde(default)]
+    pub until: Option<String>,
+    #[serde(default)]
+    pub enable: Option<bool>,
+}
+
+/// A visitor connection to one of the account's tunnels. Only metadata, never bodies.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TranscriptEntry {
+    /// rfc3339 timestamp the visitor connected at
+    pub at: String,
+    pub sub_domain: String,
+    pub request_id: Option<String>,
+    pub visitor_ip: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// the response's status, none if the visitor got no response
+    pub status: Option<u16>,
+    /// set when the edge answered instead of the tunnel, i.e. `rate_limited`
+    #[serde(default)]
+    pub answered_by: Option<String>,
+    #[serde(default)]
+    pub bytes_in: u64,
+    #[serde(default)]
+    pub bytes_out: u64,
+    #[serde(default)]
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TranscriptResponse {
+    /// by day, in the order they were recorded, `truncated` if there were more than
+    /// the server sends at once
+    Entries {
+        enabled: bool,
+        retention_days: u32,
+        entries: Vec<TranscriptEntry>,
+        truncated: bool,
+    },
+    Failed { reason: String },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
@@ -116,6 +697,13 @@ impl ClientId {
         ClientId(base64::encode_config(&id, base64::URL_SAFE_NO_PAD))
     }
 
+    /// A stable client id for an authenticated subject
+    pub fn for_subject(subject: &str) -> ClientId {
+        ClientId(base64::encode(
+            &sha2::Sha256::digest(subject.as_bytes()).to_vec(),
+        ))
+    }
+
     pub fn safe_id(self) -> ClientId {
         ClientId(base64::encode(
             &sha2::Sha256::digest(self.0.as_bytes()).to_vec(),
@@ -134,6 +722,7 @@ impl StreamId {
         StreamId(id)
     }
 
+    #[allow(clippy::inherent_to_string)]
     pub fn to_string(&self) -> String {
         format!(
             "stream_{}",
@@ -149,10 +738,76 @@ pub enum ControlPacket {
     Refused(StreamId),
     End(StreamId),
     Ping(Option<ReconnectToken>),
+    /// everything the sender sent on the stream, after its last `Data`
+    Trailer(StreamId, integrity::StreamChecksum),
+    /// server to client only, see `ClientHello::access_log`
+    AccessLog(AccessLogEntry),
+    /// server to client only: the server is being replaced, connect again now and
+    /// let this connection finish its streams, see `ClientHello::handover`
+    Handover,
+    /// client to server only: the client is shutting down, stop routing visitors to it
+    /// and free its sub-domain while it finishes the streams under way
+    Goodbye,
+    /// client to server only: a stream packet numbered within its stream, so the server
+    /// can put the packets of a stream spread over several data connections back in order
+    Sequenced(StreamId, u32, Box<ControlPacket>),
+}
+
+/// A visitor request as the edge saw it, including the ones it answered itself
+/// without reaching the client (denied, refused or failed)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessLogEntry {
+    pub request_id: Option<String>,
+    /// rfc3339
+    pub at: String,
+    pub visitor_ip: Option<String>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    /// the status the edge answered with, `None` if it forwarded the request
+    pub edge_status: Option<u16>,
+    /// what answered, i.e. `path_access_rules` or `too_many_streams`
+    pub reason: Option<String>,
 }
 
 pub const PING_INTERVAL: u64 = 30;
 
+/// Number of file descriptors this process has open, where the OS lets us count them
+pub fn open_fd_count() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd")
+        .or_else(|_| std::fs::read_dir("/dev/fd"))
+        .map(|entries| entries.count())
+        .ok()
+}
+
+/// Outgoing packets waiting for the websocket, priority packets are sent first
+#[derive(Debug, Default)]
+pub struct SendQueue {
+    priority: VecDeque<ControlPacket>,
+    data: VecDeque<ControlPacket>,
+}
+
+impl SendQueue {
+    pub fn push(&mut self, packet: ControlPacket) {
+        if packet.is_priority() {
+            self.priority.push_back(packet)
+        } else {
+            self.data.push_back(packet)
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<ControlPacket> {
+        self.priority.pop_front().or_else(|| self.data.pop_front())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.priority.is_empty() && self.data.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.priority.len() + self.data.len()
+    }
+}
+
 const EMPTY_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
 const TOKEN_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
 
@@ -165,13 +820,59 @@ impl ControlPacket {
             ControlPacket::End(sid) => [vec![0x04], sid.0.to_vec()].concat(),
             ControlPacket::Ping(tok) => {
                 let data = tok.map_or(EMPTY_STREAM.0.to_vec(), |t| {
-                    vec![TOKEN_STREAM.0.to_vec(), t.0.into_bytes()].concat()
+                    [TOKEN_STREAM.0.to_vec(), t.0.into_bytes()].concat()
                 });
                 [vec![0x05], data].concat()
             }
+            ControlPacket::Trailer(sid, checksum) => {
+                [vec![0x06], sid.0.to_vec(), checksum.encode()].concat()
+            }
+            ControlPacket::AccessLog(entry) => [
+                vec![0x07],
+                EMPTY_STREAM.0.to_vec(),
+                serde_json::to_vec(&entry).unwrap_or_default(),
+            ]
+            .concat(),
+            ControlPacket::Handover => [vec![0x08], EMPTY_STREAM.0.to_vec()].concat(),
+            ControlPacket::Goodbye => [vec![0x09], EMPTY_STREAM.0.to_vec()].concat(),
+            ControlPacket::Sequenced(sid, seq, packet) => [
+                vec![0x0A],
+                sid.0.to_vec(),
+                seq.to_be_bytes().to_vec(),
+                packet.serialize(),
+            ]
+            .concat(),
         }
     }
 
+    /// The stream a stream packet belongs to
+    pub fn stream_id(&self) -> Option<&StreamId> {
+        match self {
+            ControlPacket::Init(sid)
+            | ControlPacket::Data(sid, _)
+            | ControlPacket::Refused(sid)
+            | ControlPacket::End(sid)
+            | ControlPacket::Trailer(sid, _)
+            | ControlPacket::Sequenced(sid, _, _) => Some(sid),
+            ControlPacket::Ping(_)
+            | ControlPacket::AccessLog(_)
+            | ControlPacket::Handover
+            | ControlPacket::Goodbye => None,
+        }
+    }
+
+    /// Packets that may skip ahead of queued stream data: keepalives, stream setup,
+    /// handovers and goodbyes. `Refused`, `Trailer` and `End` stay in order behind the data of their stream.
+    pub fn is_priority(&self) -> bool {
+        matches!(
+            self,
+            ControlPacket::Ping(_)
+                | ControlPacket::Init(_)
+                | ControlPacket::Handover
+                | ControlPacket::Goodbye
+        )
+    }
+
     pub fn packet_type(&self) -> &str {
         match &self {
             ControlPacket::Ping(_) => "PING",
@@ -179,6 +880,11 @@ impl ControlPacket {
             ControlPacket::Data(_, _) => "STREAM DATA",
             ControlPacket::Refused(_) => "REFUSED",
             ControlPacket::End(_) => "END STREAM",
+            ControlPacket::Trailer(_, _) => "TRAILER",
+            ControlPacket::AccessLog(_) => "ACCESS LOG",
+            ControlPacket::Handover => "HANDOVER",
+            ControlPacket::Goodbye => "GOODBYE",
+            ControlPacket::Sequenced(_, _, packet) => packet.packet_type(),
         }
     }
 
@@ -205,6 +911,22 @@ impl ControlPacket {
                     )))
                 }
             }
+            0x06 => ControlPacket::Trailer(
+                stream_id,
+                integrity::StreamChecksum::decode(&data[9..]).ok_or("invalid trailer")?,
+            ),
+            0x07 => ControlPacket::AccessLog(serde_json::from_slice(&data[9..])?),
+            0x08 => ControlPacket::Handover,
+            0x09 => ControlPacket::Goodbye,
+            0x0A if data.len() >= 13 => {
+                let mut seq = [0u8; 4];
+                seq.clone_from_slice(&data[9..13]);
+                let packet = ControlPacket::deserialize(&data[13..])?;
+                if matches!(packet, ControlPacket::Sequenced(..)) {
+                    return Err("nested sequenced packet".into());
+                }
+                ControlPacket::Sequenced(stream_id, u32::from_be_bytes(seq), Box::new(packet))
+            }
             _ => return Err("invalid control byte in DataPacket".into()),
         };
 
@@ -0,0 +1,201 @@
+//! The client's forwarding path as a chain of stages, for embedders to add their own to.
+//! Each stage may inspect or rewrite a request, answer it directly, or pass it on, and
+//! the chain ends at an `Endpoint` answering what reaches it, i.e. the local service.
+use crate::verify;
+use futures::future::BoxFuture;
+use http::{Extensions, HeaderMap, Method, StatusCode};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// A request on its way from the tunnel to the local service
+#[derive(Debug, Clone)]
+pub struct ProxyRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    pub started: chrono::NaiveDateTime,
+}
+
+impl ProxyRequest {
+    pub fn request_id(&self) -> Option<String> {
+        self.headers
+            .get(verify::REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    }
+
+    pub fn path_and_query(&self) -> String {
+        match self.query.as_ref() {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// The response on its way back to the tunnel
+#[derive(Debug)]
+pub struct ProxyResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// whatever stages want to tell the ones before them about the exchange
+    pub extensions: Extensions,
+}
+
+impl ProxyResponse {
+    pub fn new(status: StatusCode, headers: HeaderMap, body: Vec<u8>) -> Self {
+        ProxyResponse {
+            status,
+            headers,
+            body,
+            extensions: Extensions::new(),
+        }
+    }
+}
+
+/// Why a request couldn't be answered
+#[derive(Error, Debug)]
+pub enum ForwardError {
+    #[error("failed to read the incoming request")]
+    IncomingRead,
+
+    #[error("invalid url")]
+    InvalidURL,
+
+    #[error("invalid request")]
+    InvalidRequest,
+
+    #[error("the local service failed to answer")]
+    LocalServerError,
+}
+
+pub type MiddlewareResult<'a> = BoxFuture<'a, Result<ProxyResponse, ForwardError>>;
+
+/// A stage in the forwarding path. Stages may inspect or rewrite the request,
+/// answer it directly, or pass it on with `next.run(request)`.
+pub trait Middleware: Send + Sync {
+    fn handle<'a>(&'a self, request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a>;
+}
+
+/// The end of the chain, answering every request that makes it through
+pub trait Endpoint: Send + Sync {
+    fn call(&self, request: ProxyRequest) -> MiddlewareResult<'_>;
+}
+
+/// The remainder of the chain after the current stage
+pub struct Next<'a> {
+    stages: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Endpoint,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, request: ProxyRequest) -> MiddlewareResult<'a> {
+        match self.stages.split_first() {
+            Some((stage, stages)) => stage.handle(
+                request,
+                Next {
+                    stages,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None => self.endpoint.call(request),
+        }
+    }
+}
+
+/// Ordered middleware stages ending at an endpoint
+pub struct MiddlewareChain<E> {
+    stages: Vec<Arc<dyn Middleware>>,
+    endpoint: E,
+}
+
+impl<E: Endpoint> MiddlewareChain<E> {
+    pub fn new(endpoint: E) -> Self {
+        MiddlewareChain {
+            stages: vec![],
+            endpoint,
+        }
+    }
+
+    /// Append a stage; stages run in the order they are added
+    pub fn with<M: Middleware + 'static>(mut self, stage: M) -> Self {
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    pub async fn run(&self, request: ProxyRequest) -> Result<ProxyResponse, ForwardError> {
+        Next {
+            stages: &self.stages,
+            endpoint: &self.endpoint,
+        }
+        .run(request)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    struct Echo;
+
+    impl Endpoint for Echo {
+        fn call(&self, request: ProxyRequest) -> MiddlewareResult<'_> {
+            async move {
+                Ok(ProxyResponse::new(
+                    StatusCode::OK,
+                    request.headers,
+                    request.body,
+                ))
+            }
+            .boxed()
+        }
+    }
+
+    /// Appends its name to the body on the way in
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn handle<'a>(&'a self, mut request: ProxyRequest, next: Next<'a>) -> MiddlewareResult<'a> {
+            request.body.extend_from_slice(self.0.as_bytes());
+            next.run(request)
+        }
+    }
+
+    /// Answers without passing the request on
+    struct Refuse;
+
+    impl Middleware for Refuse {
+        fn handle<'a>(&'a self, _: ProxyRequest, _: Next<'a>) -> MiddlewareResult<'a> {
+            async { Err(ForwardError::InvalidRequest) }.boxed()
+        }
+    }
+
+    fn request() -> ProxyRequest {
+        ProxyRequest {
+            method: Method::GET,
+            path: "/".to_string(),
+            query: None,
+            headers: HeaderMap::new(),
+            body: vec![],
+            started: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn stages_run_in_order() {
+        let chain = MiddlewareChain::new(Echo).with(Tag("a")).with(Tag("b"));
+        let response = futures::executor::block_on(chain.run(request())).unwrap();
+        assert_eq!(response.body, b"ab");
+    }
+
+    #[test]
+    fn stages_can_answer_themselves() {
+        let chain = MiddlewareChain::new(Echo).with(Refuse).with(Tag("a"));
+        let response = futures::executor::block_on(chain.run(request()));
+        assert!(matches!(response, Err(ForwardError::InvalidRequest)));
+    }
+}
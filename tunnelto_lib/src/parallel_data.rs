@@ -0,0 +1,152 @@
+//! Large responses spread over several data connections, for high-latency links where a
+//! single connection is window-limited. The client numbers each stream packet within
+//! its stream and sends it on the next data connection in turn, as
+//! `ControlPacket::Sequenced`, and the server puts the packets of each stream back in
+//! order before they reach the visitor. Each connection delivers in order, so only
+//! packets on different connections can overtake one another.
+use super::{ControlPacket, StreamId};
+use std::collections::{BTreeMap, HashMap};
+
+/// Packets of a stream held until the ones before them arrive, past which the stream
+/// is given up on
+pub const MAX_OUT_OF_ORDER: usize = 1024;
+
+/// Numbers stream packets and picks the data connection each goes on
+#[derive(Debug, Default)]
+pub struct Spreader {
+    next: HashMap<StreamId, u32>,
+    sent: usize,
+}
+
+impl Spreader {
+    /// The data connection, out of `connections`, to send the packet on and the packet to
+    /// send. Packets outside a stream, or with a single connection, go on the first as
+    /// they are.
+    pub fn assign(&mut self, packet: ControlPacket, connections: usize) -> (usize, ControlPacket) {
+        let stream_id = match packet.stream_id() {
+            Some(stream_id) if connections > 1 => stream_id.clone(),
+            _ => return (0, packet),
+        };
+
+        let next = self.next.entry(stream_id.clone()).or_insert(0);
+        let seq = *next;
+        *next += 1;
+        if matches!(packet, ControlPacket::End(_) | ControlPacket::Refused(_)) {
+            self.next.remove(&stream_id);
+        }
+
+        self.sent = self.sent.wrapping_add(1);
+        (
+            self.sent % connections,
+            ControlPacket::Sequenced(stream_id, seq, Box::new(packet)),
+        )
+    }
+}
+
+/// Puts the numbered packets of one stream back in order
+#[derive(Debug, Default)]
+pub struct Reorder {
+    next: u32,
+    waiting: BTreeMap<u32, ControlPacket>,
+}
+
+impl Reorder {
+    /// Take the packet numbered `seq`, returning the packets now in order, `None` if too
+    /// many are waiting on one that went missing
+    pub fn push(&mut self, seq: u32, packet: ControlPacket) -> Option<Vec<ControlPacket>> {
+        // already delivered
+        if seq < self.next {
+            return Some(vec![]);
+        }
+        self.waiting.insert(seq, packet);
+        if self.waiting.len() > MAX_OUT_OF_ORDER {
+            return None;
+        }
+
+        let mut ready = vec![];
+        while let Some(packet) = self.waiting.remove(&self.next) {
+            ready.push(packet);
+            self.next += 1;
+        }
+        Some(ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(byte: u8) -> ControlPacket {
+        ControlPacket::Data(StreamId([1; 8]), vec![byte])
+    }
+
+    fn unwrap(packet: ControlPacket) -> (u32, ControlPacket) {
+        match packet {
+            ControlPacket::Sequenced(_, seq, packet) => (seq, *packet),
+            other => panic!("expected a sequenced packet, got {}", other.packet_type()),
+        }
+    }
+
+    #[test]
+    fn spreads_stream_packets_in_turn() {
+        let mut spreader = Spreader::default();
+        let sent: Vec<_> = (0..4).map(|i| spreader.assign(data(i), 2)).collect();
+        let connections: Vec<_> = sent.iter().map(|(connection, _)| *connection).collect();
+        assert_eq!(connections, vec![1, 0, 1, 0]);
+
+        let seqs: Vec<_> = sent
+            .into_iter()
+            .map(|(_, packet)| unwrap(packet).0)
+            .collect();
+        assert_eq!(seqs, vec![0, 1, 2, 3]);
+
+        // pings aren't part of a stream, and a single connection needs no numbers
+        assert!(matches!(
+            spreader.assign(ControlPacket::Ping(None), 2),
+            (0, ControlPacket::Ping(None))
+        ));
+        assert!(matches!(
+            spreader.assign(data(0), 1),
+            (0, ControlPacket::Data(..))
+        ));
+    }
+
+    #[test]
+    fn ends_restart_the_numbering() {
+        let mut spreader = Spreader::default();
+        spreader.assign(data(0), 2);
+        let (_, end) = spreader.assign(ControlPacket::End(StreamId([1; 8])), 2);
+        assert_eq!(unwrap(end).0, 1);
+        assert_eq!(unwrap(spreader.assign(data(0), 2).1).0, 0);
+    }
+
+    #[test]
+    fn reorders_packets_that_overtook_others() {
+        let mut reorder = Reorder::default();
+        assert_eq!(reorder.push(1, data(1)).unwrap().len(), 0);
+        assert_eq!(reorder.push(2, data(2)).unwrap().len(), 0);
+
+        let ready: Vec<_> = reorder
+            .push(0, data(0))
+            .unwrap()
+            .into_iter()
+            .map(|packet| match packet {
+                ControlPacket::Data(_, data) => data[0],
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(ready, vec![0, 1, 2]);
+
+        // a packet delivered again is dropped
+        assert_eq!(reorder.push(1, data(1)).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn gives_up_on_a_missing_packet() {
+        let mut reorder = Reorder::default();
+        for seq in 1..=MAX_OUT_OF_ORDER as u32 {
+            assert!(reorder.push(seq, data(0)).is_some());
+        }
+        assert!(reorder.push(MAX_OUT_OF_ORDER as u32 + 1, data(0)).is_none());
+    }
+}
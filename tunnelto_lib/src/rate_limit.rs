@@ -0,0 +1,186 @@
+//! Request rates a tunnel asks the edge to enforce on visitor requests, so a public
+//! demo can't hammer an expensive local endpoint.
+//!
+//! A rule is written `PATH=RPS[:BURST]`, i.e. `/api/search=2` or `/*=20:40`, with paths
+//! matched as in `acl`. The first rule matching a request decides: past its rate, the
+//! edge answers 429 itself. `BURST` is how many requests may arrive at once, and
+//! defaults to the rate rounded up. Every request counts: on a tunnel with rules, visitor
+//! connections carry a single request each.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// the most rules the edge keeps for one tunnel
+pub const MAX_RULES: usize = 32;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitError {
+    #[error("expected PATH=RPS[:BURST], got `{0}`")]
+    Malformed(String),
+
+    #[error("path must start with `/`: `{0}`")]
+    InvalidPath(String),
+
+    #[error("invalid rate `{0}`, expected requests per second above 0")]
+    InvalidRate(String),
+
+    #[error("invalid burst `{0}`, expected a count above 0")]
+    InvalidBurst(String),
+}
+
+/// Visitors may request paths matching `path` at most `rps` times a second
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitRule {
+    pub path: String,
+    pub rps: f64,
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+impl RateLimitRule {
+    pub fn matches_path(&self, path: &str) -> bool {
+        crate::acl::path_matches(&self.path, path)
+    }
+
+    /// How many requests may arrive at once
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or_else(|| self.rps.ceil() as u32).max(1)
+    }
+
+    /// Whether the edge can enforce it, rules from the wire aren't parsed
+    pub fn is_valid(&self) -> bool {
+        self.path.starts_with('/') && self.rps.is_finite() && self.rps > 0.0
+    }
+}
+
+impl FromStr for RateLimitRule {
+    type Err = RateLimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, limit) = s
+            .split_once('=')
+            .ok_or_else(|| RateLimitError::Malformed(s.to_string()))?;
+
+        let path = path.trim();
+        if !path.starts_with('/') {
+            return Err(RateLimitError::InvalidPath(path.to_string()));
+        }
+
+        let (rps, burst) = match limit.split_once(':') {
+            Some((rps, burst)) => (rps.trim(), Some(burst.trim())),
+            None => (limit.trim(), None),
+        };
+
+        let rps = rps
+            .parse::<f64>()
+            .ok()
+            .filter(|rps| rps.is_finite() && *rps > 0.0)
+            .ok_or_else(|| RateLimitError::InvalidRate(rps.to_string()))?;
+        let burst = match burst {
+            Some(burst) => Some(
+                burst
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|burst| *burst > 0)
+                    .ok_or_else(|| RateLimitError::InvalidBurst(burst.to_string()))?,
+            ),
+            None => None,
+        };
+
+        Ok(RateLimitRule {
+            path: path.to_string(),
+            rps,
+            burst,
+        })
+    }
+}
+
+impl fmt::Display for RateLimitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.path, self.rps)?;
+        if let Some(burst) = self.burst {
+            write!(f, ":{}", burst)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first rule matching `path`, and its position, if any
+pub fn matching_rule<'a>(
+    rules: &'a [RateLimitRule],
+    path: &str,
+) -> Option<(usize, &'a RateLimitRule)> {
+    rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| rule.matches_path(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let rule: RateLimitRule = "/api/search=2".parse().unwrap();
+        assert_eq!(rule.path, "/api/search");
+        assert_eq!(rule.rps, 2.0);
+        assert_eq!(rule.burst(), 2);
+        assert_eq!(rule.to_string(), "/api/search=2");
+
+        let rule: RateLimitRule = " /* = 0.5 : 10 ".parse().unwrap();
+        assert_eq!(rule.burst, Some(10));
+        assert_eq!(rule.to_string(), "/*=0.5:10");
+
+        // a slow rate still lets one request through
+        assert_eq!("/x=0.2".parse::<RateLimitRule>().unwrap().burst(), 1);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(matches!(
+            "/x".parse::<RateLimitRule>(),
+            Err(RateLimitError::Malformed(_))
+        ));
+        assert!(matches!(
+            "x=1".parse::<RateLimitRule>(),
+            Err(RateLimitError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            "/x=0".parse::<RateLimitRule>(),
+            Err(RateLimitError::InvalidRate(_))
+        ));
+        assert!(matches!(
+            "/x=inf".parse::<RateLimitRule>(),
+            Err(RateLimitError::InvalidRate(_))
+        ));
+        assert!(matches!(
+            "/x=1:0".parse::<RateLimitRule>(),
+            Err(RateLimitError::InvalidBurst(_))
+        ));
+
+        let from_wire = RateLimitRule {
+            path: "/x".to_string(),
+            rps: f64::NAN,
+            burst: None,
+        };
+        assert!(!from_wire.is_valid());
+    }
+
+    #[test]
+    fn first_matching_rule() {
+        let rules: Vec<RateLimitRule> =
+            vec!["/api/search=2".parse().unwrap(), "/*=20".parse().unwrap()];
+        assert_eq!(
+            matching_rule(&rules, "/api/search?q=x").map(|(i, _)| i),
+            Some(0)
+        );
+        assert_eq!(
+            matching_rule(&rules, "/api//search").map(|(i, _)| i),
+            Some(0)
+        );
+        assert_eq!(matching_rule(&rules, "/api/other").map(|(i, _)| i), Some(1));
+        assert!(matching_rule(&rules[..1], "/").is_none());
+    }
+}
@@ -0,0 +1,114 @@
+//! Helpers for local services to verify and inspect requests arriving through a tunnel.
+//!
+//! When a tunnel is started with a signing secret, the server adds these headers to
+//! every request it forwards:
+//!
+//! - `x-tunnelto-timestamp`: unix seconds when the edge received the request
+//! - `x-tunnelto-signature`: `v1=<hex hmac-sha256 of "timestamp.METHOD.path.host">`
+//! - `x-tunnelto-sub-domain`: the tunnel's sub-domain
+//! - `x-request-id`: the id the edge assigned the request, also shown on edge error pages
+//! - `x-forwarded-for`, `x-forwarded-host`, `x-forwarded-proto`
+//!
+//! Headers are looked up through a closure, so these work with any framework:
+//! `|name| headers.get(name).and_then(|v| v.to_str().ok())`
+use std::net::IpAddr;
+use thiserror::Error;
+
+pub const TIMESTAMP_HEADER: &str = "x-tunnelto-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-tunnelto-signature";
+pub const SUB_DOMAIN_HEADER: &str = "x-tunnelto-sub-domain";
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const SIGNATURE_VERSION: &str = "v1=";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    #[error("missing or malformed {0} header")]
+    MissingHeader(&'static str),
+
+    #[error("request timestamp is outside the allowed window")]
+    Expired,
+
+    #[error("signature does not match")]
+    BadSignature,
+}
+
+/// What the edge told us about a tunneled request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelMetadata {
+    pub sub_domain: Option<String>,
+    pub request_id: Option<String>,
+    pub timestamp: Option<i64>,
+    pub visitor_ip: Option<IpAddr>,
+    pub forwarded_host: Option<String>,
+    pub forwarded_proto: Option<String>,
+}
+
+/// The signature the edge puts in `x-tunnelto-signature`
+pub fn sign(secret: &str, timestamp: i64, method: &str, path: &str, host: &str) -> String {
+    let payload = format!("{}.{}.{}.{}", timestamp, method.to_uppercase(), path, host);
+    let mac = hmac_sha256::HMAC::mac(payload.as_bytes(), secret.as_bytes());
+    format!("{}{}", SIGNATURE_VERSION, hex::encode(mac))
+}
+
+/// Check the request was signed by the edge with our secret within `max_age_secs` of `now`
+pub fn verify<'a>(
+    secret: &str,
+    method: &str,
+    path: &str,
+    host: &str,
+    now: i64,
+    max_age_secs: i64,
+    header: impl Fn(&str) -> Option<&'a str>,
+) -> Result<(), VerifyError> {
+    let timestamp: i64 = header(TIMESTAMP_HEADER)
+        .and_then(|t| t.trim().parse().ok())
+        .ok_or(VerifyError::MissingHeader(TIMESTAMP_HEADER))?;
+    let signature = header(SIGNATURE_HEADER).ok_or(VerifyError::MissingHeader(SIGNATURE_HEADER))?;
+
+    if (now - timestamp).abs() > max_age_secs {
+        return Err(VerifyError::Expired);
+    }
+
+    let expected = sign(secret, timestamp, method, path, host);
+    if !constant_time_eq(expected.as_bytes(), signature.trim().as_bytes()) {
+        return Err(VerifyError::BadSignature);
+    }
+
+    Ok(())
+}
+
+/// The visitor's address: the first valid entry of `x-forwarded-for`, falling back
+/// to `forwarded: for=...`
+pub fn visitor_ip<'a>(header: impl Fn(&str) -> Option<&'a str>) -> Option<IpAddr> {
+    if let Some(ip) = header("x-forwarded-for")
+        .and_then(|v| v.split(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+    {
+        return Some(ip);
+    }
+
+    header("forwarded")?
+        .split([';', ','])
+        .filter_map(|part| part.trim().strip_prefix("for="))
+        .map(|ip| ip.trim_matches('"').trim_start_matches('[').split(']').next())
+        .filter_map(|ip| ip.and_then(|ip| ip.parse().ok()))
+        .next()
+}
+
+/// Collect the tunnel metadata headers of a request
+pub fn metadata<'a>(header: impl Fn(&str) -> Option<&'a str>) -> TunnelMetadata {
+    TunnelMetadata {
+        sub_domain: header(SUB_DOMAIN_HEADER).map(String::from),
+        request_id: header(REQUEST_ID_HEADER).map(String::from),
+        timestamp: header(TIMESTAMP_HEADER).and_then(|t| t.trim().parse().ok()),
+        visitor_ip: visitor_ip(&header),
+        forwarded_host: header("x-forwarded-host").map(String::from),
+        forwarded_proto: header("x-forwarded-proto").map(String::from),
+    }
+}
+
+/// Compare secrets in time independent of where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,74 @@
+use super::*;
+
+/// Send an access-log entry down the tunnel, if its client asked for them.
+/// Entries are dropped rather than queued behind a full tunnel.
+fn send(client: &ConnectedClient, entry: AccessLogEntry) {
+    if !client.access_log {
+        return;
+    }
+    let mut tx = client.tx.clone();
+    if !tx.try_send(ControlPacket::AccessLog(entry)) {
+        log::debug!(
+            "tunnel queue full, dropping access log entry for {}",
+            &client.host
+        );
+    }
+}
+
+fn entry(request: &EdgeRequest, edge_status: Option<u16>, reason: Option<&str>) -> AccessLogEntry {
+    AccessLogEntry {
+        request_id: Some(request.request_id.clone()),
+        at: chrono::Utc::now().to_rfc3339(),
+        visitor_ip: request.peer_addr.map(|addr| addr.ip().to_string()),
+        method: Some(request.method.clone()),
+        path: Some(request.path.clone()),
+        edge_status,
+        reason: reason.map(String::from),
+    }
+}
+
+/// The request is on its way to the client
+pub fn forwarded(client: &ConnectedClient, request: &EdgeRequest) {
+    send(client, entry(request, None, None));
+}
+
+/// The edge answered the request itself with one of its error pages
+pub fn answered(client: &ConnectedClient, request: &EdgeRequest, error: &edge::ErrorPage) {
+    send(
+        client,
+        entry(request, Some(error.status), Some(error.code.reason())),
+    );
+}
+
+/// An edge filter answered the request, with whatever response it built
+pub fn filtered(request: &EdgeRequest, filter: &str, response: &[u8]) {
+    let client = match request.tunnel() {
+        Some(client) => client,
+        None => return,
+    };
+
+    send(
+        &client,
+        entry(request, edge::response_status(response), Some(filter)),
+    );
+}
+
+/// The edge answered a stream that was already forwarded, i.e. the client refused it
+pub fn stream_answered(
+    client: &ConnectedClient,
+    request_id: Option<&str>,
+    error: &edge::ErrorPage,
+) {
+    send(
+        client,
+        AccessLogEntry {
+            request_id: request_id.map(String::from),
+            at: chrono::Utc::now().to_rfc3339(),
+            visitor_ip: None,
+            method: None,
+            path: None,
+            edge_status: Some(error.status),
+            reason: Some(error.code.reason().to_string()),
+        },
+    );
+}
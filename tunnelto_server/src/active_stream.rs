@@ -1,24 +1,104 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use tokio::sync::Notify;
+
 #[derive(Debug, Clone)]
 pub struct ActiveStream {
     pub id: StreamId,
     pub client: ConnectedClient,
-    pub tx: UnboundedSender<StreamMessage>,
+    pub tx: QueueSender<StreamMessage>,
+    pub peer_addr: Option<SocketAddr>,
+    /// the id the edge assigned to the visitor's request, none for TLS passthrough
+    pub request_id: Option<String>,
+    pub stats: Arc<StreamStats>,
+    /// notified when the visitor is kicked, to stop reading from it
+    pub kicked: Arc<Notify>,
 }
 
 impl ActiveStream {
-    pub fn new(client: ConnectedClient) -> (Self, UnboundedReceiver<StreamMessage>) {
-        let (tx, rx) = unbounded();
+    pub fn new(
+        client: ConnectedClient,
+        peer_addr: Option<SocketAddr>,
+        request_id: Option<String>,
+    ) -> (Self, QueueReceiver<StreamMessage>) {
+        let (tx, rx) = queue(
+            crate::traffic::tuning(client.traffic_profile).stream_queue,
+            &QUEUE_METRICS.stream,
+        );
         (
             ActiveStream {
                 id: StreamId::generate(),
                 client,
                 tx,
+                peer_addr,
+                request_id,
+                stats: Arc::new(StreamStats::new()),
+                kicked: Arc::new(Notify::new()),
             },
             rx,
         )
     }
 }
 
+/// Traffic counters for a visitor connection
+#[derive(Debug)]
+pub struct StreamStats {
+    pub started: chrono::DateTime<chrono::Utc>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// of the response the visitor got, 0 until it has one
+    status: AtomicU16,
+    /// the visitor is gone, the tunnel may still be sending to nobody
+    visitor_closed: AtomicBool,
+}
+
+impl StreamStats {
+    fn new() -> Self {
+        StreamStats {
+            started: chrono::Utc::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            status: AtomicU16::new(0),
+            visitor_closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn add_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// The response's status, only the first one set counts
+    pub fn set_status(&self, status: u16) {
+        let _ = self
+            .status
+            .compare_exchange(0, status, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        Some(self.status.load(Ordering::Relaxed)).filter(|status| *status != 0)
+    }
+
+    pub fn close_visitor(&self) {
+        self.visitor_closed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn visitor_closed(&self) -> bool {
+        self.visitor_closed.load(Ordering::Relaxed)
+    }
+}
+
 pub type ActiveStreams = Arc<DashMap<StreamId, ActiveStream>>;
 
 use super::*;
@@ -27,4 +107,14 @@ pub enum StreamMessage {
     Data(Vec<u8>),
     TunnelRefused,
     NoClientTunnel,
+    /// the connection was terminated through the visitors api
+    Kicked,
+    /// a queue on the way to or from the tunnel overflowed
+    Overloaded,
+    /// the edge refused the visitor, answered with this if the response hasn't started
+    Refused(edge::ErrorPage),
+    /// the client finished sending, check the visitor got all of it
+    Trailer(tunnelto_lib::integrity::StreamChecksum),
+    /// the local service closed the stream, close the visitor's connection too
+    End,
 }
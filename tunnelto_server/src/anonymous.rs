@@ -0,0 +1,148 @@
+//! Tunnels for clients without a key. ANONYMOUS_TUNNELS says how they are taken:
+//!
+//! - `open`, the default: on a random sub-domain, prefixed with the one they asked for
+//! - `limited`, for public demo servers: held to limits instead
+//!   - a random sub-domain, whatever they asked for
+//!   - ANONYMOUS_MAX_SESSION_SECS (3600) from opening the tunnel, after which it is
+//!     closed and its reconnect tokens no longer work
+//!   - ANONYMOUS_MAX_BANDWIDTH bytes/sec (262144) each way
+//!   - ANONYMOUS_MAX_TUNNELS open at once on this instance (unlimited)
+//!
+//!   Set a limit to 0 to lift it.
+//! - `off`: refused, clients need an authentication key
+//!
+//! Values that don't parse are logged and the default is used.
+use super::*;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// What anonymous clients are told when the server doesn't take them
+const REFUSED: &str =
+    "this server only takes tunnels with an authentication key, set one with `tunnelto set-auth`";
+
+#[derive(Debug, Clone, Copy)]
+pub enum AnonymousTunnels {
+    Open,
+    Limited(AnonymousLimits),
+    Off,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AnonymousLimits {
+    pub max_session: Option<Duration>,
+    pub max_bandwidth: Option<u64>,
+    pub max_tunnels: Option<usize>,
+}
+
+impl AnonymousTunnels {
+    pub fn from_env() -> Self {
+        match crate::config::env_var("ANONYMOUS_TUNNELS").ok().as_deref() {
+            None | Some("open") => AnonymousTunnels::Open,
+            Some("limited") => AnonymousTunnels::Limited(AnonymousLimits::from_env()),
+            Some("off") => AnonymousTunnels::Off,
+            Some(other) => {
+                log::error!(
+                    "invalid ANONYMOUS_TUNNELS={}, expected open, limited or off, using open",
+                    other
+                );
+                AnonymousTunnels::Open
+            }
+        }
+    }
+
+    pub fn max_bandwidth(&self) -> Option<u64> {
+        match self {
+            AnonymousTunnels::Limited(limits) => limits.max_bandwidth,
+            _ => None,
+        }
+    }
+}
+
+impl AnonymousLimits {
+    fn from_env() -> Self {
+        let limit = |name: &str, default: u64| {
+            let limit = match crate::config::env_var(name) {
+                Ok(n) => n.parse::<u64>().unwrap_or_else(|_| {
+                    log::error!("invalid {}={}, using {}", name, n, default);
+                    default
+                }),
+                Err(_) => default,
+            };
+            Some(limit).filter(|limit| *limit > 0)
+        };
+        AnonymousLimits {
+            max_session: limit("ANONYMOUS_MAX_SESSION_SECS", 3600).map(Duration::from_secs),
+            max_bandwidth: limit("ANONYMOUS_MAX_BANDWIDTH", 256 * 1024),
+            max_tunnels: limit("ANONYMOUS_MAX_TUNNELS", 0).map(|n| n as usize),
+        }
+    }
+}
+
+/// Let a new anonymous client in, returning its sub-domain for the one it `requested`
+/// and when its session ends
+pub fn admit(requested: Option<&str>) -> Result<(String, Option<DateTime<Utc>>), TunnelError> {
+    let limits = match CONFIG.anonymous {
+        AnonymousTunnels::Open => {
+            let sub_domain = match requested.map(str::to_lowercase) {
+                Some(requested) if requested.chars().all(|c| c.is_alphanumeric() || c == '-') => {
+                    ServerHello::prefixed_random_domain(&requested)
+                }
+                Some(_) => return Err(TunnelError::InvalidSubDomain),
+                None => ServerHello::random_domain(),
+            };
+            return Ok((sub_domain, None));
+        }
+        AnonymousTunnels::Limited(limits) => limits,
+        AnonymousTunnels::Off => return Err(TunnelError::KeyRejected(REFUSED.into())),
+    };
+
+    if let Some(max_tunnels) = limits.max_tunnels {
+        if Connections::count_anonymous() >= max_tunnels {
+            log::warn!(
+                "refusing anonymous client, {} anonymous tunnels open",
+                max_tunnels
+            );
+            return Err(TunnelError::TunnelLimitReached);
+        }
+    }
+
+    // limited clients don't get to pick their sub-domain
+    let session_ends = limits
+        .max_session
+        .and_then(|max_session| chrono::Duration::from_std(max_session).ok())
+        .map(|max_session| Utc::now() + max_session);
+    Ok((ServerHello::random_domain(), session_ends))
+}
+
+/// Let an anonymous client back in with its reconnect token, until its session ends
+pub fn readmit(session_ends: Option<DateTime<Utc>>) -> Result<Option<DateTime<Utc>>, TunnelError> {
+    if let AnonymousTunnels::Off = CONFIG.anonymous {
+        return Err(TunnelError::KeyRejected(REFUSED.into()));
+    }
+
+    match session_ends {
+        Some(ends) if ends <= Utc::now() => Err(TunnelError::KeyRejected(
+            "the anonymous session ended, restart the client for a new one or set a key with `tunnelto set-auth`"
+                .into(),
+        )),
+        session_ends => Ok(session_ends),
+    }
+}
+
+/// Close the anonymous tunnel once its session ends
+pub fn spawn_session_limit(client: ConnectedClient) {
+    let ends = match client.session_ends {
+        Some(ends) => ends,
+        None => return,
+    };
+
+    tokio::spawn(async move {
+        let left = (ends - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(left).await;
+
+        if Connections::get(&client.id).is_some() {
+            info!("anonymous session of {} ended, closing it", &client.host);
+            Connections::remove(&client);
+        }
+    });
+}
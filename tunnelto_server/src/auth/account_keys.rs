@@ -0,0 +1,125 @@
+//! An account may hold several keys, i.e. one per machine (`laptop`, `ci`), each its own
+//! row named by a label the logs show. A key adds siblings with its entitlements, and is
+//! rotated alone: its replacement takes over the label and it is revoked, closing the
+//! tunnels it opened while the account's other keys keep working.
+use crate::auth_db::Entitlements;
+use crate::AUTH_DB_SERVICE;
+use thiserror::Error;
+use tunnelto_lib::{AccountKeyRequest, AccountKeyResponse, KeyRotationRequest, SecretKey};
+use warp::http::StatusCode;
+
+const MAX_LABEL_LEN: usize = 32;
+
+pub const INVALID_LABEL: &str =
+    "invalid label: use up to 32 letters, digits, hyphens, underscores and dots";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{}", INVALID_LABEL)]
+    InvalidLabel,
+
+    #[error("auth error: {0}")]
+    Auth(#[from] crate::auth_db::Error),
+}
+
+/// The label, trimmed, if it's a valid one
+pub fn parse_label(label: &str) -> Option<String> {
+    let label = label.trim();
+    let valid = !label.is_empty()
+        && label.len() <= MAX_LABEL_LEN
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    Some(label.to_string()).filter(|_| valid)
+}
+
+async fn create_key(request: AccountKeyRequest) -> Result<AccountKeyResponse, Error> {
+    let label = parse_label(&request.label).ok_or(Error::InvalidLabel)?;
+    let account = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?;
+
+    let entitlements = Entitlements {
+        label: Some(label.clone()),
+        ..account.entitlements.clone()
+    };
+    let key = SecretKey::generate();
+    AUTH_DB_SERVICE
+        .put_auth_key(&key.0, &account.account_id, &entitlements)
+        .await?;
+
+    log::info!(
+        "added key {:?} to {} with key {:?}",
+        &label,
+        &account.account_id,
+        &account.entitlements.label
+    );
+    Ok(AccountKeyResponse::Created {
+        key,
+        label: Some(label),
+    })
+}
+
+async fn rotate_key(request: KeyRotationRequest) -> Result<AccountKeyResponse, Error> {
+    let account = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?;
+
+    // store the replacement first, so a failure leaves the old key working
+    let key = SecretKey::generate();
+    AUTH_DB_SERVICE
+        .put_auth_key(&key.0, &account.account_id, &account.entitlements)
+        .await?;
+
+    let revoked = Entitlements {
+        revoked: true,
+        ..account.entitlements.clone()
+    };
+    AUTH_DB_SERVICE
+        .put_auth_key(&request.auth_key.0, &account.account_id, &revoked)
+        .await?;
+
+    log::info!(
+        "rotated key {:?} of {}",
+        &account.entitlements.label,
+        &account.account_id
+    );
+    Ok(AccountKeyResponse::Created {
+        key,
+        label: account.entitlements.label,
+    })
+}
+
+fn reply(result: Result<AccountKeyResponse, Error>) -> impl warp::Reply {
+    let (response, status) = match result {
+        Ok(response) => (response, StatusCode::OK),
+        Err(e @ Error::Auth(_)) => (
+            AccountKeyResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+        Err(e) => (
+            AccountKeyResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::BAD_REQUEST,
+        ),
+    };
+
+    warp::reply::with_status(warp::reply::json(&response), status)
+}
+
+/// Handle a request to add a key to the account
+pub async fn handle_create(
+    request: AccountKeyRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    Ok(reply(create_key(request).await))
+}
+
+/// Handle a request to rotate a key
+pub async fn handle_rotate(
+    request: KeyRotationRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    Ok(reply(rotate_key(request).await))
+}
@@ -0,0 +1,255 @@
+//! Admin endpoints for managing accounts through the server instead of writing to the
+//! auth backend by hand. All of them take the `x-admin-key` header:
+//!
+//! - `POST /admin/keys` creates a key, i.e. `{"account_id": "9a6e...", "label": "ci",
+//!   "max_tunnels": 5}` with the entitlements the auth webhook takes, for a new account
+//!   if `account_id` is missing. The key is only ever returned here.
+//! - `GET /admin/accounts/<account id>/keys` lists the account's keys by `key_id`
+//! - `POST /admin/keys/<key id>/revoke` revokes a key, its tunnels are closed at the
+//!   next recheck (KEY_RECHECK_SECS)
+//! - `PUT /admin/reservations/<sub-domain>` with `{"account_id": "9a6e..."}` reserves
+//!   the sub-domain for the account
+use super::account_keys;
+use super::auth_db::{self, EntitlementClaims, Entitlements, StoredKey};
+use super::tiers::Tier;
+use crate::load_shedding::Priority;
+use crate::AUTH_DB_SERVICE;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tunnelto_lib::SecretKey;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{}", account_keys::INVALID_LABEL)]
+    InvalidLabel,
+
+    #[error("invalid sub-domain `{0}`: only letters, digits and hyphens are allowed")]
+    InvalidSubDomain(String),
+
+    #[error("no key with this id")]
+    KeyNotFound,
+
+    #[error("auth backend error: {0}")]
+    Auth(#[from] auth_db::Error),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateKeyRequest {
+    /// a new account if missing
+    #[serde(default)]
+    account_id: Option<Uuid>,
+    #[serde(flatten)]
+    entitlements: EntitlementClaims,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedKey {
+    key: SecretKey,
+    key_hash: String,
+    account_id: Uuid,
+    label: Option<String>,
+}
+
+/// A key as listed to operators, never the key itself
+#[derive(Debug, Serialize)]
+pub struct KeyInfo {
+    key_hash: String,
+    label: Option<String>,
+    revoked: bool,
+    expires_at: Option<String>,
+    max_tunnels: Option<u32>,
+    custom_domains: bool,
+    tcp_tunnels: bool,
+    max_bandwidth: Option<u64>,
+    sub_domain_prefix: Option<String>,
+    sub_domains: Vec<String>,
+    priority: Priority,
+    catch_all: bool,
+    tier: Option<Tier>,
+}
+
+impl From<StoredKey> for KeyInfo {
+    fn from(key: StoredKey) -> Self {
+        let e = key.entitlements;
+        KeyInfo {
+            key_hash: key.key_hash,
+            label: e.label,
+            revoked: e.revoked,
+            expires_at: e.expires_at.map(|t| t.to_rfc3339()),
+            max_tunnels: e.max_tunnels,
+            custom_domains: e.custom_domains,
+            tcp_tunnels: e.tcp_tunnels,
+            max_bandwidth: e.max_bandwidth,
+            sub_domain_prefix: e.sub_domain_prefix,
+            sub_domains: e.sub_domains,
+            priority: e.priority,
+            catch_all: e.catch_all,
+            tier: e.tier,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevokedKey {
+    key_hash: String,
+    revoked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReservationRequest {
+    account_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+struct Reservation {
+    sub_domain: String,
+    account_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+struct Failed {
+    reason: String,
+}
+
+fn is_valid_sub_domain(sub_domain: &str) -> bool {
+    !sub_domain.is_empty() && sub_domain.chars().all(|c| c.is_alphanumeric() || c == '-')
+}
+
+pub async fn create_key(request: CreateKeyRequest) -> Result<CreatedKey, Error> {
+    let mut entitlements: Entitlements = request.entitlements.into();
+    entitlements.label = match entitlements.label {
+        Some(label) => Some(account_keys::parse_label(&label).ok_or(Error::InvalidLabel)?),
+        None => None,
+    };
+    if let Some(sub_domain) = entitlements
+        .sub_domains
+        .iter()
+        .find(|sub_domain| !is_valid_sub_domain(sub_domain))
+    {
+        return Err(Error::InvalidSubDomain(sub_domain.clone()));
+    }
+
+    let account_id = request.account_id.unwrap_or_else(Uuid::new_v4);
+    let key = SecretKey::generate();
+    AUTH_DB_SERVICE
+        .put_auth_key(&key.0, &account_id, &entitlements)
+        .await?;
+
+    let key_hash = auth_db::key_id(&key.0);
+    log::info!(
+        "admin created key {} ({:?}) for {}",
+        &key_hash,
+        &entitlements.label,
+        &account_id
+    );
+    Ok(CreatedKey {
+        key,
+        key_hash,
+        account_id,
+        label: entitlements.label,
+    })
+}
+
+pub async fn revoke_key(key_hash: String) -> Result<RevokedKey, Error> {
+    match AUTH_DB_SERVICE.revoke_key(&key_hash).await {
+        Ok(()) => {}
+        Err(auth_db::Error::AccountNotFound) => return Err(Error::KeyNotFound),
+        Err(e) => return Err(e.into()),
+    }
+
+    log::info!("admin revoked key {}", &key_hash);
+    Ok(RevokedKey {
+        key_hash,
+        revoked: true,
+    })
+}
+
+async fn reserve(sub_domain: String, account_id: Uuid) -> Result<Reservation, Error> {
+    let sub_domain = sub_domain.to_lowercase();
+    if !is_valid_sub_domain(&sub_domain) {
+        return Err(Error::InvalidSubDomain(sub_domain));
+    }
+
+    AUTH_DB_SERVICE
+        .put_reservation(&sub_domain, &account_id)
+        .await?;
+
+    log::info!("admin reserved {} for {}", &sub_domain, &account_id);
+    Ok(Reservation {
+        sub_domain,
+        account_id,
+    })
+}
+
+fn reply<T: Serialize>(result: Result<T, Error>) -> warp::reply::WithStatus<warp::reply::Json> {
+    let error = match result {
+        Ok(body) => return warp::reply::with_status(warp::reply::json(&body), StatusCode::OK),
+        Err(e) => e,
+    };
+
+    let status = match &error {
+        Error::InvalidLabel | Error::InvalidSubDomain(_) => StatusCode::BAD_REQUEST,
+        Error::KeyNotFound => StatusCode::NOT_FOUND,
+        Error::Auth(e) => {
+            log::error!("admin request failed: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    let failed = Failed {
+        reason: error.to_string(),
+    };
+    warp::reply::with_status(warp::reply::json(&failed), status)
+}
+
+/// Handle an operator creating a key
+pub async fn handle_create(
+    admin_key: Option<String>,
+    request: CreateKeyRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !crate::siem::admin("create_key", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+    Ok(reply(create_key(request).await))
+}
+
+/// Handle an operator listing an account's keys
+pub async fn handle_list(
+    account_id: Uuid,
+    admin_key: Option<String>,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !crate::siem::admin("list_keys", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+
+    let keys = AUTH_DB_SERVICE
+        .get_keys(&account_id)
+        .await
+        .map(|keys| keys.into_iter().map(KeyInfo::from).collect::<Vec<_>>())
+        .map_err(Error::from);
+    Ok(reply(keys))
+}
+
+/// Handle an operator revoking a key
+pub async fn handle_revoke(
+    key_hash: String,
+    admin_key: Option<String>,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !crate::siem::admin("revoke_key", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+    Ok(reply(revoke_key(key_hash).await))
+}
+
+/// Handle an operator reserving a sub-domain for an account
+pub async fn handle_reserve(
+    sub_domain: String,
+    admin_key: Option<String>,
+    request: ReservationRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !crate::siem::admin("reserve_sub_domain", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+    Ok(reply(reserve(sub_domain, request.account_id).await))
+}
@@ -0,0 +1,203 @@
+//! Two-person approval: an account's entitlements may carry an `ApprovalPolicy`, and
+//! tunnels whose sub-domain or declared target match one of its patterns are held
+//! until a teammate approves them. Until then the edge answers visitors itself.
+//!
+//! A teammate is another key of the same account, or a key of an account the policy
+//! lists as an approver. The key that opened the tunnel never counts, nor do guest keys.
+//! Approval lasts as long as the connection: a reconnecting client is held again.
+use crate::connected_clients::Connections;
+use crate::AUTH_DB_SERVICE;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use tunnelto_lib::{ApprovalRequest, ApprovalResponse, ClientId};
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+/// Which tunnels of an account need a second person's approval
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApprovalPolicy {
+    /// sub-domains or targets, `*` matching anything: i.e. `prod-*` or `*:5432`
+    pub patterns: Vec<String>,
+    /// accounts of teammates who may approve, besides the account's own keys
+    #[serde(default)]
+    pub approvers: Vec<Uuid>,
+}
+
+impl ApprovalPolicy {
+    pub fn covers(&self, sub_domain: &str, target: Option<&str>) -> bool {
+        self.patterns.iter().any(|pattern| {
+            glob_matches(pattern, sub_domain)
+                || target.is_some_and(|target| glob_matches(pattern, target))
+        })
+    }
+
+    /// The policy as the auth backends store it
+    pub fn to_stored(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Parse a stored policy. One we can't read holds every tunnel rather than none.
+    pub fn from_stored(stored: &str) -> Self {
+        serde_json::from_str(stored).unwrap_or_else(|e| {
+            log::error!("invalid stored approval policy, holding all tunnels: {}", e);
+            ApprovalPolicy {
+                patterns: vec!["*".to_string()],
+                approvers: vec![],
+            }
+        })
+    }
+}
+
+/// `*` matches any run of characters, the rest matches itself ignoring case
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+
+    let mut parts = pattern.split('*');
+    let mut rest = match value.strip_prefix(parts.next().unwrap_or_default()) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// A tunnel held by its account's approval policy
+#[derive(Debug)]
+pub struct PendingApproval {
+    /// the credentials that opened the tunnel, which can't approve it
+    opened_by: ClientId,
+    target: Option<String>,
+    approved: AtomicBool,
+}
+
+impl PendingApproval {
+    pub fn is_approved(&self) -> bool {
+        self.approved.load(Ordering::SeqCst)
+    }
+}
+
+/// Hold the tunnel if the account's policy covers it
+pub fn hold(
+    policy: Option<&ApprovalPolicy>,
+    sub_domain: &str,
+    target: Option<String>,
+    opened_by: &ClientId,
+) -> Option<Arc<PendingApproval>> {
+    if !policy?.covers(sub_domain, target.as_deref()) {
+        return None;
+    }
+
+    log::info!(
+        "holding {} (target {:?}) until a teammate approves it",
+        sub_domain,
+        target
+    );
+    Some(Arc::new(PendingApproval {
+        opened_by: opened_by.clone(),
+        target,
+        approved: AtomicBool::new(false),
+    }))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no tunnel on this sub-domain is connected to this server")]
+    NoTunnel,
+
+    #[error("this tunnel doesn't need approval")]
+    NotHeld,
+
+    #[error("you aren't a teammate of this tunnel's account")]
+    NotTeammate,
+
+    #[error("a second person must approve, not the key that opened the tunnel")]
+    SelfApproval,
+
+    #[error("auth error: {0}")]
+    Auth(#[from] crate::auth_db::Error),
+}
+
+async fn approve(request: ApprovalRequest) -> Result<ApprovalResponse, Error> {
+    let sub_domain = request.sub_domain.trim().to_lowercase();
+
+    let approver = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?;
+
+    let client = Connections::find_by_host(&sub_domain)
+        .or_else(|| crate::catch_all::find(&sub_domain))
+        .ok_or(Error::NoTunnel)?;
+    let pending = client.approval.as_ref().ok_or(Error::NotHeld)?;
+
+    let same_account = client.account_id == Some(approver.account_id);
+    let listed = client
+        .entitlements
+        .approval
+        .as_ref()
+        .is_some_and(|policy| policy.approvers.contains(&approver.account_id));
+    if !same_account && !listed {
+        return Err(Error::NotTeammate);
+    }
+    if request.auth_key.client_id() == pending.opened_by {
+        return Err(Error::SelfApproval);
+    }
+
+    if !pending.approved.swap(true, Ordering::SeqCst) {
+        log::info!(
+            "{} approved by account {}, forwarding its traffic",
+            &sub_domain,
+            &approver.account_id
+        );
+        crate::siem::tunnel_approved(&client, &approver.account_id);
+    }
+    Ok(ApprovalResponse::Approved {
+        sub_domain,
+        target: pending.target.clone(),
+    })
+}
+
+/// Handle an approval request from the control server
+pub async fn handle_approval(
+    request: ApprovalRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (response, status) = match approve(request).await {
+        Ok(response) => (response, StatusCode::OK),
+        Err(e @ Error::Auth(_)) => (
+            ApprovalResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+        Err(e @ Error::NoTunnel) => (
+            ApprovalResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => (
+            ApprovalResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::FORBIDDEN,
+        ),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
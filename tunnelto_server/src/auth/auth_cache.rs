@@ -0,0 +1,333 @@
+//! A cache in front of `get_account_id_for_auth_key` and `get_key` for the backends
+//! across the network (DynamoDB, Postgres, Redis), so a reconnect storm doesn't turn into
+//! a storm of reads. Successful lookups are kept for AUTH_CACHE_TTL_SECS, at most
+//! AUTH_CACHE_SIZE of them, and a size of 0 turns the cache off. Unknown keys are never
+//! cached, so new keys work right away.
+//!
+//! A key revoked in the store keeps working until its entry expires, unless an
+//! operator drops it: `POST /admin/auth_cache/invalidate` with
+//! `{"auth_key_hash": "<key_id>"}`, or `{}` to drop every entry.
+use super::auth_db::{
+    key_id, AuthenticatedAccount, Entitlements, Error, Grant, HistoryRecord, StoredKey,
+    VerifiedClaim,
+};
+use super::auth_service::AuthService;
+use crate::metering::UsageRecord;
+use crate::CONFIG;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use uuid::Uuid;
+
+lazy_static! {
+    /// keyed by `key_id`
+    static ref CACHE: DashMap<String, Entry> = DashMap::new();
+    static ref STATS: Stats = Stats::default();
+}
+
+struct Entry {
+    account: AuthenticatedAccount,
+    /// only known when it was looked up by `get_key`
+    hello_key: Option<String>,
+    cached_at: Instant,
+}
+
+#[derive(Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// The cache's size and hit rate since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthCacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub fn stats() -> AuthCacheStats {
+    AuthCacheStats {
+        entries: CACHE.len(),
+        hits: STATS.hits.load(Ordering::Relaxed),
+        misses: STATS.misses.load(Ordering::Relaxed),
+    }
+}
+
+/// Drop the cached lookup of a key (by `key_id`), or of every key.
+/// Returns how many entries were dropped.
+pub fn invalidate(key_hash: Option<&str>) -> usize {
+    match key_hash {
+        Some(key_hash) => CACHE.remove(key_hash).map_or(0, |_| 1),
+        None => {
+            let dropped = CACHE.len();
+            CACHE.clear();
+            dropped
+        }
+    }
+}
+
+fn get(key_hash: &str) -> Option<(AuthenticatedAccount, Option<String>)> {
+    let fresh = CACHE.get(key_hash).map(|entry| {
+        if entry.cached_at.elapsed() < CONFIG.auth_cache_ttl {
+            Some((entry.account.clone(), entry.hello_key.clone()))
+        } else {
+            None
+        }
+    });
+
+    match fresh {
+        Some(Some(account)) => {
+            STATS.hits.fetch_add(1, Ordering::Relaxed);
+            Some(account)
+        }
+        Some(None) => {
+            CACHE.remove(key_hash);
+            STATS.misses.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+        None => {
+            STATS.misses.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+fn put(key_hash: String, account: AuthenticatedAccount, hello_key: Option<String>) {
+    // make room: expired entries first, then the oldest
+    if CACHE.len() >= CONFIG.auth_cache_size {
+        CACHE.retain(|_, entry| entry.cached_at.elapsed() < CONFIG.auth_cache_ttl);
+    }
+    if CACHE.len() >= CONFIG.auth_cache_size {
+        let oldest = CACHE
+            .iter()
+            .min_by_key(|entry| entry.cached_at)
+            .map(|entry| entry.key().clone());
+        if let Some(oldest) = oldest {
+            CACHE.remove(&oldest);
+        }
+    }
+
+    CACHE.insert(
+        key_hash,
+        Entry {
+            account,
+            hello_key,
+            cached_at: Instant::now(),
+        },
+    );
+}
+
+/// Any backend, with its key lookups cached
+pub struct CachedAuthService {
+    inner: Box<dyn AuthService>,
+}
+
+impl CachedAuthService {
+    /// Put the cache in front of the backend, unless it's turned off
+    pub fn wrap(inner: Box<dyn AuthService>) -> Box<dyn AuthService> {
+        if CONFIG.auth_cache_size == 0 {
+            return inner;
+        }
+        Box::new(CachedAuthService { inner })
+    }
+}
+
+impl AuthService for CachedAuthService {
+    fn init<'a>(&'a self) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.init()
+    }
+
+    fn get_account_id_for_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+    ) -> BoxFuture<'a, Result<AuthenticatedAccount, Error>> {
+        async move {
+            let key_hash = key_id(auth_key);
+            if let Some((account, _)) = get(&key_hash) {
+                return Ok(account);
+            }
+
+            let account = self.inner.get_account_id_for_auth_key(auth_key).await?;
+            put(key_hash, account.clone(), None);
+            Ok(account)
+        }
+        .boxed()
+    }
+
+    fn get_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<StoredKey, Error>> {
+        async move {
+            if let Some((account, Some(hello_key))) = get(key_hash) {
+                return Ok(StoredKey {
+                    key_hash: key_hash.to_string(),
+                    account_id: account.account_id,
+                    entitlements: account.entitlements,
+                    hello_key: Some(hello_key),
+                });
+            }
+
+            let key = self.inner.get_key(key_hash).await?;
+            let account = AuthenticatedAccount {
+                account_id: key.account_id,
+                entitlements: key.entitlements.clone(),
+                externally_authorized: false,
+            };
+            put(key_hash.to_string(), account, key.hello_key.clone());
+            Ok(key)
+        }
+        .boxed()
+    }
+
+    fn put_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+        account_id: &'a Uuid,
+        entitlements: &'a Entitlements,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        invalidate(Some(&key_id(auth_key)));
+        self.inner.put_auth_key(auth_key, account_id, entitlements)
+    }
+
+    fn get_keys<'a>(&'a self, account_id: &'a Uuid) -> BoxFuture<'a, Result<Vec<StoredKey>, Error>> {
+        self.inner.get_keys(account_id)
+    }
+
+    fn revoke_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        invalidate(Some(key_hash));
+        self.inner.revoke_key(key_hash)
+    }
+
+    fn get_account_id_for_subdomain<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Uuid>, Error>> {
+        self.inner.get_account_id_for_subdomain(subdomain)
+    }
+
+    fn put_reservation<'a>(
+        &'a self,
+        subdomain: &'a str,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.put_reservation(subdomain, account_id)
+    }
+
+    fn get_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<VerifiedClaim>, Error>> {
+        self.inner.get_verified_claim(subdomain)
+    }
+
+    fn put_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        claim: &'a VerifiedClaim,
+        replacing: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.put_verified_claim(subdomain, claim, replacing)
+    }
+
+    fn get_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Option<Grant>, Error>> {
+        self.inner.get_grant(subdomain, grantee)
+    }
+
+    fn put_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+        grant: &'a Grant,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.put_grant(subdomain, grantee, grant)
+    }
+
+    fn delete_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.delete_grant(subdomain, grantee)
+    }
+
+    fn put_usage_record<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.put_usage_record(record)
+    }
+
+    fn put_history_record<'a>(
+        &'a self,
+        record: &'a HistoryRecord,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.put_history_record(record)
+    }
+
+    fn get_history<'a>(
+        &'a self,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        self.inner.get_history(account_id)
+    }
+
+    fn get_sub_domain_history<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        self.inner.get_sub_domain_history(subdomain)
+    }
+
+    fn acquire_sub_domain_lease<'a>(
+        &'a self,
+        subdomain: &'a str,
+        holder: &'a str,
+        expires_at: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        self.inner
+            .acquire_sub_domain_lease(subdomain, holder, expires_at)
+    }
+
+    fn release_sub_domain_lease<'a>(
+        &'a self,
+        subdomain: &'a str,
+        holder: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.inner.release_sub_domain_lease(subdomain, holder)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvalidateRequest {
+    /// the `key_id` of the key to drop, every key if missing
+    #[serde(default)]
+    auth_key_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct InvalidateResponse {
+    dropped: usize,
+}
+
+/// Handle an operator request to drop cached key lookups, i.e. after revoking a key
+pub async fn handle_invalidate(
+    admin_key: Option<String>,
+    request: InvalidateRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !crate::siem::admin("invalidate_auth_cache", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+
+    let dropped = invalidate(request.auth_key_hash.as_deref());
+    log::info!(
+        "dropped {} cached key lookups ({})",
+        dropped,
+        request.auth_key_hash.as_deref().unwrap_or("all keys")
+    );
+    Ok(warp::reply::json(&InvalidateResponse { dropped }))
+}
@@ -1,48 +1,224 @@
-use rusoto_dynamodb::{DynamoDbClient, DynamoDb, AttributeValue, GetItemInput, GetItemError};
+use rusoto_dynamodb::{DynamoDbClient, DynamoDb, AttributeValue, GetItemInput, GetItemError, PutItemInput, PutItemError, DeleteItemInput, DeleteItemError, QueryInput, QueryError, UpdateItemInput, UpdateItemError};
 use rusoto_core::{HttpClient, Client, Region};
 
 use std::collections::HashMap;
 use uuid::Uuid;
 use thiserror::Error;
-use sha2::Digest;
 use rusoto_credential::EnvironmentProvider;
 use std::str::FromStr;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use super::auth_service::AuthService;
+use super::approvals::ApprovalPolicy;
+use super::tiers::Tier;
+use crate::load_shedding::Priority;
 
+/// The DynamoDB auth backend (`AUTH_BACKEND=dynamodb`)
 pub struct AuthDbService {
     client: DynamoDbClient,
+    config: DynamoConfig,
+}
+
+/// Where the tables are and what they're called, so the same server runs against AWS,
+/// LocalStack or tables another deployment named differently:
+///
+/// - DYNAMODB_REGION, else AWS_DEFAULT_REGION or AWS_REGION, else us-east-1
+/// - DYNAMODB_ENDPOINT, i.e. `http://localhost:4566` for LocalStack, else the region's
+/// - DYNAMODB_TABLES, comma separated `<ours>=<theirs>` renaming tables and indexes,
+///   i.e. `tunnelto_auth=prod_keys,account_id-index=by_account`
+/// - DYNAMODB_ATTRIBUTES, the same for attributes, renamed in every table
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DynamoConfig {
+    pub region: Region,
+    pub tables: HashMap<String, String>,
+    pub attributes: HashMap<String, String>,
+}
+
+impl DynamoConfig {
+    pub fn from_env() -> Self {
+        let region = crate::config::env_var("DYNAMODB_REGION")
+            .map(|region| region.parse::<Region>().unwrap_or_else(|e| panic!("invalid DYNAMODB_REGION: {}", e)))
+            .unwrap_or_default();
+        let region = match crate::config::env_var("DYNAMODB_ENDPOINT") {
+            Ok(endpoint) => Region::Custom { name: region.name().to_string(), endpoint },
+            Err(_) => region,
+        };
+
+        DynamoConfig {
+            region,
+            tables: renames("DYNAMODB_TABLES"),
+            attributes: renames("DYNAMODB_ATTRIBUTES"),
+        }
+    }
+}
+
+/// The `<ours>=<theirs>` pairs in the env var
+fn renames(name: &str) -> HashMap<String, String> {
+    crate::config::env_var(name)
+        .map(|list| {
+            list.split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .map(|entry| {
+                    let mut parts = entry.trim().splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(ours), Some(theirs)) if !ours.is_empty() && !theirs.is_empty() => {
+                            (ours.to_string(), theirs.to_string())
+                        }
+                        _ => panic!("invalid {}: expected <ours>=<theirs>, got {}", name, entry),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 impl AuthDbService {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(config: DynamoConfig) -> Result<Self, Box<dyn std::error::Error>> {
         let provider = EnvironmentProvider::default();
         let http_client = HttpClient::new()?;
         let client = Client::new_with(provider, http_client);
 
-        Ok(Self { client: DynamoDbClient::new_with_client(client, Region::UsEast1) })
+        Ok(Self { client: DynamoDbClient::new_with_client(client, config.region.clone()), config })
+    }
+
+    /// The deployment's name for one of our tables or indexes
+    fn table(&self, name: &str) -> String {
+        self.config.tables.get(name).cloned().unwrap_or_else(|| name.to_string())
+    }
+
+    /// The deployment's name for one of our attributes
+    fn attr(&self, name: &str) -> String {
+        self.config.attributes.get(name).cloned().unwrap_or_else(|| name.to_string())
+    }
+
+    /// The item with the deployment's attribute names, for writing it
+    fn to_stored(&self, item: HashMap<String, AttributeValue>) -> HashMap<String, AttributeValue> {
+        if self.config.attributes.is_empty() {
+            return item;
+        }
+        item.into_iter().map(|(name, value)| (self.attr(&name), value)).collect()
+    }
+
+    /// The item read with our attribute names again
+    fn read_stored(&self, item: HashMap<String, AttributeValue>) -> HashMap<String, AttributeValue> {
+        if self.config.attributes.is_empty() {
+            return item;
+        }
+        item.into_iter()
+            .map(|(name, value)| {
+                let ours = self.config.attributes
+                    .iter()
+                    .find(|(_, theirs)| **theirs == name)
+                    .map(|(ours, _)| ours.clone())
+                    .unwrap_or(name);
+                (ours, value)
+            })
+            .collect()
     }
 }
 
 mod domain_db {
-    pub const TABLE_NAME:&'static str = "tunnelto_domains";
-    pub const PRIMARY_KEY:&'static str = "subdomain";
-    pub const ACCOUNT_ID:&'static str = "account_id";
+    pub const TABLE_NAME:&str = "tunnelto_domains";
+    pub const PRIMARY_KEY:&str = "subdomain";
+    pub const ACCOUNT_ID:&str = "account_id";
+}
+
+mod claim_db {
+    pub const TABLE_NAME:&str = "tunnelto_claims";
+    pub const PRIMARY_KEY:&str = "subdomain";
+    pub const ACCOUNT_ID:&str = "account_id";
+    pub const DOMAIN:&str = "domain";
+    pub const VERIFIED_AT:&str = "verified_at";
+}
+
+mod grant_db {
+    pub const TABLE_NAME:&str = "tunnelto_grants";
+    /// `<subdomain>:<grantee account id>`
+    pub const PRIMARY_KEY:&str = "grant_id";
+    pub const ACCOUNT_ID:&str = "account_id";
+    pub const EXPIRES_AT:&str = "expires_at";
+}
+
+mod usage_db {
+    pub const TABLE_NAME:&str = "tunnelto_usage";
+    pub const PRIMARY_KEY:&str = "record_id";
+    pub const ACCOUNT_ID:&str = "account_id";
+    pub const KIND:&str = "kind";
+    pub const SUB_DOMAIN:&str = "subdomain";
+    pub const BYTES_IN:&str = "bytes_in";
+    pub const BYTES_OUT:&str = "bytes_out";
+    pub const RECORDED_AT:&str = "recorded_at";
+}
+
+mod history_db {
+    pub const TABLE_NAME:&str = "tunnelto_history";
+    /// partition key, with `SUB_DOMAIN` as the sort key
+    pub const PRIMARY_KEY:&str = "account_id";
+    pub const SUB_DOMAIN:&str = "subdomain";
+    /// global secondary index on `SUB_DOMAIN`, for operators looking up who used one
+    pub const SUB_DOMAIN_INDEX:&str = "subdomain-index";
+    pub const LAST_CONNECTED_AT:&str = "last_connected_at";
+    pub const CLIENT_HOSTNAME:&str = "client_hostname";
+    /// unix seconds, the table's TTL attribute
+    pub const EXPIRES_AT:&str = "expires_at";
+}
+
+mod lease_db {
+    pub const TABLE_NAME:&str = "tunnelto_leases";
+    pub const PRIMARY_KEY:&str = "subdomain";
+    pub const HOLDER:&str = "holder";
+    /// unix seconds, the table's TTL attribute, though leases are checked before it runs
+    pub const EXPIRES_AT:&str = "expires_at";
 }
 
 mod key_db {
-    pub const TABLE_NAME:&'static str = "tunnelto_auth";
-    pub const PRIMARY_KEY:&'static str = "auth_key_hash";
-    pub const ACCOUNT_ID:&'static str = "account_id";
+    pub const TABLE_NAME:&str = "tunnelto_auth";
+    pub const PRIMARY_KEY:&str = "auth_key_hash";
+    pub const ACCOUNT_ID:&str = "account_id";
+    /// global secondary index on `ACCOUNT_ID`, projecting all attributes, for listing
+    /// an account's keys
+    pub const ACCOUNT_INDEX:&str = "account_id-index";
+    pub const MAX_TUNNELS:&str = "max_tunnels";
+    pub const CUSTOM_DOMAINS:&str = "custom_domains";
+    pub const TCP_TUNNELS:&str = "tcp_tunnels";
+    pub const MAX_BANDWIDTH:&str = "max_bandwidth";
+    pub const SUB_DOMAIN_PREFIX:&str = "subdomain_prefix";
+    /// comma separated, see `Entitlements::sub_domains_to_stored`
+    pub const SUB_DOMAINS:&str = "subdomains";
+    pub const EXPIRES_AT:&str = "expires_at";
+    /// json, see `ApprovalPolicy`
+    pub const APPROVAL_POLICY:&str = "approval_policy";
+    pub const REVOKED:&str = "revoked";
+    pub const LABEL:&str = "label";
+    /// `high` or `low`, normal if missing, see `Priority`
+    pub const PRIORITY:&str = "priority";
+    pub const CATCH_ALL:&str = "catch_all";
+    /// `free`, `pro` or `team`, see `Tier`
+    pub const TIER:&str = "tier";
+    /// see `hello_signature::public_key`
+    pub const HELLO_KEY:&str = "hello_key";
 }
 
-fn key_id(auth_key: &str) -> String {
-    let hash = sha2::Sha256::digest(auth_key.as_bytes()).to_vec();
-    base64::encode_config(&hash, base64::URL_SAFE_NO_PAD)
+pub(crate) fn key_id(auth_key: &str) -> String {
+    tunnelto_lib::hello_signature::key_id(auth_key)
 }
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("failed to get domain item")]
-    AuthDbGetItem(#[from] rusoto_core::RusotoError<GetItemError>),
+    AuthDbGetItem(Box<rusoto_core::RusotoError<GetItemError>>),
+
+    #[error("failed to put item")]
+    AuthDbPutItem(Box<rusoto_core::RusotoError<PutItemError>>),
+
+    #[error("failed to delete item")]
+    AuthDbDeleteItem(Box<rusoto_core::RusotoError<DeleteItemError>>),
+
+    #[error("failed to query items")]
+    AuthDbQuery(Box<rusoto_core::RusotoError<QueryError>>),
+
+    #[error("failed to update item")]
+    AuthDbUpdateItem(Box<rusoto_core::RusotoError<UpdateItemError>>),
 
     #[error("The authentication key is invalid")]
     AccountNotFound,
@@ -52,6 +228,275 @@ pub enum Error {
 
     #[error("The subdomain is not authorized")]
     SubdomainNotAuthorized,
+
+    #[error("Guest keys can only open tunnels")]
+    GuestKey,
+
+    #[error("The authentication key was revoked")]
+    KeyRevoked,
+
+    #[error("The sub-domain is claimed by another account")]
+    ClaimedByOther,
+
+    /// for backends other than DynamoDB to report their own failures
+    #[error("auth backend error: {0}")]
+    Backend(String),
+}
+
+/// The DynamoDB errors are large, boxed so every `Result<_, Error>` stays small
+macro_rules! from_rusoto_error {
+    ($($variant:ident($error:ty)),*) => {$(
+        impl From<rusoto_core::RusotoError<$error>> for Error {
+            fn from(e: rusoto_core::RusotoError<$error>) -> Self {
+                Error::$variant(Box::new(e))
+            }
+        }
+    )*};
+}
+
+from_rusoto_error!(
+    AuthDbGetItem(GetItemError),
+    AuthDbPutItem(PutItemError),
+    AuthDbDeleteItem(DeleteItemError),
+    AuthDbQuery(QueryError),
+    AuthDbUpdateItem(UpdateItemError)
+);
+
+/// A sub-domain claimed by an account that proved ownership of `domain`
+#[derive(Debug, Clone)]
+pub struct VerifiedClaim {
+    pub account_id: Uuid,
+    pub domain: String,
+}
+
+/// Permission the owner of a sub-domain (`account_id`) gave another account to claim it
+#[derive(Debug, Clone)]
+pub struct Grant {
+    pub account_id: Uuid,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+fn grant_id(subdomain: &str, grantee: &Uuid) -> String {
+    format!("{}:{}", subdomain, grantee)
+}
+
+/// How long sub-domain history is kept after an account last used the sub-domain
+pub const HISTORY_RETENTION_DAYS: i64 = 30;
+
+/// An account's last connection on a sub-domain
+#[derive(Debug, Clone)]
+pub struct HistoryRecord {
+    pub account_id: Uuid,
+    pub sub_domain: String,
+    pub last_connected_at: chrono::DateTime<chrono::Utc>,
+    pub client_hostname: Option<String>,
+}
+
+impl HistoryRecord {
+    fn from_item(item: &HashMap<String, AttributeValue>) -> Option<Self> {
+        let string = |name: &str| item.get(name).and_then(|a| a.s.clone());
+
+        Some(HistoryRecord {
+            account_id: Uuid::from_str(&string(history_db::PRIMARY_KEY)?).ok()?,
+            sub_domain: string(history_db::SUB_DOMAIN)?,
+            last_connected_at: chrono::DateTime::parse_from_rfc3339(&string(history_db::LAST_CONNECTED_AT)?)
+                .ok()?
+                .with_timezone(&chrono::Utc),
+            client_hostname: string(history_db::CLIENT_HOSTNAME),
+        })
+    }
+}
+
+/// What an account's plan allows it to do
+#[derive(Debug, Clone)]
+pub struct Entitlements {
+    /// maximum concurrent tunnels across every instance, unlimited if `None`. Counted as
+    /// a tunnel connects, so tunnels connecting at the same moment to different
+    /// instances may all get in.
+    pub max_tunnels: Option<u32>,
+    /// may request a specific sub-domain
+    pub custom_domains: bool,
+    /// may open raw TCP tunnels, i.e. `TunnelType::TlsPassthrough`
+    pub tcp_tunnels: bool,
+    /// maximum bytes/sec per tunnel, unlimited if `None`
+    pub max_bandwidth: Option<u64>,
+    /// requested sub-domains must start with this (guest keys)
+    pub sub_domain_prefix: Option<String>,
+    /// the only sub-domains the key opens, besides those matching `sub_domain_prefix`
+    /// (scoped keys), any if empty
+    pub sub_domains: Vec<String>,
+    /// the key stops working after this (guest keys)
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// tunnels a teammate must approve before they forward traffic, see `approvals`
+    pub approval: Option<ApprovalPolicy>,
+    /// the key no longer works, and tunnels it opened are closed, see `revocation`
+    pub revoked: bool,
+    /// the key's name among the account's keys (`laptop`, `ci`), for the logs
+    pub label: Option<String>,
+    /// how readily its visitors are shed when the server is overloaded, see `load_shedding`
+    pub priority: Priority,
+    /// may open a catch-all tunnel for the sub-domains no other tunnel serves, see `catch_all`
+    pub catch_all: bool,
+    /// the account's tier, capping the limits above, see `tiers`
+    pub tier: Option<Tier>,
+}
+
+impl Default for Entitlements {
+    fn default() -> Self {
+        Entitlements {
+            max_tunnels: None,
+            custom_domains: true,
+            tcp_tunnels: false,
+            max_bandwidth: None,
+            sub_domain_prefix: None,
+            sub_domains: vec![],
+            expires_at: None,
+            approval: None,
+            revoked: false,
+            label: None,
+            priority: Priority::Normal,
+            catch_all: false,
+            tier: None,
+        }
+    }
+}
+
+impl Entitlements {
+    pub fn anonymous() -> Self {
+        Entitlements {
+            max_tunnels: None,
+            custom_domains: false,
+            tcp_tunnels: false,
+            max_bandwidth: crate::CONFIG.anonymous.max_bandwidth(),
+            sub_domain_prefix: None,
+            sub_domains: vec![],
+            expires_at: None,
+            approval: None,
+            revoked: false,
+            label: None,
+            priority: Priority::Low,
+            catch_all: false,
+            tier: None,
+        }
+    }
+
+    /// Guest keys expire, and don't get to make more keys
+    pub fn is_guest(&self) -> bool {
+        self.expires_at.is_some()
+    }
+
+    /// Scoped keys only open the sub-domains they list, or those starting with their prefix
+    pub fn allows_sub_domain(&self, sub_domain: &str) -> bool {
+        if self.sub_domains.is_empty() && self.sub_domain_prefix.is_none() {
+            return true;
+        }
+
+        let sub_domain = sub_domain.to_lowercase();
+        self.sub_domains.iter().any(|allowed| allowed.eq_ignore_ascii_case(&sub_domain))
+            || self.sub_domain_prefix.as_ref().is_some_and(|prefix| sub_domain.starts_with(prefix.as_str()))
+    }
+
+    /// The key's sub-domains as the auth backends store them, `None` if it isn't scoped
+    pub fn sub_domains_to_stored(&self) -> Option<String> {
+        if self.sub_domains.is_empty() {
+            return None;
+        }
+        Some(self.sub_domains.join(","))
+    }
+
+    pub fn sub_domains_from_stored(stored: Option<&str>) -> Vec<String> {
+        stored
+            .unwrap_or_default()
+            .split(',')
+            .map(|sub_domain| sub_domain.trim().to_lowercase())
+            .filter(|sub_domain| !sub_domain.is_empty())
+            .collect()
+    }
+
+    fn from_item(item: &HashMap<String, AttributeValue>) -> Self {
+        let default = Self::default();
+        let number = |name: &str| item.get(name).and_then(|a| a.n.as_ref()).and_then(|n| n.parse().ok());
+        let boolean = |name: &str| item.get(name).and_then(|a| a.bool);
+        let string = |name: &str| item.get(name).and_then(|a| a.s.clone());
+
+        Entitlements {
+            max_tunnels: number(key_db::MAX_TUNNELS).map(|n: u64| n as u32).or(default.max_tunnels),
+            custom_domains: boolean(key_db::CUSTOM_DOMAINS).unwrap_or(default.custom_domains),
+            tcp_tunnels: boolean(key_db::TCP_TUNNELS).unwrap_or(default.tcp_tunnels),
+            max_bandwidth: number(key_db::MAX_BANDWIDTH).or(default.max_bandwidth),
+            sub_domain_prefix: string(key_db::SUB_DOMAIN_PREFIX),
+            sub_domains: Self::sub_domains_from_stored(string(key_db::SUB_DOMAINS).as_deref()),
+            expires_at: string(key_db::EXPIRES_AT)
+                .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
+                .map(|t| t.with_timezone(&chrono::Utc)),
+            approval: string(key_db::APPROVAL_POLICY).map(|s| ApprovalPolicy::from_stored(&s)),
+            revoked: boolean(key_db::REVOKED).unwrap_or(false),
+            label: string(key_db::LABEL),
+            priority: Priority::from_stored(string(key_db::PRIORITY).as_deref()),
+            catch_all: boolean(key_db::CATCH_ALL).unwrap_or(default.catch_all),
+            tier: Tier::from_stored(string(key_db::TIER).as_deref()),
+        }
+    }
+}
+
+/// Entitlements set by an external authority (auth webhook, token claims),
+/// the rest keep their defaults
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct EntitlementClaims {
+    max_tunnels: Option<u32>,
+    custom_domains: Option<bool>,
+    tcp_tunnels: Option<bool>,
+    max_bandwidth: Option<u64>,
+    sub_domain_prefix: Option<String>,
+    #[serde(default)]
+    sub_domains: Vec<String>,
+    approval: Option<ApprovalPolicy>,
+    revoked: Option<bool>,
+    label: Option<String>,
+    priority: Option<Priority>,
+    catch_all: Option<bool>,
+    tier: Option<Tier>,
+}
+
+impl From<EntitlementClaims> for Entitlements {
+    fn from(e: EntitlementClaims) -> Self {
+        let default = Entitlements::default();
+        Entitlements {
+            max_tunnels: e.max_tunnels.or(default.max_tunnels),
+            custom_domains: e.custom_domains.unwrap_or(default.custom_domains),
+            tcp_tunnels: e.tcp_tunnels.unwrap_or(default.tcp_tunnels),
+            max_bandwidth: e.max_bandwidth.or(default.max_bandwidth),
+            sub_domain_prefix: e.sub_domain_prefix,
+            sub_domains: e.sub_domains.iter().map(|s| s.to_lowercase()).collect(),
+            expires_at: None,
+            approval: e.approval,
+            revoked: e.revoked.unwrap_or(false),
+            label: e.label,
+            priority: e.priority.unwrap_or(default.priority),
+            catch_all: e.catch_all.unwrap_or(default.catch_all),
+            tier: e.tier,
+        }
+    }
+}
+
+/// A key as the store holds it, hashed by `key_id`
+#[derive(Debug, Clone)]
+pub struct StoredKey {
+    pub key_hash: String,
+    pub account_id: Uuid,
+    pub entitlements: Entitlements,
+    /// what the key signs hellos with, see `hello_signature`; keys stored before it was
+    /// kept have none, until they're rotated
+    pub hello_key: Option<String>,
+}
+
+/// The account an auth key belongs to
+#[derive(Debug, Clone)]
+pub struct AuthenticatedAccount {
+    pub account_id: Uuid,
+    pub entitlements: Entitlements,
+    /// an external authority (auth webhook, token issuer) also approved the sub-domain
+    pub externally_authorized: bool,
 }
 
 pub enum AuthResult {
@@ -59,71 +504,513 @@ pub enum AuthResult {
     ReservedByOther,
     Available,
 }
-impl AuthDbService {
-    pub async fn auth_sub_domain(&self, auth_key: &str, subdomain: &str) -> Result<AuthResult, Error> {
-        let authenticated_account_id = self.get_account_id_for_auth_key(auth_key).await?;
-        match self.get_account_id_for_subdomain(subdomain).await? {
-            Some(account_id) => {
-                if authenticated_account_id == account_id {
-                    return Ok(AuthResult::ReservedByYou)
-                }
-
-                Ok(AuthResult::ReservedByOther)
-            },
-            None => Ok(AuthResult::Available)
-        }
+impl AuthService for AuthDbService {
+    fn get_account_id_for_auth_key<'a>(&'a self, auth_key: &'a str) -> BoxFuture<'a, Result<AuthenticatedAccount, Error>> {
+        async move {
+            let auth_key_hash = key_id(auth_key);
+
+            let mut input = GetItemInput { table_name: self.table(key_db::TABLE_NAME), ..Default::default() };
+            input.key = self.to_stored({
+                let mut item = HashMap::new();
+                item.insert(key_db::PRIMARY_KEY.to_string(), AttributeValue {
+                    s: Some(auth_key_hash),
+                    ..Default::default()
+                });
+                item
+            });
+
+            let item = self.read_stored(self.client.get_item(input).await?.item.unwrap_or_default());
+            let account_str = item
+                .get(key_db::ACCOUNT_ID)
+                .cloned()
+                .unwrap_or(AttributeValue::default())
+                .s
+                .ok_or(Error::AccountNotFound)?;
+
+            Ok(AuthenticatedAccount {
+                account_id: Uuid::from_str(&account_str)?,
+                entitlements: Entitlements::from_item(&item),
+                externally_authorized: false,
+            })
+        }.boxed()
     }
 
-    async fn get_account_id_for_auth_key(&self, auth_key: &str) -> Result<Uuid, Error> {
-        let auth_key_hash = key_id(auth_key);
+    fn get_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<StoredKey, Error>> {
+        async move {
+            let mut input = GetItemInput { table_name: self.table(key_db::TABLE_NAME), ..Default::default() };
+            input.key = self.to_stored({
+                let mut item = HashMap::new();
+                item.insert(key_db::PRIMARY_KEY.to_string(), AttributeValue {
+                    s: Some(key_hash.to_string()),
+                    ..Default::default()
+                });
+                item
+            });
+
+            let item = self.read_stored(self.client.get_item(input).await?.item.unwrap_or_default());
+            let account_str = item
+                .get(key_db::ACCOUNT_ID)
+                .and_then(|value| value.s.clone())
+                .ok_or(Error::AccountNotFound)?;
+
+            Ok(StoredKey {
+                key_hash: key_hash.to_string(),
+                account_id: Uuid::from_str(&account_str)?,
+                entitlements: Entitlements::from_item(&item),
+                hello_key: item.get(key_db::HELLO_KEY).and_then(|value| value.s.clone()),
+            })
+        }.boxed()
+    }
+
+    /// Store a key for the account, with the entitlements it carries
+    fn put_auth_key<'a>(&'a self, auth_key: &'a str, account_id: &'a Uuid, entitlements: &'a Entitlements) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let string = |s: String| AttributeValue { s: Some(s), ..Default::default() };
+            let number = |n: u64| AttributeValue { n: Some(n.to_string()), ..Default::default() };
+            let boolean = |b: bool| AttributeValue { bool: Some(b), ..Default::default() };
 
-        let mut input = GetItemInput { table_name: key_db::TABLE_NAME.to_string(), ..Default::default() };
-        input.key = {
             let mut item = HashMap::new();
-            item.insert(key_db::PRIMARY_KEY.to_string(), AttributeValue {
-                s: Some(auth_key_hash),
+            item.insert(key_db::PRIMARY_KEY.to_string(), string(key_id(auth_key)));
+            item.insert(key_db::ACCOUNT_ID.to_string(), string(account_id.to_string()));
+            item.insert(key_db::HELLO_KEY.to_string(), string(tunnelto_lib::hello_signature::public_key(auth_key)));
+            item.insert(key_db::CUSTOM_DOMAINS.to_string(), boolean(entitlements.custom_domains));
+            item.insert(key_db::TCP_TUNNELS.to_string(), boolean(entitlements.tcp_tunnels));
+            if let Some(max_tunnels) = entitlements.max_tunnels {
+                item.insert(key_db::MAX_TUNNELS.to_string(), number(max_tunnels as u64));
+            }
+            if let Some(max_bandwidth) = entitlements.max_bandwidth {
+                item.insert(key_db::MAX_BANDWIDTH.to_string(), number(max_bandwidth));
+            }
+            if let Some(prefix) = entitlements.sub_domain_prefix.as_ref() {
+                item.insert(key_db::SUB_DOMAIN_PREFIX.to_string(), string(prefix.clone()));
+            }
+            if let Some(sub_domains) = entitlements.sub_domains_to_stored() {
+                item.insert(key_db::SUB_DOMAINS.to_string(), string(sub_domains));
+            }
+            if let Some(expires_at) = entitlements.expires_at {
+                item.insert(key_db::EXPIRES_AT.to_string(), string(expires_at.to_rfc3339()));
+            }
+            if let Some(policy) = entitlements.approval.as_ref() {
+                item.insert(key_db::APPROVAL_POLICY.to_string(), string(policy.to_stored()));
+            }
+            if entitlements.revoked {
+                item.insert(key_db::REVOKED.to_string(), boolean(true));
+            }
+            if let Some(label) = entitlements.label.as_ref() {
+                item.insert(key_db::LABEL.to_string(), string(label.clone()));
+            }
+            if let Some(priority) = entitlements.priority.to_stored() {
+                item.insert(key_db::PRIORITY.to_string(), string(priority));
+            }
+            if entitlements.catch_all {
+                item.insert(key_db::CATCH_ALL.to_string(), boolean(true));
+            }
+            if let Some(tier) = entitlements.tier {
+                item.insert(key_db::TIER.to_string(), string(tier.as_str().to_string()));
+            }
+
+            let input = PutItemInput { table_name: self.table(key_db::TABLE_NAME), item: self.to_stored(item), ..Default::default() };
+            self.client.put_item(input).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn get_keys<'a>(&'a self, account_id: &'a Uuid) -> BoxFuture<'a, Result<Vec<StoredKey>, Error>> {
+        async move {
+            let mut values = HashMap::new();
+            values.insert(":account_id".to_string(), AttributeValue {
+                s: Some(account_id.to_string()),
                 ..Default::default()
             });
-            item
-        };
 
-        let result = self.client.get_item(input).await?;
-        let account_str = result.item
-            .unwrap_or(HashMap::new())
-            .get(key_db::ACCOUNT_ID)
-            .cloned()
-            .unwrap_or(AttributeValue::default())
-            .s
-            .ok_or(Error::AccountNotFound)?;
+            let input = QueryInput {
+                table_name: self.table(key_db::TABLE_NAME),
+                index_name: Some(self.table(key_db::ACCOUNT_INDEX)),
+                key_condition_expression: Some(format!("{} = :account_id", self.attr(key_db::ACCOUNT_ID))),
+                expression_attribute_values: Some(values),
+                ..Default::default()
+            };
 
-        let uuid = Uuid::from_str(&account_str)?;
-        Ok(uuid)
+            let items: Vec<_> = self.client.query(input).await?.items.unwrap_or_default()
+                .into_iter()
+                .map(|item| self.read_stored(item))
+                .collect();
+            Ok(items
+                .iter()
+                .filter_map(|item| Some(StoredKey {
+                    key_hash: item.get(key_db::PRIMARY_KEY)?.s.clone()?,
+                    account_id: *account_id,
+                    entitlements: Entitlements::from_item(item),
+                    hello_key: item.get(key_db::HELLO_KEY).and_then(|value| value.s.clone()),
+                }))
+                .collect())
+        }.boxed()
     }
 
-    async fn get_account_id_for_subdomain(&self, subdomain: &str) -> Result<Option<Uuid>, Error> {
-        let mut input = GetItemInput { table_name: domain_db::TABLE_NAME.to_string(), ..Default::default() };
-        input.key = {
+    fn revoke_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut input = UpdateItemInput { table_name: self.table(key_db::TABLE_NAME), ..Default::default() };
+            input.key = self.to_stored({
+                let mut item = HashMap::new();
+                item.insert(key_db::PRIMARY_KEY.to_string(), AttributeValue {
+                    s: Some(key_hash.to_string()),
+                    ..Default::default()
+                });
+                item
+            });
+            // only update keys that exist, instead of creating a revoked stub
+            input.update_expression = Some("SET #revoked = :revoked".to_string());
+            input.condition_expression = Some("attribute_exists(#key)".to_string());
+            input.expression_attribute_names = Some({
+                let mut names = HashMap::new();
+                names.insert("#revoked".to_string(), self.attr(key_db::REVOKED));
+                names.insert("#key".to_string(), self.attr(key_db::PRIMARY_KEY));
+                names
+            });
+            input.expression_attribute_values = Some({
+                let mut values = HashMap::new();
+                values.insert(":revoked".to_string(), AttributeValue { bool: Some(true), ..Default::default() });
+                values
+            });
+
+            match self.client.update_item(input).await {
+                Ok(_) => Ok(()),
+                Err(rusoto_core::RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Err(Error::AccountNotFound),
+                Err(e) => Err(e.into()),
+            }
+        }.boxed()
+    }
+
+    fn get_account_id_for_subdomain<'a>(&'a self, subdomain: &'a str) -> BoxFuture<'a, Result<Option<Uuid>, Error>> {
+        async move {
+            let mut input = GetItemInput { table_name: self.table(domain_db::TABLE_NAME), ..Default::default() };
+            input.key = self.to_stored({
+                let mut item = HashMap::new();
+                item.insert(domain_db::PRIMARY_KEY.to_string(), AttributeValue {
+                    s: Some(subdomain.to_string()),
+                    ..Default::default()
+                });
+                item
+            });
+
+            let result = self.client.get_item(input).await?;
+            let account_str = self.read_stored(result.item.unwrap_or_default())
+                .get(domain_db::ACCOUNT_ID)
+                .cloned()
+                .unwrap_or(AttributeValue::default())
+                .s;
+
+            if let Some(account_str) = account_str {
+                let uuid = Uuid::from_str(&account_str)?;
+                Ok(Some(uuid))
+            } else {
+                Ok(None)
+            }
+        }.boxed()
+    }
+
+    fn put_reservation<'a>(&'a self, subdomain: &'a str, account_id: &'a Uuid) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
             let mut item = HashMap::new();
             item.insert(domain_db::PRIMARY_KEY.to_string(), AttributeValue {
                 s: Some(subdomain.to_string()),
                 ..Default::default()
             });
-            item
+            item.insert(domain_db::ACCOUNT_ID.to_string(), AttributeValue {
+                s: Some(account_id.to_string()),
+                ..Default::default()
+            });
+
+            let input = PutItemInput { table_name: self.table(domain_db::TABLE_NAME), item: self.to_stored(item), ..Default::default() };
+            self.client.put_item(input).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn get_verified_claim<'a>(&'a self, subdomain: &'a str) -> BoxFuture<'a, Result<Option<VerifiedClaim>, Error>> {
+        async move {
+            let mut input = GetItemInput { table_name: self.table(claim_db::TABLE_NAME), ..Default::default() };
+            input.key = self.to_stored({
+                let mut item = HashMap::new();
+                item.insert(claim_db::PRIMARY_KEY.to_string(), AttributeValue {
+                    s: Some(subdomain.to_string()),
+                    ..Default::default()
+                });
+                item
+            });
+
+            let item = self.read_stored(self.client.get_item(input).await?.item.unwrap_or_default());
+            let account_str = item.get(claim_db::ACCOUNT_ID).and_then(|a| a.s.clone());
+            let domain = item.get(claim_db::DOMAIN).and_then(|a| a.s.clone());
+
+            match (account_str, domain) {
+                (Some(account_str), Some(domain)) => Ok(Some(VerifiedClaim {
+                    account_id: Uuid::from_str(&account_str)?,
+                    domain,
+                })),
+                _ => Ok(None),
+            }
+        }.boxed()
+    }
+
+    fn put_verified_claim<'a>(&'a self, subdomain: &'a str, claim: &'a VerifiedClaim, replacing: Option<&'a str>) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut item = HashMap::new();
+            item.insert(claim_db::PRIMARY_KEY.to_string(), AttributeValue {
+                s: Some(subdomain.to_string()),
+                ..Default::default()
+            });
+            item.insert(claim_db::ACCOUNT_ID.to_string(), AttributeValue {
+                s: Some(claim.account_id.to_string()),
+                ..Default::default()
+            });
+            item.insert(claim_db::DOMAIN.to_string(), AttributeValue {
+                s: Some(claim.domain.clone()),
+                ..Default::default()
+            });
+            item.insert(claim_db::VERIFIED_AT.to_string(), AttributeValue {
+                s: Some(chrono::Utc::now().to_rfc3339()),
+                ..Default::default()
+            });
+
+            // only the account holding the claim may renew it, or a claim outranking it
+            // replace it
+            let mut input = PutItemInput { table_name: self.table(claim_db::TABLE_NAME), item: self.to_stored(item), ..Default::default() };
+            input.condition_expression = Some("attribute_not_exists(#key) OR #account_id = :account_id OR #domain = :replacing".to_string());
+            input.expression_attribute_names = Some({
+                let mut names = HashMap::new();
+                names.insert("#key".to_string(), self.attr(claim_db::PRIMARY_KEY));
+                names.insert("#account_id".to_string(), self.attr(claim_db::ACCOUNT_ID));
+                names.insert("#domain".to_string(), self.attr(claim_db::DOMAIN));
+                names
+            });
+            input.expression_attribute_values = Some({
+                let mut values = HashMap::new();
+                values.insert(":account_id".to_string(), AttributeValue {
+                    s: Some(claim.account_id.to_string()),
+                    ..Default::default()
+                });
+                // no domain is empty, so nothing is replaced without one
+                values.insert(":replacing".to_string(), AttributeValue {
+                    s: Some(replacing.unwrap_or_default().to_string()),
+                    ..Default::default()
+                });
+                values
+            });
+
+            match self.client.put_item(input).await {
+                Ok(_) => Ok(()),
+                Err(rusoto_core::RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => Err(Error::ClaimedByOther),
+                Err(e) => Err(e.into()),
+            }
+        }.boxed()
+    }
+
+    /// The unexpired grant letting `grantee` claim the sub-domain, if any
+    fn get_grant<'a>(&'a self, subdomain: &'a str, grantee: &'a Uuid) -> BoxFuture<'a, Result<Option<Grant>, Error>> {
+        async move {
+            let mut input = GetItemInput { table_name: self.table(grant_db::TABLE_NAME), ..Default::default() };
+            input.key = self.to_stored({
+                let mut item = HashMap::new();
+                item.insert(grant_db::PRIMARY_KEY.to_string(), AttributeValue {
+                    s: Some(grant_id(subdomain, grantee)),
+                    ..Default::default()
+                });
+                item
+            });
+
+            let item = self.read_stored(self.client.get_item(input).await?.item.unwrap_or_default());
+            let account_str = item.get(grant_db::ACCOUNT_ID).and_then(|a| a.s.clone());
+            let expires_at = item.get(grant_db::EXPIRES_AT)
+                .and_then(|a| a.s.as_ref())
+                .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+                .map(|t| t.with_timezone(&chrono::Utc));
+
+            match (account_str, expires_at) {
+                (Some(account_str), Some(expires_at)) if expires_at > chrono::Utc::now() => Ok(Some(Grant {
+                    account_id: Uuid::from_str(&account_str)?,
+                    expires_at,
+                })),
+                _ => Ok(None),
+            }
+        }.boxed()
+    }
+
+    fn put_grant<'a>(&'a self, subdomain: &'a str, grantee: &'a Uuid, grant: &'a Grant) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut item = HashMap::new();
+            item.insert(grant_db::PRIMARY_KEY.to_string(), AttributeValue {
+                s: Some(grant_id(subdomain, grantee)),
+                ..Default::default()
+            });
+            item.insert(grant_db::ACCOUNT_ID.to_string(), AttributeValue {
+                s: Some(grant.account_id.to_string()),
+                ..Default::default()
+            });
+            item.insert(grant_db::EXPIRES_AT.to_string(), AttributeValue {
+                s: Some(grant.expires_at.to_rfc3339()),
+                ..Default::default()
+            });
+
+            let input = PutItemInput { table_name: self.table(grant_db::TABLE_NAME), item: self.to_stored(item), ..Default::default() };
+            self.client.put_item(input).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn delete_grant<'a>(&'a self, subdomain: &'a str, grantee: &'a Uuid) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut input = DeleteItemInput { table_name: self.table(grant_db::TABLE_NAME), ..Default::default() };
+            input.key = self.to_stored({
+                let mut item = HashMap::new();
+                item.insert(grant_db::PRIMARY_KEY.to_string(), AttributeValue {
+                    s: Some(grant_id(subdomain, grantee)),
+                    ..Default::default()
+                });
+                item
+            });
+
+            self.client.delete_item(input).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn put_usage_record<'a>(&'a self, record: &'a crate::metering::UsageRecord) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let string = |s: String| AttributeValue { s: Some(s), ..Default::default() };
+            let number = |n: u64| AttributeValue { n: Some(n.to_string()), ..Default::default() };
+
+            let mut item = HashMap::new();
+            item.insert(usage_db::PRIMARY_KEY.to_string(), string(record.id.to_string()));
+            item.insert(usage_db::ACCOUNT_ID.to_string(), string(record.account_id.to_string()));
+            item.insert(usage_db::KIND.to_string(), string(record.kind.as_str().to_string()));
+            item.insert(usage_db::SUB_DOMAIN.to_string(), string(record.sub_domain.clone()));
+            item.insert(usage_db::BYTES_IN.to_string(), number(record.bytes_in));
+            item.insert(usage_db::BYTES_OUT.to_string(), number(record.bytes_out));
+            item.insert(usage_db::RECORDED_AT.to_string(), string(record.recorded_at.to_rfc3339()));
+
+            let input = PutItemInput { table_name: self.table(usage_db::TABLE_NAME), item: self.to_stored(item), ..Default::default() };
+            self.client.put_item(input).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn put_history_record<'a>(&'a self, record: &'a HistoryRecord) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let string = |s: String| AttributeValue { s: Some(s), ..Default::default() };
+            let expires_at = record.last_connected_at + chrono::Duration::days(HISTORY_RETENTION_DAYS);
+
+            let mut item = HashMap::new();
+            item.insert(history_db::PRIMARY_KEY.to_string(), string(record.account_id.to_string()));
+            item.insert(history_db::SUB_DOMAIN.to_string(), string(record.sub_domain.clone()));
+            item.insert(history_db::LAST_CONNECTED_AT.to_string(), string(record.last_connected_at.to_rfc3339()));
+            item.insert(history_db::EXPIRES_AT.to_string(), AttributeValue { n: Some(expires_at.timestamp().to_string()), ..Default::default() });
+            if let Some(hostname) = record.client_hostname.as_ref() {
+                item.insert(history_db::CLIENT_HOSTNAME.to_string(), string(hostname.clone()));
+            }
+
+            let input = PutItemInput { table_name: self.table(history_db::TABLE_NAME), item: self.to_stored(item), ..Default::default() };
+            self.client.put_item(input).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn get_history<'a>(&'a self, account_id: &'a Uuid) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        self.query_history(None, history_db::PRIMARY_KEY, account_id.to_string()).boxed()
+    }
+
+    fn get_sub_domain_history<'a>(&'a self, subdomain: &'a str) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        self.query_history(Some(history_db::SUB_DOMAIN_INDEX), history_db::SUB_DOMAIN, subdomain.to_string()).boxed()
+    }
+
+    fn acquire_sub_domain_lease<'a>(&'a self, subdomain: &'a str, holder: &'a str, expires_at: chrono::DateTime<chrono::Utc>) -> BoxFuture<'a, Result<bool, Error>> {
+        async move {
+            let string = |s: String| AttributeValue { s: Some(s), ..Default::default() };
+            let number = |n: i64| AttributeValue { n: Some(n.to_string()), ..Default::default() };
+
+            let mut item = HashMap::new();
+            item.insert(lease_db::PRIMARY_KEY.to_string(), string(subdomain.to_string()));
+            item.insert(lease_db::HOLDER.to_string(), string(holder.to_string()));
+            item.insert(lease_db::EXPIRES_AT.to_string(), number(expires_at.timestamp()));
+
+            // only put it if the lease is free, expired or already ours
+            let mut input = PutItemInput { table_name: self.table(lease_db::TABLE_NAME), item: self.to_stored(item), ..Default::default() };
+            input.condition_expression = Some("attribute_not_exists(#key) OR #holder = :holder OR #expires_at < :now".to_string());
+            input.expression_attribute_names = Some({
+                let mut names = HashMap::new();
+                names.insert("#key".to_string(), self.attr(lease_db::PRIMARY_KEY));
+                names.insert("#holder".to_string(), self.attr(lease_db::HOLDER));
+                names.insert("#expires_at".to_string(), self.attr(lease_db::EXPIRES_AT));
+                names
+            });
+            input.expression_attribute_values = Some({
+                let mut values = HashMap::new();
+                values.insert(":holder".to_string(), string(holder.to_string()));
+                values.insert(":now".to_string(), number(chrono::Utc::now().timestamp()));
+                values
+            });
+
+            match self.client.put_item(input).await {
+                Ok(_) => Ok(true),
+                Err(rusoto_core::RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => Ok(false),
+                Err(e) => Err(e.into()),
+            }
+        }.boxed()
+    }
+
+    fn release_sub_domain_lease<'a>(&'a self, subdomain: &'a str, holder: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut input = DeleteItemInput { table_name: self.table(lease_db::TABLE_NAME), ..Default::default() };
+            input.key = self.to_stored({
+                let mut item = HashMap::new();
+                item.insert(lease_db::PRIMARY_KEY.to_string(), AttributeValue {
+                    s: Some(subdomain.to_string()),
+                    ..Default::default()
+                });
+                item
+            });
+            // someone else's by now if ours expired
+            input.condition_expression = Some("#holder = :holder".to_string());
+            input.expression_attribute_names = Some({
+                let mut names = HashMap::new();
+                names.insert("#holder".to_string(), self.attr(lease_db::HOLDER));
+                names
+            });
+            input.expression_attribute_values = Some({
+                let mut values = HashMap::new();
+                values.insert(":holder".to_string(), AttributeValue { s: Some(holder.to_string()), ..Default::default() });
+                values
+            });
+
+            match self.client.delete_item(input).await {
+                Ok(_) | Err(rusoto_core::RusotoError::Service(DeleteItemError::ConditionalCheckFailed(_))) => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        }.boxed()
+    }
+}
+
+impl AuthDbService {
+    /// The history records where `key` is `value`, on the table or one of its indexes
+    async fn query_history(&self, index: Option<&str>, key: &str, value: String) -> Result<Vec<HistoryRecord>, Error> {
+        let mut names = HashMap::new();
+        names.insert("#key".to_string(), self.attr(key));
+        let mut values = HashMap::new();
+        values.insert(":value".to_string(), AttributeValue { s: Some(value), ..Default::default() });
+
+        let input = QueryInput {
+            table_name: self.table(history_db::TABLE_NAME),
+            index_name: index.map(|index| self.table(index)),
+            key_condition_expression: Some("#key = :value".to_string()),
+            expression_attribute_names: Some(names),
+            expression_attribute_values: Some(values),
+            ..Default::default()
         };
 
-        let result = self.client.get_item(input).await?;
-        let account_str = result.item
-            .unwrap_or(HashMap::new())
-            .get(domain_db::ACCOUNT_ID)
-            .cloned()
-            .unwrap_or(AttributeValue::default())
-            .s;
-
-        if let Some(account_str) = account_str {
-            let uuid = Uuid::from_str(&account_str)?;
-            Ok(Some(uuid))
-        } else {
-            Ok(None)
-        }
+        let items: Vec<_> = self.client.query(input).await?.items.unwrap_or_default()
+            .into_iter()
+            .map(|item| self.read_stored(item))
+            .collect();
+        Ok(items.iter().filter_map(HistoryRecord::from_item).collect())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,301 @@
+use super::auth_cache::CachedAuthService;
+use super::auth_db::{
+    AuthDbService, AuthResult, DynamoConfig, AuthenticatedAccount, Entitlements, Error, Grant, HistoryRecord,
+    StoredKey, VerifiedClaim,
+};
+use super::file_db::FileAuthService;
+use super::memory_db::MemoryAuthService;
+use super::postgres_db::PostgresAuthService;
+use super::redis_db::RedisAuthService;
+use super::sqlite_db::SqliteAuthService;
+use super::sub_domain_lease;
+use crate::metering::UsageRecord;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Where accounts, keys, sub-domain reservations, claims, grants, usage, sub-domain
+/// history and leases are stored.
+///
+/// The server only talks to its backend through this trait: to use another store,
+/// implement it and add the backend to `AuthBackend`.
+pub trait AuthService: Send + Sync {
+    /// Prepare the store once at startup, i.e. create missing tables
+    fn init<'a>(&'a self) -> BoxFuture<'a, Result<(), Error>> {
+        futures::future::ok(()).boxed()
+    }
+
+    /// The account an auth key belongs to, `Error::AccountNotFound` if there is none
+    fn get_account_id_for_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+    ) -> BoxFuture<'a, Result<AuthenticatedAccount, Error>>;
+
+    /// The key stored under `key_hash` (its `key_id`), `Error::AccountNotFound` if there
+    /// is none
+    fn get_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<StoredKey, Error>>;
+
+    /// Store a key for the account, with the entitlements it carries and the public key
+    /// it signs hellos with (`hello_signature::public_key`)
+    fn put_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+        account_id: &'a Uuid,
+        entitlements: &'a Entitlements,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// The account's keys, revoked ones included, in any order
+    fn get_keys<'a>(&'a self, account_id: &'a Uuid) -> BoxFuture<'a, Result<Vec<StoredKey>, Error>>;
+
+    /// Revoke the key stored under `key_hash` (its `key_id`), `Error::AccountNotFound`
+    /// if there is none
+    fn revoke_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// The account that reserved the sub-domain, if any
+    fn get_account_id_for_subdomain<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Uuid>, Error>>;
+
+    /// Reserve the sub-domain for the account, replacing any earlier reservation
+    fn put_reservation<'a>(
+        &'a self,
+        subdomain: &'a str,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn get_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<VerifiedClaim>, Error>>;
+
+    /// Store the account's verified claim on the sub-domain, `Error::ClaimedByOther` if
+    /// another account holds a claim on it already, unless that claim is for the domain
+    /// it's `replacing`, see `domain_claims`. Must be atomic across every instance sharing
+    /// the store.
+    fn put_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        claim: &'a VerifiedClaim,
+        replacing: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// The unexpired grant letting `grantee` claim the sub-domain, if any
+    fn get_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Option<Grant>, Error>>;
+
+    fn put_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+        grant: &'a Grant,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn delete_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn put_usage_record<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Store the account's latest connection on the sub-domain, replacing the previous one
+    fn put_history_record<'a>(
+        &'a self,
+        record: &'a HistoryRecord,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// The sub-domains the account used, in any order
+    fn get_history<'a>(
+        &'a self,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>>;
+
+    /// The accounts that used the sub-domain, in any order
+    fn get_sub_domain_history<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>>;
+
+    /// May the account use the sub-domain: its own reservation, lent to it, or free
+    fn auth_sub_domain<'a>(
+        &'a self,
+        account: &'a AuthenticatedAccount,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<AuthResult, Error>> {
+        async move {
+            match self.get_account_id_for_subdomain(subdomain).await? {
+                Some(account_id) => {
+                    if account.account_id == account_id {
+                        return Ok(AuthResult::ReservedByYou);
+                    }
+
+                    // the owner may have lent it out
+                    if self
+                        .get_grant(subdomain, &account.account_id)
+                        .await?
+                        .is_some()
+                    {
+                        return Ok(AuthResult::ReservedByYou);
+                    }
+
+                    Ok(AuthResult::ReservedByOther)
+                }
+                None => Ok(AuthResult::Available),
+            }
+        }
+        .boxed()
+    }
+
+    /// Like `get_account_id_for_auth_key`, but only for the account's own keys,
+    /// for managing the account (claims, grants, guest keys)
+    fn get_account_id_for_owner_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+    ) -> BoxFuture<'a, Result<AuthenticatedAccount, Error>> {
+        async move {
+            let account = self.get_account_id_for_auth_key(auth_key).await?;
+            if account.entitlements.revoked {
+                return Err(Error::KeyRevoked);
+            }
+            if account.entitlements.is_guest() {
+                return Err(Error::GuestKey);
+            }
+            Ok(account)
+        }
+        .boxed()
+    }
+
+    /// Lease the sub-domain to `holder` until `expires_at`, unless someone else holds an
+    /// unexpired lease on it. Must be atomic across every instance sharing the store.
+    /// The default keeps leases in memory, for backends serving a single instance.
+    fn acquire_sub_domain_lease<'a>(
+        &'a self,
+        subdomain: &'a str,
+        holder: &'a str,
+        expires_at: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        futures::future::ok(sub_domain_lease::acquire_local(subdomain, holder, expires_at)).boxed()
+    }
+
+    /// Give up the lease on the sub-domain, if `holder` still holds it
+    fn release_sub_domain_lease<'a>(
+        &'a self,
+        subdomain: &'a str,
+        holder: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        sub_domain_lease::release_local(subdomain, holder);
+        futures::future::ok(()).boxed()
+    }
+
+    /// The account that owns the sub-domain, by reservation or verified claim
+    fn get_owner<'a>(&'a self, subdomain: &'a str) -> BoxFuture<'a, Result<Option<Uuid>, Error>> {
+        async move {
+            if let Some(account_id) = self.get_account_id_for_subdomain(subdomain).await? {
+                return Ok(Some(account_id));
+            }
+            Ok(self
+                .get_verified_claim(subdomain)
+                .await?
+                .map(|claim| claim.account_id))
+        }
+        .boxed()
+    }
+}
+
+/// The auth backends the server can start with (AUTH_BACKEND)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthBackend {
+    /// `dynamodb`, the default, see `DynamoConfig`
+    DynamoDb(DynamoConfig),
+    /// `memory`, optionally seeded from a json file (AUTH_SEED_FILE), see `memory_db`
+    Memory { seed_file: Option<PathBuf> },
+    /// `postgres`, connecting to AUTH_DATABASE_URL, see `postgres_db`
+    Postgres { url: String },
+    /// `sqlite`, in the file at AUTH_SQLITE_PATH, see `sqlite_db`
+    Sqlite { path: PathBuf },
+    /// `redis`, at AUTH_REDIS_URL under AUTH_REDIS_PREFIX, see `redis_db`
+    Redis { url: String, prefix: String },
+    /// `file`, keys read from AUTH_KEYS_FILE and re-read on SIGHUP, see `file_db`
+    File { path: PathBuf },
+    /// `static`, only the keys listed in ALLOWED_AUTH_KEYS, see `memory_db`
+    Static { keys: Vec<String> },
+}
+
+impl FromStr for AuthBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "dynamodb" => Ok(AuthBackend::DynamoDb(DynamoConfig::default())),
+            "memory" => Ok(AuthBackend::Memory { seed_file: None }),
+            "postgres" => Ok(AuthBackend::Postgres { url: String::new() }),
+            "sqlite" => Ok(AuthBackend::Sqlite {
+                path: PathBuf::new(),
+            }),
+            "redis" => Ok(AuthBackend::Redis {
+                url: String::new(),
+                prefix: String::new(),
+            }),
+            "file" => Ok(AuthBackend::File {
+                path: PathBuf::new(),
+            }),
+            "static" => Ok(AuthBackend::Static { keys: vec![] }),
+            other => Err(format!(
+                "unknown auth backend `{}`, expected `dynamodb`, `memory`, `postgres`, `sqlite`, `redis`, `file` or `static`",
+                other
+            )),
+        }
+    }
+}
+
+impl AuthBackend {
+    /// Start the backend, panics if it can't
+    pub fn connect(&self) -> Box<dyn AuthService> {
+        let service: Box<dyn AuthService> = match self {
+            AuthBackend::DynamoDb(config) => Box::new(
+                AuthDbService::new(config.clone()).expect("failed to init auth-service"),
+            ),
+            AuthBackend::Memory { seed_file } => {
+                let service = match seed_file {
+                    Some(path) => MemoryAuthService::from_seed_file(path)
+                        .unwrap_or_else(|e| panic!("invalid AUTH_SEED_FILE {:?}: {}", path, e)),
+                    None => MemoryAuthService::default(),
+                };
+                Box::new(service)
+            }
+            AuthBackend::Postgres { url } => Box::new(
+                PostgresAuthService::new(url)
+                    .unwrap_or_else(|e| panic!("invalid AUTH_DATABASE_URL: {}", e)),
+            ),
+            AuthBackend::Sqlite { path } => {
+                Box::new(SqliteAuthService::open(path).unwrap_or_else(|e| {
+                    panic!("failed to open AUTH_SQLITE_PATH {:?}: {}", path, e)
+                }))
+            }
+            AuthBackend::Redis { url, prefix } => Box::new(
+                RedisAuthService::new(url, prefix)
+                    .unwrap_or_else(|e| panic!("invalid AUTH_REDIS_URL: {}", e)),
+            ),
+            AuthBackend::File { path } => Box::new(
+                FileAuthService::open(path)
+                    .unwrap_or_else(|e| panic!("failed to load AUTH_KEYS_FILE {:?}: {}", path, e)),
+            ),
+            AuthBackend::Static { keys } => Box::new(MemoryAuthService::from_keys(keys)),
+        };
+
+        // the in-process backends answer as fast as the cache would
+        match self {
+            AuthBackend::DynamoDb(_) | AuthBackend::Postgres { .. } | AuthBackend::Redis { .. } => {
+                CachedAuthService::wrap(service)
+            }
+            _ => service,
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::auth_db::{key_id, AuthenticatedAccount, EntitlementClaims};
+use crate::CONFIG;
+use serde::{Deserialize, Serialize};
+use reqwest::StatusCode;
+use std::time::Duration;
+use thiserror::Error;
+use tunnelto_lib::TunnelError;
+use uuid::Uuid;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("auth service request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("auth service denied the connection: {0}")]
+    Denied(String),
+
+    #[error("auth service allowed the connection without an account id")]
+    MissingAccountId,
+}
+
+impl From<Error> for TunnelError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Denied(_) => TunnelError::AuthFailed(e.to_string()),
+            _ => TunnelError::Internal(e.to_string()),
+        }
+    }
+}
+
+/// What we POST to the external auth service, the key itself never leaves the server
+#[derive(Debug, Serialize)]
+struct AuthWebhookRequest<'a> {
+    auth_key_hash: String,
+    sub_domain: &'a str,
+    base_domain: Option<&'a String>,
+}
+
+/// The auth service's decision. It may also deny with a 401 or 403, giving the
+/// `reason` in the body.
+#[derive(Debug, Deserialize)]
+struct AuthWebhookResponse {
+    #[serde(default)]
+    allow: bool,
+    account_id: Option<Uuid>,
+    reason: Option<String>,
+    #[serde(default)]
+    entitlements: EntitlementClaims,
+}
+
+/// Ask the auth service at `url` whether `auth_key` may open a tunnel on `sub_domain`
+pub async fn authorize(
+    url: &str,
+    auth_key: &str,
+    sub_domain: &str,
+    base_domain: Option<&String>,
+) -> Result<AuthenticatedAccount, Error> {
+    let request = AuthWebhookRequest {
+        auth_key_hash: key_id(auth_key),
+        sub_domain,
+        base_domain,
+    };
+
+    let mut builder = reqwest::Client::new()
+        .post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .json(&request);
+    if let Some(secret) = CONFIG.auth_webhook_secret.as_ref() {
+        builder = builder.bearer_auth(secret);
+    }
+
+    let response = builder.send().await?;
+    let status = response.status();
+    let response: AuthWebhookResponse = match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            let reason = response
+                .json::<AuthWebhookResponse>()
+                .await
+                .ok()
+                .and_then(|denied| denied.reason);
+            return Err(Error::Denied(reason.unwrap_or_else(|| status.to_string())));
+        }
+        _ => response.error_for_status()?.json().await?,
+    };
+
+    if !response.allow {
+        return Err(Error::Denied(
+            response.reason.unwrap_or_else(|| "no reason given".to_string()),
+        ));
+    }
+
+    Ok(AuthenticatedAccount {
+        account_id: response.account_id.ok_or(Error::MissingAccountId)?,
+        entitlements: response.entitlements.into(),
+        externally_authorized: true,
+    })
+}
@@ -1,207 +1,636 @@
+use crate::auth::approvals::{self, PendingApproval};
+use crate::auth::client_certs::ClientCert;
 use crate::auth::reconnect_token::ReconnectTokenPayload;
-use crate::auth_db::AuthResult;
+use crate::auth::revocation;
+use crate::auth::signed_hello::{self, SignedHello};
+use crate::auth::sub_domain_lease::{self, SubDomainLease};
+use crate::auth::tiers::{self, Tier};
+use crate::auth_db::{AuthResult, AuthenticatedAccount, Entitlements};
+use crate::connected_clients::Connections;
+use crate::handshake_audit::HandshakeRecord;
 use crate::{ReconnectToken, CONFIG};
+use chrono::{DateTime, Utc};
 use futures::{SinkExt, StreamExt};
-use log::error;
-use tunnelto_lib::{ClientHello, ClientHelloV1, ClientId, ClientType, ServerHello};
+use std::sync::Arc;
+use tunnelto_lib::{
+    acl, hello_signature, rate_limit, ClientHello, ClientHelloV1, ClientId, ClientType,
+    ErrorFormat, ServerHello, TrafficProfile, TunnelError, TunnelType,
+};
+use uuid::Uuid;
 use warp::filters::ws::{Message, WebSocket};
 
 pub struct ClientHandshake {
     pub id: ClientId,
     pub sub_domain: String,
     pub is_anonymous: bool,
+    pub account_id: Option<Uuid>,
+    /// the account's tier, its limits already applied to `entitlements`, see `tiers`
+    pub tier: Option<Tier>,
+    pub entitlements: Entitlements,
+    /// the base domain this tunnel is pinned to, or all of them
+    pub base_domain: Option<String>,
+    pub tunnel_type: TunnelType,
+    pub signing_secret: Option<String>,
+    pub access_rules: Vec<acl::AccessRule>,
+    pub rate_limits: Vec<rate_limit::RateLimitRule>,
+    pub error_format: ErrorFormat,
+    pub standby: bool,
+    pub traffic_profile: TrafficProfile,
+    pub client_hostname: Option<String>,
+    pub integrity: bool,
+    pub access_log: bool,
+    pub handover: bool,
+    /// held until a teammate approves it, see `approvals`
+    pub approval: Option<Arc<PendingApproval>>,
+    /// when an anonymous tunnel is closed, see `anonymous`
+    pub session_ends: Option<DateTime<Utc>>,
+    /// serves the sub-domains no other tunnel does, see `catch_all`
+    pub catch_all: bool,
+    /// keeps other clients off the sub-domain until the tunnel is registered,
+    /// see `sub_domain_lease`
+    pub lease: Option<SubDomainLease>,
 }
 
+impl ClientHandshake {
+    /// A plain http tunnel on `sub_domain`, nothing from the hello applied yet
+    fn new(id: ClientId, sub_domain: String, entitlements: Entitlements) -> Self {
+        ClientHandshake {
+            id,
+            sub_domain,
+            is_anonymous: false,
+            account_id: None,
+            tier: entitlements.tier,
+            entitlements,
+            base_domain: None,
+            tunnel_type: TunnelType::Http,
+            signing_secret: None,
+            access_rules: vec![],
+            rate_limits: vec![],
+            error_format: ErrorFormat::Text,
+            standby: false,
+            traffic_profile: TrafficProfile::General,
+            client_hostname: None,
+            integrity: false,
+            access_log: false,
+            handover: false,
+            approval: None,
+            session_ends: None,
+            catch_all: false,
+            lease: None,
+        }
+    }
+
+    fn anonymous(id: ClientId, sub_domain: String, session_ends: Option<DateTime<Utc>>) -> Self {
+        ClientHandshake {
+            is_anonymous: true,
+            session_ends,
+            ..Self::new(id, sub_domain, Entitlements::anonymous())
+        }
+    }
+
+    fn for_account(
+        id: ClientId,
+        sub_domain: String,
+        account: AuthenticatedAccount,
+        base_domain: Option<String>,
+        approval: Option<Arc<PendingApproval>>,
+    ) -> Self {
+        ClientHandshake {
+            account_id: Some(account.account_id),
+            base_domain,
+            approval,
+            ..Self::new(id, sub_domain, account.entitlements)
+        }
+    }
+}
+
+impl From<crate::auth_db::Error> for TunnelError {
+    fn from(e: crate::auth_db::Error) -> Self {
+        match e {
+            crate::auth_db::Error::AccountNotFound
+            | crate::auth_db::Error::InvalidAccountId(_)
+            | crate::auth_db::Error::GuestKey => {
+                TunnelError::AuthFailed(e.to_string())
+            }
+            crate::auth_db::Error::KeyRevoked => TunnelError::KeyRejected(e.to_string()),
+            crate::auth_db::Error::SubdomainNotAuthorized => TunnelError::SubDomainReserved,
+            _ => TunnelError::Internal(e.to_string()),
+        }
+    }
+}
+
+/// Authenticate the client hello, along with the certificate the client connected with
+/// if any. On failure the client is sent the matching server hello before the error is
+/// returned. What is learnt about the client on the way is kept in `attempt`, for the
+/// audit log.
 pub async fn auth_client_handshake(
     mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
+    cert: Option<&ClientCert>,
+    attempt: &mut HandshakeRecord,
+) -> Result<(WebSocket, ClientHandshake), TunnelError> {
+    match auth_client_hello(&mut websocket, cert, attempt).await {
+        Ok(handshake) => Ok((websocket, handshake)),
+        Err(e) => {
+            let server_hello = ServerHello::from(&e).for_client(attempt.client_version.as_deref());
+            let data = serde_json::to_vec(&server_hello).unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            Err(e)
+        }
+    }
+}
+
+async fn auth_client_hello(
+    websocket: &mut WebSocket,
+    cert: Option<&ClientCert>,
+    attempt: &mut HandshakeRecord,
+) -> Result<ClientHandshake, TunnelError> {
     let client_hello_data = match websocket.next().await {
         Some(Ok(msg)) => msg,
         _ => {
-            error!("no client init message");
-            return None;
+            return Err(TunnelError::InvalidClientHello(
+                "no client init message".into(),
+            ))
         }
     };
 
     if let Ok(client_hello_v1) =
         serde_json::from_slice::<ClientHelloV1>(client_hello_data.as_bytes())
     {
-        auth_client_v1(client_hello_v1, websocket).await
+        auth_client_v1(client_hello_v1).await
     } else {
-        auth_client(client_hello_data.as_bytes(), websocket).await
+        let signed = signed_hello::challenge(websocket, client_hello_data.as_bytes()).await?;
+        auth_client(client_hello_data.as_bytes(), signed.as_ref(), cert, attempt).await
     }
 }
 
-async fn auth_client_v1(
-    client_hello: ClientHelloV1,
-    mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
-    let client_id = client_hello.id.safe_id();
-    let sub_domain = match client_hello.sub_domain {
-        None => ServerHello::random_domain(),
-
-        // otherwise, try to assign the sub domain
-        Some(sub_domain) => {
-            let (ws, sub_domain) =
-                match sanitize_sub_domain_and_pre_validate(websocket, sub_domain, &client_id).await
-                {
-                    Some(s) => s,
-                    None => return None,
-                };
-            websocket = ws;
-
-            // don't allow specified domains for anonymous v1 clients
-            ServerHello::prefixed_random_domain(&sub_domain)
-        }
-    };
-
-    Some((
-        websocket,
-        ClientHandshake {
-            id: client_id,
-            sub_domain,
-            is_anonymous: true,
-        },
+async fn auth_client_v1(client_hello: ClientHelloV1) -> Result<ClientHandshake, TunnelError> {
+    let (sub_domain, session_ends) = crate::anonymous::admit(client_hello.sub_domain.as_deref())?;
+    Ok(ClientHandshake::anonymous(
+        client_hello.id.safe_id(),
+        sub_domain,
+        session_ends,
     ))
 }
 
 async fn auth_client(
     client_hello_data: &[u8],
-    mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
+    signed: Option<&SignedHello>,
+    cert: Option<&ClientCert>,
+    attempt: &mut HandshakeRecord,
+) -> Result<ClientHandshake, TunnelError> {
     // parse the client hello
-    let client_hello: ClientHello = match serde_json::from_slice(client_hello_data) {
-        Ok(ch) => ch,
-        Err(e) => {
-            error!("invalid client hello: {}", e);
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
-            return None;
+    let client_hello: ClientHello = serde_json::from_slice(client_hello_data)
+        .map_err(|e| TunnelError::InvalidClientHello(e.to_string()))?;
+    attempt.requested_sub_domain = client_hello.sub_domain.clone();
+    attempt.client_version = client_hello.client_version.clone();
+
+    let tunnel_type = client_hello.tunnel_type;
+    if tunnel_type == TunnelType::TlsPassthrough && CONFIG.tls_passthrough_port.is_none() {
+        return Err(TunnelError::UnsupportedTunnelType);
+    }
+    // passthrough tunnels are routed by SNI, which a catch-all doesn't take part in
+    if tunnel_type != TunnelType::Http && client_hello.catch_all {
+        return Err(TunnelError::InvalidClientHello(
+            "catch-all tunnels only take http".into(),
+        ));
+    }
+
+    let signing_secret = client_hello.signing_secret.clone();
+    let access_rules = client_hello.access_rules.clone();
+    let rate_limits = client_hello.rate_limits.clone();
+    if rate_limits.len() > rate_limit::MAX_RULES || !rate_limits.iter().all(|r| r.is_valid()) {
+        return Err(TunnelError::InvalidClientHello(format!(
+            "expected at most {} rate limits, each with a path and a rate above 0",
+            rate_limit::MAX_RULES
+        )));
+    }
+    let error_format = client_hello.error_format;
+    let traffic_profile = client_hello.traffic_profile;
+    let client_hostname = client_hello.client_hostname.clone();
+    let integrity = client_hello.integrity;
+    let access_log = client_hello.access_log;
+    let handover = client_hello.handover;
+    let mut handshake = auth_client_type(client_hello, signed, cert, attempt).await?;
+    // passthrough tunnels carry raw tcp, which the account has to be entitled to
+    if tunnel_type == TunnelType::TlsPassthrough && !handshake.entitlements.tcp_tunnels {
+        return Err(TunnelError::UnsupportedTunnelType);
+    }
+    handshake.tunnel_type = tunnel_type;
+    handshake.signing_secret = signing_secret;
+    handshake.access_rules = access_rules;
+    handshake.rate_limits = rate_limits;
+    handshake.error_format = error_format;
+    handshake.traffic_profile = traffic_profile;
+    handshake.client_hostname = client_hostname;
+    handshake.integrity = integrity;
+    handshake.access_log = access_log;
+    handshake.handover = handover;
+    Ok(handshake)
+}
+
+async fn auth_client_type(
+    client_hello: ClientHello,
+    signed: Option<&SignedHello>,
+    cert: Option<&ClientCert>,
+    attempt: &mut HandshakeRecord,
+) -> Result<ClientHandshake, TunnelError> {
+    // a standby waits for a sub-domain the account reserved, so it must say which
+    let standby = client_hello.standby;
+    if standby
+        && (matches!(client_hello.client_type, ClientType::Anonymous)
+            || client_hello.sub_domain.is_none())
+    {
+        return Err(TunnelError::InvalidClientHello(
+            "a standby must authenticate and request its reserved sub-domain".into(),
+        ));
+    }
+    let catch_all = client_hello.catch_all;
+    if catch_all && (standby || matches!(client_hello.client_type, ClientType::Anonymous)) {
+        return Err(TunnelError::InvalidClientHello(
+            "a catch-all tunnel must authenticate and can't stand by".into(),
+        ));
+    }
+
+    let client_type = &client_hello.client_type;
+    if let ClientType::Anonymous = client_type {
+        if let Some(token) = client_hello.reconnect_token {
+            return handle_reconnect_token(token).await;
         }
+
+        let (sub_domain, session_ends) =
+            crate::anonymous::admit(client_hello.sub_domain.as_deref())?;
+        return Ok(ClientHandshake::anonymous(
+            ClientId::generate(),
+            sub_domain,
+            session_ends,
+        ));
+    }
+
+    // a reconnect token brings back the sub-domain the client had, even a random one
+    let resumed = match client_hello.reconnect_token.clone() {
+        Some(token) if client_hello.sub_domain.is_none() && !catch_all && !standby => {
+            resume_with_token(token, client_hello.base_domain.as_ref())
+        }
+        _ => None,
     };
 
-    let (auth_key, client_id, requested_sub_domain) = match client_hello.client_type {
-        ClientType::Anonymous => {
-            // determine the client and subdomain
-            let (client_id, sub_domain) =
-                match (client_hello.reconnect_token, client_hello.sub_domain) {
-                    (Some(token), _) => {
-                        return handle_reconnect_token(token, websocket).await;
-                    }
-                    (None, Some(sd)) => (
-                        ClientId::generate(),
-                        ServerHello::prefixed_random_domain(&sd),
-                    ),
-                    (None, None) => (ClientId::generate(), ServerHello::random_domain()),
-                };
-
-            return Some((
-                websocket,
-                ClientHandshake {
-                    id: client_id,
-                    sub_domain,
-                    is_anonymous: true,
-                },
+    // keyed clients that don't ask for a sub-domain get a random one
+    let requested_sub_domain = match client_hello.sub_domain.clone() {
+        _ if catch_all => crate::catch_all::SUB_DOMAIN.to_string(),
+        Some(sub_domain) => sub_domain,
+        None => resumed
+            .as_ref()
+            .map(|payload| payload.sub_domain.clone())
+            .unwrap_or_else(ServerHello::random_domain),
+    };
+
+    if let Some(base_domain) = client_hello.base_domain.as_ref() {
+        if !CONFIG.allowed_hosts.contains(base_domain) {
+            return Err(TunnelError::InvalidBaseDomain);
+        }
+    }
+
+    // a key's client id is known before it's checked, so failed logins can be
+    // told apart
+    let key_hash = match client_type {
+        ClientType::Auth { key } => Some(hello_signature::key_id(&key.0)),
+        ClientType::SignedAuth { key_id } => Some(key_id.clone()),
+        _ => None,
+    };
+    if let Some(key_hash) = key_hash.as_ref() {
+        attempt.client_id = hello_signature::client_id(key_hash).map(|id| id.to_string());
+        crate::key_lockout::check(key_hash, attempt.source_ip)?;
+    }
+    let authenticated = authenticate(
+        client_type,
+        signed,
+        cert,
+        &requested_sub_domain,
+        client_hello.base_domain.as_ref(),
+    )
+    .await;
+    if let Some(key_hash) = key_hash.as_ref() {
+        crate::key_lockout::record(key_hash, &authenticated, attempt.source_ip);
+    }
+    let (client_id, account) = authenticated?;
+    attempt.client_id = Some(client_id.to_string());
+    attempt.account_id = Some(account.account_id);
+
+    admit(
+        client_hello,
+        client_id,
+        account,
+        requested_sub_domain,
+        resumed,
+    )
+    .await
+}
+
+/// Admit the tunnel of a client whose credentials were checked: hold it to the
+/// account's limits and settle the sub-domain it gets out of the one it asked for.
+/// `resumed` is the reconnect token it brought back its sub-domain with, if any.
+pub(crate) async fn admit(
+    client_hello: ClientHello,
+    client_id: ClientId,
+    mut account: AuthenticatedAccount,
+    requested_sub_domain: String,
+    resumed: Option<ReconnectTokenPayload>,
+) -> Result<ClientHandshake, TunnelError> {
+    let standby = client_hello.standby;
+    let catch_all = client_hello.catch_all;
+
+    // the tier's limits hold for everything checked from here on
+    account.entitlements = tiers::apply(account.entitlements);
+    check_key_limits(&account.entitlements, &requested_sub_domain)?;
+
+    // and only for the credentials it was issued to, others go through the usual
+    // checks for the sub-domain like any client asking for it
+    let resumed = resumed.filter(|payload| {
+        payload.client_id == client_id && payload.account_id == Some(account.account_id)
+    });
+
+    // a standby doesn't add a tunnel, it only stands in for one
+    if let Some(max_tunnels) = account.entitlements.max_tunnels.filter(|_| !standby) {
+        let open = crate::network::tunnels_for_account(&account.account_id).await;
+        if open >= max_tunnels as usize {
+            return Err(TunnelError::TunnelLimitReached);
+        }
+    }
+
+    // a catch-all doesn't serve a sub-domain of its own to reserve or check
+    if catch_all {
+        crate::catch_all::admit(
+            &account.entitlements,
+            &client_id,
+            client_hello.base_domain.as_ref(),
+        )?;
+
+        let approval = approvals::hold(
+            account.entitlements.approval.as_ref(),
+            &requested_sub_domain,
+            client_hello.target,
+            &client_id,
+        );
+        revocation::watch(&client_hello.client_type, &client_id);
+        return Ok(ClientHandshake {
+            catch_all: true,
+            ..ClientHandshake::for_account(
+                client_id,
+                requested_sub_domain,
+                account,
+                client_hello.base_domain,
+                approval,
+            )
+        });
+    }
+
+    let (sub_domain, lease) = sanitize_sub_domain_and_pre_validate(
+        requested_sub_domain,
+        &client_id,
+        Some(&account),
+        client_hello.base_domain.as_ref(),
+        standby,
+    )
+    .await?;
+
+    // plans without custom domains get a prefixed random one instead, unless
+    // they're getting back the one they had
+    if !account.entitlements.custom_domains {
+        if standby {
+            return Err(TunnelError::InvalidClientHello(
+                "standbys need a plan with custom sub-domains".into(),
             ));
         }
-        ClientType::Auth { key } => match client_hello.sub_domain {
-            Some(requested_sub_domain) => {
-                let client_id = key.client_id();
-                let (ws, sub_domain) = match sanitize_sub_domain_and_pre_validate(
-                    websocket,
-                    requested_sub_domain,
-                    &client_id,
-                )
-                .await
-                {
-                    Some(s) => s,
-                    None => return None,
-                };
-                websocket = ws;
-
-                (key, client_id, sub_domain)
+
+        let sub_domain = match resumed {
+            Some(_) => sub_domain,
+            None => ServerHello::prefixed_random_domain(&sub_domain),
+        };
+        let approval = approvals::hold(
+            account.entitlements.approval.as_ref(),
+            &sub_domain,
+            client_hello.target,
+            &client_id,
+        );
+        revocation::watch(&client_hello.client_type, &client_id);
+        return Ok(ClientHandshake {
+            lease: lease.filter(|_| resumed.is_some()),
+            ..ClientHandshake::for_account(
+                client_id,
+                sub_domain,
+                account,
+                client_hello.base_domain,
+                approval,
+            )
+        });
+    }
+
+    // next authenticate the sub-domain, unless an external authority already did
+    let sub_domain = if account.externally_authorized {
+        sub_domain
+    } else {
+        match crate::AUTH_DB_SERVICE
+            .auth_sub_domain(&account, &sub_domain)
+            .await?
+        {
+            AuthResult::ReservedByYou => sub_domain,
+            AuthResult::Available if !standby => sub_domain,
+            AuthResult::Available => {
+                return Err(TunnelError::InvalidClientHello(
+                    "only reserved sub-domains can have a standby".into(),
+                ))
             }
-            None => {
-                return if let Some(token) = client_hello.reconnect_token {
-                    handle_reconnect_token(token, websocket).await
-                } else {
-                    let sub_domain = ServerHello::random_domain();
-                    Some((
-                        websocket,
-                        ClientHandshake {
-                            id: ClientId::generate(),
-                            sub_domain,
-                            is_anonymous: true,
-                        },
-                    ))
-                }
+            AuthResult::ReservedByOther => {
+                log::debug!("invalid client hello: sub-domain reserved by another account!");
+                return Err(crate::auth_db::Error::SubdomainNotAuthorized.into());
             }
-        },
+        }
     };
 
-    // next authenticate the sub-domain
-    let sub_domain = match crate::AUTH_DB_SERVICE
-        .auth_sub_domain(&auth_key.0, &requested_sub_domain)
-        .await
-    {
-        Ok(AuthResult::Available) | Ok(AuthResult::ReservedByYou) => requested_sub_domain,
-        Ok(AuthResult::ReservedByOther) => {
-            let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
-            return None;
-        }
-        Err(e) => {
-            error!("error auth-ing user {:?}!", e);
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
-            return None;
-        }
+    // held against the credentials that opened it, standby or not
+    let approval = approvals::hold(
+        account.entitlements.approval.as_ref(),
+        &sub_domain,
+        client_hello.target,
+        &client_id,
+    );
+
+    // a standby runs alongside the client it stands in for, so it needs its own id
+    let client_id = if standby {
+        ClientId::generate()
+    } else {
+        client_id
     };
+    revocation::watch(&client_hello.client_type, &client_id);
 
-    Some((
-        websocket,
-        ClientHandshake {
-            id: client_id,
+    Ok(ClientHandshake {
+        standby,
+        lease,
+        ..ClientHandshake::for_account(
+            client_id,
             sub_domain,
-            is_anonymous: false,
-        },
-    ))
+            account,
+            client_hello.base_domain,
+            approval,
+        )
+    })
 }
 
-async fn handle_reconnect_token(
-    token: ReconnectToken,
-    mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
-    let payload = match ReconnectTokenPayload::verify(token, &CONFIG.master_sig_key) {
-        Ok(payload) => payload,
-        Err(e) => {
-            error!("invalid reconnect token: {:?}", e);
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
-            return None;
+/// Authenticate the credentials of a non-anonymous client
+async fn authenticate(
+    client_type: &ClientType,
+    signed: Option<&SignedHello>,
+    cert: Option<&ClientCert>,
+    requested_sub_domain: &str,
+    base_domain: Option<&String>,
+) -> Result<(ClientId, AuthenticatedAccount), TunnelError> {
+    match client_type {
+        ClientType::Auth { key } => {
+            let account = match CONFIG.auth_webhook_url.as_ref() {
+                Some(url) => {
+                    crate::auth::auth_webhook::authorize(
+                        url,
+                        &key.0,
+                        requested_sub_domain,
+                        base_domain,
+                    )
+                    .await?
+                }
+                None => {
+                    crate::AUTH_DB_SERVICE
+                        .get_account_id_for_auth_key(&key.0)
+                        .await?
+                }
+            };
+            Ok((key.client_id(), account))
         }
-    };
+        ClientType::SignedAuth { key_id } => {
+            // the webhook is handed the key itself, which a signing client never sends
+            if CONFIG.auth_webhook_url.is_some() {
+                return Err(TunnelError::AuthFailed(
+                    "this server checks keys with its auth webhook, connect without --sign-hello"
+                        .into(),
+                ));
+            }
+            let signed =
+                signed.ok_or_else(|| TunnelError::AuthFailed("the hello wasn't signed".into()))?;
+            let key = crate::AUTH_DB_SERVICE.get_key(key_id).await?;
+            signed.verify(key.hello_key.as_deref())?;
+
+            let client_id = hello_signature::client_id(key_id)
+                .ok_or_else(|| TunnelError::AuthFailed("invalid key id".into()))?;
+            Ok((
+                client_id,
+                AuthenticatedAccount {
+                    account_id: key.account_id,
+                    entitlements: key.entitlements,
+                    externally_authorized: false,
+                },
+            ))
+        }
+        ClientType::Jwt { token } => {
+            let account = crate::auth::jwt::authenticate(token, requested_sub_domain).await?;
+            Ok((
+                ClientId::for_subject(&account.account_id.to_string()),
+                account,
+            ))
+        }
+        ClientType::Certificate => {
+            Ok(crate::auth::client_certs::authenticate(cert)?)
+        }
+        ClientType::Anonymous => Err(TunnelError::AuthFailed(
+            "anonymous clients have no credentials".into(),
+        )),
+    }
+}
+
+/// Keys expire or get revoked, and scoped keys are held to the sub-domains they list
+/// or those starting with a prefix
+fn check_key_limits(entitlements: &Entitlements, requested_sub_domain: &str) -> Result<(), TunnelError> {
+    if let Some(reason) = revocation::rejection(entitlements) {
+        return Err(TunnelError::KeyRejected(reason));
+    }
+
+    if !entitlements.allows_sub_domain(requested_sub_domain) {
+        let mut scope = entitlements
+            .sub_domains
+            .iter()
+            .map(|sub_domain| format!("`{}`", sub_domain))
+            .collect::<Vec<_>>();
+        if let Some(prefix) = entitlements.sub_domain_prefix.as_ref() {
+            scope.push(format!("sub-domains starting with `{}`", prefix));
+        }
+        return Err(TunnelError::KeyRejected(format!(
+            "the key only opens {}",
+            scope.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+async fn handle_reconnect_token(token: ReconnectToken) -> Result<ClientHandshake, TunnelError> {
+    let payload = ReconnectTokenPayload::verify(token, &CONFIG.master_sig_key, CONFIG.clock_skew)
+        .map_err(|e| TunnelError::AuthFailed(format!("invalid reconnect token: {}", e)))?;
+
+    // an authenticated client's token is only good along with its key
+    if payload.account_id.is_some() {
+        return Err(TunnelError::AuthFailed(
+            "the reconnect token was issued to an authenticated client, connect with its key".into(),
+        ));
+    }
 
     log::debug!(
         "accepting reconnect token from client: {}",
         &payload.client_id
     );
 
-    Some((
-        websocket,
-        ClientHandshake {
-            id: payload.client_id,
-            sub_domain: payload.sub_domain,
-            is_anonymous: true,
-        },
+    let session_ends = crate::anonymous::readmit(payload.session_ends)?;
+    Ok(ClientHandshake::anonymous(
+        payload.client_id,
+        payload.sub_domain,
+        session_ends,
     ))
 }
 
+/// The tunnel an authenticated client's reconnect token resumes, if it's valid and for
+/// the same base domain
+fn resume_with_token(
+    token: ReconnectToken,
+    base_domain: Option<&String>,
+) -> Option<ReconnectTokenPayload> {
+    let payload =
+        match ReconnectTokenPayload::verify(token, &CONFIG.master_sig_key, CONFIG.clock_skew) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::debug!("not resuming with reconnect token: {}", e);
+                return None;
+            }
+        };
+
+    if payload.account_id.is_none() || payload.base_domain.as_ref() != base_domain {
+        log::debug!(
+            "not resuming {} with a reconnect token for another tunnel",
+            &payload.client_id
+        );
+        return None;
+    }
+
+    log::debug!(
+        "resuming {} for client: {}",
+        &payload.sub_domain,
+        &payload.client_id
+    );
+    Some(payload)
+}
+
 async fn sanitize_sub_domain_and_pre_validate(
-    mut websocket: WebSocket,
     requested_sub_domain: String,
     client_id: &ClientId,
-) -> Option<(WebSocket, String)> {
+    account: Option<&AuthenticatedAccount>,
+    base_domain: Option<&String>,
+    standby: bool,
+) -> Result<(String, Option<SubDomainLease>), TunnelError> {
     // ignore uppercase
     let sub_domain = requested_sub_domain.to_lowercase();
 
@@ -211,30 +640,71 @@ async fn sanitize_sub_domain_and_pre_validate(
         .count()
         > 0
     {
-        error!("invalid client hello: only alphanumeric/hyphen chars allowed!");
-        let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
-        return None;
+        log::debug!("invalid client hello: only alphanumeric/hyphen chars allowed!");
+        return Err(TunnelError::InvalidSubDomain);
     }
 
     // ensure it's not a restricted one
-    if CONFIG.blocked_sub_domains.contains(&sub_domain) {
-        error!("invalid client hello: sub-domain restrict!");
-        let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
-        return None;
+    if CONFIG.is_blocked(&sub_domain, base_domain) {
+        log::debug!("invalid client hello: sub-domain restrict!");
+        return Err(TunnelError::SubDomainInUse);
+    }
+
+    // verified claims take precedence over first-come-first-served
+    let claim = crate::AUTH_DB_SERVICE
+        .get_verified_claim(&sub_domain)
+        .await
+        .unwrap_or_else(|e| {
+            log::debug!("Got error checking verified claims: {:?}", e);
+            None
+        });
+
+    let claimed_by_you = match (claim.as_ref(), account) {
+        (Some(claim), Some(account)) if account.account_id == claim.account_id => true,
+        // the owner may have lent it out
+        (Some(_), Some(account)) => crate::AUTH_DB_SERVICE
+            .get_grant(&sub_domain, &account.account_id)
+            .await
+            .unwrap_or_else(|e| {
+                log::debug!("Got error checking grants: {:?}", e);
+                None
+            })
+            .is_some(),
+        _ => false,
+    };
+
+    if claim.is_some() && !claimed_by_you {
+        log::debug!("invalid client hello: sub-domain claimed by a verified owner!");
+        return Err(TunnelError::SubDomainInUse);
     }
 
+    // a standby doesn't take the sub-domain over, so it needn't hold others off
+    let lease = if standby {
+        None
+    } else {
+        sub_domain_lease::acquire(&sub_domain, client_id).await?
+    };
+
     // ensure this sub-domain isn't taken
     // check all instances
     match crate::network::instance_for_host(&sub_domain).await {
         Err(crate::network::Error::DoesNotServeHost) => {}
+        // a standby is meant for a sub-domain in use, and a standby serving it
+        // hands it back to whoever connects normally
+        Ok(_) if standby => {}
+        Ok((_, existing_client)) if Connections::is_standby(&existing_client) => {}
         Ok((_, existing_client)) => {
-            if &existing_client != client_id {
-                error!("invalid client hello: requested sub domain in use already!");
-                let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
-                return None;
+            // a claim lets its owner take the sub-domain over from its own clients, never
+            // from another account's, which we can only tell for clients on this instance
+            let own_client = || {
+                let account_id = account.map(|account| account.account_id);
+                account_id.is_some()
+                    && Connections::get(&existing_client).and_then(|client| client.account_id)
+                        == account_id
+            };
+            if &existing_client != client_id && !(claimed_by_you && own_client()) {
+                log::debug!("invalid client hello: requested sub domain in use already!");
+                return Err(TunnelError::SubDomainInUse);
             }
         }
         Err(e) => {
@@ -242,5 +712,5 @@ async fn sanitize_sub_domain_and_pre_validate(
         }
     }
 
-    Some((websocket, sub_domain))
+    Ok((sub_domain, lease))
 }
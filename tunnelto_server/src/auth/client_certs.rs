@@ -0,0 +1,219 @@
+//! Client certificates as credentials, for deployments that would rather issue each
+//! client a certificate than share auth keys. With CONTROL_TLS_CERT and CONTROL_TLS_KEY
+//! (PEM files) set, the control listener terminates TLS itself instead of relying on a
+//! proxy in front of it. CLIENT_CERTS_FILE then has it ask clients for a certificate, and
+//! maps the SHA-256 fingerprints of those it accepts to accounts, with optional
+//! entitlements like AUTH_KEYS_FILE:
+//!
+//! ```toml
+//! [[certs]]
+//! fingerprint = "9f:86:d0:81:88:4c:7d:65:9a:2f:ea:a0:c5:5a:d0:15:a3:bf:4f:1b:2b:0b:82:2c:d1:5d:6c:15:b0:f0:0a:08"
+//! account_id = "9a6e..."
+//! label = "ci runner"
+//! ```
+//!
+//! Certificates are pinned by fingerprint (`openssl x509 -noout -fingerprint -sha256`),
+//! so self-signed ones will do. Clients authenticate with `ClientType::Certificate`,
+//! keys and tokens keep working on the same listener. The file is re-read on SIGHUP.
+use super::auth_db::{AuthenticatedAccount, EntitlementClaims, Entitlements};
+use crate::CONFIG;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::RwLock;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+use tunnelto_lib::{ClientId, TunnelError};
+use uuid::Uuid;
+
+type Certs = HashMap<String, (Uuid, Entitlements)>;
+
+lazy_static::lazy_static! {
+    /// keyed by fingerprint, replaced as a whole on reload
+    static ref CERTS: RwLock<Certs> = RwLock::new(HashMap::new());
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("client certificates are not enabled on this server")]
+    Disabled,
+
+    #[error("no client certificate presented")]
+    NoCertificate,
+
+    #[error("unknown client certificate {0}")]
+    UnknownCertificate(String),
+}
+
+impl From<Error> for TunnelError {
+    fn from(e: Error) -> Self {
+        TunnelError::AuthFailed(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ControlTlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// fingerprints of the client certificates to accept, none are asked for if `None`
+    pub client_certs: Option<PathBuf>,
+}
+
+impl ControlTlsConfig {
+    /// Read the config from the env, `None` unless CONTROL_TLS_CERT is set
+    pub fn from_env() -> Option<Self> {
+        let cert = crate::config::env_var("CONTROL_TLS_CERT").ok()?;
+        let key = crate::config::env_var("CONTROL_TLS_KEY")
+            .expect("CONTROL_TLS_KEY is required along with CONTROL_TLS_CERT");
+        Some(ControlTlsConfig {
+            cert: cert.into(),
+            key: key.into(),
+            client_certs: crate::config::env_var("CLIENT_CERTS_FILE")
+                .ok()
+                .map(PathBuf::from),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CertsFile {
+    #[serde(default)]
+    certs: Vec<FileCert>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileCert {
+    fingerprint: String,
+    account_id: Uuid,
+    #[serde(flatten)]
+    entitlements: EntitlementClaims,
+}
+
+/// `9F:86:D0:...` as `9f86d0...`, the way fingerprints are compared
+fn normalize(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn load(path: &Path) -> Result<Certs, Box<dyn std::error::Error>> {
+    let file: CertsFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+    let mut certs = HashMap::new();
+    for cert in file.certs {
+        let fingerprint = normalize(&cert.fingerprint);
+        if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid sha-256 fingerprint: {}", cert.fingerprint).into());
+        }
+        certs.insert(fingerprint, (cert.account_id, cert.entitlements.into()));
+    }
+    Ok(certs)
+}
+
+/// Re-read the certs file on every SIGHUP, keeping the current certs if it's broken.
+/// Removed certs stop authenticating new tunnels, open ones stay up.
+#[cfg(unix)]
+fn reload_on_hangup(path: PathBuf) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match load(&path) {
+                Ok(reloaded) => {
+                    log::info!("reloaded {} client certs from {:?}", reloaded.len(), path);
+                    *CERTS.write().unwrap() = reloaded;
+                }
+                Err(e) => log::error!("failed to reload {:?}, keeping current certs: {}", path, e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_: PathBuf) -> std::io::Result<()> {
+    log::warn!("no SIGHUP on this platform, restart to reload CLIENT_CERTS_FILE");
+    Ok(())
+}
+
+/// Load the client certs and build the control listener's TLS acceptor
+pub fn init(config: &ControlTlsConfig) -> Result<SslAcceptor, Box<dyn std::error::Error>> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_private_key_file(&config.key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&config.cert)?;
+    builder.check_private_key()?;
+
+    if let Some(path) = config.client_certs.as_ref() {
+        let certs = load(path)?;
+        log::info!("loaded {} client certs from {:?}", certs.len(), path);
+        *CERTS.write().unwrap() = certs;
+        reload_on_hangup(path.clone())?;
+
+        // ask for a certificate without requiring one, and take any chain: it's the
+        // fingerprint that authenticates
+        builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+    }
+
+    Ok(builder.build())
+}
+
+/// The certificate a client presented on the control connection, kept as a request
+/// extension for the handshake
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    pub fingerprint: String,
+}
+
+/// Do the TLS handshake on a control connection
+pub async fn accept(
+    acceptor: &SslAcceptor,
+    socket: TcpStream,
+) -> Result<(SslStream<TcpStream>, Option<ClientCert>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut stream = SslStream::new(Ssl::new(acceptor.context())?, socket)?;
+    Pin::new(&mut stream).accept().await?;
+
+    let cert = match stream.ssl().peer_certificate() {
+        Some(cert) => Some(ClientCert {
+            fingerprint: hex::encode(cert.digest(MessageDigest::sha256())?),
+        }),
+        None => None,
+    };
+    Ok((stream, cert))
+}
+
+/// The account the client's certificate is mapped to, and a client id of its own
+pub fn authenticate(
+    cert: Option<&ClientCert>,
+) -> Result<(ClientId, AuthenticatedAccount), Error> {
+    let enabled = CONFIG
+        .control_tls
+        .as_ref()
+        .is_some_and(|tls| tls.client_certs.is_some());
+    if !enabled {
+        return Err(Error::Disabled);
+    }
+
+    let cert = cert.ok_or(Error::NoCertificate)?;
+    let (account_id, entitlements) = CERTS
+        .read()
+        .unwrap()
+        .get(&cert.fingerprint)
+        .cloned()
+        .ok_or_else(|| Error::UnknownCertificate(cert.fingerprint.clone()))?;
+
+    log::debug!("authenticated client certificate: {}", &cert.fingerprint);
+    Ok((
+        ClientId::for_subject(&cert.fingerprint),
+        AuthenticatedAccount {
+            account_id,
+            entitlements,
+            externally_authorized: false,
+        },
+    ))
+}
@@ -0,0 +1,253 @@
+use crate::auth_db::VerifiedClaim;
+use crate::{AUTH_DB_SERVICE, CONFIG};
+use std::collections::HashMap;
+use thiserror::Error;
+use trust_dns_resolver::TokioAsyncResolver;
+use tunnelto_lib::{ClaimRequest, ClaimResponse};
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+/// DNS label under the claimed domain that must hold the TXT challenge
+const CHALLENGE_LABEL: &str = "_tunnelto";
+const CHALLENGE_PREFIX: &str = "tunnelto-verify=";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid domain")]
+    InvalidDomain,
+
+    #[error("the sub-domain is reserved for another domain")]
+    Pinned,
+
+    #[error("resolver error: {0}")]
+    Resolver(Box<trust_dns_resolver::error::ResolveError>),
+
+    #[error("auth error: {0}")]
+    Auth(#[from] crate::auth_db::Error),
+}
+
+impl From<trust_dns_resolver::error::ResolveError> for Error {
+    fn from(e: trust_dns_resolver::error::ResolveError) -> Self {
+        Error::Resolver(Box::new(e))
+    }
+}
+
+/// How verified claims for the same sub-domain from different domains rank:
+///
+/// 1. a pin: the sub-domain goes to the owner of the domain an operator pinned it to,
+///    no other domain can claim it
+/// 2. the domain's public suffix, in the order listed: acme.com outranks acme.tk with
+///    the default list, and both outrank domains not directly under a listed suffix,
+///    like acme.example.tk
+///
+/// A claim replaces one it outranks, or one for the same domain, which must have
+/// changed hands for its new owner to pass the challenge. Otherwise the first verified
+/// claim keeps the sub-domain.
+#[derive(Debug, Clone)]
+pub struct ClaimPrecedence {
+    /// sub-domains and the domain they are pinned to (CLAIM_PINS, as
+    /// `sub-domain=domain` pairs)
+    pub pins: HashMap<String, String>,
+    /// public suffixes, highest ranking first (CLAIM_SUFFIXES, com,org,net)
+    pub suffixes: Vec<String>,
+}
+
+impl ClaimPrecedence {
+    pub fn from_env() -> Self {
+        let pins = crate::config::env_var("CLAIM_PINS")
+            .map(|pins| {
+                pins.split(',')
+                    .filter(|pin| !pin.trim().is_empty())
+                    .filter_map(|pin| match pin.split_once('=') {
+                        Some((sub_domain, domain)) => Some((
+                            sub_domain.trim().to_lowercase(),
+                            domain.trim().trim_end_matches('.').to_lowercase(),
+                        )),
+                        None => {
+                            log::error!("invalid CLAIM_PINS entry {}, ignoring it", pin);
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let suffixes = crate::config::env_var("CLAIM_SUFFIXES")
+            .unwrap_or_else(|_| "com,org,net".to_string())
+            .split(',')
+            .map(|suffix| suffix.trim().trim_matches('.').to_lowercase())
+            .filter(|suffix| !suffix.is_empty())
+            .collect();
+        ClaimPrecedence { pins, suffixes }
+    }
+
+    /// Where a claim for `domain` ranks, lower first
+    fn rank(&self, sub_domain: &str, domain: &str) -> usize {
+        if self.pins.get(sub_domain).map(String::as_str) == Some(domain) {
+            return 0;
+        }
+        let suffix = domain.split_once('.').map(|(_, suffix)| suffix);
+        self.suffixes
+            .iter()
+            .position(|listed| Some(listed.as_str()) == suffix)
+            .map_or(usize::MAX, |position| position + 1)
+    }
+
+    /// Whether a new claim for `domain` replaces the `existing` one
+    fn replaces(&self, sub_domain: &str, domain: &str, existing: &VerifiedClaim) -> bool {
+        existing.domain == domain
+            || self.rank(sub_domain, domain) < self.rank(sub_domain, &existing.domain)
+    }
+}
+
+/// The sub-domain a verified owner of `domain` may claim: its first label,
+/// i.e: acme.com => acme. Other domains sharing it (acme.xyz) rank by
+/// `ClaimPrecedence`.
+fn sub_domain_for(domain: &str) -> Result<String, Error> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let label = domain.split('.').next().unwrap_or_default();
+
+    if domain.split('.').count() < 2
+        || label.is_empty()
+        || label.chars().any(|c| !(c.is_alphanumeric() || c == '-'))
+    {
+        return Err(Error::InvalidDomain);
+    }
+
+    Ok(label.to_string())
+}
+
+/// The challenge is derived from our signature key, so nothing needs to be stored
+/// until the TXT record is verified
+fn challenge_value(account_id: &Uuid, domain: &str) -> String {
+    let sig = CONFIG
+        .master_sig_key
+        .sign(format!("{}:{}", account_id, domain).as_bytes());
+    format!("{}{}", CHALLENGE_PREFIX, sig.0)
+}
+
+async fn has_txt_record(name: &str, expected: &str) -> Result<bool, Error> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+    let records = match resolver.txt_lookup(name.to_string()).await {
+        Ok(records) => records,
+        Err(e) => {
+            log::debug!("no txt records for {}: {:?}", name, e);
+            return Ok(false);
+        }
+    };
+
+    Ok(records.iter().any(|txt| {
+        let value = txt
+            .txt_data()
+            .iter()
+            .map(|d| String::from_utf8_lossy(d).to_string())
+            .collect::<String>();
+        value == expected
+    }))
+}
+
+async fn claim(request: ClaimRequest) -> Result<ClaimResponse, Error> {
+    let domain = request.domain.trim().trim_end_matches('.').to_lowercase();
+    let sub_domain = sub_domain_for(&domain)?;
+    let precedence = &CONFIG.claim_precedence;
+    if matches!(precedence.pins.get(&sub_domain), Some(pinned) if pinned != &domain) {
+        return Err(Error::Pinned);
+    }
+    let account_id = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?
+        .account_id;
+
+    let txt_name = format!("{}.{}", CHALLENGE_LABEL, domain);
+    let txt_value = challenge_value(&account_id, &domain);
+
+    if !has_txt_record(&txt_name, &txt_value).await? {
+        return Ok(ClaimResponse::Pending {
+            txt_name,
+            txt_value,
+        });
+    }
+
+    let replacing = AUTH_DB_SERVICE
+        .get_verified_claim(&sub_domain)
+        .await?
+        .filter(|existing| precedence.replaces(&sub_domain, &domain, existing))
+        .map(|existing| existing.domain);
+    AUTH_DB_SERVICE
+        .put_verified_claim(
+            &sub_domain,
+            &VerifiedClaim { account_id, domain },
+            replacing.as_deref(),
+        )
+        .await?;
+
+    log::info!("verified claim for sub-domain: {}", &sub_domain);
+    Ok(ClaimResponse::Verified { sub_domain })
+}
+
+/// Handle a claim request from the control server
+pub async fn handle_claim(
+    request: ClaimRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (response, status) = match claim(request).await {
+        Ok(response @ ClaimResponse::Verified { .. }) => (response, StatusCode::OK),
+        Ok(response) => (response, StatusCode::ACCEPTED),
+        Err(e @ Error::Auth(crate::auth_db::Error::ClaimedByOther)) | Err(e @ Error::Pinned) => (
+            ClaimResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::CONFLICT,
+        ),
+        Err(e @ Error::Auth(_)) => (
+            ClaimResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+        Err(e) => (
+            ClaimResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::BAD_REQUEST,
+        ),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(domain: &str) -> VerifiedClaim {
+        VerifiedClaim {
+            account_id: Uuid::new_v4(),
+            domain: domain.to_string(),
+        }
+    }
+
+    #[test]
+    fn competing_claims() {
+        let precedence = ClaimPrecedence {
+            pins: vec![("shop".to_string(), "shop.io".to_string())]
+                .into_iter()
+                .collect(),
+            suffixes: vec!["com".to_string(), "co.uk".to_string()],
+        };
+
+        // by suffix, unlisted ones last
+        assert!(precedence.replaces("acme", "acme.com", &claim("acme.tk")));
+        assert!(precedence.replaces("acme", "acme.co.uk", &claim("acme.example.com")));
+        assert!(!precedence.replaces("acme", "acme.tk", &claim("acme.com")));
+        assert!(!precedence.replaces("acme", "acme.co.uk", &claim("acme.com")));
+        // first come among equals, unless the domain changed hands
+        assert!(!precedence.replaces("acme", "acme.xyz", &claim("acme.tk")));
+        assert!(precedence.replaces("acme", "acme.com", &claim("acme.com")));
+
+        // pins outrank everything
+        assert!(precedence.replaces("shop", "shop.io", &claim("shop.com")));
+        assert!(!precedence.replaces("shop", "shop.com", &claim("shop.io")));
+    }
+}
@@ -0,0 +1,121 @@
+use crate::auth_db::Grant;
+use crate::connected_clients::Connections;
+use crate::AUTH_DB_SERVICE;
+use std::str::FromStr;
+use thiserror::Error;
+use tunnelto_lib::{GrantRequest, GrantResponse};
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+/// Grants are for handoffs, not a way to share a sub-domain indefinitely
+const MAX_GRANT_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid account id: {0}")]
+    InvalidGrantee(String),
+
+    #[error("grants must last between 1 second and 30 days")]
+    InvalidDuration,
+
+    #[error("you don't own this sub-domain")]
+    NotOwner,
+
+    #[error("auth error: {0}")]
+    Auth(#[from] crate::auth_db::Error),
+}
+
+async fn grant(request: GrantRequest) -> Result<GrantResponse, Error> {
+    let sub_domain = request.sub_domain.trim().to_lowercase();
+    let grantee = Uuid::from_str(request.grantee.trim())
+        .map_err(|_| Error::InvalidGrantee(request.grantee.clone()))?;
+
+    let account_id = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?
+        .account_id;
+
+    if AUTH_DB_SERVICE.get_owner(&sub_domain).await? != Some(account_id) {
+        return Err(Error::NotOwner);
+    }
+
+    let duration_secs = match request.duration_secs {
+        Some(secs) => secs,
+        None => {
+            AUTH_DB_SERVICE.delete_grant(&sub_domain, &grantee).await?;
+            disconnect_grantee(&sub_domain, &grantee);
+
+            log::info!("revoked grant on {} for {}", &sub_domain, &grantee);
+            return Ok(GrantResponse::Revoked {
+                sub_domain,
+                grantee: grantee.to_string(),
+            });
+        }
+    };
+
+    if duration_secs == 0 || duration_secs > MAX_GRANT_SECS {
+        return Err(Error::InvalidDuration);
+    }
+
+    let grant = Grant {
+        account_id,
+        expires_at: chrono::Utc::now() + chrono::Duration::seconds(duration_secs as i64),
+    };
+    AUTH_DB_SERVICE
+        .put_grant(&sub_domain, &grantee, &grant)
+        .await?;
+
+    log::info!(
+        "granted {} to {} until {}",
+        &sub_domain,
+        &grantee,
+        grant.expires_at
+    );
+    Ok(GrantResponse::Granted {
+        sub_domain,
+        grantee: grantee.to_string(),
+        expires_at: grant.expires_at.to_rfc3339(),
+    })
+}
+
+/// Close the grantee's tunnel on the sub-domain, if this instance serves it
+fn disconnect_grantee(sub_domain: &str, grantee: &Uuid) {
+    if let Some(client) = Connections::find_by_host(&sub_domain.to_string()) {
+        if client.account_id.as_ref() == Some(grantee) {
+            log::info!("closing tunnel {} after its grant was revoked", &client.id);
+            Connections::remove(&client);
+        }
+    }
+}
+
+/// Handle a grant request from the control server
+pub async fn handle_grant(
+    request: GrantRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (response, status) = match grant(request).await {
+        Ok(response) => (response, StatusCode::OK),
+        Err(e @ Error::Auth(_)) => (
+            GrantResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+        Err(e @ Error::NotOwner) => (
+            GrantResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::FORBIDDEN,
+        ),
+        Err(e) => (
+            GrantResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::BAD_REQUEST,
+        ),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
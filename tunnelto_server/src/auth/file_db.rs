@@ -0,0 +1,334 @@
+//! An auth backend reading its keys from a flat file (`AUTH_BACKEND=file`), for tiny
+//! deployments that manage keys by hand with no database at all. The file is
+//! AUTH_KEYS_FILE, re-read when the server gets a SIGHUP. Keys are listed as hashed by
+//! `key_id`, either as TOML with optional entitlements:
+//!
+//! ```toml
+//! [[keys]]
+//! auth_key_hash = "F8pSqlbIOqCpfyeieI95T2ycqKjPnveR_9SwMQ4PqHk"
+//! account_id = "9a6e..."
+//! max_tunnels = 5
+//! hello_key = "..."
+//! ```
+//!
+//! where the optional `hello_key` (`hello_signature::public_key`) lets the key sign its
+//! hellos, or, when the file ends in `.csv`, as `auth_key_hash,account_id` lines with the
+//! default entitlements. Everything else (reservations, claims, grants, guest keys, rotated keys,
+//! history) is kept in memory like the `memory` backend, and lost on restart. So is
+//! revoking a listed key: remove it from the file to keep it out.
+use super::auth_db::{
+    key_id, AuthenticatedAccount, EntitlementClaims, Entitlements, Error, Grant, HistoryRecord,
+    StoredKey, VerifiedClaim,
+};
+use super::auth_service::AuthService;
+use super::memory_db::MemoryAuthService;
+use crate::metering::UsageRecord;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+type Keys = HashMap<String, (Uuid, Entitlements, Option<String>)>;
+
+#[derive(Debug, Deserialize)]
+struct KeysFile {
+    #[serde(default)]
+    keys: Vec<FileKey>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileKey {
+    auth_key_hash: String,
+    account_id: Uuid,
+    #[serde(default)]
+    hello_key: Option<String>,
+    #[serde(flatten)]
+    entitlements: EntitlementClaims,
+}
+
+pub struct FileAuthService {
+    path: PathBuf,
+    /// keyed by `key_id`, replaced as a whole on reload
+    keys: Arc<RwLock<Keys>>,
+    /// the rest of the store, and keys put at runtime (guest keys)
+    memory: MemoryAuthService,
+}
+
+impl FileAuthService {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = load(path)?;
+        log::info!("loaded {} keys from {:?}", keys.len(), path);
+
+        Ok(FileAuthService {
+            path: path.to_path_buf(),
+            keys: Arc::new(RwLock::new(keys)),
+            memory: MemoryAuthService::default(),
+        })
+    }
+}
+
+fn load(path: &Path) -> Result<Keys, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    let is_csv = path
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("csv"))
+        .unwrap_or(false);
+
+    let mut keys = HashMap::new();
+    if is_csv {
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("auth_key_hash") {
+                continue;
+            }
+
+            let mut columns = line.split(',').map(str::trim);
+            let (hash, account_id) = match (columns.next(), columns.next(), columns.next()) {
+                (Some(hash), Some(account_id), None) if !hash.is_empty() => (hash, account_id),
+                _ => {
+                    return Err(
+                        format!("line {}: expected `auth_key_hash,account_id`", i + 1).into(),
+                    )
+                }
+            };
+            let account_id = Uuid::parse_str(account_id)
+                .map_err(|e| format!("line {}: invalid account id: {}", i + 1, e))?;
+            keys.insert(
+                hash.to_string(),
+                (account_id, Entitlements::default(), None),
+            );
+        }
+    } else {
+        let file: KeysFile = toml::from_str(&contents)?;
+        for key in file.keys {
+            keys.insert(
+                key.auth_key_hash,
+                (key.account_id, key.entitlements.into(), key.hello_key),
+            );
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Re-read the keys file on every SIGHUP, keeping the current keys if it's broken.
+/// Removed keys stop authenticating new tunnels, open ones stay up.
+#[cfg(unix)]
+fn reload_on_hangup(path: PathBuf, keys: Arc<RwLock<Keys>>) -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).map_err(|e| Error::Backend(e.to_string()))?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match load(&path) {
+                Ok(reloaded) => {
+                    log::info!("reloaded {} keys from {:?}", reloaded.len(), path);
+                    *keys.write().unwrap() = reloaded;
+                }
+                Err(e) => log::error!("failed to reload {:?}, keeping current keys: {}", path, e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_: PathBuf, _: Arc<RwLock<Keys>>) -> Result<(), Error> {
+    log::warn!("no SIGHUP on this platform, restart to reload AUTH_KEYS_FILE");
+    Ok(())
+}
+
+impl AuthService for FileAuthService {
+    fn init<'a>(&'a self) -> BoxFuture<'a, Result<(), Error>> {
+        let reload = reload_on_hangup(self.path.clone(), self.keys.clone());
+        futures::future::ready(reload).boxed()
+    }
+
+    fn get_account_id_for_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+    ) -> BoxFuture<'a, Result<AuthenticatedAccount, Error>> {
+        let listed = self.keys.read().unwrap().get(&key_id(auth_key)).map(
+            |(account_id, entitlements, _)| AuthenticatedAccount {
+                account_id: *account_id,
+                entitlements: entitlements.clone(),
+                externally_authorized: false,
+            },
+        );
+
+        // keys put at runtime override the file, so a listed key can be rotated out
+        async move {
+            match self.memory.get_account_id_for_auth_key(auth_key).await {
+                Err(Error::AccountNotFound) => listed.ok_or(Error::AccountNotFound),
+                result => result,
+            }
+        }
+        .boxed()
+    }
+
+    fn get_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<StoredKey, Error>> {
+        let listed =
+            self.keys
+                .read()
+                .unwrap()
+                .get(key_hash)
+                .map(|(account_id, entitlements, hello_key)| StoredKey {
+                    key_hash: key_hash.to_string(),
+                    account_id: *account_id,
+                    entitlements: entitlements.clone(),
+                    hello_key: hello_key.clone(),
+                });
+
+        async move {
+            match self.memory.get_key(key_hash).await {
+                Err(Error::AccountNotFound) => listed.ok_or(Error::AccountNotFound),
+                result => result,
+            }
+        }
+        .boxed()
+    }
+
+    fn put_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+        account_id: &'a Uuid,
+        entitlements: &'a Entitlements,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.memory.put_auth_key(auth_key, account_id, entitlements)
+    }
+
+    fn get_keys<'a>(&'a self, account_id: &'a Uuid) -> BoxFuture<'a, Result<Vec<StoredKey>, Error>> {
+        let listed = self
+            .keys
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, (owner, _, _))| owner == account_id)
+            .map(
+                |(key_hash, (account_id, entitlements, hello_key))| StoredKey {
+                    key_hash: key_hash.clone(),
+                    account_id: *account_id,
+                    entitlements: entitlements.clone(),
+                    hello_key: hello_key.clone(),
+                },
+            )
+            .collect::<Vec<_>>();
+
+        async move {
+            let mut keys = self.memory.get_keys(account_id).await?;
+            for key in listed {
+                if !keys.iter().any(|put| put.key_hash == key.key_hash) {
+                    keys.push(key);
+                }
+            }
+            Ok(keys)
+        }
+        .boxed()
+    }
+
+    fn revoke_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        let listed = self.keys.read().unwrap().get(key_hash).cloned();
+
+        async move {
+            match self.memory.revoke_key(key_hash).await {
+                Err(Error::AccountNotFound) => {
+                    let (account_id, entitlements, hello_key) =
+                        listed.ok_or(Error::AccountNotFound)?;
+                    let revoked = Entitlements {
+                        revoked: true,
+                        ..entitlements
+                    };
+                    self.memory
+                        .put_key_hash(key_hash, account_id, revoked, hello_key);
+                    Ok(())
+                }
+                result => result,
+            }
+        }
+        .boxed()
+    }
+
+    fn get_account_id_for_subdomain<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Uuid>, Error>> {
+        self.memory.get_account_id_for_subdomain(subdomain)
+    }
+
+    fn put_reservation<'a>(
+        &'a self,
+        subdomain: &'a str,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.memory.put_reservation(subdomain, account_id)
+    }
+
+    fn get_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<VerifiedClaim>, Error>> {
+        self.memory.get_verified_claim(subdomain)
+    }
+
+    fn put_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        claim: &'a VerifiedClaim,
+        replacing: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.memory.put_verified_claim(subdomain, claim, replacing)
+    }
+
+    fn get_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Option<Grant>, Error>> {
+        self.memory.get_grant(subdomain, grantee)
+    }
+
+    fn put_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+        grant: &'a Grant,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.memory.put_grant(subdomain, grantee, grant)
+    }
+
+    fn delete_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.memory.delete_grant(subdomain, grantee)
+    }
+
+    fn put_usage_record<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), Error>> {
+        self.memory.put_usage_record(record)
+    }
+
+    fn put_history_record<'a>(
+        &'a self,
+        record: &'a HistoryRecord,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.memory.put_history_record(record)
+    }
+
+    fn get_history<'a>(
+        &'a self,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        self.memory.get_history(account_id)
+    }
+
+    fn get_sub_domain_history<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        self.memory.get_sub_domain_history(subdomain)
+    }
+}
@@ -0,0 +1,137 @@
+use crate::auth::account_keys;
+use crate::auth_db::Entitlements;
+use crate::AUTH_DB_SERVICE;
+use thiserror::Error;
+use tunnelto_lib::{GuestKeyRequest, GuestKeyResponse, SecretKey};
+use warp::http::StatusCode;
+
+/// Guest keys are for workshops and contractors, not a second permanent key
+const MAX_GUEST_KEY_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("guest keys must last between 1 second and 30 days")]
+    InvalidDuration,
+
+    #[error("invalid sub-domain prefix: only letters, digits and hyphens are allowed")]
+    InvalidPrefix,
+
+    #[error("invalid sub-domain `{0}`: only letters, digits and hyphens are allowed")]
+    InvalidSubDomain(String),
+
+    #[error("{}", account_keys::INVALID_LABEL)]
+    InvalidLabel,
+
+    #[error("your key can't open `{0}`, so neither can its guests")]
+    OutOfScope(String),
+
+    #[error("auth error: {0}")]
+    Auth(#[from] crate::auth_db::Error),
+}
+
+async fn create_guest_key(request: GuestKeyRequest) -> Result<GuestKeyResponse, Error> {
+    if request.duration_secs == 0 || request.duration_secs > MAX_GUEST_KEY_SECS {
+        return Err(Error::InvalidDuration);
+    }
+
+    let sub_domain_prefix = match request.sub_domain_prefix {
+        Some(prefix) => {
+            let prefix = prefix.trim().to_lowercase();
+            if prefix.is_empty() || prefix.chars().any(|c| !(c.is_alphanumeric() || c == '-')) {
+                return Err(Error::InvalidPrefix);
+            }
+            Some(prefix)
+        }
+        None => None,
+    };
+
+    let label = match request.label {
+        Some(label) => Some(account_keys::parse_label(&label).ok_or(Error::InvalidLabel)?),
+        None => None,
+    };
+
+    let mut sub_domains = vec![];
+    for sub_domain in request.sub_domains {
+        let sub_domain = sub_domain.trim().to_lowercase();
+        if sub_domain.is_empty() || sub_domain.chars().any(|c| !(c.is_alphanumeric() || c == '-')) {
+            return Err(Error::InvalidSubDomain(sub_domain));
+        }
+        if !sub_domains.contains(&sub_domain) {
+            sub_domains.push(sub_domain);
+        }
+    }
+
+    let account = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?;
+
+    // a scoped key hands out no more than it has
+    if let Some(sub_domain) = sub_domains
+        .iter()
+        .find(|sub_domain| !account.entitlements.allows_sub_domain(sub_domain))
+    {
+        return Err(Error::OutOfScope(sub_domain.clone()));
+    }
+    let sub_domains = if sub_domains.is_empty() {
+        account.entitlements.sub_domains.clone()
+    } else {
+        sub_domains
+    };
+
+    // the guest gets the account's plan, for a while and maybe fewer sub-domains
+    let expires_at =
+        chrono::Utc::now() + chrono::Duration::seconds(request.duration_secs as i64);
+    let entitlements = Entitlements {
+        sub_domain_prefix: sub_domain_prefix.clone(),
+        sub_domains: sub_domains.clone(),
+        expires_at: Some(expires_at),
+        label: label.clone(),
+        ..account.entitlements
+    };
+
+    let key = SecretKey::generate();
+    AUTH_DB_SERVICE
+        .put_auth_key(&key.0, &account.account_id, &entitlements)
+        .await?;
+
+    log::info!(
+        "created guest key {:?} for {} until {} with prefix {:?} and sub-domains {:?}",
+        &label,
+        &account.account_id,
+        expires_at,
+        &sub_domain_prefix,
+        &sub_domains
+    );
+    Ok(GuestKeyResponse::Created {
+        key,
+        expires_at: expires_at.to_rfc3339(),
+        sub_domain_prefix,
+        sub_domains,
+    })
+}
+
+/// Handle a guest key request from the control server
+pub async fn handle_guest_key(
+    request: GuestKeyRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (response, status) = match create_guest_key(request).await {
+        Ok(response) => (response, StatusCode::OK),
+        Err(e @ Error::Auth(_)) => (
+            GuestKeyResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+        Err(e) => (
+            GuestKeyResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::BAD_REQUEST,
+        ),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
@@ -0,0 +1,227 @@
+use crate::auth_db::{AuthenticatedAccount, EntitlementClaims};
+use crate::CONFIG;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::Digest;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tunnelto_lib::TunnelError;
+use uuid::Uuid;
+
+/// How long we trust a fetched key set before fetching it again
+const JWKS_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+/// Tokens signed with a key we don't know fetch the key set at most this often, so
+/// made-up `kid`s can't have us hammer the identity provider
+const JWKS_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Jwks {
+    /// the key set, and when it was fetched
+    set: Option<(Instant, JwkSet)>,
+    /// when we last tried to fetch it, successfully or not
+    last_fetch: Option<Instant>,
+}
+
+lazy_static::lazy_static! {
+    static ref JWKS: RwLock<Jwks> = RwLock::new(Jwks::default());
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("jwt authentication is not enabled on this server")]
+    Disabled,
+
+    #[error("failed to fetch jwks: {0}")]
+    Jwks(#[from] reqwest::Error),
+
+    #[error("invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+
+    #[error("token signed with an unknown key")]
+    UnknownKey,
+
+    #[error("token has no kid to pick one of the keys it may be signed with")]
+    MissingKeyId,
+
+    #[error("token signed with an unsupported algorithm: {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+
+    #[error("token does not allow the sub-domain {0}")]
+    SubDomainNotAllowed(String),
+}
+
+impl From<Error> for TunnelError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Jwks(_) => TunnelError::Internal(e.to_string()),
+            _ => TunnelError::AuthFailed(e.to_string()),
+        }
+    }
+}
+
+/// The claims we read from a client token
+#[derive(Debug, Deserialize)]
+struct Claims {
+    iss: Option<String>,
+    sub: String,
+    /// the account to bill, derived from `iss` and `sub` if missing
+    account_id: Option<Uuid>,
+    /// sub-domains the token may open tunnels on, any if missing
+    sub_domains: Option<Vec<String>>,
+    /// the one sub-domain the token may open tunnels on, as identity providers tend to
+    /// emit custom claims as strings
+    sub_domain: Option<String>,
+    #[serde(default)]
+    entitlements: EntitlementClaims,
+}
+
+impl Claims {
+    /// The sub-domains the token is limited to, if it is
+    fn allowed_sub_domains(&self) -> Option<Vec<&String>> {
+        if self.sub_domains.is_none() && self.sub_domain.is_none() {
+            return None;
+        }
+        Some(self.sub_domains.iter().flatten().chain(&self.sub_domain).collect())
+    }
+
+    fn account_id(&self) -> Uuid {
+        self.account_id.unwrap_or_else(|| {
+            let subject = format!("{}:{}", self.iss.as_deref().unwrap_or_default(), self.sub);
+            let hash = sha2::Sha256::digest(subject.as_bytes());
+            Uuid::from_slice(&hash[..16]).unwrap_or_default()
+        })
+    }
+}
+
+async fn fetch_jwks(url: &str) -> Result<JwkSet, Error> {
+    let jwks = reqwest::Client::new()
+        .get(url)
+        .timeout(JWKS_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(jwks)
+}
+
+/// The key `kid` names in the set, tokens without one only go with a set of one key
+fn find_key(jwks: &JwkSet, kid: Option<&str>) -> Result<Option<Jwk>, Error> {
+    match kid {
+        Some(kid) => Ok(jwks.find(kid).cloned()),
+        None if jwks.keys.len() <= 1 => Ok(jwks.keys.first().cloned()),
+        None => Err(Error::MissingKeyId),
+    }
+}
+
+/// The decoding key for `kid`, re-fetching the key set if it is stale or doesn't have it,
+/// at most once every `JWKS_REFETCH_INTERVAL`
+async fn decoding_key(url: &str, kid: Option<&str>) -> Result<DecodingKey, Error> {
+    if let Some((fetched, jwks)) = JWKS.read().await.set.as_ref() {
+        if fetched.elapsed() < JWKS_MAX_AGE {
+            if let Some(jwk) = find_key(jwks, kid)? {
+                return Ok(DecodingKey::from_jwk(&jwk)?);
+            }
+        }
+    }
+
+    // one fetch at a time, the ones waiting use what it fetched
+    let mut jwks = JWKS.write().await;
+    let fetched_lately = jwks
+        .last_fetch
+        .is_some_and(|at| at.elapsed() < JWKS_REFETCH_INTERVAL);
+    if !fetched_lately {
+        jwks.last_fetch = Some(Instant::now());
+        let set = fetch_jwks(url).await?;
+        jwks.set = Some((Instant::now(), set));
+    }
+
+    let jwk = match jwks.set.as_ref() {
+        Some((fetched, set)) if fetched.elapsed() < JWKS_MAX_AGE => find_key(set, kid)?,
+        _ => None,
+    };
+    Ok(DecodingKey::from_jwk(&jwk.ok_or(Error::UnknownKey)?)?)
+}
+
+/// Validate a client JWT and derive its account, it must allow `sub_domain`
+pub async fn authenticate(token: &str, sub_domain: &str) -> Result<AuthenticatedAccount, Error> {
+    let jwks_url = CONFIG.jwt_jwks_url.as_ref().ok_or(Error::Disabled)?;
+
+    let header = jsonwebtoken::decode_header(token)?;
+    if !matches!(header.alg, Algorithm::RS256 | Algorithm::EdDSA) {
+        return Err(Error::UnsupportedAlgorithm(header.alg));
+    }
+
+    let key = decoding_key(jwks_url, header.kid.as_deref()).await?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.leeway = CONFIG.clock_skew.num_seconds().max(0) as u64;
+    if let Some(issuer) = CONFIG.jwt_issuer.as_ref() {
+        validation.set_issuer(&[issuer]);
+    }
+    if let Some(audience) = CONFIG.jwt_audience.as_ref() {
+        validation.set_audience(&[audience]);
+    }
+
+    let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)?.claims;
+
+    if let Some(allowed) = claims.allowed_sub_domains() {
+        if !allowed.iter().any(|s| s.eq_ignore_ascii_case(sub_domain)) {
+            return Err(Error::SubDomainNotAllowed(sub_domain.to_string()));
+        }
+    }
+
+    log::debug!("authenticated jwt for subject: {}", &claims.sub);
+
+    Ok(AuthenticatedAccount {
+        account_id: claims.account_id(),
+        externally_authorized: true,
+        entitlements: claims.entitlements.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jwks(kids: &[&str]) -> JwkSet {
+        let keys: Vec<_> = kids
+            .iter()
+            .map(|kid| {
+                serde_json::json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo",
+                    "kid": kid,
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({ "keys": keys })).unwrap()
+    }
+
+    fn kid(jwk: Option<Jwk>) -> Option<String> {
+        jwk.and_then(|jwk| jwk.common.key_id)
+    }
+
+    #[test]
+    fn tokens_pick_their_key_by_kid() {
+        let set = jwks(&["a", "b"]);
+        assert_eq!(kid(find_key(&set, Some("b")).unwrap()), Some("b".into()));
+        assert_eq!(kid(find_key(&set, Some("c")).unwrap()), None);
+    }
+
+    #[test]
+    fn kid_required_with_several_keys() {
+        assert!(matches!(
+            find_key(&jwks(&["a", "b"]), None),
+            Err(Error::MissingKeyId)
+        ));
+        assert_eq!(
+            kid(find_key(&jwks(&["a"]), None).unwrap()),
+            Some("a".into())
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! `tunnelto_server keys ...`: manage keys in the configured auth backend from a shell,
+//! the same way the admin endpoints do, instead of writing items to it by hand.
+//!
+//! - `keys generate [--account-id ID] [--label L] [--max-tunnels N] [--sub-domain S]...
+//!   [--sub-domain-prefix P] [--max-bandwidth BYTES] [--no-custom-domains] [--tcp-tunnels]
+//!   [--priority high|normal|low] [--catch-all] [--tier free|pro|team]`
+//!   creates a key, for a new account without `--account-id`
+//! - `keys list ACCOUNT_ID` lists the account's keys by key id
+//! - `keys revoke KEY_ID` or `keys revoke --key KEY` revokes a key
+//! - `keys hash KEY` prints the key id the backends store a key under
+//!
+//! Results are printed as the admin endpoints' json.
+use super::admin_keys;
+use super::auth_db;
+use super::auth_service::AuthBackend;
+use crate::{AUTH_DB_SERVICE, CONFIG};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+const USAGE: &str = "usage: tunnelto_server keys <generate|list|revoke|hash> [options]";
+
+/// Run a `keys` command, the arguments following `keys`
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let (command, args) = args.split_first().ok_or(USAGE)?;
+
+    // hashing needs no backend
+    if command == "hash" {
+        let key = single(args, "KEY")?;
+        println!("{}", auth_db::key_id(&key));
+        return Ok(());
+    }
+
+    match &CONFIG.auth_backend {
+        AuthBackend::Memory { .. } | AuthBackend::File { .. } | AuthBackend::Static { .. } => {
+            return Err(
+                "this auth backend keeps keys in the server's memory, use the admin endpoints"
+                    .to_string(),
+            )
+        }
+        _ => {}
+    }
+    AUTH_DB_SERVICE
+        .init()
+        .await
+        .map_err(|e| format!("failed to init auth backend: {}", e))?;
+
+    let output = match command.as_str() {
+        "generate" => {
+            let request = serde_json::from_value(generate_request(args)?)
+                .map_err(|e| format!("invalid options: {}", e))?;
+            let created = admin_keys::create_key(request)
+                .await
+                .map_err(|e| e.to_string())?;
+            serde_json::to_value(created)
+        }
+        "list" => {
+            let account_id = single(args, "ACCOUNT_ID")?;
+            let account_id = Uuid::parse_str(&account_id)
+                .map_err(|_| format!("invalid account id `{}`", account_id))?;
+            let keys = AUTH_DB_SERVICE
+                .get_keys(&account_id)
+                .await
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(admin_keys::KeyInfo::from)
+                .collect::<Vec<_>>();
+            serde_json::to_value(keys)
+        }
+        "revoke" => {
+            let key_hash = match args {
+                [flag, key] if flag == "--key" => auth_db::key_id(key),
+                _ => single(args, "KEY_ID")?,
+            };
+            let revoked = admin_keys::revoke_key(key_hash)
+                .await
+                .map_err(|e| e.to_string())?;
+            serde_json::to_value(revoked)
+        }
+        other => return Err(format!("unknown keys command `{}`\n{}", other, USAGE)),
+    };
+
+    let output = output.map_err(|e| e.to_string())?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&output).unwrap_or_default()
+    );
+    Ok(())
+}
+
+/// The only argument, named `name` in errors
+fn single(args: &[String], name: &str) -> Result<String, String> {
+    match args {
+        [value] if !value.starts_with("--") => Ok(value.clone()),
+        _ => Err(format!("expected {}", name)),
+    }
+}
+
+/// The options of `keys generate` as the body of `POST /admin/keys`
+fn generate_request(args: &[String]) -> Result<Value, String> {
+    let mut request = Map::new();
+    let mut sub_domains = vec![];
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} expects a value", flag))
+        };
+        let number = |value: String| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} expects a number, got `{}`", flag, value))
+        };
+
+        match flag.as_str() {
+            "--account-id" => request.insert("account_id".into(), json!(value()?)),
+            "--label" => request.insert("label".into(), json!(value()?)),
+            "--max-tunnels" => request.insert("max_tunnels".into(), json!(number(value()?)?)),
+            "--max-bandwidth" => request.insert("max_bandwidth".into(), json!(number(value()?)?)),
+            "--sub-domain-prefix" => request.insert("sub_domain_prefix".into(), json!(value()?)),
+            "--sub-domain" => {
+                sub_domains.push(value()?);
+                None
+            }
+            "--no-custom-domains" => request.insert("custom_domains".into(), json!(false)),
+            "--tcp-tunnels" => request.insert("tcp_tunnels".into(), json!(true)),
+            "--priority" => request.insert("priority".into(), json!(value()?)),
+            "--catch-all" => request.insert("catch_all".into(), json!(true)),
+            "--tier" => request.insert("tier".into(), json!(value()?)),
+            other => return Err(format!("unknown option `{}`", other)),
+        };
+    }
+
+    request.insert("sub_domains".into(), json!(sub_domains));
+    Ok(Value::Object(request))
+}
@@ -0,0 +1,334 @@
+//! An auth backend kept in memory (`AUTH_BACKEND=memory`), for self-hosted servers
+//! without DynamoDB and for local development. Nothing survives a restart: keys and
+//! sub-domain reservations come from the optional seed file (AUTH_SEED_FILE), i.e.
+//!
+//! ```json
+//! {
+//!   "keys": [
+//!     { "key": "my-secret-key", "account_id": "9a6e...", "max_tunnels": 5, "tcp_tunnels": true }
+//!   ],
+//!   "domains": { "my-app": "9a6e..." }
+//! }
+//! ```
+//!
+//! where each key takes the same entitlements as the auth webhook. Usage records are
+//! only logged, sub-domain history is kept until restart.
+//!
+//! The `static` backend is this store holding only the keys listed in ALLOWED_AUTH_KEYS,
+//! each its own account with the default entitlements.
+use super::auth_db::{
+    key_id, AuthenticatedAccount, EntitlementClaims, Entitlements, Error, Grant, HistoryRecord,
+    StoredKey, VerifiedClaim, HISTORY_RETENTION_DAYS,
+};
+use super::auth_service::AuthService;
+use crate::metering::UsageRecord;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::path::Path;
+use tunnelto_lib::hello_signature;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+struct Seed {
+    #[serde(default)]
+    keys: Vec<SeedKey>,
+    /// sub-domain => account id
+    #[serde(default)]
+    domains: HashMap<String, Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SeedKey {
+    key: String,
+    account_id: Uuid,
+    #[serde(flatten)]
+    entitlements: EntitlementClaims,
+}
+
+#[derive(Default)]
+pub struct MemoryAuthService {
+    /// keyed by `key_id`, like the DynamoDB table, so keys aren't held in the clear,
+    /// with the `hello_signature::public_key` of the key
+    keys: DashMap<String, (Uuid, Entitlements, Option<String>)>,
+    domains: DashMap<String, Uuid>,
+    claims: DashMap<String, VerifiedClaim>,
+    /// keyed by (sub-domain, grantee)
+    grants: DashMap<(String, Uuid), Grant>,
+    /// keyed by (account id, sub-domain)
+    history: DashMap<(Uuid, String), HistoryRecord>,
+}
+
+impl MemoryAuthService {
+    pub fn from_seed_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let seed: Seed = serde_json::from_slice(&std::fs::read(path)?)?;
+
+        let service = MemoryAuthService::default();
+        for key in seed.keys {
+            service.keys.insert(
+                key_id(&key.key),
+                (
+                    key.account_id,
+                    key.entitlements.into(),
+                    Some(hello_signature::public_key(&key.key)),
+                ),
+            );
+        }
+        for (subdomain, account_id) in seed.domains {
+            service.domains.insert(subdomain, account_id);
+        }
+
+        log::info!(
+            "seeded memory auth backend with {} keys and {} sub-domains",
+            service.keys.len(),
+            service.domains.len()
+        );
+        Ok(service)
+    }
+
+    /// A store holding only the given keys. The account of a key is derived from it,
+    /// so it stays the same across restarts.
+    pub fn from_keys(keys: &[String]) -> Self {
+        let service = MemoryAuthService::default();
+        for key in keys {
+            let hash = sha2::Sha256::digest(key.as_bytes());
+            let account_id = Uuid::from_slice(&hash[..16]).expect("16 bytes make a uuid");
+            service.keys.insert(
+                key_id(key),
+                (
+                    account_id,
+                    Entitlements::default(),
+                    Some(hello_signature::public_key(key)),
+                ),
+            );
+        }
+        service
+    }
+
+    /// Store a key by its `key_id`, for stores layered over this one
+    pub fn put_key_hash(
+        &self,
+        key_hash: &str,
+        account_id: Uuid,
+        entitlements: Entitlements,
+        hello_key: Option<String>,
+    ) {
+        self.keys
+            .insert(key_hash.to_string(), (account_id, entitlements, hello_key));
+    }
+}
+
+impl AuthService for MemoryAuthService {
+    fn get_account_id_for_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+    ) -> BoxFuture<'a, Result<AuthenticatedAccount, Error>> {
+        let account = self
+            .keys
+            .get(&key_id(auth_key))
+            .map(|entry| AuthenticatedAccount {
+                account_id: entry.0,
+                entitlements: entry.1.clone(),
+                externally_authorized: false,
+            })
+            .ok_or(Error::AccountNotFound);
+        futures::future::ready(account).boxed()
+    }
+
+    fn get_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<StoredKey, Error>> {
+        let key = self
+            .keys
+            .get(key_hash)
+            .map(|entry| StoredKey {
+                key_hash: key_hash.to_string(),
+                account_id: entry.0,
+                entitlements: entry.1.clone(),
+                hello_key: entry.2.clone(),
+            })
+            .ok_or(Error::AccountNotFound);
+        futures::future::ready(key).boxed()
+    }
+
+    fn put_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+        account_id: &'a Uuid,
+        entitlements: &'a Entitlements,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.keys.insert(
+            key_id(auth_key),
+            (
+                *account_id,
+                entitlements.clone(),
+                Some(hello_signature::public_key(auth_key)),
+            ),
+        );
+        futures::future::ok(()).boxed()
+    }
+
+    fn get_keys<'a>(&'a self, account_id: &'a Uuid) -> BoxFuture<'a, Result<Vec<StoredKey>, Error>> {
+        let keys = self
+            .keys
+            .iter()
+            .filter(|entry| &entry.0 == account_id)
+            .map(|entry| StoredKey {
+                key_hash: entry.key().clone(),
+                account_id: entry.0,
+                entitlements: entry.1.clone(),
+                hello_key: entry.2.clone(),
+            })
+            .collect();
+        futures::future::ok(keys).boxed()
+    }
+
+    fn revoke_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        let revoked = match self.keys.get_mut(key_hash) {
+            Some(mut entry) => {
+                entry.1.revoked = true;
+                Ok(())
+            }
+            None => Err(Error::AccountNotFound),
+        };
+        futures::future::ready(revoked).boxed()
+    }
+
+    fn get_account_id_for_subdomain<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Uuid>, Error>> {
+        let account_id = self.domains.get(subdomain).map(|entry| *entry);
+        futures::future::ok(account_id).boxed()
+    }
+
+    fn put_reservation<'a>(
+        &'a self,
+        subdomain: &'a str,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.domains.insert(subdomain.to_string(), *account_id);
+        futures::future::ok(()).boxed()
+    }
+
+    fn get_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<VerifiedClaim>, Error>> {
+        let claim = self.claims.get(subdomain).map(|entry| entry.clone());
+        futures::future::ok(claim).boxed()
+    }
+
+    fn put_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        claim: &'a VerifiedClaim,
+        replacing: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let put = match self.claims.entry(subdomain.to_string()) {
+            Entry::Occupied(entry)
+                if entry.get().account_id != claim.account_id
+                    && Some(entry.get().domain.as_str()) != replacing =>
+            {
+                Err(Error::ClaimedByOther)
+            }
+            Entry::Occupied(mut entry) => {
+                entry.insert(claim.clone());
+                Ok(())
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(claim.clone());
+                Ok(())
+            }
+        };
+        futures::future::ready(put).boxed()
+    }
+
+    fn get_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Option<Grant>, Error>> {
+        let grant = self
+            .grants
+            .get(&(subdomain.to_string(), *grantee))
+            .map(|entry| entry.clone())
+            .filter(|grant| grant.expires_at > chrono::Utc::now());
+        futures::future::ok(grant).boxed()
+    }
+
+    fn put_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+        grant: &'a Grant,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.grants
+            .insert((subdomain.to_string(), *grantee), grant.clone());
+        futures::future::ok(()).boxed()
+    }
+
+    fn delete_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.grants.remove(&(subdomain.to_string(), *grantee));
+        futures::future::ok(()).boxed()
+    }
+
+    fn put_usage_record<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), Error>> {
+        log::debug!(
+            "usage account={} sub_domain={} kind={} in={} out={}",
+            record.account_id,
+            record.sub_domain,
+            record.kind.as_str(),
+            record.bytes_in,
+            record.bytes_out
+        );
+        futures::future::ok(()).boxed()
+    }
+
+    fn put_history_record<'a>(
+        &'a self,
+        record: &'a HistoryRecord,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        // nothing expires records for us, so drop old ones as new ones come in
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(HISTORY_RETENTION_DAYS);
+        self.history.retain(|_, old| old.last_connected_at > cutoff);
+
+        self.history.insert(
+            (record.account_id, record.sub_domain.clone()),
+            record.clone(),
+        );
+        futures::future::ok(()).boxed()
+    }
+
+    fn get_history<'a>(
+        &'a self,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        let records = self
+            .history
+            .iter()
+            .filter(|entry| &entry.account_id == account_id)
+            .map(|entry| entry.value().clone())
+            .collect();
+        futures::future::ok(records).boxed()
+    }
+
+    fn get_sub_domain_history<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        let records = self
+            .history
+            .iter()
+            .filter(|entry| entry.sub_domain == subdomain)
+            .map(|entry| entry.value().clone())
+            .collect();
+        futures::future::ok(records).boxed()
+    }
+}
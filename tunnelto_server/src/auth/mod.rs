@@ -3,9 +3,30 @@ use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::fmt::Formatter;
 
+pub mod account_keys;
+pub mod admin_keys;
+pub mod approvals;
+pub mod auth_cache;
 pub mod auth_db;
+pub mod auth_service;
+pub mod auth_webhook;
 pub mod client_auth;
+pub mod client_certs;
+pub mod domain_claims;
+pub mod domain_grants;
+pub mod file_db;
+pub mod guest_keys;
+pub mod jwt;
+pub mod keys_cli;
+pub mod memory_db;
+pub mod postgres_db;
 pub mod reconnect_token;
+pub mod redis_db;
+pub mod revocation;
+pub mod signed_hello;
+pub mod sqlite_db;
+pub mod sub_domain_lease;
+pub mod tiers;
 
 #[derive(Clone)]
 pub struct SigKey([u8; 32]);
@@ -18,7 +39,7 @@ impl std::fmt::Debug for SigKey {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(transparent)]
-pub struct Signature(String);
+pub struct Signature(pub(crate) String);
 
 impl SigKey {
     pub fn generate() -> Self {
@@ -33,6 +54,19 @@ impl SigKey {
         Ok(SigKey(bytes))
     }
 
+    /// A key of its own for one use of the master key, so what's signed for that use
+    /// can't pass for anything else it signs
+    pub fn derive(&self, label: &str) -> SigKey {
+        SigKey(hmac_sha256::HMAC::mac(label.as_bytes(), &self.0))
+    }
+
+    /// An Ed25519 key pair of its own for one use of the master key, the same for
+    /// every instance sharing it
+    pub fn derive_key_pair(&self, label: &str) -> ring::signature::Ed25519KeyPair {
+        ring::signature::Ed25519KeyPair::from_seed_unchecked(&self.derive(label).0)
+            .expect("a 32 byte seed is a valid key")
+    }
+
     pub fn sign(&self, data: &[u8]) -> Signature {
         let sig = hmac_sha256::HMAC::mac(data, &self.0).to_vec();
         Signature(hex::encode(sig))
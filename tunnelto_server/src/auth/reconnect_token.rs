@@ -3,6 +3,7 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tunnelto_lib::{ClientId, ReconnectToken};
+use uuid::Uuid;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -15,8 +16,15 @@ pub enum Error {
     #[error("invalid reconnect token (signature)")]
     InvalidSignature,
 
-    #[error("reconnect token expired")]
-    Expired,
+    #[error(
+        "reconnect token expired {}s ago, beyond the {}s clock-skew window",
+        ago.num_seconds(),
+        skew.num_seconds()
+    )]
+    Expired {
+        ago: chrono::Duration,
+        skew: chrono::Duration,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -24,9 +32,18 @@ pub struct ReconnectTokenPayload {
     pub sub_domain: String,
     pub client_id: ClientId,
     pub expires: DateTime<Utc>,
+    /// when the anonymous session the token resumes ends, see `anonymous`
+    #[serde(default)]
+    pub session_ends: Option<DateTime<Utc>>,
+    /// the account of an authenticated client, which must present its key again to
+    /// get the sub-domain back
+    #[serde(default)]
+    pub account_id: Option<Uuid>,
+    #[serde(default)]
+    pub base_domain: Option<String>,
 }
 impl ReconnectTokenPayload {
-    pub fn into_token(&self, key: &SigKey) -> Result<ReconnectToken, Error> {
+    pub fn to_token(&self, key: &SigKey) -> Result<ReconnectToken, Error> {
         let payload = serde_json::to_string(&self)?;
         let sig = key.sign(payload.as_bytes());
         let tok = ReconnectTokenInner { payload, sig };
@@ -34,7 +51,13 @@ impl ReconnectTokenPayload {
         Ok(ReconnectToken(tok))
     }
 
-    pub fn verify(tok: ReconnectToken, key: &SigKey) -> Result<ReconnectTokenPayload, Error> {
+    /// Check the token is ours and unexpired, allowing `skew` past expiry for clock drift
+    /// between the server that issued it and this one
+    pub fn verify(
+        tok: ReconnectToken,
+        key: &SigKey,
+        skew: chrono::Duration,
+    ) -> Result<ReconnectTokenPayload, Error> {
         let tok = base64::decode(tok.0.as_str())?;
         let tok: ReconnectTokenInner = serde_json::from_slice(&tok)?;
 
@@ -44,8 +67,12 @@ impl ReconnectTokenPayload {
 
         let payload: ReconnectTokenPayload = serde_json::from_str(&tok.payload)?;
 
-        if Utc::now() > payload.expires {
-            return Err(Error::Expired);
+        let now = Utc::now();
+        if now > payload.expires + skew {
+            return Err(Error::Expired {
+                ago: now - payload.expires,
+                skew,
+            });
         }
 
         Ok(payload)
@@ -57,3 +84,90 @@ struct ReconnectTokenInner {
     payload: String,
     sig: Signature,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(expires: DateTime<Utc>) -> ReconnectTokenPayload {
+        ReconnectTokenPayload {
+            sub_domain: "foo".to_string(),
+            client_id: ClientId::generate(),
+            expires,
+            session_ends: None,
+            account_id: Some(Uuid::new_v4()),
+            base_domain: Some("example.com".to_string()),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = SigKey::generate();
+        let issued = payload(Utc::now() + chrono::Duration::minutes(5));
+        let token = issued.to_token(&key).unwrap();
+
+        let verified =
+            ReconnectTokenPayload::verify(token, &key, chrono::Duration::zero()).unwrap();
+        assert_eq!(verified.sub_domain, issued.sub_domain);
+        assert_eq!(verified.client_id, issued.client_id);
+        assert_eq!(verified.account_id, issued.account_id);
+        assert_eq!(verified.base_domain, issued.base_domain);
+    }
+
+    #[test]
+    fn other_keys_and_tampering_refused() {
+        let key = SigKey::generate();
+        let token = payload(Utc::now() + chrono::Duration::minutes(5))
+            .to_token(&key)
+            .unwrap();
+
+        assert!(matches!(
+            ReconnectTokenPayload::verify(
+                token.clone(),
+                &SigKey::generate(),
+                chrono::Duration::zero()
+            ),
+            Err(Error::InvalidSignature)
+        ));
+
+        // the same signature over another sub-domain
+        let mut inner: ReconnectTokenInner =
+            serde_json::from_slice(&base64::decode(&token.0).unwrap()).unwrap();
+        inner.payload = inner.payload.replace("\"foo\"", "\"bar\"");
+        let tampered = ReconnectToken(base64::encode(&serde_json::to_vec(&inner).unwrap()));
+        assert!(matches!(
+            ReconnectTokenPayload::verify(tampered, &key, chrono::Duration::zero()),
+            Err(Error::InvalidSignature)
+        ));
+
+        assert!(matches!(
+            ReconnectTokenPayload::verify(
+                ReconnectToken("garbage".to_string()),
+                &key,
+                chrono::Duration::zero()
+            ),
+            Err(Error::Base64(_)) | Err(Error::Json(_))
+        ));
+    }
+
+    #[test]
+    fn expiry_within_clock_skew() {
+        let key = SigKey::generate();
+        let token = payload(Utc::now() - chrono::Duration::seconds(30))
+            .to_token(&key)
+            .unwrap();
+
+        assert!(matches!(
+            ReconnectTokenPayload::verify(token.clone(), &key, chrono::Duration::zero()),
+            Err(Error::Expired { .. })
+        ));
+        assert!(
+            ReconnectTokenPayload::verify(token.clone(), &key, chrono::Duration::seconds(60))
+                .is_ok()
+        );
+        assert!(matches!(
+            ReconnectTokenPayload::verify(token, &key, chrono::Duration::seconds(10)),
+            Err(Error::Expired { .. })
+        ));
+    }
+}
@@ -0,0 +1,614 @@
+//! An auth backend on Redis (`AUTH_BACKEND=redis`), for servers running several
+//! instances that need key changes to apply everywhere at once: nothing is cached, so
+//! a key deleted from Redis is revoked on every instance from its next lookup.
+//!
+//! Connects to AUTH_REDIS_URL, i.e. `redis://:secret@localhost:6379/0`, and keeps all
+//! its keys under AUTH_REDIS_PREFIX (`tunnelto:` by default):
+//!
+//! - `<prefix>key:<key id>`, a hash of the account_id and its entitlements
+//!   (max_tunnels, custom_domains, tcp_tunnels, max_bandwidth, subdomain_prefix,
+//!   subdomains, expires_at, approval_policy, revoked, label, priority, catch_all, tier)
+//!   and the hello_key it signs hellos with, where the key id is the auth key hashed by
+//!   `key_id`
+//! - `<prefix>account:<account id>:keys`, the ids of the account's keys, for listing them
+//! - `<prefix>domain:<sub-domain>`, the account id that reserved it
+//! - `<prefix>claim:<sub-domain>` and `<prefix>grant:<sub-domain>:<grantee>`, hashes
+//! - `<prefix>history:...`, sub-domain history and its indexes
+//! - `<prefix>usage`, a list of usage records as json, for a consumer to pop
+//! - `<prefix>lease:<sub-domain>`, the client claiming it, see `sub_domain_lease`
+//!
+//! Anything that expires (guest keys, grants, history, leases) is given a Redis TTL, so
+//! Redis drops it for us. Adding and revoking a key is then, i.e.
+//!
+//! ```text
+//! HSET tunnelto:key:<key id> account_id 9a6e... max_tunnels 5 tcp_tunnels true
+//! SADD tunnelto:account:9a6e...:keys <key id>
+//! HSET tunnelto:key:<key id> revoked true
+//! ```
+//!
+//! A revoked key is refused with that reason and its tunnels are closed, a deleted one
+//! (`DEL tunnelto:key:<key id>`) is simply unknown.
+use super::approvals::ApprovalPolicy;
+use super::auth_db::{
+    key_id, AuthenticatedAccount, Entitlements, Error, Grant, HistoryRecord, StoredKey,
+    VerifiedClaim, HISTORY_RETENTION_DAYS,
+};
+use super::auth_service::AuthService;
+use super::tiers::Tier;
+use crate::load_shedding::Priority;
+use crate::metering::UsageRecord;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use tokio::sync::OnceCell;
+use tunnelto_lib::hello_signature;
+use uuid::Uuid;
+
+fn backend_error(e: impl std::fmt::Display) -> Error {
+    Error::Backend(e.to_string())
+}
+
+/// Take the lease if it's free or already ours, in one step
+const ACQUIRE_LEASE: &str = "
+local holder = redis.call('GET', KEYS[1])
+if holder == false or holder == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0
+";
+
+/// Drop the lease only if it's still ours
+const RELEASE_LEASE: &str = "
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Put the claim unless another account holds it, in one step
+const PUT_CLAIM: &str = "
+local holder = redis.call('HGET', KEYS[1], 'account_id')
+local domain = redis.call('HGET', KEYS[1], 'domain')
+if holder and holder ~= ARGV[1] and domain ~= ARGV[4] then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'account_id', ARGV[1], 'domain', ARGV[2], 'verified_at', ARGV[3])
+return 1
+";
+
+pub struct RedisAuthService {
+    client: redis::Client,
+    prefix: String,
+    /// connected by `init`, reconnects on its own after that
+    conn: OnceCell<ConnectionManager>,
+}
+
+impl RedisAuthService {
+    pub fn new(url: &str, prefix: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(RedisAuthService {
+            client: redis::Client::open(url)?,
+            prefix: prefix.to_string(),
+            conn: OnceCell::new(),
+        })
+    }
+
+    async fn conn(&self) -> Result<ConnectionManager, Error> {
+        self.conn
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .cloned()
+            .map_err(backend_error)
+    }
+
+    fn key(&self, parts: &[&str]) -> String {
+        format!("{}{}", self.prefix, parts.join(":"))
+    }
+}
+
+fn field<'a>(fields: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    fields
+        .get(name)
+        .map(String::as_str)
+        .filter(|v| !v.is_empty())
+}
+
+fn parse<T: std::str::FromStr>(
+    fields: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<T>, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    field(fields, name)
+        .map(|v| {
+            v.parse::<T>()
+                .map_err(|e| backend_error(format!("invalid {} `{}`: {}", name, v, e)))
+        })
+        .transpose()
+}
+
+fn required<T: std::str::FromStr>(fields: &HashMap<String, String>, name: &str) -> Result<T, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    parse(fields, name)?.ok_or_else(|| backend_error(format!("missing {}", name)))
+}
+
+fn entitlements(fields: &HashMap<String, String>) -> Result<Entitlements, Error> {
+    let default = Entitlements::default();
+    Ok(Entitlements {
+        max_tunnels: parse(fields, "max_tunnels")?.or(default.max_tunnels),
+        custom_domains: parse(fields, "custom_domains")?.unwrap_or(default.custom_domains),
+        tcp_tunnels: parse(fields, "tcp_tunnels")?.unwrap_or(default.tcp_tunnels),
+        max_bandwidth: parse(fields, "max_bandwidth")?.or(default.max_bandwidth),
+        sub_domain_prefix: field(fields, "subdomain_prefix").map(String::from),
+        sub_domains: Entitlements::sub_domains_from_stored(field(fields, "subdomains")),
+        expires_at: parse::<DateTime<Utc>>(fields, "expires_at")?,
+        approval: field(fields, "approval_policy").map(ApprovalPolicy::from_stored),
+        revoked: parse(fields, "revoked")?.unwrap_or(false),
+        label: field(fields, "label").map(String::from),
+        priority: Priority::from_stored(field(fields, "priority")),
+        catch_all: parse(fields, "catch_all")?.unwrap_or(default.catch_all),
+        tier: Tier::from_stored(field(fields, "tier")),
+    })
+}
+
+fn stored_key(key_hash: String, fields: &HashMap<String, String>) -> Result<StoredKey, Error> {
+    Ok(StoredKey {
+        key_hash,
+        account_id: required(fields, "account_id")?,
+        entitlements: entitlements(fields)?,
+        hello_key: field(fields, "hello_key").map(String::from),
+    })
+}
+
+fn history_record(fields: &HashMap<String, String>) -> Result<HistoryRecord, Error> {
+    Ok(HistoryRecord {
+        account_id: required(fields, "account_id")?,
+        sub_domain: required(fields, "subdomain")?,
+        last_connected_at: required(fields, "last_connected_at")?,
+        client_hostname: field(fields, "client_hostname").map(String::from),
+    })
+}
+
+impl RedisAuthService {
+    /// The history records whose keys are in the index set, skipping expired ones
+    async fn history_from_index(&self, index: String) -> Result<Vec<HistoryRecord>, Error> {
+        let mut conn = self.conn().await?;
+        let keys: Vec<String> = conn.smembers(&index).await.map_err(backend_error)?;
+
+        let mut records = vec![];
+        for key in keys {
+            let fields: HashMap<String, String> =
+                conn.hgetall(&key).await.map_err(backend_error)?;
+            if fields.is_empty() {
+                // expired, stop indexing it
+                let _: () = conn.srem(&index, &key).await.map_err(backend_error)?;
+                continue;
+            }
+            records.push(history_record(&fields)?);
+        }
+        Ok(records)
+    }
+}
+
+impl AuthService for RedisAuthService {
+    fn init<'a>(&'a self) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut conn = self.conn().await?;
+            redis::cmd("PING")
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed()
+    }
+
+    fn get_account_id_for_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+    ) -> BoxFuture<'a, Result<AuthenticatedAccount, Error>> {
+        async move {
+            let fields: HashMap<String, String> = self
+                .conn()
+                .await?
+                .hgetall(self.key(&["key", &key_id(auth_key)]))
+                .await
+                .map_err(backend_error)?;
+            if fields.is_empty() {
+                return Err(Error::AccountNotFound);
+            }
+
+            Ok(AuthenticatedAccount {
+                account_id: required(&fields, "account_id")?,
+                entitlements: entitlements(&fields)?,
+                externally_authorized: false,
+            })
+        }
+        .boxed()
+    }
+
+    fn get_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<StoredKey, Error>> {
+        async move {
+            let fields: HashMap<String, String> = self
+                .conn()
+                .await?
+                .hgetall(self.key(&["key", key_hash]))
+                .await
+                .map_err(backend_error)?;
+            if fields.is_empty() {
+                return Err(Error::AccountNotFound);
+            }
+            stored_key(key_hash.to_string(), &fields)
+        }
+        .boxed()
+    }
+
+    fn put_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+        account_id: &'a Uuid,
+        entitlements: &'a Entitlements,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let key_hash = key_id(auth_key);
+            let key = self.key(&["key", &key_hash]);
+            let account_keys = self.key(&["account", &account_id.to_string(), "keys"]);
+            let mut fields = vec![
+                ("account_id", account_id.to_string()),
+                ("custom_domains", entitlements.custom_domains.to_string()),
+                ("tcp_tunnels", entitlements.tcp_tunnels.to_string()),
+                ("hello_key", hello_signature::public_key(auth_key)),
+            ];
+            if let Some(max_tunnels) = entitlements.max_tunnels {
+                fields.push(("max_tunnels", max_tunnels.to_string()));
+            }
+            if let Some(max_bandwidth) = entitlements.max_bandwidth {
+                fields.push(("max_bandwidth", max_bandwidth.to_string()));
+            }
+            if let Some(prefix) = entitlements.sub_domain_prefix.as_ref() {
+                fields.push(("subdomain_prefix", prefix.clone()));
+            }
+            if let Some(sub_domains) = entitlements.sub_domains_to_stored() {
+                fields.push(("subdomains", sub_domains));
+            }
+            if let Some(expires_at) = entitlements.expires_at {
+                fields.push(("expires_at", expires_at.to_rfc3339()));
+            }
+            if let Some(policy) = entitlements.approval.as_ref() {
+                fields.push(("approval_policy", policy.to_stored()));
+            }
+            if entitlements.revoked {
+                fields.push(("revoked", true.to_string()));
+            }
+            if let Some(label) = entitlements.label.as_ref() {
+                fields.push(("label", label.clone()));
+            }
+            if let Some(priority) = entitlements.priority.to_stored() {
+                fields.push(("priority", priority));
+            }
+            if entitlements.catch_all {
+                fields.push(("catch_all", true.to_string()));
+            }
+            if let Some(tier) = entitlements.tier {
+                fields.push(("tier", tier.to_string()));
+            }
+
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .del(&key)
+                .ignore()
+                .hset_multiple(&key, &fields)
+                .ignore()
+                .sadd(&account_keys, &key_hash)
+                .ignore();
+            // leave a margin for the clock-skew window before Redis forgets the key
+            if let Some(expires_at) = entitlements.expires_at {
+                let forget_at = expires_at + crate::CONFIG.clock_skew;
+                pipe.expire_at(&key, forget_at.timestamp().max(0) as usize)
+                    .ignore();
+            }
+            pipe.query_async(&mut self.conn().await?)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed()
+    }
+
+    fn get_keys<'a>(&'a self, account_id: &'a Uuid) -> BoxFuture<'a, Result<Vec<StoredKey>, Error>> {
+        async move {
+            let mut conn = self.conn().await?;
+            let index = self.key(&["account", &account_id.to_string(), "keys"]);
+            let key_hashes: Vec<String> = conn.smembers(&index).await.map_err(backend_error)?;
+
+            let mut keys = vec![];
+            for key_hash in key_hashes {
+                let fields: HashMap<String, String> = conn
+                    .hgetall(self.key(&["key", &key_hash]))
+                    .await
+                    .map_err(backend_error)?;
+                if fields.is_empty() {
+                    // expired or deleted, stop indexing it
+                    let _: () = conn.srem(&index, &key_hash).await.map_err(backend_error)?;
+                    continue;
+                }
+                keys.push(stored_key(key_hash, &fields)?);
+            }
+            Ok(keys)
+        }
+        .boxed()
+    }
+
+    fn revoke_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut conn = self.conn().await?;
+            let key = self.key(&["key", key_hash]);
+            let exists: bool = conn.exists(&key).await.map_err(backend_error)?;
+            if !exists {
+                return Err(Error::AccountNotFound);
+            }
+            conn.hset(&key, "revoked", true.to_string())
+                .await
+                .map_err(backend_error)
+        }
+        .boxed()
+    }
+
+    fn get_account_id_for_subdomain<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Uuid>, Error>> {
+        async move {
+            let account_id: Option<String> = self
+                .conn()
+                .await?
+                .get(self.key(&["domain", subdomain]))
+                .await
+                .map_err(backend_error)?;
+            account_id
+                .map(|id| Uuid::parse_str(&id).map_err(backend_error))
+                .transpose()
+        }
+        .boxed()
+    }
+
+    fn put_reservation<'a>(
+        &'a self,
+        subdomain: &'a str,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.conn()
+                .await?
+                .set(self.key(&["domain", subdomain]), account_id.to_string())
+                .await
+                .map_err(backend_error)
+        }
+        .boxed()
+    }
+
+    fn get_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<VerifiedClaim>, Error>> {
+        async move {
+            let fields: HashMap<String, String> = self
+                .conn()
+                .await?
+                .hgetall(self.key(&["claim", subdomain]))
+                .await
+                .map_err(backend_error)?;
+            if fields.is_empty() {
+                return Ok(None);
+            }
+
+            Ok(Some(VerifiedClaim {
+                account_id: required(&fields, "account_id")?,
+                domain: required(&fields, "domain")?,
+            }))
+        }
+        .boxed()
+    }
+
+    fn put_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        claim: &'a VerifiedClaim,
+        replacing: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let put: i64 = redis::Script::new(PUT_CLAIM)
+                .key(self.key(&["claim", subdomain]))
+                .arg(claim.account_id.to_string())
+                .arg(&claim.domain)
+                .arg(Utc::now().to_rfc3339())
+                .arg(replacing.unwrap_or_default())
+                .invoke_async(&mut self.conn().await?)
+                .await
+                .map_err(backend_error)?;
+            if put == 0 {
+                return Err(Error::ClaimedByOther);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Option<Grant>, Error>> {
+        async move {
+            let fields: HashMap<String, String> = self
+                .conn()
+                .await?
+                .hgetall(self.key(&["grant", subdomain, &grantee.to_string()]))
+                .await
+                .map_err(backend_error)?;
+            if fields.is_empty() {
+                return Ok(None);
+            }
+
+            let grant = Grant {
+                account_id: required(&fields, "account_id")?,
+                expires_at: required(&fields, "expires_at")?,
+            };
+            Ok(Some(grant).filter(|grant| grant.expires_at > Utc::now()))
+        }
+        .boxed()
+    }
+
+    fn put_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+        grant: &'a Grant,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let key = self.key(&["grant", subdomain, &grantee.to_string()]);
+            let fields = [
+                ("account_id", grant.account_id.to_string()),
+                ("expires_at", grant.expires_at.to_rfc3339()),
+            ];
+            redis::pipe()
+                .atomic()
+                .hset_multiple(&key, &fields)
+                .ignore()
+                .expire_at(&key, grant.expires_at.timestamp().max(0) as usize)
+                .ignore()
+                .query_async(&mut self.conn().await?)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed()
+    }
+
+    fn delete_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.conn()
+                .await?
+                .del(self.key(&["grant", subdomain, &grantee.to_string()]))
+                .await
+                .map_err(backend_error)
+        }
+        .boxed()
+    }
+
+    fn put_usage_record<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let record = serde_json::to_string(record).map_err(backend_error)?;
+            self.conn()
+                .await?
+                .rpush(self.key(&["usage"]), record)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed()
+    }
+
+    fn put_history_record<'a>(
+        &'a self,
+        record: &'a HistoryRecord,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let account_id = record.account_id.to_string();
+            let key = self.key(&["history", &account_id, &record.sub_domain]);
+            let by_account = self.key(&["history", "account", &account_id]);
+            let by_sub_domain = self.key(&["history", "subdomain", &record.sub_domain]);
+            let retention = chrono::Duration::days(HISTORY_RETENTION_DAYS).num_seconds() as usize;
+
+            let mut fields = vec![
+                ("account_id", account_id.clone()),
+                ("subdomain", record.sub_domain.clone()),
+                ("last_connected_at", record.last_connected_at.to_rfc3339()),
+            ];
+            if let Some(hostname) = record.client_hostname.as_ref() {
+                fields.push(("client_hostname", hostname.clone()));
+            }
+
+            // the indexes live as long as their newest record
+            redis::pipe()
+                .atomic()
+                .del(&key)
+                .ignore()
+                .hset_multiple(&key, &fields)
+                .ignore()
+                .expire(&key, retention)
+                .ignore()
+                .sadd(&by_account, &key)
+                .ignore()
+                .expire(&by_account, retention)
+                .ignore()
+                .sadd(&by_sub_domain, &key)
+                .ignore()
+                .expire(&by_sub_domain, retention)
+                .ignore()
+                .query_async(&mut self.conn().await?)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed()
+    }
+
+    fn get_history<'a>(
+        &'a self,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        self.history_from_index(self.key(&["history", "account", &account_id.to_string()]))
+            .boxed()
+    }
+
+    fn get_sub_domain_history<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        self.history_from_index(self.key(&["history", "subdomain", subdomain]))
+            .boxed()
+    }
+
+    fn acquire_sub_domain_lease<'a>(
+        &'a self,
+        subdomain: &'a str,
+        holder: &'a str,
+        expires_at: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        async move {
+            let ttl = (expires_at - Utc::now()).num_milliseconds().max(1);
+            let acquired: i64 = redis::Script::new(ACQUIRE_LEASE)
+                .key(self.key(&["lease", subdomain]))
+                .arg(holder)
+                .arg(ttl)
+                .invoke_async(&mut self.conn().await?)
+                .await
+                .map_err(backend_error)?;
+            Ok(acquired == 1)
+        }
+        .boxed()
+    }
+
+    fn release_sub_domain_lease<'a>(
+        &'a self,
+        subdomain: &'a str,
+        holder: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let _: i64 = redis::Script::new(RELEASE_LEASE)
+                .key(self.key(&["lease", subdomain]))
+                .arg(holder)
+                .invoke_async(&mut self.conn().await?)
+                .await
+                .map_err(backend_error)?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,117 @@
+//! Keys stop working when they expire or are revoked (`Entitlements::revoked`). New
+//! tunnels are refused with the reason, and tunnels already open are closed when the
+//! keys that opened them are looked up again, every KEY_RECHECK_SECS. Lookups go
+//! through the auth cache, so a revocation may take AUTH_CACHE_TTL_SECS longer.
+//!
+//! Tunnels authenticated by the auth webhook or a JWT aren't rechecked: their
+//! authority isn't asked again until they reconnect.
+use super::auth_db::{key_id, Entitlements, Error};
+use crate::connected_clients::Connections;
+use crate::{AUTH_DB_SERVICE, CONFIG};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::time::{Duration, Instant};
+use tunnelto_lib::{ClientId, ClientType};
+
+/// how long a watched key may wait for its tunnel to connect
+const CONNECT_GRACE: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    /// the keys of open tunnels, by the tunnel's client id
+    static ref WATCHED: DashMap<ClientId, Watched> = DashMap::new();
+}
+
+struct Watched {
+    /// the key's `key_id`
+    key_hash: String,
+    since: Instant,
+}
+
+/// Why the key may no longer open tunnels, if it may not
+pub fn rejection(entitlements: &Entitlements) -> Option<String> {
+    if entitlements.revoked {
+        return Some("the key was revoked".to_string());
+    }
+
+    let expires_at = entitlements.expires_at?;
+    let now = chrono::Utc::now();
+    if expires_at + CONFIG.clock_skew <= now {
+        return Some(format!(
+            "the key expired {}s ago, beyond the {}s clock-skew window",
+            (now - expires_at).num_seconds(),
+            CONFIG.clock_skew.num_seconds()
+        ));
+    }
+    None
+}
+
+/// Recheck the key of the tunnel about to open, if it was opened with one
+pub fn watch(client_type: &ClientType, client_id: &ClientId) {
+    if CONFIG.auth_webhook_url.is_some() {
+        return;
+    }
+    let key_hash = match client_type {
+        ClientType::Auth { key } => key_id(&key.0),
+        ClientType::SignedAuth { key_id } => key_id.clone(),
+        _ => return,
+    };
+    WATCHED.insert(
+        client_id.clone(),
+        Watched {
+            key_hash,
+            since: Instant::now(),
+        },
+    );
+}
+
+/// Close the tunnels whose keys expired, were revoked or deleted
+async fn recheck() {
+    let watched = WATCHED
+        .iter()
+        .map(|w| (w.key().clone(), w.key_hash.clone(), w.since))
+        .collect::<Vec<_>>();
+
+    for (client_id, key_hash, since) in watched {
+        let client = match Connections::get(&client_id) {
+            Some(client) => client,
+            None => {
+                if since.elapsed() > CONNECT_GRACE {
+                    WATCHED.remove(&client_id);
+                }
+                continue;
+            }
+        };
+
+        let reason = match AUTH_DB_SERVICE.get_key(&key_hash).await {
+            Ok(key) => match rejection(&key.entitlements) {
+                Some(reason) => reason,
+                None => continue,
+            },
+            Err(Error::AccountNotFound) => "the key was deleted".to_string(),
+            // don't cut tunnels because the backend is down
+            Err(e) => {
+                log::warn!("failed to recheck the key of {}: {}", &client_id, e);
+                continue;
+            }
+        };
+
+        log::info!(
+            "closing tunnel {} on {} (key {:?}): {}",
+            &client_id,
+            &client.host,
+            &client.entitlements.label,
+            reason
+        );
+        WATCHED.remove(&client_id);
+        Connections::remove(&client);
+    }
+}
+
+pub fn spawn(interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            recheck().await;
+        }
+    });
+}
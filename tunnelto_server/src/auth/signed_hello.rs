@@ -0,0 +1,92 @@
+//! Client hellos proving an auth key without sending it, see
+//! `tunnelto_lib::hello_signature`. A client sending `ClientType::SignedAuth` is answered
+//! with a challenge for this connection alone, and authenticated once it signed that
+//! challenge along with its hello, as we received it, with the key stored under its
+//! `key_id`. Nothing to remember across connections, so every instance checks its own
+//! challenges without sharing any state.
+//!
+//! Only auth keys sign: JWTs and reconnect tokens are bearer credentials the client was
+//! handed, and certificates are bound to the TLS connection already. Set
+//! REQUIRE_SIGNED_HELLO to also refuse auth keys sent as they are, once all clients sign.
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use tunnelto_lib::hello_signature::{self, HelloProof};
+use tunnelto_lib::{ClientHello, ClientType, ServerHello, TunnelError};
+use warp::filters::ws::{Message, WebSocket};
+
+/// How long the client gets to answer the challenge
+const PROOF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A hello the client signed for the challenge we sent it
+#[derive(Debug)]
+pub struct SignedHello {
+    challenge: String,
+    hello: Vec<u8>,
+    proof: HelloProof,
+}
+
+impl SignedHello {
+    /// Check the hello was signed by the key with this `hello_signature::public_key`
+    pub fn verify(&self, public_key: Option<&str>) -> Result<(), TunnelError> {
+        let public_key = public_key.ok_or_else(|| {
+            TunnelError::AuthFailed(
+                "the key can't sign hellos until it's stored again, i.e. rotated".into(),
+            )
+        })?;
+        hello_signature::verify(public_key, &self.challenge, &self.hello, &self.proof)
+            .map_err(|e| TunnelError::AuthFailed(e.to_string()))
+    }
+}
+
+/// Challenge the client to sign its hello, if it signs, and wait for its proof. Hellos
+/// that don't parse are left for the caller to refuse.
+pub async fn challenge(
+    websocket: &mut WebSocket,
+    data: &[u8],
+) -> Result<Option<SignedHello>, TunnelError> {
+    let client_hello: ClientHello = match serde_json::from_slice(data) {
+        Ok(client_hello) => client_hello,
+        Err(_) => return Ok(None),
+    };
+
+    match client_hello.client_type {
+        ClientType::SignedAuth { .. } => {}
+        ClientType::Auth { .. } if crate::CONFIG.require_signed_hello => {
+            return Err(TunnelError::AuthFailed(
+                "this server only takes signed hellos, connect with --sign-hello".into(),
+            ))
+        }
+        _ => return Ok(None),
+    }
+
+    let challenge = hello_signature::challenge();
+    let server_hello = ServerHello::Challenge {
+        challenge: challenge.clone(),
+    };
+    let message = serde_json::to_vec(&server_hello).unwrap_or_default();
+    websocket
+        .send(Message::binary(message))
+        .await
+        .map_err(|e| TunnelError::Internal(format!("failed to send challenge: {}", e)))?;
+
+    let proof = match tokio::time::timeout(PROOF_TIMEOUT, websocket.next()).await {
+        Ok(Some(Ok(msg))) => serde_json::from_slice::<HelloProof>(msg.as_bytes())
+            .map_err(|e| TunnelError::InvalidClientHello(e.to_string()))?,
+        Ok(_) => {
+            return Err(TunnelError::InvalidClientHello(
+                "no answer to the challenge".into(),
+            ))
+        }
+        Err(_) => {
+            return Err(TunnelError::AuthFailed(
+                "the challenge wasn't answered in time".into(),
+            ))
+        }
+    };
+
+    Ok(Some(SignedHello {
+        challenge,
+        hello: data.to_vec(),
+        proof,
+    }))
+}
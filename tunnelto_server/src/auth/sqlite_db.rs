@@ -0,0 +1,787 @@
+//! An auth backend in a SQLite file (`AUTH_BACKEND=sqlite`), for single-node servers
+//! that want keys and reservations to survive restarts without running a database.
+//! The file is AUTH_SQLITE_PATH, created along with the tables if missing. Accounts are
+//! managed by inserting their keys, as hashed by `key_id`, into `tunnelto_auth`:
+//!
+//! ```sql
+//! INSERT INTO tunnelto_auth (auth_key_hash, account_id, max_tunnels) VALUES (?1, ?2, 5);
+//! ```
+//!
+//! Account ids are stored as hyphenated uuids and times as RFC 3339 text. Missing
+//! entitlement columns take the same defaults as the DynamoDB backend.
+use super::approvals::ApprovalPolicy;
+use super::auth_db::{
+    key_id, AuthenticatedAccount, Entitlements, Error, Grant, HistoryRecord, StoredKey,
+    VerifiedClaim,
+};
+use super::auth_service::AuthService;
+use super::tiers::Tier;
+use crate::load_shedding::Priority;
+use crate::metering::UsageRecord;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tunnelto_lib::hello_signature;
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tunnelto_auth (
+    auth_key_hash TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    max_tunnels INTEGER,
+    custom_domains INTEGER,
+    tcp_tunnels INTEGER,
+    max_bandwidth INTEGER,
+    subdomain_prefix TEXT,
+    expires_at TEXT,
+    approval_policy TEXT,
+    revoked INTEGER NOT NULL DEFAULT 0,
+    subdomains TEXT,
+    label TEXT,
+    priority TEXT,
+    catch_all INTEGER NOT NULL DEFAULT 0,
+    tier TEXT,
+    hello_key TEXT
+);
+CREATE TABLE IF NOT EXISTS tunnelto_domains (
+    subdomain TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tunnelto_claims (
+    subdomain TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    verified_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tunnelto_grants (
+    subdomain TEXT NOT NULL,
+    grantee TEXT NOT NULL,
+    account_id TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    PRIMARY KEY (subdomain, grantee)
+);
+CREATE TABLE IF NOT EXISTS tunnelto_usage (
+    record_id TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    subdomain TEXT NOT NULL,
+    bytes_in INTEGER NOT NULL,
+    bytes_out INTEGER NOT NULL,
+    recorded_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tunnelto_history (
+    account_id TEXT NOT NULL,
+    subdomain TEXT NOT NULL,
+    last_connected_at TEXT NOT NULL,
+    client_hostname TEXT,
+    PRIMARY KEY (account_id, subdomain)
+);
+CREATE INDEX IF NOT EXISTS tunnelto_history_subdomain ON tunnelto_history (subdomain);
+CREATE TABLE IF NOT EXISTS tunnelto_leases (
+    subdomain TEXT PRIMARY KEY,
+    holder TEXT NOT NULL,
+    expires_at TEXT NOT NULL
+);
+";
+
+fn backend_error(e: impl std::fmt::Display) -> Error {
+    Error::Backend(e.to_string())
+}
+
+fn uuid(row: &Row, column: &str) -> Result<Uuid, Error> {
+    let value: String = row.get(column).map_err(backend_error)?;
+    Uuid::parse_str(&value).map_err(backend_error)
+}
+
+fn time(row: &Row, column: &str) -> Result<Option<DateTime<Utc>>, Error> {
+    let value: Option<String> = row.get(column).map_err(backend_error)?;
+    value
+        .map(|value| {
+            DateTime::parse_from_rfc3339(&value)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(backend_error)
+        })
+        .transpose()
+}
+
+fn required_time(row: &Row, column: &str) -> Result<DateTime<Utc>, Error> {
+    time(row, column)?.ok_or_else(|| backend_error(format!("{} is null", column)))
+}
+
+/// A time that compares as text, for comparing in queries
+fn sortable_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn entitlements(row: &Row) -> Result<Entitlements, Error> {
+    let default = Entitlements::default();
+    let max_tunnels: Option<i64> = row.get("max_tunnels").map_err(backend_error)?;
+    let custom_domains: Option<bool> = row.get("custom_domains").map_err(backend_error)?;
+    let tcp_tunnels: Option<bool> = row.get("tcp_tunnels").map_err(backend_error)?;
+    let max_bandwidth: Option<i64> = row.get("max_bandwidth").map_err(backend_error)?;
+    let approval_policy: Option<String> = row.get("approval_policy").map_err(backend_error)?;
+    let revoked: bool = row.get("revoked").map_err(backend_error)?;
+    let sub_domains: Option<String> = row.get("subdomains").map_err(backend_error)?;
+    let priority: Option<String> = row.get("priority").map_err(backend_error)?;
+    let catch_all: bool = row.get("catch_all").map_err(backend_error)?;
+    let tier: Option<String> = row.get("tier").map_err(backend_error)?;
+
+    Ok(Entitlements {
+        max_tunnels: max_tunnels.map(|n| n.max(0) as u32).or(default.max_tunnels),
+        custom_domains: custom_domains.unwrap_or(default.custom_domains),
+        tcp_tunnels: tcp_tunnels.unwrap_or(default.tcp_tunnels),
+        max_bandwidth: max_bandwidth
+            .map(|n| n.max(0) as u64)
+            .or(default.max_bandwidth),
+        sub_domain_prefix: row.get("subdomain_prefix").map_err(backend_error)?,
+        sub_domains: Entitlements::sub_domains_from_stored(sub_domains.as_deref()),
+        expires_at: time(row, "expires_at")?,
+        approval: approval_policy.map(|policy| ApprovalPolicy::from_stored(&policy)),
+        revoked,
+        label: row.get("label").map_err(backend_error)?,
+        priority: Priority::from_stored(priority.as_deref()),
+        catch_all,
+        tier: Tier::from_stored(tier.as_deref()),
+    })
+}
+
+fn stored_key(row: &Row) -> Result<StoredKey, Error> {
+    Ok(StoredKey {
+        key_hash: row.get("auth_key_hash").map_err(backend_error)?,
+        account_id: uuid(row, "account_id")?,
+        entitlements: entitlements(row)?,
+        hello_key: row.get("hello_key").map_err(backend_error)?,
+    })
+}
+
+fn history_record(row: &Row) -> Result<HistoryRecord, Error> {
+    Ok(HistoryRecord {
+        account_id: uuid(row, "account_id")?,
+        sub_domain: row.get("subdomain").map_err(backend_error)?,
+        last_connected_at: required_time(row, "last_connected_at")?,
+        client_hostname: row.get("client_hostname").map_err(backend_error)?,
+    })
+}
+
+fn query_history(conn: &Connection, sql: &str, param: String) -> Result<Vec<HistoryRecord>, Error> {
+    let mut statement = conn.prepare(sql).map_err(backend_error)?;
+    let mut rows = statement.query(params![param]).map_err(backend_error)?;
+
+    let mut records = vec![];
+    while let Some(row) = rows.next().map_err(backend_error)? {
+        records.push(history_record(row)?);
+    }
+    Ok(records)
+}
+
+pub struct SqliteAuthService {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteAuthService {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let conn = Connection::open(path)?;
+        // wait out a concurrent writer instead of failing with SQLITE_BUSY
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+
+        Ok(SqliteAuthService {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run `f` on the connection, on the blocking pool since sqlite calls block
+    fn call<T, F>(&self, f: F) -> BoxFuture<'static, Result<T, Error>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.lock().map_err(backend_error)?;
+                f(&conn)
+            })
+            .await
+            .map_err(backend_error)?
+        }
+        .boxed()
+    }
+}
+
+impl AuthService for SqliteAuthService {
+    fn init<'a>(&'a self) -> BoxFuture<'a, Result<(), Error>> {
+        self.call(|conn| {
+            conn.execute_batch(SCHEMA).map_err(backend_error)?;
+
+            // files created before approval policies lack the column
+            let has_approval_policy = conn
+                .prepare("SELECT approval_policy FROM tunnelto_auth LIMIT 0")
+                .is_ok();
+            if !has_approval_policy {
+                conn.execute_batch("ALTER TABLE tunnelto_auth ADD COLUMN approval_policy TEXT")
+                    .map_err(backend_error)?;
+            }
+
+            // and before revocation, the revoked flag
+            let has_revoked = conn
+                .prepare("SELECT revoked FROM tunnelto_auth LIMIT 0")
+                .is_ok();
+            if !has_revoked {
+                conn.execute_batch(
+                    "ALTER TABLE tunnelto_auth ADD COLUMN revoked INTEGER NOT NULL DEFAULT 0",
+                )
+                .map_err(backend_error)?;
+            }
+
+            // and before scoped keys, their sub-domains
+            let has_sub_domains = conn
+                .prepare("SELECT subdomains FROM tunnelto_auth LIMIT 0")
+                .is_ok();
+            if !has_sub_domains {
+                conn.execute_batch("ALTER TABLE tunnelto_auth ADD COLUMN subdomains TEXT")
+                    .map_err(backend_error)?;
+            }
+
+            // and before labelled keys, their labels
+            let has_label = conn
+                .prepare("SELECT label FROM tunnelto_auth LIMIT 0")
+                .is_ok();
+            if !has_label {
+                conn.execute_batch("ALTER TABLE tunnelto_auth ADD COLUMN label TEXT")
+                    .map_err(backend_error)?;
+            }
+
+            // and before load shedding, their priority
+            let has_priority = conn
+                .prepare("SELECT priority FROM tunnelto_auth LIMIT 0")
+                .is_ok();
+            if !has_priority {
+                conn.execute_batch("ALTER TABLE tunnelto_auth ADD COLUMN priority TEXT")
+                    .map_err(backend_error)?;
+            }
+
+            // and before catch-all tunnels, whether the key may open one
+            let has_catch_all = conn
+                .prepare("SELECT catch_all FROM tunnelto_auth LIMIT 0")
+                .is_ok();
+            if !has_catch_all {
+                conn.execute_batch(
+                    "ALTER TABLE tunnelto_auth ADD COLUMN catch_all INTEGER NOT NULL DEFAULT 0",
+                )
+                .map_err(backend_error)?;
+            }
+
+            // and before account tiers
+            let has_tier = conn
+                .prepare("SELECT tier FROM tunnelto_auth LIMIT 0")
+                .is_ok();
+            if !has_tier {
+                conn.execute_batch("ALTER TABLE tunnelto_auth ADD COLUMN tier TEXT")
+                    .map_err(backend_error)?;
+            }
+
+            // and before signed hellos, the key they're checked with
+            let has_hello_key = conn
+                .prepare("SELECT hello_key FROM tunnelto_auth LIMIT 0")
+                .is_ok();
+            if !has_hello_key {
+                conn.execute_batch("ALTER TABLE tunnelto_auth ADD COLUMN hello_key TEXT")
+                    .map_err(backend_error)?;
+            }
+
+            conn.execute_batch(
+                "CREATE INDEX IF NOT EXISTS tunnelto_auth_account_id ON tunnelto_auth (account_id)",
+            )
+            .map_err(backend_error)?;
+            Ok(())
+        })
+    }
+
+    fn get_account_id_for_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+    ) -> BoxFuture<'a, Result<AuthenticatedAccount, Error>> {
+        let key_hash = key_id(auth_key);
+        self.call(move |conn| {
+            let mut statement = conn
+                .prepare("SELECT * FROM tunnelto_auth WHERE auth_key_hash = ?1")
+                .map_err(backend_error)?;
+            let mut rows = statement.query(params![key_hash]).map_err(backend_error)?;
+            let row = rows
+                .next()
+                .map_err(backend_error)?
+                .ok_or(Error::AccountNotFound)?;
+
+            Ok(AuthenticatedAccount {
+                account_id: uuid(row, "account_id")?,
+                entitlements: entitlements(row)?,
+                externally_authorized: false,
+            })
+        })
+    }
+
+    fn get_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<StoredKey, Error>> {
+        let key_hash = key_hash.to_string();
+        self.call(move |conn| {
+            let mut statement = conn
+                .prepare("SELECT * FROM tunnelto_auth WHERE auth_key_hash = ?1")
+                .map_err(backend_error)?;
+            let mut rows = statement.query(params![key_hash]).map_err(backend_error)?;
+            let row = rows
+                .next()
+                .map_err(backend_error)?
+                .ok_or(Error::AccountNotFound)?;
+            stored_key(row)
+        })
+    }
+
+    fn put_auth_key<'a>(
+        &'a self,
+        auth_key: &'a str,
+        account_id: &'a Uuid,
+        entitlements: &'a Entitlements,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let key_hash = key_id(auth_key);
+        let hello_key = hello_signature::public_key(auth_key);
+        let account_id = account_id.to_string();
+        let entitlements = entitlements.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO tunnelto_auth (auth_key_hash, account_id, max_tunnels, \
+                 custom_domains, tcp_tunnels, max_bandwidth, subdomain_prefix, expires_at, \
+                 approval_policy, revoked, subdomains, label, priority, catch_all, tier, \
+                 hello_key) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+                params![
+                    key_hash,
+                    account_id,
+                    entitlements.max_tunnels,
+                    entitlements.custom_domains,
+                    entitlements.tcp_tunnels,
+                    entitlements.max_bandwidth.map(|n| n as i64),
+                    entitlements.sub_domain_prefix,
+                    entitlements.expires_at.map(|t| t.to_rfc3339()),
+                    entitlements
+                        .approval
+                        .as_ref()
+                        .map(ApprovalPolicy::to_stored),
+                    entitlements.revoked,
+                    entitlements.sub_domains_to_stored(),
+                    entitlements.label,
+                    entitlements.priority.to_stored(),
+                    entitlements.catch_all,
+                    entitlements.tier.map(|tier| tier.as_str()),
+                    hello_key,
+                ],
+            )
+            .map_err(backend_error)?;
+            Ok(())
+        })
+    }
+
+    fn get_keys<'a>(&'a self, account_id: &'a Uuid) -> BoxFuture<'a, Result<Vec<StoredKey>, Error>> {
+        let account_id = account_id.to_string();
+        self.call(move |conn| {
+            let mut statement = conn
+                .prepare("SELECT * FROM tunnelto_auth WHERE account_id = ?1")
+                .map_err(backend_error)?;
+            let mut rows = statement.query(params![account_id]).map_err(backend_error)?;
+
+            let mut keys = vec![];
+            while let Some(row) = rows.next().map_err(backend_error)? {
+                keys.push(stored_key(row)?);
+            }
+            Ok(keys)
+        })
+    }
+
+    fn revoke_key<'a>(&'a self, key_hash: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        let key_hash = key_hash.to_string();
+        self.call(move |conn| {
+            let updated = conn
+                .execute(
+                    "UPDATE tunnelto_auth SET revoked = 1 WHERE auth_key_hash = ?1",
+                    params![key_hash],
+                )
+                .map_err(backend_error)?;
+            if updated == 0 {
+                return Err(Error::AccountNotFound);
+            }
+            Ok(())
+        })
+    }
+
+    fn get_account_id_for_subdomain<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Uuid>, Error>> {
+        let subdomain = subdomain.to_string();
+        self.call(move |conn| {
+            let account_id: Option<String> = conn
+                .query_row(
+                    "SELECT account_id FROM tunnelto_domains WHERE subdomain = ?1",
+                    params![subdomain],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(backend_error)?;
+            account_id
+                .map(|id| Uuid::parse_str(&id).map_err(backend_error))
+                .transpose()
+        })
+    }
+
+    fn put_reservation<'a>(
+        &'a self,
+        subdomain: &'a str,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let subdomain = subdomain.to_string();
+        let account_id = account_id.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO tunnelto_domains (subdomain, account_id) VALUES (?1, ?2)",
+                params![subdomain, account_id],
+            )
+            .map_err(backend_error)?;
+            Ok(())
+        })
+    }
+
+    fn get_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Option<VerifiedClaim>, Error>> {
+        let subdomain = subdomain.to_string();
+        self.call(move |conn| {
+            let claim: Option<(String, String)> = conn
+                .query_row(
+                    "SELECT account_id, domain FROM tunnelto_claims WHERE subdomain = ?1",
+                    params![subdomain],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .map_err(backend_error)?;
+            claim
+                .map(|(account_id, domain)| {
+                    Ok(VerifiedClaim {
+                        account_id: Uuid::parse_str(&account_id).map_err(backend_error)?,
+                        domain,
+                    })
+                })
+                .transpose()
+        })
+    }
+
+    fn put_verified_claim<'a>(
+        &'a self,
+        subdomain: &'a str,
+        claim: &'a VerifiedClaim,
+        replacing: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let subdomain = subdomain.to_string();
+        let claim = claim.clone();
+        let replacing = replacing.map(str::to_string);
+        self.call(move |conn| {
+            // only the account holding the claim may renew it, or a claim outranking it
+            // replace it
+            let put = conn
+                .execute(
+                    "INSERT INTO tunnelto_claims (subdomain, account_id, domain, verified_at) \
+                     VALUES (?1, ?2, ?3, ?4) \
+                     ON CONFLICT (subdomain) DO UPDATE \
+                     SET account_id = ?2, domain = ?3, verified_at = ?4 \
+                     WHERE account_id = ?2 OR domain = ?5",
+                    params![
+                        subdomain,
+                        claim.account_id.to_string(),
+                        claim.domain,
+                        Utc::now().to_rfc3339(),
+                        replacing
+                    ],
+                )
+                .map_err(backend_error)?;
+            if put == 0 {
+                return Err(Error::ClaimedByOther);
+            }
+            Ok(())
+        })
+    }
+
+    fn get_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Option<Grant>, Error>> {
+        let subdomain = subdomain.to_string();
+        let grantee = grantee.to_string();
+        self.call(move |conn| {
+            let mut statement = conn
+                .prepare(
+                    "SELECT account_id, expires_at FROM tunnelto_grants \
+                     WHERE subdomain = ?1 AND grantee = ?2",
+                )
+                .map_err(backend_error)?;
+            let mut rows = statement
+                .query(params![subdomain, grantee])
+                .map_err(backend_error)?;
+            let row = match rows.next().map_err(backend_error)? {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+
+            let grant = Grant {
+                account_id: uuid(row, "account_id")?,
+                expires_at: required_time(row, "expires_at")?,
+            };
+            Ok(Some(grant).filter(|grant| grant.expires_at > Utc::now()))
+        })
+    }
+
+    fn put_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+        grant: &'a Grant,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let subdomain = subdomain.to_string();
+        let grantee = grantee.to_string();
+        let grant = grant.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO tunnelto_grants (subdomain, grantee, account_id, expires_at) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    subdomain,
+                    grantee,
+                    grant.account_id.to_string(),
+                    grant.expires_at.to_rfc3339()
+                ],
+            )
+            .map_err(backend_error)?;
+            Ok(())
+        })
+    }
+
+    fn delete_grant<'a>(
+        &'a self,
+        subdomain: &'a str,
+        grantee: &'a Uuid,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let subdomain = subdomain.to_string();
+        let grantee = grantee.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM tunnelto_grants WHERE subdomain = ?1 AND grantee = ?2",
+                params![subdomain, grantee],
+            )
+            .map_err(backend_error)?;
+            Ok(())
+        })
+    }
+
+    fn put_usage_record<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), Error>> {
+        let values = (
+            record.id.to_string(),
+            record.account_id.to_string(),
+            record.kind.as_str(),
+            record.sub_domain.clone(),
+            record.bytes_in as i64,
+            record.bytes_out as i64,
+            record.recorded_at.to_rfc3339(),
+        );
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO tunnelto_usage (record_id, account_id, kind, subdomain, \
+                 bytes_in, bytes_out, recorded_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![values.0, values.1, values.2, values.3, values.4, values.5, values.6],
+            )
+            .map_err(backend_error)?;
+            Ok(())
+        })
+    }
+
+    fn put_history_record<'a>(
+        &'a self,
+        record: &'a HistoryRecord,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let record = record.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO tunnelto_history (account_id, subdomain, \
+                 last_connected_at, client_hostname) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    record.account_id.to_string(),
+                    record.sub_domain,
+                    record.last_connected_at.to_rfc3339(),
+                    record.client_hostname
+                ],
+            )
+            .map_err(backend_error)?;
+            Ok(())
+        })
+    }
+
+    fn get_history<'a>(
+        &'a self,
+        account_id: &'a Uuid,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        let account_id = account_id.to_string();
+        self.call(move |conn| {
+            query_history(
+                conn,
+                "SELECT * FROM tunnelto_history WHERE account_id = ?1",
+                account_id,
+            )
+        })
+    }
+
+    fn get_sub_domain_history<'a>(
+        &'a self,
+        subdomain: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRecord>, Error>> {
+        let subdomain = subdomain.to_string();
+        self.call(move |conn| {
+            query_history(
+                conn,
+                "SELECT * FROM tunnelto_history WHERE subdomain = ?1",
+                subdomain,
+            )
+        })
+    }
+
+    fn acquire_sub_domain_lease<'a>(
+        &'a self,
+        subdomain: &'a str,
+        holder: &'a str,
+        expires_at: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        let subdomain = subdomain.to_string();
+        let holder = holder.to_string();
+        self.call(move |conn| {
+            // the row is only written if the lease is free, expired or already ours
+            let written = conn
+                .execute(
+                    "INSERT INTO tunnelto_leases (subdomain, holder, expires_at) VALUES (?1, ?2, ?3) \
+                     ON CONFLICT (subdomain) DO UPDATE SET holder = ?2, expires_at = ?3 \
+                     WHERE tunnelto_leases.holder = ?2 OR tunnelto_leases.expires_at < ?4",
+                    params![
+                        subdomain,
+                        holder,
+                        sortable_time(&expires_at),
+                        sortable_time(&Utc::now())
+                    ],
+                )
+                .map_err(backend_error)?;
+            Ok(written == 1)
+        })
+    }
+
+    fn release_sub_domain_lease<'a>(
+        &'a self,
+        subdomain: &'a str,
+        holder: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let subdomain = subdomain.to_string();
+        let holder = holder.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM tunnelto_leases WHERE subdomain = ?1 AND holder = ?2",
+                params![subdomain, holder],
+            )
+            .map_err(backend_error)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn service() -> SqliteAuthService {
+        let service = SqliteAuthService::open(Path::new(":memory:")).unwrap();
+        service.init().await.unwrap();
+        service
+    }
+
+    fn claim(account_id: Uuid, domain: &str) -> VerifiedClaim {
+        VerifiedClaim {
+            account_id,
+            domain: domain.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn claims_stay_with_the_first_account() {
+        let service = service().await;
+        let (owner, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+        service
+            .put_verified_claim("acme", &claim(owner, "acme.com"), None)
+            .await
+            .unwrap();
+        // renewing it is fine
+        service
+            .put_verified_claim("acme", &claim(owner, "acme.com"), None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            service
+                .put_verified_claim("acme", &claim(other, "acme.xyz"), None)
+                .await,
+            Err(Error::ClaimedByOther)
+        ));
+        // only when it outranks the claim it's replacing
+        assert!(matches!(
+            service
+                .put_verified_claim("acme", &claim(other, "acme.xyz"), Some("acme.org"))
+                .await,
+            Err(Error::ClaimedByOther)
+        ));
+
+        let stored = service.get_verified_claim("acme").await.unwrap().unwrap();
+        assert_eq!(stored.account_id, owner);
+        assert_eq!(stored.domain, "acme.com");
+    }
+
+    #[tokio::test]
+    async fn claims_replaced_by_outranking_ones() {
+        let service = service().await;
+        let (owner, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+        service
+            .put_verified_claim("acme", &claim(owner, "acme.tk"), None)
+            .await
+            .unwrap();
+        service
+            .put_verified_claim("acme", &claim(other, "acme.com"), Some("acme.tk"))
+            .await
+            .unwrap();
+
+        let stored = service.get_verified_claim("acme").await.unwrap().unwrap();
+        assert_eq!(stored.account_id, other);
+        assert_eq!(stored.domain, "acme.com");
+    }
+
+    #[tokio::test]
+    async fn keys_keep_their_hello_key() {
+        let service = service().await;
+        let account_id = Uuid::new_v4();
+        service
+            .put_auth_key("secret", &account_id, &Entitlements::default())
+            .await
+            .unwrap();
+
+        let key = service.get_key(&key_id("secret")).await.unwrap();
+        assert_eq!(key.account_id, account_id);
+        assert_eq!(key.hello_key, Some(hello_signature::public_key("secret")));
+        assert!(matches!(
+            service.get_key(&key_id("other")).await,
+            Err(Error::AccountNotFound)
+        ));
+    }
+}
@@ -0,0 +1,96 @@
+//! Single-flight sub-domain claims across instances. Checking that nobody serves a
+//! sub-domain and registering the new tunnel for it are two steps, so two clients racing
+//! for the same one on different instances could both pass the check. Before checking,
+//! a client takes a short-lived lease on the sub-domain in the auth backend's store and
+//! gives it up once its tunnel is registered, where every instance sees it: a racer finds
+//! the lease taken and is turned away as if the sub-domain were in use.
+//!
+//! Leases expire after SUB_DOMAIN_LEASE_SECS (10 by default), so a server that dies
+//! mid-handshake doesn't block the sub-domain for long. The in-process backends, which
+//! only ever serve one instance, keep their leases in memory. If the store can't be
+//! reached the handshake goes ahead without one, as it did before leases.
+use crate::{AUTH_DB_SERVICE, CONFIG};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use tunnelto_lib::{ClientId, TunnelError};
+
+lazy_static! {
+    /// the leases of the in-process backends, by sub-domain
+    static ref LOCAL_LEASES: DashMap<String, (String, DateTime<Utc>)> = DashMap::new();
+}
+
+/// Take the lease on an in-process backend, see `AuthService::acquire_sub_domain_lease`
+pub fn acquire_local(sub_domain: &str, holder: &str, expires_at: DateTime<Utc>) -> bool {
+    let mut lease = LOCAL_LEASES
+        .entry(sub_domain.to_string())
+        .or_insert_with(|| (holder.to_string(), expires_at));
+    if lease.0 != holder && lease.1 > Utc::now() {
+        return false;
+    }
+
+    *lease = (holder.to_string(), expires_at);
+    true
+}
+
+/// Give up the lease on an in-process backend, if it's still the holder's
+pub fn release_local(sub_domain: &str, holder: &str) {
+    LOCAL_LEASES.remove_if(sub_domain, |_, (current, _)| current == holder);
+}
+
+/// A lease on a sub-domain, given up when dropped
+#[derive(Debug)]
+pub struct SubDomainLease {
+    sub_domain: String,
+    holder: String,
+}
+
+impl Drop for SubDomainLease {
+    fn drop(&mut self) {
+        let sub_domain = std::mem::take(&mut self.sub_domain);
+        let holder = std::mem::take(&mut self.holder);
+        tokio::spawn(async move {
+            if let Err(e) = AUTH_DB_SERVICE
+                .release_sub_domain_lease(&sub_domain, &holder)
+                .await
+            {
+                // it expires on its own
+                log::debug!("failed to release the lease on {}: {:?}", sub_domain, e);
+            }
+        });
+    }
+}
+
+/// Lease the sub-domain for the client until its tunnel is registered,
+/// `SubDomainInUse` if another client holds it
+pub async fn acquire(
+    sub_domain: &str,
+    client_id: &ClientId,
+) -> Result<Option<SubDomainLease>, TunnelError> {
+    let holder = client_id.to_string();
+    let expires_at = Utc::now()
+        + chrono::Duration::from_std(CONFIG.sub_domain_lease)
+            .unwrap_or_else(|_| chrono::Duration::seconds(10));
+
+    match AUTH_DB_SERVICE
+        .acquire_sub_domain_lease(sub_domain, &holder, expires_at)
+        .await
+    {
+        Ok(true) => Ok(Some(SubDomainLease {
+            sub_domain: sub_domain.to_string(),
+            holder,
+        })),
+        Ok(false) => {
+            log::debug!("invalid client hello: another client is claiming the sub-domain!");
+            Err(TunnelError::SubDomainInUse)
+        }
+        Err(e) => {
+            log::warn!(
+                "failed to lease {}, checking it without a lease: {:?}",
+                sub_domain,
+                e
+            );
+            Ok(None)
+        }
+    }
+}
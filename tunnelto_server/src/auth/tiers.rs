@@ -0,0 +1,142 @@
+//! Account tiers, `free`, `pro` or `team`, stored with the key's entitlements. A tier
+//! caps what the key's own entitlements allow, so a key can be held to less than its
+//! tier but never get more:
+//!
+//! - TIER_<TIER>_MAX_TUNNELS open at once across the account (free 1, pro 5, team 25)
+//! - TIER_<TIER>_MAX_BANDWIDTH bytes/sec per tunnel (free 1MiB, pro 10MiB, team unlimited)
+//! - TIER_<TIER>_CUSTOM_DOMAINS, whether it may pick its sub-domain (free no, the others yes)
+//!
+//! Set a limit to 0 to lift it. Keys without a tier are held to their entitlements only.
+use super::auth_db::Entitlements;
+use crate::CONFIG;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Free,
+    Pro,
+    Team,
+}
+
+impl FromStr for Tier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free" => Ok(Tier::Free),
+            "pro" => Ok(Tier::Pro),
+            "team" => Ok(Tier::Team),
+            _ => Err(format!("unknown tier: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Tier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tier::Free => "free",
+            Tier::Pro => "pro",
+            Tier::Team => "team",
+        }
+    }
+
+    /// The tier the auth backends stored with `as_str`
+    pub fn from_stored(stored: Option<&str>) -> Option<Tier> {
+        stored.and_then(|tier| tier.parse().ok())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TierLimits {
+    /// unlimited if `None`
+    pub max_tunnels: Option<u32>,
+    /// bytes/sec per tunnel, unlimited if `None`
+    pub max_bandwidth: Option<u64>,
+    pub custom_domains: bool,
+}
+
+impl TierLimits {
+    fn from_env(tier: Tier, max_tunnels: u64, max_bandwidth: u64, custom_domains: bool) -> Self {
+        let var = |limit: &str| format!("TIER_{}_{}", tier.as_str().to_uppercase(), limit);
+        let limit = |name: String, default: u64| {
+            let limit = crate::config::env_var(&name)
+                .map(|n| {
+                    n.parse::<u64>()
+                        .unwrap_or_else(|_| panic!("invalid {}={}", name, n))
+                })
+                .unwrap_or(default);
+            Some(limit).filter(|limit| *limit > 0)
+        };
+        let custom_domains_var = var("CUSTOM_DOMAINS");
+        let custom_domains = crate::config::env_var(&custom_domains_var)
+            .map(|b| {
+                b.parse::<bool>()
+                    .unwrap_or_else(|_| panic!("invalid {}={}", custom_domains_var, b))
+            })
+            .unwrap_or(custom_domains);
+
+        TierLimits {
+            max_tunnels: limit(var("MAX_TUNNELS"), max_tunnels).map(|n| n as u32),
+            max_bandwidth: limit(var("MAX_BANDWIDTH"), max_bandwidth),
+            custom_domains,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TierConfig {
+    pub free: TierLimits,
+    pub pro: TierLimits,
+    pub team: TierLimits,
+}
+
+impl TierConfig {
+    pub fn from_env() -> Self {
+        const MIB: u64 = 1024 * 1024;
+        TierConfig {
+            free: TierLimits::from_env(Tier::Free, 1, MIB, false),
+            pro: TierLimits::from_env(Tier::Pro, 5, 10 * MIB, true),
+            team: TierLimits::from_env(Tier::Team, 25, 0, true),
+        }
+    }
+
+    pub fn limits(&self, tier: Tier) -> &TierLimits {
+        match tier {
+            Tier::Free => &self.free,
+            Tier::Pro => &self.pro,
+            Tier::Team => &self.team,
+        }
+    }
+}
+
+/// The smaller of two limits, where `None` is unlimited
+fn tighter<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// The entitlements held to the limits of their tier, if they have one
+pub fn apply(entitlements: Entitlements) -> Entitlements {
+    let limits = match entitlements.tier {
+        Some(tier) => CONFIG.tiers.limits(tier),
+        None => return entitlements,
+    };
+
+    Entitlements {
+        max_tunnels: tighter(entitlements.max_tunnels, limits.max_tunnels),
+        max_bandwidth: tighter(entitlements.max_bandwidth, limits.max_bandwidth),
+        custom_domains: entitlements.custom_domains && limits.custom_domains,
+        ..entitlements
+    }
+}
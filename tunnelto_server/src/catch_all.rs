@@ -0,0 +1,51 @@
+//! Catch-all tunnels, for wildcard routers like a staging environment serving a
+//! deployment per sub-domain. A client opening one with `--catch-all` gets the visitors
+//! of every sub-domain on its base domain, or on all of them if it isn't pinned to one,
+//! that no other tunnel serves. Tunnels for the exact sub-domain always win, on this
+//! instance or another, and a catch-all only takes visitors reaching the instance it's
+//! connected to.
+//!
+//! Only keys with the `catch_all` entitlement may open one, and each base domain has
+//! at most one, besides the unpinned one.
+use super::*;
+use crate::auth_db::Entitlements;
+
+/// The sub-domain catch-all tunnels are shown with, their public url is `*.<base domain>`
+pub const SUB_DOMAIN: &str = "*";
+
+/// Check the client may open a catch-all tunnel on the base domain
+pub fn admit(
+    entitlements: &Entitlements,
+    client_id: &ClientId,
+    base_domain: Option<&String>,
+) -> Result<(), TunnelError> {
+    if !entitlements.catch_all {
+        return Err(TunnelError::KeyRejected(
+            "the key can't open catch-all tunnels".into(),
+        ));
+    }
+
+    // a client reconnecting takes its own place back
+    match Connections::catch_all_on(base_domain) {
+        Some(existing) if &existing.id != client_id => {
+            log::debug!(
+                "invalid client hello: catch-all for {:?} taken by {}",
+                base_domain,
+                &existing.id
+            );
+            Err(TunnelError::SubDomainInUse)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The catch-all tunnel named by `host`, `*` or `*.<base domain>`
+pub fn find(host: &str) -> Option<ConnectedClient> {
+    match host {
+        SUB_DOMAIN => Connections::catch_all_on(None),
+        host => {
+            let base_domain = host.strip_prefix("*.")?.to_string();
+            Connections::catch_all_on(Some(&base_domain))
+        }
+    }
+}
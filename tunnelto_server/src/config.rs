@@ -6,7 +6,27 @@
 //     pub static ref CTRL_PORT: u16 = ctrl_port();
 //     pub static ref NET_PORT: u16 = network_port();
 
+use crate::anonymous::AnonymousTunnels;
+use crate::auth::domain_claims::ClaimPrecedence;
+use crate::auth::auth_db::DynamoConfig;
+use crate::auth::auth_service::AuthBackend;
+use crate::auth::client_certs::ControlTlsConfig;
+use crate::auth::tiers::TierConfig;
 use crate::auth::SigKey;
+use crate::queue::QueueConfig;
+use crate::control_acl::ControlAcl;
+use crate::ext_authz::ExtAuthzConfig;
+use crate::handover::HandoverConfig;
+use crate::handshake_limit::HandshakeLimit;
+use crate::key_lockout::KeyLockout;
+use crate::load_shedding::LoadShedConfig;
+use crate::not_found::NotFoundConfig;
+use crate::ssh::SshConfig;
+use crate::transcripts::TranscriptConfig;
+use crate::siem::{EventClass, SiemConfig};
+use crate::handshake_audit::HandshakeAuditConfig;
+use tunnelto_lib::interpolate::interpolate;
+use tunnelto_lib::{Deprecation, TunnelType};
 
 /// Global service configuration
 pub struct Config {
@@ -15,8 +35,9 @@ pub struct Config {
     ///         foo.bar => *.foo.bar
     pub allowed_hosts: Vec<String>,
 
-    /// What sub-domains do we always block:
-    /// i.e:    dashboard.tunnelto.dev
+    /// What sub-domains do we always block, on every host or on a single one:
+    /// i.e:    dashboard           => dashboard.*
+    ///         www.foo.bar         => www.foo.bar
     pub blocked_sub_domains: Vec<String>,
 
     /// port for remote streams (end users)
@@ -33,31 +54,625 @@ pub struct Config {
 
     /// Instance DNS discovery domain for gossip protocol
     pub gossip_dns_host: Option<String>,
+
+    /// scheme visitors use to reach tunnels (i.e. https behind a TLS proxy)
+    pub public_scheme: String,
+
+    /// port for TLS passthrough streams routed by SNI, disabled if unset
+    pub tls_passthrough_port: Option<u16>,
+
+    /// key operators present (`x-admin-key` header) to use admin endpoints,
+    /// admin endpoints are disabled if unset
+    pub admin_key: Option<String>,
+
+    /// check wildcard DNS for every allowed host on startup
+    pub dns_self_check: bool,
+
+    /// tunnels this instance takes before redirecting new clients elsewhere
+    pub max_instance_tunnels: Option<usize>,
+
+    /// the websocket url clients can use to reach this instance directly,
+    /// i.e: wss://instance-1.tunnelto.dev:5000/wormhole
+    pub public_control_url: Option<String>,
+
+    /// external service deciding on authenticated clients instead of the auth db,
+    /// it is POSTed the key hash and requested sub-domain
+    pub auth_webhook_url: Option<String>,
+
+    /// bearer token we present to the auth webhook
+    pub auth_webhook_secret: Option<String>,
+
+    /// JWKS endpoint with the keys client JWTs are signed with, jwt auth is disabled if unset
+    pub jwt_jwks_url: Option<String>,
+
+    /// required `iss` claim of client JWTs
+    pub jwt_issuer: Option<String>,
+
+    /// required `aud` claim of client JWTs
+    pub jwt_audience: Option<String>,
+
+    /// client id `tunnelto login` uses to get JWTs from the issuer with the device flow
+    pub jwt_login_client_id: Option<String>,
+
+    /// terminate TLS on the control listener (CONTROL_TLS_CERT, CONTROL_TLS_KEY), taking
+    /// client certificates listed in CLIENT_CERTS_FILE, see `client_certs`
+    pub control_tls: Option<ControlTlsConfig>,
+
+    /// how clients without a key are taken, see `anonymous`
+    pub anonymous: AnonymousTunnels,
+
+    /// how competing verified domain claims rank, see `domain_claims`
+    pub claim_precedence: ClaimPrecedence,
+
+    /// the limits of each account tier, see `tiers`
+    pub tiers: TierConfig,
+
+    /// networks tunnel clients may and may not connect from
+    /// (CONTROL_ALLOW_CIDRS, CONTROL_DENY_CIDRS), see `control_acl`
+    pub control_acl: Option<ControlAcl>,
+
+    /// bind the ports so another process can take over, see `handover`
+    pub handover: Option<HandoverConfig>,
+
+    /// failed handshakes after which an IP's control connections are refused for a while,
+    /// see `handshake_limit`
+    pub handshake_limit: Option<HandshakeLimit>,
+
+    /// consecutive failures after which an auth key is refused for a while,
+    /// see `key_lockout`
+    pub key_lockout: Option<KeyLockout>,
+
+    /// refuse auth keys that don't sign the hello, see `signed_hello`,
+    /// REQUIRE_SIGNED_HELLO
+    pub require_signed_hello: bool,
+
+    /// per-tunnel queue of packets waiting for the client's websocket,
+    /// TUNNEL_QUEUE_CAPACITY / TUNNEL_QUEUE_OVERFLOW (block, drop or disconnect)
+    pub tunnel_queue: QueueConfig,
+
+    /// per-stream queue of data waiting for the visitor's socket,
+    /// STREAM_QUEUE_CAPACITY / STREAM_QUEUE_OVERFLOW (block, drop or disconnect)
+    pub stream_queue: QueueConfig,
+
+    /// turn away visitors of low priority tunnels while queues or CPU are past these,
+    /// see `load_shedding`
+    pub load_shedding: Option<LoadShedConfig>,
+
+    /// log internal counts this often (SOAK_INTERVAL, in seconds) to hunt slow leaks
+    pub soak_interval: Option<std::time::Duration>,
+
+    /// look up the keys of open tunnels again this often (KEY_RECHECK_SECS, 0 never),
+    /// closing those whose key expired or was revoked, see `revocation`
+    pub key_recheck_interval: Option<std::time::Duration>,
+
+    /// record tunnel and stream usage of accounts in the auth db (ENABLE_METERING)
+    pub metering: bool,
+
+    /// keep the sub-domains accounts connect on, for `tunnelto history` and
+    /// abuse reports (ENABLE_HISTORY)
+    pub history: bool,
+
+    /// keep request transcripts for accounts that turn them on
+    /// (TRANSCRIPT_DIR, TRANSCRIPT_RETENTION_DAYS), see `transcripts`
+    pub transcripts: Option<TranscriptConfig>,
+
+    /// where usage records are buffered while the auth db is unreachable
+    pub usage_wal_path: std::path::PathBuf,
+
+    /// records are dropped (and an alarm logged) once the buffer is this big
+    pub usage_wal_max_bytes: u64,
+
+    /// Rhai script routing visitor requests (ROUTING_SCRIPT), see `routing_script`
+    pub routing_script: Option<std::path::PathBuf>,
+
+    /// how long the routing script may run per request (ROUTING_SCRIPT_TIMEOUT_MS)
+    pub routing_script_timeout: std::time::Duration,
+
+    /// authorization service asked about every visitor request (EXT_AUTHZ_URL), with
+    /// EXT_AUTHZ_SECRET, EXT_AUTHZ_TIMEOUT_MS, EXT_AUTHZ_FAILURE, EXT_AUTHZ_CACHE_SECS and
+    /// EXT_AUTHZ_HEADERS, see `ext_authz`
+    pub ext_authz: Option<ExtAuthzConfig>,
+
+    /// where accounts and sub-domain reservations are stored (AUTH_BACKEND),
+    /// `dynamodb` by default, `memory` seeded from AUTH_SEED_FILE, `postgres`
+    /// at AUTH_DATABASE_URL, `sqlite` in the file at AUTH_SQLITE_PATH, `redis`
+    /// at AUTH_REDIS_URL, `file` from AUTH_KEYS_FILE or `static` with ALLOWED_AUTH_KEYS
+    pub auth_backend: AuthBackend,
+
+    /// how many key lookups to cache in front of a networked auth backend
+    /// (AUTH_CACHE_SIZE), 0 turns the cache off, see `auth_cache`
+    pub auth_cache_size: usize,
+
+    /// how long a cached key lookup is trusted (AUTH_CACHE_TTL_SECS)
+    pub auth_cache_ttl: std::time::Duration,
+
+    /// how long a client's claim on a sub-domain holds off others while its tunnel is
+    /// registered (SUB_DOMAIN_LEASE_SECS), see `sub_domain_lease`
+    pub sub_domain_lease: std::time::Duration,
+
+    /// how far past expiry reconnect tokens, guest keys and JWTs are still accepted
+    /// (CLOCK_SKEW_SECS), since the clocks they were issued by may drift from ours
+    pub clock_skew: chrono::Duration,
+
+    /// how long a reconnect token stays valid (RECONNECT_TOKEN_TTL_SECS)
+    pub reconnect_token_ttl: chrono::Duration,
+
+    /// how often connected clients are sent a fresh reconnect token
+    /// (RECONNECT_TOKEN_REFRESH_SECS), a quarter of the ttl by default
+    pub reconnect_token_refresh: std::time::Duration,
+
+    /// features this server is phasing out, announced to clients (DEPRECATIONS), a json
+    /// list of `{"feature": .., "message": .., "removed_after": ..}`
+    pub deprecations: Vec<Deprecation>,
+
+    /// how long inspect links stay readable (INSPECT_LINK_TTL_SECS), 0 turns them off,
+    /// see `inspect_links`
+    pub inspect_link_ttl: std::time::Duration,
+
+    /// export security events to a SIEM collector (SIEM_URL), as `json` or `cef`
+    /// (SIEM_FORMAT), only the comma separated classes in SIEM_EVENTS if set, see `siem`
+    pub siem: Option<SiemConfig>,
+
+    /// record every handshake attempt to stdout, a file or a DynamoDB table
+    /// (HANDSHAKE_AUDIT), see `handshake_audit`
+    pub handshake_audit: Option<HandshakeAuditConfig>,
+
+    /// what visitors get when no tunnel matches the host (NOT_FOUND_RESPONSE), per base
+    /// domain in NOT_FOUND_OVERRIDES, see `not_found`
+    pub not_found: NotFoundConfig,
+
+    /// take `ssh -R` reverse forwards on SSH_PORT from the keys listed in
+    /// SSH_AUTHORIZED_KEYS, see `ssh`
+    pub ssh: Option<SshConfig>,
 }
 
 impl Config {
+    /// The public urls a sub-domain is reachable on
+    pub fn public_urls(
+        &self,
+        sub_domain: &str,
+        base_domain: Option<&String>,
+        tunnel_type: TunnelType,
+    ) -> Vec<String> {
+        let (scheme, port) = match (tunnel_type, self.tls_passthrough_port) {
+            (TunnelType::TlsPassthrough, Some(port)) if port != 443 => {
+                ("https", format!(":{}", port))
+            }
+            (TunnelType::TlsPassthrough, _) => ("https", String::new()),
+            (TunnelType::Http, _) => (self.public_scheme.as_str(), String::new()),
+        };
+
+        self.allowed_hosts
+            .iter()
+            .filter(|host| base_domain.is_none_or(|base| base == *host))
+            .map(|host| format!("{}://{}.{}{}", scheme, sub_domain, host, port))
+            .collect()
+    }
+
+    /// Is the sub-domain blocked on the base domain it will be served on,
+    /// or any base domain if it isn't pinned to one
+    pub fn is_blocked(&self, sub_domain: &str, base_domain: Option<&String>) -> bool {
+        let bases = match base_domain {
+            Some(base) => vec![base],
+            None => self.allowed_hosts.iter().collect(),
+        };
+
+        self.blocked_sub_domains.iter().any(|blocked| {
+            blocked == sub_domain
+                || bases
+                    .iter()
+                    .any(|base| blocked == &format!("{}.{}", sub_domain, base))
+        })
+    }
+
+    /// Does the presented key grant access to admin endpoints
+    pub fn is_admin(&self, presented: Option<&str>) -> bool {
+        match (self.admin_key.as_deref(), presented) {
+            (Some(admin_key), Some(presented)) => {
+                tunnelto_lib::verify::constant_time_eq(admin_key.as_bytes(), presented.as_bytes())
+            }
+            _ => false,
+        }
+    }
+
+    /// Print the effective configuration, hiding secrets if `redact` is set.
+    /// The signature key is never printed.
+    pub fn print(&self, redact: bool) {
+        let secret = |value: &Option<String>| match value {
+            Some(_) if redact => "<redacted>".to_string(),
+            value => format!("{:?}", value),
+        };
+
+        println!("allowed_hosts: {:?}", self.allowed_hosts);
+        println!("blocked_sub_domains: {:?}", self.blocked_sub_domains);
+        println!("remote_port: {}", self.remote_port);
+        println!("control_port: {}", self.control_port);
+        println!("internal_network_port: {}", self.internal_network_port);
+        println!("master_sig_key: {:?}", self.master_sig_key);
+        println!("gossip_dns_host: {:?}", self.gossip_dns_host);
+        println!("public_scheme: {}", self.public_scheme);
+        println!("tls_passthrough_port: {:?}", self.tls_passthrough_port);
+        println!("admin_key: {}", secret(&self.admin_key));
+        println!("dns_self_check: {}", self.dns_self_check);
+        println!("max_instance_tunnels: {:?}", self.max_instance_tunnels);
+        println!("public_control_url: {:?}", self.public_control_url);
+        println!("auth_webhook_url: {:?}", self.auth_webhook_url);
+        println!("auth_webhook_secret: {}", secret(&self.auth_webhook_secret));
+        println!("jwt_jwks_url: {:?}", self.jwt_jwks_url);
+        println!("jwt_issuer: {:?}", self.jwt_issuer);
+        println!("jwt_audience: {:?}", self.jwt_audience);
+        println!("jwt_login_client_id: {:?}", self.jwt_login_client_id);
+        println!("control_tls: {:?}", self.control_tls);
+        println!("anonymous: {:?}", self.anonymous);
+        println!("claim_precedence: {:?}", self.claim_precedence);
+        println!("tiers: {:?}", self.tiers);
+        match &self.control_acl {
+            Some(acl) => println!(
+                "control_acl: allow={:?} deny={:?}",
+                acl.allow.iter().map(|cidr| String::from(*cidr)).collect::<Vec<_>>(),
+                acl.deny.iter().map(|cidr| String::from(*cidr)).collect::<Vec<_>>()
+            ),
+            None => println!("control_acl: None"),
+        }
+        println!("handover: {:?}", self.handover);
+        println!("handshake_limit: {:?}", self.handshake_limit);
+        println!("key_lockout: {:?}", self.key_lockout);
+        println!("require_signed_hello: {}", self.require_signed_hello);
+        println!("tunnel_queue: {:?}", self.tunnel_queue);
+        println!("stream_queue: {:?}", self.stream_queue);
+        println!("load_shedding: {:?}", self.load_shedding);
+        println!("soak_interval: {:?}", self.soak_interval);
+        println!("key_recheck_interval: {:?}", self.key_recheck_interval);
+        println!("metering: {}", self.metering);
+        println!("history: {}", self.history);
+        println!("transcripts: {:?}", self.transcripts);
+        println!("usage_wal_path: {:?}", self.usage_wal_path);
+        println!("usage_wal_max_bytes: {}", self.usage_wal_max_bytes);
+        println!("routing_script: {:?}", self.routing_script);
+        println!("routing_script_timeout: {:?}", self.routing_script_timeout);
+        match &self.ext_authz {
+            Some(authz) => println!(
+                "ext_authz: {:?} secret={} timeout={:?} fail_open={} cache_ttl={:?} headers={:?}",
+                authz.url,
+                secret(&authz.secret),
+                authz.timeout,
+                authz.fail_open,
+                authz.cache_ttl,
+                authz.headers
+            ),
+            None => println!("ext_authz: None"),
+        }
+        match &self.auth_backend {
+            AuthBackend::DynamoDb(dynamo) => println!(
+                "auth_backend: DynamoDb region={} endpoint={} tables={:?} attributes={:?}",
+                dynamo.region.name(),
+                match &dynamo.region {
+                    rusoto_core::Region::Custom { endpoint, .. } => endpoint.as_str(),
+                    _ => "aws",
+                },
+                dynamo.tables,
+                dynamo.attributes
+            ),
+            // the url may hold the database password
+            AuthBackend::Postgres { .. } => println!("auth_backend: Postgres (AUTH_DATABASE_URL)"),
+            AuthBackend::Redis { prefix, .. } => {
+                println!("auth_backend: Redis (AUTH_REDIS_URL) prefix={:?}", prefix)
+            }
+            AuthBackend::Static { keys } if redact => {
+                println!("auth_backend: Static (ALLOWED_AUTH_KEYS) keys=<{} redacted>", keys.len())
+            }
+            backend => println!("auth_backend: {:?}", backend),
+        }
+        println!("auth_cache_size: {}", self.auth_cache_size);
+        println!("auth_cache_ttl: {:?}", self.auth_cache_ttl);
+        println!("sub_domain_lease: {:?}", self.sub_domain_lease);
+        println!("clock_skew: {}s", self.clock_skew.num_seconds());
+        println!(
+            "reconnect_token_ttl: {}s",
+            self.reconnect_token_ttl.num_seconds()
+        );
+        println!(
+            "reconnect_token_refresh: {:?}",
+            self.reconnect_token_refresh
+        );
+        println!("deprecations: {:?}", self.deprecations);
+        println!("inspect_link_ttl: {:?}", self.inspect_link_ttl);
+        match &self.siem {
+            // the collector url may hold a token
+            Some(siem) if redact => println!(
+                "siem: <redacted> format={:?} events={:?}",
+                siem.format, siem.classes
+            ),
+            Some(siem) => println!(
+                "siem: {:?} format={:?} events={:?}",
+                siem.target, siem.format, siem.classes
+            ),
+            None => println!("siem: None"),
+        }
+        println!("handshake_audit: {:?}", self.handshake_audit);
+        println!(
+            "not_found: {} overrides={:?}",
+            self.not_found.default, self.not_found.overrides
+        );
+        println!("ssh: {:?}", self.ssh);
+    }
+
     pub fn from_env() -> Config {
-        let allowed_hosts = std::env::var("ALLOWED_HOSTS")
+        let allowed_hosts = env_var("ALLOWED_HOSTS")
             .map(|s| s.split(",").map(String::from).collect())
             .unwrap_or(vec![]);
 
-        let blocked_sub_domains = std::env::var("BLOCKED_SUB_DOMAINS")
+        let blocked_sub_domains = env_var("BLOCKED_SUB_DOMAINS")
             .map(|s| s.split(",").map(String::from).collect())
             .unwrap_or(vec![]);
 
-        let master_sig_key = if let Ok(key) = std::env::var("MASTER_SIG_KEY") {
+        let master_sig_key = if let Ok(key) = env_var("MASTER_SIG_KEY") {
             SigKey::from_hex(&key).expect("invalid master key: not hex or length incorrect")
         } else {
             log::warn!("WARNING! generating ephemeral signature key!");
             SigKey::generate()
         };
 
-        let gossip_dns_host = if let Some(app_name) = std::env::var("FLY_APP_NAME").ok() {
-            Some(format!("global.{}.internal", app_name))
-        } else {
-            None
+        let gossip_dns_host = env_var("FLY_APP_NAME")
+            .ok()
+            .map(|app_name| format!("global.{}.internal", app_name));
+
+        let public_scheme = env_var("PUBLIC_SCHEME").unwrap_or("https".to_string());
+
+        let tls_passthrough_port = env_var("TLS_PASSTHROUGH_PORT")
+            .ok()
+            .map(|_| get_port("TLS_PASSTHROUGH_PORT", 443));
+
+        let admin_key = env_var("ADMIN_KEY").ok().filter(|k| !k.is_empty());
+        let dns_self_check = env_var("SKIP_DNS_SELF_CHECK").is_err();
+
+        let max_instance_tunnels = env_var("MAX_INSTANCE_TUNNELS").ok().map(|n| {
+            n.parse()
+                .unwrap_or_else(|_| panic!("invalid MAX_INSTANCE_TUNNELS={}", n))
+        });
+        let public_control_url = env_var("PUBLIC_CONTROL_URL").ok();
+
+        let auth_webhook_url = env_var("AUTH_WEBHOOK_URL").ok();
+        if let Some(url) = auth_webhook_url.as_ref() {
+            if !url.starts_with("https://") {
+                log::warn!("WARNING! auth webhook is not using https: {}", url);
+            }
+        }
+        let auth_webhook_secret = env_var("AUTH_WEBHOOK_SECRET").ok();
+
+        let soak_interval = env_var("SOAK_INTERVAL").ok().map(|n| {
+            std::time::Duration::from_secs(
+                n.parse()
+                    .unwrap_or_else(|_| panic!("invalid SOAK_INTERVAL={}", n)),
+            )
+        });
+
+        let key_recheck_interval = match env_var("KEY_RECHECK_SECS") {
+            Ok(n) => match n.parse() {
+                Ok(0) => None,
+                Ok(secs) => Some(std::time::Duration::from_secs(secs)),
+                Err(_) => panic!("invalid KEY_RECHECK_SECS={}", n),
+            },
+            Err(_) => Some(std::time::Duration::from_secs(60)),
+        };
+
+        let metering = env_var("ENABLE_METERING").is_ok();
+        let history = env_var("ENABLE_HISTORY").is_ok();
+        let usage_wal_path = env_var("USAGE_WAL_PATH")
+            .unwrap_or("usage.wal".to_string())
+            .into();
+        let usage_wal_max_bytes = env_var("USAGE_WAL_MAX_BYTES")
+            .map(|n| {
+                n.parse()
+                    .unwrap_or_else(|_| panic!("invalid USAGE_WAL_MAX_BYTES={}", n))
+            })
+            .unwrap_or(64 * 1024 * 1024);
+
+        let routing_script = env_var("ROUTING_SCRIPT").ok().map(Into::into);
+        let routing_script_timeout = std::time::Duration::from_millis(
+            env_var("ROUTING_SCRIPT_TIMEOUT_MS")
+                .map(|n| {
+                    n.parse()
+                        .unwrap_or_else(|_| panic!("invalid ROUTING_SCRIPT_TIMEOUT_MS={}", n))
+                })
+                .unwrap_or(10),
+        );
+
+        let ext_authz = env_var("EXT_AUTHZ_URL").ok().map(|url| {
+            if !url.starts_with("https://") {
+                log::warn!("WARNING! authorization service is not using https: {}", url);
+            }
+            ExtAuthzConfig {
+                url,
+                secret: env_var("EXT_AUTHZ_SECRET").ok(),
+                timeout: std::time::Duration::from_millis(
+                    env_var("EXT_AUTHZ_TIMEOUT_MS")
+                        .map(|n| {
+                            n.parse()
+                                .unwrap_or_else(|_| panic!("invalid EXT_AUTHZ_TIMEOUT_MS={}", n))
+                        })
+                        .unwrap_or(1000),
+                ),
+                fail_open: match env_var("EXT_AUTHZ_FAILURE").as_deref() {
+                    Ok("open") => true,
+                    Ok("closed") | Err(_) => false,
+                    Ok(other) => panic!("invalid EXT_AUTHZ_FAILURE={}, expected open or closed", other),
+                },
+                cache_ttl: std::time::Duration::from_secs(
+                    env_var("EXT_AUTHZ_CACHE_SECS")
+                        .map(|n| {
+                            n.parse()
+                                .unwrap_or_else(|_| panic!("invalid EXT_AUTHZ_CACHE_SECS={}", n))
+                        })
+                        .unwrap_or(10),
+                ),
+                headers: env_var("EXT_AUTHZ_HEADERS")
+                    .unwrap_or_else(|_| "authorization,cookie".to_string())
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect(),
+            }
+        });
+
+        let clock_skew = chrono::Duration::seconds(
+            env_var("CLOCK_SKEW_SECS")
+                .map(|n| {
+                    n.parse()
+                        .unwrap_or_else(|_| panic!("invalid CLOCK_SKEW_SECS={}", n))
+                })
+                .unwrap_or(60),
+        );
+
+        let reconnect_token_ttl: u64 = env_var("RECONNECT_TOKEN_TTL_SECS")
+            .map(|n| {
+                n.parse()
+                    .ok()
+                    .filter(|ttl| *ttl > 0)
+                    .unwrap_or_else(|| panic!("invalid RECONNECT_TOKEN_TTL_SECS={}", n))
+            })
+            .unwrap_or(120);
+        // a token has to be replaced before it expires, or a client that loses its
+        // connection right then can't get its tunnel back
+        let reconnect_token_refresh: u64 = env_var("RECONNECT_TOKEN_REFRESH_SECS")
+            .map(|n| {
+                n.parse()
+                    .ok()
+                    .filter(|refresh| *refresh > 0 && *refresh < reconnect_token_ttl)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "invalid RECONNECT_TOKEN_REFRESH_SECS={}, it must be under the ttl of {}s",
+                            n, reconnect_token_ttl
+                        )
+                    })
+            })
+            .unwrap_or((reconnect_token_ttl / 4).max(1));
+        let reconnect_token_ttl = chrono::Duration::seconds(reconnect_token_ttl as i64);
+        let reconnect_token_refresh = std::time::Duration::from_secs(reconnect_token_refresh);
+
+        let deprecations = env_var("DEPRECATIONS")
+            .map(|json| {
+                serde_json::from_str(&json)
+                    .unwrap_or_else(|e| panic!("invalid DEPRECATIONS: {}", e))
+            })
+            .unwrap_or_default();
+
+        let auth_backend = match env_var("AUTH_BACKEND") {
+            Ok(backend) => backend
+                .parse()
+                .unwrap_or_else(|e| panic!("invalid AUTH_BACKEND: {}", e)),
+            // a list of keys is enough to run without any database
+            Err(_) if env_var("ALLOWED_AUTH_KEYS").is_ok() => AuthBackend::Static { keys: vec![] },
+            Err(_) => AuthBackend::DynamoDb(Default::default()),
+        };
+        let auth_backend = match auth_backend {
+            AuthBackend::DynamoDb(_) => AuthBackend::DynamoDb(DynamoConfig::from_env()),
+            AuthBackend::Memory { .. } => AuthBackend::Memory {
+                seed_file: env_var("AUTH_SEED_FILE").ok().map(Into::into),
+            },
+            AuthBackend::Postgres { .. } => AuthBackend::Postgres {
+                url: env_var("AUTH_DATABASE_URL")
+                    .expect("AUTH_BACKEND=postgres requires AUTH_DATABASE_URL"),
+            },
+            AuthBackend::Sqlite { .. } => AuthBackend::Sqlite {
+                path: env_var("AUTH_SQLITE_PATH")
+                    .expect("AUTH_BACKEND=sqlite requires AUTH_SQLITE_PATH")
+                    .into(),
+            },
+            AuthBackend::Redis { .. } => AuthBackend::Redis {
+                url: env_var("AUTH_REDIS_URL").expect("AUTH_BACKEND=redis requires AUTH_REDIS_URL"),
+                prefix: env_var("AUTH_REDIS_PREFIX").unwrap_or_else(|_| "tunnelto:".to_string()),
+            },
+            AuthBackend::File { .. } => AuthBackend::File {
+                path: env_var("AUTH_KEYS_FILE")
+                    .expect("AUTH_BACKEND=file requires AUTH_KEYS_FILE")
+                    .into(),
+            },
+            AuthBackend::Static { .. } => {
+                let keys: Vec<String> = env_var("ALLOWED_AUTH_KEYS")
+                    .expect("AUTH_BACKEND=static requires ALLOWED_AUTH_KEYS")
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(String::from)
+                    .collect();
+                if keys.is_empty() {
+                    panic!("ALLOWED_AUTH_KEYS lists no keys");
+                }
+                AuthBackend::Static { keys }
+            }
         };
 
+        let auth_cache_size = env_var("AUTH_CACHE_SIZE")
+            .map(|n| {
+                n.parse()
+                    .unwrap_or_else(|_| panic!("invalid AUTH_CACHE_SIZE={}", n))
+            })
+            .unwrap_or(10_000);
+        let auth_cache_ttl = std::time::Duration::from_secs(
+            env_var("AUTH_CACHE_TTL_SECS")
+                .map(|n| {
+                    n.parse()
+                        .unwrap_or_else(|_| panic!("invalid AUTH_CACHE_TTL_SECS={}", n))
+                })
+                .unwrap_or(60),
+        );
+        let sub_domain_lease = std::time::Duration::from_secs(
+            env_var("SUB_DOMAIN_LEASE_SECS")
+                .map(|n| {
+                    n.parse()
+                        .ok()
+                        .filter(|secs| *secs > 0)
+                        .unwrap_or_else(|| panic!("invalid SUB_DOMAIN_LEASE_SECS={}", n))
+                })
+                .unwrap_or(10),
+        );
+
+        let inspect_link_ttl = std::time::Duration::from_secs(
+            env_var("INSPECT_LINK_TTL_SECS")
+                .map(|n| {
+                    n.parse()
+                        .unwrap_or_else(|_| panic!("invalid INSPECT_LINK_TTL_SECS={}", n))
+                })
+                .unwrap_or(3600),
+        );
+
+        let siem = env_var("SIEM_URL").ok().map(|url| SiemConfig {
+            target: url
+                .parse()
+                .unwrap_or_else(|e| panic!("invalid SIEM_URL: {}", e)),
+            format: env_var("SIEM_FORMAT")
+                .map(|format| {
+                    format
+                        .parse()
+                        .unwrap_or_else(|e| panic!("invalid SIEM_FORMAT: {}", e))
+                })
+                .unwrap_or_default(),
+            classes: env_var("SIEM_EVENTS")
+                .map(|classes| {
+                    classes
+                        .split(',')
+                        .map(|class| {
+                            class
+                                .parse()
+                                .unwrap_or_else(|e| panic!("invalid SIEM_EVENTS: {}", e))
+                        })
+                        .collect()
+                })
+                .unwrap_or_else(|_| EventClass::all()),
+        });
+
+        let jwt_jwks_url = env_var("JWT_JWKS_URL").ok();
+        let jwt_issuer = env_var("JWT_ISSUER").ok();
+        let jwt_audience = env_var("JWT_AUDIENCE").ok();
+        let jwt_login_client_id = env_var("JWT_LOGIN_CLIENT_ID").ok().filter(|_| {
+            let usable = jwt_issuer.is_some() && jwt_jwks_url.is_some();
+            if !usable {
+                log::warn!("WARNING! JWT_LOGIN_CLIENT_ID needs JWT_ISSUER and JWT_JWKS_URL, ignoring it");
+            }
+            usable
+        });
+
         Config {
             allowed_hosts,
             blocked_sub_domains,
@@ -66,12 +681,67 @@ impl Config {
             internal_network_port: get_port("NET_PORT", 6000),
             master_sig_key,
             gossip_dns_host,
+            public_scheme,
+            tls_passthrough_port,
+            admin_key,
+            dns_self_check,
+            max_instance_tunnels,
+            public_control_url,
+            auth_webhook_url,
+            auth_webhook_secret,
+            jwt_jwks_url,
+            jwt_issuer,
+            jwt_audience,
+            jwt_login_client_id,
+            control_tls: ControlTlsConfig::from_env(),
+            anonymous: AnonymousTunnels::from_env(),
+            claim_precedence: ClaimPrecedence::from_env(),
+            tiers: TierConfig::from_env(),
+            control_acl: ControlAcl::from_env(),
+            handover: HandoverConfig::from_env(),
+            handshake_limit: HandshakeLimit::from_env(),
+            key_lockout: KeyLockout::from_env(),
+            require_signed_hello: env_var("REQUIRE_SIGNED_HELLO").is_ok(),
+            tunnel_queue: QueueConfig::from_env("TUNNEL_QUEUE", 1024),
+            stream_queue: QueueConfig::from_env("STREAM_QUEUE", 256),
+            load_shedding: LoadShedConfig::from_env(),
+            soak_interval,
+            key_recheck_interval,
+            metering,
+            history,
+            transcripts: TranscriptConfig::from_env(),
+            usage_wal_path,
+            usage_wal_max_bytes,
+            routing_script,
+            routing_script_timeout,
+            ext_authz,
+            auth_backend,
+            auth_cache_size,
+            auth_cache_ttl,
+            sub_domain_lease,
+            clock_skew,
+            reconnect_token_ttl,
+            reconnect_token_refresh,
+            deprecations,
+            inspect_link_ttl,
+            siem,
+            handshake_audit: HandshakeAuditConfig::from_env(),
+            not_found: NotFoundConfig::from_env(),
+            ssh: SshConfig::from_env(),
         }
     }
 }
 
+/// Read an environment variable, interpolating references to other variables
+/// and secret files in its value, see `tunnelto_lib::interpolate`
+pub fn env_var(name: &str) -> Result<String, std::env::VarError> {
+    std::env::var(name).map(|value| {
+        interpolate(&value).unwrap_or_else(|e| panic!("invalid {}: {}", name, e))
+    })
+}
+
 fn get_port(var: &'static str, default: u16) -> u16 {
-    if let Ok(port) = std::env::var(var) {
+    if let Ok(port) = env_var(var) {
         port.parse().unwrap_or_else(|_| {
             panic!("invalid port ENV {}={}", var, port);
         })
@@ -1,17 +1,64 @@
 use super::*;
+use crate::auth::approvals::PendingApproval;
+use crate::auth_db::Entitlements;
+use crate::rate_limiter::{Bandwidth, RateLimiter};
 use dashmap::DashMap;
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct ConnectedClient {
     pub id: ClientId,
     pub host: String,
     pub is_anonymous: bool,
-    pub tx: UnboundedSender<ControlPacket>,
+    pub account_id: Option<Uuid>,
+    pub entitlements: Entitlements,
+    pub base_domain: Option<String>,
+    pub tunnel_type: TunnelType,
+    /// sign forwarded requests with this secret
+    pub signing_secret: Option<String>,
+    /// only let matching visitors reach these paths
+    pub access_rules: Vec<acl::AccessRule>,
+    /// hold visitors to the request rates the tunnel asked for
+    pub rate_limits: Arc<RateLimiter>,
+    /// hold the tunnel's data to its entitlements' `max_bandwidth`
+    pub bandwidth: Option<Arc<Bandwidth>>,
+    /// anonymous tunnels are closed then, see `anonymous`
+    pub session_ends: Option<chrono::DateTime<chrono::Utc>>,
+    /// how errors answered on the tunnel's behalf are rendered
+    pub error_format: ErrorFormat,
+    /// registered as a passive standby: it only serves its host while no other client does
+    pub standby: bool,
+    /// what the tunnel declared it carries, see `traffic::tuning`
+    pub traffic_profile: TrafficProfile,
+    /// exchange stream trailers with the client, see `tunnelto_lib::integrity`
+    pub integrity: bool,
+    /// stream the edge's access log for the tunnel to the client, see `access_log`
+    pub access_log: bool,
+    /// reconnects when asked before the server is replaced, see `handover`
+    pub handover: bool,
+    /// held until a teammate approves it, see `approvals`
+    pub approval: Option<Arc<PendingApproval>>,
+    /// serves the sub-domains of its base domain no other tunnel does, see `catch_all`
+    pub catch_all: bool,
+    pub tx: QueueSender<ControlPacket>,
+}
+
+impl ConnectedClient {
+    /// Held by the account's approval policy and not approved yet
+    pub fn awaiting_approval(&self) -> bool {
+        self.approval
+            .as_ref()
+            .is_some_and(|approval| !approval.is_approved())
+    }
 }
 
 pub struct Connections {
     clients: Arc<DashMap<ClientId, ConnectedClient>>,
     hosts: Arc<DashMap<String, ConnectedClient>>,
+    /// standbys waiting to take over a host
+    standbys: Arc<DashMap<String, ConnectedClient>>,
+    /// catch-all tunnels by the base domain they're pinned to
+    catch_alls: Arc<DashMap<Option<String>, ConnectedClient>>,
 }
 
 impl Connections {
@@ -19,6 +66,8 @@ impl Connections {
         Self {
             clients: Arc::new(DashMap::new()),
             hosts: Arc::new(DashMap::new()),
+            standbys: Arc::new(DashMap::new()),
+            catch_alls: Arc::new(DashMap::new()),
         }
     }
 
@@ -30,18 +79,55 @@ impl Connections {
 
     pub fn remove(client: &ConnectedClient) {
         client.tx.close_channel();
+        Self::release(client);
+    }
 
+    /// Stop routing visitors to the client and free its host, while its connection
+    /// stays open to finish the streams under way
+    pub fn release(client: &ConnectedClient) {
         // ensure another client isn't using this host
         if CONNECTIONS
             .hosts
             .get(&client.host)
-            .map_or(false, |c| c.id == client.id)
+            .is_some_and(|c| c.id == client.id)
         {
             log::debug!("dropping sub-domain: {}", &client.host);
             CONNECTIONS.hosts.remove(&client.host);
+
+            // fail over to the standby, if one is waiting
+            if let Some((_, standby)) = CONNECTIONS.standbys.remove(&client.host) {
+                log::info!(
+                    "failing over {} from {} to standby {}",
+                    &client.host,
+                    &client.id,
+                    &standby.id
+                );
+                CONNECTIONS.hosts.insert(standby.host.clone(), standby);
+            }
         };
 
-        CONNECTIONS.clients.remove(&client.id);
+        if CONNECTIONS
+            .standbys
+            .get(&client.host)
+            .is_some_and(|c| c.id == client.id)
+        {
+            CONNECTIONS.standbys.remove(&client.host);
+        }
+
+        if client.catch_all
+            && CONNECTIONS
+                .catch_alls
+                .get(&client.base_domain)
+                .is_some_and(|c| c.id == client.id)
+        {
+            log::debug!("dropping catch-all for {:?}", &client.base_domain);
+            CONNECTIONS.catch_alls.remove(&client.base_domain);
+        }
+
+        if CONNECTIONS.clients.remove(&client.id).is_some() {
+            crate::metering::tunnel_closed(client);
+            crate::siem::tunnel_closed(client);
+        }
         log::debug!("rm client: {}", &client.id);
 
         // // drop all the streams
@@ -61,18 +147,110 @@ impl Connections {
     pub fn get(client_id: &ClientId) -> Option<ConnectedClient> {
         CONNECTIONS
             .clients
-            .get(&client_id)
+            .get(client_id)
+            .map(|c| c.value().clone())
+    }
+
+    /// number of tunnels this instance serves for the account, not counting standbys
+    pub fn count_for_account(account_id: &Uuid) -> usize {
+        CONNECTIONS
+            .clients
+            .iter()
+            .filter(|c| c.account_id.as_ref() == Some(account_id) && !c.standby)
+            .count()
+    }
+
+    pub fn all() -> Vec<ConnectedClient> {
+        CONNECTIONS
+            .clients
+            .iter()
             .map(|c| c.value().clone())
+            .collect()
+    }
+
+    /// number of tunnels this instance serves
+    pub fn count() -> usize {
+        CONNECTIONS.clients.len()
+    }
+
+    pub fn count_anonymous() -> usize {
+        CONNECTIONS
+            .clients
+            .iter()
+            .filter(|c| c.is_anonymous)
+            .count()
+    }
+
+    /// number of sub-domains this instance routes
+    pub fn host_count() -> usize {
+        CONNECTIONS.hosts.len()
     }
 
     pub fn find_by_host(host: &String) -> Option<ConnectedClient> {
         CONNECTIONS.hosts.get(host).map(|c| c.value().clone())
     }
 
+    /// The catch-all tunnel pinned to the base domain, or the unpinned one for `None`
+    pub fn catch_all_on(base_domain: Option<&String>) -> Option<ConnectedClient> {
+        CONNECTIONS
+            .catch_alls
+            .get(&base_domain.cloned())
+            .map(|c| c.value().clone())
+    }
+
+    /// The catch-all tunnel for visitors of the base domain, pinned to it or not
+    pub fn catch_all_for(base_domain: Option<&String>) -> Option<ConnectedClient> {
+        base_domain
+            .and_then(|base_domain| Self::catch_all_on(Some(base_domain)))
+            .or_else(|| Self::catch_all_on(None))
+    }
+
+    /// Register the client and route its host to it. A standby only gets the host
+    /// while no other client serves it, and hands it back when that client returns.
+    /// A catch-all gets the hosts of its base domain no other client serves.
     pub fn add(client: ConnectedClient) {
         CONNECTIONS
             .clients
             .insert(client.id.clone(), client.clone());
+
+        if client.catch_all {
+            CONNECTIONS
+                .catch_alls
+                .insert(client.base_domain.clone(), client);
+            return;
+        }
+
+        let active = CONNECTIONS
+            .hosts
+            .get(&client.host)
+            .map(|c| c.value().clone())
+            .filter(|c| c.id != client.id);
+
+        match active {
+            Some(_) if client.standby => {
+                CONNECTIONS.standbys.insert(client.host.clone(), client);
+                return;
+            }
+            Some(active) if active.standby => {
+                log::info!(
+                    "{} is back, returning {} from standby {}",
+                    &client.id,
+                    &client.host,
+                    &active.id
+                );
+                CONNECTIONS.standbys.insert(active.host.clone(), active);
+            }
+            _ => {}
+        }
+
         CONNECTIONS.hosts.insert(client.host.clone(), client);
     }
+
+    /// Whether the client is a standby, serving its host or waiting to
+    pub fn is_standby(client_id: &ClientId) -> bool {
+        CONNECTIONS
+            .clients
+            .get(client_id)
+            .is_some_and(|c| c.standby)
+    }
 }
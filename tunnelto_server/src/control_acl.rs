@@ -0,0 +1,81 @@
+//! Only take control connections from known networks, for private servers:
+//! CONTROL_ALLOW_CIDRS lists the networks tunnel clients may connect from (anywhere when
+//! unset) and CONTROL_DENY_CIDRS the ones they may not, which wins over the allow list.
+//! Both are comma separated, i.e. `10.0.0.0/8,192.168.1.7`.
+//!
+//! Connections are checked before the client's hello is read, on the control port and
+//! on `wormhole.` connections relayed from the public port. Relayed ones reach the
+//! control port from localhost, so loopback peers there are only checked by the relay.
+use super::*;
+use std::net::IpAddr;
+use tunnelto_lib::acl::Cidr;
+
+#[derive(Debug, Clone)]
+pub struct ControlAcl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl ControlAcl {
+    /// Read the lists from the env, `None` if neither is set
+    pub fn from_env() -> Option<Self> {
+        let networks = |name: &str| -> Vec<Cidr> {
+            crate::config::env_var(name)
+                .map(|list| {
+                    list.split(',')
+                        .filter(|cidr| !cidr.trim().is_empty())
+                        .map(|cidr| {
+                            cidr.parse()
+                                .unwrap_or_else(|e| panic!("invalid {}: {}", name, e))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let allow = networks("CONTROL_ALLOW_CIDRS");
+        let deny = networks("CONTROL_DENY_CIDRS");
+        if allow.is_empty() && deny.is_empty() {
+            return None;
+        }
+        Some(ControlAcl { allow, deny })
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// Whether a tunnel client may connect from `peer`, unknown peers only if nothing is
+/// restricted
+pub fn allowed(peer: Option<IpAddr>) -> bool {
+    let acl = match CONFIG.control_acl.as_ref() {
+        Some(acl) => acl,
+        None => return true,
+    };
+    let allowed = peer.is_some_and(|ip| acl.allows(ip));
+    if !allowed {
+        log::warn!("refusing control connection from {:?}", peer);
+    }
+    allowed
+}
+
+/// Like `allowed`, for a peer of the control port, where relayed connections come
+/// from localhost
+pub fn allowed_direct(peer: Option<IpAddr>) -> bool {
+    match peer {
+        Some(ip) if ip.is_loopback() => true,
+        peer => allowed(peer),
+    }
+}
+
+/// Answer to an upgrade from a network that isn't allowed
+pub fn refused() -> impl warp::Reply {
+    warp::reply::with_status(
+        "Tunnel clients aren't accepted from this network",
+        warp::http::StatusCode::FORBIDDEN,
+    )
+}
@@ -1,40 +1,237 @@
 pub use super::*;
+use crate::auth::client_certs::ClientCert;
 use crate::auth::reconnect_token::ReconnectTokenPayload;
 use crate::client_auth::ClientHandshake;
-use chrono::Utc;
+use crate::data_connection::DataConnection;
+use crate::rate_limiter::{Bandwidth, RateLimiter};
+use chrono::{DateTime, Utc};
+use openssl::ssl::SslAcceptor;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
-pub fn spawn<A: Into<SocketAddr>>(addr: A) {
+pub fn spawn<A: Into<SocketAddr>>(addr: A, tls: Option<SslAcceptor>) {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         log::info!("Health Check #2 triggered");
         "ok"
     });
+    let data_conn = warp::path!("wormhole" / "data")
+        .and(warp::ws())
+        .map(move |ws: Ws| ws.on_upgrade(crate::data_connection::handle_data_connection));
     let client_conn = warp::path("wormhole")
         .and(warp::ws())
-        .map(move |ws: Ws| ws.on_upgrade(handle_new_connection));
+        .and(warp::ext::optional::<PeerAddr>())
+        .and(warp::ext::optional::<ClientCert>())
+        .map(move |ws: Ws, peer: Option<PeerAddr>, cert: Option<ClientCert>| -> Box<dyn warp::Reply> {
+            let peer = peer.map(|peer| peer.0);
+            if !crate::control_acl::allowed_direct(peer.map(|peer| peer.ip())) {
+                return Box::new(crate::control_acl::refused());
+            }
+            let blocked = peer.and_then(|peer| crate::handshake_limit::blocked(peer.ip()));
+            if let Some(retry_after) = blocked {
+                return Box::new(crate::handshake_limit::refused(retry_after));
+            }
+            Box::new(ws.on_upgrade(move |websocket| handle_new_connection(websocket, peer, cert)))
+        });
+    let claim = warp::post()
+        .and(warp::path("claim"))
+        .and(warp::body::json())
+        .and_then(crate::auth::domain_claims::handle_claim);
+    let grant = warp::post()
+        .and(warp::path("grant"))
+        .and(warp::body::json())
+        .and_then(crate::auth::domain_grants::handle_grant);
+    let guest_key = warp::post()
+        .and(warp::path!("keys" / "guest"))
+        .and(warp::body::json())
+        .and_then(crate::auth::guest_keys::handle_guest_key);
+    let add_key = warp::post()
+        .and(warp::path!("keys"))
+        .and(warp::body::json())
+        .and_then(crate::auth::account_keys::handle_create);
+    let rotate_key = warp::post()
+        .and(warp::path!("keys" / "rotate"))
+        .and(warp::body::json())
+        .and_then(crate::auth::account_keys::handle_rotate);
+    let visitors = warp::post()
+        .and(warp::path("visitors"))
+        .and(warp::body::json())
+        .and_then(crate::visitors::handle_visitors);
+    let approve = warp::post()
+        .and(warp::path("approve"))
+        .and(warp::body::json())
+        .and_then(crate::auth::approvals::handle_approval);
+    let create_inspect_link = warp::post()
+        .and(warp::path!("inspect_links"))
+        .and(warp::body::content_length_limit(256 * 1024))
+        .and(warp::body::json())
+        .and_then(crate::inspect_links::handle_create);
+    let inspect_link = warp::get()
+        .and(warp::path!("inspect" / String))
+        .and_then(crate::inspect_links::handle_view);
+    let history = warp::post()
+        .and(warp::path("history"))
+        .and(warp::body::json())
+        .and_then(crate::history::handle_history);
+    let transcripts = warp::post()
+        .and(warp::path("transcripts"))
+        .and(warp::body::json())
+        .and_then(crate::transcripts::handle_transcripts);
+    let capabilities = warp::get().and(warp::path("capabilities")).map(|| {
+        warp::reply::json(&CapabilitiesResponse {
+            features: server_features(),
+            capabilities: server_capabilities(),
+            deprecations: CONFIG.deprecations.clone(),
+        })
+    });
+
+    let dns_report = warp::get()
+        .and(warp::path!("diagnostics" / "dns"))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and_then(crate::diagnostics::handle_dns_report);
+    let census = warp::get()
+        .and(warp::path!("admin" / "census"))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and_then(crate::network::handle_census);
+    let debug_counts = warp::get()
+        .and(warp::path!("admin" / "debug" / "counts"))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and_then(crate::soak::handle_counts);
+    let invalidate_auth_cache = warp::post()
+        .and(warp::path!("admin" / "auth_cache" / "invalidate"))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and(warp::body::json())
+        .and_then(crate::auth::auth_cache::handle_invalidate);
+    let admin_history = warp::get()
+        .and(warp::path!("admin" / "history" / String))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and_then(crate::history::handle_admin_history);
+    let admin_create_key = warp::post()
+        .and(warp::path!("admin" / "keys"))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and(warp::body::json())
+        .and_then(crate::auth::admin_keys::handle_create);
+    let admin_list_keys = warp::get()
+        .and(warp::path!("admin" / "accounts" / Uuid / "keys"))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and_then(crate::auth::admin_keys::handle_list);
+    let admin_revoke_key = warp::post()
+        .and(warp::path!("admin" / "keys" / String / "revoke"))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and_then(crate::auth::admin_keys::handle_revoke);
+    let admin_reserve = warp::put()
+        .and(warp::path!("admin" / "reservations" / String))
+        .and(warp::header::optional::<String>("x-admin-key"))
+        .and(warp::body::json())
+        .and_then(crate::auth::admin_keys::handle_reserve);
 
     // spawn our websocket control server
-    tokio::spawn(warp::serve(client_conn.or(health_check)).run(addr.into()));
+    let routes = data_conn
+        .or(client_conn)
+        .or(health_check)
+        .or(claim)
+        .or(grant)
+        .or(guest_key)
+        .or(add_key)
+        .or(rotate_key)
+        .or(visitors)
+        .or(approve)
+        .or(create_inspect_link)
+        .or(inspect_link)
+        .or(history)
+        .or(transcripts)
+        .or(capabilities)
+        .or(dns_report)
+        .or(census)
+        .or(debug_counts)
+        .or(admin_history)
+        .or(invalidate_auth_cache)
+        .or(admin_create_key)
+        .or(admin_list_keys)
+        .or(admin_revoke_key)
+        .or(admin_reserve);
+    let listener = crate::handover::bind(addr.into()).expect("failed to bind control server");
+    tokio::spawn(serve(listener, tls, warp::service(routes)));
 }
 
-async fn handle_new_connection(websocket: WebSocket) {
-    let (websocket, handshake) = match try_client_handshake(websocket).await {
-        Some(ws) => ws,
-        None => return,
-    };
+/// The visitor's address, kept as a request extension since we accept connections ourselves
+#[derive(Debug, Clone, Copy)]
+struct PeerAddr(SocketAddr);
 
-    log::debug!("open tunnel: {}.", &handshake.sub_domain);
+/// Serve the control routes until handing over, connections already open stay served.
+/// With `tls`, connections are decrypted here and their client certificates kept.
+async fn serve<S>(listener: TcpListener, tls: Option<SslAcceptor>, service: S)
+where
+    S: hyper::service::Service<
+            hyper::Request<hyper::Body>,
+            Response = hyper::Response<hyper::Body>,
+            Error = std::convert::Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    loop {
+        let (socket, peer) = match crate::handover::accept(&listener).await {
+            Some(Ok(accepted)) => accepted,
+            Some(Err(e)) => {
+                error!("failed to accept control connection: {:?}", e);
+                continue;
+            }
+            None => return,
+        };
 
-    let (tx, rx) = unbounded::<ControlPacket>();
-    let mut client = ConnectedClient {
-        id: handshake.id,
-        host: handshake.sub_domain,
-        is_anonymous: handshake.is_anonymous,
-        tx,
+        let service = service.clone();
+        let with_extensions = move |cert: Option<ClientCert>| {
+            hyper::service::service_fn(move |mut request: hyper::Request<hyper::Body>| {
+                request.extensions_mut().insert(PeerAddr(peer));
+                if let Some(cert) = cert.clone() {
+                    request.extensions_mut().insert(cert);
+                }
+                service.clone().call(request)
+            })
+        };
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let http = hyper::server::conn::Http::new();
+            let result = match tls {
+                Some(acceptor) => {
+                    let (stream, cert) =
+                        match crate::auth::client_certs::accept(&acceptor, socket).await {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                log::debug!("control tls handshake failed: {:?}", e);
+                                return;
+                            }
+                        };
+                    http.serve_connection(stream, with_extensions(cert))
+                        .with_upgrades()
+                        .await
+                }
+                None => {
+                    http.serve_connection(socket, with_extensions(None))
+                        .with_upgrades()
+                        .await
+                }
+            };
+            if let Err(e) = result {
+                log::debug!("control connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_new_connection(
+    websocket: WebSocket,
+    peer: Option<SocketAddr>,
+    cert: Option<ClientCert>,
+) {
+    let (websocket, handshake) = match try_client_handshake(websocket, peer, cert).await {
+        Some(ws) => ws,
+        None => return,
     };
-    Connections::add(client.clone());
 
+    let (mut client, rx) = register(handshake, peer);
     let (sink, stream) = websocket.split();
 
     let client_clone = client.clone();
@@ -46,27 +243,41 @@ async fn handle_new_connection(websocket: WebSocket) {
     let client_clone = client.clone();
 
     tokio::spawn(async move {
-        process_client_messages(client_clone, stream).await;
+        process_client_messages(client_clone, stream, true).await;
     });
 
-    // play ping pong
+    // play ping pong, handing clients a fresh reconnect token every
+    // RECONNECT_TOKEN_REFRESH_SECS so theirs never expires while they're connected
     tokio::spawn(async move {
+        let ping_interval = Duration::new(PING_INTERVAL, 0);
+        let refresh = CONFIG.reconnect_token_refresh;
+        let mut next_ping = Instant::now();
+        // the handshake handed out the first one
+        let mut next_token = Some(Instant::now() + refresh)
+            .filter(|_| resumable(client.standby, client.catch_all));
+
         loop {
+            let wake = next_token.map_or(next_ping, |next_token| next_ping.min(next_token));
+            tokio::time::sleep_until(wake.into()).await;
             log::trace!("sending ping");
 
-            // create a new reconnect token for anonymous clients
-            let reconnect_token = if client.is_anonymous {
-                ReconnectTokenPayload {
-                    sub_domain: client.host.clone(),
-                    client_id: client.id.clone(),
-                    expires: Utc::now() + chrono::Duration::minutes(2),
-                }
-                .into_token(&CONFIG.master_sig_key)
-                .map_err(|e| error!("unable to create reconnect token: {:?}", e))
-                .ok()
-            } else {
-                None
+            let now = Instant::now();
+            let reconnect_token = match next_token {
+                Some(at) if now >= at => {
+                    next_token = Some(now + refresh);
+                    new_reconnect_token(
+                        &client.host,
+                        &client.id,
+                        client.account_id,
+                        client.base_domain.as_ref(),
+                        client.session_ends,
+                    )
+                }
+                _ => None,
             };
+            if now >= next_ping {
+                next_ping = now + ping_interval;
+            }
 
             match client.tx.send(ControlPacket::Ping(reconnect_token)).await {
                 Ok(_) => {}
@@ -76,20 +287,133 @@ async fn handle_new_connection(websocket: WebSocket) {
                     return;
                 }
             };
-
-            tokio::time::sleep(Duration::new(PING_INTERVAL, 0)).await;
         }
     });
 }
 
-async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, ClientHandshake)> {
+/// Register the tunnel of an admitted client, so visitors are routed to it. The packets
+/// for the client are queued on the receiver returned.
+pub(crate) fn register(
+    handshake: ClientHandshake,
+    peer: Option<SocketAddr>,
+) -> (ConnectedClient, QueueReceiver<ControlPacket>) {
+    log::debug!(
+        "open tunnel: {}.{}",
+        &handshake.sub_domain,
+        if handshake.standby { " (standby)" } else { "" }
+    );
+
+    let (tx, rx) = queue::<ControlPacket>(CONFIG.tunnel_queue, &QUEUE_METRICS.tunnel);
+    let bandwidth = handshake
+        .entitlements
+        .max_bandwidth
+        .map(|max_bandwidth| Arc::new(Bandwidth::new(max_bandwidth)));
+    let client = ConnectedClient {
+        id: handshake.id,
+        host: handshake.sub_domain,
+        is_anonymous: handshake.is_anonymous,
+        account_id: handshake.account_id,
+        entitlements: handshake.entitlements,
+        base_domain: handshake.base_domain,
+        tunnel_type: handshake.tunnel_type,
+        signing_secret: handshake.signing_secret,
+        access_rules: handshake.access_rules,
+        rate_limits: Arc::new(RateLimiter::new(handshake.rate_limits)),
+        bandwidth,
+        session_ends: handshake.session_ends,
+        error_format: handshake.error_format,
+        standby: handshake.standby,
+        traffic_profile: handshake.traffic_profile,
+        integrity: handshake.integrity,
+        access_log: handshake.access_log,
+        handover: handshake.handover,
+        approval: handshake.approval,
+        catch_all: handshake.catch_all,
+        tx,
+    };
+    Connections::add(client.clone());
+    // every instance sees the tunnel now, so racers for the sub-domain are turned away
+    drop(handshake.lease);
+    crate::anonymous::spawn_session_limit(client.clone());
+    crate::metering::tunnel_opened(&client);
+    crate::siem::tunnel_opened(&client, peer);
+    crate::history::record(&client, handshake.client_hostname);
+    (client, rx)
+}
+
+async fn try_client_handshake(
+    mut websocket: WebSocket,
+    peer: Option<SocketAddr>,
+    cert: Option<ClientCert>,
+) -> Option<(WebSocket, ClientHandshake)> {
+    // spread load: send new clients to a less busy instance
+    if let Some(endpoint) = crate::network::redirect_target().await {
+        info!("instance full, redirecting client to {}", &endpoint);
+        let _ = websocket.next().await;
+        let data = serde_json::to_vec(&ServerHello::Redirect { endpoint }).unwrap_or_default();
+        let _ = websocket.send(Message::binary(data)).await;
+        return None;
+    }
+
     // Authenticate client handshake
-    let (mut websocket, client_handshake) = client_auth::auth_client_handshake(websocket).await?;
+    let mut attempt = crate::handshake_audit::HandshakeRecord::new(peer);
+    let (mut websocket, client_handshake) =
+        match client_auth::auth_client_handshake(websocket, cert.as_ref(), &mut attempt).await {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                error!("client handshake failed: {:?}", e);
+                crate::handshake_audit::rejected(attempt, &e);
+                if let TunnelError::AuthFailed(reason) | TunnelError::KeyRejected(reason) = &e {
+                    crate::siem::auth_failed(reason, peer);
+                }
+                if let TunnelError::AuthFailed(_)
+                | TunnelError::KeyRejected(_)
+                | TunnelError::InvalidClientHello(_) = &e
+                {
+                    if let Some(peer) = peer {
+                        crate::handshake_limit::failed(peer.ip());
+                    }
+                }
+                return None;
+            }
+        };
+    crate::handshake_audit::accepted(attempt, &client_handshake);
+
+    if let Some(peer) = peer {
+        crate::handshake_limit::succeeded(peer.ip());
+    }
+
+    let reconnect_token = if resumable(client_handshake.standby, client_handshake.catch_all) {
+        new_reconnect_token(
+            &client_handshake.sub_domain,
+            &client_handshake.id,
+            client_handshake.account_id,
+            client_handshake.base_domain.as_ref(),
+            client_handshake.session_ends,
+        )
+    } else {
+        None
+    };
 
     // Send server hello success
     let data = serde_json::to_vec(&ServerHello::Success {
         sub_domain: client_handshake.sub_domain.clone(),
         client_id: client_handshake.id.clone(),
+        public_urls: CONFIG.public_urls(
+            &client_handshake.sub_domain,
+            client_handshake.base_domain.as_ref(),
+            client_handshake.tunnel_type,
+        ),
+        features: server_features(),
+        limits: TunnelLimits {
+            max_tunnels: client_handshake.entitlements.max_tunnels,
+            max_bandwidth: client_handshake.entitlements.max_bandwidth,
+        },
+        reconnect_token,
+        data_token: Some(crate::data_connection::issue_token(&client_handshake.id)),
+        capabilities: Box::new(server_capabilities()),
+        deprecations: CONFIG.deprecations.clone(),
+        awaiting_approval: client_handshake.approval.is_some(),
     })
     .unwrap_or_default();
 
@@ -100,17 +424,95 @@ async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, Client
     }
 
     info!(
-        "new client connected: {:?}{}",
+        "new client connected: {:?}{}{}",
         &client_handshake.id,
-        if client_handshake.is_anonymous {
-            " (anonymous)"
-        } else {
-            ""
+        match client_handshake.entitlements.label.as_ref() {
+            _ if client_handshake.is_anonymous => " (anonymous)".to_string(),
+            Some(label) => format!(" (key {:?})", label),
+            None => "".to_string(),
+        },
+        match client_handshake.tier {
+            Some(tier) => format!(" on the {} tier", tier),
+            None => "".to_string(),
         }
     );
     Some((websocket, client_handshake))
 }
 
+/// Optional protocol features this server supports
+fn server_features() -> Vec<String> {
+    let mut features = vec![
+        features::RECONNECT_TOKEN.to_string(),
+        features::DATA_CONNECTION.to_string(),
+        features::PARALLEL_DATA.to_string(),
+        features::STANDBY.to_string(),
+        features::TRAFFIC_PROFILE.to_string(),
+        features::INTEGRITY.to_string(),
+        features::ACCESS_LOG.to_string(),
+        features::APPROVAL.to_string(),
+        features::RATE_LIMITS.to_string(),
+        features::CATCH_ALL.to_string(),
+        features::GOODBYE.to_string(),
+        features::STREAM_END.to_string(),
+    ];
+    if CONFIG.handover.is_some() {
+        features.push(features::HANDOVER.to_string());
+    }
+    if CONFIG.tls_passthrough_port.is_some() {
+        features.push(features::TLS_PASSTHROUGH.to_string());
+    }
+    features
+}
+
+/// What this server can do beyond plain http tunnels
+fn server_capabilities() -> Capabilities {
+    Capabilities {
+        tcp_tunnels: false,
+        tls_passthrough: CONFIG.tls_passthrough_port.is_some(),
+        compression: vec![],
+        quic_endpoint: None,
+        inspector_relay: false,
+        login: login_provider(),
+    }
+}
+
+/// The identity provider clients sign in with, id tokens are for its client id so
+/// another JWT audience means access tokens for an api
+fn login_provider() -> Option<LoginProvider> {
+    let client_id = CONFIG.jwt_login_client_id.clone()?;
+    Some(LoginProvider {
+        issuer: CONFIG.jwt_issuer.clone()?,
+        audience: CONFIG.jwt_audience.clone().filter(|aud| aud != &client_id),
+        client_id,
+    })
+}
+
+/// Whether a tunnel gets reconnect tokens to resume its sub-domain with. Standbys and
+/// catch-alls don't hold a sub-domain of their own.
+fn resumable(standby: bool, catch_all: bool) -> bool {
+    !standby && !catch_all
+}
+
+fn new_reconnect_token(
+    sub_domain: &str,
+    client_id: &ClientId,
+    account_id: Option<Uuid>,
+    base_domain: Option<&String>,
+    session_ends: Option<DateTime<Utc>>,
+) -> Option<ReconnectToken> {
+    ReconnectTokenPayload {
+        sub_domain: sub_domain.to_string(),
+        client_id: client_id.clone(),
+        expires: Utc::now() + CONFIG.reconnect_token_ttl,
+        session_ends,
+        account_id,
+        base_domain: base_domain.cloned(),
+    }
+    .to_token(&CONFIG.master_sig_key)
+    .map_err(|e| error!("unable to create reconnect token: {:?}", e))
+    .ok()
+}
+
 /// Send the client a "stream init" message
 pub async fn send_client_stream_init(mut stream: ActiveStream) {
     match stream
@@ -129,8 +531,15 @@ pub async fn send_client_stream_init(mut stream: ActiveStream) {
     }
 }
 
-/// Process client control messages
-async fn process_client_messages(client: ConnectedClient, mut client_conn: SplitStream<WebSocket>) {
+/// Process messages from a client's control connection, or its data connection
+/// if `control` is false
+pub(crate) async fn process_client_messages(
+    client: ConnectedClient,
+    mut client_conn: SplitStream<WebSocket>,
+    control: bool,
+) {
+    // once the client says goodbye, its pings mustn't register it again
+    let mut said_goodbye = false;
     loop {
         let result = client_conn.next().await;
 
@@ -142,12 +551,18 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
             // handle close with reason
             Some(Ok(msg)) if msg.is_close() && !msg.as_bytes().is_empty() => {
                 log::debug!("got close, reason = {:?}", msg.to_str());
-                Connections::remove(&client);
+                if control {
+                    Connections::remove(&client);
+                }
                 return;
             }
             _ => {
-                info!("goodbye client: {:?}", &client.id);
-                Connections::remove(&client);
+                if control {
+                    info!("goodbye client: {:?}", &client.id);
+                    Connections::remove(&client);
+                } else {
+                    info!("data connection closed: {:?}", &client.id);
+                }
                 return;
             }
         };
@@ -160,59 +575,169 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
             }
         };
 
-        let (stream_id, message) = match packet {
-            ControlPacket::Data(stream_id, data) => {
-                info!(
-                    "forwarding to stream[id={}]: {} bytes",
-                    &stream_id.to_string(),
-                    data.len()
-                );
-                (stream_id, StreamMessage::Data(data))
-            }
-            ControlPacket::Refused(stream_id) => {
-                log::info!("tunnel says: refused");
-                (stream_id, StreamMessage::TunnelRefused)
-            }
-            ControlPacket::Init(_) | ControlPacket::End(_) => {
-                error!("invalid protocol control::init message");
-                continue;
-            }
-            ControlPacket::Ping(_) => {
-                log::trace!("pong");
-                Connections::add(client.clone());
-                continue;
+        // packets spread over several data connections are put back in order first
+        let packets = match packet {
+            ControlPacket::Sequenced(stream_id, seq, packet) => {
+                crate::data_connection::reorder(stream_id, seq, *packet)
             }
+            packet => vec![packet],
         };
 
-        let stream = ACTIVE_STREAMS.get(&stream_id).map(|s| s.value().clone());
+        for packet in packets {
+            let (stream_id, message) = match packet {
+                ControlPacket::Data(stream_id, data) => {
+                    info!(
+                        "forwarding to stream[id={}]: {} bytes",
+                        &stream_id.to_string(),
+                        data.len()
+                    );
+                    (stream_id, StreamMessage::Data(data))
+                }
+                ControlPacket::Refused(stream_id) => {
+                    log::info!("tunnel says: refused");
+                    (stream_id, StreamMessage::TunnelRefused)
+                }
+                ControlPacket::Trailer(stream_id, checksum) => {
+                    (stream_id, StreamMessage::Trailer(checksum))
+                }
+                ControlPacket::End(stream_id) => (stream_id, StreamMessage::End),
+                ControlPacket::Init(_) => {
+                    error!("invalid protocol control::init message");
+                    continue;
+                }
+                ControlPacket::AccessLog(_) => {
+                    error!("invalid protocol control::access_log message");
+                    continue;
+                }
+                ControlPacket::Handover => {
+                    error!("invalid protocol control::handover message");
+                    continue;
+                }
+                ControlPacket::Goodbye if control => {
+                    info!(
+                        "client shutting down, releasing its sub-domain: {:?}",
+                        &client.id
+                    );
+                    said_goodbye = true;
+                    Connections::release(&client);
+                    continue;
+                }
+                ControlPacket::Goodbye => {
+                    error!("invalid protocol data::goodbye message");
+                    continue;
+                }
+                ControlPacket::Sequenced(..) => {
+                    error!("invalid protocol control::sequenced message");
+                    continue;
+                }
+                ControlPacket::Ping(_) => {
+                    log::trace!("pong");
+                    if control && !said_goodbye {
+                        Connections::add(client.clone());
+                    }
+                    continue;
+                }
+            };
 
-        if let Some(mut stream) = stream {
-            let _ = stream.tx.send(message).await.map_err(|e| {
-                log::error!("Failed to send to stream tx: {:?}", e);
-            });
+            if !deliver(&client, &stream_id, message).await {
+                return;
+            }
         }
     }
 }
 
+/// Hand what the client sent to the visitor stream it's for. False when the stream's
+/// queue overflowed and the client was disconnected for it.
+pub(crate) async fn deliver(
+    client: &ConnectedClient,
+    stream_id: &StreamId,
+    message: StreamMessage,
+) -> bool {
+    let stream = ACTIVE_STREAMS.get(stream_id).map(|s| s.value().clone());
+
+    if let Some(mut stream) = stream {
+        match stream.tx.send(message).await {
+            Ok(_) => {}
+            Err(QueueError::Overflow(OverflowPolicy::Drop)) => {
+                log::warn!("stream queue full, dropping visitor stream");
+                remote::drop_overloaded_stream(&mut stream);
+            }
+            Err(QueueError::Overflow(_)) => {
+                log::warn!("stream queue full, disconnecting client: {}", &client.id);
+                Connections::remove(client);
+                return false;
+            }
+            Err(e) => log::error!("Failed to send to stream tx: {:?}", e),
+        }
+    }
+    true
+}
+
 async fn tunnel_client(
     client: ConnectedClient,
     mut sink: SplitSink<WebSocket, Message>,
-    mut queue: UnboundedReceiver<ControlPacket>,
+    mut queue: QueueReceiver<ControlPacket>,
 ) {
+    let mut pending = SendQueue::default();
+    // the data connection stream packets go over, when the client opened one
+    let mut data: Option<DataConnection> = None;
     loop {
-        match queue.next().await {
-            Some(packet) => {
-                let result = sink.send(Message::binary(packet.serialize())).await;
-                if result.is_err() {
-                    eprintln!("client disconnected: aborting.");
-                    Connections::remove(&client);
+        if pending.is_empty() {
+            match queue.next().await {
+                Some(packet) => pending.push(packet),
+                None => {
+                    info!("ending client tunnel");
+                    crate::data_connection::detach(&client.id);
+                    // hang up, in case the client was removed while still connected
+                    let _ = sink.close().await;
                     return;
                 }
+            };
+        }
+
+        // pick up everything queued meanwhile so pings can skip ahead of bulk data
+        while let Ok(Some(packet)) = queue.try_next() {
+            pending.push(packet);
+        }
+
+        let packet = match pending.pop() {
+            Some(packet) => packet,
+            None => continue,
+        };
+
+        // a data connection that failed hands back what it didn't write, to send first
+        let mut packets = vec![];
+        if let Some(failed) = data.take_if(|d| d.is_closed()) {
+            log::warn!("data connection failed, using control connection");
+            packets = failed.take_unsent().await;
+            failed.remove(&client.id);
+        }
+        if data.is_none() {
+            data = crate::data_connection::sender_for(&client.id);
+        }
+
+        // stream packets go over the data connection when the client opened one
+        match (packet, data.as_mut()) {
+            (ControlPacket::Ping(token), _) => packets.push(ControlPacket::Ping(token)),
+            (packet, None) => packets.push(packet),
+            (packet, Some(connection)) => {
+                if let Err(packet) = connection.send(packet).await {
+                    log::warn!("data connection failed, using control connection");
+                    packets.extend(connection.take_unsent().await);
+                    packets.push(packet);
+                    connection.remove(&client.id);
+                    data = None;
+                }
             }
-            None => {
-                info!("ending client tunnel");
+        }
+
+        for packet in packets {
+            let result = sink.send(Message::binary(packet.serialize())).await;
+            if result.is_err() {
+                eprintln!("client disconnected: aborting.");
+                Connections::remove(&client);
                 return;
             }
-        };
+        }
     }
 }
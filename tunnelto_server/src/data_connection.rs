@@ -0,0 +1,209 @@
+use super::*;
+use crate::queue::QueueConfig;
+use std::time::{Duration, Instant};
+use tunnelto_lib::parallel_data::Reorder;
+
+/// How long a client has to open its data connection after the handshake
+const DATA_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// tokens handed out in server hellos, with the data connections they may still open
+    static ref DATA_TOKENS: DashMap<String, (ClientId, Instant, usize)> = DashMap::new();
+    /// where to send a client's stream packets instead of its control connection, the
+    /// first of its data connections
+    static ref DATA_CONNECTIONS: DashMap<ClientId, Vec<DataConnection>> = DashMap::new();
+    /// streams whose packets the client spreads over its data connections
+    static ref REORDERS: DashMap<StreamId, Reorder> = DashMap::new();
+}
+
+/// A client's data connection, as its control connection sends through it
+#[derive(Clone)]
+pub struct DataConnection {
+    tx: QueueSender<ControlPacket>,
+    /// held by the connection's writer while it runs, then the packets it didn't write,
+    /// in order, for the control connection to send instead
+    unsent: Arc<tokio::sync::Mutex<Vec<ControlPacket>>>,
+}
+
+impl DataConnection {
+    /// Queue a packet for the connection, handing it back if the connection is gone
+    pub async fn send(&mut self, packet: ControlPacket) -> Result<(), ControlPacket> {
+        self.tx
+            .send_or_return(packet)
+            .await
+            .map_err(|(_, packet)| packet)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Wait for the connection's writer to stop, taking the packets it didn't write
+    pub async fn take_unsent(&self) -> Vec<ControlPacket> {
+        std::mem::take(&mut *self.unsent.lock().await)
+    }
+
+    /// Stop using the connection, the client's others stay open
+    pub fn remove(&self, client_id: &ClientId) {
+        if let Some(mut connections) = DATA_CONNECTIONS.get_mut(client_id) {
+            connections.retain(|c| !Arc::ptr_eq(&c.unsent, &self.unsent));
+        }
+        DATA_CONNECTIONS.remove_if(client_id, |_, connections| connections.is_empty());
+    }
+}
+
+/// A token the client can open a data connection with
+pub fn issue_token(client_id: &ClientId) -> String {
+    DATA_TOKENS.retain(|_, (_, issued, _)| issued.elapsed() < DATA_TOKEN_TTL);
+
+    let token = format!(
+        "{}{}",
+        ServerHello::random_domain(),
+        ServerHello::random_domain()
+    );
+    DATA_TOKENS.insert(
+        token.clone(),
+        (client_id.clone(), Instant::now(), MAX_DATA_CONNECTIONS),
+    );
+    token
+}
+
+/// The data connection for a client's stream packets, if it opened one
+pub fn sender_for(client_id: &ClientId) -> Option<DataConnection> {
+    DATA_CONNECTIONS
+        .get(client_id)
+        .and_then(|txs| txs.value().first().cloned())
+}
+
+/// Stop using the client's data connections, closing them
+pub fn detach(client_id: &ClientId) {
+    if let Some((_, txs)) = DATA_CONNECTIONS.remove(client_id) {
+        log::debug!("detached data connections: {}", client_id);
+        for connection in txs {
+            connection.tx.close_channel();
+        }
+    }
+}
+
+/// Take a packet the client numbered within its stream, returning the packets of the
+/// stream now in order, see `tunnelto_lib::parallel_data`. A stream missing a packet for
+/// too long is ended.
+pub fn reorder(stream_id: StreamId, seq: u32, packet: ControlPacket) -> Vec<ControlPacket> {
+    // the stream may be gone already, there's nothing to put in order then
+    if !ACTIVE_STREAMS.contains_key(&stream_id) {
+        return vec![];
+    }
+
+    let ready = REORDERS
+        .entry(stream_id.clone())
+        .or_default()
+        .push(seq, packet);
+    match ready {
+        Some(ready) => ready,
+        None => {
+            log::warn!(
+                "stream {} is missing a packet, ending it",
+                stream_id.to_string()
+            );
+            REORDERS.remove(&stream_id);
+            vec![ControlPacket::End(stream_id)]
+        }
+    }
+}
+
+/// Forget the order of a stream that ended
+pub fn forget(stream_id: &StreamId) {
+    REORDERS.remove(stream_id);
+}
+
+pub async fn handle_data_connection(mut websocket: WebSocket) {
+    let client = match accept(&mut websocket).await {
+        Some(client) => client,
+        None => {
+            let reply = serde_json::to_vec(&DataHelloReply::Rejected).unwrap_or_default();
+            let _ = websocket.send(Message::binary(reply)).await;
+            return;
+        }
+    };
+
+    let reply = serde_json::to_vec(&DataHelloReply::Accepted).unwrap_or_default();
+    if let Err(e) = websocket.send(Message::binary(reply)).await {
+        error!("failed to accept data connection: {:?}", e);
+        return;
+    }
+    info!("data connection opened: {}", &client.id);
+
+    // packets were held to the tunnel's overflow policy on the control queue already
+    let config = QueueConfig {
+        overflow: OverflowPolicy::Block,
+        ..CONFIG.tunnel_queue
+    };
+    let (tx, mut rx) = queue::<ControlPacket>(config, &QUEUE_METRICS.tunnel);
+    let unsent = Arc::new(tokio::sync::Mutex::new(vec![]));
+    let mut writing = unsent.clone().lock_owned().await;
+    let connection = DataConnection { tx, unsent };
+    DATA_CONNECTIONS
+        .entry(client.id.clone())
+        .or_default()
+        .push(connection.clone());
+
+    let (mut sink, stream) = websocket.split();
+    let writer_client = client.clone();
+    tokio::spawn(async move {
+        while let Some(packet) = rx.next().await {
+            let message = Message::binary(packet.clone().serialize());
+            if let Err(e) = sink.send(message).await {
+                log::warn!(
+                    "data connection write failed: {:?}, handing its queue to the control connection",
+                    e
+                );
+                // the control connection sends these before anything newer
+                connection.tx.close_channel();
+                writing.push(packet);
+                while let Some(packet) = rx.next().await {
+                    writing.push(packet);
+                }
+                drop(writing);
+                connection.remove(&writer_client.id);
+                // wake the control connection up, in case it has nothing else to send
+                let _ = writer_client.tx.clone().try_send(ControlPacket::Ping(None));
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    control_server::process_client_messages(client.clone(), stream, false).await;
+    detach(&client.id);
+}
+
+/// Check the data hello, returning the tunnel it belongs to
+async fn accept(websocket: &mut WebSocket) -> Option<ConnectedClient> {
+    let message = match websocket.next().await {
+        Some(Ok(message)) => message,
+        _ => return None,
+    };
+
+    let hello: DataHello = match serde_json::from_slice(message.as_bytes()) {
+        Ok(hello) => hello,
+        Err(e) => {
+            error!("invalid data hello: {:?}", e);
+            return None;
+        }
+    };
+
+    let client_id = match DATA_TOKENS.get_mut(&hello.token) {
+        Some(mut token) if token.1.elapsed() < DATA_TOKEN_TTL && token.2 > 0 => {
+            token.2 -= 1;
+            token.0.clone()
+        }
+        _ => {
+            log::warn!("data connection with unknown, expired or used up token");
+            return None;
+        }
+    };
+    DATA_TOKENS.remove_if(&hello.token, |_, (_, _, left)| *left == 0);
+
+    // the control connection may have dropped meanwhile
+    Connections::get(&client_id)
+}
@@ -0,0 +1,221 @@
+use super::*;
+use crate::auth::{SigKey, Signature};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Serialize;
+use std::time::Duration;
+use trust_dns_resolver::TokioAsyncResolver;
+use warp::http::StatusCode;
+
+/// Path visitors never use, answered by the edge to prove a request reached this cluster
+const PROBE_PATH_PREFIX: &str = "/0xDEADBEEF_DNS_PROBE/";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long after the self-check issued it a probe nonce is answered
+const NONCE_MAX_AGE_SECS: i64 = 60;
+
+/// Keys derived from the master key for the probes alone: nonces are issued under one and
+/// answered under the other, so the edge signs nothing but fresh nonces of our own
+fn nonce_key() -> SigKey {
+    CONFIG.master_sig_key.derive("dns-probe-nonce")
+}
+
+fn answer_key() -> SigKey {
+    CONFIG.master_sig_key.derive("dns-probe")
+}
+
+/// `timestamp.random.mac`, so any instance of the cluster can tell it issued the nonce
+fn issue_nonce(key: &SigKey, now: i64) -> String {
+    let nonce = format!("{}.{}", now, ServerHello::random_domain());
+    let mac = key.sign(nonce.as_bytes());
+    format!("{}.{}", nonce, mac.0)
+}
+
+/// Whether the nonce was issued under `key` recently enough to answer
+fn valid_nonce(key: &SigKey, nonce: &str, now: i64) -> bool {
+    let (issued, mac) = match nonce.rsplit_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let timestamp = match issued.split('.').next().and_then(|t| t.parse::<i64>().ok()) {
+        Some(timestamp) => timestamp,
+        None => return false,
+    };
+    (0..=NONCE_MAX_AGE_SECS).contains(&(now - timestamp))
+        && key.verify(issued.as_bytes(), &Signature(mac.to_string()))
+}
+
+/// What we found checking one of the allowed hosts
+#[derive(Debug, Clone, Serialize)]
+pub struct BaseDomainReport {
+    pub base_domain: String,
+    pub probe_host: String,
+    /// addresses a random sub-domain resolves to, empty without a wildcard record
+    pub addresses: Vec<String>,
+    pub reaches_cluster: bool,
+    pub error: Option<String>,
+}
+
+impl BaseDomainReport {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Check that a random sub-domain of `base_domain` resolves and is served by us
+pub async fn check_base_domain(base_domain: &str) -> BaseDomainReport {
+    let probe_host = format!("{}.{}", ServerHello::random_domain(), base_domain);
+    let mut report = BaseDomainReport {
+        base_domain: base_domain.to_string(),
+        probe_host: probe_host.clone(),
+        addresses: vec![],
+        reaches_cluster: false,
+        error: None,
+    };
+
+    let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+        Ok(resolver) => resolver,
+        Err(e) => {
+            report.error = Some(format!("failed to create resolver: {}", e));
+            return report;
+        }
+    };
+
+    match resolver.lookup_ip(probe_host.as_str()).await {
+        Ok(ips) => report.addresses = ips.iter().map(|ip| ip.to_string()).collect(),
+        Err(e) => {
+            report.error = Some(format!(
+                "no wildcard DNS record for *.{}: {}",
+                base_domain, e
+            ));
+            return report;
+        }
+    }
+
+    let nonce = issue_nonce(&nonce_key(), chrono::Utc::now().timestamp());
+    let url = format!(
+        "{}://{}{}{}",
+        CONFIG.public_scheme, probe_host, PROBE_PATH_PREFIX, nonce
+    );
+
+    let response = reqwest::Client::new()
+        .get(&url)
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await;
+
+    let body = match response {
+        Ok(response) => response.text().await.unwrap_or_default(),
+        Err(e) => {
+            report.error = Some(format!(
+                "could not reach {} (check DNS targets and certificates): {}",
+                url, e
+            ));
+            return report;
+        }
+    };
+
+    report.reaches_cluster =
+        answer_key().verify(nonce.as_bytes(), &Signature(body.trim().to_string()));
+
+    if !report.reaches_cluster {
+        report.error = Some(format!(
+            "*.{} resolves to {:?} but that is not this tunnelto cluster",
+            base_domain, report.addresses
+        ));
+    }
+
+    report
+}
+
+pub async fn check_all() -> Vec<BaseDomainReport> {
+    let checks = CONFIG.allowed_hosts.iter().map(|host| check_base_domain(host));
+    futures::future::join_all(checks).await
+}
+
+/// Run the checks once the listeners are up and log any misconfiguration
+pub fn spawn_startup_check() {
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        for report in check_all().await {
+            match report.error {
+                None => info!("dns self-check passed for *.{}", report.base_domain),
+                Some(e) => log::warn!("dns self-check failed for *.{}: {}", report.base_domain, e),
+            }
+        }
+    });
+}
+
+/// Answer dns probes with the signed nonce, if the self-check issued it
+pub struct DnsProbe;
+impl EdgeFilter for DnsProbe {
+    fn name(&self) -> &'static str {
+        "dns_probe"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            let nonce = match request.path.strip_prefix(PROBE_PATH_PREFIX) {
+                Some(nonce) => nonce,
+                None => return FilterAction::Continue,
+            };
+            if !valid_nonce(&nonce_key(), nonce, chrono::Utc::now().timestamp()) {
+                return FilterAction::Respond(edge::http_response("404 Not Found", ""));
+            }
+            let signature = answer_key().sign(nonce.as_bytes());
+            FilterAction::Respond(edge::http_response("200 OK", &signature.0))
+        }
+        .boxed()
+    }
+}
+
+/// Handle an operator request for the dns diagnostics report
+pub async fn handle_dns_report(
+    admin_key: Option<String>,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !crate::siem::admin("dns_report", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+
+    let reports = check_all().await;
+    let status = if reports.iter().all(BaseDomainReport::is_ok) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok(warp::reply::with_status(warp::reply::json(&reports), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_fresh_nonces_it_issued() {
+        let key = SigKey::generate();
+        let nonce = issue_nonce(&key, 1_000);
+        assert!(valid_nonce(&key, &nonce, 1_000));
+        assert!(valid_nonce(&key, &nonce, 1_000 + NONCE_MAX_AGE_SECS));
+    }
+
+    #[test]
+    fn refuses_stale_foreign_or_made_up_nonces() {
+        let key = SigKey::generate();
+        let nonce = issue_nonce(&key, 1_000);
+        assert!(!valid_nonce(&key, &nonce, 1_000 + NONCE_MAX_AGE_SECS + 1));
+        assert!(!valid_nonce(&key, &nonce, 999));
+        assert!(!valid_nonce(&SigKey::generate(), &nonce, 1_000));
+        assert!(!valid_nonce(&key, "1000.made-up.00", 1_000));
+        assert!(!valid_nonce(&key, "anything", 1_000));
+    }
+
+    #[test]
+    fn derived_keys_sign_apart_from_the_master_key() {
+        let master = SigKey::generate();
+        let signature = master.derive("dns-probe").sign(b"payload");
+        assert!(!master.verify(b"payload", &signature));
+        assert!(!master.derive("other").verify(b"payload", &signature));
+        assert!(master.derive("dns-probe").verify(b"payload", &signature));
+    }
+}
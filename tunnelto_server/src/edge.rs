@@ -0,0 +1,524 @@
+use super::*;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+
+/// What the edge knows about a visitor request before it is tunneled
+#[derive(Debug, Clone)]
+pub struct EdgeRequest {
+    /// unique id for correlating this request in logs on both sides of the tunnel
+    pub request_id: String,
+    pub peer_addr: Option<SocketAddr>,
+    pub host: String,
+    /// the tunnel sub-domain, if the host is one we serve
+    pub sub_domain: Option<String>,
+    /// the allowed host the sub-domain was requested on
+    pub base_domain: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    /// length of the request head, if it fit in the peeked bytes
+    pub head_len: Option<usize>,
+    /// headers to add to the request before it reaches the tunnel client
+    pub inject_headers: Vec<(String, String)>,
+    /// set by filters that must see every request: the connection then carries this
+    /// request alone, see `request_body`
+    pub one_request: bool,
+}
+
+impl EdgeRequest {
+    pub fn generate_request_id() -> String {
+        uuid::Uuid::new_v4().to_simple().to_string()
+    }
+
+    /// The tunnel serving the request's sub-domain on this instance, or else a catch-all
+    pub fn tunnel(&self) -> Option<ConnectedClient> {
+        let sub_domain = self.sub_domain.as_ref()?;
+        Connections::find_by_host(sub_domain)
+            .or_else(|| Connections::catch_all_for(self.base_domain.as_ref()))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub enum FilterAction {
+    Continue,
+    /// answer the visitor with this raw http response and close
+    Respond(Vec<u8>),
+}
+
+/// A check applied to the first request on each visitor connection, in registration
+/// order. Filters that must see every request set `EdgeRequest::one_request`.
+pub trait EdgeFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction>;
+}
+
+pub struct EdgeFilters {
+    filters: RwLock<Vec<Arc<dyn EdgeFilter>>>,
+}
+
+impl Default for EdgeFilters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EdgeFilters {
+    pub fn new() -> Self {
+        let filters = EdgeFilters {
+            filters: RwLock::new(vec![]),
+        };
+        filters.register(crate::diagnostics::DnsProbe);
+        filters.register(RootDomainRedirect);
+        filters.register(ValidHost);
+        filters.register(crate::load_shedding::LoadShedding);
+        if let Some(script) = crate::routing_script::RoutingScript::from_config() {
+            filters.register(script);
+        }
+        filters.register(PathAccessRules);
+        filters.register(PathRateLimits);
+        if let Some(authz) = crate::ext_authz::ExtAuthz::from_config() {
+            filters.register(authz);
+        }
+        filters.register(ForwardedHeaders);
+        filters
+    }
+
+    /// Add a filter to the end of the pipeline
+    pub fn register<F: EdgeFilter + 'static>(&self, filter: F) {
+        self.filters.write().unwrap().push(Arc::new(filter));
+    }
+
+    pub async fn run(&self, request: &mut EdgeRequest) -> FilterAction {
+        let filters = self.filters.read().unwrap().clone();
+
+        for filter in filters {
+            if let FilterAction::Respond(response) = filter.apply(request).await {
+                log::debug!("edge filter {} answered request", filter.name());
+                crate::access_log::filtered(request, filter.name(), &response);
+                crate::transcripts::filtered(request, filter.name(), &response).await;
+                return FilterAction::Respond(response);
+            }
+        }
+
+        FilterAction::Continue
+    }
+}
+
+/// Build a plain text http response
+pub fn http_response(status: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// The status code of a raw http response, i.e. `HTTP/1.1 403 Forbidden`
+pub fn response_status(response: &[u8]) -> Option<u16> {
+    let head = String::from_utf8_lossy(&response[..response.len().min(32)]);
+    head.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// An error the edge answers a visitor with instead of the local service
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorPage {
+    pub status: u16,
+    pub code: ErrorCode,
+    pub message: &'static str,
+}
+
+impl ErrorPage {
+    /// The raw http response in the tunnel's error format, tagged with the request id
+    /// if we assigned one
+    pub fn render(&self, request_id: Option<&str>, format: ErrorFormat) -> Vec<u8> {
+        self.render_with_headers(request_id, format, &[])
+    }
+
+    /// Like `render`, with extra response headers, i.e. `Retry-After`
+    pub fn render_with_headers(
+        &self,
+        request_id: Option<&str>,
+        format: ErrorFormat,
+        headers: &[(&str, String)],
+    ) -> Vec<u8> {
+        let (content_type, body) = match format {
+            ErrorFormat::Json => (
+                "application/json",
+                ErrorEnvelope::new(self.status, self.code, self.message, request_id).to_json(),
+            ),
+            ErrorFormat::Text => {
+                let message = format!("{} ({})", self.message, self.code);
+                match request_id {
+                    Some(request_id) => (
+                        "text/plain",
+                        format!("{}\nRequest ID: {}", message, request_id),
+                    ),
+                    None if headers.is_empty() => {
+                        return http_response(&self.status.to_string(), &message)
+                    }
+                    None => ("text/plain", message),
+                }
+            }
+        };
+
+        let mut extra_headers = request_id
+            .map(|id| format!("{}: {}\r\n", verify::REQUEST_ID_HEADER, id))
+            .unwrap_or_default();
+        for (name, value) in headers {
+            extra_headers.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\n{}Content-Length: {}\r\n\r\n{}",
+            self.status,
+            content_type,
+            extra_headers,
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+}
+
+pub const INVALID_HOST: ErrorPage = ErrorPage {
+    status: 400,
+    code: ErrorCode::InvalidHost,
+    message: "Error: Invalid Hostname",
+};
+pub const FORBIDDEN: ErrorPage = ErrorPage {
+    status: 403,
+    code: ErrorCode::Forbidden,
+    message: "Forbidden",
+};
+pub const AWAITING_APPROVAL: ErrorPage = ErrorPage {
+    status: 403,
+    code: ErrorCode::AwaitingApproval,
+    message: "Error: Tunnel is awaiting approval",
+};
+pub const TUNNEL_NOT_FOUND: ErrorPage = ErrorPage {
+    status: 404,
+    code: ErrorCode::TunnelNotFound,
+    message: "Error: Tunnel Not Found",
+};
+pub const REQUEST_TIMEOUT: ErrorPage = ErrorPage {
+    status: 408,
+    code: ErrorCode::RequestTimeout,
+    message: "Error: Request timed out",
+};
+pub const PAYLOAD_TOO_LARGE: ErrorPage = ErrorPage {
+    status: 413,
+    code: ErrorCode::PayloadTooLarge,
+    message: "Error: Request too large for this tunnel",
+};
+pub const TOO_MANY_STREAMS: ErrorPage = ErrorPage {
+    status: 429,
+    code: ErrorCode::TooManyConcurrentRequests,
+    message: "Error: Too many concurrent requests for this tunnel",
+};
+pub const RATE_LIMITED: ErrorPage = ErrorPage {
+    status: 429,
+    code: ErrorCode::RateLimited,
+    message: "Error: Too many requests to this path, slow down",
+};
+pub const BAD_REQUEST: ErrorPage = ErrorPage {
+    status: 400,
+    code: ErrorCode::InvalidRequest,
+    message: "Error: Ambiguous request framing",
+};
+pub const HEAD_TOO_LARGE: ErrorPage = ErrorPage {
+    status: 431,
+    code: ErrorCode::InvalidRequest,
+    message: "Error: Request head too large",
+};
+
+pub const ERROR_LOCATING_HOST: ErrorPage = ErrorPage {
+    status: 500,
+    code: ErrorCode::ErrorLocatingTunnel,
+    message: "Error: Error finding tunnel",
+};
+pub const TUNNEL_REFUSED: ErrorPage = ErrorPage {
+    status: 502,
+    code: ErrorCode::LocalUnreachable,
+    message: "Tunnel says: connection refused.",
+};
+pub const SERVICE_UNAVAILABLE: ErrorPage = ErrorPage {
+    status: 503,
+    code: ErrorCode::TunnelOverloaded,
+    message: "Error: Tunnel is overloaded",
+};
+pub const SERVER_OVERLOADED: ErrorPage = ErrorPage {
+    status: 503,
+    code: ErrorCode::ServerOverloaded,
+    message: "Error: Server is overloaded, try again later",
+};
+
+/// Rewrite a request head to carry extra headers, replacing any the visitor sent,
+/// and to request `path` if a filter changed it
+pub fn inject_headers(head: &[u8], path: &str, headers: &[(String, String)]) -> Vec<u8> {
+    // drop the blank line terminating the head and visitor copies of our headers,
+    // add ours, then terminate again
+    let mut out = vec![];
+    let mut lines = head[..head.len().saturating_sub(2)].split_inclusive(|b| *b == b'\n');
+    if let Some(request_line) = lines.next() {
+        let request_line = String::from_utf8_lossy(request_line);
+        let mut parts = request_line.trim_end().splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if target != path => {
+                out.extend_from_slice(format!("{} {} {}\r\n", method, path, version).as_bytes())
+            }
+            _ => out.extend_from_slice(request_line.as_bytes()),
+        }
+    }
+    for line in lines {
+        let name = line.split(|b| *b == b':').next().unwrap_or_default();
+        let replaced = headers
+            .iter()
+            .any(|(n, _)| n.as_bytes().eq_ignore_ascii_case(name));
+        if !replaced {
+            out.extend_from_slice(line);
+        }
+    }
+    for (name, value) in headers {
+        out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// Requests to a root domain go to the homepage
+struct RootDomainRedirect;
+impl EdgeFilter for RootDomainRedirect {
+    fn name(&self) -> &'static str {
+        "root_domain_redirect"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            if CONFIG.allowed_hosts.contains(&request.host) {
+                error!("redirect to homepage");
+                return FilterAction::Respond(HTTP_REDIRECT_RESPONSE.to_vec());
+            }
+            FilterAction::Continue
+        }
+        .boxed()
+    }
+}
+
+/// Reject hosts that aren't a sub-domain of an allowed host
+struct ValidHost;
+impl EdgeFilter for ValidHost {
+    fn name(&self) -> &'static str {
+        "valid_host"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            if request.sub_domain.is_none() {
+                error!("invalid host specified");
+                return FilterAction::Respond(INVALID_HOST.render(Some(&request.request_id), ErrorFormat::Text));
+            }
+            FilterAction::Continue
+        }
+        .boxed()
+    }
+}
+
+/// Refuse visitors outside the networks a tunnel allows on a path
+struct PathAccessRules;
+impl EdgeFilter for PathAccessRules {
+    fn name(&self) -> &'static str {
+        "path_access_rules"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            let client = match request.tunnel() {
+                Some(client) => client,
+                None => return FilterAction::Continue,
+            };
+            if !client.access_rules.is_empty() {
+                request.one_request = true;
+            }
+
+            let rule = match acl::matching_rule(&client.access_rules, &request.path) {
+                Some(rule) => rule,
+                None => return FilterAction::Continue,
+            };
+
+            match request.peer_addr {
+                Some(peer_addr) if rule.allows(peer_addr.ip()) => FilterAction::Continue,
+                peer_addr => {
+                    log::debug!(
+                        "denied {:?} access to {} on {} request_id={}",
+                        peer_addr,
+                        request.path,
+                        client.host,
+                        request.request_id
+                    );
+                    FilterAction::Respond(FORBIDDEN.render(Some(&request.request_id), client.error_format))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Answer 429 to visitors past the request rate a tunnel allows on a path
+struct PathRateLimits;
+impl EdgeFilter for PathRateLimits {
+    fn name(&self) -> &'static str {
+        "path_rate_limits"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            let client = match request.tunnel() {
+                Some(client) => client,
+                None => return FilterAction::Continue,
+            };
+            if !client.rate_limits.is_empty() {
+                request.one_request = true;
+            }
+
+            let (wait, rule) = match client.rate_limits.check(&request.path) {
+                Ok(()) => return FilterAction::Continue,
+                Err(limited) => limited,
+            };
+
+            log::debug!(
+                "rate limited {} on {} ({}) request_id={}",
+                request.path,
+                client.host,
+                rule,
+                request.request_id
+            );
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            FilterAction::Respond(RATE_LIMITED.render_with_headers(
+                Some(&request.request_id),
+                client.error_format,
+                &[("Retry-After", retry_after.to_string())],
+            ))
+        }
+        .boxed()
+    }
+}
+
+/// Tell the local service who the visitor is, and sign the request if the tunnel asked.
+/// Visitor copies of these headers are replaced on every request: later requests on the
+/// connection have their heads rewritten as they arrive, see `request_body`, except on
+/// signed tunnels, where the connection carries the signed request alone.
+struct ForwardedHeaders;
+impl EdgeFilter for ForwardedHeaders {
+    fn name(&self) -> &'static str {
+        "forwarded_headers"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            let secret = request.tunnel().and_then(|c| c.signing_secret);
+            if secret.is_some() {
+                request.one_request = true;
+            }
+
+            let headers = forwarded_headers(request, secret.as_deref());
+            request.inject_headers.extend(headers);
+            FilterAction::Continue
+        }
+        .boxed()
+    }
+}
+
+/// The headers telling the local service about a request to a tunnel, signed with the
+/// tunnel's `secret` if it has one
+pub fn forwarded_headers(request: &EdgeRequest, secret: Option<&str>) -> Vec<(String, String)> {
+    let sub_domain = match request.sub_domain.as_ref() {
+        Some(sub_domain) => sub_domain,
+        None => return vec![],
+    };
+
+    let mut headers = vec![
+        (
+            verify::REQUEST_ID_HEADER.to_string(),
+            request.request_id.clone(),
+        ),
+        ("X-Forwarded-Host".to_string(), request.host.clone()),
+        (
+            "X-Forwarded-Proto".to_string(),
+            CONFIG.public_scheme.clone(),
+        ),
+        (verify::SUB_DOMAIN_HEADER.to_string(), sub_domain.clone()),
+    ];
+    if let Some(peer_addr) = request.peer_addr {
+        headers.push((
+            "X-Forwarded-For".to_string(),
+            peer_addr.ip().to_canonical().to_string(),
+        ));
+    }
+
+    if let Some(secret) = secret {
+        let timestamp = chrono::Utc::now().timestamp();
+        let signature = verify::sign(
+            secret,
+            timestamp,
+            &request.method,
+            &request.path,
+            &request.host,
+        );
+        headers.push((verify::TIMESTAMP_HEADER.to_string(), timestamp.to_string()));
+        headers.push((verify::SIGNATURE_HEADER.to_string(), signature));
+    }
+    headers
+}
+
+const HTTP_REDIRECT_RESPONSE:&[u8] = b"HTTP/1.1 301 Moved Permanently\r\nLocation: https://tunnelto.dev/\r\nContent-Length: 20\r\n\r\nhttps://tunnelto.dev";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn injected_headers_replace_the_visitors() {
+        let head = b"GET /a HTTP/1.1\r\nHost: foo.example.com\r\nx-forwarded-for: 6.6.6.6\r\nX-Tunnelto-Sub-Domain: bar\r\nAccept: */*\r\n\r\n";
+        let out = inject_headers(
+            head,
+            "/a",
+            &headers(&[
+                ("X-Forwarded-For", "10.0.0.1"),
+                ("X-Tunnelto-Sub-Domain", "foo"),
+            ]),
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "GET /a HTTP/1.1\r\nHost: foo.example.com\r\nAccept: */*\r\nX-Forwarded-For: 10.0.0.1\r\nX-Tunnelto-Sub-Domain: foo\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn injected_path() {
+        let head = b"GET /a?q=1 HTTP/1.1\r\nHost: foo.example.com\r\n\r\n";
+        let out = inject_headers(head, "/b?q=1", &[]);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "GET /b?q=1 HTTP/1.1\r\nHost: foo.example.com\r\n\r\n"
+        );
+
+        // left alone otherwise
+        assert_eq!(inject_headers(head, "/a?q=1", &[]), head.to_vec());
+    }
+}
@@ -0,0 +1,250 @@
+//! Ask an operator's authorization service about every visitor request before it is
+//! tunneled (EXT_AUTHZ_URL), like Envoy's ext_authz, to enforce SSO, license checks or
+//! other visitor policies without changing the server. Visitor connections carry a
+//! single request each, so none gets past on a connection another request opened.
+//!
+//! The edge POSTs `{"request_id", "host", "sub_domain", "base_domain", "method", "path",
+//! "peer", "headers"}`, with only the visitor headers named in EXT_AUTHZ_HEADERS
+//! (`authorization,cookie` by default), and a bearer EXT_AUTHZ_SECRET if set. The service
+//! answers `{"allow": true}`, optionally with `headers` to add to the request (i.e. the
+//! user it resolved) and `cache_secs`, or `{"allow": false, "status": 401, "reason": ..}`;
+//! a 401 or 403 answer denies too.
+//!
+//! Decisions are cached for EXT_AUTHZ_CACHE_SECS by the request's sub-domain, method,
+//! path, visitor ip and forwarded headers. When the service fails or doesn't answer
+//! within EXT_AUTHZ_TIMEOUT_MS, requests are refused, or let through with
+//! EXT_AUTHZ_FAILURE=open.
+use super::*;
+use crate::edge::ErrorPage;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// cached decisions past which expired ones are swept
+const MAX_CACHED: usize = 10_000;
+
+const UNAUTHORIZED: ErrorPage = ErrorPage {
+    status: 401,
+    code: ErrorCode::Unauthorized,
+    message: "Unauthorized",
+};
+const AUTHZ_UNAVAILABLE: ErrorPage = ErrorPage {
+    status: 503,
+    code: ErrorCode::AuthorizationUnavailable,
+    message: "Error: Request could not be authorized",
+};
+
+#[derive(Debug, Clone)]
+pub struct ExtAuthzConfig {
+    pub url: String,
+    pub secret: Option<String>,
+    pub timeout: Duration,
+    /// let requests through when the service fails
+    pub fail_open: bool,
+    /// how long decisions are reused, unless the service says otherwise
+    pub cache_ttl: Duration,
+    /// visitor headers sent to the service, lowercase
+    pub headers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct AuthzRequest<'a> {
+    request_id: &'a str,
+    host: &'a str,
+    sub_domain: Option<&'a String>,
+    base_domain: Option<&'a String>,
+    method: &'a str,
+    path: &'a str,
+    peer: Option<String>,
+    headers: HashMap<String, &'a str>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Decision {
+    #[serde(default)]
+    allow: bool,
+    /// 401 or 403 when denying
+    status: Option<u16>,
+    reason: Option<String>,
+    /// added to allowed requests
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    cache_secs: Option<u64>,
+}
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::new();
+    static ref DECISIONS: DashMap<String, (Instant, Decision)> = DashMap::new();
+}
+
+pub struct ExtAuthz {
+    config: ExtAuthzConfig,
+}
+
+impl ExtAuthz {
+    pub fn from_config() -> Option<ExtAuthz> {
+        let config = CONFIG.ext_authz.clone()?;
+        info!("authorizing visitor requests with {}", &config.url);
+        Some(ExtAuthz { config })
+    }
+
+    fn forwarded_headers<'a>(&self, request: &'a EdgeRequest) -> HashMap<String, &'a str> {
+        self.config
+            .headers
+            .iter()
+            .filter_map(|name| Some((name.clone(), request.header(name)?)))
+            .collect()
+    }
+
+    fn cache_key(&self, request: &EdgeRequest, headers: &HashMap<String, &str>) -> String {
+        let mut hasher = sha2::Sha256::new();
+        for part in &[
+            request.sub_domain.as_deref().unwrap_or_default(),
+            &request.method,
+            &request.path,
+            &request
+                .peer_addr
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+        ] {
+            hasher.update(part.as_bytes());
+            hasher.update(b"\n");
+        }
+        for name in &self.config.headers {
+            hasher.update(headers.get(name).copied().unwrap_or_default().as_bytes());
+            hasher.update(b"\n");
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    async fn ask(
+        &self,
+        request: &EdgeRequest,
+        headers: HashMap<String, &str>,
+    ) -> Result<Decision, String> {
+        let body = AuthzRequest {
+            request_id: &request.request_id,
+            host: &request.host,
+            sub_domain: request.sub_domain.as_ref(),
+            base_domain: request.base_domain.as_ref(),
+            method: &request.method,
+            path: &request.path,
+            peer: request.peer_addr.map(|addr| addr.ip().to_string()),
+            headers,
+        };
+
+        let mut builder = CLIENT
+            .post(&self.config.url)
+            .timeout(self.config.timeout)
+            .json(&body);
+        if let Some(secret) = self.config.secret.as_ref() {
+            builder = builder.bearer_auth(secret);
+        }
+
+        let response = builder.send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            let denied = response.json::<Decision>().await.unwrap_or_default();
+            return Ok(Decision {
+                allow: false,
+                status: Some(status.as_u16()),
+                ..denied
+            });
+        }
+
+        response
+            .error_for_status()
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// The decision for the request, cached or asked for
+    async fn decide(&self, request: &EdgeRequest) -> Result<Decision, String> {
+        let headers = self.forwarded_headers(request);
+        let key = self.cache_key(request, &headers);
+
+        if let Some(cached) = DECISIONS.get(&key) {
+            if cached.0 > Instant::now() {
+                return Ok(cached.1.clone());
+            }
+        }
+
+        let decision = self.ask(request, headers).await?;
+        let ttl = decision
+            .cache_secs
+            .map(Duration::from_secs)
+            .unwrap_or(self.config.cache_ttl);
+        if ttl > Duration::ZERO {
+            if DECISIONS.len() >= MAX_CACHED {
+                let now = Instant::now();
+                DECISIONS.retain(|_, (expires, _)| *expires > now);
+            }
+            DECISIONS.insert(key, (Instant::now() + ttl, decision.clone()));
+        }
+        Ok(decision)
+    }
+}
+
+impl EdgeFilter for ExtAuthz {
+    fn name(&self) -> &'static str {
+        "ext_authz"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            if request.sub_domain.is_none() {
+                return FilterAction::Continue;
+            }
+            request.one_request = true;
+            let error_format = request
+                .tunnel()
+                .map(|client| client.error_format)
+                .unwrap_or(ErrorFormat::Text);
+
+            let decision = match self.decide(request).await {
+                Ok(decision) => decision,
+                Err(e) if self.config.fail_open => {
+                    log::warn!(
+                        "authorization failed, letting request through request_id={}: {}",
+                        request.request_id,
+                        e
+                    );
+                    return FilterAction::Continue;
+                }
+                Err(e) => {
+                    error!(
+                        "authorization failed request_id={}: {}",
+                        request.request_id, e
+                    );
+                    return FilterAction::Respond(
+                        AUTHZ_UNAVAILABLE.render(Some(&request.request_id), error_format),
+                    );
+                }
+            };
+
+            if !decision.allow {
+                log::debug!(
+                    "authorization denied {} {} request_id={}: {}",
+                    request.host,
+                    request.path,
+                    request.request_id,
+                    decision.reason.as_deref().unwrap_or("no reason given")
+                );
+                let page = match decision.status {
+                    Some(401) => UNAUTHORIZED,
+                    _ => edge::FORBIDDEN,
+                };
+                return FilterAction::Respond(page.render(Some(&request.request_id), error_format));
+            }
+
+            request.inject_headers.extend(decision.headers);
+            FilterAction::Continue
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,184 @@
+//! In-place upgrades without downtime. With HANDOVER set, the public, control, passthrough
+//! and network ports are bound with SO_REUSEPORT, so a new `tunnelto_server` can start
+//! next to the running one on the same ports. A SIGTERM then hands over to it: the old
+//! process stops accepting connections, which leaves them all to the new one, asks its
+//! clients to reconnect and exits once their streams finished, or after
+//! HANDOVER_DRAIN_SECS (60 by default).
+//!
+//! Clients reconnect with their key or reconnect token, so both processes need the same
+//! MASTER_SIG_KEY. Clients without handover support are disconnected once their streams
+//! finished and come back on their own a few seconds later. For HANDOVER_WAIT_SECS (10)
+//! after starting, visitors to a tunnel that hasn't reconnected yet are held until it
+//! does, instead of being told it doesn't exist.
+use super::*;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::watch;
+
+/// How often draining checks on the clients left
+const DRAIN_TICK: Duration = Duration::from_millis(500);
+
+/// How often a held visitor looks for its tunnel again
+const WAIT_TICK: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HandoverConfig {
+    /// how long the old process waits for streams to finish
+    pub drain: Duration,
+    /// how long after starting visitors wait for their tunnel to reconnect
+    pub wait: Duration,
+}
+
+impl HandoverConfig {
+    /// Read the config from the env, `None` unless HANDOVER is set
+    pub fn from_env() -> Option<Self> {
+        crate::config::env_var("HANDOVER").ok()?;
+
+        let secs = |name: &str, default: u64| {
+            Duration::from_secs(
+                crate::config::env_var(name)
+                    .map(|n| {
+                        n.parse::<u64>()
+                            .unwrap_or_else(|_| panic!("invalid {}={}", name, n))
+                    })
+                    .unwrap_or(default),
+            )
+        };
+        Some(HandoverConfig {
+            drain: secs("HANDOVER_DRAIN_SECS", 60),
+            wait: secs("HANDOVER_WAIT_SECS", 10),
+        })
+    }
+}
+
+lazy_static! {
+    static ref STARTED_AT: Instant = Instant::now();
+    static ref HANDING_OVER: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+}
+
+/// Bind a listener, shared with the process taking over if handovers are on
+pub fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(CONFIG.handover.is_some())?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Resolves once this process is handing over
+pub async fn started() {
+    let mut handing_over = HANDING_OVER.1.clone();
+    while !*handing_over.borrow() {
+        if handing_over.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The next connection on the listener, `None` once handing over
+pub async fn accept(listener: &TcpListener) -> Option<std::io::Result<(TcpStream, SocketAddr)>> {
+    tokio::select! {
+        accepted = listener.accept() => Some(accepted),
+        _ = started() => None,
+    }
+}
+
+/// The connections on the listener until handing over, for warp
+pub fn incoming(listener: TcpListener) -> impl futures::Stream<Item = std::io::Result<TcpStream>> {
+    futures::stream::unfold(listener, |listener| async move {
+        let accepted = accept(&listener).await?;
+        Some((accepted.map(|(socket, _)| socket), listener))
+    })
+}
+
+/// Hand over on SIGTERM, if handovers are on
+pub fn spawn() {
+    lazy_static::initialize(&STARTED_AT);
+    if CONFIG.handover.is_none() {
+        return;
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!("failed to listen for SIGTERM, handovers are off: {:?}", e);
+                return;
+            }
+        };
+        terminate.recv().await;
+        info!("handing over: no longer accepting connections");
+        let _ = HANDING_OVER.0.send(true);
+    });
+}
+
+/// Ask clients to reconnect and let their streams finish, disconnecting each once it has
+/// none left. Returns when all are gone or the drain time is up.
+pub async fn drain() {
+    let config = match CONFIG.handover.as_ref() {
+        Some(config) => config,
+        None => return,
+    };
+    let deadline = Instant::now() + config.drain;
+    let mut asked = HashSet::new();
+
+    loop {
+        let clients = Connections::all();
+        if clients.is_empty() {
+            info!("handed over all tunnels");
+            return;
+        }
+        if Instant::now() >= deadline {
+            log::warn!(
+                "handover drain timed out, dropping {} tunnels",
+                clients.len()
+            );
+            return;
+        }
+
+        for client in clients {
+            if client.handover && !asked.contains(&client.id) {
+                let mut tx = client.tx.clone();
+                if tx.try_send(ControlPacket::Handover) {
+                    asked.insert(client.id.clone());
+                }
+                continue;
+            }
+            if serving(&client.id) == 0 {
+                log::debug!("handed over {}", &client.id);
+                Connections::remove(&client);
+            }
+        }
+        tokio::time::sleep(DRAIN_TICK).await;
+    }
+}
+
+/// The client's streams with a visitor still on the other end
+fn serving(client_id: &ClientId) -> usize {
+    ACTIVE_STREAMS
+        .iter()
+        .filter(|stream| &stream.client.id == client_id && !stream.stats.visitor_closed())
+        .count()
+}
+
+/// The client serving `host` once it connects, while visitors are held after starting
+pub async fn wait_for_tunnel(host: &String) -> Option<ConnectedClient> {
+    let config = CONFIG.handover.as_ref()?;
+    let until = *STARTED_AT + config.wait;
+
+    while Instant::now() < until {
+        tokio::time::sleep(WAIT_TICK).await;
+        if let Some(client) = Connections::find_by_host(host) {
+            return Some(client);
+        }
+    }
+    None
+}
@@ -0,0 +1,333 @@
+//! An audit log of every handshake attempt, accepted or not, for operators looking into
+//! abuse and failed logins: when, the client and account, the sub-domain it asked for,
+//! the outcome and the address it came from. HANDSHAKE_AUDIT picks where records go:
+//!
+//! - `stdout`, one json object per line
+//! - `file:/var/log/tunnelto/handshakes.jsonl`, the same lines appended to a file
+//! - `dynamodb` or `dynamodb:<table>`, an item per attempt keyed by `attempt_id` in
+//!   `tunnelto_handshakes` or the given table, in the auth backend's region (see
+//!   `DynamoConfig`), expiring after HANDSHAKE_AUDIT_RETENTION_DAYS (90) by its TTL
+//!   attribute `expires_at`
+//!
+//! The log is off unless set. Records are queued and written in the background: when the
+//! sink falls behind they are dropped, never held up the handshakes.
+use super::*;
+use crate::client_auth::ClientHandshake;
+use rusoto_core::{Client, HttpClient};
+use rusoto_credential::EnvironmentProvider;
+use rusoto_dynamodb::{AttributeValue, DynamoDb, DynamoDbClient, PutItemInput};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Records waiting for the sink
+const QUEUE: usize = 1024;
+
+const DEFAULT_TABLE: &str = "tunnelto_handshakes";
+
+lazy_static! {
+    static ref RECORDS: RwLock<Option<mpsc::Sender<HandshakeRecord>>> = RwLock::new(None);
+}
+
+/// Where handshake records go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditSink {
+    Stdout,
+    File(PathBuf),
+    DynamoDb { table: String },
+}
+
+impl FromStr for AuditSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("stdout"), None) => Ok(AuditSink::Stdout),
+            (Some("file"), Some(path)) if !path.is_empty() => Ok(AuditSink::File(path.into())),
+            (Some("dynamodb"), None) => Ok(AuditSink::DynamoDb {
+                table: DEFAULT_TABLE.to_string(),
+            }),
+            (Some("dynamodb"), Some(table)) if !table.is_empty() => Ok(AuditSink::DynamoDb {
+                table: table.to_string(),
+            }),
+            _ => Err(format!(
+                "unknown sink `{}`, expected `stdout`, `file:<path>` or `dynamodb[:<table>]`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HandshakeAuditConfig {
+    pub sink: AuditSink,
+    pub retention_days: u32,
+}
+
+impl HandshakeAuditConfig {
+    /// Read the config from the env, `None` unless HANDSHAKE_AUDIT is set
+    pub fn from_env() -> Option<Self> {
+        let sink = crate::config::env_var("HANDSHAKE_AUDIT").ok()?;
+        let sink = sink
+            .parse()
+            .unwrap_or_else(|e| panic!("invalid HANDSHAKE_AUDIT: {}", e));
+        let retention_days = crate::config::env_var("HANDSHAKE_AUDIT_RETENTION_DAYS")
+            .map(|n| {
+                n.parse::<u32>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .unwrap_or_else(|| panic!("invalid HANDSHAKE_AUDIT_RETENTION_DAYS={}", n))
+            })
+            .unwrap_or(90);
+        Some(HandshakeAuditConfig {
+            sink,
+            retention_days,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Accepted,
+    Rejected,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Accepted => "accepted",
+            Outcome::Rejected => "rejected",
+        }
+    }
+}
+
+/// A handshake attempt, filled in as the handshake learns who the client is
+#[derive(Debug, Clone, Serialize)]
+pub struct HandshakeRecord {
+    pub attempt_id: Uuid,
+    #[serde(serialize_with = "rfc3339")]
+    pub at: chrono::DateTime<chrono::Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<Uuid>,
+    /// the sub-domain the client asked for, none for a random one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_sub_domain: Option<String>,
+    /// the sub-domain it got, if accepted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_domain: Option<String>,
+    /// the version of the protocol crate the client said it was built with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+    pub outcome: Outcome,
+    /// the error code it was rejected with, i.e. `TUN-3001`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<&'static str>,
+    /// why it was rejected, in more detail than the client was told
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<IpAddr>,
+}
+
+impl HandshakeRecord {
+    pub fn new(peer: Option<SocketAddr>) -> Self {
+        HandshakeRecord {
+            attempt_id: Uuid::new_v4(),
+            at: chrono::Utc::now(),
+            client_id: None,
+            account_id: None,
+            requested_sub_domain: None,
+            sub_domain: None,
+            client_version: None,
+            outcome: Outcome::Rejected,
+            error_code: None,
+            reason: None,
+            source_ip: peer.map(|peer| peer.ip()),
+        }
+    }
+}
+
+fn rfc3339<S: serde::Serializer>(
+    at: &chrono::DateTime<chrono::Utc>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+/// Queue the record for the sink, if the log is on
+fn record(record: HandshakeRecord) {
+    if let Some(records) = RECORDS.read().unwrap().as_ref() {
+        if records.try_send(record).is_err() {
+            log::debug!("handshake audit queue full, dropping record");
+        }
+    }
+}
+
+/// The handshake succeeded
+pub fn accepted(mut attempt: HandshakeRecord, handshake: &ClientHandshake) {
+    attempt.outcome = Outcome::Accepted;
+    attempt.client_id = Some(handshake.id.to_string());
+    attempt.account_id = handshake.account_id;
+    attempt.sub_domain = Some(handshake.sub_domain.clone());
+    record(attempt);
+}
+
+/// The handshake failed
+pub fn rejected(mut attempt: HandshakeRecord, error: &TunnelError) {
+    attempt.outcome = Outcome::Rejected;
+    attempt.error_code = Some(error.code().code());
+    attempt.reason = Some(match error {
+        TunnelError::InvalidClientHello(reason)
+        | TunnelError::AuthFailed(reason)
+        | TunnelError::KeyRejected(reason)
+        | TunnelError::Internal(reason) => reason.clone(),
+        error => error.to_string(),
+    });
+    record(attempt);
+}
+
+/// Start writing recorded handshakes to the sink
+pub fn spawn(config: &'static HandshakeAuditConfig) {
+    let (tx, mut rx) = mpsc::channel(QUEUE);
+    *RECORDS.write().unwrap() = Some(tx);
+
+    tokio::spawn(async move {
+        let mut sink = Sink::new(config);
+        while let Some(attempt) = rx.recv().await {
+            if let Err(e) = sink.write(&attempt).await {
+                log::warn!("failed to write handshake record: {}", e);
+            }
+        }
+    });
+}
+
+/// The open sink, files opened on first use
+enum Sink {
+    Stdout,
+    File(PathBuf, Option<tokio::fs::File>),
+    DynamoDb {
+        client: Option<DynamoDbClient>,
+        table: String,
+        retention: chrono::Duration,
+    },
+}
+
+impl Sink {
+    fn new(config: &HandshakeAuditConfig) -> Self {
+        match &config.sink {
+            AuditSink::Stdout => Sink::Stdout,
+            AuditSink::File(path) => Sink::File(path.clone(), None),
+            AuditSink::DynamoDb { table } => {
+                let region = crate::auth_db::DynamoConfig::from_env().region;
+                let client = HttpClient::new()
+                    .map(|http| {
+                        let client = Client::new_with(EnvironmentProvider::default(), http);
+                        DynamoDbClient::new_with_client(client, region)
+                    })
+                    .map_err(|e| log::error!("failed to create the handshake audit client: {}", e))
+                    .ok();
+                Sink::DynamoDb {
+                    client,
+                    table: table.clone(),
+                    retention: chrono::Duration::days(config.retention_days as i64),
+                }
+            }
+        }
+    }
+
+    async fn write(&mut self, attempt: &HandshakeRecord) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Sink::Stdout => {
+                println!("{}", serde_json::to_string(attempt)?);
+            }
+            Sink::File(path, file) => {
+                if file.is_none() {
+                    *file = Some(
+                        tokio::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(&path)
+                            .await?,
+                    );
+                }
+
+                let mut line = serde_json::to_vec(attempt)?;
+                line.push(b'\n');
+                if let Some(open) = file.as_mut() {
+                    if let Err(e) = open.write_all(&line).await {
+                        // reopen it next time, i.e. after it was rotated away
+                        *file = None;
+                        return Err(e.into());
+                    }
+                }
+            }
+            Sink::DynamoDb {
+                client,
+                table,
+                retention,
+            } => {
+                let client = client.as_ref().ok_or("no DynamoDB client")?;
+                let input = PutItemInput {
+                    table_name: table.clone(),
+                    item: item(attempt, *retention),
+                    ..Default::default()
+                };
+                client.put_item(input).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The record as a DynamoDB item
+fn item(attempt: &HandshakeRecord, retention: chrono::Duration) -> HashMap<String, AttributeValue> {
+    let string = |s: String| AttributeValue {
+        s: Some(s),
+        ..Default::default()
+    };
+    let number = |n: i64| AttributeValue {
+        n: Some(n.to_string()),
+        ..Default::default()
+    };
+
+    let mut item = HashMap::new();
+    item.insert(
+        "attempt_id".to_string(),
+        string(attempt.attempt_id.to_string()),
+    );
+    item.insert("at".to_string(), string(attempt.at.to_rfc3339()));
+    item.insert(
+        "outcome".to_string(),
+        string(attempt.outcome.as_str().to_string()),
+    );
+    item.insert(
+        "expires_at".to_string(),
+        number((attempt.at + retention).timestamp()),
+    );
+    let optional = [
+        ("client_id", attempt.client_id.clone()),
+        ("account_id", attempt.account_id.map(|id| id.to_string())),
+        ("requested_subdomain", attempt.requested_sub_domain.clone()),
+        ("subdomain", attempt.sub_domain.clone()),
+        ("client_version", attempt.client_version.clone()),
+        ("error_code", attempt.error_code.map(String::from)),
+        ("reason", attempt.reason.clone()),
+        ("source_ip", attempt.source_ip.map(|ip| ip.to_string())),
+    ];
+    for (name, value) in optional.iter() {
+        if let Some(value) = value {
+            item.insert(name.to_string(), string(value.clone()));
+        }
+    }
+    item
+}
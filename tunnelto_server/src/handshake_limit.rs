@@ -0,0 +1,123 @@
+//! Refuse new control connections from IPs that keep failing to authenticate, to keep
+//! brute force off the auth backend: HANDSHAKE_MAX_FAILURES failed client hellos within
+//! HANDSHAKE_FAILURE_WINDOW_SECS get the IP's websocket upgrades refused with a 429 for
+//! HANDSHAKE_BLOCK_SECS. Each instance counts on its own, set HANDSHAKE_MAX_FAILURES=0
+//! to turn it off.
+use super::*;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// IPs tracked past which expired ones are swept
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeLimit {
+    pub max_failures: u32,
+    pub window: Duration,
+    pub block: Duration,
+}
+
+impl HandshakeLimit {
+    /// Read the limit from the env, `None` if turned off
+    pub fn from_env() -> Option<Self> {
+        let number = |name: &str, default: u64| {
+            crate::config::env_var(name)
+                .map(|n| {
+                    n.parse::<u64>()
+                        .unwrap_or_else(|_| panic!("invalid {}={}", name, n))
+                })
+                .unwrap_or(default)
+        };
+
+        let max_failures = number("HANDSHAKE_MAX_FAILURES", 10) as u32;
+        if max_failures == 0 {
+            return None;
+        }
+        Some(HandshakeLimit {
+            max_failures,
+            window: Duration::from_secs(number("HANDSHAKE_FAILURE_WINDOW_SECS", 300)),
+            block: Duration::from_secs(number("HANDSHAKE_BLOCK_SECS", 900)),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn expired(&self, limit: &HandshakeLimit, now: Instant) -> bool {
+        match self.blocked_until {
+            Some(until) => until <= now,
+            None => now.duration_since(self.since) > limit.window,
+        }
+    }
+}
+
+lazy_static! {
+    static ref FAILURES: DashMap<IpAddr, Failures> = DashMap::new();
+}
+
+/// How long the IP's connections are still refused for, if they are
+pub fn blocked(ip: IpAddr) -> Option<Duration> {
+    CONFIG.handshake_limit?;
+    let until = FAILURES.get(&ip)?.blocked_until?;
+    until.checked_duration_since(Instant::now())
+}
+
+/// The IP failed a handshake, block it once it failed too often
+pub fn failed(ip: IpAddr) {
+    let limit = match CONFIG.handshake_limit.as_ref() {
+        Some(limit) => limit,
+        None => return,
+    };
+    let now = Instant::now();
+
+    if FAILURES.len() >= MAX_TRACKED {
+        FAILURES.retain(|_, failures| !failures.expired(limit, now));
+    }
+
+    let mut failures = FAILURES.entry(ip).or_insert(Failures {
+        count: 0,
+        since: now,
+        blocked_until: None,
+    });
+    if failures.expired(limit, now) {
+        *failures = Failures {
+            count: 0,
+            since: now,
+            blocked_until: None,
+        };
+    }
+
+    failures.count += 1;
+    if failures.count >= limit.max_failures && failures.blocked_until.is_none() {
+        log::warn!(
+            "blocking control connections from {} for {:?} after {} failed handshakes",
+            ip,
+            limit.block,
+            failures.count
+        );
+        failures.blocked_until = Some(now + limit.block);
+    }
+}
+
+/// The IP authenticated, forget its failures
+pub fn succeeded(ip: IpAddr) {
+    FAILURES.remove(&ip);
+}
+
+/// Answer to an upgrade from a blocked IP
+pub fn refused(retry_after: Duration) -> impl warp::Reply {
+    warp::reply::with_header(
+        warp::reply::with_status(
+            "Too many failed handshakes, try again later",
+            warp::http::StatusCode::TOO_MANY_REQUESTS,
+        ),
+        "Retry-After",
+        (retry_after.as_secs() + 1).to_string(),
+    )
+}
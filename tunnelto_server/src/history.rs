@@ -0,0 +1,149 @@
+use super::*;
+use crate::auth_db::{HistoryRecord, HISTORY_RETENTION_DAYS};
+use serde::Serialize;
+use thiserror::Error;
+use tunnelto_lib::{HistoryEntry, HistoryRequest, HistoryResponse};
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+/// Longest client hostname we keep, the longest a DNS name can be
+const MAX_HOSTNAME_LEN: usize = 253;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("sub-domain history is not enabled on this server")]
+    Disabled,
+
+    #[error("auth error: {0}")]
+    Auth(#[from] crate::auth_db::Error),
+}
+
+/// Remember the sub-domain an account's tunnel connected on
+pub fn record(client: &ConnectedClient, client_hostname: Option<String>) {
+    if !CONFIG.history {
+        return;
+    }
+    let account_id = match client.account_id {
+        Some(account_id) => account_id,
+        None => return,
+    };
+
+    // the hostname is whatever the client says it is, only keep something printable
+    let client_hostname = client_hostname
+        .map(|h| {
+            h.chars()
+                .filter(|c| !c.is_control())
+                .take(MAX_HOSTNAME_LEN)
+                .collect::<String>()
+        })
+        .filter(|h| !h.is_empty());
+
+    let record = HistoryRecord {
+        account_id,
+        sub_domain: client.host.clone(),
+        last_connected_at: chrono::Utc::now(),
+        client_hostname,
+    };
+    tokio::spawn(async move {
+        if let Err(e) = AUTH_DB_SERVICE.put_history_record(&record).await {
+            error!("failed to record sub-domain history: {:?}", e);
+        }
+    });
+}
+
+/// Newest first, without records past retention the backend hasn't expired yet
+fn recent(mut records: Vec<HistoryRecord>) -> Vec<HistoryRecord> {
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(HISTORY_RETENTION_DAYS);
+    records.retain(|r| r.last_connected_at > cutoff);
+    records.sort_by_key(|r| std::cmp::Reverse(r.last_connected_at));
+    records
+}
+
+async fn history(request: HistoryRequest) -> Result<HistoryResponse, Error> {
+    if !CONFIG.history {
+        return Err(Error::Disabled);
+    }
+
+    let account = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?;
+    let records = AUTH_DB_SERVICE.get_history(&account.account_id).await?;
+
+    Ok(HistoryResponse::Entries {
+        entries: recent(records)
+            .into_iter()
+            .map(|r| HistoryEntry {
+                sub_domain: r.sub_domain,
+                last_connected_at: r.last_connected_at.to_rfc3339(),
+                client_hostname: r.client_hostname,
+            })
+            .collect(),
+    })
+}
+
+/// Handle a history request from the control server
+pub async fn handle_history(
+    request: HistoryRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (response, status) = match history(request).await {
+        Ok(response) => (response, StatusCode::OK),
+        Err(e @ Error::Disabled) => (
+            HistoryResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => (
+            HistoryResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+/// Who used a sub-domain, for operators following up on abuse reports
+#[derive(Serialize)]
+struct SubDomainUse {
+    account_id: Uuid,
+    last_connected_at: String,
+    client_hostname: Option<String>,
+}
+
+pub async fn handle_admin_history(
+    sub_domain: String,
+    admin_key: Option<String>,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !CONFIG.history || !crate::siem::admin("sub_domain_history", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+
+    let records = match AUTH_DB_SERVICE.get_sub_domain_history(&sub_domain).await {
+        Ok(records) => records,
+        Err(e) => {
+            error!("failed to get history of {}: {:?}", &sub_domain, e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&Vec::<SubDomainUse>::new()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
+
+    let uses = recent(records)
+        .into_iter()
+        .map(|r| SubDomainUse {
+            account_id: r.account_id,
+            last_connected_at: r.last_connected_at.to_rfc3339(),
+            client_hostname: r.client_hostname,
+        })
+        .collect::<Vec<_>>();
+    Ok(warp::reply::with_status(
+        warp::reply::json(&uses),
+        StatusCode::OK,
+    ))
+}
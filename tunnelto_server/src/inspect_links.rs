@@ -0,0 +1,218 @@
+//! Inspect links: a client shares one captured request and its response, redacted, and
+//! anyone with the link can read it at `/inspect/<token>` until it expires
+//! (INSPECT_LINK_TTL_SECS). Snapshots are only kept in memory, on the instance that
+//! created them, so links die with it.
+use super::*;
+use dashmap::DashMap;
+use std::time::Instant;
+use thiserror::Error;
+use tunnelto_lib::inspect::{InspectLinkRequest, InspectLinkResponse, InspectSnapshot};
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+/// open links per account, the oldest is dropped to make room
+const MAX_LINKS_PER_ACCOUNT: usize = 20;
+/// open links on this instance
+const MAX_LINKS: usize = 10_000;
+
+lazy_static! {
+    /// keyed by token
+    static ref LINKS: DashMap<String, Link> = DashMap::new();
+}
+
+struct Link {
+    account_id: Uuid,
+    snapshot: InspectSnapshot,
+    created: Instant,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("inspect links are turned off on this server")]
+    Disabled,
+
+    #[error("this server holds too many inspect links, try again later")]
+    Full,
+
+    #[error("auth error: {0}")]
+    Auth(#[from] auth_db::Error),
+}
+
+/// Drop expired links, and the account's oldest if it has too many
+fn make_room(account_id: &Uuid) -> Result<(), Error> {
+    LINKS.retain(|_, link| link.created.elapsed() < CONFIG.inspect_link_ttl);
+
+    let mut own = LINKS
+        .iter()
+        .filter(|link| &link.account_id == account_id)
+        .map(|link| (link.key().clone(), link.created))
+        .collect::<Vec<_>>();
+    if own.len() >= MAX_LINKS_PER_ACCOUNT {
+        own.sort_by_key(|(_, created)| *created);
+        for (token, _) in own.iter().take(own.len() + 1 - MAX_LINKS_PER_ACCOUNT) {
+            LINKS.remove(token);
+        }
+    }
+
+    if LINKS.len() >= MAX_LINKS {
+        return Err(Error::Full);
+    }
+    Ok(())
+}
+
+/// The link on this instance's public address, if it has one
+fn public_url(path: &str) -> Option<String> {
+    let mut url = url::Url::parse(CONFIG.public_control_url.as_ref()?).ok()?;
+    let scheme = if url.scheme() == "ws" {
+        "http"
+    } else {
+        "https"
+    };
+    url.set_scheme(scheme).ok()?;
+    url.set_path(path);
+    Some(url.to_string())
+}
+
+async fn create(request: InspectLinkRequest) -> Result<InspectLinkResponse, Error> {
+    if CONFIG.inspect_link_ttl.as_secs() == 0 {
+        return Err(Error::Disabled);
+    }
+
+    let account = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?;
+    make_room(&account.account_id)?;
+
+    let token = format!(
+        "{}{}",
+        ServerHello::random_domain(),
+        ServerHello::random_domain()
+    );
+    LINKS.insert(
+        token.clone(),
+        Link {
+            account_id: account.account_id,
+            // whatever the client did, never serve credentials
+            snapshot: request.snapshot.redacted(),
+            created: Instant::now(),
+        },
+    );
+
+    let path = format!("/inspect/{}", token);
+    let expires_at = chrono::Utc::now()
+        + chrono::Duration::from_std(CONFIG.inspect_link_ttl)
+            .unwrap_or_else(|_| chrono::Duration::zero());
+    log::info!("account {} shared an inspect link", &account.account_id);
+
+    Ok(InspectLinkResponse::Created {
+        url: public_url(&path),
+        path,
+        expires_at: expires_at.to_rfc3339(),
+    })
+}
+
+/// Handle a client sharing a snapshot
+pub async fn handle_create(
+    request: InspectLinkRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (response, status) = match create(request).await {
+        Ok(response) => (response, StatusCode::OK),
+        Err(e @ Error::Auth(_)) => (
+            InspectLinkResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+        Err(e) => (
+            InspectLinkResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+/// Serve a shared snapshot, read-only
+pub async fn handle_view(token: String) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let page = match LINKS.get(&token) {
+        Some(link) if link.created.elapsed() < CONFIG.inspect_link_ttl => render(&link.snapshot),
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    Ok(warp::http::Response::builder()
+        .header(warp::http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(warp::http::header::CACHE_CONTROL, "no-store")
+        .header(
+            warp::http::header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; style-src 'unsafe-inline'",
+        )
+        .header(warp::http::header::REFERRER_POLICY, "no-referrer")
+        .header("x-robots-tag", "noindex")
+        .body(page)
+        .unwrap_or_default())
+}
+
+fn render(snapshot: &InspectSnapshot) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{method} {path} - tunnelto</title>
+<style>body {{ font-family: sans-serif; margin: 2em; }} pre {{ background: #f5f5f5; padding: 1em; overflow-x: auto; }} td {{ font-family: monospace; padding-right: 1em; vertical-align: top; }}</style>
+</head>
+<body>
+<h1>{method} {path} &rarr; {status}</h1>
+<p>Captured at {captured_at}{request_id}. Credentials were redacted before sharing.</p>
+<h2>Request</h2>
+{request_headers}
+<pre>{request_body}</pre>
+<h2>Response</h2>
+{response_headers}
+<pre>{response_body}</pre>
+</body>
+</html>
+"#,
+        method = escape(&snapshot.method),
+        path = escape(&snapshot.path),
+        status = snapshot.status,
+        captured_at = escape(&snapshot.captured_at),
+        request_id = snapshot
+            .request_id
+            .as_ref()
+            .map(|id| format!(", request id {}", escape(id)))
+            .unwrap_or_default(),
+        request_headers = render_headers(&snapshot.request_headers),
+        request_body = escape(&snapshot.request_body),
+        response_headers = render_headers(&snapshot.response_headers),
+        response_body = escape(&snapshot.response_body),
+    )
+}
+
+fn render_headers(headers: &[(String, String)]) -> String {
+    let rows = headers
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "<tr><td>{}</td><td>{}</td></tr>",
+                escape(name),
+                escape(value)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("<table>\n{}\n</table>", rows)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,227 @@
+//! Lock auth keys that keep failing, to slow credential stuffing down: after
+//! KEY_LOCKOUT_MAX_FAILURES consecutive failed handshakes with the same key from the same
+//! address, the key is refused to that address for KEY_LOCKOUT_SECS without a lookup in
+//! the auth backend, and a `key_locked` warning goes to the log and the SIEM. Keys are
+//! tracked by their hash (`key_id`, as the store holds them), each instance on its own.
+//! Signing clients send their `key_id` in the clear, so a lock only holds for the address
+//! that earned it: anyone can fail with someone else's `key_id`, but only lock themselves
+//! out. Set KEY_LOCKOUT_MAX_FAILURES=0 to turn it off.
+use super::*;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tunnelto_lib::TunnelError;
+
+/// Keys tracked past which unlocked ones are swept
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyLockout {
+    pub max_failures: u32,
+    pub cooldown: Duration,
+}
+
+impl KeyLockout {
+    /// Read the lockout from the env, `None` if turned off
+    pub fn from_env() -> Option<Self> {
+        let number = |name: &str, default: u64| match crate::config::env_var(name) {
+            Ok(n) => n.parse::<u64>().unwrap_or_else(|_| {
+                log::error!("invalid {}={}, using {}", name, n, default);
+                default
+            }),
+            Err(_) => default,
+        };
+
+        let max_failures = number("KEY_LOCKOUT_MAX_FAILURES", 5) as u32;
+        if max_failures == 0 {
+            return None;
+        }
+        Some(KeyLockout {
+            max_failures,
+            cooldown: Duration::from_secs(number("KEY_LOCKOUT_SECS", 600)),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// A key, by its `key_id`, and the address it's tried from
+type Attempts = (String, Option<IpAddr>);
+
+lazy_static! {
+    static ref FAILURES: DashMap<Attempts, Failures> = DashMap::new();
+}
+
+/// Refuse the key, by its `key_id`, to `source_ip` while it's locked there
+pub fn check(key_hash: &str, source_ip: Option<IpAddr>) -> Result<(), TunnelError> {
+    if CONFIG.key_lockout.is_none() {
+        return Ok(());
+    }
+    locked(&(key_hash.to_string(), source_ip), Instant::now())
+}
+
+fn locked(attempts: &Attempts, now: Instant) -> Result<(), TunnelError> {
+    let locked_for = FAILURES
+        .get(attempts)
+        .and_then(|failures| failures.locked_until)
+        .and_then(|until| until.checked_duration_since(now));
+
+    match locked_for {
+        Some(locked_for) => Err(TunnelError::KeyRejected(format!(
+            "the key is locked after repeated failures, try again in {}s",
+            locked_for.as_secs() + 1
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Count a handshake with the key, by its `key_id`, from `source_ip`: failures to
+/// authenticate it towards a lock there, a success clears them
+pub fn record<T>(key_hash: &str, result: &Result<T, TunnelError>, source_ip: Option<IpAddr>) {
+    let lockout = match CONFIG.key_lockout.as_ref() {
+        Some(lockout) => lockout,
+        None => return,
+    };
+
+    let attempts = (key_hash.to_string(), source_ip);
+    if let Some(failures) = count(lockout, &attempts, result, Instant::now()) {
+        log::warn!(
+            "key_locked key_id={} failures={} cooldown_secs={} source_ip={}",
+            key_hash,
+            failures,
+            lockout.cooldown.as_secs(),
+            source_ip.map_or("-".to_string(), |ip| ip.to_string())
+        );
+        crate::siem::key_locked(key_hash, failures, lockout.cooldown, source_ip);
+    }
+}
+
+/// The failures the key was locked after, if this one locked it
+fn count<T>(
+    lockout: &KeyLockout,
+    attempts: &Attempts,
+    result: &Result<T, TunnelError>,
+    now: Instant,
+) -> Option<u32> {
+    match result {
+        Ok(_) => {
+            FAILURES.remove(attempts);
+            return None;
+        }
+        Err(TunnelError::AuthFailed(_)) | Err(TunnelError::KeyRejected(_)) => {}
+        // the backend failing says nothing about the key
+        Err(_) => return None,
+    }
+
+    if FAILURES.len() >= MAX_TRACKED {
+        FAILURES.retain(|_, failures| failures.locked_until.is_some_and(|until| until > now));
+    }
+
+    let mut failures = FAILURES.entry(attempts.clone()).or_insert(Failures {
+        count: 0,
+        locked_until: None,
+    });
+    // a lock that ran out gives the key a fresh start
+    if failures.locked_until.is_some_and(|until| until <= now) {
+        *failures = Failures {
+            count: 0,
+            locked_until: None,
+        };
+    }
+
+    failures.count += 1;
+    if failures.count >= lockout.max_failures && failures.locked_until.is_none() {
+        failures.locked_until = Some(now + lockout.cooldown);
+        return Some(failures.count);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCKOUT: KeyLockout = KeyLockout {
+        max_failures: 3,
+        cooldown: Duration::from_secs(60),
+    };
+
+    fn from(key_hash: &str) -> Attempts {
+        (key_hash.to_string(), Some([192, 0, 2, 1].into()))
+    }
+
+    fn failed() -> Result<(), TunnelError> {
+        Err(TunnelError::AuthFailed("bad key".into()))
+    }
+
+    #[test]
+    fn locks_after_max_failures() {
+        let now = Instant::now();
+        assert_eq!(count(&LOCKOUT, &from("lock"), &failed(), now), None);
+        assert_eq!(count(&LOCKOUT, &from("lock"), &failed(), now), None);
+        assert!(locked(&from("lock"), now).is_ok());
+
+        assert_eq!(count(&LOCKOUT, &from("lock"), &failed(), now), Some(3));
+        assert!(matches!(
+            locked(&from("lock"), now),
+            Err(TunnelError::KeyRejected(_))
+        ));
+        // other keys aren't affected
+        assert!(locked(&from("other"), now).is_ok());
+        // nor locked twice
+        assert_eq!(count(&LOCKOUT, &from("lock"), &failed(), now), None);
+    }
+
+    #[test]
+    fn locks_only_the_failing_source() {
+        let now = Instant::now();
+        for _ in 0..LOCKOUT.max_failures {
+            count(&LOCKOUT, &from("victim"), &failed(), now);
+        }
+        assert!(locked(&from("victim"), now).is_err());
+
+        // the key's owner, connecting from elsewhere, still gets in
+        let owner = ("victim".to_string(), Some([198, 51, 100, 7].into()));
+        assert!(locked(&owner, now).is_ok());
+        assert!(locked(&("victim".to_string(), None), now).is_ok());
+    }
+
+    #[test]
+    fn success_clears_failures() {
+        let now = Instant::now();
+        count(&LOCKOUT, &from("success"), &failed(), now);
+        count(&LOCKOUT, &from("success"), &failed(), now);
+        count(&LOCKOUT, &from("success"), &Ok(()), now);
+        assert_eq!(count(&LOCKOUT, &from("success"), &failed(), now), None);
+        assert_eq!(count(&LOCKOUT, &from("success"), &failed(), now), None);
+        assert!(locked(&from("success"), now).is_ok());
+    }
+
+    #[test]
+    fn backend_errors_dont_count() {
+        let now = Instant::now();
+        let backend_down: Result<(), TunnelError> = Err(TunnelError::Internal("down".into()));
+        for _ in 0..LOCKOUT.max_failures {
+            assert_eq!(count(&LOCKOUT, &from("backend"), &backend_down, now), None);
+        }
+        assert!(locked(&from("backend"), now).is_ok());
+    }
+
+    #[test]
+    fn lock_runs_out() {
+        let now = Instant::now();
+        for _ in 0..LOCKOUT.max_failures {
+            count(&LOCKOUT, &from("expiry"), &failed(), now);
+        }
+        assert!(locked(&from("expiry"), now).is_err());
+
+        let later = now + LOCKOUT.cooldown + Duration::from_secs(1);
+        assert!(locked(&from("expiry"), later).is_ok());
+        // with a fresh start
+        assert_eq!(count(&LOCKOUT, &from("expiry"), &failed(), later), None);
+        assert!(locked(&from("expiry"), later).is_ok());
+    }
+}
@@ -0,0 +1,227 @@
+//! Shed visitors of low-priority tunnels when the server is overloaded, instead of
+//! slowing every tunnel down: new requests to them are answered with a 503 and a
+//! Retry-After until the load drops. The load is sampled every second as packets
+//! waiting in tunnel and stream queues against LOAD_SHED_QUEUED, and the host's CPU use
+//! (Linux only) against LOAD_SHED_CPU percent, whichever is higher. Shedding is off
+//! unless one of them is set.
+//!
+//! Tunnels take the priority class of their key's entitlements (`priority`, one of
+//! `high`, `normal` or `low`). Low priority tunnels are shed at the threshold, normal
+//! ones at twice it, high priority ones never.
+use super::*;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How readily a tunnel's visitors are shed when the server is overloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("unknown priority: {}", s)),
+        }
+    }
+}
+
+impl Priority {
+    /// The priority as the auth backends store it, `None` for the default
+    pub fn to_stored(self) -> Option<String> {
+        match self {
+            Priority::Low => Some("low".into()),
+            Priority::Normal => None,
+            Priority::High => Some("high".into()),
+        }
+    }
+
+    pub fn from_stored(stored: Option<&str>) -> Self {
+        stored
+            .and_then(|priority| priority.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// The load, relative to the threshold, past which visitors are shed
+    fn shed_at(&self) -> Option<f64> {
+        match self {
+            Priority::Low => Some(1.0),
+            Priority::Normal => Some(2.0),
+            Priority::High => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadShedConfig {
+    /// packets waiting across all queues
+    pub queued: Option<u64>,
+    /// percent of the host's CPU in use
+    pub cpu: Option<f64>,
+    /// what shed visitors are told to wait
+    pub retry_after: Duration,
+}
+
+impl LoadShedConfig {
+    /// Read the thresholds from the env, `None` if neither is set
+    pub fn from_env() -> Option<Self> {
+        let queued = crate::config::env_var("LOAD_SHED_QUEUED").ok().map(|n| {
+            n.parse::<u64>()
+                .unwrap_or_else(|_| panic!("invalid LOAD_SHED_QUEUED={}", n))
+        });
+        let cpu = crate::config::env_var("LOAD_SHED_CPU").ok().map(|n| {
+            n.parse::<f64>()
+                .ok()
+                .filter(|cpu| *cpu > 0.0 && *cpu <= 100.0)
+                .unwrap_or_else(|| panic!("invalid LOAD_SHED_CPU={}", n))
+        });
+        if queued.is_none() && cpu.is_none() {
+            return None;
+        }
+
+        let retry_after = crate::config::env_var("LOAD_SHED_RETRY_AFTER_SECS")
+            .map(|n| {
+                n.parse::<u64>()
+                    .unwrap_or_else(|_| panic!("invalid LOAD_SHED_RETRY_AFTER_SECS={}", n))
+            })
+            .unwrap_or(5);
+        Some(LoadShedConfig {
+            queued,
+            cpu,
+            retry_after: Duration::from_secs(retry_after),
+        })
+    }
+}
+
+lazy_static! {
+    /// the last sampled load, in percent of the threshold
+    static ref LOAD: AtomicU32 = AtomicU32::new(0);
+}
+
+fn load() -> f64 {
+    LOAD.load(Ordering::Relaxed) as f64 / 100.0
+}
+
+/// Busy and total jiffies of the host's CPUs so far
+fn cpu_times() -> Option<(u64, u64)> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let times = stat
+        .lines()
+        .next()?
+        .split_whitespace()
+        .skip(1)
+        .map(|n| n.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    let total = times.iter().sum();
+    // idle and iowait
+    let idle = times.iter().skip(3).take(2).sum::<u64>();
+    Some((total - idle, total))
+}
+
+/// Sample the load every second, if shedding is on
+pub fn spawn() {
+    let config = match CONFIG.load_shedding {
+        Some(config) => config,
+        None => return,
+    };
+
+    tokio::spawn(async move {
+        let mut last_cpu = cpu_times();
+        let mut shedding = false;
+        loop {
+            tokio::time::sleep(SAMPLE_INTERVAL).await;
+
+            let mut load: f64 = 0.0;
+            if let Some(threshold) = config.queued {
+                let queues = QUEUE_METRICS.snapshot();
+                let queued = (queues.tunnel.queued + queues.stream.queued).max(0) as f64;
+                load = load.max(queued / threshold.max(1) as f64);
+            }
+            if let Some(threshold) = config.cpu {
+                let cpu = cpu_times();
+                if let (Some((busy, total)), Some((last_busy, last_total))) = (cpu, last_cpu) {
+                    if total > last_total {
+                        let used = 100.0 * (busy - last_busy) as f64 / (total - last_total) as f64;
+                        load = load.max(used / threshold);
+                    }
+                }
+                last_cpu = cpu;
+            }
+            LOAD.store(
+                (load * 100.0).min(u32::MAX as f64) as u32,
+                Ordering::Relaxed,
+            );
+
+            if load >= 1.0 && !shedding {
+                log::warn!(
+                    "overloaded at {:.0}% of the threshold, shedding visitors",
+                    load * 100.0
+                );
+            } else if load < 1.0 && shedding {
+                info!("load is back under the threshold, no longer shedding");
+            }
+            shedding = load >= 1.0;
+        }
+    });
+}
+
+/// Whether new visitors of a tunnel with this priority are turned away right now
+pub fn sheds(priority: Priority) -> bool {
+    CONFIG.load_shedding.is_some() && priority.shed_at().is_some_and(|at| load() >= at)
+}
+
+/// Answer 503 to new visitors of tunnels shed while the server is overloaded
+pub struct LoadShedding;
+impl EdgeFilter for LoadShedding {
+    fn name(&self) -> &'static str {
+        "load_shedding"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            let config = match CONFIG.load_shedding {
+                Some(config) => config,
+                None => return FilterAction::Continue,
+            };
+            let client = match request.tunnel() {
+                Some(client) => client,
+                None => return FilterAction::Continue,
+            };
+            if !sheds(client.entitlements.priority) {
+                return FilterAction::Continue;
+            }
+
+            log::debug!(
+                "shedding {} ({:?} priority) request_id={}",
+                client.host,
+                client.entitlements.priority,
+                request.request_id
+            );
+            FilterAction::Respond(edge::SERVER_OVERLOADED.render_with_headers(
+                Some(&request.request_id),
+                client.error_format,
+                &[(
+                    "Retry-After",
+                    config.retry_after.as_secs().max(1).to_string(),
+                )],
+            ))
+        }
+        .boxed()
+    }
+}
@@ -8,7 +8,6 @@ pub use tunnelto_lib::*;
 
 use tokio::net::TcpListener;
 
-use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::stream::{SplitSink, SplitStream};
 use lazy_static::lazy_static;
 use log::{error, info};
@@ -18,32 +17,102 @@ use self::connected_clients::*;
 mod active_stream;
 use self::active_stream::*;
 
+mod access_log;
 mod auth;
 pub use self::auth::auth_db;
 pub use self::auth::client_auth;
 
-pub use self::auth_db::AuthDbService;
+pub use self::auth::auth_service::{AuthBackend, AuthService};
 
+mod anonymous;
+mod catch_all;
+mod control_acl;
 mod control_server;
+mod data_connection;
+mod diagnostics;
+mod edge;
+mod ext_authz;
+mod handover;
+mod handshake_audit;
+mod handshake_limit;
+mod history;
+mod inspect_links;
+mod key_lockout;
+mod load_shedding;
+mod metering;
+mod not_found;
+mod rate_limiter;
+pub use self::edge::{EdgeFilter, EdgeFilters, EdgeRequest, FilterAction};
 mod remote;
+mod request_body;
+mod routing_script;
+mod siem;
+mod sni;
+mod soak;
+mod ssh;
+mod stream_integrity;
+mod traffic;
+mod transcripts;
+mod visitors;
 
 mod config;
 pub use self::config::Config;
 mod network;
+mod queue;
+use self::queue::{queue, OverflowPolicy, QueueError, QueueReceiver, QueueSender, QUEUE_METRICS};
 
 lazy_static! {
     pub static ref CONNECTIONS: Connections = Connections::new();
     pub static ref ACTIVE_STREAMS: ActiveStreams = Arc::new(DashMap::new());
-    pub static ref AUTH_DB_SERVICE: AuthDbService =
-        AuthDbService::new().expect("failed to init auth-service");
     pub static ref CONFIG: Config = Config::from_env();
+    pub static ref AUTH_DB_SERVICE: Box<dyn AuthService> = CONFIG.auth_backend.connect();
+    pub static ref EDGE_FILTERS: EdgeFilters = EdgeFilters::new();
 }
 
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
 
-    control_server::spawn(([0, 0, 0, 0], CONFIG.control_port));
+    // check what the environment resolves to without serving: --print-config [--redact]
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args.iter().any(|a| a == "--print-config") {
+        CONFIG.print(args.iter().any(|a| a == "--redact"));
+        return;
+    }
+
+    // manage keys in the auth backend without serving: keys <generate|list|revoke|hash>
+    if args.first().map(String::as_str) == Some("keys") {
+        if let Err(e) = auth::keys_cli::run(&args[1..]).await {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // connect the auth backend before taking clients so a broken one fails startup
+    lazy_static::initialize(&AUTH_DB_SERVICE);
+    if let Err(e) = AUTH_DB_SERVICE.init().await {
+        panic!("failed to init auth backend: {}", e);
+    }
+
+    // before taking clients, so their first events are exported too
+    if let Some(siem) = CONFIG.siem.as_ref() {
+        siem::spawn(siem);
+        info!("exporting {:?} security events", siem.format);
+    }
+    if let Some(audit) = CONFIG.handshake_audit.as_ref() {
+        handshake_audit::spawn(audit);
+        info!("auditing handshakes to {:?}", audit.sink);
+    }
+
+    // before binding, so a process taking over finds its ports shared
+    handover::spawn();
+
+    let control_tls = CONFIG.control_tls.as_ref().map(|tls| {
+        auth::client_certs::init(tls)
+            .unwrap_or_else(|e| panic!("failed to set up control tls: {}", e))
+    });
+    control_server::spawn(([0, 0, 0, 0], CONFIG.control_port), control_tls);
     info!("started tunnelto server on 0.0.0.0:{}", CONFIG.control_port);
 
     network::spawn(([0, 0, 0, 0, 0, 0, 0, 0], CONFIG.internal_network_port));
@@ -52,25 +121,61 @@ async fn main() {
         CONFIG.internal_network_port
     );
 
-    let listen_addr = format!("[::]:{}", CONFIG.remote_port);
+    if CONFIG.dns_self_check {
+        diagnostics::spawn_startup_check();
+    }
+
+    if let Some(port) = CONFIG.tls_passthrough_port {
+        sni::spawn(port);
+    }
+
+    if let Some(ssh) = CONFIG.ssh.as_ref() {
+        ssh::spawn(ssh, &CONFIG.master_sig_key);
+    }
+
+    if CONFIG.metering {
+        metering::spawn_replay();
+    }
+
+    if let Some(transcripts) = CONFIG.transcripts.clone() {
+        transcripts::spawn_sweep(transcripts);
+    }
+
+    load_shedding::spawn();
+
+    if let Some(interval) = CONFIG.soak_interval {
+        soak::spawn(interval);
+    }
+
+    if let Some(interval) = CONFIG.key_recheck_interval {
+        auth::revocation::spawn(interval);
+    }
+
+    // load the routing script before taking visitors so a broken one fails startup
+    lazy_static::initialize(&EDGE_FILTERS);
+
+    let listen_addr = std::net::SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], CONFIG.remote_port));
     info!("listening on: {}", &listen_addr);
 
     // create our accept any server
-    let listener = TcpListener::bind(listen_addr)
-        .await
-        .expect("failed to bind");
+    let listener = handover::bind(listen_addr).expect("failed to bind");
 
     loop {
-        let socket = match listener.accept().await {
-            Ok((socket, _)) => socket,
-            _ => {
+        let socket = match handover::accept(&listener).await {
+            Some(Ok((socket, _))) => socket,
+            Some(Err(_)) => {
                 error!("failed to accept socket");
                 continue;
             }
+            None => break,
         };
 
         tokio::spawn(async move {
             remote::accept_connection(socket).await;
         });
     }
+
+    // another process took over the listeners
+    drop(listener);
+    handover::drain().await;
 }
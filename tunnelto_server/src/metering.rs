@@ -0,0 +1,252 @@
+use super::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// How often we try to replay buffered records while the backend is down
+const REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref METER: Meter = Meter::new();
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    TunnelOpened,
+    TunnelClosed,
+    StreamClosed,
+}
+
+impl RecordKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordKind::TunnelOpened => "tunnel_opened",
+            RecordKind::TunnelClosed => "tunnel_closed",
+            RecordKind::StreamClosed => "stream_closed",
+        }
+    }
+}
+
+/// An audit/usage event for an account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub id: Uuid,
+    pub kind: RecordKind,
+    pub account_id: Uuid,
+    pub sub_domain: String,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub recorded_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl UsageRecord {
+    fn new(kind: RecordKind, client: &ConnectedClient) -> Option<Self> {
+        Some(UsageRecord {
+            id: Uuid::new_v4(),
+            kind,
+            account_id: client.account_id?,
+            sub_domain: client.host.clone(),
+            bytes_in: 0,
+            bytes_out: 0,
+            recorded_at: chrono::Utc::now(),
+        })
+    }
+}
+
+pub fn tunnel_opened(client: &ConnectedClient) {
+    record(UsageRecord::new(RecordKind::TunnelOpened, client));
+}
+
+pub fn tunnel_closed(client: &ConnectedClient) {
+    record(UsageRecord::new(RecordKind::TunnelClosed, client));
+}
+
+pub fn stream_closed(client: &ConnectedClient, stats: &StreamStats) {
+    record(
+        UsageRecord::new(RecordKind::StreamClosed, client).map(|record| UsageRecord {
+            bytes_in: stats.bytes_in(),
+            bytes_out: stats.bytes_out(),
+            ..record
+        }),
+    );
+}
+
+/// Send records of authenticated accounts to the backend in the background
+fn record(record: Option<UsageRecord>) {
+    if !CONFIG.metering {
+        return;
+    }
+
+    if let Some(record) = record {
+        tokio::spawn(async move { METER.submit(record).await });
+    }
+}
+
+/// Buffering state, for the debug counts
+#[derive(Debug, Clone, Serialize)]
+pub struct MeteringStats {
+    pub buffering: bool,
+    pub buffered_bytes: u64,
+    pub dropped: u64,
+}
+
+pub fn stats() -> MeteringStats {
+    MeteringStats {
+        buffering: METER.buffering.load(Ordering::Relaxed),
+        buffered_bytes: METER.wal_bytes.load(Ordering::Relaxed),
+        dropped: METER.dropped.load(Ordering::Relaxed),
+    }
+}
+
+/// Writes records to the backend, falling back to a local write-ahead log
+/// of json lines while the backend is unreachable
+struct Meter {
+    wal_path: PathBuf,
+    /// serializes writes to the log
+    wal_lock: tokio::sync::Mutex<()>,
+    wal_bytes: AtomicU64,
+    /// records are going to the log until it has been replayed
+    buffering: AtomicBool,
+    dropped: AtomicU64,
+}
+
+impl Meter {
+    fn new() -> Self {
+        let wal_bytes = std::fs::metadata(&CONFIG.usage_wal_path)
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        Meter {
+            wal_path: CONFIG.usage_wal_path.clone(),
+            wal_lock: tokio::sync::Mutex::new(()),
+            wal_bytes: AtomicU64::new(wal_bytes),
+            // records left over from before a restart still need replaying
+            buffering: AtomicBool::new(wal_bytes > 0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    async fn submit(&self, record: UsageRecord) {
+        if !self.buffering.load(Ordering::Relaxed) {
+            match AUTH_DB_SERVICE.put_usage_record(&record).await {
+                Ok(_) => return,
+                Err(e) => {
+                    if !self.buffering.swap(true, Ordering::Relaxed) {
+                        error!(
+                            "ALARM: usage backend unreachable, buffering records to {:?}: {:?}",
+                            self.wal_path, e
+                        );
+                    }
+                }
+            }
+        }
+
+        self.append(&record).await;
+    }
+
+    async fn append(&self, record: &UsageRecord) {
+        let mut line = serde_json::to_vec(record).unwrap_or_default();
+        line.push(b'\n');
+
+        let _guard = self.wal_lock.lock().await;
+        if self.wal_bytes.load(Ordering::Relaxed) + line.len() as u64 > CONFIG.usage_wal_max_bytes {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            error!(
+                "ALARM: usage log is full ({} bytes), dropped {} records",
+                CONFIG.usage_wal_max_bytes, dropped
+            );
+            return;
+        }
+
+        let result = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.wal_path)
+                .await?;
+            file.write_all(&line).await?;
+            file.sync_data().await
+        }
+        .await;
+
+        match result {
+            Ok(_) => {
+                self.wal_bytes.fetch_add(line.len() as u64, Ordering::Relaxed);
+                // a replay may have finished while we waited for the lock
+                self.buffering.store(true, Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                error!("ALARM: failed to write usage log, dropped record: {:?}", e);
+            }
+        }
+    }
+
+    /// Send buffered records to the backend, keeping whatever fails for next time
+    async fn replay(&self) {
+        let _guard = self.wal_lock.lock().await;
+
+        let data = match tokio::fs::read(&self.wal_path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                error!("failed to read usage log: {:?}", e);
+                return;
+            }
+        };
+
+        let mut lines = data.split(|b| *b == b'\n').filter(|l| !l.is_empty());
+        let mut replayed = 0;
+        let mut remaining: Vec<u8> = vec![];
+        while let Some(line) = lines.next() {
+            let record: UsageRecord = match serde_json::from_slice(line) {
+                Ok(record) => record,
+                Err(e) => {
+                    error!("skipping corrupt usage log entry: {:?}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = AUTH_DB_SERVICE.put_usage_record(&record).await {
+                log::warn!("usage backend still unreachable: {:?}", e);
+                for line in std::iter::once(line).chain(lines) {
+                    remaining.extend_from_slice(line);
+                    remaining.push(b'\n');
+                }
+                break;
+            }
+            replayed += 1;
+        }
+
+        if let Err(e) = tokio::fs::write(&self.wal_path, &remaining).await {
+            error!("failed to rewrite usage log: {:?}", e);
+            return;
+        }
+        self.wal_bytes.store(remaining.len() as u64, Ordering::Relaxed);
+
+        if replayed > 0 {
+            info!("replayed {} buffered usage records", replayed);
+        }
+
+        if remaining.is_empty() {
+            info!("usage backend recovered, stopped buffering");
+            self.buffering.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Periodically replay the log while we are buffering
+pub fn spawn_replay() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(REPLAY_INTERVAL).await;
+            if METER.buffering.load(Ordering::Relaxed) {
+                METER.replay().await;
+            }
+        }
+    });
+}
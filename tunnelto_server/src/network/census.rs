@@ -0,0 +1,127 @@
+use super::*;
+use crate::connected_clients::Connections;
+use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
+
+/// Load on a single instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceStats {
+    pub tunnels: usize,
+    pub anonymous_tunnels: usize,
+    /// size of the sub-domain routing table
+    pub hosts: usize,
+    pub active_streams: usize,
+    /// where clients can reach this instance directly
+    #[serde(default)]
+    pub control_url: Option<String>,
+    /// how often send queues overflowed
+    #[serde(default)]
+    pub queues: crate::queue::QueueMetricsSnapshot,
+}
+
+impl InstanceStats {
+    pub fn local() -> Self {
+        InstanceStats {
+            tunnels: Connections::count(),
+            anonymous_tunnels: Connections::count_anonymous(),
+            hosts: Connections::host_count(),
+            active_streams: crate::ACTIVE_STREAMS.len(),
+            control_url: crate::CONFIG.public_control_url.clone(),
+            queues: crate::QUEUE_METRICS.snapshot(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InstanceReport {
+    pub ip: Option<IpAddr>,
+    pub stats: Option<InstanceStats>,
+    pub error: Option<String>,
+}
+
+/// Tunnel totals across every instance we could reach
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterCensus {
+    pub total_tunnels: usize,
+    pub total_anonymous_tunnels: usize,
+    pub total_active_streams: usize,
+    pub instances: Vec<InstanceReport>,
+}
+
+impl Instance {
+    async fn stats(&self) -> Result<InstanceStats, Error> {
+        let addr = SocketAddr::new(self.ip, crate::CONFIG.internal_network_port);
+        let url = format!("http://{}/stats", addr);
+        Ok(reqwest::get(url).await?.json().await?)
+    }
+}
+
+pub async fn cluster_census() -> ClusterCensus {
+    let instances = match Instance::get_instances().await {
+        Ok(instances) if !instances.is_empty() => {
+            let reports = instances.into_iter().map(|instance| async move {
+                match instance.stats().await {
+                    Ok(stats) => InstanceReport {
+                        ip: Some(instance.ip),
+                        stats: Some(stats),
+                        error: None,
+                    },
+                    Err(e) => InstanceReport {
+                        ip: Some(instance.ip),
+                        stats: None,
+                        error: Some(e.to_string()),
+                    },
+                }
+            });
+            futures::future::join_all(reports).await
+        }
+        // gossip is disabled or discovery failed: report ourselves
+        result => vec![InstanceReport {
+            ip: None,
+            stats: Some(InstanceStats::local()),
+            error: result.err().map(|e| e.to_string()),
+        }],
+    };
+
+    let stats = instances.iter().filter_map(|i| i.stats.as_ref());
+    ClusterCensus {
+        total_tunnels: stats.clone().map(|s| s.tunnels).sum(),
+        total_anonymous_tunnels: stats.clone().map(|s| s.anonymous_tunnels).sum(),
+        total_active_streams: stats.map(|s| s.active_streams).sum(),
+        instances,
+    }
+}
+
+/// If this instance is full, the control url of the least loaded instance with room
+pub async fn redirect_target() -> Option<String> {
+    let limit = crate::CONFIG.max_instance_tunnels?;
+    if Connections::count() < limit {
+        return None;
+    }
+
+    let census = cluster_census().await;
+    let target = census
+        .instances
+        .into_iter()
+        .filter_map(|i| i.stats)
+        .filter(|s| s.tunnels < limit && s.control_url != crate::CONFIG.public_control_url)
+        .filter(|s| s.control_url.is_some())
+        .min_by_key(|s| s.tunnels)?;
+
+    target.control_url
+}
+
+/// Handle an operator request for the cluster census
+pub async fn handle_census(
+    admin_key: Option<String>,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !crate::siem::admin("census", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+
+    let census = cluster_census().await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&census),
+        StatusCode::OK,
+    ))
+}
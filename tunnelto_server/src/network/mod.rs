@@ -2,30 +2,39 @@ use futures::future::select_ok;
 use futures::FutureExt;
 use std::net::{IpAddr, SocketAddr};
 use thiserror::Error;
+mod census;
+pub use self::census::{handle_census, redirect_target, InstanceStats};
 mod server;
 pub use self::server::spawn;
 mod proxy;
-pub use self::proxy::proxy_stream;
-use crate::network::server::{HostQuery, HostQueryResponse};
+pub use self::proxy::{parse_proxy_line, proxy_stream, MAX_PROXY_LINE};
+use crate::network::server::{AccountQuery, AccountQueryResponse, HostQuery, HostQueryResponse};
 use crate::ClientId;
 use reqwest::StatusCode;
 use trust_dns_resolver::TokioAsyncResolver;
+use uuid::Uuid;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("IOError: {0}")]
-    IoError(#[from] std::io::Error),
+    Io(#[from] std::io::Error),
 
     #[error("RequestError: {0}")]
     Request(#[from] reqwest::Error),
 
     #[error("ResolverError: {0}")]
-    Resolver(#[from] trust_dns_resolver::error::ResolveError),
+    Resolver(Box<trust_dns_resolver::error::ResolveError>),
 
     #[error("Does not serve host")]
     DoesNotServeHost,
 }
 
+impl From<trust_dns_resolver::error::ResolveError> for Error {
+    fn from(e: trust_dns_resolver::error::ResolveError) -> Self {
+        Error::Resolver(Box::new(e))
+    }
+}
+
 /// An instance of our server
 #[derive(Debug, Clone)]
 pub struct Instance {
@@ -55,8 +64,8 @@ impl Instance {
 
     /// query the instance and see if it runs our host
     async fn serves_host(self, host: &str) -> Result<(Instance, ClientId), Error> {
-        let addr = SocketAddr::new(self.ip.clone(), crate::CONFIG.internal_network_port);
-        let url = format!("http://{}", addr.to_string());
+        let addr = SocketAddr::new(self.ip, crate::CONFIG.internal_network_port);
+        let url = format!("http://{}", addr);
         let client = reqwest::Client::new();
         let response = client
             .get(url)
@@ -75,6 +84,64 @@ impl Instance {
             _ => Err(Error::DoesNotServeHost),
         }
     }
+
+    /// how many tunnels the account has open on the instance
+    async fn account_tunnels(&self, account_id: &Uuid) -> Result<usize, Error> {
+        let addr = SocketAddr::new(self.ip, crate::CONFIG.internal_network_port);
+        let url = format!("http://{}/account_tunnels", addr);
+        let response: AccountQueryResponse = reqwest::Client::new()
+            .get(url)
+            .query(&AccountQuery {
+                account_id: *account_id,
+            })
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(response.tunnels)
+    }
+}
+
+/// The tunnels the account has open on every instance, ours included. Instances that
+/// can't be reached aren't counted, and without gossip only ours is.
+pub async fn tunnels_for_account(account_id: &Uuid) -> usize {
+    let instances = match Instance::get_instances().await {
+        Ok(instances) if !instances.is_empty() => instances,
+        Ok(_) => return crate::connected_clients::Connections::count_for_account(account_id),
+        Err(e) => {
+            log::debug!(
+                "failed to find instances, counting tunnels here only: {:?}",
+                e
+            );
+            return crate::connected_clients::Connections::count_for_account(account_id);
+        }
+    };
+
+    let counts = instances
+        .iter()
+        .map(|instance| instance.account_tunnels(account_id));
+    futures::future::join_all(counts)
+        .await
+        .into_iter()
+        .filter_map(|count| {
+            count
+                .map_err(|e| log::debug!("failed to count tunnels on an instance: {:?}", e))
+                .ok()
+        })
+        .sum()
+}
+
+/// Whether `ip` is one of our instances, which may relay visitor streams to us
+pub async fn is_instance(ip: IpAddr) -> bool {
+    match Instance::get_instances().await {
+        Ok(instances) => instances
+            .iter()
+            .any(|i| i.ip.to_canonical() == ip.to_canonical()),
+        Err(e) => {
+            log::debug!("failed to find instances: {:?}", e);
+            false
+        }
+    }
 }
 
 /// get the ip address we need to connect to that runs our host
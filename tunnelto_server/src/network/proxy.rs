@@ -1,13 +1,23 @@
 use crate::network::Instance;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 
-const HTTP_ERROR_PROXYING_TUNNEL_RESPONSE: &'static [u8] =
+const HTTP_ERROR_PROXYING_TUNNEL_RESPONSE: &[u8] =
     b"HTTP/1.1 500\r\nContent-Length: 28\r\n\r\nError: Error proxying tunnel";
 
-pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
-    let addr = SocketAddr::new(instance.ip, crate::CONFIG.remote_port);
+/// Longest PROXY protocol (v1) line, with its CRLF
+pub const MAX_PROXY_LINE: usize = 107;
+
+/// Relay a stream to the instance serving it. Visitor streams start with a PROXY
+/// protocol line naming the `visitor`, so the instance can tell the tunnel who it is.
+pub async fn proxy_stream(
+    instance: Instance,
+    mut stream: TcpStream,
+    port: u16,
+    visitor: Option<SocketAddr>,
+) {
+    let addr = SocketAddr::new(instance.ip, port);
     let mut instance = match TcpStream::connect(addr).await {
         Ok(stream) => stream,
         Err(e) => {
@@ -17,6 +27,16 @@ pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
         }
     };
 
+    if let Some(visitor) = visitor {
+        if let Err(e) = instance
+            .write_all(proxy_line(visitor, addr).as_bytes())
+            .await
+        {
+            log::error!("Error relaying visitor to instance: {:?}", e);
+            return;
+        }
+    }
+
     let (mut i_read, mut i_write) = instance.split();
     let (mut r_read, mut r_write) = stream.split();
 
@@ -26,3 +46,67 @@ pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
     )
     .await;
 }
+
+/// The PROXY protocol line for a stream from `source` to `destination`
+fn proxy_line(source: SocketAddr, destination: SocketAddr) -> String {
+    let (family, source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            ("TCP4", source.to_string(), destination.to_string())
+        }
+        (source, destination) => ("TCP6", v6(source), v6(destination)),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source_ip,
+        destination_ip,
+        source.port(),
+        destination.port()
+    )
+}
+
+fn v6(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().to_string(),
+        IpAddr::V6(ip) => ip.to_string(),
+    }
+}
+
+/// The source of a stream from its PROXY protocol line
+pub fn parse_proxy_line(line: &[u8]) -> Option<SocketAddr> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.trim_end().split(' ');
+    match (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) {
+        (Some("PROXY"), Some("TCP4") | Some("TCP6"), Some(source), Some(_), Some(port)) => {
+            let ip: IpAddr = source.parse().ok()?;
+            Some(SocketAddr::new(ip.to_canonical(), port.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_line_round_trip() {
+        let visitor: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let line = proxy_line(visitor, "10.0.0.2:8080".parse().unwrap());
+        assert_eq!(line, "PROXY TCP4 203.0.113.7 10.0.0.2 51234 8080\r\n");
+        assert_eq!(parse_proxy_line(line.as_bytes()), Some(visitor));
+
+        let line = proxy_line(visitor, "[fd00::2]:8080".parse().unwrap());
+        assert_eq!(line, "PROXY TCP6 ::ffff:203.0.113.7 fd00::2 51234 8080\r\n");
+        assert_eq!(parse_proxy_line(line.as_bytes()), Some(visitor));
+
+        assert_eq!(parse_proxy_line(b"PROXY UNKNOWN\r\n"), None);
+        assert_eq!(parse_proxy_line(b"GET / HTTP/1.1\r\n"), None);
+    }
+}
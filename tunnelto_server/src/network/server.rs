@@ -2,6 +2,7 @@ use super::*;
 use crate::connected_clients::Connections;
 use crate::ClientId;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 use warp::Filter;
 
 pub fn spawn<A: Into<SocketAddr>>(addr: A) {
@@ -15,8 +16,25 @@ pub fn spawn<A: Into<SocketAddr>>(addr: A) {
         .and(warp::query::<HostQuery>())
         .map(|query| warp::reply::json(&handle_query(query)));
 
+    let stats = warp::get()
+        .and(warp::path("stats"))
+        .map(|| warp::reply::json(&InstanceStats::local()));
+
+    let account_tunnels = warp::get()
+        .and(warp::path("account_tunnels"))
+        .and(warp::query::<AccountQuery>())
+        .map(|query: AccountQuery| {
+            warp::reply::json(&AccountQueryResponse {
+                tunnels: Connections::count_for_account(&query.account_id),
+            })
+        });
+
     // spawn our websocket control server
-    tokio::spawn(warp::serve(query_svc.or(health_check)).run(addr.into()));
+    let listener = crate::handover::bind(addr.into()).expect("failed to bind network service");
+    tokio::spawn(
+        warp::serve(query_svc.or(health_check).or(stats).or(account_tunnels))
+            .serve_incoming(crate::handover::incoming(listener)),
+    );
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,6 +47,16 @@ pub struct HostQueryResponse {
     pub client_id: Option<ClientId>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountQuery {
+    pub account_id: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountQueryResponse {
+    pub tunnels: usize,
+}
+
 fn handle_query(query: HostQuery) -> HostQueryResponse {
     log::debug!("got query: {:?}", &query.host);
     HostQueryResponse {
@@ -0,0 +1,186 @@
+//! What visitors get when no tunnel matches the host, so hosted servers can show their
+//! own branding or send them to a signup page. NOT_FOUND_RESPONSE is one of:
+//!
+//! - `text` (the default), the plain error page
+//! - `json`, the error envelope tunnels get with `--error-format json`
+//! - `page:<path>`, a 404 with the html page at `path`, read at startup
+//! - `redirect:<url>`, a 302 to `url`
+//!
+//! NOT_FOUND_OVERRIDES picks another response for some base domains, as comma separated
+//! `<base domain>=<response>`, i.e. `tunnel.acme.io=redirect:https://acme.io/signup`.
+//! Pages and redirect urls may use `{sub_domain}`, `{base_domain}` and `{request_id}`,
+//! filled in for the visitor's request.
+use super::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone)]
+pub enum NotFoundResponse {
+    Text,
+    Json,
+    Page { path: String, html: String },
+    Redirect(String),
+}
+
+impl FromStr for NotFoundResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.splitn(2, ':').collect::<Vec<_>>().as_slice() {
+            ["text"] => Ok(NotFoundResponse::Text),
+            ["json"] => Ok(NotFoundResponse::Json),
+            ["page", path] => {
+                let html = std::fs::read_to_string(path)
+                    .map_err(|e| format!("can't read page {}: {}", path, e))?;
+                Ok(NotFoundResponse::Page {
+                    path: path.to_string(),
+                    html,
+                })
+            }
+            ["redirect", url] => {
+                url::Url::parse(&fill(url, "sub-domain", "example.com", "request-id"))
+                    .map_err(|e| format!("invalid redirect url {}: {}", url, e))?;
+                Ok(NotFoundResponse::Redirect(url.to_string()))
+            }
+            _ => Err(format!("unknown response: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for NotFoundResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotFoundResponse::Text => write!(f, "text"),
+            NotFoundResponse::Json => write!(f, "json"),
+            NotFoundResponse::Page { path, .. } => write!(f, "page:{}", path),
+            NotFoundResponse::Redirect(url) => write!(f, "redirect:{}", url),
+        }
+    }
+}
+
+impl fmt::Debug for NotFoundResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NotFoundConfig {
+    pub default: NotFoundResponse,
+    /// by base domain
+    pub overrides: HashMap<String, NotFoundResponse>,
+}
+
+impl NotFoundConfig {
+    pub fn from_env() -> Self {
+        let default = crate::config::env_var("NOT_FOUND_RESPONSE")
+            .map(|response| {
+                response
+                    .parse()
+                    .unwrap_or_else(|e| panic!("invalid NOT_FOUND_RESPONSE: {}", e))
+            })
+            .unwrap_or(NotFoundResponse::Text);
+
+        let overrides = crate::config::env_var("NOT_FOUND_OVERRIDES")
+            .map(|list| {
+                list.split(',')
+                    .filter(|entry| !entry.trim().is_empty())
+                    .map(|entry| {
+                        let mut parts = entry.trim().splitn(2, '=');
+                        match (parts.next(), parts.next()) {
+                            (Some(base_domain), Some(response)) => (
+                                base_domain.to_string(),
+                                response.parse().unwrap_or_else(|e| {
+                                    panic!("invalid NOT_FOUND_OVERRIDES for {}: {}", base_domain, e)
+                                }),
+                            ),
+                            _ => panic!(
+                                "invalid NOT_FOUND_OVERRIDES: expected <base domain>=<response>, got {}",
+                                entry
+                            ),
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        NotFoundConfig { default, overrides }
+    }
+
+    /// The response for visitors to a base domain
+    pub fn for_base_domain(&self, base_domain: Option<&String>) -> &NotFoundResponse {
+        base_domain
+            .and_then(|base_domain| self.overrides.get(base_domain))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Fill in the placeholders of a page or redirect url
+fn fill(template: &str, sub_domain: &str, base_domain: &str, request_id: &str) -> String {
+    template
+        .replace("{sub_domain}", sub_domain)
+        .replace("{base_domain}", base_domain)
+        .replace("{request_id}", request_id)
+}
+
+/// Escape a value filled into a page, the host it came from is the visitor's
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// The raw http response for a visitor to a host no tunnel matches
+pub fn response(request: &EdgeRequest) -> Vec<u8> {
+    let sub_domain = request.sub_domain.as_deref().unwrap_or_default();
+    let base_domain = request.base_domain.as_deref().unwrap_or_default();
+
+    let (status, headers, body) = match CONFIG
+        .not_found
+        .for_base_domain(request.base_domain.as_ref())
+    {
+        NotFoundResponse::Text => {
+            return edge::TUNNEL_NOT_FOUND.render(Some(&request.request_id), ErrorFormat::Text)
+        }
+        NotFoundResponse::Json => {
+            return edge::TUNNEL_NOT_FOUND.render(Some(&request.request_id), ErrorFormat::Json)
+        }
+        NotFoundResponse::Page { html, .. } => (
+            "404 Not Found",
+            vec![("Content-Type", "text/html; charset=utf-8".to_string())],
+            fill(
+                html,
+                &escape_html(sub_domain),
+                &escape_html(base_domain),
+                &request.request_id,
+            ),
+        ),
+        NotFoundResponse::Redirect(url) => {
+            let location = fill(url, sub_domain, base_domain, &request.request_id);
+            (
+                "302 Found",
+                vec![
+                    ("Location", location.clone()),
+                    // the tunnel may be up by the next visit
+                    ("Cache-Control", "no-store".to_string()),
+                ],
+                location,
+            )
+        }
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\n{}: {}\r\n",
+        status,
+        verify::REQUEST_ID_HEADER,
+        request.request_id
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    format!("{}Content-Length: {}\r\n\r\n{}", head, body.len(), body).into_bytes()
+}
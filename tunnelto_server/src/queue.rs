@@ -0,0 +1,260 @@
+use futures::channel::mpsc::{channel, Receiver, Sender, TryRecvError};
+use futures::task::{Context, Poll};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+lazy_static::lazy_static! {
+    pub static ref QUEUE_METRICS: QueueMetrics = QueueMetrics::default();
+}
+
+/// What happens when a queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// wait for room, slowing down whoever is sending
+    Block,
+    /// fail the visitor connection, with a 503 if it can still be answered
+    Drop,
+    /// disconnect the tunnel client
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop" => Ok(OverflowPolicy::Drop),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+/// Size and overflow behavior of one kind of queue
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl QueueConfig {
+    /// Read `<PREFIX>_CAPACITY` and `<PREFIX>_OVERFLOW` from the env
+    pub fn from_env(prefix: &str, default_capacity: usize) -> Self {
+        let capacity_var = format!("{}_CAPACITY", prefix);
+        let overflow_var = format!("{}_OVERFLOW", prefix);
+
+        let capacity = crate::config::env_var(&capacity_var)
+            .map(|n| {
+                n.parse()
+                    .unwrap_or_else(|_| panic!("invalid {}={}", capacity_var, n))
+            })
+            .unwrap_or(default_capacity);
+        let overflow = crate::config::env_var(&overflow_var)
+            .map(|p| p.parse().unwrap_or_else(|e| panic!("invalid {}: {}", overflow_var, e)))
+            .unwrap_or(OverflowPolicy::Block);
+
+        QueueConfig { capacity, overflow }
+    }
+}
+
+/// How often one kind of queue filled up and what we did about it
+#[derive(Debug, Default)]
+pub struct QueueCounters {
+    /// items waiting across every queue of this kind
+    queued: AtomicI64,
+    full: AtomicU64,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+impl QueueCounters {
+    fn snapshot(&self) -> QueueCountersSnapshot {
+        QueueCountersSnapshot {
+            queued: self.queued.load(Ordering::Relaxed),
+            full: self.full.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    /// per-tunnel queues of packets for the client
+    pub tunnel: QueueCounters,
+    /// per-stream queues of data for the visitor
+    pub stream: QueueCounters,
+}
+
+impl QueueMetrics {
+    pub fn snapshot(&self) -> QueueMetricsSnapshot {
+        QueueMetricsSnapshot {
+            tunnel: self.tunnel.snapshot(),
+            stream: self.stream.snapshot(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueCountersSnapshot {
+    #[serde(default)]
+    pub queued: i64,
+    pub full: u64,
+    pub dropped: u64,
+    pub disconnected: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueMetricsSnapshot {
+    pub tunnel: QueueCountersSnapshot,
+    pub stream: QueueCountersSnapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// the receiving side is gone
+    Closed,
+    /// the queue is full and its policy says not to wait
+    Overflow(OverflowPolicy),
+}
+
+/// A bounded sender that applies its queue's overflow policy
+#[derive(Debug)]
+pub struct QueueSender<T> {
+    tx: Sender<T>,
+    config: QueueConfig,
+    counters: &'static QueueCounters,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender {
+            tx: self.tx.clone(),
+            config: self.config,
+            counters: self.counters,
+        }
+    }
+}
+
+/// The receiving end, keeping the queued count up to date
+#[derive(Debug)]
+pub struct QueueReceiver<T> {
+    rx: Receiver<T>,
+    counters: &'static QueueCounters,
+}
+
+pub fn queue<T>(
+    config: QueueConfig,
+    counters: &'static QueueCounters,
+) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (tx, rx) = channel(config.capacity);
+    (
+        QueueSender {
+            tx,
+            config,
+            counters,
+        },
+        QueueReceiver { rx, counters },
+    )
+}
+
+impl<T> QueueSender<T> {
+    pub async fn send(&mut self, item: T) -> Result<(), QueueError> {
+        self.send_or_return(item).await.map_err(|(e, _)| e)
+    }
+
+    /// Like `send`, handing the item back if the queue didn't take it
+    pub async fn send_or_return(&mut self, item: T) -> Result<(), (QueueError, T)> {
+        let mut item = match self.tx.try_send(item) {
+            Ok(()) => {
+                self.counters.queued.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            Err(e) if e.is_disconnected() => return Err((QueueError::Closed, e.into_inner())),
+            Err(e) => e.into_inner(),
+        };
+
+        self.counters.full.fetch_add(1, Ordering::Relaxed);
+        match self.config.overflow {
+            OverflowPolicy::Block => loop {
+                // wait for room, another sender may take it first
+                if futures::future::poll_fn(|cx| self.tx.poll_ready(cx))
+                    .await
+                    .is_err()
+                {
+                    return Err((QueueError::Closed, item));
+                }
+                item = match self.tx.try_send(item) {
+                    Ok(()) => {
+                        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                    Err(e) if e.is_disconnected() => {
+                        return Err((QueueError::Closed, e.into_inner()))
+                    }
+                    Err(e) => e.into_inner(),
+                };
+            },
+            OverflowPolicy::Drop => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                Err((QueueError::Overflow(OverflowPolicy::Drop), item))
+            }
+            OverflowPolicy::Disconnect => {
+                self.counters.disconnected.fetch_add(1, Ordering::Relaxed);
+                Err((QueueError::Overflow(OverflowPolicy::Disconnect), item))
+            }
+        }
+    }
+
+    /// Queue the item if there is room, bypassing the overflow policy
+    pub fn try_send(&mut self, item: T) -> bool {
+        let sent = self.tx.try_send(item).is_ok();
+        if sent {
+            self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Close the queue for every sender
+    pub fn close_channel(&self) {
+        self.tx.clone().close_channel();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    pub fn try_next(&mut self) -> Result<Option<T>, TryRecvError> {
+        let item = self.rx.try_next();
+        if let Ok(Some(_)) = item {
+            self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        item
+    }
+}
+
+impl<T> Stream for QueueReceiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let item = Pin::new(&mut self.rx).poll_next(cx);
+        if let Poll::Ready(Some(_)) = item {
+            self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        item
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    /// items still queued are dropped with us
+    fn drop(&mut self) {
+        self.rx.close();
+        while let Ok(Some(_)) = self.try_next() {}
+    }
+}
@@ -0,0 +1,128 @@
+//! Token buckets behind a tunnel's `rate_limit` rules, one per rule. They live with the
+//! connected client, so each server instance enforces the rates on its own and they
+//! start over when the client reconnects. `Bandwidth` paces a tunnel's data the same way.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tunnelto_lib::rate_limit::{self, RateLimitRule};
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    rules: Vec<RateLimitRule>,
+    buckets: Vec<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rules: Vec<RateLimitRule>) -> Self {
+        let buckets = rules
+            .iter()
+            .map(|rule| {
+                Mutex::new(Bucket {
+                    tokens: rule.burst() as f64,
+                    updated: Instant::now(),
+                })
+            })
+            .collect();
+        RateLimiter { rules, buckets }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Let a request to `path` through, or say how long until the rule matching it
+    /// would, along with the rule
+    pub fn check(&self, path: &str) -> Result<(), (Duration, &RateLimitRule)> {
+        let (index, rule) = match rate_limit::matching_rule(&self.rules, path) {
+            Some(matching) => matching,
+            None => return Ok(()),
+        };
+
+        let mut bucket = self.buckets[index].lock().unwrap();
+        let now = Instant::now();
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rule.rps;
+        bucket.tokens = (bucket.tokens + refilled).min(rule.burst() as f64);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rule.rps);
+        Err((wait, rule))
+    }
+}
+
+/// Holds a tunnel's data to a rate in bytes/sec, each way
+#[derive(Debug)]
+pub struct Bandwidth {
+    bytes_per_sec: u64,
+    /// when the data sent so far has used up the rate, each way
+    inbound: Mutex<Instant>,
+    outbound: Mutex<Instant>,
+}
+
+impl Bandwidth {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Bandwidth {
+            bytes_per_sec: bytes_per_sec.max(1),
+            inbound: Mutex::new(Instant::now()),
+            outbound: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `n` bytes from visitors fit in the rate
+    pub async fn pace_in(&self, n: usize) {
+        self.pace(&self.inbound, n).await
+    }
+
+    /// Wait until `n` bytes to visitors fit in the rate
+    pub async fn pace_out(&self, n: usize) {
+        self.pace(&self.outbound, n).await
+    }
+
+    async fn pace(&self, used_until: &Mutex<Instant>, n: usize) {
+        let start = {
+            let mut used_until = used_until.lock().unwrap();
+            let start = (*used_until).max(Instant::now());
+            *used_until = start + Duration::from_secs_f64(n as f64 / self.bytes_per_sec as f64);
+            start
+        };
+        tokio::time::sleep_until(start.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_limited() {
+        let limiter = RateLimiter::new(vec!["/api=1:3".parse().unwrap()]);
+        for _ in 0..3 {
+            assert!(limiter.check("/api/search").is_ok());
+        }
+
+        let (wait, rule) = limiter.check("/api/search").unwrap_err();
+        assert_eq!(rule.path, "/api");
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+
+        // paths no rule matches aren't limited
+        assert!(limiter.check("/public").is_ok());
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let limiter = RateLimiter::new(vec!["/=50:1".parse().unwrap()]);
+        assert!(limiter.check("/").is_ok());
+        assert!(limiter.check("/").is_err());
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(limiter.check("/").is_ok());
+    }
+}
@@ -1,7 +1,11 @@
 use super::*;
+use crate::request_body::{RequestBody, Requests};
+use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tunnelto_lib::integrity::StreamChecksum;
 
 async fn direct_to_control(mut incoming: TcpStream) {
     let mut control_socket =
@@ -30,78 +34,236 @@ async fn direct_to_control(mut incoming: TcpStream) {
 pub async fn accept_connection(socket: TcpStream) {
     // peek the host of the http request
     // if health check, then handle it and return
-    let (mut socket, host) = match peek_http_request_host(socket).await {
+    let (mut socket, mut request) = match peek_http_request(socket).await {
         Some(s) => s,
         None => return,
     };
 
-    // parse the host string and find our client
-    if CONFIG.allowed_hosts.contains(&host) {
-        error!("redirect to homepage");
-        let _ = socket.write_all(HTTP_REDIRECT_RESPONSE).await;
+    // run the edge filters
+    if let FilterAction::Respond(response) = EDGE_FILTERS.run(&mut request).await {
+        let _ = socket.write_all(&response).await;
         return;
     }
-    let host = match validate_host_prefix(&host) {
+
+    let host = match request.sub_domain.clone() {
         Some(sub_domain) => sub_domain,
-        None => {
-            error!("invalid host specified");
-            let _ = socket.write_all(HTTP_INVALID_HOST_RESPONSE).await;
-            return;
-        }
+        None => return,
     };
 
     // Special case -- we redirect this tcp connection to the control server
     if host.as_str() == "wormhole" {
+        if !control_acl::allowed(request.peer_addr.map(|addr| addr.ip())) {
+            let _ = socket
+                .write_all(&edge::http_response(
+                    "403 Forbidden",
+                    "Tunnel clients aren't accepted from this network",
+                ))
+                .await;
+            return;
+        }
         direct_to_control(socket).await;
         return;
     }
 
     // find the client listening for this host
     let client = match Connections::find_by_host(&host) {
-        Some(client) => client.clone(),
+        Some(client) => client,
         None => {
             // check other instances that may be serving this host
             match network::instance_for_host(&host).await {
                 Ok((instance, _)) => {
-                    network::proxy_stream(instance, socket).await;
-                    return;
-                }
-                Err(network::Error::DoesNotServeHost) => {
-                    error!("No tunnel found for host: {}.<>", host);
-                    let _ = socket.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    network::proxy_stream(instance, socket, CONFIG.remote_port, request.peer_addr)
+                        .await;
                     return;
                 }
+                // we may have just taken over from a process it's still reconnecting from,
+                // or else a catch-all serves it
+                Err(network::Error::DoesNotServeHost) => match handover::wait_for_tunnel(&host)
+                    .await
+                    .or_else(|| Connections::catch_all_for(request.base_domain.as_ref()))
+                {
+                    Some(client) => client,
+                    None => {
+                        error!("No tunnel found for host: {}.<>", host);
+                        let _ = socket.write_all(&not_found::response(&request)).await;
+                        return;
+                    }
+                },
                 Err(e) => {
                     error!("error finding host {} for tunnel: {:?}, ", host, e);
-                    let _ = socket.write_all(HTTP_ERROR_LOCATING_HOST_RESPONSE).await;
+                    let _ = socket
+                        .write_all(
+                            &edge::ERROR_LOCATING_HOST
+                                .render(Some(&request.request_id), ErrorFormat::Text),
+                        )
+                        .await;
                     return;
                 }
             }
         }
     };
 
+    // tunnels pinned to a base domain aren't served on the others,
+    // and passthrough tunnels only take TLS on the passthrough port
+    if (client.base_domain.is_some() && client.base_domain != request.base_domain)
+        || client.tunnel_type != TunnelType::Http
+    {
+        error!("tunnel for host {} not served on this base domain", host);
+        access_log::answered(&client, &request, &edge::TUNNEL_NOT_FOUND);
+        let _ = socket.write_all(&not_found::response(&request)).await;
+        transcripts::answered(&client, &request, &edge::TUNNEL_NOT_FOUND).await;
+        return;
+    }
+
+    // the account's policy may hold the tunnel until a teammate approves it
+    if client.awaiting_approval() {
+        log::warn!(
+            "{} is awaiting approval, refusing request_id={}",
+            &client.host,
+            request.request_id
+        );
+        access_log::answered(&client, &request, &edge::AWAITING_APPROVAL);
+        let _ = socket
+            .write_all(
+                &edge::AWAITING_APPROVAL.render(Some(&request.request_id), client.error_format),
+            )
+            .await;
+        transcripts::answered(&client, &request, &edge::AWAITING_APPROVAL).await;
+        return;
+    }
+
+    // tunnels declared for light traffic only take so many visitors at once
+    if let Some(max_streams) = crate::traffic::tuning(client.traffic_profile).max_streams {
+        if crate::traffic::open_streams(&client.id) >= max_streams {
+            log::warn!(
+                "{} has {} open streams, refusing request_id={}",
+                &client.host,
+                max_streams,
+                request.request_id
+            );
+            access_log::answered(&client, &request, &edge::TOO_MANY_STREAMS);
+            let _ = socket
+                .write_all(
+                    &edge::TOO_MANY_STREAMS.render(Some(&request.request_id), client.error_format),
+                )
+                .await;
+            transcripts::answered(&client, &request, &edge::TOO_MANY_STREAMS).await;
+            return;
+        }
+    }
+
+    // filters that must see every request get a connection per request, other
+    // connections stay open with the heads of later requests rewritten as they come
+    let body = match (request.head_len, RequestBody::of(&request)) {
+        (None, _) if request.one_request => {
+            access_log::answered(&client, &request, &edge::HEAD_TOO_LARGE);
+            let _ = socket
+                .write_all(
+                    &edge::HEAD_TOO_LARGE.render(Some(&request.request_id), client.error_format),
+                )
+                .await;
+            return;
+        }
+        // the head is read and rewritten along with the rest of the stream
+        (None, _) => None,
+        (Some(_), None) => {
+            log::warn!(
+                "refusing request with ambiguous framing to {} request_id={}",
+                &client.host,
+                request.request_id
+            );
+            access_log::answered(&client, &request, &edge::BAD_REQUEST);
+            let _ = socket
+                .write_all(
+                    &edge::BAD_REQUEST.render(Some(&request.request_id), client.error_format),
+                )
+                .await;
+            return;
+        }
+        (Some(_), Some(RequestBody::Upgrade)) => Some(RequestBody::Upgrade),
+        (Some(_), Some(body)) => {
+            if request.one_request {
+                request
+                    .inject_headers
+                    .push(("Connection".to_string(), "close".to_string()));
+            }
+            Some(body)
+        }
+    };
+
+    // rewrite the request head if filters added headers, or to frame the request
+    let initial_data = match request.head_len {
+        Some(head_len) => {
+            let mut head = vec![0; head_len];
+            if let Err(e) = socket.read_exact(&mut head).await {
+                error!("failed to read request head: {:?}", e);
+                return;
+            }
+            Some(edge::inject_headers(
+                &head,
+                &request.path,
+                &request.inject_headers,
+            ))
+        }
+        None => None,
+    };
+    let requests = Requests::new(request.clone(), body);
+
     // allocate a new stream for this request
-    let (active_stream, queue_rx) = ActiveStream::new(client.clone());
+    let (active_stream, queue_rx) = ActiveStream::new(
+        client.clone(),
+        request.peer_addr,
+        Some(request.request_id.clone()),
+    );
     let stream_id = active_stream.id.clone();
+    let stats = active_stream.stats.clone();
 
     info!("new stream connected: {}", active_stream.id.to_string());
+    info!(
+        "access: request_id={} peer={:?} host={} {} {}",
+        request.request_id,
+        request.peer_addr.map(|addr| addr.ip()),
+        request.host,
+        request.method,
+        request.path
+    );
+    access_log::forwarded(&client, &request);
+    let transcript = transcripts::forwarded(&client, &request).await.map(Arc::new);
     let (stream, sink) = tokio::io::split(socket);
 
     // add our stream
     ACTIVE_STREAMS.insert(stream_id.clone(), active_stream.clone());
 
     // read from socket, write to client
+    let visitor_transcript = transcript.clone();
+    let visitor_stats = stats.clone();
     tokio::spawn(async move {
-        process_tcp_stream(active_stream, stream).await;
+        process_tcp_stream(active_stream, stream, initial_data, Some(requests)).await;
+        if let Some(transcript) = visitor_transcript {
+            transcript.record(&visitor_stats).await;
+        }
     });
 
     // read from client, write to socket
     tokio::spawn(async move {
-        tunnel_to_stream(stream_id, sink, queue_rx).await;
+        tunnel_to_stream(
+            stream_id,
+            Some(request.request_id),
+            &client,
+            stats.clone(),
+            sink,
+            queue_rx,
+        )
+        .await;
+        metering::stream_closed(&client, &stats);
+        if let Some(transcript) = transcript {
+            transcript.record(&stats).await;
+        }
     });
 }
 
-fn validate_host_prefix(host: &str) -> Option<String> {
+/// Split a host into its sub-domain and the allowed base domain it belongs to
+pub(crate) fn validate_host_prefix(host: &str) -> Option<(String, String)> {
     let url = format!("http://{}", host);
 
     let host = match url::Url::parse(&url)
@@ -120,36 +282,121 @@ fn validate_host_prefix(host: &str) -> Option<String> {
     let remaining = &domain_segments[1..].join(".");
 
     if CONFIG.allowed_hosts.contains(remaining) {
-        Some(prefix.to_string())
+        Some((prefix.to_string(), remaining.to_string()))
     } else {
         None
     }
 }
 
 /// Response Constants
-const HTTP_REDIRECT_RESPONSE:&'static [u8] = b"HTTP/1.1 301 Moved Permanently\r\nLocation: https://tunnelto.dev/\r\nContent-Length: 20\r\n\r\nhttps://tunnelto.dev";
-const HTTP_INVALID_HOST_RESPONSE: &'static [u8] =
-    b"HTTP/1.1 400\r\nContent-Length: 23\r\n\r\nError: Invalid Hostname";
-const HTTP_NOT_FOUND_RESPONSE: &'static [u8] =
-    b"HTTP/1.1 404\r\nContent-Length: 23\r\n\r\nError: Tunnel Not Found";
-const HTTP_ERROR_LOCATING_HOST_RESPONSE: &'static [u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 27\r\n\r\nError: Error finding tunnel";
-const HTTP_TUNNEL_REFUSED_RESPONSE: &'static [u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 32\r\n\r\nTunnel says: connection refused.";
-const HTTP_OK_RESPONSE: &'static [u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
-const HEALTH_CHECK_PATH: &'static [u8] = b"/0xDEADBEEF_HEALTH_CHECK";
+const HTTP_OK_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+const HEALTH_CHECK_PATH: &[u8] = b"/0xDEADBEEF_HEALTH_CHECK";
+
+/// Request heads are read up to this size, past it the head isn't parsed in full
+const MAX_HEAD_PEEK: usize = 16 * 1024;
+/// How long a visitor gets to send its request head
+const HEAD_PEEK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for more bytes when a peek brought nothing new
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Peek at the socket until `complete` is satisfied with the bytes, `max` bytes are
+/// waiting, the peer stops sending or `timeout` passes, returning what was peeked
+pub(crate) async fn peek_until(
+    socket: &TcpStream,
+    max: usize,
+    timeout: Duration,
+    complete: impl Fn(&[u8]) -> bool,
+) -> std::io::Result<Vec<u8>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = vec![0; max];
+    let mut peeked = 0;
+
+    loop {
+        let n = match tokio::time::timeout_at(deadline, socket.peek(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => break,
+        };
+        if n == 0 || n == max || complete(&buf[..n]) {
+            peeked = n;
+            break;
+        }
+        // peek returns right away while bytes are waiting, give the peer a moment to
+        // send more
+        if n == peeked
+            && tokio::time::timeout_at(deadline, tokio::time::sleep(PEEK_INTERVAL))
+                .await
+                .is_err()
+        {
+            break;
+        }
+        peeked = n;
+    }
+
+    buf.truncate(peeked);
+    Ok(buf)
+}
+
+/// Whether the bytes hold a whole request head, or enough to tell they aren't one
+fn head_complete(bytes: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    !matches!(
+        httparse::Request::new(&mut headers).parse(bytes),
+        Ok(httparse::Status::Partial)
+    )
+}
+
+/// The visitor another instance relayed the stream for, from the PROXY line it sent
+/// ahead of the request, which is read off the stream. Only our instances may send one.
+async fn relayed_visitor(socket: &mut TcpStream) -> Result<Option<SocketAddr>, ()> {
+    const PREFIX: &[u8] = b"PROXY ";
+    let buf = peek_until(
+        socket,
+        network::MAX_PROXY_LINE,
+        HEAD_PEEK_TIMEOUT,
+        |bytes| {
+            !bytes.starts_with(&PREFIX[..bytes.len().min(PREFIX.len())])
+                || bytes.windows(2).any(|w| w == b"\r\n")
+        },
+    )
+    .await
+    .map_err(|e| error!("failed to read from tcp socket: {:?}", e))?;
+    if !buf.starts_with(PREFIX) {
+        return Ok(None);
+    }
+
+    let peer = socket.peer_addr().map_err(|_| ())?;
+    if !network::is_instance(peer.ip()).await {
+        log::warn!(
+            "dropping stream relayed by {}, not one of our instances",
+            peer
+        );
+        return Err(());
+    }
+    let line_len = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end + 2,
+        None => return Err(()),
+    };
+    let mut line = vec![0; line_len];
+    socket
+        .read_exact(&mut line)
+        .await
+        .map_err(|e| error!("failed to read relayed stream: {:?}", e))?;
+    network::parse_proxy_line(&line).map(Some).ok_or(())
+}
 
 /// Filter incoming remote streams
-async fn peek_http_request_host(mut socket: TcpStream) -> Option<(TcpStream, String)> {
-    /// Note we return out if the host header is not found
-    /// within the first 4kb of the request.
-    const MAX_HEADER_PEAK: usize = 4096;
-    let mut buf = vec![0; MAX_HEADER_PEAK]; //1kb
+async fn peek_http_request(mut socket: TcpStream) -> Option<(TcpStream, EdgeRequest)> {
+    // streams relayed by another instance are for the visitor it names
+    let peer_addr = match relayed_visitor(&mut socket).await {
+        Ok(Some(visitor)) => Some(visitor),
+        Ok(None) => socket.peer_addr().ok(),
+        Err(()) => return None,
+    };
 
     log::debug!("checking stream headers");
 
-    let n = match socket.peek(&mut buf).await {
-        Ok(n) => n,
+    let buf = match peek_until(&socket, MAX_HEAD_PEEK, HEAD_PEEK_TIMEOUT, head_complete).await {
+        Ok(buf) => buf,
         Err(e) => {
             error!("failed to read from tcp socket to determine host: {:?}", e);
             return None;
@@ -157,20 +404,24 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<(TcpStream, Str
     };
 
     // make sure we're not peeking the same header bytes
-    if n == 0 {
+    if buf.is_empty() {
         log::debug!("unable to peek header bytes");
         return None;
     }
 
-    log::debug!("peeked {} stream bytes ", n);
+    log::debug!("peeked {} stream bytes ", buf.len());
 
     let mut headers = [httparse::EMPTY_HEADER; 64]; // 30 seems like a generous # of headers
     let mut req = httparse::Request::new(&mut headers);
 
-    if let Err(e) = req.parse(&buf[..n]) {
-        error!("failed to parse incoming http bytes: {:?}", e);
-        return None;
-    }
+    let head_len = match req.parse(&buf) {
+        Ok(httparse::Status::Complete(head_len)) => Some(head_len),
+        Ok(httparse::Status::Partial) => None,
+        Err(e) => {
+            error!("failed to parse incoming http bytes: {:?}", e);
+            return None;
+        }
+    };
 
     // Handle the health check route
     if req.path.map(|s| s.as_bytes()) == Some(HEALTH_CHECK_PATH) {
@@ -187,11 +438,38 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<(TcpStream, Str
     if let Some(Ok(host)) = req
         .headers
         .iter()
-        .filter(|h| h.name.to_lowercase() == "host".to_string())
+        .filter(|h| h.name.to_lowercase() == "host")
         .map(|h| std::str::from_utf8(h.value))
         .next()
     {
-        return Some((socket, host.to_string()));
+        let (sub_domain, base_domain) = match validate_host_prefix(host) {
+            Some((sub_domain, base_domain)) => (Some(sub_domain), Some(base_domain)),
+            None => (None, None),
+        };
+
+        let request = EdgeRequest {
+            request_id: EdgeRequest::generate_request_id(),
+            peer_addr,
+            host: host.to_string(),
+            sub_domain,
+            base_domain,
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or_default().to_string(),
+            headers: req
+                .headers
+                .iter()
+                .map(|h| {
+                    (
+                        h.name.to_string(),
+                        String::from_utf8_lossy(h.value).to_string(),
+                    )
+                })
+                .collect(),
+            head_len,
+            inject_headers: vec![],
+            one_request: false,
+        };
+        return Some((socket, request));
     }
 
     log::debug!("Found no host header, dropping connection.");
@@ -199,12 +477,42 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<(TcpStream, Str
 }
 
 /// Process Messages from the control path in & out of the remote stream
-async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: ReadHalf<TcpStream>) {
+pub(crate) async fn process_tcp_stream(
+    mut tunnel_stream: ActiveStream,
+    mut tcp_stream: ReadHalf<TcpStream>,
+    initial_data: Option<Vec<u8>>,
+    mut requests: Option<Requests>,
+) {
     // send initial control stream init to client
     control_server::send_client_stream_init(tunnel_stream.clone()).await;
 
+    // everything sent to the client, for the trailer
+    let mut checksum = StreamChecksum::default();
+
+    // send any bytes we already consumed from the stream
+    if let Some(data) = initial_data {
+        tunnel_stream.stats.add_in(data.len());
+        checksum.update(&data);
+        let packet = ControlPacket::Data(tunnel_stream.id.clone(), data);
+        match tunnel_stream.client.tx.send(packet).await {
+            Ok(_) => {}
+            Err(QueueError::Overflow(OverflowPolicy::Drop)) => {
+                error!("tunnel queue full, dropping visitor stream");
+                drop_overloaded_stream(&mut tunnel_stream);
+                return;
+            }
+            Err(_) => {
+                error!("failed to forward request head to disconnected client. dropping client.");
+                Connections::remove(&tunnel_stream.client);
+                return;
+            }
+        }
+    }
+
     // now read from stream and forward to clients
-    let mut buf = [0; 1024];
+    let tuning = crate::traffic::tuning(tunnel_stream.client.traffic_profile);
+    let mut buf = vec![0; tuning.read_buffer];
+    let mut last_bytes_out = tunnel_stream.stats.bytes_out();
 
     loop {
         // client is no longer connected
@@ -215,17 +523,54 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
             return;
         }
 
-        // read from stream
-        let n = match tcp_stream.read(&mut buf).await {
-            Ok(n) => n,
-            Err(e) => {
-                eprintln!("failed to read from tcp socket: {:?}", e);
+        // read from stream, giving up on visitors idle longer than the profile allows,
+        // and cutting off kicked ones without waiting for them
+        let read = tcp_stream.read(&mut buf);
+        let read = async {
+            tokio::select! {
+                read = read => Some(read),
+                _ = tunnel_stream.kicked.notified() => None,
+            }
+        };
+        let read = match tuning.idle_timeout {
+            Some(idle_timeout) => match tokio::time::timeout(idle_timeout, read).await {
+                Ok(read) => read,
+                // the visitor may be quietly receiving a response
+                Err(_) if tunnel_stream.stats.bytes_out() != last_bytes_out => {
+                    last_bytes_out = tunnel_stream.stats.bytes_out();
+                    continue;
+                }
+                Err(_) => {
+                    info!("visitor idle for {:?}, closing stream", idle_timeout);
+                    refuse_stream(&mut tunnel_stream, edge::REQUEST_TIMEOUT).await;
+                    return;
+                }
+            },
+            None => read.await,
+        };
+        let n = match read {
+            Some(Ok(n)) => n,
+            Some(Err(e)) => {
+                error!("failed to read from tcp socket: {:?}", e);
+                tunnel_stream.stats.close_visitor();
+                return;
+            }
+            None => {
+                info!("visitor kicked, closing stream");
                 return;
             }
         };
 
         if n == 0 {
             info!("stream ended");
+            tunnel_stream.stats.close_visitor();
+            if tunnel_stream.client.integrity {
+                let _ = tunnel_stream
+                    .client
+                    .tx
+                    .send(ControlPacket::Trailer(tunnel_stream.id.clone(), checksum))
+                    .await;
+            }
             let _ = tunnel_stream
                 .client
                 .tx
@@ -237,13 +582,57 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
             return;
         }
 
+        // the visitor was kicked while we were waiting on it
+        if !ACTIVE_STREAMS.contains_key(&tunnel_stream.id) {
+            info!("stream closed, dropping visitor data");
+            return;
+        }
+
+        // rewrite the heads of later requests, or drop them past the end of a request
+        // that has the connection to itself
+        let data = match requests.as_mut().map(|requests| requests.forward(&buf[..n])) {
+            Some(Ok(data)) => data,
+            Some(Err(error)) => {
+                log::warn!("refusing visitor stream: {}", error.code);
+                refuse_stream(&mut tunnel_stream, error).await;
+                return;
+            }
+            None => buf[..n].to_vec(),
+        };
+        let n = data.len();
+        if n == 0 {
+            continue;
+        }
+
         info!("read {} bytes", n);
+        tunnel_stream.stats.add_in(n);
+        if let Some(bandwidth) = tunnel_stream.client.bandwidth.as_ref() {
+            bandwidth.pace_in(n).await;
+        }
 
-        let data = &buf[..n];
-        let packet = ControlPacket::Data(tunnel_stream.id.clone(), data.to_vec());
+        if let Some(max_request_bytes) = tuning.max_request_bytes {
+            if tunnel_stream.stats.bytes_in() > max_request_bytes {
+                log::warn!(
+                    "visitor sent over {} bytes to {} declared as {:?}, closing stream",
+                    max_request_bytes,
+                    &tunnel_stream.client.host,
+                    tunnel_stream.client.traffic_profile
+                );
+                refuse_stream(&mut tunnel_stream, edge::PAYLOAD_TOO_LARGE).await;
+                return;
+            }
+        }
+
+        checksum.update(&data);
+        let packet = ControlPacket::Data(tunnel_stream.id.clone(), data);
 
         match tunnel_stream.client.tx.send(packet.clone()).await {
             Ok(_) => info!("sent data packet to client: {}", &tunnel_stream.client.id),
+            Err(QueueError::Overflow(OverflowPolicy::Drop)) => {
+                error!("tunnel queue full, dropping visitor stream");
+                drop_overloaded_stream(&mut tunnel_stream);
+                return;
+            }
             Err(_) => {
                 error!("failed to forward tcp packets to disconnected client. dropping client.");
                 Connections::remove(&tunnel_stream.client);
@@ -252,25 +641,123 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
     }
 }
 
-async fn tunnel_to_stream(
+/// Fail a visitor stream whose queue overflowed without disturbing the rest of the tunnel
+pub(crate) fn drop_overloaded_stream(stream: &mut ActiveStream) {
+    ACTIVE_STREAMS.remove(&stream.id);
+    stream.tx.try_send(StreamMessage::Overloaded);
+    stream.tx.close_channel();
+    stream
+        .client
+        .tx
+        .try_send(ControlPacket::End(stream.id.clone()));
+}
+
+/// Stop forwarding a visitor stream the edge won't serve, answering the visitor
+/// with `error` if the tunnel's response hasn't started
+async fn refuse_stream(stream: &mut ActiveStream, error: edge::ErrorPage) {
+    ACTIVE_STREAMS.remove(&stream.id);
+    let _ = stream.tx.send(StreamMessage::Refused(error)).await;
+    stream.tx.close_channel();
+    let _ = stream
+        .client
+        .tx
+        .send(ControlPacket::End(stream.id.clone()))
+        .await;
+}
+
+pub(crate) async fn tunnel_to_stream(
     stream_id: StreamId,
+    request_id: Option<String>,
+    client: &ConnectedClient,
+    stats: Arc<StreamStats>,
     mut sink: WriteHalf<TcpStream>,
-    mut queue: UnboundedReceiver<StreamMessage>,
+    mut queue: QueueReceiver<StreamMessage>,
 ) {
+    let error_format = client.error_format;
+    // everything written to the visitor, to check against the client's trailer
+    let mut checksum = StreamChecksum::default();
+
     loop {
         let result = queue.next().await;
 
         let result = if let Some(message) = result {
             match message {
                 StreamMessage::Data(data) => Some(data),
+                StreamMessage::Trailer(sent) => {
+                    crate::stream_integrity::verify(
+                        &stream_id,
+                        request_id.as_deref(),
+                        &checksum,
+                        &sent,
+                    );
+                    continue;
+                }
                 StreamMessage::TunnelRefused => {
                     info!("tunnel refused");
-                    let _ = sink.write_all(HTTP_TUNNEL_REFUSED_RESPONSE).await;
+                    stats.set_status(edge::TUNNEL_REFUSED.status);
+                    access_log::stream_answered(
+                        client,
+                        request_id.as_deref(),
+                        &edge::TUNNEL_REFUSED,
+                    );
+                    let _ = sink
+                        .write_all(
+                            &edge::TUNNEL_REFUSED.render(request_id.as_deref(), error_format),
+                        )
+                        .await;
                     None
                 }
                 StreamMessage::NoClientTunnel => {
                     info!("client tunnel not found");
-                    let _ = sink.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    stats.set_status(edge::TUNNEL_NOT_FOUND.status);
+                    access_log::stream_answered(
+                        client,
+                        request_id.as_deref(),
+                        &edge::TUNNEL_NOT_FOUND,
+                    );
+                    let _ = sink
+                        .write_all(
+                            &edge::TUNNEL_NOT_FOUND.render(request_id.as_deref(), error_format),
+                        )
+                        .await;
+                    None
+                }
+                StreamMessage::Kicked => {
+                    info!("visitor kicked");
+                    None
+                }
+                StreamMessage::End => {
+                    info!("tunnel ended stream");
+                    None
+                }
+                StreamMessage::Refused(error) => {
+                    info!("stream refused: {}", error.code);
+                    access_log::stream_answered(client, request_id.as_deref(), &error);
+                    if stats.bytes_out() == 0 {
+                        stats.set_status(error.status);
+                        let _ = sink
+                            .write_all(&error.render(request_id.as_deref(), error_format))
+                            .await;
+                    }
+                    None
+                }
+                StreamMessage::Overloaded => {
+                    info!("stream overloaded");
+                    access_log::stream_answered(
+                        client,
+                        request_id.as_deref(),
+                        &edge::SERVICE_UNAVAILABLE,
+                    );
+                    // only answer if the tunnel's response hasn't started
+                    if stats.bytes_out() == 0 {
+                        stats.set_status(edge::SERVICE_UNAVAILABLE.status);
+                        let _ = sink
+                            .write_all(
+                                &edge::SERVICE_UNAVAILABLE
+                                    .render(request_id.as_deref(), error_format),
+                            )
+                            .await;
+                    }
                     None
                 }
             }
@@ -287,15 +774,28 @@ async fn tunnel_to_stream(
                 });
 
                 ACTIVE_STREAMS.remove(&stream_id);
+                crate::data_connection::forget(&stream_id);
                 return;
             }
         };
 
+        // the response's status line comes first
+        if stats.bytes_out() == 0 {
+            if let Some(status) = edge::response_status(&data) {
+                stats.set_status(status);
+            }
+        }
+        if let Some(bandwidth) = client.bandwidth.as_ref() {
+            bandwidth.pace_out(data.len()).await;
+        }
         let result = sink.write_all(&data).await;
 
         if result.is_err() {
             info!("stream closed, disconnecting");
+            crate::data_connection::forget(&stream_id);
             return;
         }
+        stats.add_out(data.len());
+        checksum.update(&data);
     }
 }
@@ -0,0 +1,460 @@
+//! Where a visitor's request ends on its connection. The edge filters only see the
+//! first head on a connection. Later requests on a kept-alive connection get their
+//! forwarded headers rewritten here as their heads arrive. Filters that judge requests
+//! one at a time (access rules, rate limits, external authorization, signed tunnels)
+//! can't be run again mid-stream, so when one of them is in play the edge asks for
+//! `Connection: close` and stops forwarding at the end of that request's body, as framed
+//! by its head. Pipelined requests after it are dropped, and the visitor sends them again
+//! on a new connection.
+use super::*;
+
+/// Heads of later requests are read up to this size, past it the connection is closed
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// How a request's body is framed on the connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestBody {
+    /// this many bytes, from `Content-Length`, or none
+    Length(u64),
+    /// `Transfer-Encoding: chunked`, up to the last chunk and the trailers
+    Chunked(Chunked),
+    /// the connection switches protocols, everything after the head belongs to it
+    Upgrade,
+}
+
+/// Progress through a chunked body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunked {
+    /// the chunk size line, with the size read so far, and whether an extension started
+    Size {
+        size: u64,
+        digits: bool,
+        extension: bool,
+    },
+    /// chunk data left, followed by its CRLF
+    Data(u64),
+    /// the CRLF after chunk data
+    DataEnd,
+    /// trailer fields, with the length of the current line
+    Trailer(usize),
+    Done,
+}
+
+const CHUNK_SIZE_START: Chunked = Chunked::Size {
+    size: 0,
+    digits: false,
+    extension: false,
+};
+
+impl RequestBody {
+    /// The request's body framing, `None` if its head is ambiguous about it, i.e. both a
+    /// `Content-Length` and `Transfer-Encoding` or lengths that disagree, which is how
+    /// requests get smuggled past the edge
+    pub fn of(request: &EdgeRequest) -> Option<Self> {
+        let header_values = |name: &str| {
+            request
+                .headers
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+                .flat_map(|(_, v)| v.split(','))
+                .map(|v| v.trim().to_ascii_lowercase())
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        let upgrade = request.header("upgrade").is_some()
+            && header_values("connection").iter().any(|v| v == "upgrade");
+        if upgrade {
+            return Some(RequestBody::Upgrade);
+        }
+
+        let encodings = header_values("transfer-encoding");
+        let lengths = header_values("content-length");
+        match (encodings.last(), lengths.first()) {
+            (Some(_), Some(_)) => None,
+            (Some(encoding), None) if encoding == "chunked" => {
+                Some(RequestBody::Chunked(CHUNK_SIZE_START))
+            }
+            (Some(_), None) => None,
+            (None, Some(length)) if lengths.iter().all(|l| l == length) => {
+                length.parse().ok().map(RequestBody::Length)
+            }
+            (None, Some(_)) => None,
+            (None, None) => Some(RequestBody::Length(0)),
+        }
+    }
+
+    /// Take the part of `data` that belongs to the body, the rest comes after the request
+    pub fn consume(&mut self, data: &[u8]) -> usize {
+        match self {
+            RequestBody::Upgrade => data.len(),
+            RequestBody::Length(remaining) => {
+                let n = (*remaining).min(data.len() as u64);
+                *remaining -= n;
+                n as usize
+            }
+            RequestBody::Chunked(chunked) => chunked.consume(data),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        matches!(
+            self,
+            RequestBody::Length(0) | RequestBody::Chunked(Chunked::Done)
+        )
+    }
+}
+
+/// The requests a visitor sends on its connection, as they are forwarded to the tunnel
+pub struct Requests {
+    /// the connection's first request, as the edge filters saw it
+    first: EdgeRequest,
+    state: RequestsState,
+    /// whether a head has been forwarded yet
+    forwarded: bool,
+}
+
+enum RequestsState {
+    /// forwarding a request's body
+    Body(RequestBody),
+    /// collecting the next request's head
+    Head(Vec<u8>),
+    /// the connection carries no more requests, whatever else the visitor sends is dropped
+    Done,
+}
+
+impl Requests {
+    /// Track the requests on a connection whose first head was forwarded with its
+    /// `body` framing, or is still to be read if it didn't fit in the edge's peek
+    pub fn new(first: EdgeRequest, body: Option<RequestBody>) -> Self {
+        let forwarded = body.is_some();
+        let state = match body {
+            Some(body) => RequestsState::Body(body),
+            None => RequestsState::Head(vec![]),
+        };
+        Requests {
+            first,
+            state,
+            forwarded,
+        }
+    }
+
+    /// The bytes to forward for `data` read from the visitor, with the heads of the
+    /// requests in it rewritten. A first head the edge couldn't read fails the connection
+    /// with the error page for the visitor.
+    pub fn forward(&mut self, data: &[u8]) -> Result<Vec<u8>, edge::ErrorPage> {
+        let mut out = vec![];
+        // what was collected past a head, going to its body or the requests after it
+        let mut rest;
+        let mut data: &[u8] = data;
+        while !data.is_empty() {
+            match &mut self.state {
+                RequestsState::Body(body) => {
+                    let n = body.consume(data);
+                    out.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    if body.is_complete() {
+                        self.state = match self.first.one_request {
+                            true => RequestsState::Done,
+                            false => RequestsState::Head(vec![]),
+                        };
+                    }
+                }
+                RequestsState::Head(head) => {
+                    head.extend_from_slice(data);
+                    data = &[];
+                    let head = std::mem::take(head);
+                    match self.next_request(&head) {
+                        Ok(Some((head_len, rewritten, body))) => {
+                            out.extend(rewritten);
+                            self.forwarded = true;
+                            self.state = RequestsState::Body(body);
+                            rest = head;
+                            data = &rest[head_len..];
+                        }
+                        Ok(None) => self.state = RequestsState::Head(head),
+                        Err(error) => {
+                            self.state = RequestsState::Done;
+                            if !self.forwarded {
+                                return Err(error);
+                            }
+                            log::warn!(
+                                "closing kept-alive connection to {:?} on a bad request head",
+                                self.first.sub_domain
+                            );
+                        }
+                    }
+                }
+                RequestsState::Done => {
+                    log::debug!("dropping visitor data after the request");
+                    break;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// The next request's head length, rewritten head and body framing, if `head` holds
+    /// all of it
+    fn next_request(
+        &self,
+        head: &[u8],
+    ) -> Result<Option<(usize, Vec<u8>, RequestBody)>, edge::ErrorPage> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        let head_len = match req.parse(head) {
+            Ok(httparse::Status::Complete(head_len)) => head_len,
+            Ok(httparse::Status::Partial) if head.len() > MAX_HEAD_LEN => {
+                return Err(edge::HEAD_TOO_LARGE)
+            }
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(_) => return Err(edge::BAD_REQUEST),
+        };
+
+        let headers = req
+            .headers
+            .iter()
+            .map(|h| {
+                (
+                    h.name.to_string(),
+                    String::from_utf8_lossy(h.value).to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let request = EdgeRequest {
+            request_id: EdgeRequest::generate_request_id(),
+            host: headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case("host"))
+                .map(|(_, v)| v.clone())
+                .unwrap_or_else(|| self.first.host.clone()),
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or_default().to_string(),
+            headers,
+            head_len: Some(head_len),
+            inject_headers: vec![],
+            ..self.first.clone()
+        };
+        let body = RequestBody::of(&request).ok_or(edge::BAD_REQUEST)?;
+
+        let forwarded = edge::forwarded_headers(&request, None);
+        let rewritten = edge::inject_headers(&head[..head_len], &request.path, &forwarded);
+        Ok(Some((head_len, rewritten, body)))
+    }
+}
+
+impl Chunked {
+    fn consume(&mut self, data: &[u8]) -> usize {
+        let mut i = 0;
+        while i < data.len() {
+            match self {
+                Chunked::Done => break,
+                Chunked::Data(remaining) => {
+                    let n = (*remaining).min((data.len() - i) as u64);
+                    *remaining -= n;
+                    i += n as usize;
+                    if *remaining == 0 {
+                        *self = Chunked::DataEnd;
+                    }
+                    continue;
+                }
+                Chunked::DataEnd => match data[i] {
+                    b'\r' => {}
+                    b'\n' => *self = CHUNK_SIZE_START,
+                    // malformed, the request ends here
+                    _ => *self = Chunked::Done,
+                },
+                Chunked::Size {
+                    size,
+                    digits,
+                    extension,
+                } => match (data[i], *extension) {
+                    (b'\n', _) if !*digits => *self = Chunked::Done,
+                    (b'\n', _) if *size == 0 => *self = Chunked::Trailer(0),
+                    (b'\n', _) => *self = Chunked::Data(*size),
+                    (_, true) | (b'\r', _) => {}
+                    (b';', false) | (b' ', false) | (b'\t', false) => *extension = true,
+                    (b, false) => {
+                        let digit = (b as char).to_digit(16);
+                        match digit.and_then(|d| size.checked_mul(16)?.checked_add(d as u64)) {
+                            Some(next) => {
+                                *size = next;
+                                *digits = true;
+                            }
+                            None => *self = Chunked::Done,
+                        }
+                    }
+                },
+                Chunked::Trailer(line_len) => match data[i] {
+                    b'\r' => {}
+                    b'\n' if *line_len == 0 => *self = Chunked::Done,
+                    b'\n' => *line_len = 0,
+                    _ => *line_len += 1,
+                },
+            }
+            i += 1;
+        }
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> EdgeRequest {
+        EdgeRequest {
+            request_id: "test".to_string(),
+            peer_addr: None,
+            host: "foo.example.com".to_string(),
+            sub_domain: Some("foo".to_string()),
+            base_domain: Some("example.com".to_string()),
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            head_len: Some(0),
+            inject_headers: vec![],
+            one_request: false,
+        }
+    }
+
+    #[test]
+    fn framing_from_head() {
+        assert_eq!(RequestBody::of(&request(&[])), Some(RequestBody::Length(0)));
+        assert_eq!(
+            RequestBody::of(&request(&[("Content-Length", "12")])),
+            Some(RequestBody::Length(12))
+        );
+        assert_eq!(
+            RequestBody::of(&request(&[
+                ("content-length", "12"),
+                ("Content-Length", "12")
+            ])),
+            Some(RequestBody::Length(12))
+        );
+        assert_eq!(
+            RequestBody::of(&request(&[("Transfer-Encoding", "gzip, chunked")])),
+            Some(RequestBody::Chunked(CHUNK_SIZE_START))
+        );
+        assert_eq!(
+            RequestBody::of(&request(&[
+                ("Upgrade", "websocket"),
+                ("Connection", "keep-alive, Upgrade")
+            ])),
+            Some(RequestBody::Upgrade)
+        );
+    }
+
+    #[test]
+    fn ambiguous_heads_refused() {
+        assert_eq!(
+            RequestBody::of(&request(&[
+                ("Content-Length", "12"),
+                ("Transfer-Encoding", "chunked")
+            ])),
+            None
+        );
+        assert_eq!(
+            RequestBody::of(&request(&[
+                ("Content-Length", "12"),
+                ("Content-Length", "13")
+            ])),
+            None
+        );
+        assert_eq!(RequestBody::of(&request(&[("Content-Length", "-1")])), None);
+        assert_eq!(
+            RequestBody::of(&request(&[("Transfer-Encoding", "chunked, gzip")])),
+            None
+        );
+    }
+
+    #[test]
+    fn length_body() {
+        let mut body = RequestBody::Length(5);
+        assert_eq!(body.consume(b"abc"), 3);
+        assert!(!body.is_complete());
+        assert_eq!(body.consume(b"deGET / HTTP/1.1"), 2);
+        assert!(body.is_complete());
+        assert_eq!(body.consume(b"more"), 0);
+    }
+
+    #[test]
+    fn chunked_body() {
+        let data =
+            b"4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nGET / HTTP/1.1\r\n";
+        let body_len = data.len() - "GET / HTTP/1.1\r\n".len();
+
+        let mut body = RequestBody::Chunked(CHUNK_SIZE_START);
+        assert_eq!(body.consume(data), body_len);
+        assert!(body.is_complete());
+
+        // a byte at a time
+        let mut body = RequestBody::Chunked(CHUNK_SIZE_START);
+        let consumed: usize = data.chunks(1).map(|byte| body.consume(byte)).sum();
+        assert_eq!(consumed, body_len);
+        assert!(body.is_complete());
+    }
+
+    #[test]
+    fn later_heads_rewritten() {
+        let mut first = request(&[]);
+        first.peer_addr = Some("203.0.113.7:5000".parse().unwrap());
+        let mut requests = Requests::new(first, Some(RequestBody::Length(2)));
+
+        let data = b"hiGET /b HTTP/1.1\r\nHost: foo.example.com\r\nX-Forwarded-For: 6.6.6.6\r\nContent-Length: 1\r\n\r\nxGET /c HT";
+        let out = String::from_utf8(requests.forward(data).unwrap()).unwrap();
+        assert!(out.starts_with("hiGET /b HTTP/1.1\r\n"));
+        assert!(out.contains("X-Forwarded-For: 203.0.113.7\r\n"));
+        assert!(!out.contains("6.6.6.6"));
+        assert!(out.ends_with("\r\n\r\nx"));
+
+        // the next head is held until it's whole
+        let out = requests
+            .forward(b"TP/1.1\r\nHost: foo.example.com\r\n")
+            .unwrap();
+        assert!(out.is_empty());
+        let out = String::from_utf8(requests.forward(b"\r\n").unwrap()).unwrap();
+        assert!(out.starts_with("GET /c HTTP/1.1\r\n"));
+        assert!(out.contains("x-tunnelto-sub-domain: foo\r\n"));
+    }
+
+    #[test]
+    fn one_request_drops_the_rest() {
+        let mut first = request(&[]);
+        first.one_request = true;
+        let mut requests = Requests::new(first, Some(RequestBody::Length(2)));
+        let out = requests.forward(b"hiGET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(out, b"hi".to_vec());
+        assert!(requests.forward(b"more").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_heads() {
+        // the first head is refused
+        let mut requests = Requests::new(request(&[]), None);
+        let head = b"POST / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(requests.forward(head).unwrap_err().status, 400);
+
+        // later ones close the connection
+        let mut requests = Requests::new(request(&[]), Some(RequestBody::Length(0)));
+        assert!(requests.forward(head).unwrap().is_empty());
+        assert!(requests
+            .forward(b"GET / HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn malformed_chunks_end_the_request() {
+        let mut body = RequestBody::Chunked(CHUNK_SIZE_START);
+        assert_eq!(body.consume(b"zz\r\nsmuggled"), 1);
+        assert!(body.is_complete());
+
+        let mut body = RequestBody::Chunked(CHUNK_SIZE_START);
+        assert_eq!(body.consume(b"fffffffffffffffff\r\n"), 17);
+        assert!(body.is_complete());
+    }
+}
@@ -0,0 +1,269 @@
+//! Operator routing rules written in Rhai (https://rhai.rs), loaded from ROUTING_SCRIPT
+//! and evaluated for every visitor request before it is tunneled.
+//!
+//! The script defines `fn route(request)`, where `request` is a map of `host`, `sub_domain`,
+//! `base_domain`, `method`, `path`, `peer` and `headers` (lowercase names). It returns `()`
+//! to let the request through untouched, or a map of any of:
+//!
+//! - `reject`: answer the visitor with this status (and `body`, if given) instead
+//! - `host`: route the request to the tunnel serving this host
+//! - `path`: forward the request with this path instead
+//! - `tags`: a string or array of strings, forwarded to the client in `x-tunnelto-tags`
+//!
+//! ```rhai
+//! fn route(request) {
+//!     if request.path.starts_with("/internal") { return #{ reject: 404 }; }
+//!     if request.sub_domain == "old-name" { return #{ host: "new-name.tunnelto.dev" }; }
+//!     #{ tags: ["beta"] }
+//! }
+//! ```
+//!
+//! Scripts can't touch the file system or network, and are stopped once they exceed
+//! their operation budget or ROUTING_SCRIPT_TIMEOUT_MS. A failing script rejects the request.
+use super::*;
+use crate::edge::{http_response, ErrorPage};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::cell::Cell;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const TAGS_HEADER: &str = "x-tunnelto-tags";
+
+const MAX_OPERATIONS: u64 = 100_000;
+
+const SCRIPT_FAILED: ErrorPage = ErrorPage {
+    status: 500,
+    code: ErrorCode::RoutingScriptFailed,
+    message: "Error: Request could not be routed",
+};
+
+thread_local! {
+    /// when the script running on this thread must stop
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// What the script decided for a request
+#[derive(Debug, Default)]
+struct Decision {
+    reject: Option<(u16, String)>,
+    host: Option<String>,
+    path: Option<String>,
+    tags: Vec<String>,
+}
+
+pub struct RoutingScript {
+    engine: Engine,
+    ast: AST,
+    timeout: Duration,
+}
+
+impl RoutingScript {
+    /// Load the configured script, panics if it doesn't compile
+    pub fn from_config() -> Option<RoutingScript> {
+        let path = CONFIG.routing_script.as_ref()?;
+        let script = RoutingScript::load(path, CONFIG.routing_script_timeout)
+            .unwrap_or_else(|e| panic!("invalid ROUTING_SCRIPT {:?}: {}", path, e));
+
+        info!("loaded routing script {:?}", path);
+        Some(script)
+    }
+
+    fn load(path: &Path, timeout: Duration) -> Result<RoutingScript, String> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(64 * 1024)
+            .set_max_array_size(1024)
+            .set_max_map_size(1024)
+            .disable_symbol("eval");
+        engine.on_print(|s| log::info!("routing script: {}", s));
+        engine.on_debug(|s, _, pos| log::debug!("routing script {}: {}", pos, s));
+        engine.on_progress(|_| {
+            let expired = DEADLINE.with(|d| d.get().is_some_and(|d| Instant::now() > d));
+            if expired {
+                Some("timed out".into())
+            } else {
+                None
+            }
+        });
+
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| e.to_string())?;
+        if !ast.iter_functions().any(|f| f.name == "route" && f.params.len() == 1) {
+            return Err("the script must define `fn route(request)`".into());
+        }
+
+        Ok(RoutingScript {
+            engine,
+            ast,
+            timeout,
+        })
+    }
+
+    fn decide(&self, request: &EdgeRequest) -> Result<Decision, String> {
+        DEADLINE.with(|d| d.set(Some(Instant::now() + self.timeout)));
+        let result = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &self.ast,
+            "route",
+            (request_map(request),),
+        );
+        DEADLINE.with(|d| d.set(None));
+
+        let result = result.map_err(|e| e.to_string())?;
+        if result.is_unit() {
+            return Ok(Decision::default());
+        }
+
+        let map = result
+            .try_cast::<Map>()
+            .ok_or("`route` must return () or a map")?;
+        let string = |key: &str| -> Result<Option<String>, String> {
+            match map.get(key) {
+                None => Ok(None),
+                Some(value) => value
+                    .clone()
+                    .into_string()
+                    .map(Some)
+                    .map_err(|_| format!("`{}` must be a string", key)),
+            }
+        };
+
+        let reject = match map.get("reject") {
+            None => None,
+            Some(status) => {
+                let status = status
+                    .as_int()
+                    .ok()
+                    .filter(|s| (400..600).contains(s))
+                    .ok_or("`reject` must be a 4xx or 5xx status")?;
+                Some((status as u16, string("body")?.unwrap_or_default()))
+            }
+        };
+
+        let tags = match map.get("tags") {
+            None => vec![],
+            Some(tags) if tags.is_string() => vec![tags.to_string()],
+            Some(tags) => tags
+                .clone()
+                .try_cast::<Array>()
+                .ok_or("`tags` must be a string or an array")?
+                .into_iter()
+                .map(|tag| tag.to_string())
+                .collect(),
+        };
+
+        Ok(Decision {
+            reject,
+            host: string("host")?,
+            path: string("path")?,
+            tags,
+        })
+    }
+}
+
+fn request_map(request: &EdgeRequest) -> Map {
+    let optional = |value: &Option<String>| match value {
+        Some(value) => Dynamic::from(value.clone()),
+        None => Dynamic::UNIT,
+    };
+
+    let headers = request
+        .headers
+        .iter()
+        .map(|(name, value)| (name.to_lowercase().into(), Dynamic::from(value.clone())))
+        .collect::<Map>();
+
+    let mut map = Map::new();
+    map.insert("host".into(), request.host.clone().into());
+    map.insert("sub_domain".into(), optional(&request.sub_domain));
+    map.insert("base_domain".into(), optional(&request.base_domain));
+    map.insert("method".into(), request.method.clone().into());
+    map.insert("path".into(), request.path.clone().into());
+    map.insert(
+        "peer".into(),
+        optional(&request.peer_addr.map(|addr| addr.ip().to_string())),
+    );
+    map.insert("headers".into(), headers.into());
+    map
+}
+
+impl EdgeFilter for RoutingScript {
+    fn name(&self) -> &'static str {
+        "routing_script"
+    }
+
+    fn apply<'a>(&'a self, request: &'a mut EdgeRequest) -> BoxFuture<'a, FilterAction> {
+        async move {
+            let decision = match self.decide(request) {
+                Ok(decision) => decision,
+                Err(e) => {
+                    error!(
+                        "routing script failed request_id={}: {}",
+                        request.request_id, e
+                    );
+                    return FilterAction::Respond(
+                        SCRIPT_FAILED.render(Some(&request.request_id), ErrorFormat::Text),
+                    );
+                }
+            };
+
+            if let Some((status, body)) = decision.reject {
+                log::debug!(
+                    "routing script rejected {} {} with {} request_id={}",
+                    request.host,
+                    request.path,
+                    status,
+                    request.request_id
+                );
+                return FilterAction::Respond(http_response(&status.to_string(), &body));
+            }
+
+            if let Some(host) = decision.host {
+                let (sub_domain, base_domain) = match remote::validate_host_prefix(&host) {
+                    Some(prefix) => prefix,
+                    None => {
+                        error!("routing script returned a host we don't serve: {}", host);
+                        return FilterAction::Respond(
+                            SCRIPT_FAILED.render(Some(&request.request_id), ErrorFormat::Text),
+                        );
+                    }
+                };
+                log::debug!(
+                    "routing script sent {} to {} request_id={}",
+                    request.host,
+                    host,
+                    request.request_id
+                );
+                request.host = host;
+                request.sub_domain = Some(sub_domain);
+                request.base_domain = Some(base_domain);
+            }
+
+            if let Some(path) = decision.path {
+                // the path is rewritten in the request head, which must have fit in the peek
+                if request.head_len.is_none() {
+                    return FilterAction::Respond(http_response(
+                        "431 Request Header Fields Too Large",
+                        "request head too large to route",
+                    ));
+                }
+                request.path = path;
+            }
+
+            if !decision.tags.is_empty() {
+                request
+                    .inject_headers
+                    .push((TAGS_HEADER.to_string(), decision.tags.join(", ")));
+            }
+
+            FilterAction::Continue
+        }
+        .boxed()
+    }
+}
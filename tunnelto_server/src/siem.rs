@@ -0,0 +1,487 @@
+//! Export of security events to a SIEM (SIEM_URL), i.e. Splunk or Elastic, as CEF or
+//! json (SIEM_FORMAT) over syslog or http:
+//!
+//! - `syslog+udp://collector:514` or `syslog+tcp://collector:601`, RFC 5424 messages
+//!   (octet-counted over tcp)
+//! - `https://collector/events`, one event per POST
+//!
+//! The event classes (SIEM_EVENTS, all of them by default) are `auth` failures, `admin`
+//! actions, `ban`s (visitor connections kicked off a tunnel) and `tunnel` lifecycle.
+//! Events are queued and sent in the background: when the collector falls behind they
+//! are dropped, never held up the tunnels.
+use super::*;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Events waiting for the collector
+const QUEUE: usize = 1024;
+/// local0, the facility of our syslog messages
+const SYSLOG_FACILITY: u8 = 16;
+
+lazy_static! {
+    static ref EVENTS: RwLock<Option<mpsc::Sender<SecurityEvent>>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventClass {
+    Auth,
+    Admin,
+    Ban,
+    Tunnel,
+}
+
+impl EventClass {
+    pub fn all() -> Vec<EventClass> {
+        vec![
+            EventClass::Auth,
+            EventClass::Admin,
+            EventClass::Ban,
+            EventClass::Tunnel,
+        ]
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventClass::Auth => "auth",
+            EventClass::Admin => "admin",
+            EventClass::Ban => "ban",
+            EventClass::Tunnel => "tunnel",
+        }
+    }
+}
+
+impl FromStr for EventClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "auth" => Ok(EventClass::Auth),
+            "admin" => Ok(EventClass::Admin),
+            "ban" => Ok(EventClass::Ban),
+            "tunnel" => Ok(EventClass::Tunnel),
+            other => Err(format!(
+                "unknown event class `{}`, expected `auth`, `admin`, `ban` or `tunnel`",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SiemFormat {
+    /// ArcSight Common Event Format
+    Cef,
+    #[default]
+    Json,
+}
+
+impl FromStr for SiemFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "cef" => Ok(SiemFormat::Cef),
+            "json" => Ok(SiemFormat::Json),
+            other => Err(format!(
+                "unknown format `{}`, expected `cef` or `json`",
+                other
+            )),
+        }
+    }
+}
+
+/// Where events go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SiemTarget {
+    /// `host:port`
+    SyslogUdp(String),
+    /// `host:port`
+    SyslogTcp(String),
+    Http(String),
+}
+
+impl FromStr for SiemTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = url::Url::parse(s).map_err(|e| e.to_string())?;
+        let addr = || -> Result<String, String> {
+            let host = url.host_str().ok_or("missing collector host")?;
+            Ok(format!("{}:{}", host, url.port().unwrap_or(514)))
+        };
+
+        match url.scheme() {
+            "syslog+udp" | "syslog" => Ok(SiemTarget::SyslogUdp(addr()?)),
+            "syslog+tcp" => Ok(SiemTarget::SyslogTcp(addr()?)),
+            "http" | "https" => Ok(SiemTarget::Http(s.to_string())),
+            other => Err(format!(
+                "unknown scheme `{}`, expected `syslog+udp`, `syslog+tcp` or `https`",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SiemConfig {
+    pub target: SiemTarget,
+    pub format: SiemFormat,
+    pub classes: Vec<EventClass>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SecurityEvent {
+    #[serde(serialize_with = "rfc3339")]
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub class: EventClass,
+    /// i.e. `auth_failed` or `tunnel_opened`
+    pub event: &'static str,
+    /// 0 (routine) to 10 (critical), as in CEF
+    pub severity: u8,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// the label of the key that opened the tunnel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_label: Option<String>,
+    /// the hash of the key the event is about, as the store holds it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// the address the event came from: a client, an operator or a visitor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<IpAddr>,
+}
+
+impl SecurityEvent {
+    fn new(class: EventClass, event: &'static str, severity: u8, message: String) -> Self {
+        SecurityEvent {
+            at: chrono::Utc::now(),
+            class,
+            event,
+            severity,
+            message,
+            account_id: None,
+            sub_domain: None,
+            client_id: None,
+            key_label: None,
+            key_id: None,
+            source_ip: None,
+        }
+    }
+
+    fn tunnel(mut self, client: &ConnectedClient) -> Self {
+        self.account_id = client.account_id;
+        self.sub_domain = Some(client.host.clone());
+        self.client_id = Some(client.id.to_string());
+        self.key_label = client.entitlements.label.clone();
+        self
+    }
+}
+
+fn rfc3339<S: serde::Serializer>(
+    at: &chrono::DateTime<chrono::Utc>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+/// Queue the event for the collector, if it takes this class of events
+fn record(event: SecurityEvent) {
+    let classes = match CONFIG.siem.as_ref() {
+        Some(siem) => &siem.classes,
+        None => return,
+    };
+    if !classes.contains(&event.class) {
+        return;
+    }
+
+    if let Some(events) = EVENTS.read().unwrap().as_ref() {
+        if events.try_send(event).is_err() {
+            log::debug!("security event queue full, dropping event");
+        }
+    }
+}
+
+/// A client failed to authenticate its tunnel
+pub fn auth_failed(reason: &str, peer: Option<SocketAddr>) {
+    let mut event = SecurityEvent::new(
+        EventClass::Auth,
+        "auth_failed",
+        5,
+        format!("tunnel authentication failed: {}", reason),
+    );
+    event.source_ip = peer.map(|peer| peer.ip());
+    record(event);
+}
+
+/// A key failed too many times in a row and is locked for a while
+pub fn key_locked(key_id: &str, failures: u32, cooldown: Duration, source_ip: Option<IpAddr>) {
+    let mut event = SecurityEvent::new(
+        EventClass::Auth,
+        "key_locked",
+        7,
+        format!(
+            "key locked for {}s after {} failed attempts",
+            cooldown.as_secs(),
+            failures
+        ),
+    );
+    event.key_id = Some(key_id.to_string());
+    event.source_ip = source_ip;
+    record(event);
+}
+
+/// Check an operator's admin key for the action, recording the action or, if a key
+/// was presented but is wrong, the failure
+pub fn admin(action: &'static str, admin_key: Option<&str>) -> bool {
+    let allowed = CONFIG.is_admin(admin_key);
+    match (allowed, admin_key) {
+        (true, _) => record(SecurityEvent::new(
+            EventClass::Admin,
+            action,
+            3,
+            format!("admin action: {}", action),
+        )),
+        (false, Some(_)) => record(SecurityEvent::new(
+            EventClass::Auth,
+            "admin_auth_failed",
+            7,
+            format!("invalid admin key for {}", action),
+        )),
+        (false, None) => {}
+    }
+    allowed
+}
+
+/// The account kicked a visitor connection off its tunnel
+pub fn visitor_kicked(stream: &ActiveStream) {
+    let mut event = SecurityEvent::new(
+        EventClass::Ban,
+        "visitor_kicked",
+        5,
+        format!("visitor connection {} kicked", stream.id.to_string()),
+    )
+    .tunnel(&stream.client);
+    event.source_ip = stream.peer_addr.map(|peer| peer.ip());
+    record(event);
+}
+
+pub fn tunnel_opened(client: &ConnectedClient, peer: Option<SocketAddr>) {
+    let mut event = SecurityEvent::new(
+        EventClass::Tunnel,
+        "tunnel_opened",
+        1,
+        format!("tunnel opened on {}", &client.host),
+    )
+    .tunnel(client);
+    event.source_ip = peer.map(|peer| peer.ip());
+    record(event);
+}
+
+pub fn tunnel_closed(client: &ConnectedClient) {
+    record(
+        SecurityEvent::new(
+            EventClass::Tunnel,
+            "tunnel_closed",
+            1,
+            format!("tunnel closed on {}", &client.host),
+        )
+        .tunnel(client),
+    );
+}
+
+/// A teammate approved a tunnel the account's policy held, see `approvals`
+pub fn tunnel_approved(client: &ConnectedClient, approver: &Uuid) {
+    record(
+        SecurityEvent::new(
+            EventClass::Tunnel,
+            "tunnel_approved",
+            3,
+            format!(
+                "tunnel on {} approved by account {}",
+                &client.host, approver
+            ),
+        )
+        .tunnel(client),
+    );
+}
+
+/// Start sending recorded events to the collector
+pub fn spawn(siem: &'static SiemConfig) {
+    let (tx, mut rx) = mpsc::channel(QUEUE);
+    *EVENTS.write().unwrap() = Some(tx);
+
+    tokio::spawn(async move {
+        let mut sink = Sink::new(&siem.target);
+        while let Some(event) = rx.recv().await {
+            let message = render(siem.format, &event);
+            if let Err(e) = sink.send(&event, message).await {
+                log::warn!("failed to export security event {}: {}", event.event, e);
+            }
+        }
+    });
+}
+
+fn render(format: SiemFormat, event: &SecurityEvent) -> String {
+    match format {
+        SiemFormat::Json => serde_json::to_string(event).unwrap_or_default(),
+        SiemFormat::Cef => cef(event),
+    }
+}
+
+/// `CEF:0|vendor|product|version|event|name|severity|extensions`
+fn cef(event: &SecurityEvent) -> String {
+    let mut extensions = vec![
+        format!("rt={}", event.at.timestamp_millis()),
+        format!("cat={}", event.class.as_str()),
+    ];
+    if let Some(account_id) = event.account_id {
+        extensions.push(format!("suser={}", account_id));
+    }
+    if let Some(sub_domain) = event.sub_domain.as_ref() {
+        extensions.push(format!("dhost={}", cef_extension(sub_domain)));
+    }
+    if let Some(client_id) = event.client_id.as_ref() {
+        extensions.push(format!(
+            "cs1Label=clientId cs1={}",
+            cef_extension(client_id)
+        ));
+    }
+    if let Some(key_label) = event.key_label.as_ref() {
+        extensions.push(format!(
+            "cs2Label=keyLabel cs2={}",
+            cef_extension(key_label)
+        ));
+    }
+    if let Some(key_id) = event.key_id.as_ref() {
+        extensions.push(format!("cs3Label=keyId cs3={}", cef_extension(key_id)));
+    }
+    if let Some(source_ip) = event.source_ip {
+        extensions.push(format!("src={}", source_ip));
+    }
+
+    format!(
+        "CEF:0|tunnelto|tunnelto_server|{}|{}|{}|{}|{}",
+        env!("CARGO_PKG_VERSION"),
+        event.event,
+        cef_header(&event.message),
+        event.severity,
+        extensions.join(" ")
+    )
+}
+
+fn cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_extension(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\n', "\\n")
+}
+
+/// An RFC 5424 syslog message
+fn syslog(event: &SecurityEvent, message: &str) -> String {
+    let severity = if event.severity >= 5 { 4 } else { 6 };
+    let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string());
+    format!(
+        "<{}>1 {} {} tunnelto_server - {} - {}",
+        SYSLOG_FACILITY * 8 + severity,
+        event
+            .at
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        hostname,
+        event.event,
+        message
+    )
+}
+
+/// The connection to the collector, opened on first use
+enum Sink {
+    SyslogUdp(String, Option<UdpSocket>),
+    SyslogTcp(String, Option<TcpStream>),
+    Http(String, reqwest::Client),
+}
+
+impl Sink {
+    fn new(target: &SiemTarget) -> Self {
+        match target {
+            SiemTarget::SyslogUdp(addr) => Sink::SyslogUdp(addr.clone(), None),
+            SiemTarget::SyslogTcp(addr) => Sink::SyslogTcp(addr.clone(), None),
+            SiemTarget::Http(url) => Sink::Http(url.clone(), reqwest::Client::new()),
+        }
+    }
+
+    async fn send(
+        &mut self,
+        event: &SecurityEvent,
+        message: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Sink::SyslogUdp(addr, socket) => {
+                if socket.is_none() {
+                    let udp = UdpSocket::bind("[::]:0").await?;
+                    udp.connect(addr.as_str()).await?;
+                    *socket = Some(udp);
+                }
+                let line = syslog(event, &message);
+                if let Some(udp) = socket.as_ref() {
+                    udp.send(line.as_bytes()).await?;
+                }
+            }
+            Sink::SyslogTcp(addr, stream) => {
+                // octet counting, so messages may hold newlines
+                let line = syslog(event, &message);
+                let frame = format!("{} {}", line.len(), line);
+
+                // reconnect once if the collector dropped us
+                for attempt in 0..2 {
+                    if stream.is_none() {
+                        *stream = Some(TcpStream::connect(addr.as_str()).await?);
+                    }
+                    let result = match stream.as_mut() {
+                        Some(tcp) => tcp.write_all(frame.as_bytes()).await,
+                        None => continue,
+                    };
+                    match result {
+                        Ok(()) => break,
+                        Err(e) => {
+                            *stream = None;
+                            if attempt > 0 {
+                                return Err(e.into());
+                            }
+                        }
+                    }
+                }
+            }
+            Sink::Http(url, client) => {
+                let content_type = if message.starts_with("CEF:") {
+                    "text/plain"
+                } else {
+                    "application/json"
+                };
+                client
+                    .post(url.as_str())
+                    .header(reqwest::header::CONTENT_TYPE, content_type)
+                    .body(message)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
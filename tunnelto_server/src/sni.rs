@@ -0,0 +1,228 @@
+use super::*;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// The largest TLS record, with its header, a client hello is read up to
+const MAX_HELLO_PEEK: usize = 5 + 16 * 1024;
+/// How long a client gets to send its hello
+const HELLO_PEEK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accept raw TLS connections and route them by SNI without terminating TLS
+pub fn spawn(port: u16) {
+    tokio::spawn(async move {
+        let listen_addr = std::net::SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], port));
+        let listener = match crate::handover::bind(listen_addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("failed to bind tls passthrough listener: {:?}", e);
+                return;
+            }
+        };
+        info!("tls passthrough listening on: {}", &listen_addr);
+
+        loop {
+            let socket = match crate::handover::accept(&listener).await {
+                Some(Ok((socket, _))) => socket,
+                Some(Err(_)) => {
+                    error!("failed to accept tls passthrough socket");
+                    continue;
+                }
+                None => return,
+            };
+
+            tokio::spawn(async move {
+                accept_connection(socket, port).await;
+            });
+        }
+    });
+}
+
+async fn accept_connection(socket: TcpStream, port: u16) {
+    let server_name = match peek_server_name(&socket).await {
+        Some(name) => name,
+        None => {
+            log::debug!("no server name in tls client hello, dropping connection");
+            return;
+        }
+    };
+
+    let (sub_domain, base_domain) = match remote::validate_host_prefix(&server_name) {
+        Some(parts) => parts,
+        None => {
+            error!("invalid tls server name: {}", server_name);
+            return;
+        }
+    };
+
+    let client = match Connections::find_by_host(&sub_domain) {
+        Some(client) => client,
+        None => {
+            // check other instances that may be serving this host
+            match network::instance_for_host(&sub_domain).await {
+                Ok((instance, _)) => network::proxy_stream(instance, socket, port, None).await,
+                Err(e) => error!("no tunnel found for sni host {}: {:?}", sub_domain, e),
+            }
+            return;
+        }
+    };
+
+    if client.tunnel_type != TunnelType::TlsPassthrough
+        || (client.base_domain.is_some() && client.base_domain != Some(base_domain))
+    {
+        error!("tunnel for {} does not accept tls passthrough", sub_domain);
+        return;
+    }
+
+    if client.awaiting_approval() {
+        error!("tunnel for {} is awaiting approval", sub_domain);
+        return;
+    }
+
+    // allocate a new stream for this connection
+    let (active_stream, queue_rx) =
+        ActiveStream::new(client.clone(), socket.peer_addr().ok(), None);
+    let stream_id = active_stream.id.clone();
+    let stats = active_stream.stats.clone();
+
+    info!("new tls stream connected: {}", stream_id.to_string());
+    let (stream, sink) = tokio::io::split(socket);
+
+    ACTIVE_STREAMS.insert(stream_id.clone(), active_stream.clone());
+
+    tokio::spawn(async move {
+        remote::process_tcp_stream(active_stream, stream, None, None).await;
+    });
+
+    tokio::spawn(async move {
+        remote::tunnel_to_stream(
+            stream_id,
+            None,
+            &client,
+            stats.clone(),
+            sink,
+            queue_rx,
+        )
+        .await;
+        crate::metering::stream_closed(&client, &stats);
+    });
+}
+
+/// Read the SNI server name from the client hello without consuming it, waiting for the
+/// whole record the hello came in as it may arrive in several segments
+async fn peek_server_name(socket: &TcpStream) -> Option<String> {
+    let buf = match remote::peek_until(socket, MAX_HELLO_PEEK, HELLO_PEEK_TIMEOUT, record_complete)
+        .await
+    {
+        Ok(buf) => buf,
+        Err(e) => {
+            error!("failed to peek tls client hello: {:?}", e);
+            return None;
+        }
+    };
+
+    parse_server_name(&buf)
+}
+
+/// Whether the bytes hold the whole first TLS record, or enough to tell they aren't one
+fn record_complete(data: &[u8]) -> bool {
+    match data {
+        [0x16, _, _, high, low, record @ ..] => {
+            record.len() >= u16::from_be_bytes([*high, *low]) as usize
+        }
+        [0x16, ..] => false,
+        _ => !data.is_empty(),
+    }
+}
+
+/// Minimal parse of a TLS record holding a ClientHello, down to the server_name extension
+fn parse_server_name(data: &[u8]) -> Option<String> {
+    let mut reader = Reader(data);
+
+    // record header: handshake content type, version, length
+    if reader.u8()? != 0x16 {
+        return None;
+    }
+    reader.skip(4)?;
+
+    // handshake header: client hello type and length
+    if reader.u8()? != 0x01 {
+        return None;
+    }
+    reader.skip(3)?;
+
+    // version and random
+    reader.skip(2 + 32)?;
+
+    // session id, cipher suites, compression methods
+    let len = reader.u8()? as usize;
+    reader.skip(len)?;
+    let len = reader.u16()? as usize;
+    reader.skip(len)?;
+    let len = reader.u8()? as usize;
+    reader.skip(len)?;
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+
+    while let Some(extension_type) = extensions.u16() {
+        let len = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(len)?);
+
+        if extension_type != 0x0000 {
+            continue;
+        }
+
+        let list_len = extension.u16()? as usize;
+        let mut names = Reader(extension.take(list_len)?);
+        while let Some(name_type) = names.u8() {
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == 0x00 {
+                return std::str::from_utf8(name).ok().map(|s| s.to_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_the_whole_record() {
+        let record = [&[0x16, 0x03, 0x01, 0x00, 0x04][..], &[0x01, 0, 0, 0]].concat();
+        assert!(!record_complete(&[]));
+        assert!(!record_complete(&record[..3]));
+        assert!(!record_complete(&record[..7]));
+        assert!(record_complete(&record));
+        // not tls, no point waiting
+        assert!(record_complete(b"GET / HTTP/1.1\r\n"));
+    }
+}
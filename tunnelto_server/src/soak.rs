@@ -0,0 +1,62 @@
+use super::*;
+use crate::queue::QueueMetricsSnapshot;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Sizes of everything that could grow without bound in a long-running server
+#[derive(Debug, Clone, Serialize)]
+pub struct InternalCounts {
+    pub clients: usize,
+    pub hosts: usize,
+    pub active_streams: usize,
+    pub queues: QueueMetricsSnapshot,
+    pub open_fds: Option<usize>,
+    pub metering: crate::metering::MeteringStats,
+    pub integrity: crate::stream_integrity::IntegrityStats,
+    pub auth_cache: crate::auth::auth_cache::AuthCacheStats,
+}
+
+impl InternalCounts {
+    pub fn collect() -> Self {
+        InternalCounts {
+            clients: Connections::count(),
+            hosts: Connections::host_count(),
+            active_streams: ACTIVE_STREAMS.len(),
+            queues: QUEUE_METRICS.snapshot(),
+            open_fds: open_fd_count(),
+            metering: crate::metering::stats(),
+            integrity: crate::stream_integrity::stats(),
+            auth_cache: crate::auth::auth_cache::stats(),
+        }
+    }
+}
+
+/// Log the internal counts every `interval` to help spot slow leaks
+pub fn spawn(interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            let counts = InternalCounts::collect();
+            info!(
+                "soak: clients={} hosts={} active_streams={} queued_tunnel={} queued_stream={} open_fds={:?}",
+                counts.clients,
+                counts.hosts,
+                counts.active_streams,
+                counts.queues.tunnel.queued,
+                counts.queues.stream.queued,
+                counts.open_fds
+            );
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+/// Handle an operator request for the internal counts
+pub async fn handle_counts(
+    admin_key: Option<String>,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if !crate::siem::admin("internal_counts", admin_key.as_deref()) {
+        return Err(warp::reject::not_found());
+    }
+
+    Ok(warp::reply::json(&InternalCounts::collect()))
+}
@@ -0,0 +1,226 @@
+//! The host key the server signs key exchanges with, and the client keys users sign in
+//! with. SSH_AUTHORIZED_KEYS lists one client key per line:
+//!
+//!     <key id> ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAI... [comment]
+//!
+//! where the key id is what the account's auth key is stored under
+//! (`tunnelto_server keys hash KEY`): a user signing in with the key opens tunnels as
+//! that auth key would, held to its entitlements and revoked along with it. Ed25519 and
+//! RSA keys are taken. The file is read again at every sign in, so keys can be added and
+//! removed while the server runs.
+use super::wire::{Reader, Writer};
+use super::Error;
+use crate::auth::SigKey;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, RsaPublicKeyComponents, UnparsedPublicKey};
+use sha2::Digest;
+use std::path::Path;
+
+const ED25519: &str = "ssh-ed25519";
+const RSA: &str = "ssh-rsa";
+/// RSA signatures we take, RFC 8332. Plain `ssh-rsa` ones are SHA-1.
+const RSA_SHA256: &str = "rsa-sha2-256";
+const RSA_SHA512: &str = "rsa-sha2-512";
+
+/// The signature algorithms clients may sign in with, for the `server-sig-algs`
+/// extension
+pub const SIGNATURE_ALGORITHMS: &[&str] = &[ED25519, RSA_SHA256, RSA_SHA512];
+
+/// The Ed25519 host key. It's derived from the master key, so every instance sharing
+/// it shows clients the same one, across restarts too.
+pub struct HostKey(Ed25519KeyPair);
+
+impl HostKey {
+    pub fn derive(master_sig_key: &SigKey) -> Self {
+        HostKey(master_sig_key.derive_key_pair("tunnelto ssh host key v1"))
+    }
+
+    pub const ALGORITHM: &'static str = ED25519;
+
+    /// The public key, in the ssh format
+    pub fn blob(&self) -> Vec<u8> {
+        Writer::default()
+            .string(ED25519)
+            .bytes(self.0.public_key().as_ref())
+            .finish()
+    }
+
+    /// A signature of `data`, in the ssh format
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        Writer::default()
+            .string(ED25519)
+            .bytes(self.0.sign(data).as_ref())
+            .finish()
+    }
+
+    /// As `ssh-keygen -l` shows it, for users to check what they connect to
+    pub fn fingerprint(&self) -> String {
+        let hash = sha2::Sha256::digest(&self.blob());
+        format!(
+            "SHA256:{}",
+            base64::encode_config(&hash, base64::STANDARD_NO_PAD)
+        )
+    }
+}
+
+/// A client's public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Ed25519(Vec<u8>),
+    Rsa { e: Vec<u8>, n: Vec<u8> },
+}
+
+impl PublicKey {
+    /// Parse a public key in the ssh format
+    pub fn parse(blob: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(blob);
+        match reader.string()? {
+            ED25519 => Ok(PublicKey::Ed25519(reader.bytes()?.to_vec())),
+            RSA => Ok(PublicKey::Rsa {
+                e: unsigned(reader.bytes()?).to_vec(),
+                n: unsigned(reader.bytes()?).to_vec(),
+            }),
+            _ => Err(Error::Unsupported("public key type")),
+        }
+    }
+
+    /// Whether the key signs with `algorithm`
+    pub fn signs_with(&self, algorithm: &str) -> bool {
+        match self {
+            PublicKey::Ed25519(_) => algorithm == ED25519,
+            PublicKey::Rsa { .. } => algorithm == RSA_SHA256 || algorithm == RSA_SHA512,
+        }
+    }
+
+    /// Check `signature`, in the ssh format, is the key's signature of `message`
+    pub fn verify(&self, algorithm: &str, message: &[u8], signature: &[u8]) -> bool {
+        let mut reader = Reader::new(signature);
+        let signature = match (reader.string(), reader.bytes()) {
+            (Ok(signed_with), Ok(signature)) if signed_with == algorithm => signature,
+            _ => return false,
+        };
+
+        match self {
+            PublicKey::Ed25519(key) => UnparsedPublicKey::new(&signature::ED25519, key)
+                .verify(message, signature)
+                .is_ok(),
+            PublicKey::Rsa { e, n } => {
+                let params = match algorithm {
+                    RSA_SHA256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    RSA_SHA512 => &signature::RSA_PKCS1_2048_8192_SHA512,
+                    _ => return false,
+                };
+                // some clients drop the leading zeros of the signature
+                let mut padded = vec![0; n.len().saturating_sub(signature.len())];
+                padded.extend_from_slice(signature);
+                RsaPublicKeyComponents { n, e }
+                    .verify(params, message, &padded)
+                    .is_ok()
+            }
+        }
+    }
+}
+
+/// A big-endian number without the zeros mpints lead with
+fn unsigned(n: &[u8]) -> &[u8] {
+    let start = n.iter().position(|b| *b != 0).unwrap_or(n.len());
+    &n[start..]
+}
+
+/// The key id the key in `blob` is listed for in the authorized keys file, if it is
+pub async fn authorized_key_id(path: &Path, blob: &[u8]) -> std::io::Result<Option<String>> {
+    let authorized_keys = tokio::fs::read_to_string(path).await?;
+    Ok(authorized_keys
+        .lines()
+        .filter_map(parse_line)
+        .find(|(_, key)| key.as_slice() == blob)
+        .map(|(key_id, _)| key_id.to_string()))
+}
+
+/// The key id and key of a line, skipping comments and lines that don't parse
+fn parse_line(line: &str) -> Option<(&str, Vec<u8>)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let mut fields = line.split_whitespace();
+    let (key_id, key_type, key) = (fields.next()?, fields.next()?, fields.next()?);
+    let blob = match base64::decode(key) {
+        Ok(blob) => blob,
+        Err(_) => {
+            log::warn!("skipping authorized ssh key of {}: not base64", key_id);
+            return None;
+        }
+    };
+    // the type is repeated in the key itself
+    if Reader::new(&blob).string().ok() != Some(key_type) {
+        log::warn!(
+            "skipping authorized ssh key of {}: not a {} key",
+            key_id,
+            key_type
+        );
+        return None;
+    }
+    Some((key_id, blob))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIGFwKSA6lJtvHChcCd6GXoJJ+ixKnV+4MUW6acjqy7Wk";
+
+    #[test]
+    fn parses_authorized_keys() {
+        let blob = base64::decode(KEY).unwrap();
+        assert_eq!(
+            parse_line(&format!("key-id ssh-ed25519 {} alice@laptop", KEY)),
+            Some(("key-id", blob.clone()))
+        );
+        assert_eq!(
+            parse_line(&format!("  key-id ssh-ed25519 {}", KEY)),
+            Some(("key-id", blob.clone()))
+        );
+        assert_eq!(parse_line(&format!("# key-id ssh-ed25519 {}", KEY)), None);
+        assert_eq!(parse_line(&format!("key-id ssh-rsa {}", KEY)), None);
+        assert_eq!(parse_line(&format!("ssh-ed25519 {}", KEY)), None);
+        assert_eq!(parse_line(""), None);
+
+        assert_eq!(
+            PublicKey::parse(&blob).unwrap(),
+            PublicKey::Ed25519(blob[19..].to_vec())
+        );
+    }
+
+    #[test]
+    fn verifies_ed25519_signatures() {
+        let host_key = HostKey::derive(&SigKey::generate());
+        let key = PublicKey::parse(&host_key.blob()).unwrap();
+        let signature = host_key.sign(b"message");
+
+        assert!(key.signs_with(ED25519));
+        assert!(!key.signs_with(RSA_SHA256));
+        assert!(key.verify(ED25519, b"message", &signature));
+        assert!(!key.verify(ED25519, b"other message", &signature));
+        assert!(!key.verify(RSA_SHA256, b"message", &signature));
+
+        let other = HostKey::derive(&SigKey::generate());
+        assert!(!key.verify(ED25519, b"message", &other.sign(b"message")));
+    }
+
+    #[test]
+    fn host_keys_are_stable() {
+        let master = SigKey::generate();
+        assert_eq!(
+            HostKey::derive(&master).blob(),
+            HostKey::derive(&master.clone()).blob()
+        );
+        assert_ne!(
+            HostKey::derive(&master).blob(),
+            HostKey::derive(&SigKey::generate()).blob()
+        );
+        assert!(HostKey::derive(&master)
+            .fingerprint()
+            .starts_with("SHA256:"));
+    }
+}
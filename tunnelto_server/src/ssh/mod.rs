@@ -0,0 +1,167 @@
+//! Tunnels for users with nothing but an ssh client: with SSH_PORT set, the server takes
+//! `ssh -R` reverse forwards and serves each on a sub-domain, as a tunnelto client would.
+//!
+//!     ssh -p 2222 -R myapp:80:localhost:3000 tunnel@tunnelto.dev
+//!
+//! serves localhost:3000 on myapp.tunnelto.dev. The bind address names the sub-domain,
+//! forwards without one (`-R 80:localhost:3000`) get a random one, and the port is
+//! ignored since visitors reach every tunnel on the same ones. Users sign in with a key
+//! listed in SSH_AUTHORIZED_KEYS for an account's auth key, see `keys`, and the tunnels
+//! are held to everything that key is. The public urls are written to the session,
+//! so they show up unless the client was started with `-N`.
+//!
+//! The connection is refused like a control connection would be by CONTROL_ALLOW_CIDRS,
+//! CONTROL_DENY_CIDRS and the handshake limit, and each forward is audited as a handshake.
+//! Keys are checked by the auth backend directly, so an auth webhook, which would need
+//! the auth key itself, can't be used along with it.
+use crate::auth::SigKey;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+
+mod keys;
+mod session;
+mod transport;
+mod wire;
+
+use self::keys::HostKey;
+
+#[derive(Debug, Clone)]
+pub struct SshConfig {
+    pub port: u16,
+    /// the keys users sign in with, and the auth key each opens tunnels as
+    pub authorized_keys: PathBuf,
+}
+
+impl SshConfig {
+    /// Read the listener's settings from the env, `None` if it's turned off
+    pub fn from_env() -> Option<Self> {
+        let port = crate::config::env_var("SSH_PORT").ok()?;
+        let port = match port.parse() {
+            Ok(port) => port,
+            Err(_) => {
+                log::error!("invalid SSH_PORT={}, not taking ssh connections", port);
+                return None;
+            }
+        };
+        let authorized_keys = match crate::config::env_var("SSH_AUTHORIZED_KEYS") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                log::error!(
+                    "SSH_PORT is set without SSH_AUTHORIZED_KEYS, not taking ssh connections"
+                );
+                return None;
+            }
+        };
+        Some(SshConfig {
+            port,
+            authorized_keys,
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IOError: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("malformed message")]
+    Malformed,
+
+    #[error("protocol error: {0}")]
+    Protocol(&'static str),
+
+    #[error("no {0} in common with the client")]
+    Unsupported(&'static str),
+
+    #[error("key exchange failed")]
+    KeyExchange,
+
+    #[error("connection closed")]
+    Closed,
+}
+
+/// Message numbers, RFC 4250 section 4.1
+mod msg {
+    pub const DISCONNECT: u8 = 1;
+    pub const IGNORE: u8 = 2;
+    pub const UNIMPLEMENTED: u8 = 3;
+    pub const DEBUG: u8 = 4;
+    pub const SERVICE_REQUEST: u8 = 5;
+    pub const SERVICE_ACCEPT: u8 = 6;
+    pub const EXT_INFO: u8 = 7;
+    pub const KEXINIT: u8 = 20;
+    pub const NEWKEYS: u8 = 21;
+    pub const KEX_ECDH_INIT: u8 = 30;
+    pub const KEX_ECDH_REPLY: u8 = 31;
+    pub const USERAUTH_REQUEST: u8 = 50;
+    pub const USERAUTH_FAILURE: u8 = 51;
+    pub const USERAUTH_SUCCESS: u8 = 52;
+    pub const USERAUTH_PK_OK: u8 = 60;
+    pub const GLOBAL_REQUEST: u8 = 80;
+    pub const REQUEST_SUCCESS: u8 = 81;
+    pub const REQUEST_FAILURE: u8 = 82;
+    pub const CHANNEL_OPEN: u8 = 90;
+    pub const CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+    pub const CHANNEL_OPEN_FAILURE: u8 = 92;
+    pub const CHANNEL_WINDOW_ADJUST: u8 = 93;
+    pub const CHANNEL_DATA: u8 = 94;
+    pub const CHANNEL_EXTENDED_DATA: u8 = 95;
+    pub const CHANNEL_EOF: u8 = 96;
+    pub const CHANNEL_CLOSE: u8 = 97;
+    pub const CHANNEL_REQUEST: u8 = 98;
+    pub const CHANNEL_SUCCESS: u8 = 99;
+    pub const CHANNEL_FAILURE: u8 = 100;
+}
+
+/// Take ssh connections on the configured port
+pub fn spawn(config: &'static SshConfig, master_sig_key: &SigKey) {
+    if crate::CONFIG.auth_webhook_url.is_some() {
+        log::error!(
+            "ssh sign ins can't be checked by the auth webhook, not taking ssh connections"
+        );
+        return;
+    }
+
+    let host_key = Arc::new(HostKey::derive(master_sig_key));
+    tokio::spawn(async move {
+        let listen_addr = std::net::SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], config.port));
+        let listener = match crate::handover::bind(listen_addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("failed to bind ssh listener: {:?}", e);
+                return;
+            }
+        };
+        log::info!(
+            "ssh listening on: {}, host key {}",
+            &listen_addr,
+            host_key.fingerprint()
+        );
+
+        loop {
+            let (socket, peer) = match crate::handover::accept(&listener).await {
+                Some(Ok(accepted)) => accepted,
+                Some(Err(_)) => {
+                    log::error!("failed to accept ssh socket");
+                    continue;
+                }
+                None => return,
+            };
+
+            if !crate::control_acl::allowed_direct(Some(peer.ip())) {
+                log::debug!("refusing ssh connection from {}", peer.ip());
+                continue;
+            }
+            if crate::handshake_limit::blocked(peer.ip()).is_some() {
+                log::debug!("refusing ssh connection from blocked {}", peer.ip());
+                continue;
+            }
+
+            let host_key = host_key.clone();
+            tokio::spawn(async move {
+                session::run(socket, peer, host_key, config).await;
+            });
+        }
+    });
+}
@@ -0,0 +1,900 @@
+//! An ssh connection once the transport is up: the user signs in (RFC 4252), then its
+//! reverse forwards are registered as tunnels, and each visitor stream of theirs is
+//! opened as a `forwarded-tcpip` channel to the client (RFC 4254).
+use super::keys::{self, HostKey, PublicKey};
+use super::transport::{self, Transport};
+use super::wire::{Reader, Writer};
+use super::{msg, Error, SshConfig};
+use crate::auth_db::AuthenticatedAccount;
+use crate::client_auth::{self, ClientHandshake};
+use crate::connected_clients::{ConnectedClient, Connections};
+use crate::handshake_audit::HandshakeRecord;
+use crate::{StreamMessage, ACTIVE_STREAMS, CONFIG};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tunnelto_lib::{
+    ClientHello, ClientId, ClientType, ControlPacket, ServerHello, StreamId, TunnelError,
+};
+
+/// How long the user gets to sign in
+const AUTH_TIMEOUT: Duration = Duration::from_secs(60);
+/// Sign in attempts before we hang up
+const MAX_AUTH_ATTEMPTS: u32 = 10;
+/// The window we give the client on each channel, and adjust once half of it is used
+const WINDOW: u32 = 2 * 1024 * 1024;
+/// The most data we send or take in one packet
+const MAX_DATA: u32 = 32 * 1024;
+/// Visitor data held for a channel whose window is full, before the visitor is dropped
+const MAX_PENDING: usize = 4 * 1024 * 1024;
+/// Packets of the forwards waiting for the session
+const EVENTS: usize = 64;
+/// RFC 4254 section 5.1
+const ADMINISTRATIVELY_PROHIBITED: u32 = 1;
+/// RFC 4253 section 11.1
+const BY_APPLICATION: u32 = 11;
+
+/// What the forwards' tunnels hand the session
+enum Event {
+    Packet(u32, ControlPacket),
+    /// the tunnel was closed, i.e. its key revoked
+    Closed(u32),
+}
+
+/// A reverse forward the client asked for, served as a tunnel
+struct Forward {
+    /// as the client asked for it, which it expects back on the channels opened for it
+    address: String,
+    port: u32,
+    client: ConnectedClient,
+}
+
+enum ChannelKind {
+    /// the user's shell, where we tell them the urls of their tunnels
+    Session { started: bool },
+    /// a visitor stream to one of the forwards
+    Stream {
+        forward: u32,
+        stream_id: StreamId,
+        /// visitor data waiting for the client's window
+        pending: Vec<u8>,
+        /// the visitor is done sending, the client is told once `pending` is sent
+        visitor_done: bool,
+        eof_sent: bool,
+        eof_received: bool,
+        /// the data taken since the window was last adjusted
+        consumed: u32,
+    },
+}
+
+struct Channel {
+    /// the client's number for the channel, once it took it
+    remote: Option<u32>,
+    /// what the client takes before adjusting the window
+    window: u32,
+    max_packet: u32,
+    close_sent: bool,
+    kind: ChannelKind,
+}
+
+impl Channel {
+    /// Send what the client's window allows, then the end of the stream once the
+    /// visitor is done and everything was sent
+    fn flush(&mut self, transport: &mut Transport) {
+        let remote = match self.remote {
+            Some(remote) => remote,
+            None => return,
+        };
+        let (pending, visitor_done, eof_sent, eof_received) = match &mut self.kind {
+            ChannelKind::Stream {
+                pending,
+                visitor_done,
+                eof_sent,
+                eof_received,
+                ..
+            } => (pending, *visitor_done, eof_sent, *eof_received),
+            ChannelKind::Session { .. } => return,
+        };
+
+        while !pending.is_empty() && self.window > 0 {
+            let n = pending
+                .len()
+                .min(self.window as usize)
+                .min(self.max_packet.min(MAX_DATA) as usize);
+            let data = pending.drain(..n).collect::<Vec<u8>>();
+            transport.send(
+                Writer::message(msg::CHANNEL_DATA)
+                    .u32(remote)
+                    .bytes(&data)
+                    .finish(),
+            );
+            self.window -= n as u32;
+        }
+
+        if pending.is_empty() && visitor_done && !*eof_sent {
+            transport.send(Writer::message(msg::CHANNEL_EOF).u32(remote).finish());
+            *eof_sent = true;
+        }
+        if *eof_sent && eof_received && !self.close_sent {
+            transport.send(Writer::message(msg::CHANNEL_CLOSE).u32(remote).finish());
+            self.close_sent = true;
+        }
+    }
+}
+
+struct Session {
+    transport: Transport,
+    peer: SocketAddr,
+    config: &'static SshConfig,
+    events: mpsc::Sender<Event>,
+    /// the key id the user signed in with, once they did
+    key_id: Option<String>,
+    auth_attempts: u32,
+    forwards: HashMap<u32, Forward>,
+    next_forward: u32,
+    channels: HashMap<u32, Channel>,
+    next_channel: u32,
+    streams: HashMap<StreamId, u32>,
+    /// what the user is told once their session channel is up
+    notices: Vec<String>,
+}
+
+/// Serve an ssh connection until the client hangs up
+pub async fn run(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    host_key: Arc<HostKey>,
+    config: &'static SshConfig,
+) {
+    let mut input = vec![];
+    let client_version = match read_version(&mut socket, &mut input).await {
+        Ok(client_version) => client_version,
+        Err(e) => {
+            log::debug!("ssh connection from {} failed: {}", peer, e);
+            return;
+        }
+    };
+    log::debug!(
+        "ssh connection from {}: {}",
+        peer,
+        String::from_utf8_lossy(&client_version)
+    );
+
+    let (events, events_rx) = mpsc::channel(EVENTS);
+    let mut session = Session {
+        transport: Transport::new(host_key, client_version),
+        peer,
+        config,
+        events,
+        key_id: None,
+        auth_attempts: 0,
+        forwards: HashMap::new(),
+        next_forward: 0,
+        channels: HashMap::new(),
+        next_channel: 0,
+        streams: HashMap::new(),
+        notices: vec![],
+    };
+    let result = session.serve(&mut socket, input, events_rx).await;
+    // say goodbye, or why we hang up
+    let _ = socket.write_all(&session.transport.take_output()).await;
+    session.close().await;
+
+    match result {
+        Ok(()) | Err(Error::Closed) => log::debug!("ssh connection from {} closed", peer),
+        Err(e) => log::info!("ssh connection from {} failed: {}", peer, e),
+    }
+}
+
+async fn read_version(socket: &mut TcpStream, input: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+    socket
+        .write_all(format!("{}\r\n", transport::VERSION).as_bytes())
+        .await?;
+    let read = async {
+        loop {
+            if let Some(client_version) = transport::client_version(input)? {
+                return Ok(client_version);
+            }
+            if socket.read_buf(input).await? == 0 {
+                return Err(Error::Closed);
+            }
+        }
+    };
+    tokio::time::timeout(AUTH_TIMEOUT, read)
+        .await
+        .map_err(|_| Error::Protocol("no version line in time"))?
+}
+
+impl Session {
+    async fn serve(
+        &mut self,
+        socket: &mut TcpStream,
+        mut input: Vec<u8>,
+        mut events: mpsc::Receiver<Event>,
+    ) -> Result<(), Error> {
+        let auth_deadline = tokio::time::Instant::now() + AUTH_TIMEOUT;
+        loop {
+            while let Some(payload) = self.transport.read(&mut input)? {
+                self.handle(payload).await?;
+            }
+            socket.write_all(&self.transport.take_output()).await?;
+
+            tokio::select! {
+                read = socket.read_buf(&mut input) => {
+                    if read? == 0 {
+                        return Err(Error::Closed);
+                    }
+                }
+                Some(event) = events.recv() => self.handle_event(event).await,
+                _ = tokio::time::sleep_until(auth_deadline), if self.key_id.is_none() => {
+                    self.transport.disconnect(BY_APPLICATION, "sign in timed out");
+                    return Err(Error::Protocol("sign in timed out"));
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, payload: Vec<u8>) {
+        self.transport.send(payload);
+    }
+
+    async fn handle(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        let mut reader = Reader::new(&payload);
+        match reader.u8()? {
+            msg::SERVICE_REQUEST => match reader.string()? {
+                "ssh-userauth" if self.key_id.is_none() => self.send(
+                    Writer::message(msg::SERVICE_ACCEPT)
+                        .string("ssh-userauth")
+                        .finish(),
+                ),
+                _ => return Err(Error::Protocol("unknown service")),
+            },
+            // further requests are ignored once signed in, RFC 4252 section 5.1
+            msg::USERAUTH_REQUEST if self.key_id.is_some() => {}
+            msg::USERAUTH_REQUEST => self.sign_in(reader).await?,
+            _ if self.key_id.is_none() => return Err(Error::Protocol("not signed in")),
+            msg::GLOBAL_REQUEST => self.global_request(reader).await?,
+            msg::CHANNEL_OPEN => self.channel_open(reader)?,
+            msg::CHANNEL_OPEN_CONFIRMATION => {
+                let id = reader.u32()?;
+                let channel = find_channel(&mut self.channels, id)?;
+                channel.remote = Some(reader.u32()?);
+                channel.window = reader.u32()?;
+                channel.max_packet = reader.u32()?;
+                channel.flush(&mut self.transport);
+            }
+            msg::CHANNEL_OPEN_FAILURE => {
+                let id = reader.u32()?;
+                log::debug!("ssh client refused stream: {}", reader.u32()?);
+                find_channel(&mut self.channels, id)?;
+                self.deliver(id, StreamMessage::TunnelRefused).await;
+                self.remove_channel(id);
+            }
+            msg::CHANNEL_WINDOW_ADJUST => {
+                let id = reader.u32()?;
+                let channel = find_channel(&mut self.channels, id)?;
+                channel.window = channel.window.saturating_add(reader.u32()?);
+                channel.flush(&mut self.transport);
+            }
+            msg::CHANNEL_DATA => {
+                let id = reader.u32()?;
+                let data = reader.bytes()?;
+                self.channel_data(id, data).await?;
+            }
+            // stderr of ours, the client never has any
+            msg::CHANNEL_EXTENDED_DATA => {}
+            msg::CHANNEL_EOF => {
+                let id = reader.u32()?;
+                let channel = find_channel(&mut self.channels, id)?;
+                match &mut channel.kind {
+                    ChannelKind::Stream { eof_received, .. } => {
+                        *eof_received = true;
+                        channel.flush(&mut self.transport);
+                        self.deliver(id, StreamMessage::End).await;
+                    }
+                    ChannelKind::Session { .. } => self.close_channel(id),
+                }
+            }
+            msg::CHANNEL_CLOSE => {
+                let id = reader.u32()?;
+                let channel = find_channel(&mut self.channels, id)?;
+                let visitor_open = matches!(
+                    channel.kind,
+                    ChannelKind::Stream {
+                        eof_received: false,
+                        ..
+                    }
+                );
+                self.close_channel(id);
+                if visitor_open {
+                    self.deliver(id, StreamMessage::End).await;
+                }
+                self.remove_channel(id);
+            }
+            msg::CHANNEL_REQUEST => self.channel_request(reader)?,
+            msg::CHANNEL_SUCCESS | msg::CHANNEL_FAILURE => {}
+            msg::REQUEST_SUCCESS | msg::REQUEST_FAILURE => {}
+            _ => self.transport.unimplemented(),
+        }
+        Ok(())
+    }
+
+    async fn sign_in(&mut self, mut reader: Reader<'_>) -> Result<(), Error> {
+        let user = reader.string()?;
+        let service = reader.string()?;
+        let method = reader.string()?;
+        if service != "ssh-connection" {
+            return Err(Error::Protocol("unknown service"));
+        }
+
+        self.auth_attempts += 1;
+        if self.auth_attempts > MAX_AUTH_ATTEMPTS {
+            self.transport
+                .disconnect(BY_APPLICATION, "too many sign in attempts");
+            return Err(Error::Protocol("too many sign in attempts"));
+        }
+        if method != "publickey" {
+            self.refuse_sign_in();
+            return Ok(());
+        }
+
+        let signed = reader.bool()?;
+        let algorithm = reader.string()?;
+        let blob = reader.bytes()?;
+        let key = match PublicKey::parse(blob) {
+            Ok(key) if key.signs_with(algorithm) => key,
+            _ => {
+                self.refuse_sign_in();
+                return Ok(());
+            }
+        };
+        let key_id = match keys::authorized_key_id(&self.config.authorized_keys, blob).await {
+            Ok(Some(key_id)) => key_id,
+            Ok(None) => {
+                self.refuse_sign_in();
+                return Ok(());
+            }
+            Err(e) => {
+                log::error!("failed to read SSH_AUTHORIZED_KEYS: {:?}", e);
+                self.refuse_sign_in();
+                return Ok(());
+            }
+        };
+
+        // the client asks if the key would do before signing with it
+        if !signed {
+            self.send(
+                Writer::message(msg::USERAUTH_PK_OK)
+                    .string(algorithm)
+                    .bytes(blob)
+                    .finish(),
+            );
+            return Ok(());
+        }
+
+        let signature = reader.bytes()?;
+        let message = Writer::default()
+            .bytes(self.transport.session_id().unwrap_or_default())
+            .u8(msg::USERAUTH_REQUEST)
+            .string(user)
+            .string(service)
+            .string("publickey")
+            .bool(true)
+            .string(algorithm)
+            .bytes(blob)
+            .finish();
+        // held to the auth key's lockout like a client signing hellos with it, and it
+        // has to open tunnels still
+        let source_ip = Some(self.peer.ip());
+        let checked = match crate::key_lockout::check(&key_id, source_ip) {
+            Err(e) => Err(e),
+            Ok(()) if !key.verify(algorithm, &message, signature) => Err(TunnelError::AuthFailed(
+                "the ssh key's signature does not match".into(),
+            )),
+            Ok(()) => crate::AUTH_DB_SERVICE
+                .get_key(&key_id)
+                .await
+                .map(|_| ())
+                .map_err(TunnelError::from),
+        };
+        crate::key_lockout::record(&key_id, &checked, source_ip);
+        if let Err(e) = checked {
+            self.sign_in_failed(&e.to_string());
+            return Ok(());
+        }
+
+        log::info!("ssh sign in from {} with key {}", self.peer, &key_id);
+        crate::handshake_limit::succeeded(self.peer.ip());
+        self.key_id = Some(key_id);
+        self.send(vec![msg::USERAUTH_SUCCESS]);
+        Ok(())
+    }
+
+    fn refuse_sign_in(&mut self) {
+        self.send(
+            Writer::message(msg::USERAUTH_FAILURE)
+                .name_list(&["publickey"])
+                .bool(false)
+                .finish(),
+        );
+    }
+
+    /// Refuse an attempt that counts against the peer, unlike asking about a key
+    fn sign_in_failed(&mut self, reason: &str) {
+        log::info!("ssh sign in from {} failed: {}", self.peer, reason);
+        crate::siem::auth_failed(reason, Some(self.peer));
+        crate::handshake_limit::failed(self.peer.ip());
+        self.refuse_sign_in();
+    }
+
+    async fn global_request(&mut self, mut reader: Reader<'_>) -> Result<(), Error> {
+        let name = reader.string()?;
+        let want_reply = reader.bool()?;
+        let reply = match name {
+            "tcpip-forward" => {
+                let address = reader.string()?;
+                let port = reader.u32()?;
+                match self.forward(address, port).await {
+                    // the port is what the client has to know when it asked for any
+                    Some(_) if port == 0 => Writer::message(msg::REQUEST_SUCCESS)
+                        .u32(CONFIG.remote_port as u32)
+                        .finish(),
+                    Some(_) => vec![msg::REQUEST_SUCCESS],
+                    None => vec![msg::REQUEST_FAILURE],
+                }
+            }
+            "cancel-tcpip-forward" => {
+                let address = reader.string()?;
+                let port = reader.u32()?;
+                let forward = self
+                    .forwards
+                    .iter()
+                    .find(|(_, f)| f.address == address && (f.port == port || port == 0))
+                    .map(|(id, _)| *id);
+                match forward.and_then(|id| self.forwards.remove(&id)) {
+                    Some(forward) => {
+                        Connections::remove(&forward.client);
+                        vec![msg::REQUEST_SUCCESS]
+                    }
+                    None => vec![msg::REQUEST_FAILURE],
+                }
+            }
+            _ => vec![msg::REQUEST_FAILURE],
+        };
+        if want_reply {
+            self.send(reply);
+        }
+        Ok(())
+    }
+
+    /// Serve a reverse forward as a tunnel, telling the user what became of it
+    async fn forward(&mut self, address: &str, port: u32) -> Option<()> {
+        let key_id = self.key_id.clone()?;
+        let requested_sub_domain = sub_domain(address);
+
+        let mut attempt = HandshakeRecord::new(Some(self.peer));
+        attempt.requested_sub_domain = requested_sub_domain.clone();
+        attempt.client_id = Some(forward_client_id(&key_id, address).to_string());
+        let handshake = match admit(&key_id, address, requested_sub_domain).await {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                log::info!(
+                    "refusing ssh forward of {} for {}: {:?}",
+                    self.peer,
+                    address,
+                    e
+                );
+                crate::handshake_audit::rejected(attempt, &e);
+                self.notify(format!("can't forward {}: {}", address, e));
+                return None;
+            }
+        };
+        crate::handshake_audit::accepted(attempt, &handshake);
+
+        let urls = CONFIG.public_urls(
+            &handshake.sub_domain,
+            handshake.base_domain.as_ref(),
+            handshake.tunnel_type,
+        );
+        let awaiting_approval = handshake.approval.is_some();
+        let (client, mut rx) = crate::control_server::register(handshake, Some(self.peer));
+
+        let id = self.next_forward;
+        self.next_forward += 1;
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = rx.next().await {
+                if events.send(Event::Packet(id, packet)).await.is_err() {
+                    return;
+                }
+            }
+            let _ = events.send(Event::Closed(id)).await;
+        });
+
+        log::info!("ssh forward of {} served on {}", self.peer, &client.host);
+        self.forwards.insert(
+            id,
+            Forward {
+                address: address.to_string(),
+                port,
+                client,
+            },
+        );
+        for url in urls {
+            self.notify(format!("forwarding {}", url));
+        }
+        if awaiting_approval {
+            self.notify("waiting for a teammate to approve the tunnel".to_string());
+        }
+        Some(())
+    }
+
+    fn channel_open(&mut self, mut reader: Reader) -> Result<(), Error> {
+        let kind = reader.string()?;
+        let remote = reader.u32()?;
+        let window = reader.u32()?;
+        let max_packet = reader.u32()?;
+
+        let has_session = self
+            .channels
+            .values()
+            .any(|channel| matches!(channel.kind, ChannelKind::Session { .. }));
+        if kind != "session" || has_session {
+            self.send(
+                Writer::message(msg::CHANNEL_OPEN_FAILURE)
+                    .u32(remote)
+                    .u32(ADMINISTRATIVELY_PROHIBITED)
+                    .string("only remote forwards are served")
+                    .string("")
+                    .finish(),
+            );
+            return Ok(());
+        }
+
+        let id = self.next_channel();
+        self.channels.insert(
+            id,
+            Channel {
+                remote: Some(remote),
+                window,
+                max_packet,
+                close_sent: false,
+                kind: ChannelKind::Session { started: false },
+            },
+        );
+        self.send(
+            Writer::message(msg::CHANNEL_OPEN_CONFIRMATION)
+                .u32(remote)
+                .u32(id)
+                .u32(WINDOW)
+                .u32(MAX_DATA)
+                .finish(),
+        );
+        Ok(())
+    }
+
+    fn channel_request(&mut self, mut reader: Reader) -> Result<(), Error> {
+        let id = reader.u32()?;
+        let request = reader.string()?;
+        let want_reply = reader.bool()?;
+
+        let channel = find_channel(&mut self.channels, id)?;
+        let remote = channel.remote.unwrap_or_default();
+        let starts = match (&mut channel.kind, request) {
+            (ChannelKind::Session { started }, "shell")
+            | (ChannelKind::Session { started }, "exec") => {
+                *started = true;
+                Some(true)
+            }
+            (ChannelKind::Session { .. }, "pty-req")
+            | (ChannelKind::Session { .. }, "env")
+            | (ChannelKind::Session { .. }, "window-change") => Some(false),
+            _ => None,
+        };
+        if want_reply {
+            let reply = match starts {
+                Some(_) => msg::CHANNEL_SUCCESS,
+                None => msg::CHANNEL_FAILURE,
+            };
+            self.send(Writer::message(reply).u32(remote).finish());
+        }
+        if starts == Some(true) {
+            self.notify("press ctrl-c to close your tunnels".to_string());
+        }
+        Ok(())
+    }
+
+    async fn channel_data(&mut self, id: u32, data: &[u8]) -> Result<(), Error> {
+        let channel = find_channel(&mut self.channels, id)?;
+        let remote = channel.remote.unwrap_or_default();
+        match &mut channel.kind {
+            // ctrl-c or ctrl-d
+            ChannelKind::Session { .. } if data.contains(&3) || data.contains(&4) => {
+                self.transport.disconnect(BY_APPLICATION, "bye");
+                Err(Error::Closed)
+            }
+            ChannelKind::Session { .. } => Ok(()),
+            ChannelKind::Stream { consumed, .. } => {
+                *consumed += data.len() as u32;
+                if *consumed >= WINDOW / 2 {
+                    let adjust = Writer::message(msg::CHANNEL_WINDOW_ADJUST)
+                        .u32(remote)
+                        .u32(*consumed)
+                        .finish();
+                    *consumed = 0;
+                    self.send(adjust);
+                }
+                self.deliver(id, StreamMessage::Data(data.to_vec())).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Hand a visitor stream what the client sent on its channel
+    async fn deliver(&mut self, id: u32, message: StreamMessage) {
+        let (forward, stream_id) = match self.channels.get(&id).map(|c| &c.kind) {
+            Some(ChannelKind::Stream {
+                forward, stream_id, ..
+            }) => (*forward, stream_id.clone()),
+            _ => return,
+        };
+        if let Some(forward) = self.forwards.get(&forward) {
+            let client = forward.client.clone();
+            crate::control_server::deliver(&client, &stream_id, message).await;
+        }
+    }
+
+    fn close_channel(&mut self, id: u32) {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            if !channel.close_sent {
+                channel.close_sent = true;
+                let remote = channel.remote.unwrap_or_default();
+                self.transport
+                    .send(Writer::message(msg::CHANNEL_CLOSE).u32(remote).finish());
+            }
+        }
+    }
+
+    fn remove_channel(&mut self, id: u32) {
+        if let Some(channel) = self.channels.remove(&id) {
+            if let ChannelKind::Stream { stream_id, .. } = channel.kind {
+                self.streams.remove(&stream_id);
+            }
+        }
+    }
+
+    fn next_channel(&mut self) -> u32 {
+        let id = self.next_channel;
+        self.next_channel = self.next_channel.wrapping_add(1);
+        id
+    }
+
+    /// Tell the user something on their session channel, once it's up
+    fn notify(&mut self, notice: String) {
+        self.notices.push(notice);
+        let session = self
+            .channels
+            .values()
+            .find_map(|channel| match channel.kind {
+                ChannelKind::Session { started: true } if !channel.close_sent => channel.remote,
+                _ => None,
+            });
+        if let Some(remote) = session {
+            for notice in std::mem::take(&mut self.notices) {
+                self.transport.send(
+                    Writer::message(msg::CHANNEL_DATA)
+                        .u32(remote)
+                        .string(&format!("{}\r\n", notice))
+                        .finish(),
+                );
+            }
+        }
+    }
+
+    async fn handle_event(&mut self, event: Event) {
+        let (forward, packet) = match event {
+            Event::Packet(forward, packet) => (forward, packet),
+            Event::Closed(forward) => {
+                if let Some(forward) = self.forwards.remove(&forward) {
+                    log::info!(
+                        "ssh forward of {} closed: {}",
+                        self.peer,
+                        &forward.client.host
+                    );
+                    self.notify(format!("tunnel for {} closed", forward.address));
+                }
+                return;
+            }
+        };
+
+        match packet {
+            ControlPacket::Init(stream_id) => self.open_stream(forward, stream_id),
+            ControlPacket::Data(stream_id, data) => {
+                let id = match self.streams.get(&stream_id) {
+                    Some(id) => *id,
+                    None => return,
+                };
+                let overflowed = match self.channels.get_mut(&id) {
+                    Some(Channel {
+                        kind: ChannelKind::Stream { pending, .. },
+                        ..
+                    }) => {
+                        pending.extend_from_slice(&data);
+                        pending.len() > MAX_PENDING
+                    }
+                    _ => return,
+                };
+                if overflowed {
+                    log::warn!("ssh client not reading, dropping visitor stream");
+                    let stream = ACTIVE_STREAMS.get(&stream_id).map(|s| s.value().clone());
+                    if let Some(mut stream) = stream {
+                        crate::remote::drop_overloaded_stream(&mut stream);
+                    }
+                    self.close_channel(id);
+                    return;
+                }
+                if let Some(channel) = self.channels.get_mut(&id) {
+                    channel.flush(&mut self.transport);
+                }
+            }
+            ControlPacket::End(stream_id) => {
+                let id = match self.streams.get(&stream_id) {
+                    Some(id) => *id,
+                    None => return,
+                };
+                if let Some(channel) = self.channels.get_mut(&id) {
+                    if let ChannelKind::Stream { visitor_done, .. } = &mut channel.kind {
+                        *visitor_done = true;
+                    }
+                    channel.flush(&mut self.transport);
+                }
+            }
+            // pings keep websockets alive, ssh clients have their own keepalives
+            _ => {}
+        }
+    }
+
+    /// Open a channel to the client for a new visitor stream
+    fn open_stream(&mut self, forward: u32, stream_id: StreamId) {
+        let (address, port) = match self.forwards.get(&forward) {
+            Some(forward) => (forward.address.clone(), forward.port),
+            None => return,
+        };
+        let visitor = ACTIVE_STREAMS
+            .get(&stream_id)
+            .and_then(|stream| stream.peer_addr);
+        let (visitor_ip, visitor_port) = match visitor {
+            Some(visitor) => (visitor.ip().to_string(), visitor.port() as u32),
+            None => ("0.0.0.0".to_string(), 0),
+        };
+
+        let id = self.next_channel();
+        self.streams.insert(stream_id.clone(), id);
+        self.channels.insert(
+            id,
+            Channel {
+                remote: None,
+                window: 0,
+                max_packet: 0,
+                close_sent: false,
+                kind: ChannelKind::Stream {
+                    forward,
+                    stream_id,
+                    pending: vec![],
+                    visitor_done: false,
+                    eof_sent: false,
+                    eof_received: false,
+                    consumed: 0,
+                },
+            },
+        );
+        self.send(
+            Writer::message(msg::CHANNEL_OPEN)
+                .string("forwarded-tcpip")
+                .u32(id)
+                .u32(WINDOW)
+                .u32(MAX_DATA)
+                .string(&address)
+                .u32(port)
+                .string(&visitor_ip)
+                .u32(visitor_port)
+                .finish(),
+        );
+    }
+
+    /// Close the tunnels and the visitor streams left on them
+    async fn close(&mut self) {
+        let open = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| {
+                matches!(
+                    channel.kind,
+                    ChannelKind::Stream {
+                        eof_received: false,
+                        ..
+                    }
+                )
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<u32>>();
+        for id in open {
+            self.deliver(id, StreamMessage::End).await;
+        }
+        for (_, forward) in self.forwards.drain() {
+            Connections::remove(&forward.client);
+        }
+    }
+}
+
+fn find_channel(channels: &mut HashMap<u32, Channel>, id: u32) -> Result<&mut Channel, Error> {
+    channels
+        .get_mut(&id)
+        .ok_or(Error::Protocol("unknown channel"))
+}
+
+/// The sub-domain a forward's bind address asks for, none for the addresses of
+/// forwards that don't name one
+fn sub_domain(address: &str) -> Option<String> {
+    match address {
+        "" | "*" | "localhost" | "0.0.0.0" | "::" | "127.0.0.1" | "::1" => None,
+        address => Some(address.to_string()),
+    }
+}
+
+/// Forwards of a key to the same address are one tunnel, so a user reconnecting takes
+/// theirs over again
+fn forward_client_id(key_id: &str, address: &str) -> ClientId {
+    ClientId::for_subject(&format!("ssh/{}/{}", key_id, address))
+}
+
+/// Admit a forward as a tunnel of the auth key the user signed in for, as its client
+/// asking for `sub_domain` would be
+async fn admit(
+    key_id: &str,
+    address: &str,
+    sub_domain: Option<String>,
+) -> Result<ClientHandshake, TunnelError> {
+    let key = crate::AUTH_DB_SERVICE.get_key(key_id).await?;
+    let account = AuthenticatedAccount {
+        account_id: key.account_id,
+        entitlements: key.entitlements,
+        externally_authorized: false,
+    };
+    let requested_sub_domain = sub_domain
+        .clone()
+        .unwrap_or_else(ServerHello::random_domain);
+    let client_hello = ClientHello::generate(
+        sub_domain,
+        ClientType::SignedAuth {
+            key_id: key_id.to_string(),
+        },
+    );
+    client_auth::admit(
+        client_hello,
+        forward_client_id(key_id, address),
+        account,
+        requested_sub_domain,
+        None,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_addresses_name_sub_domains() {
+        assert_eq!(sub_domain("myapp"), Some("myapp".to_string()));
+        assert_eq!(sub_domain("localhost"), None);
+        assert_eq!(sub_domain(""), None);
+        assert_eq!(sub_domain("*"), None);
+        assert_eq!(sub_domain("0.0.0.0"), None);
+    }
+}
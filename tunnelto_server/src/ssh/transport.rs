@@ -0,0 +1,453 @@
+//! The ssh transport layer, RFC 4253: binary packets, key exchange and encryption. We
+//! offer one of each, all current OpenSSH clients take them: curve25519-sha256 key
+//! exchange (RFC 8731), an ssh-ed25519 host key and the chacha20-poly1305@openssh.com
+//! cipher, which authenticates packets itself so the MAC negotiated is never used.
+//! Strict key exchange (OpenSSH's fix for CVE-2023-48795) and the `server-sig-algs`
+//! extension (RFC 8308) are taken when the client offers them.
+//!
+//! No I/O happens here: packets are read off the bytes the session received, and what's
+//! sent is buffered for the session to write.
+use super::keys::{self, HostKey};
+use super::wire::{Reader, Writer};
+use super::{msg, Error};
+use rand::RngCore;
+use ring::aead::chacha20_poly1305_openssh::{OpeningKey, SealingKey, KEY_LEN, TAG_LEN};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+/// What we tell clients we are
+pub const VERSION: &str = concat!("SSH-2.0-tunnelto_", env!("CARGO_PKG_VERSION"));
+
+const KEX: &[&str] = &["curve25519-sha256", "curve25519-sha256@libssh.org"];
+const CIPHER: &str = "chacha20-poly1305@openssh.com";
+const MAC: &str = "hmac-sha2-256";
+const COMPRESSION: &str = "none";
+/// Pseudo-algorithms the client lists along with its key exchanges
+const EXT_INFO_C: &str = "ext-info-c";
+const STRICT_C: &str = "kex-strict-c-v00@openssh.com";
+const STRICT_S: &str = "kex-strict-s-v00@openssh.com";
+
+/// The largest packet taken, RFC 4253 requires at least 35000
+const MAX_PACKET: usize = 256 * 1024;
+/// The longest version line, RFC 4253 section 4.2
+const MAX_VERSION_LINE: usize = 255;
+/// The cipher block size packets are padded to
+const BLOCK: usize = 8;
+
+/// Take the client's version line off `input`, once all of it was received
+pub fn client_version(input: &mut Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+    let end = match input.iter().position(|b| *b == b'\n') {
+        Some(end) => end,
+        None if input.len() > MAX_VERSION_LINE => return Err(Error::Protocol("no version line")),
+        None => return Ok(None),
+    };
+    let mut line = input.drain(..=end).collect::<Vec<u8>>();
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    if !line.starts_with(b"SSH-2.0-") && !line.starts_with(b"SSH-1.99-") {
+        return Err(Error::Protocol("unsupported protocol version"));
+    }
+    Ok(Some(line))
+}
+
+/// A key exchange under way
+struct Kex {
+    /// our KEXINIT
+    ours: Vec<u8>,
+    /// the client's KEXINIT, once received
+    theirs: Option<Vec<u8>>,
+    /// the client guessed the algorithms wrong and sent its first key exchange packet
+    /// for them, which is to be ignored
+    ignore_guess: bool,
+    /// the key the client encrypts with once it sends NEWKEYS
+    opening: Option<OpeningKey>,
+}
+
+pub struct Transport {
+    host_key: Arc<HostKey>,
+    client_version: Vec<u8>,
+    /// the hash of the first key exchange
+    session_id: Option<Vec<u8>>,
+    kex: Option<Kex>,
+    /// sequence numbers of the next packet each way
+    seq_in: u32,
+    seq_out: u32,
+    /// of the packet read last, for UNIMPLEMENTED replies
+    last_seq: u32,
+    opening: Option<OpeningKey>,
+    sealing: Option<SealingKey>,
+    /// the client does strict key exchange
+    strict: bool,
+    /// the client takes EXT_INFO
+    ext_info: bool,
+    /// messages of the session sent during a key exchange, sent once it's done
+    held: Vec<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Transport {
+    /// Start the first key exchange with a client that sent `client_version`
+    pub fn new(host_key: Arc<HostKey>, client_version: Vec<u8>) -> Self {
+        let mut transport = Transport {
+            host_key,
+            client_version,
+            session_id: None,
+            kex: None,
+            seq_in: 0,
+            seq_out: 0,
+            last_seq: 0,
+            opening: None,
+            sealing: None,
+            strict: false,
+            ext_info: false,
+            held: vec![],
+            output: vec![],
+        };
+        transport.start_kex();
+        transport
+    }
+
+    /// Identifies the session, what clients sign to authenticate
+    pub fn session_id(&self) -> Option<&[u8]> {
+        self.session_id.as_deref()
+    }
+
+    /// Send a message, or hold it back until the key exchange under way is done
+    pub fn send(&mut self, payload: Vec<u8>) {
+        if self.kex.is_some() && payload[0] >= msg::SERVICE_REQUEST {
+            self.held.push(payload);
+            return;
+        }
+        self.seal(&payload);
+    }
+
+    /// Tell the client we don't know the message it sent last
+    pub fn unimplemented(&mut self) {
+        self.seal(
+            &Writer::message(msg::UNIMPLEMENTED)
+                .u32(self.last_seq)
+                .finish(),
+        );
+    }
+
+    /// Tell the client why we hang up
+    pub fn disconnect(&mut self, reason: u32, description: &str) {
+        let payload = Writer::message(msg::DISCONNECT)
+            .u32(reason)
+            .string(description)
+            .string("")
+            .finish();
+        self.seal(&payload);
+    }
+
+    /// What's to be written to the client
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// The next message for the session out of the bytes received, handling those of
+    /// the transport on the way. None until a whole one was received.
+    pub fn read(&mut self, input: &mut Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        while let Some(payload) = self.read_packet(input)? {
+            if let Some(payload) = self.handle(payload)? {
+                return Ok(Some(payload));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_packet(&mut self, input: &mut Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        if input.len() < 4 {
+            return Ok(None);
+        }
+        let length = [input[0], input[1], input[2], input[3]];
+        let length = match self.opening.as_ref() {
+            Some(key) => key.decrypt_packet_length(self.seq_in, length),
+            None => length,
+        };
+        let length = u32::from_be_bytes(length) as usize;
+        if !(BLOCK..=MAX_PACKET).contains(&length) {
+            return Err(Error::Malformed);
+        }
+        let tag_len = if self.opening.is_some() { TAG_LEN } else { 0 };
+        if input.len() < 4 + length + tag_len {
+            return Ok(None);
+        }
+
+        let mut packet = input.drain(..4 + length + tag_len).collect::<Vec<u8>>();
+        let plaintext = match self.opening.as_ref() {
+            Some(key) => {
+                let (packet, tag) = packet.split_at_mut(4 + length);
+                let tag: &[u8; TAG_LEN] = (&*tag).try_into().map_err(|_| Error::Malformed)?;
+                key.open_in_place(self.seq_in, packet, tag)
+                    .map_err(|_| Error::Protocol("packet failed to authenticate"))?
+            }
+            None => &packet[4..],
+        };
+        let padding = plaintext[0] as usize;
+        if padding < 4 || padding + 1 >= plaintext.len() {
+            return Err(Error::Malformed);
+        }
+        let payload = plaintext[1..plaintext.len() - padding].to_vec();
+
+        self.last_seq = self.seq_in;
+        self.seq_in = self.seq_in.wrapping_add(1);
+        Ok(Some(payload))
+    }
+
+    fn seal(&mut self, payload: &[u8]) {
+        // the packet length is left out when it's encrypted on its own
+        let aligned = 1 + payload.len() + if self.sealing.is_some() { 0 } else { 4 };
+        let mut padding = BLOCK - aligned % BLOCK;
+        if padding < 4 {
+            padding += BLOCK;
+        }
+
+        let mut packet = Vec::with_capacity(4 + 1 + payload.len() + padding + TAG_LEN);
+        packet.extend_from_slice(&((1 + payload.len() + padding) as u32).to_be_bytes());
+        packet.push(padding as u8);
+        packet.extend_from_slice(payload);
+        let mut random = [0; 2 * BLOCK];
+        rand::thread_rng().fill_bytes(&mut random[..padding]);
+        packet.extend_from_slice(&random[..padding]);
+
+        if let Some(key) = self.sealing.as_ref() {
+            let mut tag = [0; TAG_LEN];
+            key.seal_in_place(self.seq_out, &mut packet, &mut tag);
+            packet.extend_from_slice(&tag);
+        }
+        self.output.extend_from_slice(&packet);
+        self.seq_out = self.seq_out.wrapping_add(1);
+    }
+
+    /// Handle a message of the transport, or pass it on to the session
+    fn handle(&mut self, payload: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        let mut reader = Reader::new(&payload);
+        let message = reader.u8()?;
+
+        // nothing else is taken during a strict first key exchange, so no one can slip
+        // messages in ahead of it
+        let first_kex = self.opening.is_none();
+        let kex_message = (msg::KEXINIT..msg::USERAUTH_REQUEST).contains(&message);
+        if first_kex && self.strict && !kex_message && message != msg::DISCONNECT {
+            return Err(Error::Protocol(
+                "unexpected message during strict key exchange",
+            ));
+        }
+
+        match message {
+            msg::DISCONNECT => Err(Error::Closed),
+            msg::IGNORE | msg::UNIMPLEMENTED | msg::DEBUG => Ok(None),
+            msg::KEXINIT => {
+                self.kexinit(&payload)?;
+                Ok(None)
+            }
+            msg::KEX_ECDH_INIT => {
+                self.kex_ecdh_init(reader)?;
+                Ok(None)
+            }
+            msg::NEWKEYS => {
+                self.newkeys()?;
+                Ok(None)
+            }
+            _ if first_kex => Err(Error::Protocol("message before key exchange")),
+            message if message < msg::SERVICE_REQUEST => {
+                self.unimplemented();
+                Ok(None)
+            }
+            _ => Ok(Some(payload)),
+        }
+    }
+
+    fn start_kex(&mut self) {
+        let mut cookie = [0; 16];
+        rand::thread_rng().fill_bytes(&mut cookie);
+        let mut kex_algorithms = KEX.to_vec();
+        if self.session_id.is_none() {
+            kex_algorithms.push(STRICT_S);
+        }
+
+        let ours = Writer::message(msg::KEXINIT)
+            .raw(&cookie)
+            .name_list(&kex_algorithms)
+            .name_list(&[HostKey::ALGORITHM])
+            .name_list(&[CIPHER])
+            .name_list(&[CIPHER])
+            .name_list(&[MAC])
+            .name_list(&[MAC])
+            .name_list(&[COMPRESSION])
+            .name_list(&[COMPRESSION])
+            .name_list(&[])
+            .name_list(&[])
+            .bool(false)
+            .u32(0)
+            .finish();
+        self.seal(&ours);
+        self.kex = Some(Kex {
+            ours,
+            theirs: None,
+            ignore_guess: false,
+            opening: None,
+        });
+    }
+
+    fn kexinit(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let first_kex = self.session_id.is_none();
+        // the client starts a key exchange again
+        if self.kex.is_none() {
+            self.start_kex();
+        }
+
+        // after the message type and the cookie
+        let mut reader = Reader::new(payload.get(17..).ok_or(Error::Malformed)?);
+        let kex_algorithms = reader.name_list()?;
+        let host_key_algorithms = reader.name_list()?;
+        let ciphers = (reader.name_list()?, reader.name_list()?);
+        let _macs = (reader.name_list()?, reader.name_list()?);
+        let compression = (reader.name_list()?, reader.name_list()?);
+        let _languages = (reader.name_list()?, reader.name_list()?);
+        let guessed = reader.bool()?;
+
+        let kex_algorithm = kex_algorithms
+            .iter()
+            .find(|algorithm| KEX.contains(algorithm))
+            .ok_or(Error::Unsupported("key exchange"))?;
+        if !host_key_algorithms.contains(&HostKey::ALGORITHM) {
+            return Err(Error::Unsupported("host key"));
+        }
+        if !ciphers.0.contains(&CIPHER) || !ciphers.1.contains(&CIPHER) {
+            return Err(Error::Unsupported("cipher"));
+        }
+        if !compression.0.contains(&COMPRESSION) || !compression.1.contains(&COMPRESSION) {
+            return Err(Error::Unsupported("compression"));
+        }
+
+        if first_kex {
+            self.strict = kex_algorithms.contains(&STRICT_C);
+            self.ext_info = kex_algorithms.contains(&EXT_INFO_C);
+            if self.strict && self.last_seq != 0 {
+                return Err(Error::Protocol(
+                    "strict key exchange must start the session",
+                ));
+            }
+        }
+
+        let kex = self.kex.as_mut().expect("a key exchange was started");
+        if kex.theirs.is_some() {
+            return Err(Error::Protocol("KEXINIT sent twice"));
+        }
+        kex.theirs = Some(payload.to_vec());
+        kex.ignore_guess = guessed
+            && (kex_algorithms.first() != Some(kex_algorithm)
+                || host_key_algorithms.first() != Some(&HostKey::ALGORITHM));
+        Ok(())
+    }
+
+    fn kex_ecdh_init(&mut self, mut reader: Reader) -> Result<(), Error> {
+        let first_kex = self.session_id.is_none();
+        let kex = match self.kex.as_mut() {
+            Some(kex) if kex.theirs.is_some() && kex.opening.is_none() => kex,
+            _ => return Err(Error::Protocol("KEX_ECDH_INIT out of order")),
+        };
+        if kex.ignore_guess {
+            kex.ignore_guess = false;
+            return Ok(());
+        }
+        let client_public = reader.bytes()?;
+
+        let rng = ring::rand::SystemRandom::new();
+        let private =
+            EphemeralPrivateKey::generate(&X25519, &rng).map_err(|_| Error::KeyExchange)?;
+        let public = private
+            .compute_public_key()
+            .map_err(|_| Error::KeyExchange)?;
+        let shared_secret = agreement::agree_ephemeral(
+            private,
+            &UnparsedPublicKey::new(&X25519, client_public),
+            Error::KeyExchange,
+            |shared_secret| Ok(Writer::default().mpint(shared_secret).finish()),
+        )?;
+
+        let host_key = self.host_key.blob();
+        let exchange_hash = Sha256::digest(
+            &Writer::default()
+                .bytes(&self.client_version)
+                .string(VERSION)
+                .bytes(kex.theirs.as_deref().unwrap_or_default())
+                .bytes(&kex.ours)
+                .bytes(&host_key)
+                .bytes(client_public)
+                .bytes(public.as_ref())
+                .raw(&shared_secret)
+                .finish(),
+        )
+        .to_vec();
+        let session_id = self.session_id.get_or_insert_with(|| exchange_hash.clone());
+
+        let derive = |letter: u8| {
+            let mut key = Sha256::new()
+                .chain(&shared_secret)
+                .chain(&exchange_hash)
+                .chain([letter])
+                .chain(&*session_id)
+                .finalize()
+                .to_vec();
+            let more = Sha256::new()
+                .chain(&shared_secret)
+                .chain(&exchange_hash)
+                .chain(&key)
+                .finalize();
+            key.extend_from_slice(&more);
+            let key: [u8; KEY_LEN] = key.try_into().expect("two sha256 hashes make a key");
+            key
+        };
+        let opening = OpeningKey::new(&derive(b'C'));
+        let sealing = SealingKey::new(&derive(b'D'));
+        kex.opening = Some(opening);
+
+        let reply = Writer::message(msg::KEX_ECDH_REPLY)
+            .bytes(&host_key)
+            .bytes(public.as_ref())
+            .bytes(&self.host_key.sign(&exchange_hash))
+            .finish();
+        self.seal(&reply);
+        self.seal(&[msg::NEWKEYS]);
+        self.sealing = Some(sealing);
+        if self.strict {
+            self.seq_out = 0;
+        }
+
+        // the first thing the client gets under the new keys
+        if self.ext_info && first_kex {
+            let ext_info = Writer::message(msg::EXT_INFO)
+                .u32(1)
+                .string("server-sig-algs")
+                .name_list(keys::SIGNATURE_ALGORITHMS)
+                .finish();
+            self.seal(&ext_info);
+        }
+        Ok(())
+    }
+
+    fn newkeys(&mut self) -> Result<(), Error> {
+        let opening = match self.kex.as_mut().and_then(|kex| kex.opening.take()) {
+            Some(opening) => opening,
+            None => return Err(Error::Protocol("NEWKEYS out of order")),
+        };
+        self.opening = Some(opening);
+        if self.strict {
+            self.seq_in = 0;
+        }
+        self.kex = None;
+
+        for payload in std::mem::take(&mut self.held) {
+            self.seal(&payload);
+        }
+        Ok(())
+    }
+}
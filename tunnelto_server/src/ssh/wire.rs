@@ -0,0 +1,154 @@
+//! The data types of the ssh wire format, RFC 4251 section 5
+use super::Error;
+
+/// Reads the fields of a message in order
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < n {
+            return Err(Error::Malformed);
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> Result<&'a str, Error> {
+        std::str::from_utf8(self.bytes()?).map_err(|_| Error::Malformed)
+    }
+
+    pub fn name_list(&mut self) -> Result<Vec<&'a str>, Error> {
+        Ok(self
+            .string()?
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .collect())
+    }
+}
+
+/// Writes the fields of a message in order
+#[derive(Default)]
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    /// A message of this type
+    pub fn message(message_type: u8) -> Self {
+        Writer {
+            data: vec![message_type],
+        }
+    }
+
+    pub fn u8(mut self, n: u8) -> Self {
+        self.data.push(n);
+        self
+    }
+
+    pub fn bool(self, b: bool) -> Self {
+        self.u8(b as u8)
+    }
+
+    pub fn u32(mut self, n: u32) -> Self {
+        self.data.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    pub fn raw(mut self, bytes: &[u8]) -> Self {
+        self.data.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn bytes(self, bytes: &[u8]) -> Self {
+        self.u32(bytes.len() as u32).raw(bytes)
+    }
+
+    pub fn string(self, s: &str) -> Self {
+        self.bytes(s.as_bytes())
+    }
+
+    pub fn name_list(self, names: &[&str]) -> Self {
+        self.string(&names.join(","))
+    }
+
+    /// An unsigned big-endian number as a positive mpint
+    pub fn mpint(self, n: &[u8]) -> Self {
+        let start = n.iter().position(|b| *b != 0).unwrap_or(n.len());
+        let n = &n[start..];
+        match n.first() {
+            // a set top bit would make it negative
+            Some(first) if first & 0x80 != 0 => self.u32(n.len() as u32 + 1).u8(0).raw(n),
+            _ => self.bytes(n),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mpints_are_minimal_and_positive() {
+        let mpint = |n: &[u8]| Writer::default().mpint(n).finish();
+        // RFC 4251 section 5 examples
+        assert_eq!(mpint(&[0, 0]), vec![0, 0, 0, 0]);
+        assert_eq!(
+            mpint(&[0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7]),
+            vec![0, 0, 0, 8, 0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7]
+        );
+        assert_eq!(mpint(&[0x80]), vec![0, 0, 0, 2, 0, 0x80]);
+        assert_eq!(mpint(&[0, 0, 0x80]), vec![0, 0, 0, 2, 0, 0x80]);
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let data = Writer::message(80)
+            .string("tcpip-forward")
+            .bool(true)
+            .name_list(&["a", "b"])
+            .u32(8080)
+            .finish();
+        let mut reader = Reader::new(&data);
+        assert_eq!(reader.u8().unwrap(), 80);
+        assert_eq!(reader.string().unwrap(), "tcpip-forward");
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.name_list().unwrap(), vec!["a", "b"]);
+        assert_eq!(reader.u32().unwrap(), 8080);
+        assert!(matches!(reader.u8(), Err(Error::Malformed)));
+    }
+
+    #[test]
+    fn lengths_past_the_end_are_malformed() {
+        let data = Writer::default().u32(10).raw(b"short").finish();
+        assert!(matches!(Reader::new(&data).bytes(), Err(Error::Malformed)));
+    }
+}
@@ -0,0 +1,56 @@
+use super::*;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tunnelto_lib::integrity::StreamChecksum;
+
+lazy_static! {
+    static ref STATS: Stats = Stats::default();
+}
+
+#[derive(Default)]
+struct Stats {
+    verified: AtomicU64,
+    mismatched: AtomicU64,
+}
+
+/// Streams checked against their client's trailer since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrityStats {
+    pub verified: u64,
+    pub mismatched: u64,
+}
+
+pub fn stats() -> IntegrityStats {
+    IntegrityStats {
+        verified: STATS.verified.load(Ordering::Relaxed),
+        mismatched: STATS.mismatched.load(Ordering::Relaxed),
+    }
+}
+
+/// Check what we delivered to the visitor against what the client says it sent
+pub fn verify(
+    stream_id: &StreamId,
+    request_id: Option<&str>,
+    delivered: &StreamChecksum,
+    sent: &StreamChecksum,
+) {
+    match delivered.verify(sent) {
+        Ok(()) => {
+            STATS.verified.fetch_add(1, Ordering::Relaxed);
+            log::debug!(
+                "stream intact: {} bytes stream={}",
+                delivered.bytes,
+                stream_id.to_string()
+            );
+        }
+        Err(mismatch) => {
+            STATS.mismatched.fetch_add(1, Ordering::Relaxed);
+            error!(
+                "INTEGRITY: response to visitor differs from what the client sent, {} stream={} request_id={}",
+                mismatch,
+                stream_id.to_string(),
+                request_id.unwrap_or("-")
+            );
+        }
+    }
+}
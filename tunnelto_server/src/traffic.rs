@@ -0,0 +1,66 @@
+use super::*;
+use crate::queue::QueueConfig;
+use std::time::Duration;
+
+/// How visitor streams of a tunnel are handled, picked by the traffic profile it declared
+#[derive(Debug, Clone, Copy)]
+pub struct Tuning {
+    /// bytes read from the visitor per packet
+    pub read_buffer: usize,
+    /// the stream queue towards the visitor, scaled from STREAM_QUEUE_CAPACITY
+    pub stream_queue: QueueConfig,
+    /// close visitor streams that send nothing for this long
+    pub idle_timeout: Option<Duration>,
+    /// refuse visitors sending more than this, the profile doesn't expect it
+    pub max_request_bytes: Option<u64>,
+    /// refuse visitors beyond this many concurrent streams
+    pub max_streams: Option<usize>,
+}
+
+pub fn tuning(profile: TrafficProfile) -> Tuning {
+    let scaled = |divisor: usize| QueueConfig {
+        capacity: (CONFIG.stream_queue.capacity / divisor).max(1),
+        overflow: CONFIG.stream_queue.overflow,
+    };
+
+    match profile {
+        TrafficProfile::General => Tuning {
+            read_buffer: 1024,
+            stream_queue: CONFIG.stream_queue,
+            idle_timeout: None,
+            max_request_bytes: None,
+            max_streams: None,
+        },
+        // a webhook is one small request: anything else is a misdeclared or abused tunnel
+        TrafficProfile::Webhooks => Tuning {
+            read_buffer: 1024,
+            stream_queue: scaled(8),
+            idle_timeout: Some(Duration::from_secs(30)),
+            max_request_bytes: Some(10 * 1024 * 1024),
+            max_streams: Some(32),
+        },
+        TrafficProfile::Streaming => Tuning {
+            read_buffer: 16 * 1024,
+            stream_queue: CONFIG.stream_queue,
+            idle_timeout: None,
+            max_request_bytes: None,
+            max_streams: None,
+        },
+        // bigger packets, so fewer of them queued for the same memory
+        TrafficProfile::BulkFiles => Tuning {
+            read_buffer: 64 * 1024,
+            stream_queue: scaled(4),
+            idle_timeout: Some(Duration::from_secs(5 * 60)),
+            max_request_bytes: None,
+            max_streams: None,
+        },
+    }
+}
+
+/// The visitor streams open on a tunnel
+pub fn open_streams(client_id: &ClientId) -> usize {
+    ACTIVE_STREAMS
+        .iter()
+        .filter(|stream| &stream.client.id == client_id)
+        .count()
+}
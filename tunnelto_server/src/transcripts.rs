@@ -0,0 +1,389 @@
+//! Request transcripts for accounts that need an audit trail of who accessed their
+//! tunnels: one entry per visitor connection with its time, visitor ip, request line,
+//! response status and byte counts, never bodies or headers.
+//!
+//! Set TRANSCRIPT_DIR to offer them, on storage every instance shares, and accounts turn
+//! them on through the `/transcripts` account api. Entries are kept as json lines in
+//! `TRANSCRIPT_DIR/<account id>/<YYYY-MM-DD>.jsonl` for TRANSCRIPT_RETENTION_DAYS (90 by
+//! default), also after an account turns them off again.
+use super::*;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tunnelto_lib::{TranscriptEntry, TranscriptRequest, TranscriptResponse};
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+/// Marks an account as having turned transcripts on
+const ENABLED_FILE: &str = "enabled";
+
+/// How long an account's setting is trusted before looking again, for changes made
+/// through other instances
+const SETTING_TTL: Duration = Duration::from_secs(60);
+
+/// Most entries sent in one export
+const MAX_EXPORT_ENTRIES: usize = 50_000;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct TranscriptConfig {
+    pub dir: PathBuf,
+    pub retention_days: u32,
+}
+
+impl TranscriptConfig {
+    /// Read the config from the env, `None` unless TRANSCRIPT_DIR is set
+    pub fn from_env() -> Option<Self> {
+        let dir = crate::config::env_var("TRANSCRIPT_DIR").ok()?;
+        let retention_days = crate::config::env_var("TRANSCRIPT_RETENTION_DAYS")
+            .map(|n| {
+                n.parse::<u32>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .unwrap_or_else(|| panic!("invalid TRANSCRIPT_RETENTION_DAYS={}", n))
+            })
+            .unwrap_or(90);
+        Some(TranscriptConfig {
+            dir: dir.into(),
+            retention_days,
+        })
+    }
+
+    fn account_dir(&self, account_id: &Uuid) -> PathBuf {
+        self.dir.join(account_id.to_string())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("request transcripts are not enabled on this server")]
+    Disabled,
+
+    #[error("invalid date {0:?}, expected YYYY-MM-DD")]
+    InvalidDate(String),
+
+    #[error("auth error: {0}")]
+    Auth(#[from] crate::auth_db::Error),
+
+    #[error("failed to read transcripts")]
+    Io(#[from] std::io::Error),
+}
+
+lazy_static! {
+    static ref SETTINGS: DashMap<Uuid, (Instant, bool)> = DashMap::new();
+    /// serializes appends, so entries don't interleave
+    static ref WRITE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// Whether the account turned transcripts on
+async fn enabled(config: &TranscriptConfig, account_id: &Uuid) -> bool {
+    if let Some(setting) = SETTINGS.get(account_id) {
+        if setting.0 > Instant::now() {
+            return setting.1;
+        }
+    }
+
+    let enabled = tokio::fs::metadata(config.account_dir(account_id).join(ENABLED_FILE))
+        .await
+        .is_ok();
+    SETTINGS.insert(*account_id, (Instant::now() + SETTING_TTL, enabled));
+    enabled
+}
+
+/// The entry for a visitor connection to the client's tunnel, if its account keeps transcripts
+async fn entry(client: &ConnectedClient, request: &EdgeRequest) -> Option<TranscriptEntry> {
+    let config = CONFIG.transcripts.as_ref()?;
+    if !enabled(config, &client.account_id?).await {
+        return None;
+    }
+
+    Some(TranscriptEntry {
+        at: chrono::Utc::now().to_rfc3339(),
+        sub_domain: client.host.clone(),
+        request_id: Some(request.request_id.clone()),
+        visitor_ip: request.peer_addr.map(|addr| addr.ip().to_string()),
+        method: request.method.clone(),
+        path: request.path.clone(),
+        status: None,
+        answered_by: None,
+        bytes_in: 0,
+        bytes_out: 0,
+        duration_ms: 0,
+    })
+}
+
+/// A transcript entry for the request that is on its way to the client,
+/// to `record` with the stream's stats once it closed
+pub async fn forwarded(client: &ConnectedClient, request: &EdgeRequest) -> Option<PendingEntry> {
+    Some(PendingEntry {
+        account_id: client.account_id?,
+        entry: std::sync::Mutex::new(Some(entry(client, request).await?)),
+    })
+}
+
+/// The edge answered the request itself with one of its error pages
+pub async fn answered(client: &ConnectedClient, request: &EdgeRequest, error: &edge::ErrorPage) {
+    if let (Some(account_id), Some(entry)) = (client.account_id, entry(client, request).await) {
+        append(
+            account_id,
+            TranscriptEntry {
+                status: Some(error.status),
+                answered_by: Some(error.code.reason().to_string()),
+                ..entry
+            },
+        )
+        .await;
+    }
+}
+
+/// An edge filter answered the request, with whatever response it built
+pub async fn filtered(request: &EdgeRequest, filter: &str, response: &[u8]) {
+    let client = match request.tunnel() {
+        Some(client) => client,
+        None => return,
+    };
+
+    if let (Some(account_id), Some(entry)) = (client.account_id, entry(&client, request).await) {
+        append(
+            account_id,
+            TranscriptEntry {
+                status: edge::response_status(response),
+                answered_by: Some(filter.to_string()),
+                ..entry
+            },
+        )
+        .await;
+    }
+}
+
+/// The entry of a forwarded request, until its stream closes
+pub struct PendingEntry {
+    account_id: Uuid,
+    entry: std::sync::Mutex<Option<TranscriptEntry>>,
+}
+
+impl PendingEntry {
+    /// Record the entry with how the stream went, once: the tunnel's side of a stream
+    /// may outlive the visitor's, so whichever ends first records it
+    pub async fn record(&self, stats: &StreamStats) {
+        let entry = match self.entry.lock().ok().and_then(|mut entry| entry.take()) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let duration = chrono::Utc::now() - stats.started;
+        let entry = TranscriptEntry {
+            status: stats.status(),
+            bytes_in: stats.bytes_in(),
+            bytes_out: stats.bytes_out(),
+            duration_ms: duration.num_milliseconds().max(0) as u64,
+            ..entry
+        };
+        append(self.account_id, entry).await;
+    }
+}
+
+async fn append(account_id: Uuid, entry: TranscriptEntry) {
+    let config = match CONFIG.transcripts.as_ref() {
+        Some(config) => config,
+        None => return,
+    };
+    let mut line = serde_json::to_vec(&entry).unwrap_or_default();
+    line.push(b'\n');
+
+    let path = config
+        .account_dir(&account_id)
+        .join(format!("{}.jsonl", chrono::Utc::now().format("%Y-%m-%d")));
+
+    let _guard = WRITE_LOCK.lock().await;
+    let result = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.write_all(&line).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        error!(
+            "ALARM: failed to write transcript for account {}, dropped entry: {:?}",
+            account_id, e
+        );
+    }
+}
+
+/// Turn the account's transcripts on or off
+async fn set_enabled(
+    config: &TranscriptConfig,
+    account_id: &Uuid,
+    enable: bool,
+) -> Result<(), Error> {
+    let dir = config.account_dir(account_id);
+    if enable {
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(dir.join(ENABLED_FILE), chrono::Utc::now().to_rfc3339()).await?;
+    } else {
+        match tokio::fs::remove_file(dir.join(ENABLED_FILE)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    info!(
+        "account {} turned transcripts {}",
+        account_id,
+        if enable { "on" } else { "off" }
+    );
+    SETTINGS.insert(*account_id, (Instant::now() + SETTING_TTL, enable));
+    Ok(())
+}
+
+fn parse_day(day: Option<&String>) -> Result<Option<chrono::NaiveDate>, Error> {
+    day.map(|day| {
+        chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d")
+            .map_err(|_| Error::InvalidDate(day.clone()))
+    })
+    .transpose()
+}
+
+async fn transcripts(request: TranscriptRequest) -> Result<TranscriptResponse, Error> {
+    let config = CONFIG.transcripts.as_ref().ok_or(Error::Disabled)?;
+    let since = parse_day(request.since.as_ref())?;
+    let until = parse_day(request.until.as_ref())?;
+
+    let account = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?;
+    let account_id = account.account_id;
+
+    if let Some(enable) = request.enable {
+        set_enabled(config, &account_id, enable).await?;
+    }
+
+    let today = chrono::Utc::now().naive_utc().date();
+    let oldest = today - chrono::Duration::days(config.retention_days as i64);
+    let mut day = since.unwrap_or(oldest).max(oldest);
+    let until = until.unwrap_or(today).min(today);
+
+    let mut entries = vec![];
+    let mut truncated = false;
+    while day <= until && !truncated {
+        let path = config
+            .account_dir(&account_id)
+            .join(format!("{}.jsonl", day.format("%Y-%m-%d")));
+        day += chrono::Duration::days(1);
+
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for line in data.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            let entry = match serde_json::from_slice::<TranscriptEntry>(line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::warn!("skipping unreadable transcript entry in {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            if request
+                .sub_domain
+                .as_ref()
+                .is_some_and(|sub_domain| sub_domain != &entry.sub_domain)
+            {
+                continue;
+            }
+            if entries.len() == MAX_EXPORT_ENTRIES {
+                truncated = true;
+                break;
+            }
+            entries.push(entry);
+        }
+    }
+
+    Ok(TranscriptResponse::Entries {
+        enabled: enabled(config, &account_id).await,
+        retention_days: config.retention_days,
+        entries,
+        truncated,
+    })
+}
+
+/// Handle a transcript export from the control server
+pub async fn handle_transcripts(
+    request: TranscriptRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (response, status) = match transcripts(request).await {
+        Ok(response) => (response, StatusCode::OK),
+        Err(e) => {
+            let status = match e {
+                Error::Disabled => StatusCode::NOT_FOUND,
+                Error::InvalidDate(_) => StatusCode::BAD_REQUEST,
+                Error::Auth(_) => StatusCode::UNAUTHORIZED,
+                Error::Io(ref io) => {
+                    error!("failed to export transcripts: {:?}", io);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            (
+                TranscriptResponse::Failed {
+                    reason: e.to_string(),
+                },
+                status,
+            )
+        }
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+/// Delete day files past retention
+async fn sweep(config: &TranscriptConfig) -> std::io::Result<()> {
+    let cutoff = chrono::Utc::now().naive_utc().date()
+        - chrono::Duration::days(config.retention_days as i64);
+
+    let mut accounts = match tokio::fs::read_dir(&config.dir).await {
+        Ok(accounts) => accounts,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    while let Some(account) = accounts.next_entry().await? {
+        if !account.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut days = tokio::fs::read_dir(account.path()).await?;
+        while let Some(day) = days.next_entry().await? {
+            let name = day.file_name();
+            let expired = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".jsonl"))
+                .and_then(|day| chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").ok())
+                .is_some_and(|day| day < cutoff);
+            if expired {
+                tokio::fs::remove_file(day.path()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Delete expired transcripts now and every hour
+pub fn spawn_sweep(config: TranscriptConfig) {
+    info!(
+        "keeping request transcripts in {:?} for {} days",
+        config.dir, config.retention_days
+    );
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = sweep(&config).await {
+                error!("failed to delete expired transcripts: {:?}", e);
+            }
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+        }
+    });
+}
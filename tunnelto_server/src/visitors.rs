@@ -0,0 +1,88 @@
+use super::*;
+use tunnelto_lib::{VisitorConnection, VisitorsRequest, VisitorsResponse};
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+/// Visitor connections currently open on the account's tunnels on this instance
+fn connections_for_account(account_id: &Uuid) -> Vec<ActiveStream> {
+    ACTIVE_STREAMS
+        .iter()
+        .filter(|s| s.client.account_id.as_ref() == Some(account_id))
+        .map(|s| s.value().clone())
+        .collect()
+}
+
+fn describe(stream: &ActiveStream) -> VisitorConnection {
+    VisitorConnection {
+        id: stream.id.to_string(),
+        sub_domain: stream.client.host.clone(),
+        peer_addr: stream.peer_addr.map(|a| a.to_string()),
+        connected_secs: (chrono::Utc::now() - stream.stats.started)
+            .num_seconds()
+            .max(0) as u64,
+        bytes_in: stream.stats.bytes_in(),
+        bytes_out: stream.stats.bytes_out(),
+    }
+}
+
+/// Close the visitor connection and tell the tunnel client to drop its side
+async fn kick(mut stream: ActiveStream) {
+    ACTIVE_STREAMS.remove(&stream.id);
+    crate::siem::visitor_kicked(&stream);
+    // the reader lets go of the socket, the writer shuts it down once it sees `Kicked`
+    stream.kicked.notify_one();
+    let _ = stream.tx.send(StreamMessage::Kicked).await;
+    let _ = stream
+        .client
+        .tx
+        .send(ControlPacket::End(stream.id.clone()))
+        .await;
+    info!("kicked visitor stream: {}", stream.id.to_string());
+}
+
+async fn visitors(request: VisitorsRequest) -> Result<VisitorsResponse, auth_db::Error> {
+    let account = AUTH_DB_SERVICE
+        .get_account_id_for_owner_key(&request.auth_key.0)
+        .await?;
+    let streams = connections_for_account(&account.account_id);
+
+    let id = match request.kick {
+        Some(id) => id,
+        None => {
+            return Ok(VisitorsResponse::Connections {
+                connections: streams.iter().map(describe).collect(),
+            })
+        }
+    };
+
+    match streams.into_iter().find(|s| s.id.to_string() == id) {
+        Some(stream) => {
+            kick(stream).await;
+            Ok(VisitorsResponse::Kicked { id })
+        }
+        None => Ok(VisitorsResponse::Failed {
+            reason: format!("visitor connection {} is not on this instance", id),
+        }),
+    }
+}
+
+/// Handle a visitors request from the control server
+pub async fn handle_visitors(
+    request: VisitorsRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (response, status) = match visitors(request).await {
+        Ok(response @ VisitorsResponse::Failed { .. }) => (response, StatusCode::NOT_FOUND),
+        Ok(response) => (response, StatusCode::OK),
+        Err(e) => (
+            VisitorsResponse::Failed {
+                reason: e.to_string(),
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}